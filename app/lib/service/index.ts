@@ -3,7 +3,7 @@ import { notification } from 'antd'
 import { getSession } from 'next-auth/react'
 
 const service = axios.create({
-  baseURL: process.env.NEXT_PUBLIC_SERVER_URL,
+  baseURL: `${process.env.NEXT_PUBLIC_SERVER_URL ?? ''}/v1`,
 })
 
 service.interceptors.request.use(async (config) => {
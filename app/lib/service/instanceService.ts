@@ -22,7 +22,9 @@ export type Instance = {
   cpu: number
   memory: number
   disk_size: number
-  status: string
+  // The stage the user asked for, i.e. Running, Stopped or Deleted.
+  stage: string
+  status: Status
   // Deprecated: use external_ip instead.
   ssh_host: string
   // Deprecated: use 22 instead.
@@ -35,6 +37,16 @@ export type Instance = {
   storage_pool: string
 }
 
+export type Status = {
+  state: string
+  // Why the state differs from the stage, e.g. StoppedByBackend.
+  reason?: string
+  // The error of the Error state.
+  message?: string
+  // Unix timestamp the instance entered the state.
+  since?: number
+}
+
 export enum InstanceStatus {
   Creating = 'Creating',
   Starting = 'Starting',
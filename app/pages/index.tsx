@@ -167,7 +167,7 @@ const Home: NextPage = () => {
         </Popconfirm>
       )
     }
-    if (record.status === InstanceStatus.Running) {
+    if (record.status.state === InstanceStatus.Running) {
       return (
         <div className={styles.operation}>
           {deleteInstancePopconfirm()}/
@@ -184,13 +184,13 @@ const Home: NextPage = () => {
         </div>
       )
     } else if (
-      record.status == InstanceStatus.Creating ||
-      record.status === InstanceStatus.Starting
+      record.status.state == InstanceStatus.Creating ||
+      record.status.state === InstanceStatus.Starting
     ) {
       return (
         <div className={styles.operation}>{deleteInstancePopconfirm()}</div>
       )
-    } else if (record.status === InstanceStatus.Stopped) {
+    } else if (record.status.state === InstanceStatus.Stopped) {
       return (
         <div className={styles.operation}>
           <Popconfirm
@@ -269,7 +269,7 @@ const Home: NextPage = () => {
       dataIndex: 'external_ip',
       key: 'external_ip',
       render: (_, record: Instance) => {
-        if (isRunnable(record.status)) {
+        if (isRunnable(record.status.state)) {
           if (!record.external_ip) {
             return <Spin />
           } else {
@@ -300,8 +300,8 @@ const Home: NextPage = () => {
       dataIndex: 'password',
       key: 'password',
       render: (password, record) => {
-        if (isRunnable(record.status)) {
-          if (record.status === InstanceStatus.Starting) {
+        if (isRunnable(record.status.state)) {
+          if (record.status.state === InstanceStatus.Starting) {
             return <Spin />
           } else {
             return (
@@ -335,7 +335,7 @@ const Home: NextPage = () => {
       dataIndex: 'external_ip',
       key: 'external_ip',
       render: (_, record) => {
-        if (isRunnable(record.status)) {
+        if (isRunnable(record.status.state)) {
           if (!record.external_ip) {
             return <Spin />
           } else {
@@ -355,7 +355,7 @@ const Home: NextPage = () => {
       title: 'Status',
       dataIndex: 'status',
       key: 'status',
-      render: (status) => getStatusTag(status),
+      render: (status) => getStatusTag(status.state),
     },
     {
       title: 'Operation',
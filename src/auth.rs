@@ -1,6 +1,7 @@
 use axum::{
     async_trait,
     extract::{Extension, FromRequest, RequestParts, TypedHeader},
+    http::header::SEC_WEBSOCKET_PROTOCOL,
 };
 use google_signin;
 use google_signin::{CachedCerts, Client};
@@ -8,8 +9,9 @@ use headers::{authorization::Bearer, Authorization};
 use once_cell::sync::Lazy;
 use serde::{Deserialize, Serialize};
 use tokio::sync::RwLock;
-use tracing::warn;
+use tracing::{info, warn};
 
+use crate::config;
 use crate::env::GOOGLE_CLIENT_ID;
 use crate::error::AuthError;
 use crate::storage::Storage;
@@ -22,6 +24,30 @@ static CLIENT: Lazy<Client> = Lazy::new(|| {
 
 static CACHEDCERTS: Lazy<RwLock<CachedCerts>> = Lazy::new(|| RwLock::new(CachedCerts::new()));
 
+/// Header with which admins act as another user.
+const IMPERSONATE_USER_HEADER: &str = "x-impersonate-user";
+
+/// WebSocket subprotocol announced along with the token by browsers, which can't set the
+/// authorization header of WebSocket requests: `Sec-WebSocket-Protocol: bearer, <token>`.
+crate const WEBSOCKET_BEARER_PROTOCOL: &str = "bearer";
+
+/// Returns the token of the request, taken from the authorization header or from the WebSocket
+/// subprotocols following `WEBSOCKET_BEARER_PROTOCOL`.
+async fn bearer_token<B>(req: &mut RequestParts<B>) -> Option<String>
+where
+    B: Send,
+{
+    if let Ok(TypedHeader(Authorization(bearer))) =
+        TypedHeader::<Authorization<Bearer>>::from_request(req).await
+    {
+        return Some(bearer.token().to_owned());
+    }
+    let protocols = req.headers()?.get(SEC_WEBSOCKET_PROTOCOL)?.to_str().ok()?;
+    let mut protocols = protocols.split(',').map(|p| p.trim());
+    protocols.find(|p| *p == WEBSOCKET_BEARER_PROTOCOL)?;
+    protocols.next().map(|t| t.to_owned())
+}
+
 #[derive(Debug, Clone, Default, Serialize, Deserialize)]
 #[serde(default)]
 pub struct UserClaims {
@@ -29,6 +55,12 @@ pub struct UserClaims {
     crate email: String,
 }
 
+impl UserClaims {
+    crate fn is_admin(&self) -> bool {
+        config::current().admins.contains(&self.username)
+    }
+}
+
 #[async_trait]
 impl<B> FromRequest<B> for UserClaims
 where
@@ -37,11 +69,11 @@ where
     type Rejection = AuthError;
 
     async fn from_request(req: &mut RequestParts<B>) -> Result<Self, Self::Rejection> {
-        // Extract the token from the authorization header
-        let TypedHeader(Authorization(bearer)) =
-            TypedHeader::<Authorization<Bearer>>::from_request(req)
-                .await
-                .map_err(|_| AuthError::InvalidToken)?;
+        // Claims already verified by the rate limiter, see `RateLimitLayer`.
+        if let Some(user) = req.extensions().and_then(|e| e.get::<UserClaims>()) {
+            return Ok(user.clone());
+        }
+        let token = bearer_token(req).await.ok_or(AuthError::InvalidToken)?;
 
         let mut certs = CACHEDCERTS.read().await.clone();
         match certs.refresh_if_needed().await {
@@ -55,7 +87,7 @@ where
             }
         }
 
-        let id_info = CLIENT.verify(bearer.token(), &certs).await.map_err(|e| {
+        let id_info = CLIENT.verify(&token, &certs).await.map_err(|e| {
             warn!("verify token err {:?}", e);
             AuthError::InvalidToken
         })?;
@@ -72,15 +104,58 @@ where
             .await
             .expect("`Storage` extension is missing");
 
+        let impersonated = match req.headers().and_then(|h| h.get(IMPERSONATE_USER_HEADER)) {
+            Some(value) => Some(
+                value
+                    .to_str()
+                    .map_err(|_| AuthError::UnauthorizedUser)?
+                    .to_owned(),
+            ),
+            None => None,
+        };
+
         let mut found = false;
+        let mut impersonated_found = false;
         storage
-            .read_only(|state| found = state.find_user(&username).is_some())
+            .read_only(|state| {
+                found = state.find_user(&username).is_some();
+                impersonated_found = impersonated
+                    .as_ref()
+                    .map_or(false, |u| state.find_user(u).is_some());
+            })
             .await;
-        if found {
-            Ok(UserClaims { username, email })
-        } else {
+        if !found {
             warn!("unauthorized user {}", username);
-            Err(AuthError::UnauthorizedUser)
+            return Err(AuthError::UnauthorizedUser);
+        }
+        let user = UserClaims { username, email };
+        let impersonated = match impersonated {
+            Some(impersonated) => impersonated,
+            None => return Ok(user),
+        };
+        if !user.is_admin() {
+            warn!(
+                username = user.username.as_str(),
+                impersonated = impersonated.as_str(),
+                "non-admin user attempted impersonation"
+            );
+            return Err(AuthError::ImpersonationForbidden);
+        }
+        if !impersonated_found {
+            return Err(AuthError::UnauthorizedUser);
         }
+        // Every request made on behalf of another user is logged for auditing.
+        info!(
+            admin = user.username.as_str(),
+            username = impersonated.as_str(),
+            method = req.method().as_str(),
+            path = req.uri().path(),
+            "impersonating user"
+        );
+        // The email of the impersonated user is unknown as it comes from their token.
+        Ok(UserClaims {
+            username: impersonated,
+            email: String::new(),
+        })
     }
 }
@@ -0,0 +1,209 @@
+//! Takes the backups of instances according to their backup policies.
+//!
+//! A due backup is started as an export transfer of the instance, which the LXD operator creates
+//! and uploads to the bucket of the policy. Successful backups beyond the retention are deleted
+//! from the bucket, while the ones of instances removed from the state are left there.
+
+use chrono::{DateTime, Utc};
+use tokio::time::Duration;
+use tracing::{info, instrument, warn};
+
+use crate::metrics::BACKEND_ERRORS;
+use crate::model::{
+    Backup, BackupPolicy, Instance, InstanceStage, InstanceStatus, Runtime, Transfer, TransferKind,
+    TransferStatus,
+};
+use crate::s3::Bucket;
+use crate::shutdown;
+use crate::storage::Storage;
+
+// A backup expired by the retention of a policy, to be deleted from the bucket.
+struct ExpiredBackup {
+    username: String,
+    instance: String,
+    backup: Backup,
+}
+
+pub struct BackupScheduler {
+    storage: Storage,
+    bucket: Option<Bucket>,
+}
+
+impl BackupScheduler {
+    pub fn new(storage: Storage) -> Self {
+        BackupScheduler {
+            storage,
+            bucket: Bucket::from_env(),
+        }
+    }
+
+    pub async fn run(&self) {
+        while !shutdown::is_triggered() {
+            self.run_once().await;
+            shutdown::sleep(Duration::from_secs(60)).await;
+        }
+    }
+
+    #[instrument(skip_all)]
+    async fn run_once(&self) {
+        let now = Utc::now();
+        let mut expired = Vec::new();
+        let res = self
+            .storage
+            .read_write(|state| {
+                expired.clear();
+                let mut changed = false;
+                for u in &mut state.users {
+                    for i in &mut u.instances {
+                        if let Some(mut policy) = i.backup_policy.take() {
+                            changed |= apply_policy(&u.username, i, &mut policy, now, &mut expired);
+                            i.backup_policy = Some(policy);
+                        }
+                    }
+                }
+                changed
+            })
+            .await;
+        if let Err(e) = res {
+            warn!(
+                error = e.to_string().as_str(),
+                "apply backup policies encountered error"
+            );
+            return;
+        }
+
+        for e in expired {
+            self.delete_backup(e).await;
+        }
+    }
+
+    async fn delete_backup(&self, expired: ExpiredBackup) {
+        let bucket = match &self.bucket {
+            Some(bucket) => bucket.with_bucket(&expired.backup.bucket),
+            None => return,
+        };
+        info!(
+            username = expired.username.as_str(),
+            instance = expired.instance.as_str(),
+            object_key = expired.backup.object_key.as_str(),
+            "deleting expired backup"
+        );
+        if let Err(e) = bucket.delete_object(&expired.backup.object_key).await {
+            warn!(
+                username = expired.username.as_str(),
+                instance = expired.instance.as_str(),
+                error = e.to_string().as_str(),
+                "deleting expired backup encountered error"
+            );
+            BACKEND_ERRORS.with_label_values(&["s3"]).inc();
+            return;
+        }
+        let res = self
+            .storage
+            .read_write(|state| {
+                match state
+                    .find_mut_user(&expired.username)
+                    .and_then(|u| u.find_mut_instance(&expired.instance))
+                    .and_then(|i| i.backup_policy.as_mut())
+                {
+                    Some(policy) => {
+                        let len = policy.backups.len();
+                        policy.backups.retain(|b| *b != expired.backup);
+                        policy.backups.len() != len
+                    }
+                    None => false,
+                }
+            })
+            .await;
+        if let Err(e) = res {
+            warn!(
+                username = expired.username.as_str(),
+                instance = expired.instance.as_str(),
+                error = e.to_string().as_str(),
+                "removing expired backup encountered error"
+            );
+        }
+    }
+}
+
+/// Follows the backup in progress, expires the old backups and starts a backup if it's due.
+/// Returns true if the instance or the policy is changed.
+fn apply_policy(
+    username: &str,
+    i: &mut Instance,
+    policy: &mut BackupPolicy,
+    now: DateTime<Utc>,
+    expired: &mut Vec<ExpiredBackup>,
+) -> bool {
+    let mut changed = false;
+    if let Some(b) = policy.backups.last_mut().filter(|b| !b.is_finished()) {
+        let status = match &i.transfer {
+            Some(t) if t.object_key == b.object_key => t.status.clone(),
+            _ => TransferStatus::Failed("superseded by another transfer".to_owned()),
+        };
+        if b.status != status {
+            if let TransferStatus::Failed(err) = &status {
+                i.add_event(format!("scheduled backup failed: {}", err));
+            }
+            b.status = status;
+            changed = true;
+        }
+    }
+
+    // Only the latest failure is worth keeping.
+    if let Some(latest) = policy.backups.pop() {
+        let len = policy.backups.len();
+        policy
+            .backups
+            .retain(|b| !matches!(b.status, TransferStatus::Failed(_)));
+        changed |= policy.backups.len() != len;
+        policy.backups.push(latest);
+    }
+    let succeeded: Vec<&Backup> = policy
+        .backups
+        .iter()
+        .filter(|b| b.status == TransferStatus::Succeeded)
+        .collect();
+    let excess = succeeded.len().saturating_sub(policy.retention);
+    expired.extend(succeeded.into_iter().take(excess).map(|b| ExpiredBackup {
+        username: username.to_owned(),
+        instance: i.name.clone(),
+        backup: b.clone(),
+    }));
+
+    if i.stage == InstanceStage::Deleted
+        || !matches!(i.runtime, Runtime::Lxc | Runtime::Kvm)
+        || !matches!(i.status, InstanceStatus::Running | InstanceStatus::Stopped)
+        || matches!(&i.transfer, Some(t) if !t.is_finished())
+        || !policy.is_due(now.timestamp())
+    {
+        return changed;
+    }
+    info!(
+        username = username,
+        instance = i.name.as_str(),
+        "starting scheduled backup"
+    );
+    let backup = Backup {
+        object_key: format!(
+            "{}/{}/scheduled-{}.tar.gz",
+            username,
+            i.name,
+            now.format("%Y%m%d%H%M%S")
+        ),
+        bucket: policy.bucket.clone(),
+        started_at: now.timestamp(),
+        status: TransferStatus::Pending,
+    };
+    i.transfer = Some(Transfer {
+        kind: TransferKind::Export,
+        object_key: backup.object_key.clone(),
+        bucket: backup.bucket.clone(),
+        operation: None,
+        status: TransferStatus::Pending,
+        progress: None,
+    });
+    policy.backups.push(backup);
+    i.add_event("scheduled backup started".to_owned());
+    true
+}
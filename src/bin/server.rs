@@ -1,73 +1,304 @@
 use std::{net::SocketAddr, time::Duration};
 
+use axum::body::Body;
+use axum::http::Request;
 use axum::{error_handling::HandleErrorLayer, Router};
-use reqwest::{Client as ReqwestClient, Identity};
-use std::fs::File;
-use std::io::Read;
-use tower::ServiceBuilder;
+use axum_server::tls_rustls::RustlsConfig;
+use axum_server::Handle;
+use opentelemetry::sdk::Resource;
+use opentelemetry::KeyValue;
+use reqwest::Client as ReqwestClient;
+use tokio::signal::unix::{signal, SignalKind};
+use tokio::task::JoinHandle;
+use tower::{ServiceBuilder, ServiceExt};
 use tower_http::cors::{any, CorsLayer, Origin};
 use tower_http::{add_extension::AddExtensionLayer, trace::TraceLayer};
-use tracing::{info, warn};
+use tracing::{info, info_span, warn};
+use tracing_subscriber::layer::SubscriberExt;
+use tracing_subscriber::util::SubscriberInitExt;
+use tracing_subscriber::EnvFilter;
 
+use tispace::backup::BackupScheduler;
 use tispace::collector::Collector;
-use tispace::env::LXD_CLIENT_CERT;
+use tispace::config;
+use tispace::dns::DnsPublisher;
+use tispace::env::{
+    DNS_ZONE, EC2_REGION, IMAGE_PREPULL_INTERVAL, LXD_CLIENT_CERT, METADATA_PORT, MICROVM_AGENTS,
+    OCI_AGENTS, ORPHAN_GC_INTERVAL, OTEL_EXPORTER_OTLP_ENDPOINT, TLS_CERT, TLS_KEY, WEB_UI_DIR,
+};
 use tispace::error::handle_error;
-use tispace::operator_lxd::Operator as LxdOperator;
+use tispace::gc::GarbageCollector;
+use tispace::image_builder::ImageBuilder;
+use tispace::leader::LeaderElector;
+use tispace::maintenance::MaintenanceNotifier;
+use tispace::metering::Meter;
+use tispace::metrics::HttpMetricsLayer;
+use tispace::operator_ec2::Operator as Ec2Operator;
+use tispace::operator_lxd::{self, Operator as LxdOperator};
+use tispace::operator_microvm::Operator as MicroVmOperator;
+use tispace::operator_oci::Operator as OciOperator;
+use tispace::power_scheduler::PowerScheduler;
+use tispace::prepull::ImagePrePuller;
+use tispace::recycle_bin::RecycleBinPurger;
+use tispace::request_id::{RequestId, RequestIdLayer};
 use tispace::scheduler::Scheduler;
-use tispace::service::{metrics_routes, protected_routes};
+use tispace::service::{
+    api_routes, health_routes, metadata_routes, metrics_routes, rewrite_overlapping_paths,
+    web_ui_routes,
+};
+use tispace::shutdown;
 use tispace::storage::Storage;
 
-#[tokio::main]
-async fn main() {
-    if std::env::var_os("RUST_LOG").is_none() {
-        std::env::set_var("RUST_LOG", "tispace=debug,tower_http=debug,server=debug")
+fn init_tracing() {
+    let registry = tracing_subscriber::registry()
+        .with(EnvFilter::from_default_env())
+        .with(tracing_subscriber::fmt::layer());
+    if OTEL_EXPORTER_OTLP_ENDPOINT.is_empty() {
+        registry.init();
+        return;
     }
-    tracing_subscriber::fmt::init();
+    let tracer = opentelemetry_otlp::new_pipeline()
+        .tracing()
+        .with_exporter(
+            opentelemetry_otlp::new_exporter()
+                .tonic()
+                .with_endpoint(OTEL_EXPORTER_OTLP_ENDPOINT.as_str()),
+        )
+        .with_trace_config(
+            opentelemetry::sdk::trace::config().with_resource(Resource::new(vec![KeyValue::new(
+                "service.name",
+                "tispace",
+            )])),
+        )
+        .install_batch(opentelemetry::runtime::Tokio)
+        .unwrap();
+    registry
+        .with(tracing_opentelemetry::layer().with_tracer(tracer))
+        .init();
+}
 
-    let s: Storage = Storage::open("state.json").await.unwrap();
+// Returns the value of the `--config <path>` or `--config=<path>` argument if any.
+fn config_path() -> Option<String> {
+    let mut args = std::env::args().skip(1);
+    while let Some(arg) = args.next() {
+        if arg == "--config" {
+            return args.next();
+        }
+        if let Some(path) = arg.strip_prefix("--config=") {
+            return Some(path.to_owned());
+        }
+    }
+    None
+}
 
+// Starts the operators, the collector and the scheduler. They are awaited on shutdown so that
+// they can finish their current work.
+fn start_background_tasks(s: &Storage) -> Vec<JoinHandle<()>> {
+    let mut tasks = Vec::new();
     let mut lxd_client = None;
     if !LXD_CLIENT_CERT.is_empty() {
-        let mut buf = Vec::new();
-        File::open(LXD_CLIENT_CERT.as_str())
-            .unwrap()
-            .read_to_end(&mut buf)
-            .unwrap();
-        let id = Identity::from_pkcs12_der(&buf, "").unwrap();
-        let client = ReqwestClient::builder()
-            .danger_accept_invalid_certs(true)
-            .identity(id)
-            .build()
-            .unwrap();
+        let client = operator_lxd::new_client();
         let lxd_operator = LxdOperator::new(client.clone(), s.clone());
-        tokio::spawn(async move { lxd_operator.run().await });
-        lxd_client = Some(client);
+        tasks.push(tokio::spawn(async move { lxd_operator.run().await }));
         info!("lxd operator started");
+        let image_builder = ImageBuilder::new(client.clone(), s.clone());
+        tasks.push(tokio::spawn(async move { image_builder.run().await }));
+        info!("image builder started");
+        lxd_client = Some(client);
     } else {
         warn!("lxd client cert not provided, will not start lxd operator");
     }
 
+    if !MICROVM_AGENTS.is_empty() {
+        let microvm_operator = MicroVmOperator::new(ReqwestClient::new(), s.clone());
+        tasks.push(tokio::spawn(async move { microvm_operator.run().await }));
+        info!("micro-VM operator started");
+    }
+
+    if !OCI_AGENTS.is_empty() {
+        let oci_operator = OciOperator::new(ReqwestClient::new(), s.clone());
+        tasks.push(tokio::spawn(async move { oci_operator.run().await }));
+        info!("oci operator started");
+    }
+
+    if !EC2_REGION.is_empty() {
+        let ec2_operator = Ec2Operator::new(ReqwestClient::new(), s.clone());
+        tasks.push(tokio::spawn(async move { ec2_operator.run().await }));
+        info!("ec2 operator started");
+    }
+
+    if *ORPHAN_GC_INTERVAL > 0 {
+        let gc = GarbageCollector::new(s.clone(), None, lxd_client.clone());
+        tasks.push(tokio::spawn(async move { gc.run().await }));
+        info!("garbage collector started");
+    }
+
+    if *IMAGE_PREPULL_INTERVAL > 0 {
+        let pre_puller = ImagePrePuller::new(s.clone(), None, lxd_client.clone());
+        tasks.push(tokio::spawn(async move { pre_puller.run().await }));
+        info!("image pre-puller started");
+    }
+
     let collector = Collector::new(s.clone(), None, lxd_client);
-    tokio::spawn(async move { collector.run().await });
+    tasks.push(tokio::spawn(async move { collector.run().await }));
     info!("collector started");
 
     let scheduler = Scheduler::new(s.clone());
-    tokio::spawn(async move { scheduler.run().await });
+    tasks.push(tokio::spawn(async move { scheduler.run().await }));
     info!("scheduler started");
 
-    let app = Router::new()
-        .merge(protected_routes())
+    let power_scheduler = PowerScheduler::new(s.clone());
+    tasks.push(tokio::spawn(async move { power_scheduler.run().await }));
+    info!("power scheduler started");
+
+    let maintenance_notifier = MaintenanceNotifier::new(s.clone());
+    tasks.push(tokio::spawn(
+        async move { maintenance_notifier.run().await },
+    ));
+    info!("maintenance notifier started");
+
+    let recycle_bin_purger = RecycleBinPurger::new(s.clone());
+    tasks.push(tokio::spawn(async move { recycle_bin_purger.run().await }));
+    info!("recycle bin purger started");
+
+    let backup_scheduler = BackupScheduler::new(s.clone());
+    tasks.push(tokio::spawn(async move { backup_scheduler.run().await }));
+    info!("backup scheduler started");
+
+    if !DNS_ZONE.is_empty() {
+        let dns_publisher = DnsPublisher::new(ReqwestClient::new(), s.clone());
+        tasks.push(tokio::spawn(async move { dns_publisher.run().await }));
+        info!("dns publisher started");
+    }
+
+    let meter = Meter::new(s.clone());
+    tasks.push(tokio::spawn(async move { meter.run().await }));
+    info!("meter started");
+    tasks
+}
+
+#[tokio::main]
+async fn main() {
+    if let Err(e) = config::init(config_path()) {
+        eprintln!("invalid config: {:#}", e);
+        std::process::exit(1);
+    }
+    if std::env::var_os("RUST_LOG").is_none() {
+        std::env::set_var("RUST_LOG", "tispace=debug,tower_http=debug,server=debug")
+    }
+    init_tracing();
+
+    let s: Storage = Storage::open("state.json").await.unwrap();
+
+    let background = match LeaderElector::new().await.unwrap() {
+        Some(elector) => {
+            let storage = s.clone();
+            tokio::spawn(async move {
+                // Followers serve the state written by the leader.
+                let follow = async {
+                    while !shutdown::is_triggered() {
+                        if let Err(e) = storage.reload().await {
+                            warn!(
+                                error = e.to_string().as_str(),
+                                "reload state encountered error"
+                            );
+                        }
+                        shutdown::sleep(Duration::from_secs(3)).await;
+                    }
+                };
+                let acquired = tokio::select! {
+                    acquired = elector.acquire() => acquired,
+                    _ = follow => false,
+                };
+                if !acquired {
+                    return;
+                }
+                // Pick up the last writes of the previous leader.
+                if let Err(e) = storage.reload().await {
+                    warn!(
+                        error = e.to_string().as_str(),
+                        "reload state encountered error"
+                    );
+                }
+                if let Err(e) = storage.remove_interrupted_write().await {
+                    warn!(
+                        error = e.to_string().as_str(),
+                        "remove interrupted write encountered error"
+                    );
+                }
+                let tasks = start_background_tasks(&storage);
+                tokio::join!(elector.renew(), async {
+                    for task in tasks {
+                        let _ = task.await;
+                    }
+                });
+                // The next leader picks up the state from the file.
+                if let Err(e) = storage.flush().await {
+                    warn!(
+                        error = e.to_string().as_str(),
+                        "flushing state encountered error"
+                    );
+                }
+                // Let another replica take over without waiting for the lease to expire.
+                if let Err(e) = elector.release().await {
+                    warn!(
+                        error = e.to_string().as_str(),
+                        "release lease encountered error"
+                    );
+                }
+            })
+        }
+        None => {
+            s.remove_interrupted_write().await.unwrap();
+            let tasks = start_background_tasks(&s);
+            tokio::spawn(async move {
+                for task in tasks {
+                    let _ = task.await;
+                }
+            })
+        }
+    };
+
+    let mut routes = Router::new()
+        .merge(api_routes())
         .merge(metrics_routes())
+        .merge(health_routes());
+    if !WEB_UI_DIR.is_empty() {
+        routes = routes.merge(web_ui_routes(&WEB_UI_DIR));
+        info!("serving web ui from {}", WEB_UI_DIR.as_str());
+    }
+    // Paths are rewritten before the routing, which the layers of the router don't see.
+    let app = Router::new()
+        .fallback(routes.map_request(rewrite_overlapping_paths))
         // Add middleware to all routes
         .layer(
             ServiceBuilder::new()
+                // Assign an ID to each request before anything else, so that all spans and
+                // error responses carry it
+                .layer(RequestIdLayer)
+                // Record metrics of the responses including the ones generated from errors
+                .layer(HttpMetricsLayer)
                 // Handle errors from middleware
                 .layer(HandleErrorLayer::new(handle_error))
                 .load_shed()
                 .concurrency_limit(1024)
                 .timeout(Duration::from_secs(10))
-                .layer(TraceLayer::new_for_http())
-                .layer(AddExtensionLayer::new(s))
+                .layer(
+                    TraceLayer::new_for_http().make_span_with(|req: &Request<Body>| {
+                        let request_id = req
+                            .extensions()
+                            .get::<RequestId>()
+                            .map(|id| id.0.as_str())
+                            .unwrap_or_default();
+                        info_span!(
+                            "request",
+                            method = %req.method(),
+                            uri = %req.uri(),
+                            request_id = request_id,
+                        )
+                    }),
+                )
+                .layer(AddExtensionLayer::new(s.clone()))
                 .into_inner(),
         )
         .layer(
@@ -82,10 +313,90 @@ async fn main() {
                 .allow_headers(any()),
         );
 
+    tokio::spawn(async move {
+        let mut hangup = signal(SignalKind::hangup()).unwrap();
+        while hangup.recv().await.is_some() {
+            match config::reload() {
+                Ok(()) => info!("config reloaded"),
+                Err(e) => warn!(
+                    error = format!("{:#}", e).as_str(),
+                    "reload config encountered error"
+                ),
+            }
+        }
+    });
+
+    tokio::spawn(async move {
+        let mut terminate = signal(SignalKind::terminate()).unwrap();
+        tokio::select! {
+            _ = terminate.recv() => {}
+            _ = tokio::signal::ctrl_c() => {}
+        }
+        info!("shutting down");
+        shutdown::trigger();
+    });
+
+    if *METADATA_PORT != 0 {
+        let metadata_app = metadata_routes().layer(
+            ServiceBuilder::new()
+                .layer(RequestIdLayer)
+                .layer(TraceLayer::new_for_http())
+                .layer(AddExtensionLayer::new(s.clone()))
+                .into_inner(),
+        );
+        let addr = SocketAddr::from(([0, 0, 0, 0], *METADATA_PORT));
+        info!("serving metadata on http://{}", addr);
+        tokio::spawn(async move {
+            axum::Server::bind(&addr)
+                .serve(metadata_app.into_make_service_with_connect_info::<SocketAddr, _>())
+                .with_graceful_shutdown(shutdown::triggered())
+                .await
+                .unwrap();
+        });
+    }
+
     let addr = SocketAddr::from(([0, 0, 0, 0], 8080));
-    info!("listening on {}", addr);
-    axum::Server::bind(&addr)
-        .serve(app.into_make_service())
+    if TLS_CERT.is_empty() {
+        info!("listening on http://{}", addr);
+        axum::Server::bind(&addr)
+            .serve(app.into_make_service_with_connect_info::<SocketAddr, _>())
+            .with_graceful_shutdown(shutdown::triggered())
+            .await
+            .unwrap();
+    } else {
+        let tls_config = RustlsConfig::from_pem_file(TLS_CERT.as_str(), TLS_KEY.as_str())
+            .await
+            .unwrap();
+        let handle = Handle::new();
+        let shutdown_handle = handle.clone();
+        tokio::spawn(async move {
+            shutdown::triggered().await;
+            shutdown_handle.graceful_shutdown(None);
+        });
+        info!("listening on https://{}", addr);
+        axum_server::bind_rustls(addr, tls_config)
+            .handle(handle)
+            .serve(app.into_make_service_with_connect_info::<SocketAddr, _>())
+            .await
+            .unwrap();
+    }
+
+    // Operators stop after the instance they are syncing, which may take a while if a backend is
+    // slow, so give up before the pod is killed anyway.
+    let drain = background;
+    if tokio::time::timeout(Duration::from_secs(25), drain)
         .await
-        .unwrap();
+        .is_err()
+    {
+        warn!("background tasks did not finish in time");
+    }
+    if let Err(e) = s.flush().await {
+        warn!(
+            error = e.to_string().as_str(),
+            "flushing state encountered error"
+        );
+    }
+    info!("shutdown completed");
+    // Flush the spans still buffered by the batch exporter.
+    opentelemetry::global::shutdown_tracer_provider();
 }
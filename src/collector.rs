@@ -1,26 +1,37 @@
+use std::collections::{BTreeMap, HashMap};
+
 use anyhow::{anyhow, Result};
+use chrono::Utc;
 use k8s_openapi::api::core::v1::Node as KubeNode;
 use k8s_quantity_parser::QuantityParser;
 use kube::core::params::ListParams;
 use kube::{Api, Client as KubeClient};
 use reqwest::Client as ReqwestClient;
-use tokio::time::{sleep, Duration};
-use tracing::warn;
+use tokio::time::{Duration, Instant};
+use tracing::{instrument, warn};
 
+use crate::config;
 use crate::env::{
-    CPU_OVERCOMMIT_FACTOR, LXD_PROJECT, LXD_SERVER_URL, LXD_STORAGE_POOL_DRIVER,
-    MEMORY_OVERCOMMIT_FACTOR,
+    COLLECTOR_INTERVAL, LXD_PROJECT, LXD_STORAGE_POOL_DRIVERS, MICROVM_AGENTS, OCI_AGENTS,
 };
-use crate::model::{Node, Runtime, StoragePool};
-use crate::operator_lxd::check_error;
+use crate::metrics::BACKEND_ERRORS;
+use crate::model::{Arch, Node, Runtime, StoragePool};
+use crate::operator_k8s::NAMESPACE;
+use crate::operator_lxd::{api_url, check_error, check_server};
+use crate::shutdown;
 use crate::storage::Storage;
 
 pub struct Collector {
     storage: Storage,
     kube_client: Option<KubeClient>,
     lxd_client: Option<ReqwestClient>,
+    microvm_client: ReqwestClient,
+    oci_client: ReqwestClient,
 }
 
+// The storage pool of the containers of Podman, in the graph root of its storage.
+const OCI_STORAGE_POOL: &str = "podman";
+
 impl Collector {
     pub fn new(
         storage: Storage,
@@ -31,16 +42,103 @@ impl Collector {
             storage,
             kube_client,
             lxd_client,
+            microvm_client: ReqwestClient::new(),
+            oci_client: ReqwestClient::new(),
         }
     }
 
     pub async fn run(&self) {
-        loop {
-            self.run_once().await;
-            sleep(Duration::from_secs(60)).await;
+        let interval = Duration::from_secs(*COLLECTOR_INTERVAL);
+        let mut last_collected: Option<Instant> = None;
+        while !shutdown::is_triggered() {
+            if last_collected.map_or(true, |t| t.elapsed() >= interval) {
+                // A full collection refreshes the dirty nodes as well.
+                self.storage.take_dirty_nodes();
+                self.run_once().await;
+                last_collected = Some(Instant::now());
+            } else {
+                let dirty_nodes = self.storage.take_dirty_nodes();
+                if !dirty_nodes.is_empty() {
+                    self.refresh_nodes(&dirty_nodes).await;
+                }
+            }
+            shutdown::sleep(Duration::from_secs(1)).await;
         }
     }
 
+    /// Refreshes the storage usage of the given nodes right after instances are created on or
+    /// deleted from them, so the scheduler doesn't wait a full interval for accurate numbers.
+    ///
+    /// CPU and memory are accounted from the state, only the storage pools reported by LXD and
+    /// the volumes of the pods reported by Kubernetes are refreshed.
+    async fn refresh_nodes(&self, node_names: &[String]) {
+        let snapshot = self.storage.snapshot().await;
+        let mut usages = Vec::new();
+        let mut disk_used = HashMap::new();
+        for node_name in node_names {
+            let node = match snapshot.find_node(node_name) {
+                Some(node) if node.is_healthy() => node,
+                _ => continue,
+            };
+            if let (Some(kube_client), true) =
+                (&self.kube_client, node.runtimes.contains(&Runtime::Runc))
+            {
+                match get_kube_node_pvc_usage(kube_client, node_name).await {
+                    Ok(usage) => disk_used.extend(usage),
+                    Err(e) => warn!("failed to refresh pvc usage on node {}: {}", node_name, e),
+                }
+            }
+            let lxd_client = match &self.lxd_client {
+                Some(lxd_client) => lxd_client,
+                None => continue,
+            };
+            for pool in node
+                .storage_pools
+                .iter()
+                .filter(|p| p.name != OCI_STORAGE_POOL)
+            {
+                match get_lxd_storage_pool_usage(lxd_client, node_name, &pool.name).await {
+                    Ok((_, used)) => usages.push((node_name.clone(), pool.name.clone(), used)),
+                    Err(e) => warn!(
+                        "failed to refresh storage pool {} on node {}: {}",
+                        pool.name, node_name, e
+                    ),
+                }
+            }
+        }
+        if usages.is_empty() && disk_used.is_empty() {
+            return;
+        }
+
+        if let Err(e) = self
+            .storage
+            .read_write_deferred(|state| {
+                for (node_name, pool_name, used) in &usages {
+                    for node in state.nodes.iter_mut().filter(|n| &n.name == node_name) {
+                        for pool in node.storage_pools.iter_mut() {
+                            if &pool.name == pool_name {
+                                pool.used = *used;
+                            }
+                        }
+                        node.storage_used = node.storage_pools.iter().map(|p| p.used).sum();
+                    }
+                }
+                for u in &mut state.users {
+                    for i in &mut u.instances {
+                        if let Some(used) = disk_used.get(&i.backend_name(&u.username)) {
+                            i.disk_used = Some(*used);
+                        }
+                    }
+                }
+                true
+            })
+            .await
+        {
+            warn!("failed to read/write storage: {}", e);
+        }
+    }
+
+    #[instrument(skip_all)]
     async fn run_once(&self) {
         let mut nodes = Vec::new();
         if let Some(kube_client) = &self.kube_client {
@@ -48,19 +146,27 @@ impl Collector {
                 Ok(n) => nodes.extend(n),
                 Err(e) => {
                     warn!("failed to collect kube nodes: {}", e);
+                    BACKEND_ERRORS.with_label_values(&["k8s"]).inc();
                     return;
                 }
             }
         }
         if let Some(lxd_client) = &self.lxd_client {
-            match self.collect_lxd_nodes(lxd_client).await {
+            let res = match check_server(lxd_client).await {
+                Ok(()) => self.collect_lxd_nodes(lxd_client).await,
+                Err(e) => Err(e),
+            };
+            match res {
                 Ok(n) => nodes.extend(n),
                 Err(e) => {
                     warn!("failed to collect lxd nodes: {}", e);
+                    BACKEND_ERRORS.with_label_values(&["lxd"]).inc();
                     return;
                 }
             }
         }
+        nodes.extend(self.collect_microvm_nodes().await);
+        nodes.extend(self.collect_oci_nodes().await);
         nodes.sort_by(|a, b| a.name.cmp(&b.name));
 
         let mut merged_nodes = Vec::new();
@@ -72,7 +178,19 @@ impl Collector {
             let mut storage_pools: Vec<StoragePool> = Vec::new();
             let mut cpu_total = 0;
             let mut memory_total = 0;
+            let mut unhealthy_reasons: Vec<String> = Vec::new();
+            let mut arch = None;
+            let mut numa_cpus = Vec::new();
             while j < nodes.len() && nodes[i].name == nodes[j].name {
+                if let Some(reason) = &nodes[j].unhealthy_reason {
+                    unhealthy_reasons.push(reason.clone());
+                }
+                if arch.is_none() {
+                    arch = nodes[j].arch.clone();
+                }
+                if numa_cpus.is_empty() {
+                    numa_cpus = nodes[j].numa_cpus.clone();
+                }
                 for runtime in &nodes[j].runtimes {
                     if !runtimes.contains(runtime) {
                         runtimes.push(runtime.clone());
@@ -94,6 +212,11 @@ impl Collector {
                 j += 1;
             }
 
+            let unhealthy_reason = if unhealthy_reasons.is_empty() {
+                None
+            } else {
+                Some(unhealthy_reasons.join("; "))
+            };
             let storage_total = storage_pools.iter().map(|s| s.total).sum();
             let storage_used = storage_pools.iter().map(|s| s.used).sum();
 
@@ -108,20 +231,42 @@ impl Collector {
                 storage_total,
                 storage_used,
                 storage_allocated: 0,
+                arch,
+                unhealthy_reason,
+                numa_cpus,
+                pinned_cpus: Vec::new(),
+                schedulable: true,
+                labels: BTreeMap::new(),
+                notes: String::new(),
+                missing_since: None,
+                draining: false,
+                maintenance_windows: Vec::new(),
             });
             i = j;
         }
 
+        let disk_used = self.collect_disk_usage(&merged_nodes).await;
+
         if let Err(e) = self
             .storage
-            .read_write(|state| {
-                state.nodes = merged_nodes.clone();
+            .read_write_deferred(|state| {
+                state.merge_collected_nodes(&merged_nodes, Utc::now().timestamp());
+                for u in &mut state.users {
+                    for i in &mut u.instances {
+                        let name = i.backend_name(&u.username);
+                        if let Some(used) = disk_used.get(&name) {
+                            i.disk_used = Some(*used);
+                        }
+                    }
+                }
                 true
             })
             .await
         {
             warn!("failed to read/write storage: {}", e);
+            return;
         }
+        self.storage.mark_collected();
     }
 
     async fn collect_kube_nodes(&self, kube_client: &KubeClient) -> Result<Vec<Node>> {
@@ -148,6 +293,25 @@ impl Collector {
                         .map(|v| v.to_bytes().ok().flatten().unwrap_or_default() as usize >> 30)
                 })
                 .unwrap_or_default();
+            let arch = kube_node
+                .metadata
+                .labels
+                .as_ref()
+                .and_then(|l| l.get("kubernetes.io/arch"))
+                .and_then(|a| a.parse().ok());
+            let ready = kube_node
+                .status
+                .as_ref()
+                .and_then(|s| s.conditions.as_ref())
+                .and_then(|c| c.iter().find(|c| c.type_ == "Ready"));
+            let unhealthy_reason = match ready {
+                Some(c) if c.status == "True" => None,
+                Some(c) => Some(format!(
+                    "kubelet is not ready: {}",
+                    c.message.clone().unwrap_or_else(|| c.status.clone())
+                )),
+                None => Some("kubelet has not reported readiness".to_owned()),
+            };
             nodes.push(Node {
                 name: name.clone(),
                 storage_pools: Vec::new(),
@@ -159,43 +323,85 @@ impl Collector {
                 storage_total: 0,
                 storage_used: 0,
                 storage_allocated: 0,
+                arch,
+                unhealthy_reason,
+                numa_cpus: Vec::new(),
+                pinned_cpus: Vec::new(),
+                schedulable: true,
+                labels: BTreeMap::new(),
+                notes: String::new(),
+                missing_since: None,
+                draining: false,
+                maintenance_windows: Vec::new(),
             });
         }
         Ok(nodes)
     }
 
     async fn collect_lxd_nodes(&self, lxd_client: &ReqwestClient) -> Result<Vec<Node>> {
-        let node_names = list_lxd_nodes(lxd_client).await?;
-        let mut pool_names = Vec::new();
+        let members = list_lxd_nodes(lxd_client).await?;
+        let mut pools = Vec::new();
         for pool_name in list_lxd_storage_pools(lxd_client).await? {
             let driver = get_lxd_storage_pool_driver(lxd_client, &pool_name).await?;
-            if driver == LXD_STORAGE_POOL_DRIVER.as_str() {
-                pool_names.push(pool_name);
+            if LXD_STORAGE_POOL_DRIVERS.contains(&driver) {
+                pools.push((pool_name, is_shared_storage_driver(&driver)));
             }
         }
+        // Shared pools report the capacity of the whole cluster from every member, so query them
+        // only once instead of once per member.
+        let mut shared_pool_usage: HashMap<String, (usize, usize)> = HashMap::new();
         let mut nodes = Vec::new();
-        for node_name in &node_names {
-            let (cpu_total, memory_total) = get_lxd_node_capacity(lxd_client, node_name).await?;
+        for (node_name, arch, unhealthy_reason) in members {
             let mut node = Node {
                 name: node_name.clone(),
                 storage_pools: Vec::new(),
                 runtimes: vec![Runtime::Lxc, Runtime::Kvm],
-                cpu_total,
+                cpu_total: 0,
                 cpu_allocated: 0,
-                memory_total,
+                memory_total: 0,
                 memory_allocated: 0,
                 storage_total: 0,
                 storage_used: 0,
                 storage_allocated: 0,
+                arch,
+                unhealthy_reason,
+                numa_cpus: Vec::new(),
+                pinned_cpus: Vec::new(),
+                schedulable: true,
+                labels: BTreeMap::new(),
+                notes: String::new(),
+                missing_since: None,
+                draining: false,
+                maintenance_windows: Vec::new(),
             };
-            for pool_name in &pool_names {
-                let (total, used) =
-                    get_lxd_storage_pool_usage(lxd_client, node_name, pool_name).await?;
+            // The resources of an unreachable member can't be queried, report it without capacity.
+            if !node.is_healthy() {
+                nodes.push(node);
+                continue;
+            }
+            let (cpu_total, memory_total, numa_cpus) =
+                get_lxd_node_capacity(lxd_client, &node_name).await?;
+            node.cpu_total = cpu_total;
+            node.memory_total = memory_total;
+            node.numa_cpus = numa_cpus;
+            for (pool_name, shared) in &pools {
+                let (total, used) = match shared_pool_usage.get(pool_name) {
+                    Some(usage) => *usage,
+                    None => {
+                        let usage =
+                            get_lxd_storage_pool_usage(lxd_client, &node_name, pool_name).await?;
+                        if *shared {
+                            shared_pool_usage.insert(pool_name.clone(), usage);
+                        }
+                        usage
+                    }
+                };
                 let storage_pool = StoragePool {
                     name: pool_name.clone(),
                     total,
                     used,
                     allocated: 0,
+                    shared: *shared,
                 };
                 node.storage_pools.push(storage_pool);
             }
@@ -203,53 +409,319 @@ impl Collector {
         }
         Ok(nodes)
     }
+
+    /// Returns the root disk usage in GiB of each instance, keyed by `{username}-{instance}`.
+    ///
+    /// Failures are only logged since the usage is informational and shouldn't block the
+    /// collection of nodes.
+    async fn collect_disk_usage(&self, nodes: &[Node]) -> HashMap<String, usize> {
+        let mut disk_used = HashMap::new();
+        if let Some(kube_client) = &self.kube_client {
+            for node in nodes {
+                if !node.runtimes.contains(&Runtime::Runc) || !node.is_healthy() {
+                    continue;
+                }
+                match get_kube_node_pvc_usage(kube_client, &node.name).await {
+                    Ok(usage) => disk_used.extend(usage),
+                    Err(e) => warn!("failed to collect pvc usage on node {}: {}", node.name, e),
+                }
+            }
+        }
+        if let Some(lxd_client) = &self.lxd_client {
+            match get_lxd_instance_disk_usage(lxd_client).await {
+                Ok(usage) => disk_used.extend(usage),
+                Err(e) => warn!("failed to collect lxd instance disk usage: {}", e),
+            }
+        }
+        disk_used
+    }
+
+    /// Returns the nodes designated to run micro-VMs whose agents are reachable.
+    ///
+    /// The micro-VM agent doesn't report node capacity, so the returned nodes only carry the
+    /// runtime and are expected to be merged with the same nodes collected from Kubernetes or LXD.
+    async fn collect_microvm_nodes(&self) -> Vec<Node> {
+        let mut nodes = Vec::new();
+        for (node_name, agent) in MICROVM_AGENTS.iter() {
+            let url = format!("{}/api/v1/vmm.ping", agent);
+            match self
+                .microvm_client
+                .get(url)
+                .send()
+                .await
+                .and_then(|r| r.error_for_status())
+            {
+                Ok(_) => nodes.push(Node {
+                    name: node_name.clone(),
+                    storage_pools: Vec::new(),
+                    runtimes: vec![Runtime::MicroVm],
+                    cpu_total: 0,
+                    cpu_allocated: 0,
+                    memory_total: 0,
+                    memory_allocated: 0,
+                    storage_total: 0,
+                    storage_used: 0,
+                    storage_allocated: 0,
+                    arch: None,
+                    unhealthy_reason: None,
+                    numa_cpus: Vec::new(),
+                    pinned_cpus: Vec::new(),
+                    schedulable: true,
+                    labels: BTreeMap::new(),
+                    notes: String::new(),
+                    missing_since: None,
+                    draining: false,
+                    maintenance_windows: Vec::new(),
+                }),
+                Err(e) => warn!("failed to ping micro-VM agent on node {}: {}", node_name, e),
+            }
+        }
+        nodes
+    }
+
+    /// Returns the nodes designated to run Podman containers whose APIs are reachable, with the
+    /// capacity and the storage Podman reports.
+    async fn collect_oci_nodes(&self) -> Vec<Node> {
+        let mut nodes = Vec::new();
+        for (node_name, agent) in OCI_AGENTS.iter() {
+            let url = format!("{}/v4.0.0/libpod/info", agent);
+            let res = match self.oci_client.get(url).send().await {
+                Ok(res) => res.error_for_status(),
+                Err(e) => Err(e),
+            };
+            let info: serde_json::Value = match res {
+                Ok(res) => match res.json().await {
+                    Ok(info) => info,
+                    Err(e) => {
+                        warn!("failed to read podman info of node {}: {}", node_name, e);
+                        continue;
+                    }
+                },
+                Err(e) => {
+                    warn!("failed to get podman info of node {}: {}", node_name, e);
+                    continue;
+                }
+            };
+            let get = |pointer| {
+                info.pointer(pointer)
+                    .and_then(|v| v.as_u64())
+                    .unwrap_or_default() as usize
+            };
+            let total = get("/store/graphRootAllocated") >> 30;
+            let used = get("/store/graphRootUsed") >> 30;
+            nodes.push(Node {
+                name: node_name.clone(),
+                storage_pools: vec![StoragePool {
+                    name: OCI_STORAGE_POOL.to_owned(),
+                    total,
+                    used,
+                    allocated: 0,
+                    shared: false,
+                }],
+                runtimes: vec![Runtime::Oci],
+                cpu_total: get("/host/cpus"),
+                cpu_allocated: 0,
+                memory_total: get("/host/memTotal") >> 30,
+                memory_allocated: 0,
+                storage_total: total,
+                storage_used: used,
+                storage_allocated: 0,
+                arch: info
+                    .pointer("/host/arch")
+                    .and_then(|a| a.as_str())
+                    .and_then(|a| a.parse().ok()),
+                unhealthy_reason: None,
+                numa_cpus: Vec::new(),
+                pinned_cpus: Vec::new(),
+                schedulable: true,
+                labels: BTreeMap::new(),
+                notes: String::new(),
+                missing_since: None,
+                draining: false,
+                maintenance_windows: Vec::new(),
+            });
+        }
+        nodes
+    }
+}
+
+async fn get_kube_node_pvc_usage(
+    kube_client: &KubeClient,
+    node_name: &str,
+) -> Result<HashMap<String, usize>> {
+    let req = http::Request::get(format!("/api/v1/nodes/{}/proxy/stats/summary", node_name))
+        .body(Vec::new())?;
+    let res: serde_json::Value = kube_client.request(req).await?;
+    // The response is like:
+    // {
+    //   "node": { ... },
+    //   "pods": [
+    //     {
+    //       "podRef": { "name": "alice-dev", "namespace": "tispace", ... },
+    //       "volume": [
+    //         {
+    //           "name": "rootfs",
+    //           "usedBytes": 5368709120,
+    //           "capacityBytes": 107374182400,
+    //           "pvcRef": { "name": "alice-dev-rootfs", "namespace": "tispace" },
+    //           ...
+    //         }
+    //       ],
+    //       ...
+    //     }
+    //   ]
+    // }
+    let mut usage = HashMap::new();
+    let volumes = res
+        .get("pods")
+        .and_then(|p| p.as_array())
+        .into_iter()
+        .flatten()
+        .filter_map(|p| p.get("volume").and_then(|v| v.as_array()))
+        .flatten();
+    for volume in volumes {
+        let pvc_ref = match volume.get("pvcRef") {
+            Some(pvc_ref) => pvc_ref,
+            None => continue,
+        };
+        if pvc_ref.get("namespace").and_then(|n| n.as_str()) != Some(NAMESPACE) {
+            continue;
+        }
+        let name = pvc_ref
+            .get("name")
+            .and_then(|n| n.as_str())
+            .and_then(|n| n.strip_suffix("-rootfs"));
+        let used = volume.get("usedBytes").and_then(|u| u.as_u64());
+        if let (Some(name), Some(used)) = (name, used) {
+            usage.insert(name.to_owned(), (used >> 30) as usize);
+        }
+    }
+    Ok(usage)
+}
+
+async fn get_lxd_instance_disk_usage(lxd_client: &ReqwestClient) -> Result<HashMap<String, usize>> {
+    let url = api_url(&format!(
+        "/instances?project={}&recursion=2",
+        LXD_PROJECT.as_str()
+    ));
+    let res: serde_json::Value = lxd_client.get(url).send().await?.json().await?;
+    check_error(&res)?;
+    // The response is like:
+    // {
+    //   "metadata": [
+    //     {
+    //       "name": "alice-dev",
+    //       "state": {
+    //         "disk": {
+    //           "root": {
+    //             "usage": 5368709120
+    //           }
+    //         },
+    //         ...
+    //       },
+    //       ...
+    //     }
+    //   ],
+    //   "status": "Success",
+    //   "status_code": 200,
+    //   "type": "sync"
+    // }
+    let mut usage = HashMap::new();
+    for instance in res
+        .get("metadata")
+        .ok_or_else(|| anyhow!("no metadata"))?
+        .as_array()
+        .ok_or_else(|| anyhow!("no metadata array"))?
+    {
+        let name = instance.get("name").and_then(|n| n.as_str());
+        // Some storage drivers, such as lvm, don't report the usage of stopped instances.
+        let used = instance
+            .pointer("/state/disk/root/usage")
+            .and_then(|u| u.as_u64());
+        if let (Some(name), Some(used)) = (name, used) {
+            usage.insert(name.to_owned(), (used >> 30) as usize);
+        }
+    }
+    Ok(usage)
+}
+
+/// Returns whether pools of the driver are backed by remote storage shared by all members.
+fn is_shared_storage_driver(driver: &str) -> bool {
+    matches!(driver, "ceph" | "cephfs" | "cephobject")
 }
 
 fn overcommit_cpu(cpu: usize) -> usize {
-    (cpu as f64 * CPU_OVERCOMMIT_FACTOR.to_owned()) as usize
+    (cpu as f64 * config::current().cpu_overcommit_factor) as usize
 }
 
 fn overcommit_memory(memory: usize) -> usize {
-    (memory as f64 * MEMORY_OVERCOMMIT_FACTOR.to_owned()) as usize
+    (memory as f64 * config::current().memory_overcommit_factor) as usize
 }
 
-async fn list_lxd_nodes(lxd_client: &ReqwestClient) -> Result<Vec<String>> {
-    let url = format!("{}/1.0/cluster/members", LXD_SERVER_URL.as_str());
+/// Returns the name and architecture of each cluster member together with the reason why it is
+/// unhealthy, if any.
+async fn list_lxd_nodes(
+    lxd_client: &ReqwestClient,
+) -> Result<Vec<(String, Option<Arch>, Option<String>)>> {
+    let url = api_url("/cluster/members?recursion=1");
     let res: serde_json::Value = lxd_client.get(url).send().await?.json().await?;
     check_error(&res)?;
     // The response is like:
     // {
     //   "metadata": [
-    //     "/1.0/cluster/members/lxd01",
-    //     "/1.0/cluster/members/lxd02"
+    //     {
+    //       "server_name": "lxd01",
+    //       "architecture": "x86_64",
+    //       "status": "Online",
+    //       "message": "Fully operational",
+    //       ...
+    //     },
+    //     {
+    //       "server_name": "lxd02",
+    //       "architecture": "aarch64",
+    //       "status": "Offline",
+    //       "message": "No heartbeat for 1m0s",
+    //       ...
+    //     }
     //   ],
     //   "status": "Success",
     //   "status_code": 200,
     //   "type": "sync"
     // }
-    let nodes: Vec<String> = res
+    let nodes = res
         .get("metadata")
         .ok_or_else(|| anyhow!("no metadata"))?
         .as_array()
         .ok_or_else(|| anyhow!("no metadata array"))?
         .iter()
         .map(|n| {
-            n.as_str()
+            let name = n
+                .get("server_name")
+                .and_then(|v| v.as_str())
                 .unwrap()
-                .strip_prefix("/1.0/cluster/members/")
-                .unwrap()
-                .to_owned()
+                .to_owned();
+            let status = n.get("status").and_then(|v| v.as_str()).unwrap_or_default();
+            let unhealthy_reason = if status == "Online" {
+                None
+            } else {
+                let message = n
+                    .get("message")
+                    .and_then(|v| v.as_str())
+                    .unwrap_or_default();
+                Some(format!("LXD member is {}: {}", status, message))
+            };
+            let arch = n
+                .get("architecture")
+                .and_then(|v| v.as_str())
+                .and_then(|a| a.parse().ok());
+            (name, arch, unhealthy_reason)
         })
         .collect();
     Ok(nodes)
 }
 
 async fn list_lxd_storage_pools(lxd_client: &ReqwestClient) -> Result<Vec<String>> {
-    let url = format!(
-        "{}/1.0/storage-pools?project={}",
-        LXD_SERVER_URL.as_str(),
-        LXD_PROJECT.as_str()
-    );
+    let url = api_url(&format!("/storage-pools?project={}", LXD_PROJECT.as_str()));
     let res: serde_json::Value = lxd_client.get(url).send().await?.json().await?;
     check_error(&res)?;
     // The response is like:
@@ -283,11 +755,7 @@ async fn get_lxd_storage_pool_driver(
     lxd_client: &ReqwestClient,
     pool_name: &str,
 ) -> Result<String> {
-    let url = format!(
-        "{}/1.0/storage-pools/{}",
-        LXD_SERVER_URL.as_str(),
-        pool_name
-    );
+    let url = api_url(&format!("/storage-pools/{}", pool_name));
     let res: serde_json::Value = lxd_client.get(url).send().await?.json().await?;
     check_error(&res)?;
     // The response is like:
@@ -331,12 +799,10 @@ async fn get_lxd_storage_pool_usage(
     node_name: &str,
     pool_name: &str,
 ) -> Result<(usize, usize)> {
-    let url = format!(
-        "{}/1.0/storage-pools/{}/resources?target={}",
-        LXD_SERVER_URL.as_str(),
-        pool_name,
-        node_name
-    );
+    let url = api_url(&format!(
+        "/storage-pools/{}/resources?target={}",
+        pool_name, node_name
+    ));
     let res: serde_json::Value = lxd_client.get(url).send().await?.json().await?;
     check_error(&res)?;
     // The response is like:
@@ -369,12 +835,8 @@ async fn get_lxd_storage_pool_usage(
 async fn get_lxd_node_capacity(
     lxd_client: &ReqwestClient,
     node_name: &str,
-) -> Result<(usize, usize)> {
-    let url = format!(
-        "{}/1.0/resources?target={}",
-        LXD_SERVER_URL.as_str(),
-        node_name
-    );
+) -> Result<(usize, usize, Vec<Vec<usize>>)> {
+    let url = api_url(&format!("/resources?target={}", node_name));
     let res: serde_json::Value = lxd_client.get(url).send().await?.json().await?;
     check_error(&res)?;
     // The response is like:
@@ -416,5 +878,27 @@ async fn get_lxd_node_capacity(
         .get("total")
         .map_or(0, |v| v.as_u64().unwrap())
         >> 30;
-    Ok((cpu_total as usize, memory_total as usize))
+    // Each thread of the cores of the sockets is like {"id": 3, "numa_node": 0, ...}.
+    let mut numa_cpus: Vec<Vec<usize>> = Vec::new();
+    let threads = res
+        .pointer("/metadata/cpu/sockets")
+        .and_then(|s| s.as_array())
+        .into_iter()
+        .flatten()
+        .filter_map(|s| s.get("cores").and_then(|c| c.as_array()))
+        .flatten()
+        .filter_map(|c| c.get("threads").and_then(|t| t.as_array()))
+        .flatten();
+    for t in threads {
+        let id = match t.get("id").and_then(|v| v.as_u64()) {
+            Some(id) => id as usize,
+            None => continue,
+        };
+        let numa_node = t.get("numa_node").and_then(|v| v.as_u64()).unwrap_or(0) as usize;
+        if numa_cpus.len() <= numa_node {
+            numa_cpus.resize(numa_node + 1, Vec::new());
+        }
+        numa_cpus[numa_node].push(id);
+    }
+    Ok((cpu_total as usize, memory_total as usize, numa_cpus))
 }
@@ -0,0 +1,1357 @@
+//! Settings of the server.
+//!
+//! Settings are loaded from an optional YAML file given by `--config` and then overridden by the
+//! environment variables of the same name in upper case, e.g. `lxd_server_url` in the file is
+//! overridden by `LXD_SERVER_URL`. Everything is parsed and validated once at startup, so that
+//! misconfiguration is reported before any component starts.
+//!
+//! The settings read through [`current`] can be reloaded without restarting the server, see
+//! [`reload`]. Changes of the other settings take effect after a restart.
+
+use std::collections::{HashMap, HashSet};
+use std::net::{IpAddr, Ipv4Addr};
+use std::path::Path;
+use std::str::FromStr;
+use std::sync::{Arc, Mutex, RwLock};
+use std::time::Duration;
+
+use anyhow::{anyhow, Context, Result};
+use once_cell::sync::{Lazy, OnceCell};
+use rand::{thread_rng, Rng};
+use ring::constant_time;
+use serde::Deserialize;
+
+use crate::operator_lxd::ApiFlavor;
+
+static PATH: OnceCell<Option<String>> = OnceCell::new();
+
+// The settings loaded at startup.
+static CONFIG: OnceCell<Config> = OnceCell::new();
+
+// The settings loaded by the last reload.
+static CURRENT: Lazy<RwLock<Arc<Config>>> = Lazy::new(|| RwLock::new(Arc::new(get().clone())));
+
+// The addresses of the IP pools, keyed by their ranges and exclusions, as the pools are expanded
+// on every allocation.
+static IP_POOL_ADDRESSES: Lazy<Mutex<HashMap<(Vec<String>, Vec<String>), Vec<String>>>> =
+    Lazy::new(Default::default);
+
+// The most addresses a single entry of an IP pool may span, that of a /16 block.
+const MAX_IP_BLOCK_SIZE: u32 = 1 << 16;
+
+#[derive(Debug, Clone, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct Config {
+    pub google_client_id: String,
+    pub storage_class_name: String,
+    pub default_rootfs_image_tag: String,
+    // The gRPC endpoint traces are exported to, e.g. http://otel-collector:4317. Traces are not
+    // exported if it's empty.
+    pub otel_exporter_otlp_endpoint: String,
+    // PEM encoded certificate chain and private key of the API server. The API is served over
+    // plain HTTP if they are empty.
+    pub tls_cert: String,
+    pub tls_key: String,
+    // Port the metadata document is served to the guests on over plain HTTP, disabled if 0. Guests
+    // are identified by their source address, so the port must be reachable from the instances
+    // without NAT, e.g. through the subdomain service from pods or routed from LXD instances.
+    pub metadata_port: u16,
+    // URL the guests reach the metadata endpoint at, e.g. http://10.0.0.1:8081. Once created,
+    // guests provisioned by cloud-init post their SSH host keys to it, which are published to
+    // users. Host keys are not collected if empty.
+    pub metadata_url: String,
+    // Directory of the built web UI, e.g. the output of `next export`, served on the paths not
+    // taken by the API so that the web UI needs neither a web server of its own nor CORS. Pages
+    // without a file of their own get index.html. The web UI is not served if it's empty.
+    pub web_ui_dir: String,
+    // Whether replicas elect a leader through a Kubernetes Lease, see `crate::leader`.
+    pub leader_election: bool,
+    // The identity of this replica in the election, defaults to the hostname.
+    pub leader_election_identity: String,
+
+    pub lxd_project: String,
+    pub lxd_client_cert: String,
+    // Addresses of the members of the LXD cluster separated by commas, e.g.
+    // `https://10.0.0.1:8443,https://10.0.0.2:8443`. Requests go to the first healthy member.
+    pub lxd_server_url: String,
+    // The REST API dialect of lxd_server_url, either "lxd" or "incus".
+    pub lxd_api_flavor: String,
+    pub lxd_image_server_url: String,
+    // Storage drivers whose pools are used for instances.
+    pub lxd_storage_driver: Vec<String>,
+    // Kubernetes cluster and LXD cluster may share the same storage pool but with different
+    // names. This is a map from openebs volume name to LXD storage pool name.
+    pub lxd_storage_pool_mapping: HashMap<String, String>,
+    // Where the LXD nodes mount their storage pools, through which the root disk of the previous
+    // backend of a converted instance is attached to the rebuilt instance.
+    pub lxd_storage_pools_dir: String,
+
+    // IP addresses for instances exposed outside of the cluster, each entry being an inclusive
+    // start-end range, e.g. 192.168.100.1-192.168.100.254, a CIDR block, e.g. 192.168.100.0/24,
+    // whose network and broadcast addresses are left out, or a single address. The IP addresses
+    // must be in the same subnet with the prefix length external_ip_prefix_length.
+    pub external_ip_pool: Vec<String>,
+    // Addresses of the pool never allocated to instances, e.g. gateways, in the same notations.
+    pub external_ip_exclude: Vec<String>,
+    pub external_ip_prefix_length: u8,
+    // Additional named pools for nodes spanning several L2 segments, only configurable in the
+    // config file. external_ip_pool forms the pool named `default` serving all nodes.
+    pub ip_pools: Vec<IpPool>,
+    // Minutes a released external IP is held back before it's allocated again, so that stale DNS
+    // caches and known_hosts entries don't point to another instance right away.
+    pub ip_release_grace_period: u64,
+    // Maximum number of external IPs a user can pin, counting the IPs still reserved for deleted
+    // instances, unlimited if 0.
+    pub max_pinned_ips_per_user: usize,
+    // Hours an IP pinned by a deleted instance stays reserved for the instance name, forever if 0.
+    pub pinned_ip_ttl: u64,
+
+    pub rate_limit_burst: usize,
+    pub rate_limit_per_second: f64,
+    // CIDR blocks like 10.0.0.0/8 or 2001:db8::/32, or single addresses, the management API
+    // accepts requests from. Everyone is allowed if empty.
+    pub api_allowlist: Vec<String>,
+    // Same as api_allowlist for /metrics, which is scraped from other networks than the API is
+    // used from.
+    pub metrics_allowlist: Vec<String>,
+    // Credentials /metrics requires, either a bearer token or `<username>:<password>` for basic
+    // auth. Either is accepted if both are set, and none is required if neither is.
+    pub metrics_bearer_token: String,
+    pub metrics_basic_auth: String,
+    // Hours ahead of a maintenance window of a node the owners of the instances on the node are
+    // warned of it.
+    pub maintenance_notice: u64,
+    // Milliseconds between two passes of the operators over the instances, and of the scheduler
+    // over the pending ones.
+    pub reconcile_interval: u64,
+    pub schedule_interval: u64,
+    // Percentage of the intervals above by which each wait is randomly lengthened or shortened,
+    // so that the components of several servers don't hit the backends at the same moments.
+    pub reconcile_jitter: u64,
+    // Seconds between two full collections of the nodes.
+    pub collector_interval: u64,
+    // Minutes between two passes of the garbage collector looking for backend resources without
+    // an instance, disabled if 0. The orphans are only reported unless orphan_gc_delete is set.
+    pub orphan_gc_interval: u64,
+    pub orphan_gc_delete: bool,
+    // Minutes between two passes pulling the images of the catalog onto the nodes ahead of the
+    // first instances created from them, disabled if 0. LXD images are only pulled into the
+    // image store of the cluster, set `cluster.images_minimal_replica` of LXD to -1 to have them
+    // copied to all members as well.
+    pub image_prepull_interval: u64,
+    // Milliseconds the status updates of the operators and the collector are held back to be
+    // written to the state file together, 0 to write each of them right away. Changes made
+    // through the API are always written right away.
+    pub state_write_delay: u64,
+    // Minutes between two checks of the backends of running instances against their specs,
+    // disabled if 0. Drifted settings are recorded as instance events, and the ones that can be
+    // changed in place are corrected if drift_correction is set.
+    pub drift_check_interval: u64,
+    pub drift_correction: bool,
+    // Minutes since the last successful collection after which the server is no longer ready.
+    pub readiness_collector_max_age: u64,
+    pub cpu_overcommit_factor: f64,
+    pub memory_overcommit_factor: f64,
+    // Percentages of the CPU and memory of each node held back from instances for the host, after
+    // overcommitting. node_reserve overrides them for single nodes, each entry being a
+    // node=cpu:memory triple, e.g. `node1=10:20`.
+    pub node_cpu_reserve: usize,
+    pub node_memory_reserve: usize,
+    pub node_reserve: Vec<String>,
+    // Percentage of each storage pool held back from instances, so that thin-provisioned pools
+    // never fill up and stall every instance on the node. storage_pool_reserve overrides it for
+    // single pools, each entry being a pool=percent pair, e.g. `lvm-thin=20`.
+    pub storage_reserve: usize,
+    pub storage_pool_reserve: Vec<String>,
+    // Ratios of the CPU and memory requests to the limits of the pods of a runtime, each one is a
+    // runtime=cpu:memory triple, e.g. `runc=0.5:0.8`. Requests equal limits for runtimes not
+    // listed, which gives their pods the Guaranteed QoS class and leaves no room to overcommit.
+    pub k8s_request_ratios: Vec<String>,
+    // Tolerations of the pods in the notation of taints, i.e. key[=value][:effect], e.g.
+    // `dedicated=tispace:NoSchedule`, to run them on dedicated, tainted nodes. A toleration
+    // without effect tolerates all effects of the key.
+    pub k8s_tolerations: Vec<String>,
+    // Labels the nodes pods are scheduled to must have, e.g. `pool=tispace`.
+    pub k8s_node_affinity: HashMap<String, String>,
+    // Quotas of users whose quotas are not set in the state.
+    pub default_cpu_quota: usize,
+    pub default_memory_quota: usize,
+    pub default_disk_quota: usize,
+    pub default_instance_quota: usize,
+    // Maximum CPU, memory (GiB) and disk size (GiB) of a single instance, unlimited if 0.
+    pub max_instance_cpu: usize,
+    pub max_instance_memory: usize,
+    pub max_instance_disk_size: usize,
+    // Maximum number of instances a user may create per hour and per day, unlimited if 0.
+    // Deleting instances doesn't make room for more creations.
+    pub max_creations_per_hour: usize,
+    pub max_creations_per_day: usize,
+    // Allowed range of the memory (GiB) per CPU of an instance, unbounded on the side set to 0.
+    pub min_memory_per_cpu: f64,
+    pub max_memory_per_cpu: f64,
+    // Maximum size (MiB) of a file pulled from or pushed to an instance through the files API.
+    pub max_file_transfer_size: usize,
+    // Whether deleting an instance requires `?confirm=<instance name>`, which guards against
+    // misclicks and copy-pasted commands deleting the wrong instance. Deleting a cluster likewise
+    // requires `?confirm=<cluster name>`, and batch deletes each name in `confirm`.
+    pub delete_confirmation: bool,
+    // Hours deleted instances are kept in the recycle bin, stopped along with their disks, before
+    // they are deleted for good. Instances are deleted right away if 0.
+    pub recycle_bin_retention: u64,
+    // Days the usage of the users is kept for the usage reports, forever if 0.
+    pub usage_retention: u64,
+    // Whether root passwords are hashed once instances are provisioned. The password is then
+    // only returned when the instance is created.
+    pub hash_passwords: bool,
+    // Base64 encoded 256-bit key the state file is encrypted with using AES-256-GCM, e.g. from
+    // `openssl rand -base64 32`. The state is written in plaintext if empty. A plaintext state
+    // file is still read, and encrypted on the next write.
+    pub state_encryption_key: String,
+    // Usernames allowed to call the admin API.
+    pub admins: Vec<String>,
+    // Prefixes the sources of images registered by users must start with, e.g. `ghcr.io/acme/`
+    // for OCI images or `acme/` for LXD image aliases. Users cannot register images if empty.
+    pub custom_image_allowlist: Vec<String>,
+    // Where the home volumes users opt in to are mounted in every instance of theirs, `{username}`
+    // being replaced with the username. They are ReadWriteMany PVCs of the storage class
+    // home_volume_storage_class on Kubernetes, e.g. backed by NFS, and custom volumes of the
+    // storage pool home_volume_lxd_pool on LXD, which must be shared by the nodes, e.g. CephFS.
+    // Instances of a backend whose setting is empty get no home volume.
+    pub home_volume_path: String,
+    pub home_volume_storage_class: String,
+    pub home_volume_lxd_pool: String,
+    // Paths the sources of the shares mounted into instances must be or lie under, e.g.
+    // `/srv/datasets` for paths of the hosts or `nfs://nas.internal/datasets` for NFS exports.
+    // Admins can't declare shares in the image catalog if empty.
+    pub shared_mount_allowlist: Vec<String>,
+
+    // S3 compatible object storage (e.g. AWS S3 or MinIO) where instance backups are exported to
+    // and imported from. Exporting and importing are disabled if the endpoint or bucket is empty.
+    pub backup_s3_endpoint: String,
+    pub backup_s3_bucket: String,
+    pub backup_s3_region: String,
+    pub backup_s3_access_key: String,
+    pub backup_s3_secret_key: String,
+    // Other buckets of the backup storage which backup policies may store backups in.
+    pub backup_s3_extra_buckets: Vec<String>,
+
+    // SSH bastion instances without external IPs are reached through, as given to `ssh -J`,
+    // e.g. `jump@bastion.tispace.dev:2222`. It must reach the nodes and the instance network.
+    pub ssh_bastion: String,
+
+    // Zone the DNS records of the instances are published to, e.g. `tispace.dev`, through the
+    // PowerDNS API at powerdns_url, e.g. http://pdns:8081/api/v1/servers/localhost. Records are
+    // not published if it's empty.
+    pub dns_zone: String,
+    pub dns_ttl: u32,
+    pub powerdns_url: String,
+    pub powerdns_api_key: String,
+
+    // Map from nodes designated to run micro-VMs to the endpoints of the agents running on them.
+    // Only Cloud Hypervisor agents are supported, Firecracker has a different API.
+    pub microvm_agents: HashMap<String, String>,
+    // The default gateway of micro-VMs.
+    pub microvm_gateway: String,
+
+    // Map from nodes designated to run Podman containers to the endpoints of their Podman REST
+    // APIs, e.g. http://127.0.0.1:18080 forwarded over SSH to the socket of
+    // `podman system service`, see `operator_oci`.
+    pub oci_agents: HashMap<String, String>,
+    // The Podman network the containers are attached to with their external IPs, e.g. a macvlan
+    // network on the segment of the IP pools, which must exist on each node.
+    pub oci_network: String,
+
+    // The AWS region to burst instances to. The EC2 runtime is disabled if it's empty.
+    pub ec2_region: String,
+    // Defaults to the public endpoint of ec2_region.
+    pub ec2_endpoint: String,
+    pub ec2_access_key: String,
+    pub ec2_secret_key: String,
+    // Map from image to the AMI used to launch EC2 instances of that image. Only used to populate
+    // the image catalog when the state is created, the catalog is managed by admins afterwards.
+    pub ec2_images: HashMap<String, String>,
+    // The EC2 instance types instances can be mapped to, each one is a name=cpu:memory triple
+    // where memory is in GiB.
+    pub ec2_instance_types: Vec<String>,
+    pub ec2_subnet_id: String,
+    pub ec2_security_group_ids: Vec<String>,
+    pub ec2_key_name: String,
+    pub ec2_root_device_name: String,
+    // Whether to create LXC and KVM instances on EC2 when on-premise nodes are exhausted.
+    pub ec2_burst: bool,
+}
+
+impl Default for Config {
+    fn default() -> Self {
+        Config {
+            google_client_id: String::new(),
+            storage_class_name: "openebs-lvm".to_owned(),
+            default_rootfs_image_tag: "latest".to_owned(),
+            otel_exporter_otlp_endpoint: String::new(),
+            tls_cert: String::new(),
+            tls_key: String::new(),
+            metadata_port: 0,
+            metadata_url: String::new(),
+            web_ui_dir: String::new(),
+            leader_election: false,
+            leader_election_identity: std::env::var("HOSTNAME").unwrap_or_default(),
+            lxd_project: "tispace".to_owned(),
+            lxd_client_cert: String::new(),
+            lxd_server_url: String::new(),
+            lxd_api_flavor: "lxd".to_owned(),
+            lxd_image_server_url: "https://mirrors.tuna.tsinghua.edu.cn/lxc-images".to_owned(),
+            lxd_storage_driver: vec!["lvm".to_owned()],
+            lxd_storage_pool_mapping: HashMap::new(),
+            lxd_storage_pools_dir: "/var/snap/lxd/common/lxd/storage-pools".to_owned(),
+            external_ip_pool: Vec::new(),
+            external_ip_exclude: Vec::new(),
+            external_ip_prefix_length: 32,
+            ip_pools: Vec::new(),
+            ip_release_grace_period: 60,
+            max_pinned_ips_per_user: 4,
+            pinned_ip_ttl: 30 * 24,
+            rate_limit_burst: 60,
+            rate_limit_per_second: 10.0,
+            api_allowlist: Vec::new(),
+            metrics_allowlist: Vec::new(),
+            metrics_bearer_token: String::new(),
+            metrics_basic_auth: String::new(),
+            maintenance_notice: 24,
+            reconcile_interval: 3000,
+            schedule_interval: 3000,
+            reconcile_jitter: 20,
+            collector_interval: 60,
+            orphan_gc_interval: 30,
+            orphan_gc_delete: false,
+            image_prepull_interval: 0,
+            state_write_delay: 1000,
+            drift_check_interval: 10,
+            drift_correction: false,
+            readiness_collector_max_age: 5,
+            cpu_overcommit_factor: 1.0,
+            memory_overcommit_factor: 1.0,
+            node_cpu_reserve: 0,
+            node_memory_reserve: 0,
+            node_reserve: Vec::new(),
+            storage_reserve: 0,
+            storage_pool_reserve: Vec::new(),
+            k8s_request_ratios: Vec::new(),
+            k8s_tolerations: Vec::new(),
+            k8s_node_affinity: HashMap::new(),
+            default_cpu_quota: 8,
+            default_memory_quota: 16,
+            default_disk_quota: 100,
+            default_instance_quota: 2,
+            max_instance_cpu: 0,
+            max_instance_memory: 0,
+            max_instance_disk_size: 0,
+            max_creations_per_hour: 0,
+            max_creations_per_day: 0,
+            min_memory_per_cpu: 0.0,
+            max_memory_per_cpu: 0.0,
+            max_file_transfer_size: 16,
+            delete_confirmation: false,
+            recycle_bin_retention: 0,
+            usage_retention: 400,
+            hash_passwords: false,
+            state_encryption_key: String::new(),
+            admins: Vec::new(),
+            custom_image_allowlist: Vec::new(),
+            home_volume_path: "/home/{username}".to_owned(),
+            home_volume_storage_class: String::new(),
+            home_volume_lxd_pool: String::new(),
+            shared_mount_allowlist: Vec::new(),
+            backup_s3_endpoint: String::new(),
+            backup_s3_bucket: String::new(),
+            backup_s3_region: "us-east-1".to_owned(),
+            backup_s3_access_key: String::new(),
+            backup_s3_secret_key: String::new(),
+            backup_s3_extra_buckets: Vec::new(),
+            ssh_bastion: String::new(),
+            dns_zone: String::new(),
+            dns_ttl: 300,
+            powerdns_url: String::new(),
+            powerdns_api_key: String::new(),
+            microvm_agents: HashMap::new(),
+            microvm_gateway: String::new(),
+            oci_agents: HashMap::new(),
+            oci_network: "tispace".to_owned(),
+            ec2_region: String::new(),
+            ec2_endpoint: String::new(),
+            ec2_access_key: String::new(),
+            ec2_secret_key: String::new(),
+            ec2_images: HashMap::new(),
+            ec2_instance_types: [
+                "t3.small=2:2",
+                "t3.medium=2:4",
+                "t3.large=2:8",
+                "t3.xlarge=4:16",
+                "t3.2xlarge=8:32",
+                "m5.4xlarge=16:64",
+                "m5.8xlarge=32:128",
+                "m5.12xlarge=48:192",
+                "m5.16xlarge=64:256",
+                "m5.24xlarge=96:384",
+            ]
+            .iter()
+            .map(|s| s.to_string())
+            .collect(),
+            ec2_subnet_id: String::new(),
+            ec2_security_group_ids: Vec::new(),
+            ec2_key_name: String::new(),
+            ec2_root_device_name: "/dev/sda1".to_owned(),
+            ec2_burst: false,
+        }
+    }
+}
+
+impl Config {
+    /// Loads the settings from the file if any, applies the environment variable overrides and
+    /// validates the result.
+    pub fn load(path: Option<&str>) -> Result<Self> {
+        let mut config = match path {
+            Some(path) => {
+                let contents = std::fs::read(path)
+                    .with_context(|| format!("failed to read config file {}", path))?;
+                serde_yaml::from_slice(&contents)
+                    .with_context(|| format!("failed to parse config file {}", path))?
+            }
+            None => Config::default(),
+        };
+        config.apply_env()?;
+        config.validate()?;
+        Ok(config)
+    }
+
+    fn apply_env(&mut self) -> Result<()> {
+        env_string("GOOGLE_CLIENT_ID", &mut self.google_client_id);
+        env_string("STORAGE_CLASS_NAME", &mut self.storage_class_name);
+        env_string(
+            "DEFAULT_ROOTFS_IMAGE_TAG",
+            &mut self.default_rootfs_image_tag,
+        );
+        env_string(
+            "OTEL_EXPORTER_OTLP_ENDPOINT",
+            &mut self.otel_exporter_otlp_endpoint,
+        );
+        env_string("TLS_CERT", &mut self.tls_cert);
+        env_string("TLS_KEY", &mut self.tls_key);
+        env_parse("METADATA_PORT", &mut self.metadata_port)?;
+        env_string("METADATA_URL", &mut self.metadata_url);
+        env_string("WEB_UI_DIR", &mut self.web_ui_dir);
+        env_parse("LEADER_ELECTION", &mut self.leader_election)?;
+        env_string(
+            "LEADER_ELECTION_IDENTITY",
+            &mut self.leader_election_identity,
+        );
+        env_string("LXD_PROJECT", &mut self.lxd_project);
+        env_string("LXD_CLIENT_CERT", &mut self.lxd_client_cert);
+        env_string("LXD_SERVER_URL", &mut self.lxd_server_url);
+        env_string("LXD_API_FLAVOR", &mut self.lxd_api_flavor);
+        env_string("LXD_IMAGE_SERVER_URL", &mut self.lxd_image_server_url);
+        env_list("LXD_STORAGE_DRIVER", &mut self.lxd_storage_driver);
+        env_map(
+            "LXD_STORAGE_POOL_MAPPING",
+            &mut self.lxd_storage_pool_mapping,
+        )?;
+        env_string("LXD_STORAGE_POOLS_DIR", &mut self.lxd_storage_pools_dir);
+        env_list("EXTERNAL_IP_POOL", &mut self.external_ip_pool);
+        env_list("EXTERNAL_IP_EXCLUDE", &mut self.external_ip_exclude);
+        env_parse("IP_RELEASE_GRACE_PERIOD", &mut self.ip_release_grace_period)?;
+        env_parse("MAX_PINNED_IPS_PER_USER", &mut self.max_pinned_ips_per_user)?;
+        env_parse("PINNED_IP_TTL", &mut self.pinned_ip_ttl)?;
+        env_parse(
+            "EXTERNAL_IP_PREFIX_LENGTH",
+            &mut self.external_ip_prefix_length,
+        )?;
+        env_parse("RATE_LIMIT_BURST", &mut self.rate_limit_burst)?;
+        env_parse("RATE_LIMIT_PER_SECOND", &mut self.rate_limit_per_second)?;
+        env_list("API_ALLOWLIST", &mut self.api_allowlist);
+        env_list("METRICS_ALLOWLIST", &mut self.metrics_allowlist);
+        env_string("METRICS_BEARER_TOKEN", &mut self.metrics_bearer_token);
+        env_string("METRICS_BASIC_AUTH", &mut self.metrics_basic_auth);
+        env_parse("MAINTENANCE_NOTICE", &mut self.maintenance_notice)?;
+        env_parse("RECONCILE_INTERVAL", &mut self.reconcile_interval)?;
+        env_parse("SCHEDULE_INTERVAL", &mut self.schedule_interval)?;
+        env_parse("RECONCILE_JITTER", &mut self.reconcile_jitter)?;
+        env_parse("COLLECTOR_INTERVAL", &mut self.collector_interval)?;
+        env_parse("ORPHAN_GC_INTERVAL", &mut self.orphan_gc_interval)?;
+        env_parse("ORPHAN_GC_DELETE", &mut self.orphan_gc_delete)?;
+        env_parse("IMAGE_PREPULL_INTERVAL", &mut self.image_prepull_interval)?;
+        env_parse("STATE_WRITE_DELAY", &mut self.state_write_delay)?;
+        env_parse("DRIFT_CHECK_INTERVAL", &mut self.drift_check_interval)?;
+        env_parse("DRIFT_CORRECTION", &mut self.drift_correction)?;
+        env_parse(
+            "READINESS_COLLECTOR_MAX_AGE",
+            &mut self.readiness_collector_max_age,
+        )?;
+        env_parse("CPU_OVERCOMMIT_FACTOR", &mut self.cpu_overcommit_factor)?;
+        env_parse(
+            "MEMORY_OVERCOMMIT_FACTOR",
+            &mut self.memory_overcommit_factor,
+        )?;
+        env_parse("NODE_CPU_RESERVE", &mut self.node_cpu_reserve)?;
+        env_parse("NODE_MEMORY_RESERVE", &mut self.node_memory_reserve)?;
+        env_list("NODE_RESERVE", &mut self.node_reserve);
+        env_parse("STORAGE_RESERVE", &mut self.storage_reserve)?;
+        env_list("STORAGE_POOL_RESERVE", &mut self.storage_pool_reserve);
+        env_list("K8S_REQUEST_RATIOS", &mut self.k8s_request_ratios);
+        env_list("K8S_TOLERATIONS", &mut self.k8s_tolerations);
+        env_map("K8S_NODE_AFFINITY", &mut self.k8s_node_affinity)?;
+        env_parse("DEFAULT_CPU_QUOTA", &mut self.default_cpu_quota)?;
+        env_parse("DEFAULT_MEMORY_QUOTA", &mut self.default_memory_quota)?;
+        env_parse("DEFAULT_DISK_QUOTA", &mut self.default_disk_quota)?;
+        env_parse("DEFAULT_INSTANCE_QUOTA", &mut self.default_instance_quota)?;
+        env_parse("MAX_INSTANCE_CPU", &mut self.max_instance_cpu)?;
+        env_parse("MAX_INSTANCE_MEMORY", &mut self.max_instance_memory)?;
+        env_parse("MAX_INSTANCE_DISK_SIZE", &mut self.max_instance_disk_size)?;
+        env_parse("MAX_CREATIONS_PER_HOUR", &mut self.max_creations_per_hour)?;
+        env_parse("MAX_CREATIONS_PER_DAY", &mut self.max_creations_per_day)?;
+        env_parse("MIN_MEMORY_PER_CPU", &mut self.min_memory_per_cpu)?;
+        env_parse("MAX_MEMORY_PER_CPU", &mut self.max_memory_per_cpu)?;
+        env_parse("MAX_FILE_TRANSFER_SIZE", &mut self.max_file_transfer_size)?;
+        env_parse("DELETE_CONFIRMATION", &mut self.delete_confirmation)?;
+        env_parse("RECYCLE_BIN_RETENTION", &mut self.recycle_bin_retention)?;
+        env_parse("USAGE_RETENTION", &mut self.usage_retention)?;
+        env_parse("HASH_PASSWORDS", &mut self.hash_passwords)?;
+        env_string("STATE_ENCRYPTION_KEY", &mut self.state_encryption_key);
+        env_list("ADMINS", &mut self.admins);
+        env_list("CUSTOM_IMAGE_ALLOWLIST", &mut self.custom_image_allowlist);
+        env_string("HOME_VOLUME_PATH", &mut self.home_volume_path);
+        env_string(
+            "HOME_VOLUME_STORAGE_CLASS",
+            &mut self.home_volume_storage_class,
+        );
+        env_string("HOME_VOLUME_LXD_POOL", &mut self.home_volume_lxd_pool);
+        env_list("SHARED_MOUNT_ALLOWLIST", &mut self.shared_mount_allowlist);
+        env_string("BACKUP_S3_ENDPOINT", &mut self.backup_s3_endpoint);
+        env_string("BACKUP_S3_BUCKET", &mut self.backup_s3_bucket);
+        env_string("BACKUP_S3_REGION", &mut self.backup_s3_region);
+        env_string("BACKUP_S3_ACCESS_KEY", &mut self.backup_s3_access_key);
+        env_string("BACKUP_S3_SECRET_KEY", &mut self.backup_s3_secret_key);
+        env_list("BACKUP_S3_EXTRA_BUCKETS", &mut self.backup_s3_extra_buckets);
+        env_string("SSH_BASTION", &mut self.ssh_bastion);
+        env_string("DNS_ZONE", &mut self.dns_zone);
+        env_parse("DNS_TTL", &mut self.dns_ttl)?;
+        env_string("POWERDNS_URL", &mut self.powerdns_url);
+        env_string("POWERDNS_API_KEY", &mut self.powerdns_api_key);
+        env_map("MICROVM_AGENTS", &mut self.microvm_agents)?;
+        env_string("MICROVM_GATEWAY", &mut self.microvm_gateway);
+        env_map("OCI_AGENTS", &mut self.oci_agents)?;
+        env_string("OCI_NETWORK", &mut self.oci_network);
+        env_string("EC2_REGION", &mut self.ec2_region);
+        env_string("EC2_ENDPOINT", &mut self.ec2_endpoint);
+        env_string("EC2_ACCESS_KEY", &mut self.ec2_access_key);
+        env_string("EC2_SECRET_KEY", &mut self.ec2_secret_key);
+        env_map("EC2_IMAGES", &mut self.ec2_images)?;
+        env_list("EC2_INSTANCE_TYPES", &mut self.ec2_instance_types);
+        env_string("EC2_SUBNET_ID", &mut self.ec2_subnet_id);
+        env_list("EC2_SECURITY_GROUP_IDS", &mut self.ec2_security_group_ids);
+        env_string("EC2_KEY_NAME", &mut self.ec2_key_name);
+        env_string("EC2_ROOT_DEVICE_NAME", &mut self.ec2_root_device_name);
+        env_parse("EC2_BURST", &mut self.ec2_burst)?;
+        Ok(())
+    }
+
+    fn validate(&self) -> Result<()> {
+        if self.google_client_id.is_empty() {
+            return Err(anyhow!("google_client_id is required"));
+        }
+        if self.tls_cert.is_empty() != self.tls_key.is_empty() {
+            return Err(anyhow!("tls_cert and tls_key must be set together"));
+        }
+        if !self.web_ui_dir.is_empty() && !Path::new(&self.web_ui_dir).is_dir() {
+            return Err(anyhow!("web_ui_dir {} is not a directory", self.web_ui_dir));
+        }
+        if self.leader_election && self.leader_election_identity.is_empty() {
+            return Err(anyhow!(
+                "leader_election_identity is required when leader_election is enabled"
+            ));
+        }
+        if !self.lxd_client_cert.is_empty()
+            && self.lxd_server_url.split(',').all(|s| s.trim().is_empty())
+        {
+            return Err(anyhow!(
+                "lxd_server_url is required when lxd_client_cert is set"
+            ));
+        }
+        if !self.state_encryption_key.is_empty()
+            && base64::decode(&self.state_encryption_key).map_or(true, |k| k.len() != 32)
+        {
+            return Err(anyhow!(
+                "state_encryption_key must be 32 bytes encoded in base64"
+            ));
+        }
+        if !self.metadata_url.is_empty()
+            && !self.metadata_url.starts_with("http://")
+            && !self.metadata_url.starts_with("https://")
+        {
+            return Err(anyhow!("metadata_url must be an http or https URL"));
+        }
+        if !self.dns_zone.is_empty() && self.powerdns_url.is_empty() {
+            return Err(anyhow!("powerdns_url is required when dns_zone is set"));
+        }
+        for (name, agents) in [
+            ("microvm_agents", &self.microvm_agents),
+            ("oci_agents", &self.oci_agents),
+        ] {
+            for (node_name, endpoint) in agents {
+                if node_name.is_empty()
+                    || !endpoint.starts_with("http://") && !endpoint.starts_with("https://")
+                {
+                    return Err(anyhow!(
+                        "{} must map node names to http or https URLs, got {}={}",
+                        name,
+                        node_name,
+                        endpoint
+                    ));
+                }
+            }
+        }
+        self.lxd_api_flavor
+            .parse::<ApiFlavor>()
+            .context("invalid lxd_api_flavor")?;
+        let mut allocatable: HashSet<String> =
+            parse_ip_pool(&self.external_ip_pool, &self.external_ip_exclude)
+                .context("invalid external_ip_pool")?
+                .into_iter()
+                .collect();
+        if self.external_ip_prefix_length > 32 {
+            return Err(anyhow!("invalid external_ip_prefix_length"));
+        }
+        let mut names = HashSet::from([DEFAULT_IP_POOL]);
+        for p in &self.ip_pools {
+            if p.name.is_empty() || !names.insert(p.name.as_str()) {
+                return Err(anyhow!(
+                    "ip_pools has an empty or duplicate name {}",
+                    p.name
+                ));
+            }
+            if p.prefix_length > 32 {
+                return Err(anyhow!("invalid prefix_length of ip pool {}", p.name));
+            }
+            for ip in parse_ip_pool(&p.ranges, &p.exclude)
+                .with_context(|| format!("invalid ip pool {}", p.name))?
+            {
+                if !allocatable.insert(ip.clone()) {
+                    return Err(anyhow!("{} of ip pool {} is in another pool", ip, p.name));
+                }
+            }
+        }
+        if self.min_memory_per_cpu < 0.0 || self.max_memory_per_cpu < 0.0 {
+            return Err(anyhow!(
+                "min_memory_per_cpu and max_memory_per_cpu must not be negative"
+            ));
+        }
+        if self.max_memory_per_cpu > 0.0 && self.min_memory_per_cpu > self.max_memory_per_cpu {
+            return Err(anyhow!(
+                "min_memory_per_cpu must not be greater than max_memory_per_cpu"
+            ));
+        }
+        if self.rate_limit_per_second <= 0.0 {
+            return Err(anyhow!("rate_limit_per_second must be positive"));
+        }
+        for s in &self.api_allowlist {
+            parse_cidr(s).context("invalid api_allowlist")?;
+        }
+        for s in &self.metrics_allowlist {
+            parse_cidr(s).context("invalid metrics_allowlist")?;
+        }
+        if !self.metrics_basic_auth.is_empty() && !self.metrics_basic_auth.contains(':') {
+            return Err(anyhow!(
+                "metrics_basic_auth must be of the form <username>:<password>"
+            ));
+        }
+        if self.reconcile_interval == 0 || self.schedule_interval == 0 {
+            return Err(anyhow!(
+                "reconcile_interval and schedule_interval must be positive"
+            ));
+        }
+        if self.reconcile_jitter >= 100 {
+            return Err(anyhow!("reconcile_jitter must be less than 100"));
+        }
+        if !self.home_volume_path.starts_with('/')
+            || self.home_volume_path.split('/').any(|c| c == "..")
+        {
+            return Err(anyhow!("home_volume_path must be an absolute path"));
+        }
+        if self
+            .shared_mount_allowlist
+            .iter()
+            .any(|p| !p.starts_with('/') && !p.starts_with("nfs://"))
+        {
+            return Err(anyhow!(
+                "shared_mount_allowlist must only have absolute paths and NFS exports"
+            ));
+        }
+        if self.node_cpu_reserve >= 100
+            || self.node_memory_reserve >= 100
+            || self.storage_reserve >= 100
+        {
+            return Err(anyhow!(
+                "node_cpu_reserve, node_memory_reserve and storage_reserve must be less than 100"
+            ));
+        }
+        parse_node_reserve(&self.node_reserve).context("invalid node_reserve")?;
+        parse_storage_pool_reserve(&self.storage_pool_reserve)
+            .context("invalid storage_pool_reserve")?;
+        parse_instance_types(&self.ec2_instance_types).context("invalid ec2_instance_types")?;
+        parse_request_ratios(&self.k8s_request_ratios).context("invalid k8s_request_ratios")?;
+        parse_tolerations(&self.k8s_tolerations).context("invalid k8s_tolerations")?;
+        Ok(())
+    }
+
+    crate fn lxd_api_flavor(&self) -> ApiFlavor {
+        self.lxd_api_flavor.parse().unwrap()
+    }
+
+    /// Returns the IP pool named `name` with the defaults filled in. The default pool is returned
+    /// if `name` is None or the pool is no longer configured.
+    crate fn ip_pool(&self, name: Option<&str>) -> IpPool {
+        let mut pool = name
+            .and_then(|name| self.ip_pools.iter().find(|p| p.name == name))
+            .cloned()
+            .unwrap_or_else(|| IpPool {
+                name: DEFAULT_IP_POOL.to_owned(),
+                ranges: self.external_ip_pool.clone(),
+                exclude: self.external_ip_exclude.clone(),
+                ..Default::default()
+            });
+        if pool.prefix_length == 0 {
+            pool.prefix_length = self.external_ip_prefix_length;
+        }
+        if pool.gateway.is_empty() {
+            pool.gateway = self.microvm_gateway.clone();
+        }
+        pool
+    }
+
+    crate fn ec2_endpoint(&self) -> String {
+        if self.ec2_endpoint.is_empty() {
+            format!("https://ec2.{}.amazonaws.com", self.ec2_region)
+        } else {
+            self.ec2_endpoint.clone()
+        }
+    }
+
+    /// Returns how long the operators wait before their next pass, with the jitter applied.
+    crate fn reconcile_wait(&self) -> Duration {
+        jittered(self.reconcile_interval, self.reconcile_jitter)
+    }
+
+    /// Returns how long the scheduler waits before its next pass, with the jitter applied.
+    crate fn schedule_wait(&self) -> Duration {
+        jittered(self.schedule_interval, self.reconcile_jitter)
+    }
+
+    /// Returns true if the instances of any backend can mount home volumes.
+    crate fn home_volumes_enabled(&self) -> bool {
+        !self.home_volume_storage_class.is_empty() || !self.home_volume_lxd_pool.is_empty()
+    }
+
+    /// Returns where the home volume of the user is mounted.
+    crate fn home_volume_mount_path(&self, username: &str) -> String {
+        self.home_volume_path.replace("{username}", username)
+    }
+
+    /// Returns true if the admins allow shares to be mounted from the source, which must be an
+    /// allowed path or lie under one.
+    crate fn shared_mount_allowed(&self, source: &str) -> bool {
+        !source.split('/').any(|c| c == "..")
+            && self.shared_mount_allowlist.iter().any(|prefix| {
+                let prefix = prefix.trim_end_matches('/');
+                source == prefix || source.starts_with(&format!("{}/", prefix))
+            })
+    }
+
+    /// Returns true if the management API accepts requests from the address.
+    crate fn api_allowed(&self, ip: &IpAddr) -> bool {
+        ip_allowed(&self.api_allowlist, ip)
+    }
+
+    /// Returns true if /metrics accepts requests from the address.
+    crate fn metrics_allowed(&self, ip: &IpAddr) -> bool {
+        ip_allowed(&self.metrics_allowlist, ip)
+    }
+
+    /// Returns true if the Authorization header of a request to /metrics carries the credentials
+    /// it requires, or if it requires none.
+    crate fn metrics_authorized(&self, authorization: Option<&str>) -> bool {
+        if self.metrics_bearer_token.is_empty() && self.metrics_basic_auth.is_empty() {
+            return true;
+        }
+        let (scheme, credentials) = match authorization.and_then(|a| a.split_once(' ')) {
+            Some(a) => a,
+            None => return false,
+        };
+        let (expected, credentials) = if scheme.eq_ignore_ascii_case("bearer") {
+            (
+                &self.metrics_bearer_token,
+                credentials.trim().as_bytes().to_vec(),
+            )
+        } else if scheme.eq_ignore_ascii_case("basic") {
+            match base64::decode(credentials.trim()) {
+                Ok(credentials) => (&self.metrics_basic_auth, credentials),
+                Err(_) => return false,
+            }
+        } else {
+            return false;
+        };
+        !expected.is_empty()
+            && constant_time::verify_slices_are_equal(expected.as_bytes(), &credentials).is_ok()
+    }
+
+    /// Returns the percentages of the CPU and memory of `node` held back from instances.
+    crate fn node_reserve(&self, node: &str) -> (usize, usize) {
+        parse_node_reserve(&self.node_reserve)
+            .unwrap()
+            .get(node)
+            .copied()
+            .unwrap_or((self.node_cpu_reserve, self.node_memory_reserve))
+    }
+
+    /// Returns the percentage of the storage pool `pool` held back from instances.
+    crate fn storage_pool_reserve(&self, pool: &str) -> usize {
+        parse_storage_pool_reserve(&self.storage_pool_reserve)
+            .unwrap()
+            .get(pool)
+            .copied()
+            .unwrap_or(self.storage_reserve)
+    }
+
+    /// Returns the ratios of the CPU and memory requests to the limits of the pods of `runtime`.
+    crate fn k8s_request_ratios(&self, runtime: &str) -> (f64, f64) {
+        parse_request_ratios(&self.k8s_request_ratios)
+            .unwrap()
+            .get(runtime)
+            .copied()
+            .unwrap_or((1.0, 1.0))
+    }
+
+    /// Returns the tolerations of the pods as (key, value, effect).
+    crate fn k8s_tolerations(&self) -> Vec<(String, Option<String>, Option<String>)> {
+        parse_tolerations(&self.k8s_tolerations).unwrap()
+    }
+
+    /// Returns the EC2 instance types as (name, cpu, memory) sorted by cpu and memory.
+    crate fn ec2_instance_types(&self) -> Vec<(String, usize, usize)> {
+        parse_instance_types(&self.ec2_instance_types).unwrap()
+    }
+}
+
+crate const DEFAULT_IP_POOL: &str = "default";
+
+/// A named pool of external IP addresses, see [`Config::ip_pools`].
+#[derive(Debug, Clone, Default, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct IpPool {
+    pub name: String,
+    // Entries in the notations of external_ip_pool and external_ip_exclude.
+    pub ranges: Vec<String>,
+    pub exclude: Vec<String>,
+    // Defaults to external_ip_prefix_length.
+    pub prefix_length: u8,
+    // The default gateway of micro-VMs in the pool, defaults to microvm_gateway.
+    pub gateway: String,
+    // Nodes whose instances get addresses of the pool, all nodes if empty.
+    pub nodes: Vec<String>,
+    // Host interface the external NIC of LXD instances is attached to with macvlan, tagged with
+    // vlan if set. The NIC of the profiles of the instances is used if empty.
+    pub parent: String,
+    pub vlan: Option<u16>,
+}
+
+impl IpPool {
+    crate fn serves(&self, node_name: &str) -> bool {
+        self.nodes.is_empty() || self.nodes.iter().any(|n| n == node_name)
+    }
+
+    /// Returns the addresses of the pool, leaving out the excluded ones.
+    crate fn addresses(&self) -> Result<Vec<String>> {
+        let key = (self.ranges.clone(), self.exclude.clone());
+        if let Some(ips) = IP_POOL_ADDRESSES.lock().unwrap().get(&key) {
+            return Ok(ips.clone());
+        }
+        let ips = parse_ip_pool(&self.ranges, &self.exclude)?;
+        let mut cache = IP_POOL_ADDRESSES.lock().unwrap();
+        // Pools are rarely edited, drop the stale expansions once in a while.
+        if cache.len() >= 64 {
+            cache.clear();
+        }
+        cache.insert(key, ips.clone());
+        Ok(ips)
+    }
+}
+
+/// Loads the settings from the file if any and makes them available to all components. It must
+/// be called before any component reads a setting, otherwise the settings are loaded from the
+/// environment variables only.
+pub fn init(path: Option<String>) -> Result<()> {
+    let config = Config::load(path.as_deref())?;
+    PATH.set(path).expect("config is already initialized");
+    CONFIG.set(config).expect("config is already initialized");
+    Ok(())
+}
+
+/// Returns the settings loaded at startup.
+crate fn get() -> &'static Config {
+    CONFIG.get_or_init(|| Config::load(None).unwrap())
+}
+
+/// Initializes the settings with the defaults for the tests of the components reading them,
+/// unless they are initialized already.
+#[cfg(test)]
+crate fn init_defaults() {
+    CONFIG.get_or_init(|| Config {
+        google_client_id: "client".to_owned(),
+        ..Default::default()
+    });
+}
+
+/// Returns the settings loaded by the last reload.
+crate fn current() -> Arc<Config> {
+    CURRENT.read().unwrap().clone()
+}
+
+/// Loads the settings again from the same file and environment variables as at startup.
+///
+/// The current settings are kept if the new ones are invalid.
+pub fn reload() -> Result<()> {
+    let path = PATH.get().cloned().flatten();
+    let config = Config::load(path.as_deref())?;
+    *CURRENT.write().unwrap() = Arc::new(config);
+    Ok(())
+}
+
+fn env_string(name: &str, value: &mut String) {
+    if let Ok(s) = std::env::var(name) {
+        *value = s;
+    }
+}
+
+fn env_parse<T>(name: &str, value: &mut T) -> Result<()>
+where
+    T: FromStr,
+    T::Err: std::fmt::Display,
+{
+    if let Ok(s) = std::env::var(name) {
+        *value = s
+            .parse()
+            .map_err(|e| anyhow!("invalid {} {}: {}", name, s, e))?;
+    }
+    Ok(())
+}
+
+// A list is a comma-separated string, e.g. LXD_STORAGE_DRIVER=lvm,zfs.
+fn env_list(name: &str, value: &mut Vec<String>) {
+    if let Ok(s) = std::env::var(name) {
+        *value = s
+            .split(',')
+            .map(|s| s.trim().to_owned())
+            .filter(|s| !s.is_empty())
+            .collect();
+    }
+}
+
+// A map is a comma-separated list of key=value pairs, e.g. EC2_IMAGES=ubuntu:20.04=ami-0a1b2c3d.
+fn env_map(name: &str, value: &mut HashMap<String, String>) -> Result<()> {
+    if let Ok(s) = std::env::var(name) {
+        let mut m = HashMap::new();
+        for s in s.split(',').filter(|s| !s.is_empty()) {
+            let (k, v) = s
+                .split_once('=')
+                .ok_or_else(|| anyhow!("invalid {}: {} is not a key=value pair", name, s))?;
+            m.insert(k.to_owned(), v.to_owned());
+        }
+        *value = m;
+    }
+    Ok(())
+}
+
+fn parse_ipv4(s: &str) -> Result<u32> {
+    let ip: Ipv4Addr = s.trim().parse().map_err(|e| anyhow!("{}: {}", s, e))?;
+    Ok(u32::from(ip))
+}
+
+/// Parses a start-end range, a CIDR block or a single address into an inclusive range.
+fn parse_ip_block(s: &str) -> Result<(u32, u32)> {
+    if let Some((start, end)) = s.split_once('-') {
+        let (start, end) = (parse_ipv4(start)?, parse_ipv4(end)?);
+        if start > end {
+            return Err(anyhow!("{} starts after its end", s));
+        }
+        return Ok((start, end));
+    }
+    if let Some((addr, prefix_length)) = s.split_once('/') {
+        let addr = parse_ipv4(addr)?;
+        let prefix_length: u32 = prefix_length
+            .trim()
+            .parse()
+            .ok()
+            .filter(|l| *l <= 32)
+            .ok_or_else(|| anyhow!("{} has an invalid prefix length", s))?;
+        let host_mask = u32::MAX.checked_shr(prefix_length).unwrap_or(0);
+        if addr & host_mask != 0 {
+            return Err(anyhow!("{} has host bits set", s));
+        }
+        let (start, end) = (addr, addr | host_mask);
+        // /31 and /32 blocks have no network and broadcast addresses.
+        if prefix_length >= 31 {
+            return Ok((start, end));
+        }
+        return Ok((start + 1, end - 1));
+    }
+    let addr = parse_ipv4(s)?;
+    Ok((addr, addr))
+}
+
+/// Parses a CIDR block of IPv4 or IPv6 addresses, or a single address, into the address and the
+/// prefix length.
+fn parse_cidr(s: &str) -> Result<(IpAddr, u32)> {
+    let (addr, prefix_length) = match s.split_once('/') {
+        Some((addr, prefix_length)) => (addr, Some(prefix_length)),
+        None => (s, None),
+    };
+    let addr: IpAddr = addr.trim().parse().map_err(|e| anyhow!("{}: {}", s, e))?;
+    let max = if addr.is_ipv4() { 32 } else { 128 };
+    let prefix_length = match prefix_length {
+        Some(l) => l
+            .trim()
+            .parse()
+            .ok()
+            .filter(|l| *l <= max)
+            .ok_or_else(|| anyhow!("{} has an invalid prefix length", s))?,
+        None => max,
+    };
+    Ok((addr, prefix_length))
+}
+
+/// Returns the address as bits along with their number. IPv4-mapped IPv6 addresses, which IPv4
+/// clients of dual-stack listeners show up as, are taken as IPv4 addresses.
+fn ip_bits(ip: &IpAddr) -> (u128, u32) {
+    match ip {
+        IpAddr::V4(v4) => (u32::from(*v4) as u128, 32),
+        IpAddr::V6(v6) => match v6.segments() {
+            [0, 0, 0, 0, 0, 0xffff, ..] => (u128::from(*v6) & u32::MAX as u128, 32),
+            _ => (u128::from(*v6), 128),
+        },
+    }
+}
+
+/// Returns true if the address is in any of the blocks of the allowlist, or if it's empty.
+fn ip_allowed(allowlist: &[String], ip: &IpAddr) -> bool {
+    let (bits, len) = ip_bits(ip);
+    allowlist.is_empty()
+        || allowlist.iter().any(|s| {
+            let (addr, prefix_length) = parse_cidr(s).unwrap();
+            let (block, block_len) = ip_bits(&addr);
+            let prefix_length = match addr {
+                IpAddr::V6(_) => prefix_length.saturating_sub(128 - block_len),
+                IpAddr::V4(_) => prefix_length,
+            };
+            block_len == len && (bits ^ block).checked_shr(len - prefix_length).unwrap_or(0) == 0
+        })
+}
+
+/// Expands the IP pool into the list of IP addresses, leaving out the excluded ones. The entries
+/// of the pool must not overlap, as that's most likely a typo, and none may span more than
+/// `MAX_IP_BLOCK_SIZE` addresses.
+fn parse_ip_pool(pool: &[String], exclude: &[String]) -> Result<Vec<String>> {
+    let mut blocks = Vec::new();
+    for s in pool {
+        let block = parse_ip_block(s).with_context(|| format!("invalid entry {}", s))?;
+        if block.1 - block.0 >= MAX_IP_BLOCK_SIZE {
+            return Err(anyhow!(
+                "entry {} spans more than {} addresses",
+                s,
+                MAX_IP_BLOCK_SIZE
+            ));
+        }
+        blocks.push((block, s));
+    }
+    blocks.sort();
+    for w in blocks.windows(2) {
+        let (((_, end), a), ((start, _), b)) = (w[0], w[1]);
+        if start <= end {
+            return Err(anyhow!("entries {} and {} overlap", a, b));
+        }
+    }
+    let excluded = exclude
+        .iter()
+        .map(|s| parse_ip_block(s))
+        .collect::<Result<Vec<_>>>()
+        .context("invalid exclusion")?;
+
+    let mut ips = Vec::new();
+    for ((start, end), _) in blocks {
+        ips.extend(
+            (start..=end)
+                .filter(|a| !excluded.iter().any(|(s, e)| (*s..=*e).contains(a)))
+                .map(|a| Ipv4Addr::from(a).to_string()),
+        );
+    }
+    Ok(ips)
+}
+
+/// Returns the interval in milliseconds randomly lengthened or shortened by up to `jitter`
+/// percent of it.
+fn jittered(interval: u64, jitter: u64) -> Duration {
+    let spread = interval * jitter / 100;
+    Duration::from_millis(interval - spread + thread_rng().gen_range(0..=spread * 2))
+}
+
+fn parse_percent(s: &str) -> Option<usize> {
+    s.parse::<usize>().ok().filter(|p| *p < 100)
+}
+
+fn parse_node_reserve(reserve: &[String]) -> Result<HashMap<String, (usize, usize)>> {
+    let mut parsed = HashMap::new();
+    for s in reserve {
+        let invalid = || anyhow!("{} is not a node=cpu:memory triple", s);
+        let (node, spec) = s.split_once('=').ok_or_else(invalid)?;
+        let (cpu, memory) = spec.split_once(':').ok_or_else(invalid)?;
+        match (parse_percent(cpu), parse_percent(memory)) {
+            (Some(cpu), Some(memory)) => parsed.insert(node.to_owned(), (cpu, memory)),
+            _ => return Err(anyhow!("percentages of {} must be in [0, 100)", s)),
+        };
+    }
+    Ok(parsed)
+}
+
+fn parse_storage_pool_reserve(reserve: &[String]) -> Result<HashMap<String, usize>> {
+    let mut parsed = HashMap::new();
+    for s in reserve {
+        let (pool, percent) = s
+            .split_once('=')
+            .ok_or_else(|| anyhow!("{} is not a pool=percent pair", s))?;
+        let percent = parse_percent(percent)
+            .ok_or_else(|| anyhow!("percentage of {} must be in [0, 100)", s))?;
+        parsed.insert(pool.to_owned(), percent);
+    }
+    Ok(parsed)
+}
+
+fn parse_request_ratios(ratios: &[String]) -> Result<HashMap<String, (f64, f64)>> {
+    let mut parsed = HashMap::new();
+    for s in ratios {
+        let invalid = || anyhow!("{} is not a runtime=cpu:memory triple", s);
+        let (runtime, spec) = s.split_once('=').ok_or_else(invalid)?;
+        if runtime != "kata" && runtime != "runc" {
+            return Err(anyhow!("{} is not a runtime on kubernetes", runtime));
+        }
+        let (cpu, memory) = spec.split_once(':').ok_or_else(invalid)?;
+        let cpu = cpu.parse::<f64>().map_err(|_| invalid())?;
+        let memory = memory.parse::<f64>().map_err(|_| invalid())?;
+        if !(cpu > 0.0 && cpu <= 1.0 && memory > 0.0 && memory <= 1.0) {
+            return Err(anyhow!("ratios of {} must be in (0, 1]", s));
+        }
+        parsed.insert(runtime.to_owned(), (cpu, memory));
+    }
+    Ok(parsed)
+}
+
+fn parse_tolerations(
+    tolerations: &[String],
+) -> Result<Vec<(String, Option<String>, Option<String>)>> {
+    let mut parsed = Vec::new();
+    for s in tolerations {
+        let (taint, effect) = match s.rsplit_once(':') {
+            Some((taint, effect)) => (taint, Some(effect)),
+            None => (s.as_str(), None),
+        };
+        if let Some(effect) = effect {
+            if !matches!(effect, "NoSchedule" | "PreferNoSchedule" | "NoExecute") {
+                return Err(anyhow!("{} has an invalid effect {}", s, effect));
+            }
+        }
+        let (key, value) = match taint.split_once('=') {
+            Some((key, value)) => (key, Some(value)),
+            None => (taint, None),
+        };
+        if key.is_empty() {
+            return Err(anyhow!("{} has no key", s));
+        }
+        parsed.push((
+            key.to_owned(),
+            value.map(|v| v.to_owned()),
+            effect.map(|e| e.to_owned()),
+        ));
+    }
+    Ok(parsed)
+}
+
+fn parse_instance_types(types: &[String]) -> Result<Vec<(String, usize, usize)>> {
+    let mut parsed = Vec::new();
+    for s in types {
+        let invalid = || anyhow!("{} is not a name=cpu:memory triple", s);
+        let (name, spec) = s.split_once('=').ok_or_else(invalid)?;
+        let (cpu, memory) = spec.split_once(':').ok_or_else(invalid)?;
+        let cpu = cpu.parse::<usize>().map_err(|_| invalid())?;
+        let memory = memory.parse::<usize>().map_err(|_| invalid())?;
+        parsed.push((name.to_owned(), cpu, memory));
+    }
+    parsed.sort_by_key(|(_, cpu, memory)| (*cpu, *memory));
+    Ok(parsed)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_ip_pool_addresses() {
+        let addresses = |ranges: &[&str], exclude: &[&str]| {
+            IpPool {
+                ranges: ranges.iter().map(|s| s.to_string()).collect(),
+                exclude: exclude.iter().map(|s| s.to_string()).collect(),
+                ..Default::default()
+            }
+            .addresses()
+        };
+        assert_eq!(
+            addresses(&["10.0.0.0/30"], &[]).unwrap(),
+            ["10.0.0.1", "10.0.0.2"]
+        );
+        // /31 and /32 blocks have no network and broadcast addresses.
+        assert_eq!(
+            addresses(&["10.0.0.8/31", "10.0.0.5/32"], &[]).unwrap(),
+            ["10.0.0.5", "10.0.0.8", "10.0.0.9"]
+        );
+        assert_eq!(
+            addresses(&["10.0.0.1-10.0.0.4", "10.0.1.1"], &["10.0.0.2-10.0.0.3"]).unwrap(),
+            ["10.0.0.1", "10.0.0.4", "10.0.1.1"]
+        );
+        assert_eq!(addresses(&["10.0.0.0/16"], &[]).unwrap().len(), 65534);
+
+        for invalid in [
+            "10.0.0.0/33",
+            "10.0.0.1/30",
+            "10.0.0.0/15",
+            "10.0.0.4-10.0.0.1",
+            "10.0.0.1-10.1.0.1",
+            "10.0.0",
+            "fd00::1",
+        ] {
+            assert!(addresses(&[invalid], &[]).is_err(), "{}", invalid);
+        }
+        assert!(addresses(&["10.0.0.1"], &["10.0.0"]).is_err());
+    }
+
+    #[test]
+    fn test_ip_pool_overlaps() {
+        let addresses = |ranges: &[&str]| {
+            IpPool {
+                ranges: ranges.iter().map(|s| s.to_string()).collect(),
+                ..Default::default()
+            }
+            .addresses()
+        };
+        assert!(addresses(&["10.0.0.0/30", "10.0.0.2"]).is_err());
+        assert!(addresses(&["10.0.0.1-10.0.0.5", "10.0.0.4-10.0.0.8"]).is_err());
+        assert!(addresses(&["10.0.0.1", "10.0.0.1"]).is_err());
+        // Adjacent entries don't overlap.
+        assert_eq!(
+            addresses(&["10.0.0.3-10.0.0.4", "10.0.0.1-10.0.0.2"]).unwrap(),
+            ["10.0.0.1", "10.0.0.2", "10.0.0.3", "10.0.0.4"]
+        );
+    }
+
+    #[test]
+    fn test_validate() {
+        let valid = || Config {
+            google_client_id: "client".to_owned(),
+            ..Default::default()
+        };
+        assert!(valid().validate().is_ok());
+        assert!(Config::default().validate().is_err());
+        let invalid = |update: &dyn Fn(&mut Config)| {
+            let mut config = valid();
+            update(&mut config);
+            config.validate().is_err()
+        };
+        // Percentages.
+        assert!(invalid(&|c| c.node_cpu_reserve = 100));
+        assert!(invalid(&|c| c.storage_reserve = 100));
+        assert!(invalid(&|c| c.reconcile_jitter = 100));
+        assert!(invalid(
+            &|c| c.node_reserve = vec!["node1=10:100".to_owned()]
+        ));
+        assert!(invalid(&|c| c.node_reserve = vec!["node1=10".to_owned()]));
+        assert!(invalid(
+            &|c| c.storage_pool_reserve = vec!["lvm=100".to_owned()]
+        ));
+        assert!(!invalid(
+            &|c| c.node_reserve = vec!["node1=10:20".to_owned()]
+        ));
+
+        // IP pools.
+        let pool = |name: &str, ranges: &[&str]| IpPool {
+            name: name.to_owned(),
+            ranges: ranges.iter().map(|s| s.to_string()).collect(),
+            ..Default::default()
+        };
+        assert!(invalid(
+            &|c| c.external_ip_pool = vec!["10.0.0.0/33".to_owned()]
+        ));
+        assert!(invalid(&|c| c.external_ip_prefix_length = 33));
+        assert!(invalid(&|c| c.ip_pools = vec![pool("", &["10.0.1.1"])]));
+        assert!(invalid(
+            &|c| c.ip_pools = vec![pool(DEFAULT_IP_POOL, &["10.0.1.1"])]
+        ));
+        assert!(invalid(&|c| {
+            c.ip_pools = vec![pool("a", &["10.0.1.1"]), pool("a", &["10.0.2.1"])]
+        }));
+        assert!(invalid(&|c| {
+            c.external_ip_pool = vec!["10.0.0.0/30".to_owned()];
+            c.ip_pools = vec![pool("a", &["10.0.0.2"])];
+        }));
+        assert!(!invalid(&|c| {
+            c.external_ip_pool = vec!["10.0.0.0/30".to_owned()];
+            c.ip_pools = vec![pool("a", &["10.0.0.4-10.0.0.5"])];
+        }));
+
+        // Agent URLs.
+        let agents =
+            |node: &str, endpoint: &str| HashMap::from([(node.to_owned(), endpoint.to_owned())]);
+        assert!(invalid(
+            &|c| c.microvm_agents = agents("node1", "node1:8080")
+        ));
+        assert!(invalid(
+            &|c| c.microvm_agents = agents("", "http://node1:8080")
+        ));
+        assert!(invalid(&|c| c.oci_agents = agents("node1", "ftp://node1")));
+        assert!(!invalid(
+            &|c| c.microvm_agents = agents("node1", "http://node1:8080")
+        ));
+        assert!(!invalid(
+            &|c| c.oci_agents = agents("node1", "https://node1:8443")
+        ));
+    }
+
+    #[test]
+    fn test_env_overrides() {
+        let path = std::env::temp_dir().join(format!("tispace-config-{}.yaml", std::process::id()));
+        std::fs::write(
+            &path,
+            "google_client_id: client\nstorage_class_name: yaml\nreconcile_jitter: 10\n",
+        )
+        .unwrap();
+        std::env::set_var("STORAGE_CLASS_NAME", "env");
+        let config = Config::load(path.to_str());
+        std::env::remove_var("STORAGE_CLASS_NAME");
+        std::fs::remove_file(&path).unwrap();
+        let config = config.unwrap();
+        assert_eq!(config.storage_class_name, "env");
+        // The settings without overrides keep the values of the file.
+        assert_eq!(config.reconcile_jitter, 10);
+
+        let mut port = 80u16;
+        std::env::set_var("TISPACE_TEST_PORT", "8080");
+        assert!(env_parse("TISPACE_TEST_PORT", &mut port).is_ok());
+        assert_eq!(port, 8080);
+        std::env::set_var("TISPACE_TEST_PORT", "http");
+        assert!(env_parse("TISPACE_TEST_PORT", &mut port).is_err());
+        std::env::remove_var("TISPACE_TEST_PORT");
+        assert_eq!(port, 8080);
+    }
+}
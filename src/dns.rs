@@ -0,0 +1,232 @@
+//! Publishes DNS records of the instances to PowerDNS.
+//!
+//! Every instance with an external IP gets an A or AAAA record `<instance>.<user>.<zone>`, which
+//! is removed once the instance is deleted or loses the IP. Members of an instance group also get
+//! a record `<instance>.<group>.<user>.<zone>`, so that the group has a subdomain of its own. The
+//! records are marked by a comment so that the other records of the zone are left alone.
+
+use std::collections::HashMap;
+use std::net::IpAddr;
+
+use anyhow::{anyhow, Result};
+use once_cell::sync::Lazy;
+use regex::Regex;
+use reqwest::{Client, RequestBuilder};
+use tokio::time::Duration;
+use tracing::{info, instrument, warn};
+
+use crate::env::{DNS_TTL, DNS_ZONE, POWERDNS_API_KEY, POWERDNS_URL};
+use crate::metrics::BACKEND_ERRORS;
+use crate::model::{InstanceStage, State};
+use crate::shutdown;
+use crate::storage::Storage;
+
+// Account of the comments marking the records managed by tispace.
+const ACCOUNT: &str = "tispace";
+
+static DNS_LABEL_REGEX: Lazy<Regex> =
+    Lazy::new(|| Regex::new(r"^[a-z0-9]([-a-z0-9]{0,61}[a-z0-9])?$").unwrap());
+
+/// Returns the name of the record of the instance, or None if DNS records are not published or
+/// the username is not a valid DNS label.
+crate fn record_name(username: &str, instance_name: &str) -> Option<String> {
+    if DNS_ZONE.is_empty()
+        || !DNS_LABEL_REGEX.is_match(username)
+        || !DNS_LABEL_REGEX.is_match(instance_name)
+    {
+        return None;
+    }
+    Some(format!(
+        "{}.{}.{}",
+        instance_name,
+        username,
+        DNS_ZONE.trim_end_matches('.')
+    ))
+}
+
+/// Returns the name of the record of the member of the group, like `record_name`.
+crate fn group_record_name(username: &str, group: &str, instance_name: &str) -> Option<String> {
+    if !DNS_LABEL_REGEX.is_match(instance_name) {
+        return None;
+    }
+    record_name(username, group).map(|name| format!("{}.{}", instance_name, name))
+}
+
+pub struct DnsPublisher {
+    client: Client,
+    storage: Storage,
+}
+
+impl DnsPublisher {
+    pub fn new(client: Client, storage: Storage) -> Self {
+        DnsPublisher { client, storage }
+    }
+
+    pub async fn run(&self) {
+        while !shutdown::is_triggered() {
+            self.run_once().await;
+            shutdown::sleep(Duration::from_secs(15)).await;
+        }
+    }
+
+    #[instrument(skip_all)]
+    async fn run_once(&self) {
+        let state = self.storage.snapshot().await;
+        if let Err(e) = self.sync(&state).await {
+            warn!(
+                error = e.to_string().as_str(),
+                "sync dns records encountered error"
+            );
+            BACKEND_ERRORS.with_label_values(&["dns"]).inc();
+        }
+    }
+
+    async fn sync(&self, state: &State) -> Result<()> {
+        let desired = desired_records(state);
+        let current = self.list_records().await?;
+
+        let mut rrsets = Vec::new();
+        for ((name, kind), content) in &desired {
+            if current.get(&(name.clone(), kind.clone())) == Some(content) {
+                continue;
+            }
+            info!(
+                name = name.as_str(),
+                content = content.as_str(),
+                "publishing dns record"
+            );
+            rrsets.push(serde_json::json!({
+                "name": name,
+                "type": kind,
+                "ttl": *DNS_TTL,
+                "changetype": "REPLACE",
+                "records": [{"content": content, "disabled": false}],
+                "comments": [{"content": "managed by tispace", "account": ACCOUNT}]
+            }));
+        }
+        for (name, kind) in current.keys() {
+            if desired.contains_key(&(name.clone(), kind.clone())) {
+                continue;
+            }
+            info!(name = name.as_str(), "removing dns record");
+            rrsets.push(serde_json::json!({
+                "name": name,
+                "type": kind,
+                "changetype": "DELETE"
+            }));
+        }
+        if rrsets.is_empty() {
+            return Ok(());
+        }
+
+        let res = self
+            .request(self.client.patch(zone_url()))
+            .json(&serde_json::json!({ "rrsets": rrsets }))
+            .send()
+            .await?;
+        if !res.status().is_success() {
+            return Err(anyhow!(
+                "update zone failed with {}: {}",
+                res.status(),
+                res.text().await.unwrap_or_default()
+            ));
+        }
+        Ok(())
+    }
+
+    /// Returns the contents of the records managed by tispace keyed by their names and types.
+    async fn list_records(&self) -> Result<HashMap<(String, String), String>> {
+        // The zone is like:
+        // {
+        //   "name": "tispace.dev.",
+        //   "rrsets": [{
+        //     "name": "dev.alice.tispace.dev.",
+        //     "type": "A",
+        //     "records": [{"content": "10.0.0.8", "disabled": false}],
+        //     "comments": [{"content": "managed by tispace", "account": "tispace"}]
+        //   }]
+        // }
+        let res = self.request(self.client.get(zone_url())).send().await?;
+        if !res.status().is_success() {
+            return Err(anyhow!("get zone failed with {}", res.status()));
+        }
+        let zone: serde_json::Value = res.json().await?;
+        let mut records = HashMap::new();
+        for rrset in zone
+            .get("rrsets")
+            .and_then(|r| r.as_array())
+            .into_iter()
+            .flatten()
+        {
+            let managed = rrset
+                .get("comments")
+                .and_then(|c| c.as_array())
+                .map_or(false, |c| {
+                    c.iter()
+                        .any(|c| c.get("account").and_then(|a| a.as_str()) == Some(ACCOUNT))
+                });
+            if !managed {
+                continue;
+            }
+            let (name, kind) = match (
+                rrset.get("name").and_then(|n| n.as_str()),
+                rrset.get("type").and_then(|t| t.as_str()),
+            ) {
+                (Some(name), Some(kind)) => (name.to_owned(), kind.to_owned()),
+                _ => continue,
+            };
+            let content = rrset
+                .get("records")
+                .and_then(|r| r.get(0))
+                .and_then(|r| r.get("content"))
+                .and_then(|c| c.as_str())
+                .unwrap_or_default()
+                .to_owned();
+            records.insert((name, kind), content);
+        }
+        Ok(records)
+    }
+
+    fn request(&self, builder: RequestBuilder) -> RequestBuilder {
+        builder.header("X-API-Key", POWERDNS_API_KEY.as_str())
+    }
+}
+
+/// Returns the records the instances should have keyed by their fully qualified names and types.
+fn desired_records(state: &State) -> HashMap<(String, String), String> {
+    let mut records = HashMap::new();
+    for u in &state.users {
+        for i in &u.instances {
+            if i.stage == InstanceStage::Deleted {
+                continue;
+            }
+            let ip: IpAddr = match i.external_ip.as_ref().and_then(|ip| ip.parse().ok()) {
+                Some(ip) => ip,
+                None => continue,
+            };
+            let kind = match ip {
+                IpAddr::V4(_) => "A",
+                IpAddr::V6(_) => "AAAA",
+            };
+            let group_name = i
+                .instance_group
+                .as_ref()
+                .and_then(|g| group_record_name(&u.username, g, &i.name));
+            for name in record_name(&u.username, &i.name)
+                .into_iter()
+                .chain(group_name)
+            {
+                records.insert((format!("{}.", name), kind.to_owned()), ip.to_string());
+            }
+        }
+    }
+    records
+}
+
+fn zone_url() -> String {
+    format!(
+        "{}/zones/{}.",
+        POWERDNS_URL.trim_end_matches('/'),
+        DNS_ZONE.trim_end_matches('.')
+    )
+}
@@ -1,5 +1,13 @@
+use std::collections::BTreeMap;
+
+use chrono::Utc;
+use ring::digest;
 use serde::{Deserialize, Serialize};
 
+use crate::config;
+use crate::env::{HASH_PASSWORDS, SSH_BASTION};
+use crate::storage::StatusChange;
+
 #[derive(Debug, Clone, Default, Serialize, Deserialize)]
 #[serde(default)]
 crate struct CreateInstanceRequest {
@@ -13,6 +21,164 @@ crate struct CreateInstanceRequest {
     crate node_name: String,
     #[serde(default)]
     crate storage_pool: String,
+    // CPU architecture of the instance, amd64 if empty.
+    #[serde(default)]
+    crate arch: String,
+    // Public keys authorized to log in as root.
+    #[serde(default)]
+    crate ssh_authorized_keys: Vec<String>,
+    // Key of a previously exported backup in the backup storage to import the instance from.
+    #[serde(default)]
+    crate backup: String,
+    // Project to create the instance in, a personal instance if empty.
+    #[serde(default)]
+    crate project: String,
+    // Key/value pairs exposed to the guest by the metadata endpoint.
+    #[serde(default)]
+    crate labels: BTreeMap<String, String>,
+    // Free-form notes on what the instance is for.
+    #[serde(default)]
+    crate description: String,
+    // Either "normal" or "preemptible", normal if empty. Taken from the priority class if any.
+    #[serde(default)]
+    crate priority: String,
+    // Name of a priority class defined by admins.
+    #[serde(default)]
+    crate priority_class: String,
+    // External IP out of the IP pools to assign instead of a random one. An IP pinned by a
+    // deleted instance of the same name is assigned again if empty.
+    #[serde(default)]
+    crate external_ip: String,
+    // Whether the external IP stays reserved for the name of the instance once it's deleted.
+    #[serde(default)]
+    crate pin_external_ip: bool,
+    // Rate limits of the traffic to and from the instance in Mbit/s, unlimited if empty.
+    #[serde(default)]
+    crate ingress_limit: Option<usize>,
+    #[serde(default)]
+    crate egress_limit: Option<usize>,
+    // Pin the instance to host CPUs of its own, which are not shared with other instances.
+    #[serde(default)]
+    crate dedicated_cpu: bool,
+    // Allow running Docker or minikube inside the instance. Only the lxc and kata runtimes
+    // support it.
+    #[serde(default)]
+    crate nesting: bool,
+    // Pass /dev/kvm through to the instance to run VMs inside. Only the lxc and kata runtimes
+    // support it.
+    #[serde(default)]
+    crate kvm_passthrough: bool,
+    // Options tuning the VM of Kata instances: default_vcpus, default_memory (MiB) and
+    // enable_hugepages. The VM can't have more CPUs or memory than the instance.
+    #[serde(default)]
+    crate runtime_options: BTreeMap<String, String>,
+    // Sysctls to set, e.g. {"fs.inotify.max_user_watches": "524288"}. Only the LXC, kata and runc
+    // runtimes support them, and the fs.* ones are only supported by the LXC runtime.
+    #[serde(default)]
+    crate sysctls: BTreeMap<String, String>,
+    // Size of /dev/shm in MiB. Only the kata and runc runtimes support it.
+    #[serde(default)]
+    crate shm_size: Option<usize>,
+    // Volumes besides the root disk, e.g. for code and data which should survive rebuilds of the
+    // root filesystem. Only the kata and runc runtimes support them.
+    #[serde(default)]
+    crate volumes: Vec<Volume>,
+}
+
+#[derive(Debug, Clone, Default, Serialize, Deserialize)]
+#[serde(default)]
+crate struct HomeVolume {
+    // In GiB. The volume can only grow.
+    crate size: usize,
+    // Where the volume is mounted in the instances, ignored in requests.
+    crate mount_path: String,
+}
+
+#[derive(Debug, Clone, Default, Serialize, Deserialize)]
+#[serde(default)]
+crate struct Volume {
+    crate name: String,
+    // In GiB, counted towards the disk quota along with the root disk.
+    crate size: usize,
+    // Absolute path the volume is mounted at, e.g. /data.
+    crate mount_path: String,
+    // Name of a detached volume of the user to attach instead of creating an empty volume, in
+    // which case size is ignored. Empty in responses.
+    crate source: String,
+}
+
+impl From<&crate::model::Volume> for Volume {
+    fn from(m: &crate::model::Volume) -> Self {
+        Volume {
+            name: m.name.clone(),
+            size: m.size,
+            mount_path: m.mount_path.clone(),
+            source: String::new(),
+        }
+    }
+}
+
+#[derive(Debug, Clone, Default, Serialize, Deserialize)]
+#[serde(default)]
+crate struct DetachedVolume {
+    // Name of the deleted instance the volume is the root disk of.
+    crate name: String,
+    // In GiB.
+    crate size: usize,
+    crate node_name: Option<String>,
+    crate image: String,
+    crate created_at: i64,
+}
+
+impl From<&crate::model::DetachedVolume> for DetachedVolume {
+    fn from(m: &crate::model::DetachedVolume) -> Self {
+        DetachedVolume {
+            name: m.name.clone(),
+            size: m.size,
+            node_name: m.node_name.clone(),
+            image: m.image.clone(),
+            created_at: m.created_at,
+        }
+    }
+}
+
+#[derive(Debug, Clone, Default, Serialize, Deserialize)]
+#[serde(default)]
+crate struct ListVolumesResponse {
+    crate volumes: Vec<DetachedVolume>,
+}
+
+#[derive(Debug, Clone, Default, Serialize, Deserialize)]
+#[serde(default)]
+crate struct PowerSchedule {
+    // Times of day formatted as HH:MM.
+    crate start_at: Option<String>,
+    crate stop_at: Option<String>,
+    // Days of week, e.g. ["mon", "tue"]. Every day if empty.
+    crate days: Vec<String>,
+    // Offset of the timezone of start_at and stop_at from UTC in minutes.
+    crate utc_offset: i32,
+    // Scheduled actions due before this Unix timestamp are skipped.
+    crate skip_until: Option<i64>,
+}
+
+impl From<&crate::model::PowerSchedule> for PowerSchedule {
+    fn from(m: &crate::model::PowerSchedule) -> Self {
+        PowerSchedule {
+            start_at: m.start_at.clone(),
+            stop_at: m.stop_at.clone(),
+            days: m.days.clone(),
+            utc_offset: m.utc_offset,
+            skip_until: m.skip_until,
+        }
+    }
+}
+
+#[derive(Debug, Clone, Default, Serialize, Deserialize)]
+#[serde(default)]
+crate struct SkipScheduleRequest {
+    // Unix timestamp until which scheduled actions are skipped.
+    crate until: i64,
 }
 
 #[derive(Debug, Clone, Default, Serialize, Deserialize)]
@@ -21,50 +187,500 @@ crate struct UpdateInstanceRequest {
     crate cpu: Option<usize>,
     crate memory: Option<usize>,
     crate runtime: Option<String>,
+    // In Mbit/s, 0 removes the limit.
+    crate ingress_limit: Option<usize>,
+    crate egress_limit: Option<usize>,
+    // May be changed at any time, an empty description removes it.
+    crate description: Option<String>,
 }
 
 #[derive(Debug, Clone, Default, Serialize, Deserialize)]
 #[serde(default)]
 crate struct Instance {
+    // Stays the same across renames and ownership transfers.
+    crate id: String,
     crate name: String,
     crate cpu: usize,
     crate memory: usize,
     crate disk_size: usize,
+    crate disk_used: Option<usize>,
     crate hostname: String,
     // Deprecated: use external_ip instead.
     crate ssh_host: Option<String>,
     // Deprecated: use 22 instead.
     crate ssh_port: Option<i32>,
     crate password: String,
-    crate status: String,
+    crate ssh_authorized_keys: Vec<String>,
+    // The stage the user asked for, i.e. Running, Stopped or Deleted.
+    crate stage: String,
+    crate status: InstanceStatus,
     crate image: String,
+    crate arch: String,
     crate internal_ip: Option<String>,
     crate external_ip: Option<String>,
     crate runtime: String,
     crate node_name: Option<String>,
     crate storage_pool: Option<String>,
+    crate transfer: Option<Transfer>,
+    // Progress of the provisioning while the instance is being created.
+    crate progress: Option<Progress>,
+    crate conversion: Option<Conversion>,
+    crate events: Vec<Event>,
+    crate schedule: Option<PowerSchedule>,
+    crate backup_policy: Option<BackupPolicy>,
+    // The latest backup taken according to the backup policy.
+    crate last_backup: Option<Backup>,
+    crate project: Option<String>,
+    // Username of the owner, filled in when listing the instances of a project or shared ones.
+    crate owner: String,
+    crate shares: Vec<InstanceShare>,
+    crate labels: BTreeMap<String, String>,
+    crate description: String,
+    crate priority: String,
+    crate priority_class: Option<String>,
+    crate instance_group: Option<String>,
+    crate group_role: Option<String>,
+    crate volumes: Vec<Volume>,
+    crate mounts: Vec<SharedMount>,
+    crate ip_pinned: bool,
+    crate ingress_limit: Option<usize>,
+    crate egress_limit: Option<usize>,
+    crate dedicated_cpu: bool,
+    crate pinned_cpus: Vec<usize>,
+    crate nesting: bool,
+    crate kvm_passthrough: bool,
+    crate runtime_options: BTreeMap<String, String>,
+    crate sysctls: BTreeMap<String, String>,
+    crate shm_size: Option<usize>,
+    // Unix timestamp the instance entered Running, None while it's not running.
+    crate started_at: Option<i64>,
+    // Unix timestamp the instance was last seen running, which is now while it's running.
+    crate last_running_at: Option<i64>,
+    // Seconds the instance has been running in total.
+    crate uptime: u64,
+    crate created_at: Option<i64>,
+    crate updated_at: Option<i64>,
+    crate deleted_at: Option<i64>,
+    // When the instance was moved to the recycle bin and when it's deleted for good, if it's in
+    // the recycle bin.
+    crate trashed_at: Option<i64>,
+    crate purge_at: Option<i64>,
+    // Name of the DNS record pointing to the external IP, if records are published.
+    crate dns_name: Option<String>,
+    // Bastion to pass to `ssh -J` if the instance is only reachable through it.
+    crate ssh_proxy_jump: Option<String>,
+    // Command logging in to the instance as root, e.g. `ssh -J jump@bastion -p 30022 root@10.0.0.3`.
+    crate ssh_command: Option<String>,
+    // Fingerprints of the SSH host keys as printed by `ssh-keygen -l`, e.g. `SHA256:... (ED25519)`.
+    // Only LXD and EC2 instances publish their host keys, empty for the other runtimes.
+    crate ssh_host_key_fingerprints: Vec<String>,
 }
 
 impl From<&crate::model::Instance> for Instance {
     fn from(m: &crate::model::Instance) -> Self {
+        let now = Utc::now().timestamp();
         Instance {
+            id: m.id.clone(),
             name: m.name.clone(),
             cpu: m.cpu,
             memory: m.memory,
             disk_size: m.disk_size,
+            disk_used: m.disk_used,
             hostname: m.name.clone(),
             ssh_host: m.ssh_host.clone(),
             ssh_port: m.ssh_port,
-            password: m.password.clone(),
-            status: m.status.to_string(),
+            // Hashed passwords are useless to users, and with password hashing enabled the
+            // password is only returned when the instance is created.
+            password: if *HASH_PASSWORDS || m.password_hashed {
+                String::new()
+            } else {
+                m.password.clone()
+            },
+            ssh_authorized_keys: m.ssh_authorized_keys.clone(),
+            stage: m.stage.to_string(),
+            status: InstanceStatus::from(m),
             image: m.image.to_string(),
+            arch: m.arch.to_string(),
             internal_ip: m.internal_ip.clone(),
             external_ip: m.external_ip.clone(),
             runtime: m.runtime.to_string(),
             node_name: m.node_name.clone(),
             storage_pool: m.storage_pool.clone(),
+            transfer: m.transfer.as_ref().map(Transfer::from),
+            progress: m.progress.as_ref().map(Progress::from),
+            conversion: m.conversion.as_ref().map(Conversion::from),
+            events: m.events.iter().map(Event::from).collect(),
+            schedule: m.schedule.as_ref().map(PowerSchedule::from),
+            backup_policy: m.backup_policy.as_ref().map(BackupPolicy::from),
+            last_backup: m
+                .backup_policy
+                .as_ref()
+                .and_then(|p| p.backups.last())
+                .map(Backup::from),
+            project: m.project.clone(),
+            owner: String::new(),
+            shares: m.shares.iter().map(InstanceShare::from).collect(),
+            labels: m.labels.clone(),
+            description: m.description.clone(),
+            priority: m.priority.to_string(),
+            priority_class: m.priority_class.clone(),
+            instance_group: m.instance_group.clone(),
+            group_role: m.group_role.clone(),
+            volumes: m.volumes.iter().map(Volume::from).collect(),
+            mounts: m.mounts.iter().map(SharedMount::from).collect(),
+            ip_pinned: m.ip_pinned,
+            ingress_limit: m.ingress_limit,
+            egress_limit: m.egress_limit,
+            dedicated_cpu: m.dedicated_cpu,
+            pinned_cpus: m.pinned_cpus.clone(),
+            nesting: m.nesting,
+            kvm_passthrough: m.kvm_passthrough,
+            runtime_options: m.runtime_options.clone(),
+            sysctls: m.sysctls.clone(),
+            shm_size: m.shm_size,
+            started_at: m.started_at,
+            last_running_at: m.started_at.map(|_| now).or(m.last_running_at),
+            uptime: m.uptime(now),
+            created_at: m.created_at,
+            updated_at: m.updated_at,
+            deleted_at: m.deleted_at,
+            trashed_at: m.trashed_at,
+            purge_at: m
+                .trashed_at
+                .map(|t| t + config::current().recycle_bin_retention as i64 * 3600),
+            dns_name: None,
+            ssh_proxy_jump: m
+                .ssh_target()
+                .filter(|(_, _, internal)| *internal && !SSH_BASTION.is_empty())
+                .map(|_| SSH_BASTION.clone()),
+            ssh_command: ssh_command(m),
+            ssh_host_key_fingerprints: m
+                .ssh_host_keys
+                .iter()
+                .filter_map(|k| host_key_fingerprint(k))
+                .collect(),
+        }
+    }
+}
+
+/// Returns the SHA256 fingerprint of the public key like `ssh-ed25519 AAAA...`, in the format of
+/// `ssh-keygen -l`.
+fn host_key_fingerprint(key: &str) -> Option<String> {
+    let (kind, blob) = key.split_once(' ')?;
+    let blob = base64::decode(blob).ok()?;
+    let hash = digest::digest(&digest::SHA256, &blob);
+    let kind = match kind {
+        "ssh-rsa" => "RSA",
+        "ssh-ed25519" => "ED25519",
+        _ if kind.starts_with("ecdsa-") => "ECDSA",
+        _ => return None,
+    };
+    Some(format!(
+        "SHA256:{} ({})",
+        base64::encode_config(hash, base64::STANDARD_NO_PAD),
+        kind
+    ))
+}
+
+/// Returns the command logging in to the instance, None if it's unreachable from outside the
+/// cluster.
+fn ssh_command(m: &crate::model::Instance) -> Option<String> {
+    let (host, port, internal) = m.ssh_target()?;
+    let mut command = "ssh".to_owned();
+    if internal {
+        if SSH_BASTION.is_empty() {
+            return None;
         }
+        command.push_str(&format!(" -J {}", SSH_BASTION.as_str()));
+    }
+    if port != 22 {
+        command.push_str(&format!(" -p {}", port));
     }
+    command.push_str(&format!(" root@{}", host));
+    Some(command)
+}
+
+#[derive(Debug, Clone, Default, Serialize, Deserialize)]
+#[serde(default)]
+crate struct InstanceShare {
+    crate username: String,
+    crate ssh_authorized_keys: Vec<String>,
+}
+
+impl From<&crate::model::InstanceShare> for InstanceShare {
+    fn from(m: &crate::model::InstanceShare) -> Self {
+        InstanceShare {
+            username: m.username.clone(),
+            ssh_authorized_keys: m.ssh_authorized_keys.clone(),
+        }
+    }
+}
+
+#[derive(Debug, Clone, Default, Serialize, Deserialize)]
+#[serde(default)]
+crate struct ShareInstanceRequest {
+    // Public keys of the user to authorize to log in as root.
+    crate ssh_authorized_keys: Vec<String>,
+}
+
+#[derive(Debug, Clone, Default, Serialize, Deserialize)]
+#[serde(default)]
+crate struct ConvertInstanceRequest {
+    // The runtime to rebuild the instance with.
+    crate runtime: String,
+}
+
+#[derive(Debug, Clone, Default, Serialize, Deserialize)]
+#[serde(default)]
+crate struct Conversion {
+    crate from: String,
+    // The stopped backend holding the data of the instance before the conversion, whose root disk
+    // is attached read-only at /mnt/previous-root (/mnt/previous-root.img for VMs).
+    crate previous_backend: String,
+    crate discarded: bool,
+}
+
+impl From<&crate::model::Conversion> for Conversion {
+    fn from(m: &crate::model::Conversion) -> Self {
+        Conversion {
+            from: m.from.to_string(),
+            previous_backend: m.previous_backend.clone(),
+            discarded: m.discarded,
+        }
+    }
+}
+
+#[derive(Debug, Clone, Default, Serialize, Deserialize)]
+#[serde(default)]
+crate struct TransferOwnershipRequest {
+    // Username of the new owner.
+    crate to: String,
+}
+
+#[derive(Debug, Clone, Default, Serialize, Deserialize)]
+#[serde(default)]
+crate struct InstanceOwnerQuery {
+    // Owner of an instance shared with the user, the user itself if empty.
+    crate owner: String,
+}
+
+#[derive(Debug, Clone, Default, Serialize, Deserialize)]
+#[serde(default)]
+crate struct FileQuery {
+    // Absolute path of the file in the instance.
+    crate path: String,
+    // Owner of an instance shared with the user, the user itself if empty.
+    crate owner: String,
+}
+
+#[derive(Debug, Clone, Default, Serialize, Deserialize)]
+#[serde(default)]
+crate struct InstanceSchedulingResponse {
+    // Whether the instance still waits for a node.
+    crate pending: bool,
+    // The last attempt of the scheduler to place the instance, None if it never tried, e.g. for
+    // EC2 instances which are placed by AWS.
+    crate decision: Option<SchedulingDecision>,
+}
+
+#[derive(Debug, Clone, Default, Serialize, Deserialize)]
+#[serde(default)]
+crate struct SchedulingDecision {
+    crate decided_at: i64,
+    crate node_name: Option<String>,
+    crate storage_pool: Option<String>,
+    crate nodes: Vec<NodeVerdict>,
+}
+
+impl From<&crate::model::SchedulingDecision> for SchedulingDecision {
+    fn from(m: &crate::model::SchedulingDecision) -> Self {
+        SchedulingDecision {
+            decided_at: m.decided_at,
+            node_name: m.node_name.clone(),
+            storage_pool: m.storage_pool.clone(),
+            nodes: m.nodes.iter().map(NodeVerdict::from).collect(),
+        }
+    }
+}
+
+#[derive(Debug, Clone, Default, Serialize, Deserialize)]
+#[serde(default)]
+crate struct NodeVerdict {
+    crate node_name: String,
+    crate rejection: Option<String>,
+    crate free_cpu: usize,
+    crate free_memory: usize,
+    crate free_storage: usize,
+}
+
+impl From<&crate::model::NodeVerdict> for NodeVerdict {
+    fn from(m: &crate::model::NodeVerdict) -> Self {
+        NodeVerdict {
+            node_name: m.node_name.clone(),
+            rejection: m.rejection.clone(),
+            free_cpu: m.free_cpu,
+            free_memory: m.free_memory,
+            free_storage: m.free_storage,
+        }
+    }
+}
+
+#[derive(Debug, Clone, Default, Serialize, Deserialize)]
+#[serde(default)]
+crate struct DeleteInstanceQuery {
+    // Name of the instance, required if `Config::delete_confirmation` is enabled.
+    crate confirm: String,
+    // Keep the root disk as a detached volume, see GET /volumes. The instance skips the recycle
+    // bin. Only the kata and runc runtimes support it, as LXD root disks can't outlive their
+    // instances.
+    crate keep_disk: bool,
+}
+
+#[derive(Debug, Clone, Default, Serialize, Deserialize)]
+#[serde(default)]
+crate struct DeleteClusterQuery {
+    // Name of the cluster, required if `Config::delete_confirmation` is enabled.
+    crate confirm: String,
+}
+
+#[derive(Debug, Clone, Default, Serialize, Deserialize)]
+#[serde(default)]
+crate struct DryRunQuery {
+    // Only validate the request and return the instance it would result in.
+    crate dry_run: bool,
+}
+
+#[derive(Debug, Clone, Default, Serialize, Deserialize)]
+#[serde(default)]
+crate struct Transfer {
+    crate kind: String,
+    crate object_key: String,
+    crate status: String,
+    crate progress: Option<String>,
+}
+
+/// The status of an instance as observed from its backend.
+#[derive(Debug, Clone, Default, Serialize, Deserialize)]
+#[serde(default)]
+crate struct InstanceStatus {
+    // The status without the error message, e.g. Running or Error.
+    crate state: String,
+    // Why the state differs from the stage, e.g. StoppedByBackend, None if it doesn't.
+    crate reason: Option<String>,
+    // The error of the Error state.
+    crate message: Option<String>,
+    // Unix timestamp the instance entered the state, None if it's not recorded.
+    crate since: Option<i64>,
+}
+
+impl From<&crate::model::Instance> for InstanceStatus {
+    fn from(m: &crate::model::Instance) -> Self {
+        InstanceStatus {
+            state: m.status.state().to_owned(),
+            reason: m.status_reason().map(|r| r.to_owned()),
+            message: match &m.status {
+                crate::model::InstanceStatus::Error(e) => Some(e.clone()),
+                _ => None,
+            },
+            since: m.status_since(),
+        }
+    }
+}
+
+#[derive(Debug, Clone, Default, Serialize, Deserialize)]
+#[serde(default)]
+crate struct Progress {
+    crate phase: String,
+    crate percent: Option<u8>,
+}
+
+impl From<&crate::model::Progress> for Progress {
+    fn from(m: &crate::model::Progress) -> Self {
+        Progress {
+            phase: m.phase.clone(),
+            percent: m.percent,
+        }
+    }
+}
+
+impl From<&crate::model::Transfer> for Transfer {
+    fn from(m: &crate::model::Transfer) -> Self {
+        Transfer {
+            kind: m.kind.to_string(),
+            object_key: m.object_key.clone(),
+            status: m.status.to_string(),
+            progress: m.progress.clone(),
+        }
+    }
+}
+
+#[derive(Debug, Clone, Default, Serialize, Deserialize)]
+#[serde(default)]
+crate struct BackupPolicy {
+    // Hours between two backups.
+    crate interval: u32,
+    // Number of successful backups kept.
+    crate retention: usize,
+    // The default bucket of the backup storage if empty.
+    crate bucket: String,
+}
+
+impl From<&crate::model::BackupPolicy> for BackupPolicy {
+    fn from(m: &crate::model::BackupPolicy) -> Self {
+        BackupPolicy {
+            interval: m.interval,
+            retention: m.retention,
+            bucket: m.bucket.clone(),
+        }
+    }
+}
+
+#[derive(Debug, Clone, Default, Serialize, Deserialize)]
+#[serde(default)]
+crate struct Backup {
+    crate object_key: String,
+    crate bucket: String,
+    crate started_at: i64,
+    crate status: String,
+}
+
+impl From<&crate::model::Backup> for Backup {
+    fn from(m: &crate::model::Backup) -> Self {
+        Backup {
+            object_key: m.object_key.clone(),
+            bucket: m.bucket.clone(),
+            started_at: m.started_at,
+            status: m.status.to_string(),
+        }
+    }
+}
+
+#[derive(Debug, Clone, Default, Serialize, Deserialize)]
+#[serde(default)]
+crate struct Event {
+    crate timestamp: i64,
+    crate message: String,
+}
+
+impl From<&crate::model::Event> for Event {
+    fn from(m: &crate::model::Event) -> Self {
+        Event {
+            timestamp: m.timestamp,
+            message: m.message.clone(),
+        }
+    }
+}
+
+#[derive(Debug, Clone, Default, Serialize, Deserialize)]
+#[serde(default)]
+crate struct ListInstancesQuery {
+    // List the instances of all members in the project instead of the personal instances.
+    crate project: String,
+    // List the instances other users shared with the user instead of the personal instances.
+    crate shared: bool,
+    // Field to sort the instances by, one of name, created_at and updated_at, prefixed with `-`
+    // for descending order. The instances are listed in the order they were created if empty.
+    crate sort: String,
 }
 
 #[derive(Debug, Clone, Default, Serialize, Deserialize)]
@@ -72,3 +688,625 @@ impl From<&crate::model::Instance> for Instance {
 crate struct ListInstancesResponse {
     crate instances: Vec<Instance>,
 }
+
+#[derive(Debug, Clone, Default, Serialize, Deserialize)]
+#[serde(default)]
+crate struct StatusTransition {
+    crate timestamp: i64,
+    crate status: String,
+}
+
+impl From<&crate::model::StatusTransition> for StatusTransition {
+    fn from(m: &crate::model::StatusTransition) -> Self {
+        StatusTransition {
+            timestamp: m.timestamp,
+            status: m.status.to_string(),
+        }
+    }
+}
+
+#[derive(Debug, Clone, Default, Serialize, Deserialize)]
+#[serde(default)]
+crate struct InstanceHistoryResponse {
+    // Oldest first.
+    crate history: Vec<StatusTransition>,
+}
+
+/// Data of the events streamed by `GET /instances/events`.
+#[derive(Debug, Clone, Default, Serialize, Deserialize)]
+#[serde(default)]
+crate struct InstanceStatusEvent {
+    crate name: String,
+    // The status of the instance, or "Deleted" once it's removed.
+    crate status: String,
+}
+
+impl From<&StatusChange> for InstanceStatusEvent {
+    fn from(c: &StatusChange) -> Self {
+        InstanceStatusEvent {
+            name: c.instance.clone(),
+            status: c.status.clone(),
+        }
+    }
+}
+
+#[derive(Debug, Clone, Default, Serialize, Deserialize)]
+#[serde(default)]
+crate struct BatchInstancesRequest {
+    crate names: Vec<String>,
+    // One of "start", "stop" and "delete".
+    crate action: String,
+    // Names of the instances to delete once more, each required if
+    // `Config::delete_confirmation` is enabled.
+    crate confirm: Vec<String>,
+}
+
+#[derive(Debug, Clone, Default, Serialize, Deserialize)]
+#[serde(default)]
+crate struct BatchInstanceResult {
+    crate name: String,
+    // The code and message of the error if the action failed on the instance.
+    crate code: Option<String>,
+    crate error: Option<String>,
+}
+
+#[derive(Debug, Clone, Default, Serialize, Deserialize)]
+#[serde(default)]
+crate struct BatchInstancesResponse {
+    crate results: Vec<BatchInstanceResult>,
+}
+
+#[derive(Debug, Clone, Default, Serialize, Deserialize)]
+#[serde(default)]
+crate struct UsageQuery {
+    // Inclusive range of UTC dates formatted as YYYY-MM-DD.
+    crate from: String,
+    crate to: String,
+    // Either "user" or "day".
+    crate group_by: String,
+    // Either "json" or "csv".
+    crate format: String,
+}
+
+#[derive(Debug, Clone, Default, Serialize, Deserialize)]
+#[serde(default)]
+crate struct UsageRow {
+    // The username or the date depending on group_by.
+    crate key: String,
+    crate instance_hours: f64,
+    crate cpu_hours: f64,
+    crate memory_gib_hours: f64,
+    crate disk_gib_hours: f64,
+}
+
+#[derive(Debug, Clone, Default, Serialize, Deserialize)]
+#[serde(default)]
+crate struct UsageReport {
+    crate from: String,
+    crate to: String,
+    crate group_by: String,
+    crate rows: Vec<UsageRow>,
+}
+
+#[derive(Debug, Clone, Default, Serialize, Deserialize)]
+#[serde(default)]
+crate struct Group {
+    crate name: String,
+    crate members: Vec<String>,
+    crate cpu_quota: usize,
+    crate memory_quota: usize,
+    crate disk_quota: usize,
+    crate instance_quota: usize,
+}
+
+impl From<&crate::model::Group> for Group {
+    fn from(m: &crate::model::Group) -> Self {
+        Group {
+            name: m.name.clone(),
+            members: m.members.clone(),
+            cpu_quota: m.cpu_quota,
+            memory_quota: m.memory_quota,
+            disk_quota: m.disk_quota,
+            instance_quota: m.instance_quota,
+        }
+    }
+}
+
+#[derive(Debug, Clone, Default, Serialize, Deserialize)]
+#[serde(default)]
+crate struct ListGroupsResponse {
+    crate groups: Vec<Group>,
+}
+
+#[derive(Debug, Clone, Default, Serialize, Deserialize)]
+#[serde(default)]
+crate struct Project {
+    crate name: String,
+    crate members: Vec<String>,
+    crate cpu_quota: usize,
+    crate memory_quota: usize,
+    crate disk_quota: usize,
+    crate instance_quota: usize,
+}
+
+impl From<&crate::model::Project> for Project {
+    fn from(m: &crate::model::Project) -> Self {
+        Project {
+            name: m.name.clone(),
+            members: m.members.clone(),
+            cpu_quota: m.cpu_quota,
+            memory_quota: m.memory_quota,
+            disk_quota: m.disk_quota,
+            instance_quota: m.instance_quota,
+        }
+    }
+}
+
+#[derive(Debug, Clone, Default, Serialize, Deserialize)]
+#[serde(default)]
+crate struct ListProjectsResponse {
+    crate projects: Vec<Project>,
+}
+
+#[derive(Debug, Clone, Default, Serialize, Deserialize)]
+#[serde(default)]
+crate struct PriorityClass {
+    crate name: String,
+    crate value: i32,
+    crate preemptible: bool,
+    crate description: String,
+    // Users allowed to create instances of the class besides the admins, anyone if empty.
+    crate users: Vec<String>,
+}
+
+impl From<&crate::model::PriorityClass> for PriorityClass {
+    fn from(m: &crate::model::PriorityClass) -> Self {
+        PriorityClass {
+            name: m.name.clone(),
+            value: m.value,
+            preemptible: m.preemptible,
+            description: m.description.clone(),
+            users: m.users.clone(),
+        }
+    }
+}
+
+#[derive(Debug, Clone, Default, Serialize, Deserialize)]
+#[serde(default)]
+crate struct ListPriorityClassesResponse {
+    crate priority_classes: Vec<PriorityClass>,
+}
+
+#[derive(Debug, Clone, Default, Serialize, Deserialize)]
+#[serde(default)]
+crate struct CreateInstanceGroupRequest {
+    crate name: String,
+    // Number of the members, ignored for topology templates which have their own.
+    crate size: usize,
+    crate template: InstanceGroupTemplate,
+    // Labels shared by the members, overriding the ones of the template.
+    crate labels: BTreeMap<String, String>,
+}
+
+/// What the members of a group are created from: either the name of a topology template of the
+/// catalog, or a request creating each member, whose name is ignored.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[serde(untagged)]
+crate enum InstanceGroupTemplate {
+    Topology(String),
+    Instance(CreateInstanceRequest),
+}
+
+impl Default for InstanceGroupTemplate {
+    fn default() -> Self {
+        InstanceGroupTemplate::Instance(CreateInstanceRequest::default())
+    }
+}
+
+#[derive(Debug, Clone, Default, Serialize, Deserialize)]
+#[serde(default)]
+crate struct InstanceGroup {
+    crate name: String,
+    crate size: usize,
+    crate labels: BTreeMap<String, String>,
+    crate members: Vec<Instance>,
+    crate created_at: i64,
+    crate topology: Option<String>,
+    // How to connect to each role of the topology, empty for groups without one.
+    crate roles: Vec<GroupRole>,
+    // Set while deleted groups wait for their members to leave the recycle bin.
+    crate deleted_at: Option<i64>,
+}
+
+#[derive(Debug, Clone, Default, Serialize, Deserialize)]
+#[serde(default)]
+crate struct GroupRole {
+    crate name: String,
+    crate port: u16,
+    // `<host>:<port>` of each member, or only the host if the role has no port. The host is the
+    // DNS name of the member in the group if published, its IP otherwise.
+    crate endpoints: Vec<String>,
+}
+
+#[derive(Debug, Clone, Default, Serialize, Deserialize)]
+#[serde(default)]
+crate struct TopologyTemplate {
+    crate name: String,
+    crate description: String,
+    crate roles: Vec<TopologyRole>,
+}
+
+impl From<&crate::model::TopologyTemplate> for TopologyTemplate {
+    fn from(m: &crate::model::TopologyTemplate) -> Self {
+        TopologyTemplate {
+            name: m.name.clone(),
+            description: m.description.clone(),
+            roles: m.roles.iter().map(TopologyRole::from).collect(),
+        }
+    }
+}
+
+#[derive(Debug, Clone, Default, Serialize, Deserialize)]
+#[serde(default)]
+crate struct TopologyRole {
+    crate name: String,
+    crate count: usize,
+    crate cpu: usize,
+    crate memory: usize,
+    crate disk_size: usize,
+    crate image: String,
+    crate runtime: String,
+    crate port: u16,
+}
+
+impl From<&crate::model::TopologyRole> for TopologyRole {
+    fn from(m: &crate::model::TopologyRole) -> Self {
+        TopologyRole {
+            name: m.name.clone(),
+            count: m.count,
+            cpu: m.cpu,
+            memory: m.memory,
+            disk_size: m.disk_size,
+            image: m.image.clone(),
+            runtime: m.runtime.clone(),
+            port: m.port,
+        }
+    }
+}
+
+#[derive(Debug, Clone, Default, Serialize, Deserialize)]
+#[serde(default)]
+crate struct ListTopologyTemplatesResponse {
+    crate topology_templates: Vec<TopologyTemplate>,
+}
+
+#[derive(Debug, Clone, Default, Serialize, Deserialize)]
+#[serde(default)]
+crate struct ListInstanceGroupsResponse {
+    crate instance_groups: Vec<InstanceGroup>,
+}
+
+#[derive(Debug, Clone, Default, Serialize, Deserialize)]
+#[serde(default)]
+crate struct Node {
+    crate name: String,
+    crate runtimes: Vec<String>,
+    crate cpu_total: usize,
+    crate cpu_allocated: usize,
+    crate memory_total: usize,
+    crate memory_allocated: usize,
+    crate storage_total: usize,
+    crate storage_used: usize,
+    crate storage_allocated: usize,
+    crate unhealthy_reason: Option<String>,
+    crate schedulable: bool,
+    crate labels: BTreeMap<String, String>,
+    crate notes: String,
+    crate missing_since: Option<i64>,
+    crate draining: bool,
+    crate maintenance_windows: Vec<MaintenanceWindow>,
+}
+
+impl From<&crate::model::Node> for Node {
+    fn from(m: &crate::model::Node) -> Self {
+        Node {
+            name: m.name.clone(),
+            runtimes: m.runtimes.iter().map(|r| r.to_string()).collect(),
+            cpu_total: m.cpu_total,
+            cpu_allocated: m.cpu_allocated,
+            memory_total: m.memory_total,
+            memory_allocated: m.memory_allocated,
+            storage_total: m.storage_total,
+            storage_used: m.storage_used,
+            storage_allocated: m.storage_allocated,
+            unhealthy_reason: m.unhealthy_reason.clone(),
+            schedulable: m.schedulable,
+            labels: m.labels.clone(),
+            notes: m.notes.clone(),
+            missing_since: m.missing_since,
+            draining: m.draining,
+            maintenance_windows: m
+                .maintenance_windows
+                .iter()
+                .map(MaintenanceWindow::from)
+                .collect(),
+        }
+    }
+}
+
+#[derive(Debug, Clone, Default, Serialize, Deserialize)]
+#[serde(default)]
+crate struct MaintenanceWindow {
+    // Unix timestamps of the start and the end of the window.
+    crate start: i64,
+    crate end: i64,
+    crate reason: String,
+    // Whether the owners of the instances on the node have been warned, output only.
+    crate notified: bool,
+}
+
+impl From<&crate::model::MaintenanceWindow> for MaintenanceWindow {
+    fn from(m: &crate::model::MaintenanceWindow) -> Self {
+        MaintenanceWindow {
+            start: m.start,
+            end: m.end,
+            reason: m.reason.clone(),
+            notified: m.notified,
+        }
+    }
+}
+
+#[derive(Debug, Clone, Default, Serialize, Deserialize)]
+#[serde(default)]
+crate struct ListNodesResponse {
+    crate nodes: Vec<Node>,
+}
+
+#[derive(Debug, Clone, Default, Serialize, Deserialize)]
+#[serde(default)]
+crate struct ReconcileQuery {
+    // Scope of the reconciliation, all instances if empty. `instance` requires `username`.
+    crate username: String,
+    crate instance: String,
+}
+
+#[derive(Debug, Clone, Default, Serialize, Deserialize)]
+#[serde(default)]
+crate struct UpdateNodeRequest {
+    crate schedulable: Option<bool>,
+    crate labels: Option<BTreeMap<String, String>>,
+    crate notes: Option<String>,
+    crate draining: Option<bool>,
+    // Replaces the maintenance windows of the node.
+    crate maintenance_windows: Option<Vec<MaintenanceWindow>>,
+}
+
+#[derive(Debug, Clone, Default, Serialize, Deserialize)]
+#[serde(default)]
+crate struct CatalogImage {
+    crate name: String,
+    // Either "centos" or "ubuntu".
+    crate family: String,
+    // Source of the image per runtime, e.g. {"lxc": "ubuntu/22.04/cloud"}.
+    crate sources: BTreeMap<String, String>,
+    crate arches: Vec<String>,
+    crate enabled: bool,
+    crate default: bool,
+    // The user who registered the image, empty for images managed by admins.
+    crate owner: Option<String>,
+    // Shares mounted into the instances created from the image.
+    crate mounts: Vec<SharedMount>,
+}
+
+impl From<&crate::model::CatalogImage> for CatalogImage {
+    fn from(m: &crate::model::CatalogImage) -> Self {
+        CatalogImage {
+            name: m.name.to_string(),
+            family: m.family.to_string(),
+            sources: m.sources.clone(),
+            arches: m.arches.iter().map(|a| a.to_string()).collect(),
+            enabled: m.enabled,
+            default: m.default,
+            owner: m.owner.clone(),
+            mounts: m.mounts.iter().map(SharedMount::from).collect(),
+        }
+    }
+}
+
+#[derive(Debug, Clone, Default, Serialize, Deserialize)]
+#[serde(default)]
+crate struct SharedMount {
+    // Either an absolute path on the hosts or an NFS export like nfs://nas.internal/datasets.
+    // LXD instances only mount paths of the hosts.
+    crate source: String,
+    // Absolute path in the instances.
+    crate path: String,
+    crate read_only: bool,
+}
+
+impl From<&crate::model::SharedMount> for SharedMount {
+    fn from(m: &crate::model::SharedMount) -> Self {
+        SharedMount {
+            source: m.source.clone(),
+            path: m.path.clone(),
+            read_only: m.read_only,
+        }
+    }
+}
+
+#[derive(Debug, Clone, Default, Serialize, Deserialize)]
+#[serde(default)]
+crate struct RegisterImageRequest {
+    // Either "centos" or "ubuntu".
+    crate family: String,
+    // Alias of the image on the LXD image server, without the arch, for the LXD runtimes.
+    crate lxd_alias: String,
+    // Reference of the rootfs image, e.g. ghcr.io/acme/dev:1.0, for the Kubernetes runtimes.
+    crate oci_image: String,
+    // amd64 if empty.
+    crate arches: Vec<String>,
+}
+
+#[derive(Debug, Clone, Default, Serialize, Deserialize)]
+#[serde(default)]
+crate struct ListImagesResponse {
+    crate images: Vec<CatalogImage>,
+}
+
+#[derive(Debug, Clone, Default, Serialize, Deserialize)]
+#[serde(default)]
+crate struct CreateImageBuildRequest {
+    // Name of the image to publish, prefixed by the username in the catalog.
+    crate name: String,
+    crate base_image: String,
+    // Either "lxc" or "kvm", lxc if empty.
+    crate runtime: String,
+    // Shell script provisioning the image, run as root once cloud-init is done.
+    crate script: String,
+}
+
+#[derive(Debug, Clone, Default, Serialize, Deserialize)]
+#[serde(default)]
+crate struct ImageBuild {
+    crate name: String,
+    crate base_image: String,
+    crate runtime: String,
+    crate instance_name: String,
+    crate status: String,
+    crate events: Vec<Event>,
+}
+
+impl From<&crate::model::ImageBuild> for ImageBuild {
+    fn from(m: &crate::model::ImageBuild) -> Self {
+        ImageBuild {
+            name: m.name.to_string(),
+            base_image: m.base_image.to_string(),
+            runtime: m.runtime.to_string(),
+            instance_name: m.instance_name.clone(),
+            status: m.status.to_string(),
+            events: m.events.iter().map(Event::from).collect(),
+        }
+    }
+}
+
+#[derive(Debug, Clone, Default, Serialize, Deserialize)]
+#[serde(default)]
+crate struct ListImageBuildsResponse {
+    crate image_builds: Vec<ImageBuild>,
+}
+
+/// The SSH host keys posted by the phone_home module of cloud-init, each the content of a public
+/// key file like `ssh-ed25519 AAAA... root@host`.
+#[derive(Debug, Clone, Default, Serialize, Deserialize)]
+#[serde(default)]
+crate struct HostKeysForm {
+    crate pub_key_rsa: Option<String>,
+    crate pub_key_ecdsa: Option<String>,
+    crate pub_key_ed25519: Option<String>,
+}
+
+/// The document served to the guest by the metadata endpoint.
+#[derive(Debug, Clone, Default, Serialize, Deserialize)]
+#[serde(default)]
+crate struct InstanceMetadata {
+    crate owner: String,
+    crate name: String,
+    crate hostname: String,
+    crate project: Option<String>,
+    crate runtime: String,
+    crate image: String,
+    crate arch: String,
+    crate cpu: usize,
+    crate memory: usize,
+    crate disk_size: usize,
+    crate internal_ip: Option<String>,
+    crate external_ip: Option<String>,
+    crate labels: BTreeMap<String, String>,
+    // Keys of the owner and of the users the instance is shared with.
+    crate ssh_authorized_keys: Vec<String>,
+}
+
+#[derive(Debug, Clone, Default, Serialize, Deserialize)]
+#[serde(default)]
+crate struct IpAssignmentsQuery {
+    // Only the assignments of the address or of the user if not empty.
+    crate ip: String,
+    crate username: String,
+}
+
+#[derive(Debug, Clone, Default, Serialize, Deserialize)]
+#[serde(default)]
+crate struct IpAssignment {
+    crate ip: String,
+    crate username: String,
+    crate instance: String,
+    crate assigned_at: i64,
+    // None while the instance holds the address.
+    crate released_at: Option<i64>,
+}
+
+impl From<&crate::model::IpAssignment> for IpAssignment {
+    fn from(m: &crate::model::IpAssignment) -> Self {
+        IpAssignment {
+            ip: m.ip.clone(),
+            username: m.username.clone(),
+            instance: m.instance.clone(),
+            assigned_at: m.assigned_at,
+            released_at: m.released_at,
+        }
+    }
+}
+
+#[derive(Debug, Clone, Default, Serialize, Deserialize)]
+#[serde(default)]
+crate struct ListIpAssignmentsResponse {
+    crate ip_assignments: Vec<IpAssignment>,
+}
+
+#[derive(Debug, Clone, Default, Serialize, Deserialize)]
+#[serde(default)]
+crate struct IpPoolQuery {
+    // Only the pool of the name if not empty.
+    crate pool: String,
+}
+
+#[derive(Debug, Clone, Default, Serialize, Deserialize)]
+#[serde(default)]
+crate struct IpAddress {
+    crate ip: String,
+    // free, allocated, or reserved if released within the grace period or pinned.
+    crate status: String,
+    // The instance holding or having released the address, if any.
+    crate username: Option<String>,
+    crate instance: Option<String>,
+}
+
+#[derive(Debug, Clone, Default, Serialize, Deserialize)]
+#[serde(default)]
+crate struct IpPool {
+    crate name: String,
+    crate ranges: Vec<String>,
+    crate exclude: Vec<String>,
+    crate nodes: Vec<String>,
+    // Whether the addresses were edited at runtime rather than taken from the config.
+    crate edited: bool,
+    crate addresses: Vec<IpAddress>,
+}
+
+#[derive(Debug, Clone, Default, Serialize, Deserialize)]
+#[serde(default)]
+crate struct ListIpPoolsResponse {
+    crate pools: Vec<IpPool>,
+}
+
+#[derive(Debug, Clone, Default, Serialize, Deserialize)]
+#[serde(default)]
+crate struct IpRangeRequest {
+    // Name of the pool, the default one if empty.
+    crate pool: String,
+    // A range in the notations of external_ip_pool, e.g. 192.168.100.1-192.168.100.254,
+    // 192.168.100.0/24 or a single address.
+    crate range: String,
+    // Whether the range is left out of the pool rather than added to it.
+    crate exclude: bool,
+}
@@ -1,109 +1,145 @@
+//! Settings read by the components, see [`crate::config::Config`] for their meanings.
+//!
+//! Each setting is taken from the config loaded at startup the first time it's read. Settings
+//! that can be reloaded are read from [`crate::config::current`] instead.
+
 use std::collections::HashMap;
-use std::net::Ipv4Addr;
 
 use once_cell::sync::Lazy;
 
-crate static GOOGLE_CLIENT_ID: Lazy<String> =
-    Lazy::new(|| std::env::var("GOOGLE_CLIENT_ID").unwrap());
+use crate::config;
+use crate::operator_lxd::ApiFlavor;
+
+crate static GOOGLE_CLIENT_ID: Lazy<String> = Lazy::new(|| config::get().google_client_id.clone());
 
 crate static STORAGE_CLASS_NAME: Lazy<String> =
-    Lazy::new(|| std::env::var("STORAGE_CLASS_NAME").unwrap_or_else(|_| "openebs-lvm".to_owned()));
+    Lazy::new(|| config::get().storage_class_name.clone());
 
 crate static DEFAULT_ROOTFS_IMAGE_TAG: Lazy<String> =
-    Lazy::new(|| std::env::var("DEFAULT_ROOTFS_IMAGE_TAG").unwrap_or_else(|_| "latest".to_owned()));
+    Lazy::new(|| config::get().default_rootfs_image_tag.clone());
 
-crate static LXD_PROJECT: Lazy<String> =
-    Lazy::new(|| std::env::var("LXD_PROJECT").unwrap_or_else(|_| "tispace".to_owned()));
+pub static OTEL_EXPORTER_OTLP_ENDPOINT: Lazy<String> =
+    Lazy::new(|| config::get().otel_exporter_otlp_endpoint.clone());
 
-pub static LXD_CLIENT_CERT: Lazy<String> =
-    Lazy::new(|| std::env::var("LXD_CLIENT_CERT").unwrap_or_default());
+pub static TLS_CERT: Lazy<String> = Lazy::new(|| config::get().tls_cert.clone());
 
-crate static LXD_SERVER_URL: Lazy<String> = Lazy::new(|| std::env::var("LXD_SERVER_URL").unwrap());
+pub static TLS_KEY: Lazy<String> = Lazy::new(|| config::get().tls_key.clone());
 
-crate static LXD_IMAGE_SERVER_URL: Lazy<String> = Lazy::new(|| {
-    std::env::var("LXD_IMAGE_SERVER_URL")
-        .unwrap_or_else(|_| "https://mirrors.tuna.tsinghua.edu.cn/lxc-images".to_owned())
-});
+pub static METADATA_PORT: Lazy<u16> = Lazy::new(|| config::get().metadata_port);
 
-crate static LXD_STORAGE_POOL_DRIVER: Lazy<String> =
-    Lazy::new(|| std::env::var("LXD_STORAGE_DRIVER").unwrap_or_else(|_| "lvm".to_owned()));
-
-// Kubernetes cluster and LXD cluster may share the same storage pool but with different names.
-// LXD_STORAGE_MAPPING is a map from openebs volume name to LXD storage pool name.
-crate static LXD_STORAGE_POOL_MAPPING: Lazy<HashMap<String, String>> = Lazy::new(|| {
-    if let Ok(s) = std::env::var("LXD_STORAGE_POOL_MAPPING") {
-        let mut m = HashMap::new();
-        for s in s.split(',') {
-            let mut parts = s.splitn(2, '=');
-            let vg_name = parts.next().unwrap();
-            let storage_pool = parts.next().unwrap();
-            m.insert(vg_name.to_owned(), storage_pool.to_owned());
-        }
-        m
-    } else {
-        HashMap::new()
-    }
-});
+pub static WEB_UI_DIR: Lazy<String> = Lazy::new(|| config::get().web_ui_dir.clone());
 
-// A list of IP addresses for instances exposed outside of the cluster.
-// The value of the environment variable is a comma-separated list of IP ranges.
-// Each IP range is an explicit inclusive start-end ip address. For example:
-// EXTERNAL_IP_POOL=192.168.100.1-192.168.100.254,192.168.101.1-192.168.101.254.
-// Please note that the IP addresses must be in the same subnet with same prefix length.
-// The prefix length is configured by variable EXTERNAL_IP_PREFIX_LENGTH.
-crate static EXTERNAL_IP_POOL: Lazy<Vec<String>> = Lazy::new(|| {
-    if let Ok(s) = std::env::var("EXTERNAL_IP_POOL") {
-        s.split(',')
-            .flat_map(|s| {
-                let mut parts = s.splitn(2, '-');
-                let start = parts
-                    .next()
-                    .unwrap()
-                    .parse::<Ipv4Addr>()
-                    .unwrap()
-                    .octets()
-                    .into_iter()
-                    .fold(0, |a, b| (a << 8) + b as u32);
-                let end = parts
-                    .next()
-                    .unwrap()
-                    .parse::<Ipv4Addr>()
-                    .unwrap()
-                    .octets()
-                    .into_iter()
-                    .fold(0, |a, b| (a << 8) + b as u32);
-                (start..=end)
-                    .into_iter()
-                    .map(Ipv4Addr::from)
-                    .map(|a| a.to_string())
-            })
-            .collect()
-    } else {
-        Vec::new()
-    }
-});
+crate static LEADER_ELECTION: Lazy<bool> = Lazy::new(|| config::get().leader_election);
+
+crate static LEADER_ELECTION_IDENTITY: Lazy<String> =
+    Lazy::new(|| config::get().leader_election_identity.clone());
 
-// The prefix length of the IP addresses in the EXTERNAL_IP_POOL.
-crate static EXTERNAL_IP_PREFIX_LENGTH: Lazy<u8> = Lazy::new(|| {
-    if let Ok(s) = std::env::var("EXTERNAL_IP_PREFIX_LENGTH") {
-        s.parse::<u8>().unwrap()
-    } else {
-        32
-    }
+crate static LXD_PROJECT: Lazy<String> = Lazy::new(|| config::get().lxd_project.clone());
+
+pub static LXD_CLIENT_CERT: Lazy<String> = Lazy::new(|| config::get().lxd_client_cert.clone());
+
+crate static LXD_SERVER_URLS: Lazy<Vec<String>> = Lazy::new(|| {
+    config::get()
+        .lxd_server_url
+        .split(',')
+        .map(|s| s.trim().trim_end_matches('/').to_owned())
+        .filter(|s| !s.is_empty())
+        .collect()
 });
 
-crate static CPU_OVERCOMMIT_FACTOR: Lazy<f64> = Lazy::new(|| {
-    if let Ok(s) = std::env::var("CPU_OVERCOMMIT_FACTOR") {
-        s.parse::<f64>().unwrap()
-    } else {
-        1.0
-    }
+crate static LXD_API_FLAVOR: Lazy<ApiFlavor> = Lazy::new(|| config::get().lxd_api_flavor());
+
+crate static LXD_IMAGE_SERVER_URL: Lazy<String> =
+    Lazy::new(|| config::get().lxd_image_server_url.clone());
+
+crate static LXD_STORAGE_POOL_DRIVERS: Lazy<Vec<String>> =
+    Lazy::new(|| config::get().lxd_storage_driver.clone());
+
+crate static LXD_STORAGE_POOL_MAPPING: Lazy<HashMap<String, String>> =
+    Lazy::new(|| config::get().lxd_storage_pool_mapping.clone());
+
+crate static RATE_LIMIT_BURST: Lazy<usize> = Lazy::new(|| config::get().rate_limit_burst);
+
+crate static RATE_LIMIT_PER_SECOND: Lazy<f64> = Lazy::new(|| config::get().rate_limit_per_second);
+
+crate static COLLECTOR_INTERVAL: Lazy<u64> = Lazy::new(|| config::get().collector_interval);
+
+pub static ORPHAN_GC_INTERVAL: Lazy<u64> = Lazy::new(|| config::get().orphan_gc_interval);
+
+pub static IMAGE_PREPULL_INTERVAL: Lazy<u64> = Lazy::new(|| config::get().image_prepull_interval);
+
+crate static READINESS_COLLECTOR_MAX_AGE: Lazy<u64> =
+    Lazy::new(|| config::get().readiness_collector_max_age);
+
+crate static HASH_PASSWORDS: Lazy<bool> = Lazy::new(|| config::get().hash_passwords);
+
+crate static STATE_ENCRYPTION_KEY: Lazy<String> =
+    Lazy::new(|| config::get().state_encryption_key.clone());
+
+crate static BACKUP_S3_ENDPOINT: Lazy<String> =
+    Lazy::new(|| config::get().backup_s3_endpoint.clone());
+
+crate static BACKUP_S3_BUCKET: Lazy<String> = Lazy::new(|| config::get().backup_s3_bucket.clone());
+
+crate static BACKUP_S3_REGION: Lazy<String> = Lazy::new(|| config::get().backup_s3_region.clone());
+
+crate static BACKUP_S3_ACCESS_KEY: Lazy<String> =
+    Lazy::new(|| config::get().backup_s3_access_key.clone());
+
+crate static BACKUP_S3_SECRET_KEY: Lazy<String> =
+    Lazy::new(|| config::get().backup_s3_secret_key.clone());
+
+crate static BACKUP_S3_EXTRA_BUCKETS: Lazy<Vec<String>> =
+    Lazy::new(|| config::get().backup_s3_extra_buckets.clone());
+
+crate static SSH_BASTION: Lazy<String> = Lazy::new(|| config::get().ssh_bastion.clone());
+
+pub static DNS_ZONE: Lazy<String> = Lazy::new(|| config::get().dns_zone.clone());
+
+crate static DNS_TTL: Lazy<u32> = Lazy::new(|| config::get().dns_ttl);
+
+crate static POWERDNS_URL: Lazy<String> = Lazy::new(|| config::get().powerdns_url.clone());
+
+crate static POWERDNS_API_KEY: Lazy<String> = Lazy::new(|| config::get().powerdns_api_key.clone());
+
+pub static MICROVM_AGENTS: Lazy<HashMap<String, String>> = Lazy::new(|| {
+    config::get()
+        .microvm_agents
+        .iter()
+        .map(|(node_name, endpoint)| (node_name.clone(), endpoint.trim_end_matches('/').to_owned()))
+        .collect()
 });
 
-crate static MEMORY_OVERCOMMIT_FACTOR: Lazy<f64> = Lazy::new(|| {
-    if let Ok(s) = std::env::var("MEMORY_OVERCOMMIT_FACTOR") {
-        s.parse::<f64>().unwrap()
-    } else {
-        1.0
-    }
+pub static OCI_AGENTS: Lazy<HashMap<String, String>> = Lazy::new(|| {
+    config::get()
+        .oci_agents
+        .iter()
+        .map(|(node_name, endpoint)| (node_name.clone(), endpoint.trim_end_matches('/').to_owned()))
+        .collect()
 });
+
+pub static EC2_REGION: Lazy<String> = Lazy::new(|| config::get().ec2_region.clone());
+
+crate static EC2_ENDPOINT: Lazy<String> = Lazy::new(|| config::get().ec2_endpoint());
+
+crate static EC2_ACCESS_KEY: Lazy<String> = Lazy::new(|| config::get().ec2_access_key.clone());
+
+crate static EC2_SECRET_KEY: Lazy<String> = Lazy::new(|| config::get().ec2_secret_key.clone());
+
+crate static EC2_IMAGES: Lazy<HashMap<String, String>> =
+    Lazy::new(|| config::get().ec2_images.clone());
+
+crate static EC2_INSTANCE_TYPES: Lazy<Vec<(String, usize, usize)>> =
+    Lazy::new(|| config::get().ec2_instance_types());
+
+crate static EC2_SUBNET_ID: Lazy<String> = Lazy::new(|| config::get().ec2_subnet_id.clone());
+
+crate static EC2_SECURITY_GROUP_IDS: Lazy<Vec<String>> =
+    Lazy::new(|| config::get().ec2_security_group_ids.clone());
+
+crate static EC2_KEY_NAME: Lazy<String> = Lazy::new(|| config::get().ec2_key_name.clone());
+
+crate static EC2_ROOT_DEVICE_NAME: Lazy<String> =
+    Lazy::new(|| config::get().ec2_root_device_name.clone());
+
+crate static EC2_BURST: Lazy<bool> = Lazy::new(|| config::get().ec2_burst);
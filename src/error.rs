@@ -1,7 +1,5 @@
-use std::borrow::Cow;
-
 use axum::{
-    http::StatusCode,
+    http::{header::RETRY_AFTER, StatusCode},
     response::{IntoResponse, Response},
     Json,
 };
@@ -9,6 +7,8 @@ use serde_json::json;
 use thiserror::Error;
 use tower::BoxError;
 
+use crate::request_id::current_request_id;
+
 pub type Result<T> = std::result::Result<T, BoxError>;
 
 #[derive(Debug, Error)]
@@ -17,6 +17,8 @@ pub enum AuthError {
     UnauthorizedUser,
     #[error("Invalid token")]
     InvalidToken,
+    #[error("Only admins may impersonate users")]
+    ImpersonationForbidden,
 }
 
 impl IntoResponse for AuthError {
@@ -24,18 +26,40 @@ impl IntoResponse for AuthError {
         let (status, error_message) = match self {
             AuthError::UnauthorizedUser => (StatusCode::UNAUTHORIZED, self.to_string()),
             AuthError::InvalidToken => (StatusCode::BAD_REQUEST, self.to_string()),
+            AuthError::ImpersonationForbidden => (StatusCode::FORBIDDEN, self.to_string()),
         };
-        let body = Json(json!({
-            "error": error_message,
-        }));
-        (status, body).into_response()
+        (status, error_body(self.code(), error_message)).into_response()
     }
 }
 
+impl AuthError {
+    fn code(&self) -> &'static str {
+        match self {
+            AuthError::UnauthorizedUser => "unauthorized_user",
+            AuthError::InvalidToken => "invalid_token",
+            AuthError::ImpersonationForbidden => "impersonation_forbidden",
+        }
+    }
+}
+
+/// Returns the JSON body of an error response.
+///
+/// `code` is a stable machine-readable identifier of the error while `error` is meant for humans
+/// and may change. `request_id` lets support correlate the response with the server logs.
+fn error_body(code: &str, error_message: String) -> Json<serde_json::Value> {
+    Json(json!({
+        "error": error_message,
+        "code": code,
+        "request_id": current_request_id(),
+    }))
+}
+
 #[derive(Debug, Error)]
 crate enum InstanceError {
     #[error("Invalid arg `{0}`")]
     InvalidArgs(String),
+    #[error("Instance not found")]
+    NotFound,
     #[error("Instance already exists")]
     AlreadyExists,
     #[error("Instance is already deleted")]
@@ -50,6 +74,21 @@ crate enum InstanceError {
         requested: usize,
         unit: String,
     },
+    #[error(
+        "{resource} of a single instance is limited to {limit}{unit}, requested: {requested}{unit}"
+    )]
+    SizeLimitExceeded {
+        resource: String,
+        limit: usize,
+        requested: usize,
+        unit: String,
+    },
+    #[error("Memory per CPU must be {allowed}GiB, requested: {memory}GiB for {cpu}C")]
+    MemoryPerCpuUnallowed {
+        cpu: usize,
+        memory: usize,
+        allowed: String,
+    },
     #[error("Create instance failed")]
     CreateFailed,
     #[error("Delete instance failed")]
@@ -62,6 +101,8 @@ crate enum InstanceError {
     StopFailed,
     #[error("Image {image} is unavailable on runtime {runtime}")]
     ImageUnavailable { image: String, runtime: String },
+    #[error("Arch {arch} is unavailable on runtime {runtime}")]
+    ArchUnavailable { arch: String, runtime: String },
     #[error("Runtime {target} is incompatible with runtime {current}")]
     RuntimeIncompatible { current: String, target: String },
     #[error("No node has enough resources to create instance")]
@@ -70,54 +111,365 @@ crate enum InstanceError {
     UnknownNode(String),
     #[error("Unknown storage pool {0}")]
     UnknownStoragePool(String),
+    #[error("Unknown project {0}")]
+    UnknownProject(String),
+    #[error("Unknown topology template {0}")]
+    UnknownTopologyTemplate(String),
     #[error("Runtime {runtime} cannot specify storage pool")]
     StoragePoolCannotBeSpecified { runtime: String },
+    #[error("Runtime {runtime} cannot specify node")]
+    NodeCannotBeSpecified { runtime: String },
+    #[error("Runtime {runtime} is not enabled")]
+    RuntimeUnavailable { runtime: String },
+    #[error("Update schedule failed")]
+    ScheduleFailed,
+    #[error("Update backup policy failed")]
+    BackupPolicyFailed,
+    #[error("Export instance failed")]
+    ExportFailed,
+    #[error("Backup storage is not configured")]
+    BackupStorageUnavailable,
+    #[error("Runtime {runtime} does not support export and import")]
+    TransferUnsupported { runtime: String },
+    #[error("Instance is being exported or imported")]
+    TransferInProgress,
+    #[error("Instance keeps the backend of a previous conversion, discard it first")]
+    ConversionPending,
+    #[error("Convert instance failed")]
+    ConvertFailed,
+    #[error("External IP {0} is unavailable")]
+    IpUnavailable(String),
+    #[error("Idempotency key is already used by a different request")]
+    IdempotencyKeyReused,
+    #[error(
+        "At most {limit} instances can be created per {period}, retry after {retry_after} seconds"
+    )]
+    CreationRateLimited {
+        limit: usize,
+        period: String,
+        retry_after: i64,
+    },
+    #[error("Instance is not running")]
+    NotRunning,
+    #[error("SSH of the instance is unreachable")]
+    SshUnreachable,
+    #[error("Runtime {runtime} does not support file transfers")]
+    FilesUnsupported { runtime: String },
+    #[error("File {0} not found")]
+    FileNotFound(String),
+    #[error("File exceeds the size limit of {limit} bytes")]
+    FileTooLarge { limit: usize },
+    #[error("Transfer file failed")]
+    FileTransferFailed,
+    #[error(
+        "Files can only be transferred by the owner and users with SSH access to the instance"
+    )]
+    FilesForbidden,
+    #[error("Home volume not found")]
+    HomeVolumeNotFound,
+    #[error("Home volumes are not enabled")]
+    HomeVolumeUnavailable,
+    #[error("Home volume is mounted by instances, delete them first")]
+    HomeVolumeInUse,
+    #[error("Cluster not found")]
+    ClusterNotFound,
+    #[error("Cluster already exists")]
+    ClusterAlreadyExists,
+    #[error("Deleting the instance must be confirmed with `confirm` set to its name")]
+    ConfirmationRequired,
+    #[error("Instance is in the recycle bin, restore it first")]
+    InRecycleBin,
+    #[error("Instance is not in the recycle bin")]
+    NotInRecycleBin,
+    #[error("Volume not found")]
+    VolumeNotFound,
+    #[error("A volume kept from a deleted instance of the same name exists, delete it first")]
+    VolumeAlreadyExists,
+    #[error("Runtime {runtime} does not support keeping the root disk")]
+    KeepDiskUnsupported { runtime: String },
 }
 
 impl IntoResponse for InstanceError {
     fn into_response(self) -> Response {
         let (status, error_message) = match self {
             InstanceError::InvalidArgs(_) => (StatusCode::BAD_REQUEST, self.to_string()),
-            InstanceError::AlreadyExists => (StatusCode::CONFLICT, self.to_string()),
+            InstanceError::NotFound
+            | InstanceError::FileNotFound(_)
+            | InstanceError::HomeVolumeNotFound
+            | InstanceError::ClusterNotFound
+            | InstanceError::VolumeNotFound => (StatusCode::NOT_FOUND, self.to_string()),
+            InstanceError::FileTooLarge { .. } => (StatusCode::PAYLOAD_TOO_LARGE, self.to_string()),
+            InstanceError::AlreadyExists
+            | InstanceError::ClusterAlreadyExists
+            | InstanceError::VolumeAlreadyExists => (StatusCode::CONFLICT, self.to_string()),
             InstanceError::AlreadyDeleted
             | InstanceError::NotYetStopped
             | InstanceError::ImageUnavailable { .. }
+            | InstanceError::ArchUnavailable { .. }
             | InstanceError::RuntimeIncompatible { .. }
             | InstanceError::UnknownNode(_)
             | InstanceError::UnknownStoragePool(_)
-            | InstanceError::StoragePoolCannotBeSpecified { .. } => {
-                (StatusCode::BAD_REQUEST, self.to_string())
+            | InstanceError::UnknownProject(_)
+            | InstanceError::UnknownTopologyTemplate(_)
+            | InstanceError::StoragePoolCannotBeSpecified { .. }
+            | InstanceError::NodeCannotBeSpecified { .. }
+            | InstanceError::RuntimeUnavailable { .. }
+            | InstanceError::BackupStorageUnavailable
+            | InstanceError::TransferUnsupported { .. }
+            | InstanceError::FilesUnsupported { .. }
+            | InstanceError::KeepDiskUnsupported { .. }
+            | InstanceError::HomeVolumeUnavailable => (StatusCode::BAD_REQUEST, self.to_string()),
+            InstanceError::TransferInProgress
+            | InstanceError::ConversionPending
+            | InstanceError::IpUnavailable(_)
+            | InstanceError::NotRunning
+            | InstanceError::HomeVolumeInUse
+            | InstanceError::InRecycleBin
+            | InstanceError::NotInRecycleBin => (StatusCode::CONFLICT, self.to_string()),
+            InstanceError::SshUnreachable => (StatusCode::BAD_GATEWAY, self.to_string()),
+            InstanceError::FilesForbidden => (StatusCode::FORBIDDEN, self.to_string()),
+            InstanceError::ConfirmationRequired => {
+                (StatusCode::PRECONDITION_REQUIRED, self.to_string())
+            }
+            InstanceError::CreationRateLimited { retry_after, .. } => {
+                return (
+                    StatusCode::TOO_MANY_REQUESTS,
+                    [(RETRY_AFTER, retry_after.to_string())],
+                    error_body(self.code(), self.to_string()),
+                )
+                    .into_response();
             }
-            InstanceError::QuotaExceeded { .. } | InstanceError::ResourceExhausted => {
+            InstanceError::QuotaExceeded { .. }
+            | InstanceError::SizeLimitExceeded { .. }
+            | InstanceError::MemoryPerCpuUnallowed { .. }
+            | InstanceError::ResourceExhausted
+            | InstanceError::IdempotencyKeyReused => {
                 (StatusCode::UNPROCESSABLE_ENTITY, self.to_string())
             }
             InstanceError::CreateFailed
             | InstanceError::DeleteFailed
             | InstanceError::UpdateFailed
             | InstanceError::StartFailed
-            | InstanceError::StopFailed => (StatusCode::INTERNAL_SERVER_ERROR, self.to_string()),
+            | InstanceError::StopFailed
+            | InstanceError::ExportFailed
+            | InstanceError::ConvertFailed
+            | InstanceError::ScheduleFailed
+            | InstanceError::BackupPolicyFailed
+            | InstanceError::FileTransferFailed => {
+                (StatusCode::INTERNAL_SERVER_ERROR, self.to_string())
+            }
+        };
+        (status, error_body(self.code(), error_message)).into_response()
+    }
+}
+
+impl InstanceError {
+    crate fn code(&self) -> &'static str {
+        match self {
+            InstanceError::InvalidArgs(_) => "invalid_args",
+            InstanceError::NotFound => "not_found",
+            InstanceError::AlreadyExists => "already_exists",
+            InstanceError::AlreadyDeleted => "already_deleted",
+            InstanceError::NotYetStopped => "not_yet_stopped",
+            InstanceError::QuotaExceeded { .. } => "quota_exceeded",
+            InstanceError::SizeLimitExceeded { .. } => "size_limit_exceeded",
+            InstanceError::MemoryPerCpuUnallowed { .. } => "memory_per_cpu_unallowed",
+            InstanceError::CreateFailed => "create_failed",
+            InstanceError::DeleteFailed => "delete_failed",
+            InstanceError::UpdateFailed => "update_failed",
+            InstanceError::StartFailed => "start_failed",
+            InstanceError::StopFailed => "stop_failed",
+            InstanceError::ImageUnavailable { .. } => "image_unavailable",
+            InstanceError::ArchUnavailable { .. } => "arch_unavailable",
+            InstanceError::RuntimeIncompatible { .. } => "runtime_incompatible",
+            InstanceError::ResourceExhausted => "resource_exhausted",
+            InstanceError::UnknownNode(_) => "unknown_node",
+            InstanceError::UnknownStoragePool(_) => "unknown_storage_pool",
+            InstanceError::UnknownProject(_) => "unknown_project",
+            InstanceError::UnknownTopologyTemplate(_) => "unknown_topology_template",
+            InstanceError::StoragePoolCannotBeSpecified { .. } => {
+                "storage_pool_cannot_be_specified"
+            }
+            InstanceError::NodeCannotBeSpecified { .. } => "node_cannot_be_specified",
+            InstanceError::RuntimeUnavailable { .. } => "runtime_unavailable",
+            InstanceError::ScheduleFailed => "schedule_failed",
+            InstanceError::BackupPolicyFailed => "backup_policy_failed",
+            InstanceError::ExportFailed => "export_failed",
+            InstanceError::BackupStorageUnavailable => "backup_storage_unavailable",
+            InstanceError::TransferUnsupported { .. } => "transfer_unsupported",
+            InstanceError::TransferInProgress => "transfer_in_progress",
+            InstanceError::ConversionPending => "conversion_pending",
+            InstanceError::ConvertFailed => "convert_failed",
+            InstanceError::IpUnavailable(_) => "ip_unavailable",
+            InstanceError::IdempotencyKeyReused => "idempotency_key_reused",
+            InstanceError::CreationRateLimited { .. } => "creation_rate_limited",
+            InstanceError::NotRunning => "not_running",
+            InstanceError::SshUnreachable => "ssh_unreachable",
+            InstanceError::FilesUnsupported { .. } => "files_unsupported",
+            InstanceError::FileNotFound(_) => "file_not_found",
+            InstanceError::FileTooLarge { .. } => "file_too_large",
+            InstanceError::FileTransferFailed => "file_transfer_failed",
+            InstanceError::FilesForbidden => "files_forbidden",
+            InstanceError::HomeVolumeNotFound => "home_volume_not_found",
+            InstanceError::HomeVolumeUnavailable => "home_volume_unavailable",
+            InstanceError::HomeVolumeInUse => "home_volume_in_use",
+            InstanceError::ClusterNotFound => "cluster_not_found",
+            InstanceError::ClusterAlreadyExists => "cluster_already_exists",
+            InstanceError::ConfirmationRequired => "confirmation_required",
+            InstanceError::InRecycleBin => "in_recycle_bin",
+            InstanceError::NotInRecycleBin => "not_in_recycle_bin",
+            InstanceError::VolumeNotFound => "volume_not_found",
+            InstanceError::VolumeAlreadyExists => "volume_already_exists",
+            InstanceError::KeepDiskUnsupported { .. } => "keep_disk_unsupported",
+        }
+    }
+}
+
+#[derive(Debug, Error)]
+#[error("Too many requests, retry after {retry_after} seconds")]
+crate struct RateLimited {
+    crate retry_after: u64,
+}
+
+impl IntoResponse for RateLimited {
+    fn into_response(self) -> Response {
+        (
+            StatusCode::TOO_MANY_REQUESTS,
+            [(RETRY_AFTER, self.retry_after.to_string())],
+            error_body("rate_limited", self.to_string()),
+        )
+            .into_response()
+    }
+}
+
+#[derive(Debug, Error)]
+#[error("Requests from {ip} are not allowed")]
+crate struct IpForbidden {
+    crate ip: std::net::IpAddr,
+}
+
+impl IntoResponse for IpForbidden {
+    fn into_response(self) -> Response {
+        (
+            StatusCode::FORBIDDEN,
+            error_body("ip_forbidden", self.to_string()),
+        )
+            .into_response()
+    }
+}
+
+#[derive(Debug, Error)]
+#[error("This replica is not the leader, retry later")]
+crate struct NotLeader;
+
+impl IntoResponse for NotLeader {
+    fn into_response(self) -> Response {
+        (
+            StatusCode::SERVICE_UNAVAILABLE,
+            error_body("not_leader", self.to_string()),
+        )
+            .into_response()
+    }
+}
+
+#[derive(Debug, Error)]
+crate enum AdminError {
+    #[error("Admin privilege required")]
+    Forbidden,
+    #[error("Invalid config: {0}")]
+    InvalidConfig(String),
+    #[error("Invalid arg `{0}`")]
+    InvalidArgs(String),
+    #[error("Group {0} not found")]
+    GroupNotFound(String),
+    #[error("Project {0} not found")]
+    ProjectNotFound(String),
+    #[error("Image {0} not found")]
+    ImageNotFound(String),
+    #[error("Project {0} still has instances")]
+    ProjectInUse(String),
+    #[error("IP pool {0} not found")]
+    IpPoolNotFound(String),
+    #[error("Node {0} not found")]
+    NodeNotFound(String),
+    #[error("Priority class {0} not found")]
+    PriorityClassNotFound(String),
+    #[error("Priority class {0} still has instances")]
+    PriorityClassInUse(String),
+    #[error("Topology template {0} not found")]
+    TopologyTemplateNotFound(String),
+    #[error("Node {0} is still reported by its backends or has instances")]
+    NodeInUse(String),
+    #[error("IP range conflicts: {0}")]
+    IpRangeConflict(String),
+    #[error("Update failed")]
+    UpdateFailed,
+}
+
+impl IntoResponse for AdminError {
+    fn into_response(self) -> Response {
+        let status = match self {
+            AdminError::Forbidden => StatusCode::FORBIDDEN,
+            AdminError::InvalidConfig(_) | AdminError::InvalidArgs(_) => StatusCode::BAD_REQUEST,
+            AdminError::GroupNotFound(_)
+            | AdminError::ProjectNotFound(_)
+            | AdminError::ImageNotFound(_)
+            | AdminError::IpPoolNotFound(_)
+            | AdminError::NodeNotFound(_)
+            | AdminError::PriorityClassNotFound(_)
+            | AdminError::TopologyTemplateNotFound(_) => StatusCode::NOT_FOUND,
+            AdminError::ProjectInUse(_)
+            | AdminError::IpRangeConflict(_)
+            | AdminError::NodeInUse(_)
+            | AdminError::PriorityClassInUse(_) => StatusCode::CONFLICT,
+            AdminError::UpdateFailed => StatusCode::INTERNAL_SERVER_ERROR,
         };
-        let body = Json(json!({
-            "error": error_message,
-        }));
-        (status, body).into_response()
+        (status, error_body(self.code(), self.to_string())).into_response()
+    }
+}
+
+impl AdminError {
+    fn code(&self) -> &'static str {
+        match self {
+            AdminError::Forbidden => "forbidden",
+            AdminError::InvalidConfig(_) => "invalid_config",
+            AdminError::InvalidArgs(_) => "invalid_args",
+            AdminError::GroupNotFound(_) => "group_not_found",
+            AdminError::ProjectNotFound(_) => "project_not_found",
+            AdminError::ImageNotFound(_) => "image_not_found",
+            AdminError::ProjectInUse(_) => "project_in_use",
+            AdminError::IpPoolNotFound(_) => "ip_pool_not_found",
+            AdminError::IpRangeConflict(_) => "ip_range_conflict",
+            AdminError::NodeNotFound(_) => "node_not_found",
+            AdminError::NodeInUse(_) => "node_in_use",
+            AdminError::PriorityClassNotFound(_) => "priority_class_not_found",
+            AdminError::PriorityClassInUse(_) => "priority_class_in_use",
+            AdminError::TopologyTemplateNotFound(_) => "topology_template_not_found",
+            AdminError::UpdateFailed => "update_failed",
+        }
     }
 }
 
 pub async fn handle_error(error: BoxError) -> impl IntoResponse {
     if error.is::<tower::timeout::error::Elapsed>() {
-        return (StatusCode::REQUEST_TIMEOUT, Cow::from("request timed out"));
+        return (
+            StatusCode::REQUEST_TIMEOUT,
+            error_body("request_timeout", "request timed out".to_owned()),
+        );
     }
 
     if error.is::<tower::load_shed::error::Overloaded>() {
         return (
             StatusCode::SERVICE_UNAVAILABLE,
-            Cow::from("service is overloaded, try again later"),
+            error_body(
+                "overloaded",
+                "service is overloaded, try again later".to_owned(),
+            ),
         );
     }
 
     (
         StatusCode::INTERNAL_SERVER_ERROR,
-        Cow::from(format!("Unhandled internal error: {}", error)),
+        error_body("internal", format!("Unhandled internal error: {}", error)),
     )
 }
@@ -0,0 +1,229 @@
+use anyhow::{anyhow, Result};
+use k8s_openapi::api::core::v1::Pod;
+use k8s_openapi::apimachinery::pkg::apis::meta::v1::Status;
+use kube::api::{AttachParams, AttachedProcess};
+use kube::{Api, Client};
+use once_cell::sync::Lazy;
+use reqwest::header::CONTENT_LENGTH;
+use reqwest::StatusCode;
+use tokio::io::{AsyncReadExt, AsyncWriteExt};
+use tracing::warn;
+
+use crate::env::{LXD_API_FLAVOR, LXD_CLIENT_CERT, LXD_PROJECT};
+use crate::error::InstanceError;
+use crate::model::{Instance, Runtime};
+use crate::operator_k8s::NAMESPACE;
+use crate::operator_lxd::{self, api_url, check_error};
+
+static LXD_CLIENT: Lazy<reqwest::Client> = Lazy::new(operator_lxd::new_client);
+
+// Exit codes of the commands run in pods, telling why a file can't be pulled.
+const EXIT_NOT_FOUND: &str = "2";
+const EXIT_TOO_LARGE: &str = "3";
+
+/// Returns the content of the regular file at `path` in the instance, failing if it's larger than
+/// `limit` bytes.
+crate async fn pull(
+    username: &str,
+    instance: &Instance,
+    path: &str,
+    limit: usize,
+) -> Result<Vec<u8>, InstanceError> {
+    let name = instance.backend_name(username);
+    let res = match instance.runtime {
+        Runtime::Lxc | Runtime::Kvm => {
+            check_lxd(&instance.runtime)?;
+            pull_lxd(&name, path, limit).await
+        }
+        Runtime::Kata | Runtime::Runc => pull_pod(&name, path, limit).await,
+        Runtime::MicroVm | Runtime::Ec2 | Runtime::Oci => {
+            return Err(InstanceError::FilesUnsupported {
+                runtime: instance.runtime.to_string(),
+            })
+        }
+    };
+    res.map_err(|e| {
+        e.downcast::<InstanceError>().unwrap_or_else(|e| {
+            warn!(
+                instance = name.as_str(),
+                path,
+                error = e.to_string().as_str(),
+                "pull file encountered error"
+            );
+            InstanceError::FileTransferFailed
+        })
+    })
+}
+
+/// Writes `data` to the file at `path` in the instance, replacing the existing file if any.
+crate async fn push(
+    username: &str,
+    instance: &Instance,
+    path: &str,
+    data: Vec<u8>,
+) -> Result<(), InstanceError> {
+    let name = instance.backend_name(username);
+    let res = match instance.runtime {
+        Runtime::Lxc | Runtime::Kvm => {
+            check_lxd(&instance.runtime)?;
+            push_lxd(&name, path, data).await
+        }
+        Runtime::Kata | Runtime::Runc => push_pod(&name, path, data).await,
+        Runtime::MicroVm | Runtime::Ec2 | Runtime::Oci => {
+            return Err(InstanceError::FilesUnsupported {
+                runtime: instance.runtime.to_string(),
+            })
+        }
+    };
+    res.map_err(|e| {
+        warn!(
+            instance = name.as_str(),
+            path,
+            error = e.to_string().as_str(),
+            "push file encountered error"
+        );
+        InstanceError::FileTransferFailed
+    })
+}
+
+fn check_lxd(runtime: &Runtime) -> Result<(), InstanceError> {
+    if LXD_CLIENT_CERT.is_empty() {
+        return Err(InstanceError::RuntimeUnavailable {
+            runtime: runtime.to_string(),
+        });
+    }
+    Ok(())
+}
+
+fn lxd_file_url(name: &str, path: &str) -> Result<reqwest::Url> {
+    let url = api_url(&format!("/instances/{}/files", name));
+    Ok(reqwest::Url::parse_with_params(
+        &url,
+        &[("path", path), ("project", LXD_PROJECT.as_str())],
+    )?)
+}
+
+async fn pull_lxd(name: &str, path: &str, limit: usize) -> Result<Vec<u8>> {
+    let mut res = LXD_CLIENT.get(lxd_file_url(name, path)?).send().await?;
+    if res.status() == StatusCode::NOT_FOUND {
+        return Err(InstanceError::FileNotFound(path.to_owned()).into());
+    }
+    if !res.status().is_success() {
+        let res: serde_json::Value = res.json().await?;
+        check_error(&res)?;
+        return Err(anyhow!("unexpected response {}", res));
+    }
+    // Directories are listed instead of being returned as files.
+    let file_type = res
+        .headers()
+        .get(LXD_API_FLAVOR.header("Type"))
+        .and_then(|v| v.to_str().ok())
+        .unwrap_or("file");
+    if file_type != "file" {
+        return Err(InstanceError::InvalidArgs("path".to_owned()).into());
+    }
+    let length = res
+        .headers()
+        .get(CONTENT_LENGTH)
+        .and_then(|v| v.to_str().ok())
+        .and_then(|v| v.parse::<usize>().ok());
+    if length.map_or(false, |l| l > limit) {
+        return Err(InstanceError::FileTooLarge { limit }.into());
+    }
+    let mut data = Vec::new();
+    while let Some(chunk) = res.chunk().await? {
+        if data.len() + chunk.len() > limit {
+            return Err(InstanceError::FileTooLarge { limit }.into());
+        }
+        data.extend_from_slice(&chunk);
+    }
+    Ok(data)
+}
+
+async fn push_lxd(name: &str, path: &str, data: Vec<u8>) -> Result<()> {
+    let res: serde_json::Value = LXD_CLIENT
+        .post(lxd_file_url(name, path)?)
+        .header(LXD_API_FLAVOR.header("Type"), "file")
+        .header(LXD_API_FLAVOR.header("Write"), "overwrite")
+        .body(data)
+        .send()
+        .await?
+        .json()
+        .await?;
+    check_error(&res)
+}
+
+async fn exec_pod(name: &str, command: Vec<String>, stdin: bool) -> Result<AttachedProcess> {
+    let client = Client::try_default().await?;
+    let pods: Api<Pod> = Api::namespaced(client, NAMESPACE);
+    let params = AttachParams::default()
+        .container(name)
+        .stdin(stdin)
+        .stdout(!stdin)
+        .stderr(false);
+    Ok(pods.exec(name, command, &params).await?)
+}
+
+/// Returns the exit code of a command which didn't succeed.
+fn exit_code(status: &Status) -> Option<&str> {
+    status
+        .details
+        .as_ref()?
+        .causes
+        .as_ref()?
+        .iter()
+        .find(|c| c.reason.as_deref() == Some("ExitCode"))
+        .and_then(|c| c.message.as_deref())
+}
+
+/// Waits for the command to exit, returning its exit code if it failed.
+async fn wait_for(process: &mut AttachedProcess) -> Result<Option<String>> {
+    let status = match process.take_status() {
+        Some(status) => status.await,
+        None => None,
+    };
+    match status {
+        Some(s) if s.status.as_deref() == Some("Success") => Ok(None),
+        Some(s) => Ok(Some(exit_code(&s).unwrap_or("unknown").to_owned())),
+        None => Err(anyhow!("command exited without status")),
+    }
+}
+
+async fn pull_pod(name: &str, path: &str, limit: usize) -> Result<Vec<u8>> {
+    let script = format!(
+        r#"[ -f "$0" ] || exit {}; [ "$(wc -c < "$0")" -le {} ] || exit {}; cat "$0""#,
+        EXIT_NOT_FOUND, limit, EXIT_TOO_LARGE
+    );
+    let command = vec!["sh".to_owned(), "-c".to_owned(), script, path.to_owned()];
+    let mut process = exec_pod(name, command, false).await?;
+    let mut data = Vec::new();
+    if let Some(stdout) = process.stdout() {
+        // The file may grow after being checked.
+        stdout.take(limit as u64 + 1).read_to_end(&mut data).await?;
+    }
+    if data.len() > limit {
+        return Err(InstanceError::FileTooLarge { limit }.into());
+    }
+    match wait_for(&mut process).await?.as_deref() {
+        None => Ok(data),
+        Some(EXIT_NOT_FOUND) => Err(InstanceError::FileNotFound(path.to_owned()).into()),
+        Some(EXIT_TOO_LARGE) => Err(InstanceError::FileTooLarge { limit }.into()),
+        Some(code) => Err(anyhow!("command failed with exit code {}", code)),
+    }
+}
+
+async fn push_pod(name: &str, path: &str, data: Vec<u8>) -> Result<()> {
+    // The stdin of exec sessions can't be closed, so the size tells when the file is complete.
+    let script = format!(r#"head -c {} > "$0""#, data.len());
+    let command = vec!["sh".to_owned(), "-c".to_owned(), script, path.to_owned()];
+    let mut process = exec_pod(name, command, true).await?;
+    let mut stdin = process
+        .stdin()
+        .ok_or_else(|| anyhow!("stdin of exec session is unavailable"))?;
+    stdin.write_all(&data).await?;
+    stdin.flush().await?;
+    match wait_for(&mut process).await? {
+        None => Ok(()),
+        Some(code) => Err(anyhow!("command failed with exit code {}", code)),
+    }
+}
@@ -0,0 +1,342 @@
+//! Finds the backend resources no instance in the state accounts for, e.g. left behind by failed
+//! deletes or created by hand, and deletes them if `orphan_gc_delete` is enabled.
+//!
+//! Pods, Services and PersistentVolumeClaims are only considered if they carry the labels the
+//! Kubernetes operator sets, while all LXD instances in the project are, along with the custom
+//! volumes of `home_volume_lxd_pool` named like home volumes. A resource must be found
+//! orphaned by two passes in a row before it's deleted, so that backends created or deleted
+//! while a pass is running are not mistaken for orphans.
+
+use std::collections::HashSet;
+use std::sync::Mutex;
+
+use anyhow::Result;
+use k8s_openapi::api::core::v1::{PersistentVolumeClaim, Pod, Service};
+use kube::api::{DeleteParams, ListParams};
+use kube::{Api, Client as KubeClient, Resource};
+use reqwest::Client as ReqwestClient;
+use serde::de::DeserializeOwned;
+use tokio::time::Duration;
+use tracing::{info, instrument, warn};
+
+use crate::config;
+use crate::env::{LXD_PROJECT, ORPHAN_GC_INTERVAL};
+use crate::metrics::{BACKEND_ERRORS, ORPHANED_RESOURCES};
+use crate::model::{Runtime, State};
+use crate::operator_k8s::{home_pvc_name, volume_pvc_name, NAMESPACE};
+use crate::operator_lxd::{api_url, check_error, home_volume_name};
+use crate::shutdown;
+use crate::storage::Storage;
+
+// A backend resource, identified by its kind and name.
+#[derive(Debug, Clone, Hash, Eq, PartialEq)]
+struct Orphan {
+    kind: &'static str,
+    name: String,
+}
+
+pub struct GarbageCollector {
+    storage: Storage,
+    kube_client: Option<KubeClient>,
+    lxd_client: Option<ReqwestClient>,
+    // Orphans found by the last pass.
+    suspects: Mutex<HashSet<Orphan>>,
+}
+
+impl GarbageCollector {
+    pub fn new(
+        storage: Storage,
+        kube_client: Option<KubeClient>,
+        lxd_client: Option<ReqwestClient>,
+    ) -> Self {
+        GarbageCollector {
+            storage,
+            kube_client,
+            lxd_client,
+            suspects: Mutex::new(HashSet::new()),
+        }
+    }
+
+    pub async fn run(&self) {
+        while !shutdown::is_triggered() {
+            self.run_once().await;
+            shutdown::sleep(Duration::from_secs(*ORPHAN_GC_INTERVAL * 60)).await;
+        }
+    }
+
+    #[instrument(skip_all)]
+    async fn run_once(&self) {
+        // The state is read before the backends are listed, so that a backend is never listed
+        // before the instance it belongs to is seen.
+        let state = self.storage.snapshot().await;
+        let mut orphans = Vec::new();
+        if let Some(kube_client) = &self.kube_client {
+            match find_kube_orphans(kube_client, &state).await {
+                Ok(o) => orphans.extend(o.into_iter().map(|o| ("k8s", o))),
+                Err(e) => {
+                    warn!(
+                        error = e.to_string().as_str(),
+                        "listing kubernetes resources encountered error"
+                    );
+                    BACKEND_ERRORS.with_label_values(&["k8s"]).inc();
+                    return;
+                }
+            }
+        }
+        if let Some(lxd_client) = &self.lxd_client {
+            match find_lxd_orphans(lxd_client, &state).await {
+                Ok(o) => orphans.extend(o.into_iter().map(|o| ("lxd", o))),
+                Err(e) => {
+                    warn!(
+                        error = e.to_string().as_str(),
+                        "listing lxd instances encountered error"
+                    );
+                    BACKEND_ERRORS.with_label_values(&["lxd"]).inc();
+                    return;
+                }
+            }
+        }
+
+        ORPHANED_RESOURCES.reset();
+        for (backend, o) in &orphans {
+            ORPHANED_RESOURCES
+                .with_label_values(&[backend, o.kind])
+                .inc();
+        }
+        let confirmed: Vec<(&str, Orphan)> = {
+            let mut suspects = self.suspects.lock().unwrap();
+            let confirmed = orphans
+                .iter()
+                .filter(|(_, o)| suspects.contains(o))
+                .cloned()
+                .collect();
+            *suspects = orphans.into_iter().map(|(_, o)| o).collect();
+            confirmed
+        };
+
+        let delete = config::current().orphan_gc_delete;
+        for (backend, o) in confirmed {
+            if !delete {
+                warn!(
+                    backend = backend,
+                    kind = o.kind,
+                    name = o.name.as_str(),
+                    "found orphaned resource"
+                );
+                continue;
+            }
+            info!(
+                backend = backend,
+                kind = o.kind,
+                name = o.name.as_str(),
+                "deleting orphaned resource"
+            );
+            let res = match (&self.kube_client, &self.lxd_client, backend) {
+                (Some(kube_client), _, "k8s") => delete_kube_orphan(kube_client, &o).await,
+                (_, Some(lxd_client), "lxd") => delete_lxd_orphan(lxd_client, &o).await,
+                _ => Ok(()),
+            };
+            if let Err(e) = res {
+                warn!(
+                    backend = backend,
+                    kind = o.kind,
+                    name = o.name.as_str(),
+                    error = e.to_string().as_str(),
+                    "deleting orphaned resource encountered error"
+                );
+                BACKEND_ERRORS.with_label_values(&[backend]).inc();
+            }
+        }
+    }
+}
+
+/// Returns the labeled Pods, Services and PersistentVolumeClaims without an instance.
+async fn find_kube_orphans(kube_client: &KubeClient, state: &State) -> Result<Vec<Orphan>> {
+    let mut pods = HashSet::new();
+    let mut services = HashSet::new();
+    let mut pvcs = HashSet::new();
+    let mut homes = HashSet::new();
+    for u in &state.users {
+        if u.home_volume.is_some() {
+            homes.insert(home_pvc_name(&u.username));
+        }
+        pvcs.extend(u.detached_volumes.iter().map(|v| v.claim_name.clone()));
+        // The Service of the subdomain is kept as long as the user has any instance.
+        if !u.instances.is_empty() {
+            services.insert(u.username.clone());
+        }
+        for i in &u.instances {
+            if i.runtime != Runtime::Kata && i.runtime != Runtime::Runc {
+                continue;
+            }
+            let name = i.backend_name(&u.username);
+            pvcs.insert(format!("{}-rootfs", name));
+            pvcs.extend(i.volumes.iter().map(|v| volume_pvc_name(&name, v)));
+            services.insert(name.clone());
+            pods.insert(name);
+        }
+    }
+
+    let mut orphans = Vec::new();
+    for (kind, expected, selector) in [
+        ("pod", &pods, "tispace/instance"),
+        ("service", &services, "tispace/instance"),
+        ("service", &services, "tispace/subdomain"),
+        ("pvc", &pvcs, "tispace/instance"),
+        ("pvc", &homes, "tispace/home"),
+    ] {
+        let names = match kind {
+            "pod" => list_kube_names::<Pod>(kube_client, selector).await?,
+            "service" => list_kube_names::<Service>(kube_client, selector).await?,
+            _ => list_kube_names::<PersistentVolumeClaim>(kube_client, selector).await?,
+        };
+        orphans.extend(
+            names
+                .into_iter()
+                .filter(|n| !expected.contains(n))
+                .map(|name| Orphan { kind, name }),
+        );
+    }
+    Ok(orphans)
+}
+
+async fn list_kube_names<K>(kube_client: &KubeClient, selector: &str) -> Result<Vec<String>>
+where
+    K: Resource<DynamicType = ()> + Clone + DeserializeOwned + std::fmt::Debug,
+{
+    let api: Api<K> = Api::namespaced(kube_client.clone(), NAMESPACE);
+    let list = api.list(&ListParams::default().labels(selector)).await?;
+    Ok(list
+        .into_iter()
+        .filter_map(|r| r.meta().name.clone())
+        .collect())
+}
+
+async fn delete_kube_orphan(kube_client: &KubeClient, orphan: &Orphan) -> Result<()> {
+    let dp = DeleteParams::default();
+    match orphan.kind {
+        "pod" => {
+            let api: Api<Pod> = Api::namespaced(kube_client.clone(), NAMESPACE);
+            api.delete(&orphan.name, &dp).await?;
+        }
+        "service" => {
+            let api: Api<Service> = Api::namespaced(kube_client.clone(), NAMESPACE);
+            api.delete(&orphan.name, &dp).await?;
+        }
+        _ => {
+            let api: Api<PersistentVolumeClaim> = Api::namespaced(kube_client.clone(), NAMESPACE);
+            api.delete(&orphan.name, &dp).await?;
+        }
+    }
+    Ok(())
+}
+
+/// Returns the LXD instances of the project without an instance.
+async fn find_lxd_orphans(lxd_client: &ReqwestClient, state: &State) -> Result<Vec<Orphan>> {
+    let mut expected = HashSet::new();
+    for u in &state.users {
+        for i in &u.instances {
+            if i.runtime != Runtime::Lxc && i.runtime != Runtime::Kvm {
+                continue;
+            }
+            expected.insert(i.backend_name(&u.username));
+            if let Some(c) = &i.conversion {
+                expected.insert(c.previous_backend.clone());
+            }
+        }
+    }
+
+    let url = api_url(&format!("/instances?project={}", LXD_PROJECT.as_str()));
+    let res: serde_json::Value = lxd_client.get(url).send().await?.json().await?;
+    check_error(&res)?;
+    // The metadata is a list of URLs like /1.0/instances/alice-dev.
+    let mut orphans: Vec<Orphan> = res
+        .get("metadata")
+        .and_then(|m| m.as_array())
+        .into_iter()
+        .flatten()
+        .filter_map(|u| u.as_str())
+        .filter_map(|u| u.split('?').next()?.rsplit('/').next())
+        .filter(|name| !expected.contains(*name))
+        .map(|name| Orphan {
+            kind: "instance",
+            name: name.to_owned(),
+        })
+        .collect();
+    orphans.extend(find_lxd_home_orphans(lxd_client, state).await?);
+    Ok(orphans)
+}
+
+/// Returns the custom volumes of home volumes the users opted out of.
+async fn find_lxd_home_orphans(lxd_client: &ReqwestClient, state: &State) -> Result<Vec<Orphan>> {
+    let pool = config::current().home_volume_lxd_pool.clone();
+    if pool.is_empty() {
+        return Ok(Vec::new());
+    }
+    let expected: HashSet<String> = state
+        .users
+        .iter()
+        .filter(|u| u.home_volume.is_some())
+        .map(|u| home_volume_name(&u.username))
+        .collect();
+    let url = api_url(&format!(
+        "/storage-pools/{}/volumes/custom?project={}",
+        pool,
+        LXD_PROJECT.as_str()
+    ));
+    let res: serde_json::Value = lxd_client.get(url).send().await?.json().await?;
+    check_error(&res)?;
+    // The metadata is a list of URLs like /1.0/storage-pools/cephfs/volumes/custom/home-alice.
+    Ok(res
+        .get("metadata")
+        .and_then(|m| m.as_array())
+        .into_iter()
+        .flatten()
+        .filter_map(|u| u.as_str())
+        .filter_map(|u| u.split('?').next()?.rsplit('/').next())
+        .filter(|name| name.starts_with("home-") && !expected.contains(*name))
+        .map(|name| Orphan {
+            kind: "volume",
+            name: name.to_owned(),
+        })
+        .collect())
+}
+
+async fn delete_lxd_orphan(lxd_client: &ReqwestClient, orphan: &Orphan) -> Result<()> {
+    if orphan.kind == "volume" {
+        let url = api_url(&format!(
+            "/storage-pools/{}/volumes/custom/{}?project={}",
+            config::current().home_volume_lxd_pool,
+            orphan.name,
+            LXD_PROJECT.as_str()
+        ));
+        let res: serde_json::Value = lxd_client.delete(url).send().await?.json().await?;
+        return check_error(&res);
+    }
+    let url = api_url(&format!(
+        "/instances/{}?project={}",
+        orphan.name,
+        LXD_PROJECT.as_str()
+    ));
+    let res: serde_json::Value = lxd_client.delete(url).send().await?.json().await?;
+    if check_error(&res).is_ok() {
+        return Ok(());
+    }
+    // Running instances can't be deleted, stop them to be deleted by the next pass.
+    let url = api_url(&format!(
+        "/instances/{}/state?project={}",
+        orphan.name,
+        LXD_PROJECT.as_str()
+    ));
+    let res: serde_json::Value = lxd_client
+        .put(url)
+        .json(&serde_json::json!({
+            "action": "stop",
+            "force": true
+        }))
+        .send()
+        .await?
+        .json()
+        .await?;
+    check_error(&res)
+}
@@ -0,0 +1,456 @@
+use std::collections::BTreeMap;
+
+use anyhow::{anyhow, Result};
+use chrono::Utc;
+use rand::{distributions::Alphanumeric, thread_rng, Rng};
+use reqwest::Client;
+use tokio::time::Duration;
+use tracing::{info, instrument, warn};
+
+use crate::env::LXD_PROJECT;
+use crate::lifecycle::{self, Action};
+use crate::model::{
+    new_instance_id, Arch, CatalogImage, ImageBuild, ImageBuildStatus, Instance, InstanceStage,
+    InstanceStatus, Priority, State, LOCAL_IMAGE_PREFIX,
+};
+use crate::operator_lxd::{
+    api_url, check_error, parse_operation, parse_operation_status, OperationStatus,
+};
+use crate::shutdown;
+use crate::storage::Storage;
+
+// Resources of the temporary instances running the provisioning scripts.
+const BUILDER_CPU: usize = 2;
+const BUILDER_MEMORY: usize = 4;
+const BUILDER_DISK_SIZE: usize = 20;
+// Lines of the output of the script recorded as events of the build.
+const MAX_OUTPUT_LINES: usize = 50;
+
+/// Builds images by running provisioning scripts on temporary LXD instances and publishing
+/// their root filesystems to the catalog.
+///
+/// The instances are provisioned and stopped by the LXD operator like any other instance, the
+/// builder only runs the script and publishes the image.
+pub struct ImageBuilder {
+    client: Client,
+    storage: Storage,
+}
+
+impl ImageBuilder {
+    pub fn new(client: Client, storage: Storage) -> Self {
+        ImageBuilder { client, storage }
+    }
+
+    pub async fn run(&self) {
+        while !shutdown::is_triggered() {
+            self.run_once().await;
+            shutdown::sleep(Duration::from_secs(5)).await;
+        }
+    }
+
+    #[instrument(skip_all)]
+    async fn run_once(&self) {
+        let state = self.storage.snapshot().await;
+        for build in state.image_builds.iter().filter(|b| !b.is_finished()) {
+            if shutdown::is_triggered() {
+                return;
+            }
+            if let Err(e) = self.sync_build(&state, build).await {
+                warn!(
+                    username = build.username.as_str(),
+                    image = build.name.to_string().as_str(),
+                    error = e.to_string().as_str(),
+                    "image build failed"
+                );
+                self.update_build(build, |b, state| {
+                    b.status = ImageBuildStatus::Failed(e.to_string());
+                    b.add_event(format!("build failed: {}", e));
+                    delete_builder_instance(state, b);
+                })
+                .await;
+            }
+        }
+    }
+
+    async fn sync_build(&self, state: &State, build: &ImageBuild) -> Result<()> {
+        let instance = state
+            .find_user(&build.username)
+            .and_then(|u| u.find_instance(&build.instance_name));
+        match &build.status {
+            ImageBuildStatus::Pending => {
+                let base = state
+                    .find_image(Some(&build.base_image), &build.username)
+                    .ok_or_else(|| anyhow!("base image {} is unavailable", build.base_image))?;
+                let source = base.source(&build.runtime).ok_or_else(|| {
+                    anyhow!(
+                        "base image {} is unavailable on runtime {}",
+                        build.base_image,
+                        build.runtime
+                    )
+                })?;
+                if instance.is_some() {
+                    return Err(anyhow!("instance {} already exists", build.instance_name));
+                }
+                let now = Utc::now().timestamp();
+                let instance = Instance {
+                    id: new_instance_id(),
+                    name: build.instance_name.clone(),
+                    cpu: BUILDER_CPU,
+                    memory: BUILDER_MEMORY,
+                    disk_size: BUILDER_DISK_SIZE,
+                    disk_used: None,
+                    image: base.name.clone(),
+                    arch: Arch::Amd64,
+                    hostname: build.instance_name.clone(),
+                    ssh_host: None,
+                    ssh_port: None,
+                    password: thread_rng()
+                        .sample_iter(&Alphanumeric)
+                        .take(16)
+                        .map(char::from)
+                        .collect(),
+                    password_hashed: false,
+                    ssh_authorized_keys: Vec::new(),
+                    stage: InstanceStage::Running,
+                    status: InstanceStatus::Creating,
+                    internal_ip: None,
+                    external_ip: None,
+                    ip_pool: None,
+                    ip_pinned: false,
+                    ingress_limit: None,
+                    egress_limit: None,
+                    dedicated_cpu: false,
+                    pinned_cpus: Vec::new(),
+                    nesting: false,
+                    kvm_passthrough: false,
+                    runtime_options: BTreeMap::new(),
+                    sysctls: BTreeMap::new(),
+                    shm_size: None,
+                    volumes: Vec::new(),
+                    mounts: Vec::new(),
+                    conversion: None,
+                    runtime: build.runtime.clone(),
+                    node_name: None,
+                    storage_pool: None,
+                    transfer: None,
+                    progress: None,
+                    cloud_instance_id: None,
+                    events: Vec::new(),
+                    status_history: Vec::new(),
+                    started_at: None,
+                    last_running_at: None,
+                    accumulated_uptime: 0,
+                    created_at: Some(now),
+                    updated_at: Some(now),
+                    deleted_at: None,
+                    trashed_at: None,
+                    schedule: None,
+                    backup_policy: None,
+                    project: None,
+                    shares: Vec::new(),
+                    backend_name: None,
+                    image_source: Some(source.to_owned()),
+                    image_family: base.family,
+                    labels: BTreeMap::new(),
+                    description: format!("builder of image {}", build.name),
+                    priority: Priority::Normal,
+                    priority_class: None,
+                    instance_group: None,
+                    group_role: None,
+                    ssh_host_keys: Vec::new(),
+                    scheduling: None,
+                    keep_disk: false,
+                };
+                info!(
+                    username = build.username.as_str(),
+                    image = build.name.to_string().as_str(),
+                    instance = build.instance_name.as_str(),
+                    "provisioning builder instance"
+                );
+                // The instance is not subject to the quotas of the user as it only lives for
+                // the duration of the build.
+                self.update_build(build, |b, state| {
+                    if let Some(u) = state.find_mut_user(&b.username) {
+                        u.instances.push(instance.clone());
+                    }
+                    b.status = ImageBuildStatus::Provisioning;
+                    b.add_event(format!("provisioning instance {}", b.instance_name));
+                })
+                .await;
+            }
+            ImageBuildStatus::Provisioning => {
+                let instance = instance.ok_or_else(|| anyhow!("builder instance is missing"))?;
+                match &instance.status {
+                    InstanceStatus::Running => {}
+                    InstanceStatus::Error(e) => {
+                        return Err(anyhow!("builder instance failed: {}", e));
+                    }
+                    _ => return Ok(()),
+                }
+                let operation = self
+                    .exec_script(&instance.backend_name(&build.username), &build.script)
+                    .await?;
+                self.update_build(build, |b, _| {
+                    b.status = ImageBuildStatus::Running;
+                    b.operation = Some(operation.clone());
+                    b.add_event("running provisioning script".to_owned());
+                })
+                .await;
+            }
+            ImageBuildStatus::Running => {
+                let operation = build
+                    .operation
+                    .as_ref()
+                    .ok_or_else(|| anyhow!("no script operation"))?;
+                let res = self.get_operation(operation).await?;
+                match parse_operation_status(&res)? {
+                    OperationStatus::Running(_) => return Ok(()),
+                    OperationStatus::Failure(e) => return Err(anyhow!("script failed: {}", e)),
+                    OperationStatus::Success => {}
+                }
+                let metadata = res
+                    .get("metadata")
+                    .and_then(|m| m.get("metadata"))
+                    .ok_or_else(|| anyhow!("no script metadata"))?;
+                let output = self.get_script_output(metadata).await;
+                let code = metadata
+                    .get("return")
+                    .and_then(|r| r.as_i64())
+                    .ok_or_else(|| anyhow!("no exit code"))?;
+                self.update_build(build, |b, state| {
+                    for line in &output {
+                        b.add_event(line.clone());
+                    }
+                    b.operation = None;
+                    if code != 0 {
+                        return;
+                    }
+                    b.status = ImageBuildStatus::Stopping;
+                    b.add_event("stopping instance".to_owned());
+                    if let Some(i) = state
+                        .find_mut_user(&b.username)
+                        .and_then(|u| u.find_mut_instance(&b.instance_name))
+                    {
+                        let _ = lifecycle::apply(i, Action::Stop);
+                    }
+                })
+                .await;
+                if code != 0 {
+                    return Err(anyhow!("script exited with code {}", code));
+                }
+            }
+            ImageBuildStatus::Stopping => {
+                let instance = instance.ok_or_else(|| anyhow!("builder instance is missing"))?;
+                if instance.status != InstanceStatus::Stopped {
+                    return Ok(());
+                }
+                let operation = self
+                    .publish_image(&instance.backend_name(&build.username), build)
+                    .await?;
+                self.update_build(build, |b, _| {
+                    b.status = ImageBuildStatus::Publishing;
+                    b.operation = Some(operation.clone());
+                    b.add_event("publishing image".to_owned());
+                })
+                .await;
+            }
+            ImageBuildStatus::Publishing => {
+                let operation = build
+                    .operation
+                    .as_ref()
+                    .ok_or_else(|| anyhow!("no publish operation"))?;
+                match parse_operation_status(&self.get_operation(operation).await?)? {
+                    OperationStatus::Running(_) => return Ok(()),
+                    OperationStatus::Failure(e) => return Err(anyhow!("publish failed: {}", e)),
+                    OperationStatus::Success => {}
+                }
+                let family = instance.map(|i| i.image_family).unwrap_or_default();
+                let image = CatalogImage {
+                    name: build.name.clone(),
+                    family,
+                    sources: [(
+                        build.runtime.to_string(),
+                        format!("{}{}", LOCAL_IMAGE_PREFIX, get_alias(build)),
+                    )]
+                    .into_iter()
+                    .collect(),
+                    arches: vec![Arch::Amd64],
+                    enabled: true,
+                    default: false,
+                    owner: Some(build.username.clone()),
+                    mounts: Vec::new(),
+                };
+                info!(
+                    username = build.username.as_str(),
+                    image = build.name.to_string().as_str(),
+                    "image built"
+                );
+                self.update_build(build, |b, state| {
+                    match state.images.iter_mut().find(|i| i.name == b.name) {
+                        Some(i) => *i = image.clone(),
+                        None => state.images.push(image.clone()),
+                    }
+                    b.status = ImageBuildStatus::Succeeded;
+                    b.operation = None;
+                    b.add_event("image published".to_owned());
+                    delete_builder_instance(state, b);
+                })
+                .await;
+            }
+            ImageBuildStatus::Succeeded | ImageBuildStatus::Failed(_) => {}
+        }
+        Ok(())
+    }
+
+    /// Runs the script on the instance once cloud-init is done, returning the operation.
+    async fn exec_script(&self, name: &str, script: &str) -> Result<String> {
+        let url = api_url(&format!(
+            "/instances/{}/exec?project={}",
+            name,
+            LXD_PROJECT.as_str()
+        ));
+        // The script is passed as $0 of the outer shell to avoid quoting it.
+        let res: serde_json::Value = self
+            .client
+            .post(url)
+            .json(&serde_json::json!({
+                "command": [
+                    "/bin/sh",
+                    "-c",
+                    "cloud-init status --wait >/dev/null 2>&1; exec /bin/sh -ec \"$0\"",
+                    script
+                ],
+                "wait-for-websocket": false,
+                "interactive": false,
+                "record-output": true
+            }))
+            .send()
+            .await?
+            .json()
+            .await?;
+        check_error(&res)?;
+        parse_operation(&res)
+    }
+
+    /// Returns the last lines of the recorded stdout and stderr of the script.
+    async fn get_script_output(&self, metadata: &serde_json::Value) -> Vec<String> {
+        // The metadata is like:
+        // {
+        //   "return": 0,
+        //   "output": {
+        //     "1": "/1.0/instances/alice-build-dev/logs/exec_b0f3.stdout",
+        //     "2": "/1.0/instances/alice-build-dev/logs/exec_b0f3.stderr"
+        //   }
+        // }
+        let mut lines = Vec::new();
+        for fd in ["1", "2"] {
+            let path = match metadata
+                .get("output")
+                .and_then(|o| o.get(fd))
+                .and_then(|p| p.as_str())
+            {
+                Some(path) => path,
+                None => continue,
+            };
+            let url = api_url(&format!(
+                "{}?project={}",
+                path.trim_start_matches("/1.0"),
+                LXD_PROJECT.as_str()
+            ));
+            match self.client.get(url).send().await {
+                Ok(res) => match res.text().await {
+                    Ok(text) => lines.extend(text.lines().map(|l| l.to_owned())),
+                    Err(e) => warn!(error = e.to_string().as_str(), "read script output failed"),
+                },
+                Err(e) => warn!(error = e.to_string().as_str(), "get script output failed"),
+            }
+        }
+        let skip = lines.len().saturating_sub(MAX_OUTPUT_LINES);
+        lines.split_off(skip)
+    }
+
+    /// Publishes the root filesystem of the stopped instance, returning the operation.
+    async fn publish_image(&self, name: &str, build: &ImageBuild) -> Result<String> {
+        let alias = get_alias(build);
+        // Rebuilding an image moves the alias to the new image. The previous image is kept as
+        // instances created from it may still be refreshed from it.
+        let url = api_url(&format!(
+            "/images/aliases/{}?project={}",
+            alias,
+            LXD_PROJECT.as_str()
+        ));
+        self.client.delete(url).send().await?;
+
+        let url = api_url(&format!("/images?project={}", LXD_PROJECT.as_str()));
+        let res: serde_json::Value = self
+            .client
+            .post(url)
+            .json(&serde_json::json!({
+                "source": {
+                    "type": "instance",
+                    "name": name
+                },
+                "aliases": [{
+                    "name": alias,
+                    "description": format!("built from {}", build.base_image)
+                }],
+                "public": false
+            }))
+            .send()
+            .await?
+            .json()
+            .await?;
+        check_error(&res)?;
+        parse_operation(&res)
+    }
+
+    async fn get_operation(&self, operation: &str) -> Result<serde_json::Value> {
+        let url = api_url(operation.trim_start_matches("/1.0"));
+        let res: serde_json::Value = self.client.get(url).send().await?.json().await?;
+        check_error(&res)?;
+        Ok(res)
+    }
+
+    async fn update_build<F>(&self, build: &ImageBuild, mut f: F)
+    where
+        F: FnMut(&mut ImageBuild, &mut State),
+    {
+        let res = self
+            .storage
+            .read_write(|state| {
+                let mut builds = std::mem::take(&mut state.image_builds);
+                if let Some(b) = builds
+                    .iter_mut()
+                    .find(|b| b.name == build.name && b.username == build.username)
+                {
+                    f(b, state);
+                }
+                state.image_builds = builds;
+                true
+            })
+            .await;
+        if let Err(e) = res {
+            warn!(
+                username = build.username.as_str(),
+                image = build.name.to_string().as_str(),
+                error = e.to_string().as_str(),
+                "update image build encountered error"
+            );
+        }
+    }
+}
+
+/// Returns the alias of the image on the LXD server.
+fn get_alias(build: &ImageBuild) -> String {
+    format!("tispace/{}", build.name)
+}
+
+/// Lets the operator delete the builder instance of the build, if any.
+fn delete_builder_instance(state: &mut State, build: &ImageBuild) {
+    if let Some(i) = state
+        .find_mut_user(&build.username)
+        .and_then(|u| u.find_mut_instance(&build.instance_name))
+    {
+        let _ = lifecycle::apply(i, Action::Delete);
+    }
+}
@@ -0,0 +1,62 @@
+use std::collections::HashMap;
+use std::sync::{Arc, Mutex};
+
+use once_cell::sync::Lazy;
+use tokio::sync::{Mutex as AsyncMutex, OwnedMutexGuard};
+
+// The owner and the name of an instance.
+type Key = (String, String);
+
+// Locks of the instances held or waited for, the others are dropped.
+static LOCKS: Lazy<Mutex<HashMap<Key, Arc<AsyncMutex<()>>>>> = Lazy::new(Default::default);
+
+/// A held lock of an instance, so that the scheduler and the operators never interleave their
+/// operations on the same instance. The lock is released when dropped.
+crate struct InstanceLock {
+    key: Key,
+    guard: Option<OwnedMutexGuard<()>>,
+}
+
+impl Drop for InstanceLock {
+    fn drop(&mut self) {
+        let mut locks = LOCKS.lock().unwrap();
+        self.guard.take();
+        // Only the map refers to the lock if nobody else holds or waits for it.
+        if locks
+            .get(&self.key)
+            .map_or(false, |l| Arc::strong_count(l) == 1)
+        {
+            locks.remove(&self.key);
+        }
+    }
+}
+
+fn entry(username: &str, name: &str) -> (Key, Arc<AsyncMutex<()>>) {
+    let key = (username.to_owned(), name.to_owned());
+    let lock = LOCKS
+        .lock()
+        .unwrap()
+        .entry(key.clone())
+        .or_default()
+        .clone();
+    (key, lock)
+}
+
+/// Waits for the lock of the instance of the user.
+crate async fn lock(username: &str, name: &str) -> InstanceLock {
+    let (key, lock) = entry(username, name);
+    InstanceLock {
+        key,
+        guard: Some(lock.lock_owned().await),
+    }
+}
+
+/// Takes the lock of the instance of the user, or returns None if it's held by another worker.
+crate fn try_lock(username: &str, name: &str) -> Option<InstanceLock> {
+    let (key, lock) = entry(username, name);
+    let guard = lock.try_lock_owned().ok()?;
+    Some(InstanceLock {
+        key,
+        guard: Some(guard),
+    })
+}
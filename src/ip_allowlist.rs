@@ -0,0 +1,75 @@
+use std::future::Future;
+use std::net::{IpAddr, Ipv4Addr, SocketAddr};
+use std::pin::Pin;
+use std::task::{Context, Poll};
+
+use axum::body::Body;
+use axum::extract::ConnectInfo;
+use axum::http::Request;
+use axum::response::{IntoResponse, Response};
+use tower::{Layer, Service};
+
+use crate::config::{self, Config};
+use crate::error::IpForbidden;
+
+/// Layer rejecting requests from addresses the config doesn't allow, e.g. `Config::api_allowed`.
+///
+/// The config is consulted on every request, so that reloads take effect right away. The address
+/// is the peer of the connection, so a proxy in front of the server must be allowed as a whole.
+#[derive(Clone, Copy)]
+crate struct IpAllowlistLayer {
+    allowed: fn(&Config, &IpAddr) -> bool,
+}
+
+impl IpAllowlistLayer {
+    crate fn new(allowed: fn(&Config, &IpAddr) -> bool) -> Self {
+        IpAllowlistLayer { allowed }
+    }
+}
+
+impl<S> Layer<S> for IpAllowlistLayer {
+    type Service = IpAllowlist<S>;
+
+    fn layer(&self, inner: S) -> Self::Service {
+        IpAllowlist {
+            inner,
+            allowed: self.allowed,
+        }
+    }
+}
+
+#[derive(Clone)]
+crate struct IpAllowlist<S> {
+    inner: S,
+    allowed: fn(&Config, &IpAddr) -> bool,
+}
+
+impl<S> Service<Request<Body>> for IpAllowlist<S>
+where
+    S: Service<Request<Body>, Response = Response> + Clone + Send + 'static,
+    S::Future: Send + 'static,
+{
+    type Response = Response;
+    type Error = S::Error;
+    #[allow(clippy::type_complexity)]
+    type Future = Pin<Box<dyn Future<Output = Result<Self::Response, Self::Error>> + Send>>;
+
+    fn poll_ready(&mut self, cx: &mut Context<'_>) -> Poll<Result<(), Self::Error>> {
+        self.inner.poll_ready(cx)
+    }
+
+    fn call(&mut self, req: Request<Body>) -> Self::Future {
+        // Only an empty allowlist lets requests of unknown peers through.
+        let ip = req
+            .extensions()
+            .get::<ConnectInfo<SocketAddr>>()
+            .map_or(IpAddr::V4(Ipv4Addr::UNSPECIFIED), |c| c.0.ip());
+        if !(self.allowed)(&config::current(), &ip) {
+            return Box::pin(async move { Ok(IpForbidden { ip }.into_response()) });
+        }
+        // The inner service is ready, use it and leave the clone for the next call.
+        let clone = self.inner.clone();
+        let mut inner = std::mem::replace(&mut self.inner, clone);
+        Box::pin(async move { inner.call(req).await })
+    }
+}
@@ -0,0 +1,206 @@
+//! Leader election between replicas of the server.
+//!
+//! Replicas compete for a Kubernetes Lease and only the holder runs the operators, the scheduler
+//! and the collector. The others serve read-only API traffic from the state file, which must be
+//! on a volume shared by all replicas, and reject requests changing the state.
+
+use std::sync::atomic::{AtomicBool, Ordering};
+
+use anyhow::Result;
+use axum::async_trait;
+use axum::extract::{FromRequest, RequestParts};
+use chrono::Utc;
+use k8s_openapi::api::coordination::v1::{Lease, LeaseSpec};
+use k8s_openapi::apimachinery::pkg::apis::meta::v1::{MicroTime, ObjectMeta};
+use kube::api::PostParams;
+use kube::{Api, Client};
+use tokio::time::{Duration, Instant};
+use tracing::{error, info, warn};
+
+use crate::env::{LEADER_ELECTION, LEADER_ELECTION_IDENTITY};
+use crate::error::NotLeader;
+use crate::operator_k8s::NAMESPACE;
+use crate::shutdown;
+
+const LEASE_NAME: &str = "tispace-leader";
+// Seconds a lease is valid for without being renewed.
+const LEASE_DURATION: i32 = 15;
+
+// Replicas are leaders unless leader election is enabled.
+static IS_LEADER: AtomicBool = AtomicBool::new(true);
+
+crate fn is_leader() -> bool {
+    IS_LEADER.load(Ordering::SeqCst)
+}
+
+/// Extractor rejecting the request unless this replica is the leader.
+///
+/// Handlers changing the state take it, as only the leader may write the state file.
+crate struct Leader;
+
+#[async_trait]
+impl<B> FromRequest<B> for Leader
+where
+    B: Send,
+{
+    type Rejection = NotLeader;
+
+    async fn from_request(_req: &mut RequestParts<B>) -> Result<Self, Self::Rejection> {
+        if is_leader() {
+            Ok(Leader)
+        } else {
+            Err(NotLeader)
+        }
+    }
+}
+
+pub struct LeaderElector {
+    api: Api<Lease>,
+    identity: String,
+}
+
+impl LeaderElector {
+    /// Returns None if leader election is disabled.
+    pub async fn new() -> Result<Option<Self>> {
+        if !*LEADER_ELECTION {
+            return Ok(None);
+        }
+        IS_LEADER.store(false, Ordering::SeqCst);
+        let client = Client::try_default().await?;
+        Ok(Some(LeaderElector {
+            api: Api::namespaced(client, NAMESPACE),
+            identity: LEADER_ELECTION_IDENTITY.clone(),
+        }))
+    }
+
+    /// Waits until the lease is acquired. Returns false if shutdown is triggered before that.
+    pub async fn acquire(&self) -> bool {
+        while !shutdown::is_triggered() {
+            match self.try_acquire_or_renew().await {
+                Ok(true) => {
+                    info!(identity = self.identity.as_str(), "became leader");
+                    IS_LEADER.store(true, Ordering::SeqCst);
+                    return true;
+                }
+                Ok(false) => {}
+                Err(e) => warn!(
+                    identity = self.identity.as_str(),
+                    error = e.to_string().as_str(),
+                    "acquire lease encountered error"
+                ),
+            }
+            shutdown::sleep(Duration::from_secs(LEASE_DURATION as u64 / 3)).await;
+        }
+        false
+    }
+
+    /// Keeps renewing the acquired lease until shutdown is triggered. Exits the process if the
+    /// lease is lost.
+    pub async fn renew(&self) {
+        let mut renewed_at = Instant::now();
+        while !shutdown::is_triggered() {
+            shutdown::sleep(Duration::from_secs(LEASE_DURATION as u64 / 3)).await;
+            if shutdown::is_triggered() {
+                break;
+            }
+            match self.try_acquire_or_renew().await {
+                Ok(true) => renewed_at = Instant::now(),
+                Ok(false) => break,
+                Err(e) => warn!(
+                    identity = self.identity.as_str(),
+                    error = e.to_string().as_str(),
+                    "renew lease encountered error"
+                ),
+            }
+            if renewed_at.elapsed() >= Duration::from_secs(LEASE_DURATION as u64) {
+                break;
+            }
+        }
+        if shutdown::is_triggered() {
+            return;
+        }
+        // Another replica may already be running the operators, exit to avoid racing with it.
+        // The replica becomes a follower after being restarted.
+        error!(
+            identity = self.identity.as_str(),
+            "lost leadership, exiting"
+        );
+        std::process::exit(1);
+    }
+
+    async fn try_acquire_or_renew(&self) -> Result<bool> {
+        let now = MicroTime(Utc::now());
+        let mut lease = match self.api.get(LEASE_NAME).await {
+            Ok(lease) => lease,
+            Err(kube::Error::Api(e)) if e.code == 404 => {
+                let lease = Lease {
+                    metadata: ObjectMeta {
+                        name: Some(LEASE_NAME.to_owned()),
+                        namespace: Some(NAMESPACE.to_owned()),
+                        ..Default::default()
+                    },
+                    spec: Some(LeaseSpec {
+                        holder_identity: Some(self.identity.clone()),
+                        lease_duration_seconds: Some(LEASE_DURATION),
+                        acquire_time: Some(now.clone()),
+                        renew_time: Some(now),
+                        lease_transitions: Some(0),
+                    }),
+                };
+                return match self.api.create(&PostParams::default(), &lease).await {
+                    Ok(_) => Ok(true),
+                    // Another replica created it first.
+                    Err(kube::Error::Api(e)) if e.code == 409 => Ok(false),
+                    Err(e) => Err(e.into()),
+                };
+            }
+            Err(e) => return Err(e.into()),
+        };
+
+        let spec = lease.spec.get_or_insert_with(Default::default);
+        let held = spec.holder_identity.as_deref() == Some(self.identity.as_str());
+        let expired = match (&spec.holder_identity, &spec.renew_time) {
+            (Some(_), Some(renew_time)) => {
+                let duration = spec.lease_duration_seconds.unwrap_or(LEASE_DURATION);
+                renew_time.0 + chrono::Duration::seconds(duration as i64) < now.0
+            }
+            _ => true,
+        };
+        if !held && !expired {
+            return Ok(false);
+        }
+        if !held {
+            spec.holder_identity = Some(self.identity.clone());
+            spec.acquire_time = Some(now.clone());
+            spec.lease_transitions = Some(spec.lease_transitions.unwrap_or(0) + 1);
+        }
+        spec.lease_duration_seconds = Some(LEASE_DURATION);
+        spec.renew_time = Some(now);
+        // The resource version of the lease makes the update fail if another replica updated it
+        // in the meantime.
+        match self
+            .api
+            .replace(LEASE_NAME, &PostParams::default(), &lease)
+            .await
+        {
+            Ok(_) => Ok(true),
+            Err(kube::Error::Api(e)) if e.code == 409 => Ok(false),
+            Err(e) => Err(e.into()),
+        }
+    }
+
+    /// Gives up the lease so that another replica takes over without waiting for it to expire.
+    pub async fn release(&self) -> Result<()> {
+        let mut lease = self.api.get(LEASE_NAME).await?;
+        if let Some(spec) = lease.spec.as_mut() {
+            if spec.holder_identity.as_deref() == Some(self.identity.as_str()) {
+                spec.holder_identity = None;
+                self.api
+                    .replace(LEASE_NAME, &PostParams::default(), &lease)
+                    .await?;
+                info!(identity = self.identity.as_str(), "released lease");
+            }
+        }
+        Ok(())
+    }
+}
@@ -2,13 +2,38 @@
 #![feature(crate_visibility_modifier)]
 
 pub mod auth;
+mod aws;
+pub mod backup;
 pub mod collector;
+pub mod config;
+pub mod dns;
 mod dto;
 pub mod env;
 pub mod error;
+mod files;
+pub mod gc;
+pub mod image_builder;
+mod instance_lock;
+mod ip_allowlist;
+pub mod leader;
+mod lifecycle;
+pub mod maintenance;
+pub mod metering;
+pub mod metrics;
 mod model;
+pub mod operator_ec2;
 pub mod operator_k8s;
 pub mod operator_lxd;
+pub mod operator_microvm;
+pub mod operator_oci;
+pub mod power_scheduler;
+pub mod prepull;
+mod rate_limit;
+mod reconcile;
+pub mod recycle_bin;
+pub mod request_id;
+mod s3;
 pub mod scheduler;
 pub mod service;
+pub mod shutdown;
 pub mod storage;
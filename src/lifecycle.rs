@@ -0,0 +1,281 @@
+use tracing::warn;
+
+use crate::error::InstanceError;
+use crate::model::{Instance, InstanceStage, InstanceStatus, Runtime};
+
+/// Actions users, or the schedulers on their behalf, take on instances.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+crate enum Action {
+    Start,
+    Stop,
+    Delete,
+    Convert,
+}
+
+/// Returns whether no action may be taken on instances in the stage, which are only waiting for
+/// their backends to be torn down.
+crate fn is_terminal(stage: &InstanceStage) -> bool {
+    *stage == InstanceStage::Deleted
+}
+
+/// Returns whether the instance may be in the status while in the stage. Running instances may
+/// be stopped by their backends, which is told apart from stops the users asked for.
+crate fn is_consistent(stage: &InstanceStage, status: &InstanceStatus) -> bool {
+    use InstanceStatus::*;
+    match stage {
+        InstanceStage::Running => matches!(
+            status,
+            Creating | Starting | Running | Stopping | Stopped | Missing | Error(_)
+        ),
+        InstanceStage::Stopped => matches!(status, Stopping | Stopped | Missing | Error(_)),
+        InstanceStage::Deleted => matches!(status, Stopping | Deleting | Error(_)),
+    }
+}
+
+/// Returns whether the backend may move the instance from a status to another while the stage
+/// stays the same. Moving to another stage is up to the actions.
+crate fn is_valid_transition(
+    stage: &InstanceStage,
+    from: &InstanceStatus,
+    to: &InstanceStatus,
+) -> bool {
+    use InstanceStatus::*;
+    if !is_consistent(stage, to) {
+        return false;
+    }
+    match (from, to) {
+        _ if from == to => true,
+        // Backends fail or lose instances at any time, and may recover from it.
+        (_, Error(_) | Missing) | (Error(_) | Missing, _) => true,
+        (Creating, Starting | Running) | (Starting, Running) => true,
+        (Running, Stopping | Stopped) | (Stopped, Starting | Running) => true,
+        (Stopping, Stopped | Deleting) => true,
+        _ => false,
+    }
+}
+
+/// Returns the status of instances on their way to the stage.
+fn transitional_status(stage: &InstanceStage, runtime: &Runtime) -> InstanceStatus {
+    match stage {
+        InstanceStage::Running => InstanceStatus::Starting,
+        InstanceStage::Stopped => InstanceStatus::Stopping,
+        // Containers on Kubernetes and EC2 instances are deleted right away, the others are
+        // stopped first.
+        InstanceStage::Deleted => match runtime {
+            Runtime::Kata | Runtime::Runc | Runtime::Ec2 => InstanceStatus::Deleting,
+            Runtime::Lxc | Runtime::Kvm | Runtime::MicroVm | Runtime::Oci => {
+                InstanceStatus::Stopping
+            }
+        },
+    }
+}
+
+/// Checks whether the action may be taken on the instance in its current state.
+crate fn check(instance: &Instance, action: Action) -> Result<(), InstanceError> {
+    if is_terminal(&instance.stage) {
+        return Err(InstanceError::AlreadyDeleted);
+    }
+    // Instances in the recycle bin are only restored or deleted for good.
+    if instance.trashed_at.is_some() && action != Action::Delete {
+        return Err(InstanceError::InRecycleBin);
+    }
+    match action {
+        Action::Convert if instance.status != InstanceStatus::Stopped => {
+            Err(InstanceError::NotYetStopped)
+        }
+        _ => Ok(()),
+    }
+}
+
+/// Takes the action on the instance, moving it to the stage the action asks for and to the
+/// status on the way there. Returns false if the instance is already in that stage.
+crate fn apply(instance: &mut Instance, action: Action) -> Result<bool, InstanceError> {
+    check(instance, action)?;
+    let (stage, status) = match action {
+        Action::Start | Action::Stop | Action::Delete => {
+            let stage = match action {
+                Action::Start => InstanceStage::Running,
+                Action::Stop => InstanceStage::Stopped,
+                _ => InstanceStage::Deleted,
+            };
+            if instance.stage == stage {
+                return Ok(false);
+            }
+            let status = transitional_status(&stage, &instance.runtime);
+            (stage, status)
+        }
+        // A converted instance is created again by its new runtime.
+        Action::Convert => (InstanceStage::Running, InstanceStatus::Creating),
+    };
+    instance.stage = stage;
+    instance.status = status;
+    Ok(true)
+}
+
+/// Deletes the instance on behalf of its users. If deleted instances are retained for `retention`
+/// hours, the instance is moved to the recycle bin instead, stopped and kept along with its disk
+/// until it's restored or purged. Deleting an instance in the recycle bin deletes it for good.
+crate fn delete(instance: &mut Instance, retention: u64, now: i64) -> Result<bool, InstanceError> {
+    if retention == 0 || instance.trashed_at.is_some() {
+        return apply(instance, Action::Delete);
+    }
+    apply(instance, Action::Stop)?;
+    instance.trashed_at = Some(now);
+    instance.add_event(format!("moved to the recycle bin for {} hours", retention));
+    Ok(true)
+}
+
+/// Moves the instance to the status observed by its backend, unless the stage of the instance
+/// doesn't allow it, e.g. the instance was stopped meanwhile. Returns whether the status changed.
+crate fn observe(instance: &mut Instance, status: InstanceStatus) -> bool {
+    if instance.status == status {
+        return false;
+    }
+    if !is_valid_transition(&instance.stage, &instance.status, &status) {
+        warn!(
+            instance = instance.name.as_str(),
+            stage = instance.stage.to_string().as_str(),
+            from = instance.status.to_string().as_str(),
+            to = status.to_string().as_str(),
+            "ignored invalid status transition"
+        );
+        return false;
+    }
+    instance.status = status;
+    true
+}
+
+/// Moves the instance to be running to the status its backend reports, `Running` or `Stopped` as
+/// the LXD, micro-VM and OCI backends name them. A created instance reported stopped is on its way
+/// to be started, while a running one was shut down by its guest or its backend, and is started
+/// again as its stage asks for. Returns whether the status changed.
+crate fn observe_running(instance: &mut Instance, status: &str) -> bool {
+    match (status, &instance.status) {
+        ("Stopped", InstanceStatus::Creating) => observe(instance, InstanceStatus::Starting),
+        ("Stopped", InstanceStatus::Running) => observe(instance, InstanceStatus::Stopped),
+        ("Running", _) => observe(instance, InstanceStatus::Running),
+        _ => false,
+    }
+}
+
+/// Moves the instance back to the status on the way to its stage if the status contradicts the
+/// stage, so that its backend settles it. Returns whether the instance was repaired.
+crate fn repair(instance: &mut Instance) -> bool {
+    if is_consistent(&instance.stage, &instance.status) {
+        return false;
+    }
+    let status = transitional_status(&instance.stage, &instance.runtime);
+    warn!(
+        instance = instance.name.as_str(),
+        stage = instance.stage.to_string().as_str(),
+        from = instance.status.to_string().as_str(),
+        to = status.to_string().as_str(),
+        "repaired inconsistent instance status"
+    );
+    instance.status = status;
+    true
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn new_instance(stage: InstanceStage, status: InstanceStatus) -> Instance {
+        let mut instance: Instance = serde_json::from_value(serde_json::json!({
+            "name": "a",
+            "cpu": 2,
+            "memory": 4,
+            "disk_size": 10,
+            "image": "ubuntu:22.04",
+            "hostname": "a",
+            "password": "",
+            "stage": "Running",
+            "status": "Running",
+            "runtime": "lxc",
+        }))
+        .unwrap();
+        instance.stage = stage;
+        instance.status = status;
+        instance
+    }
+
+    #[test]
+    fn test_lifecycle_transitions() {
+        use InstanceStage::{Deleted, Running as Up, Stopped as Down};
+        use InstanceStatus::*;
+        let cases = [
+            (Up, Creating, Starting, true),
+            (Up, Creating, Running, true),
+            (Up, Starting, Running, true),
+            (Up, Running, Starting, false),
+            (Up, Running, Creating, false),
+            // The backend stops the instance, and starts it again.
+            (Up, Running, Stopped, true),
+            (Up, Stopped, Starting, true),
+            (Up, Stopped, Running, true),
+            (Up, Running, Deleting, false),
+            (Down, Stopping, Stopped, true),
+            (Down, Stopped, Running, false),
+            (Down, Stopped, Starting, false),
+            (Deleted, Stopping, Deleting, true),
+            (Deleted, Deleting, Stopped, false),
+            (Deleted, Running, Missing, false),
+            (Up, Running, Missing, true),
+            (Up, Missing, Running, true),
+            (Down, Error("oom".to_owned()), Stopped, true),
+        ];
+        for (stage, from, to, valid) in cases {
+            assert_eq!(
+                is_valid_transition(&stage, &from, &to),
+                valid,
+                "{:?}: {:?} -> {:?}",
+                stage,
+                from,
+                to
+            );
+        }
+    }
+
+    #[test]
+    fn test_lifecycle_repair() {
+        use InstanceStage::{Deleted, Running as Up, Stopped as Down};
+        use InstanceStatus::*;
+        let cases = [
+            (Up, Running, Running),
+            // Stopped by the backend, which is reported as such instead of repaired.
+            (Up, Stopped, Stopped),
+            (Down, Running, Stopping),
+            (Down, Creating, Stopping),
+            (Deleted, Running, Deleting),
+            (Deleted, Stopped, Deleting),
+        ];
+        for (stage, status, repaired) in cases {
+            let mut instance = new_instance(stage.clone(), status.clone());
+            assert_eq!(repair(&mut instance), status != repaired);
+            assert_eq!(instance.status, repaired, "{:?}: {:?}", stage, status);
+        }
+        let instance = new_instance(Up, Stopped);
+        assert_eq!(instance.status_reason(), Some("StoppedByBackend"));
+    }
+
+    #[test]
+    fn test_observe_running() {
+        use InstanceStage::Running as Up;
+        use InstanceStatus::*;
+        let cases = [
+            (Creating, "Stopped", Starting),
+            (Creating, "Running", Running),
+            (Starting, "Stopped", Starting),
+            (Starting, "Running", Running),
+            // Shut down by the guest, and started again.
+            (Running, "Stopped", Stopped),
+            (Stopped, "Running", Running),
+            (Running, "Unknown", Running),
+        ];
+        for (status, reported, observed) in cases {
+            let mut instance = new_instance(Up, status.clone());
+            assert_eq!(observe_running(&mut instance, reported), status != observed);
+            assert_eq!(instance.status, observed, "{:?}: {}", status, reported);
+        }
+    }
+}
@@ -0,0 +1,86 @@
+use chrono::{TimeZone, Utc};
+use tokio::time::Duration;
+use tracing::{info, instrument, warn};
+
+use crate::config;
+use crate::model::InstanceStage;
+use crate::shutdown;
+use crate::storage::Storage;
+
+/// Warns the owners of instances ahead of the maintenance windows of their nodes, through the
+/// events of the instances, and forgets the windows which are over.
+pub struct MaintenanceNotifier {
+    storage: Storage,
+}
+
+impl MaintenanceNotifier {
+    pub fn new(storage: Storage) -> Self {
+        MaintenanceNotifier { storage }
+    }
+
+    pub async fn run(&self) {
+        while !shutdown::is_triggered() {
+            self.run_once().await;
+            shutdown::sleep(Duration::from_secs(60)).await;
+        }
+    }
+
+    #[instrument(skip_all)]
+    async fn run_once(&self) {
+        let now = Utc::now().timestamp();
+        let notice = config::current().maintenance_notice as i64 * 3600;
+        let res = self
+            .storage
+            .read_write(|state| {
+                let mut changed = false;
+                let mut warnings = Vec::new();
+                for n in &mut state.nodes {
+                    let len = n.maintenance_windows.len();
+                    n.maintenance_windows.retain(|w| w.end > now);
+                    changed |= n.maintenance_windows.len() != len;
+                    for w in &mut n.maintenance_windows {
+                        if w.notified || w.start - notice > now {
+                            continue;
+                        }
+                        w.notified = true;
+                        changed = true;
+                        info!(
+                            node = n.name.as_str(),
+                            start = w.start,
+                            end = w.end,
+                            "warning of maintenance window"
+                        );
+                        warnings.push((
+                            n.name.clone(),
+                            format!(
+                                "node {} is under maintenance from {} to {}: {}",
+                                n.name,
+                                Utc.timestamp(w.start, 0).to_rfc3339(),
+                                Utc.timestamp(w.end, 0).to_rfc3339(),
+                                w.reason
+                            ),
+                        ));
+                    }
+                }
+                for (node_name, message) in warnings {
+                    for i in state
+                        .users
+                        .iter_mut()
+                        .flat_map(|u| &mut u.instances)
+                        .filter(|i| i.node_name.as_ref() == Some(&node_name))
+                        .filter(|i| i.stage != InstanceStage::Deleted)
+                    {
+                        i.add_event(message.clone());
+                    }
+                }
+                changed
+            })
+            .await;
+        if let Err(e) = res {
+            warn!(
+                error = e.to_string().as_str(),
+                "notify maintenance windows encountered error"
+            );
+        }
+    }
+}
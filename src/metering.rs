@@ -0,0 +1,67 @@
+use chrono::Utc;
+use tokio::time::{Duration, Instant};
+use tracing::{instrument, warn};
+
+use crate::config;
+use crate::shutdown;
+use crate::storage::Storage;
+
+/// Records the resources consumed by running instances into the usage ledger of the state, and
+/// forgets the usage older than `Config::usage_retention`.
+pub struct Meter {
+    storage: Storage,
+}
+
+impl Meter {
+    pub fn new(storage: Storage) -> Self {
+        Meter { storage }
+    }
+
+    pub async fn run(&self) {
+        // Time is metered from the start of the loop rather than from the last record in the
+        // ledger, so that downtime of the server is not charged.
+        let mut last_metered = Instant::now();
+        while !shutdown::is_triggered() {
+            shutdown::sleep(Duration::from_secs(60)).await;
+            let now = Instant::now();
+            let seconds = now.duration_since(last_metered).as_secs();
+            if seconds == 0 {
+                continue;
+            }
+            if self.run_once(seconds).await {
+                // Keep the remainder of the second, it's metered next time.
+                last_metered += Duration::from_secs(seconds);
+            }
+        }
+    }
+
+    #[instrument(skip(self))]
+    async fn run_once(&self, seconds: u64) -> bool {
+        let now = Utc::now();
+        let date = now.format("%Y-%m-%d").to_string();
+        let retention = config::current().usage_retention;
+        let oldest = (now - chrono::Duration::days(retention as i64))
+            .format("%Y-%m-%d")
+            .to_string();
+        let res = self
+            .storage
+            .read_write(|state| {
+                state.meter_usage(&date, seconds);
+                if retention > 0 {
+                    state.prune_usage(&oldest);
+                }
+                true
+            })
+            .await;
+        match res {
+            Ok(()) => true,
+            Err(e) => {
+                warn!(
+                    error = e.to_string().as_str(),
+                    "meter usage encountered error"
+                );
+                false
+            }
+        }
+    }
+}
@@ -0,0 +1,475 @@
+use std::future::Future;
+use std::pin::Pin;
+use std::sync::Mutex;
+use std::task::{Context, Poll};
+use std::time::Instant;
+
+use chrono::Utc;
+use http::{Request, Response};
+use once_cell::sync::Lazy;
+use prometheus::core::Collector;
+use prometheus::{
+    Encoder, GaugeVec, HistogramOpts, HistogramVec, IntCounter, IntCounterVec, Opts, Registry,
+    TextEncoder,
+};
+
+use tower::{Layer, Service};
+
+use crate::model::State;
+use crate::service::ApiVersion;
+
+// All metrics are registered once into this registry and exported by `/metrics`.
+static REGISTRY: Lazy<Registry> = Lazy::new(Registry::new);
+
+fn register<C: Collector + Clone + 'static>(c: C) -> C {
+    REGISTRY.register(Box::new(c.clone())).unwrap();
+    c
+}
+
+static CPU_ALLOCATED: Lazy<GaugeVec> = Lazy::new(|| {
+    register(
+        GaugeVec::new(
+            Opts::new("cpu_allocated", "Total cpu allocated").namespace("tispace"),
+            &["node_name"],
+        )
+        .unwrap(),
+    )
+});
+
+static MEMORY_ALLOCATED: Lazy<GaugeVec> = Lazy::new(|| {
+    register(
+        GaugeVec::new(
+            Opts::new("memory_allocated", "Total memory allocated").namespace("tispace"),
+            &["node_name"],
+        )
+        .unwrap(),
+    )
+});
+
+static STORAGE_TOTAL: Lazy<GaugeVec> = Lazy::new(|| {
+    register(
+        GaugeVec::new(
+            Opts::new("storage_total", "Total storage").namespace("tispace"),
+            &["node_name", "storage_pool"],
+        )
+        .unwrap(),
+    )
+});
+
+static STORAGE_ALLOCATED: Lazy<GaugeVec> = Lazy::new(|| {
+    register(
+        GaugeVec::new(
+            Opts::new("storage_allocated", "Total storage allocated").namespace("tispace"),
+            &["node_name", "storage_pool"],
+        )
+        .unwrap(),
+    )
+});
+
+static STORAGE_USED: Lazy<GaugeVec> = Lazy::new(|| {
+    register(
+        GaugeVec::new(
+            Opts::new("storage_used", "Total storage used").namespace("tispace"),
+            &["node_name", "storage_pool"],
+        )
+        .unwrap(),
+    )
+});
+
+static INSTANCE_DISK_USED: Lazy<GaugeVec> = Lazy::new(|| {
+    register(
+        GaugeVec::new(
+            Opts::new("instance_disk_used", "Disk used by instance").namespace("tispace"),
+            &["username", "instance", "node_name", "storage_pool"],
+        )
+        .unwrap(),
+    )
+});
+
+static INSTANCE_UPTIME: Lazy<GaugeVec> = Lazy::new(|| {
+    register(
+        GaugeVec::new(
+            Opts::new(
+                "instance_uptime_seconds",
+                "Total seconds the instance has been running",
+            )
+            .namespace("tispace"),
+            &["username", "instance"],
+        )
+        .unwrap(),
+    )
+});
+
+static INSTANCE_STATUS: Lazy<GaugeVec> = Lazy::new(|| {
+    register(
+        GaugeVec::new(
+            Opts::new("instance_status", "Instance status").namespace("tispace"),
+            &["node_name", "storage_pool", "runtime", "status"],
+        )
+        .unwrap(),
+    )
+});
+
+static USER_CPU_ALLOCATED: Lazy<GaugeVec> = Lazy::new(|| {
+    register(
+        GaugeVec::new(
+            Opts::new("user_cpu_allocated", "Total cpu allocated by user").namespace("tispace"),
+            &["username"],
+        )
+        .unwrap(),
+    )
+});
+
+static USER_MEMORY_ALLOCATED: Lazy<GaugeVec> = Lazy::new(|| {
+    register(
+        GaugeVec::new(
+            Opts::new("user_memory_allocated", "Total memory allocated by user")
+                .namespace("tispace"),
+            &["username"],
+        )
+        .unwrap(),
+    )
+});
+
+static USER_DISK_ALLOCATED: Lazy<GaugeVec> = Lazy::new(|| {
+    register(
+        GaugeVec::new(
+            Opts::new("user_disk_allocated", "Total disk allocated by user").namespace("tispace"),
+            &["username"],
+        )
+        .unwrap(),
+    )
+});
+
+static USER_INSTANCE_COUNT: Lazy<GaugeVec> = Lazy::new(|| {
+    register(
+        GaugeVec::new(
+            Opts::new("user_instance_count", "Number of instances owned by user")
+                .namespace("tispace"),
+            &["username"],
+        )
+        .unwrap(),
+    )
+});
+
+crate static ORPHANED_RESOURCES: Lazy<GaugeVec> = Lazy::new(|| {
+    register(
+        GaugeVec::new(
+            Opts::new(
+                "orphaned_resources",
+                "Backend resources without an instance, as found by the last garbage collection",
+            )
+            .namespace("tispace"),
+            &["backend", "kind"],
+        )
+        .unwrap(),
+    )
+});
+
+crate static RECONCILE_DURATION: Lazy<HistogramVec> = Lazy::new(|| {
+    register(
+        HistogramVec::new(
+            HistogramOpts::new(
+                "reconcile_duration_seconds",
+                "Duration of a reconcile loop over all instances",
+            )
+            .namespace("tispace"),
+            &["operator"],
+        )
+        .unwrap(),
+    )
+});
+
+crate static BACKEND_ERRORS: Lazy<IntCounterVec> = Lazy::new(|| {
+    register(
+        IntCounterVec::new(
+            Opts::new("backend_errors_total", "Errors returned by backend APIs")
+                .namespace("tispace"),
+            &["backend"],
+        )
+        .unwrap(),
+    )
+});
+
+crate static SCHEDULING_FAILURES: Lazy<IntCounter> = Lazy::new(|| {
+    register(
+        IntCounter::with_opts(
+            Opts::new(
+                "scheduling_failures_total",
+                "Instances that couldn't be placed on any node",
+            )
+            .namespace("tispace"),
+        )
+        .unwrap(),
+    )
+});
+
+crate static PREEMPTIONS: Lazy<IntCounter> = Lazy::new(|| {
+    register(
+        IntCounter::with_opts(
+            Opts::new(
+                "preemptions_total",
+                "Preemptible instances stopped to make room for others",
+            )
+            .namespace("tispace"),
+        )
+        .unwrap(),
+    )
+});
+
+static HTTP_REQUESTS: Lazy<IntCounterVec> = Lazy::new(|| {
+    register(
+        IntCounterVec::new(
+            Opts::new("http_requests_total", "HTTP requests served").namespace("tispace"),
+            &["method", "route", "status"],
+        )
+        .unwrap(),
+    )
+});
+
+static HTTP_REQUEST_DURATION: Lazy<HistogramVec> = Lazy::new(|| {
+    register(
+        HistogramVec::new(
+            HistogramOpts::new("http_request_duration_seconds", "HTTP request latency")
+                .namespace("tispace"),
+            &["method", "route"],
+        )
+        .unwrap(),
+    )
+});
+
+/// Layer recording the count, latency and status code of HTTP requests per route.
+#[derive(Clone, Default)]
+pub struct HttpMetricsLayer;
+
+impl<S> Layer<S> for HttpMetricsLayer {
+    type Service = HttpMetrics<S>;
+
+    fn layer(&self, inner: S) -> Self::Service {
+        HttpMetrics { inner }
+    }
+}
+
+#[derive(Clone)]
+pub struct HttpMetrics<S> {
+    inner: S,
+}
+
+impl<S, B, ResBody> Service<Request<B>> for HttpMetrics<S>
+where
+    S: Service<Request<B>, Response = Response<ResBody>>,
+    S::Future: Send + 'static,
+{
+    type Response = S::Response;
+    type Error = S::Error;
+    #[allow(clippy::type_complexity)]
+    type Future = Pin<Box<dyn Future<Output = Result<Self::Response, Self::Error>> + Send>>;
+
+    fn poll_ready(&mut self, cx: &mut Context<'_>) -> Poll<Result<(), Self::Error>> {
+        self.inner.poll_ready(cx)
+    }
+
+    fn call(&mut self, req: Request<B>) -> Self::Future {
+        let method = req.method().to_string();
+        let route = route_label(req.uri().path());
+        let start = Instant::now();
+        let fut = self.inner.call(req);
+        Box::pin(async move {
+            let res = fut.await;
+            let status = match &res {
+                Ok(r) => r.status().as_u16().to_string(),
+                Err(_) => "error".to_owned(),
+            };
+            HTTP_REQUESTS
+                .with_label_values(&[method.as_str(), route, status.as_str()])
+                .inc();
+            HTTP_REQUEST_DURATION
+                .with_label_values(&[method.as_str(), route])
+                .observe(start.elapsed().as_secs_f64());
+            res
+        })
+    }
+}
+
+/// Maps a request path to its route pattern, so that instance names don't blow up the
+/// cardinality of the labels.
+fn route_label(path: &str) -> &'static str {
+    let mut segments: Vec<&str> = path.trim_matches('/').split('/').collect();
+    // Versioned paths share the labels with their unversioned aliases.
+    if ApiVersion::ALL
+        .iter()
+        .any(|v| segments.first() == Some(&v.prefix().trim_start_matches('/')))
+    {
+        segments.remove(0);
+    }
+    match segments.as_slice() {
+        ["instances"] => "/instances",
+        ["images"] => "/images",
+        ["images", _] => "/images/:image_name",
+        ["image-builds"] => "/image-builds",
+        ["ssh-config"] => "/ssh-config",
+        ["known-hosts"] => "/known-hosts",
+        ["home-volume"] => "/home-volume",
+        ["volumes"] => "/volumes",
+        ["volumes", _] => "/volumes/:volume_name",
+        ["clusters"] => "/clusters",
+        ["topologies"] => "/topologies",
+        ["clusters", _] => "/clusters/:cluster_name",
+        ["clusters", _, "start"] => "/clusters/:cluster_name/start",
+        ["clusters", _, "stop"] => "/clusters/:cluster_name/stop",
+        ["instances:batch"] => "/instances:batch",
+        ["instances", "events"] => "/instances/events",
+        ["instances", _] => "/instances/:instance_name",
+        ["instances", "by-id", _] => "/instances/by-id/:id",
+        ["instances", _, "start"] => "/instances/:instance_name/start",
+        ["instances", _, "stop"] => "/instances/:instance_name/stop",
+        ["instances", _, "ssh"] => "/instances/:instance_name/ssh",
+        ["instances", _, "files"] => "/instances/:instance_name/files",
+        ["instances", _, "export"] => "/instances/:instance_name/export",
+        ["instances", _, "conversion"] => "/instances/:instance_name/conversion",
+        ["instances", _, "history"] => "/instances/:instance_name/history",
+        ["instances", _, "scheduling"] => "/instances/:instance_name/scheduling",
+        ["instances", _, "restore"] => "/instances/:instance_name/restore",
+        ["instances", _, "schedule"] => "/instances/:instance_name/schedule",
+        ["instances", _, "schedule", "skip"] => "/instances/:instance_name/schedule/skip",
+        ["instances", _, "transfer"] => "/instances/:instance_name/transfer",
+        ["instances", _, "backup-policy"] => "/instances/:instance_name/backup-policy",
+        ["instances", _, "ip-pin"] => "/instances/:instance_name/ip-pin",
+        ["instances", _, "shares", _] => "/instances/:instance_name/shares/:username",
+        ["admin", "config", "reload"] => "/admin/config/reload",
+        ["admin", "reconcile"] => "/admin/reconcile",
+        ["admin", "usage"] => "/admin/usage",
+        ["admin", "ip-assignments"] => "/admin/ip-assignments",
+        ["admin", "ip-pool"] => "/admin/ip-pool",
+        ["admin", "ip-pool", "ranges"] => "/admin/ip-pool/ranges",
+        ["admin", "groups"] => "/admin/groups",
+        ["admin", "groups", _] => "/admin/groups/:group_name",
+        ["admin", "images"] => "/admin/images",
+        ["admin", "images", _] => "/admin/images/:image_name",
+        ["admin", "projects"] => "/admin/projects",
+        ["admin", "projects", _] => "/admin/projects/:project_name",
+        ["admin", "nodes"] => "/admin/nodes",
+        ["admin", "nodes", _] => "/admin/nodes/:node_name",
+        ["admin", "priority-classes"] => "/admin/priority-classes",
+        ["admin", "priority-classes", _] => "/admin/priority-classes/:class_name",
+        ["admin", "topologies", _] => "/admin/topologies/:topology_name",
+        ["metrics"] => "/metrics",
+        ["healthz"] => "/healthz",
+        ["readyz"] => "/readyz",
+        _ => "other",
+    }
+}
+
+/// Updates the gauges derived from the state and encodes all metrics in the text format.
+crate fn gather(state: &State) -> String {
+    // Concurrent scrapes would otherwise interleave resetting and setting the gauges.
+    static LOCK: Lazy<Mutex<()>> = Lazy::new(|| Mutex::new(()));
+    let _guard = LOCK.lock().unwrap();
+
+    // Gauges are reset so that deleted nodes and instances disappear from the output.
+    for gauge in [
+        &*CPU_ALLOCATED,
+        &*MEMORY_ALLOCATED,
+        &*STORAGE_TOTAL,
+        &*STORAGE_ALLOCATED,
+        &*STORAGE_USED,
+        &*INSTANCE_DISK_USED,
+        &*INSTANCE_UPTIME,
+        &*INSTANCE_STATUS,
+        &*USER_CPU_ALLOCATED,
+        &*USER_MEMORY_ALLOCATED,
+        &*USER_DISK_ALLOCATED,
+        &*USER_INSTANCE_COUNT,
+    ] {
+        gauge.reset();
+    }
+    // Make sure the operator metrics are exported even if they are never touched.
+    Lazy::force(&RECONCILE_DURATION);
+    Lazy::force(&BACKEND_ERRORS);
+    Lazy::force(&SCHEDULING_FAILURES);
+    Lazy::force(&PREEMPTIONS);
+    Lazy::force(&ORPHANED_RESOURCES);
+
+    for node in &state.nodes {
+        CPU_ALLOCATED
+            .with_label_values(&[node.name.as_str()])
+            .add(node.cpu_allocated as f64);
+        MEMORY_ALLOCATED
+            .with_label_values(&[node.name.as_str()])
+            .add(node.memory_allocated as f64);
+        for pool in &node.storage_pools {
+            STORAGE_TOTAL
+                .with_label_values(&[node.name.as_str(), pool.name.as_str()])
+                .add(pool.total as f64);
+            STORAGE_ALLOCATED
+                .with_label_values(&[node.name.as_str(), pool.name.as_str()])
+                .add(pool.allocated as f64);
+            STORAGE_USED
+                .with_label_values(&[node.name.as_str(), pool.name.as_str()])
+                .add(pool.used as f64);
+        }
+    }
+
+    let now = Utc::now().timestamp();
+    for user in &state.users {
+        let username = user.username.as_str();
+        USER_CPU_ALLOCATED
+            .with_label_values(&[username])
+            .set(user.instances.iter().map(|i| i.cpu).sum::<usize>() as f64);
+        USER_MEMORY_ALLOCATED
+            .with_label_values(&[username])
+            .set(user.instances.iter().map(|i| i.memory).sum::<usize>() as f64);
+        USER_DISK_ALLOCATED.with_label_values(&[username]).set(
+            user.instances
+                .iter()
+                .map(|i| i.total_disk_size())
+                .sum::<usize>() as f64
+                + user.home_volume_size() as f64
+                + user.detached_volume_size() as f64,
+        );
+        USER_INSTANCE_COUNT
+            .with_label_values(&[username])
+            .set(user.instances.len() as f64);
+
+        for instance in &user.instances {
+            INSTANCE_UPTIME
+                .with_label_values(&[username, instance.name.as_str()])
+                .set(instance.uptime(now) as f64);
+            if let Some(disk_used) = instance.disk_used {
+                INSTANCE_DISK_USED
+                    .with_label_values(&[
+                        user.username.as_str(),
+                        instance.name.as_str(),
+                        instance.node_name.as_deref().unwrap_or_default(),
+                        instance.storage_pool.as_deref().unwrap_or_default(),
+                    ])
+                    .set(disk_used as f64);
+            }
+        }
+    }
+
+    for instance in state.users.iter().flat_map(|u| u.instances.iter()) {
+        let mut status = instance.status.to_string();
+        if status.starts_with("Error:") {
+            status = "Error".to_owned();
+        }
+
+        let node_name = instance.node_name.clone().unwrap_or_default();
+        let storage_pool = instance.storage_pool.clone().unwrap_or_default();
+
+        INSTANCE_STATUS
+            .with_label_values(&[
+                node_name.as_str(),
+                storage_pool.as_str(),
+                instance.runtime.to_string().as_str(),
+                status.as_str(),
+            ])
+            .inc();
+    }
+
+    let mut buffer = vec![];
+    let encoder = TextEncoder::new();
+    let metric_families = REGISTRY.gather();
+    encoder.encode(&metric_families, &mut buffer).unwrap();
+    String::from_utf8(buffer).unwrap()
+}
@@ -1,11 +1,21 @@
-use std::collections::HashMap;
+use std::collections::{BTreeMap, HashMap};
 use std::fmt::Formatter;
+use std::num::NonZeroU32;
 use std::{fmt, str::FromStr};
 
 use anyhow::{anyhow, Error, Result};
+use chrono::{DateTime, Datelike, FixedOffset, NaiveTime, TimeZone, Utc, Weekday};
+use once_cell::sync::Lazy;
+use rand::{thread_rng, Rng};
+use regex::Regex;
+use ring::{digest, pbkdf2};
 use serde::de::Error as SerdeError;
 use serde::{Deserialize, Deserializer, Serialize, Serializer};
 
+use crate::config::{self, IpPool};
+use crate::env::EC2_IMAGES;
+use crate::lifecycle;
+
 #[derive(Debug, Clone, Serialize, Deserialize, Eq, PartialEq)]
 crate enum InstanceStage {
     Stopped,
@@ -50,6 +60,22 @@ impl fmt::Display for InstanceStatus {
     }
 }
 
+impl InstanceStatus {
+    /// Returns the name of the status without the error message.
+    crate fn state(&self) -> &'static str {
+        match self {
+            InstanceStatus::Creating => "Creating",
+            InstanceStatus::Starting => "Starting",
+            InstanceStatus::Running => "Running",
+            InstanceStatus::Stopping => "Stopping",
+            InstanceStatus::Stopped => "Stopped",
+            InstanceStatus::Deleting => "Deleting",
+            InstanceStatus::Missing => "Missing",
+            InstanceStatus::Error(_) => "Error",
+        }
+    }
+}
+
 impl Serialize for InstanceStatus {
     fn serialize<S>(&self, serializer: S) -> Result<S::Ok, S::Error>
     where
@@ -91,6 +117,69 @@ crate enum Runtime {
     Runc,
     Lxc,
     Kvm,
+    MicroVm,
+    Ec2,
+    // System containers of Podman, sharing the kernel of the node without further isolation.
+    Oci,
+}
+
+impl Runtime {
+    /// Returns true if the bandwidth of instances of the runtime can be limited.
+    crate fn supports_bandwidth_limits(&self) -> bool {
+        matches!(
+            self,
+            Runtime::Lxc | Runtime::Kvm | Runtime::Kata | Runtime::Runc
+        )
+    }
+
+    /// Returns true if instances of the runtime can enable nesting and KVM passthrough. VMs don't
+    /// need either to run containers, and runc containers can't have either without access to
+    /// the host.
+    crate fn supports_nesting(&self) -> bool {
+        matches!(self, Runtime::Lxc | Runtime::Kata)
+    }
+
+    /// Returns true if instances of the runtime can set the sysctls in `TUNABLE_SYSCTLS`. The
+    /// sysctls of VMs are set by the guest.
+    crate fn supports_sysctls(&self) -> bool {
+        matches!(self, Runtime::Lxc | Runtime::Kata | Runtime::Runc)
+    }
+
+    /// Returns true if instances of the runtime can set the sysctl of `TUNABLE_SYSCTLS`. Kubelet
+    /// rejects pods setting sysctls which are not namespaced, such as the `fs.*` ones.
+    crate fn supports_sysctl(&self, key: &str) -> bool {
+        match self {
+            Runtime::Lxc => true,
+            Runtime::Kata | Runtime::Runc => !key.starts_with("fs."),
+            _ => false,
+        }
+    }
+
+    /// Returns true if instances of the runtime can have volumes besides their root disks.
+    crate fn supports_volumes(&self) -> bool {
+        matches!(self, Runtime::Kata | Runtime::Runc)
+    }
+
+    /// Returns true if the size of /dev/shm of instances of the runtime can be set.
+    crate fn supports_shm_size(&self) -> bool {
+        matches!(self, Runtime::Kata | Runtime::Runc)
+    }
+
+    /// Returns true if instances of the runtime can be pinned to dedicated host CPUs.
+    crate fn supports_dedicated_cpu(&self) -> bool {
+        matches!(self, Runtime::Lxc | Runtime::Kvm)
+    }
+
+    /// Returns true if the CPU limit of running instances of the runtime can be changed.
+    crate fn supports_cpu_hotplug(&self) -> bool {
+        matches!(self, Runtime::Lxc | Runtime::Kvm)
+    }
+
+    /// Returns true if the memory limit of running instances of the runtime can be changed. LXD
+    /// VMs need a restart to change it.
+    crate fn supports_memory_hotplug(&self) -> bool {
+        matches!(self, Runtime::Lxc)
+    }
 }
 
 impl fmt::Display for Runtime {
@@ -100,6 +189,9 @@ impl fmt::Display for Runtime {
             Runtime::Runc => write!(f, "runc"),
             Runtime::Lxc => write!(f, "lxc"),
             Runtime::Kvm => write!(f, "kvm"),
+            Runtime::MicroVm => write!(f, "microvm"),
+            Runtime::Ec2 => write!(f, "ec2"),
+            Runtime::Oci => write!(f, "oci"),
         }
     }
 }
@@ -114,6 +206,9 @@ impl FromStr for Runtime {
             "runc" => Ok(Self::Runc),
             "lxc" => Ok(Self::Lxc),
             "kvm" => Ok(Self::Kvm),
+            "microvm" => Ok(Self::MicroVm),
+            "ec2" => Ok(Self::Ec2),
+            "oci" => Ok(Self::Oci),
             _ => Err(anyhow!("invalid runtime {}", s)),
         }
     }
@@ -131,23 +226,13 @@ impl<'de> Deserialize<'de> for Runtime {
 }
 
 impl Runtime {
-    crate fn supported_images(&self) -> Vec<Image> {
-        match self {
-            Runtime::Kata => Vec::new(),
-            Runtime::Runc => Vec::new(),
-            Runtime::Lxc => vec![
-                Image::CentOS7,
-                Image::CentOS9Stream,
-                Image::Ubuntu2004,
-                Image::Ubuntu2204,
-            ],
-            Runtime::Kvm => vec![
-                Image::CentOS7,
-                Image::CentOS9Stream,
-                Image::Ubuntu2004,
-                Image::Ubuntu2204,
-            ],
-        }
+    /// Returns true if instances of the runtime can be rebuilt with the other runtime, see
+    /// [`Conversion`].
+    crate fn convertible_to(&self, other: &Runtime) -> bool {
+        matches!(
+            (self, other),
+            (Runtime::Lxc, Runtime::Kvm) | (Runtime::Kvm, Runtime::Lxc)
+        )
     }
 
     crate fn compatiable_with(&self, other: &Runtime) -> bool {
@@ -162,53 +247,89 @@ impl Runtime {
 }
 
 #[derive(Debug, Clone, Serialize, Eq, PartialEq)]
-crate enum Image {
-    CentOS7,
-    CentOS8,
-    CentOS9Stream,
-    Ubuntu2004,
-    Ubuntu2204,
+crate enum Arch {
+    Amd64,
+    Arm64,
 }
 
-impl fmt::Display for Image {
+impl Default for Arch {
+    fn default() -> Self {
+        Arch::Amd64
+    }
+}
+
+impl fmt::Display for Arch {
     fn fmt(&self, f: &mut Formatter<'_>) -> fmt::Result {
         match self {
-            Image::CentOS7 => write!(f, "centos:7"),
-            Image::CentOS8 => write!(f, "centos:8"),
-            Image::CentOS9Stream => write!(f, "centos:9-Stream"),
-            Image::Ubuntu2004 => write!(f, "ubuntu:20.04"),
-            Image::Ubuntu2204 => write!(f, "ubuntu:22.04"),
+            Arch::Amd64 => write!(f, "amd64"),
+            Arch::Arm64 => write!(f, "arm64"),
+        }
+    }
+}
+
+impl FromStr for Arch {
+    type Err = Error;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let lower = s.to_lowercase();
+        match lower.as_str() {
+            "amd64" | "x86_64" => Ok(Self::Amd64),
+            "arm64" | "aarch64" => Ok(Self::Arm64),
+            _ => Err(anyhow!("invalid arch {}", s)),
         }
     }
 }
 
+impl<'de> Deserialize<'de> for Arch {
+    fn deserialize<D>(deserializer: D) -> Result<Self, D::Error>
+    where
+        D: Deserializer<'de>,
+    {
+        let s = String::deserialize(deserializer)?;
+        Arch::from_str(&s).map_err(|_| SerdeError::custom(format!("invalid arch {}", s)))
+    }
+}
+
+/// Name of an image in the image catalog, such as `ubuntu:22.04`.
+#[derive(Debug, Clone, Serialize, Eq, PartialEq)]
+crate struct Image(String);
+
+impl fmt::Display for Image {
+    fn fmt(&self, f: &mut Formatter<'_>) -> fmt::Result {
+        write!(f, "{}", self.0)
+    }
+}
+
 impl FromStr for Image {
     type Err = Error;
 
     fn from_str(s: &str) -> Result<Self, Self::Err> {
+        // Names used before the catalog was introduced.
         let lower = s.to_lowercase();
         if lower.starts_with("tispace/centos7:") {
-            return Ok(Self::CentOS7);
+            return Ok(Image("centos:7".to_owned()));
         }
         if lower.starts_with("tispace/centos8:") {
-            return Ok(Self::CentOS8);
+            return Ok(Image("centos:8".to_owned()));
         }
         if lower.starts_with("tispace/centos9-stream:") {
-            return Ok(Self::CentOS9Stream);
+            return Ok(Image("centos:9-Stream".to_owned()));
         }
         if lower.starts_with("tispace/ubuntu2004:") {
-            return Ok(Self::Ubuntu2004);
+            return Ok(Image("ubuntu:20.04".to_owned()));
         }
-        return match lower.as_str() {
-            "tispace/centos7" | "centos7" | "centos:7" => Ok(Self::CentOS7),
-            "tispace/centos8" | "centos8" | "centos:8" => Ok(Self::CentOS8),
+        let name = match lower.as_str() {
+            "tispace/centos7" | "centos7" | "centos:7" => "centos:7",
+            "tispace/centos8" | "centos8" | "centos:8" => "centos:8",
             "tispace/centos9-stream" | "centos9-stream" | "centos:9-stream" | "centos9stream" => {
-                Ok(Self::CentOS9Stream)
+                "centos:9-Stream"
             }
-            "tispace/ubuntu2004" | "ubuntu2004" | "ubuntu:20.04" => Ok(Self::Ubuntu2004),
-            "ubuntu2204" | "ubuntu:22.04" => Ok(Self::Ubuntu2204),
-            _ => Err(anyhow!("invalid image {}", s)),
+            "tispace/ubuntu2004" | "ubuntu2004" | "ubuntu:20.04" => "ubuntu:20.04",
+            "ubuntu2204" | "ubuntu:22.04" => "ubuntu:22.04",
+            _ if IMAGE_NAME_REGEX.is_match(s) => s,
+            _ => return Err(anyhow!("invalid image {}", s)),
         };
+        Ok(Image(name.to_owned()))
     }
 }
 
@@ -222,122 +343,1811 @@ impl<'de> Deserialize<'de> for Image {
     }
 }
 
+// Images registered by users are prefixed by the username and a slash. Usernames are the local
+// parts of the emails without dots, so they may have uppercase letters and underscores.
+static IMAGE_NAME_REGEX: Lazy<Regex> = Lazy::new(|| {
+    Regex::new(r"^([a-zA-Z0-9][-_a-zA-Z0-9]{0,62}/)?[a-zA-Z0-9][-a-zA-Z0-9._:]{0,62}$").unwrap()
+});
+
+/// Whether an instance keeps its resources when others need them. Preemptible instances don't
+/// count towards the quotas of their owners, and are stopped to make room for normal instances
+/// which no node has enough CPU or memory for.
+#[derive(Debug, Clone, Copy, Serialize, Deserialize, Eq, PartialEq)]
+#[serde(rename_all = "lowercase")]
+crate enum Priority {
+    Normal,
+    Preemptible,
+}
+
+impl Default for Priority {
+    fn default() -> Self {
+        Priority::Normal
+    }
+}
+
+impl fmt::Display for Priority {
+    fn fmt(&self, f: &mut Formatter<'_>) -> fmt::Result {
+        match self {
+            Priority::Normal => write!(f, "normal"),
+            Priority::Preemptible => write!(f, "preemptible"),
+        }
+    }
+}
+
+impl FromStr for Priority {
+    type Err = Error;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s.to_lowercase().as_str() {
+            "normal" => Ok(Self::Normal),
+            "preemptible" => Ok(Self::Preemptible),
+            _ => Err(anyhow!("invalid priority {}", s)),
+        }
+    }
+}
+
+/// Determines the format of the cloud-init network config of an image.
+#[derive(Debug, Clone, Copy, Serialize, Deserialize, Eq, PartialEq)]
+#[serde(rename_all = "lowercase")]
+crate enum ImageFamily {
+    CentOS,
+    Ubuntu,
+}
+
+impl Default for ImageFamily {
+    fn default() -> Self {
+        ImageFamily::Ubuntu
+    }
+}
+
+impl fmt::Display for ImageFamily {
+    fn fmt(&self, f: &mut Formatter<'_>) -> fmt::Result {
+        match self {
+            ImageFamily::CentOS => write!(f, "centos"),
+            ImageFamily::Ubuntu => write!(f, "ubuntu"),
+        }
+    }
+}
+
+impl FromStr for ImageFamily {
+    type Err = Error;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s.to_lowercase().as_str() {
+            "centos" => Ok(Self::CentOS),
+            "ubuntu" => Ok(Self::Ubuntu),
+            _ => Err(anyhow!("invalid image family {}", s)),
+        }
+    }
+}
+
+/// An image instances can be created from, managed by admins.
+#[derive(Debug, Clone, Serialize, Deserialize, Eq, PartialEq)]
+crate struct CatalogImage {
+    crate name: Image,
+    crate family: ImageFamily,
+    // Source of the image per runtime name: the LXD image alias without the arch for LXD
+    // runtimes, the repository of the rootfs image for Kubernetes runtimes, the image name for
+    // micro-VMs and the AMI ID for EC2. The image is unavailable on runtimes without a source.
+    crate sources: BTreeMap<String, String>,
+    crate arches: Vec<Arch>,
+    crate enabled: bool,
+    // Whether the image is used when none is specified on creation.
+    crate default: bool,
+    // The user who registered the image, only available to that user. None for images managed
+    // by admins, which are available to everyone.
+    #[serde(default)]
+    crate owner: Option<String>,
+    // Shares mounted into the instances created from the image, only declared by admins.
+    #[serde(default)]
+    crate mounts: Vec<SharedMount>,
+}
+
+/// A share of the hosts or of an NFS server mounted into instances, e.g. a dataset too large to
+/// be copied into each of them. Sources must be allowed by `Config::shared_mount_allowlist`.
+#[derive(Debug, Clone, Serialize, Deserialize, Eq, PartialEq)]
+crate struct SharedMount {
+    // Either an absolute path on the hosts or an NFS export like nfs://nas.internal/datasets.
+    crate source: String,
+    // Absolute path in the instances.
+    crate path: String,
+    #[serde(default)]
+    crate read_only: bool,
+}
+
+impl SharedMount {
+    /// Returns the server and the path of the NFS export, None if the source is a host path.
+    crate fn nfs_export(&self) -> Option<(&str, &str)> {
+        let rest = self.source.strip_prefix("nfs://")?;
+        let i = rest.find('/')?;
+        Some((&rest[..i], &rest[i..]))
+    }
+
+    /// Returns true if instances of the runtime can mount the share. LXD and Podman only mount
+    /// paths of the hosts, which must mount NFS exports themselves.
+    crate fn supported_on(&self, runtime: &Runtime) -> bool {
+        match runtime {
+            Runtime::Kata | Runtime::Runc => true,
+            Runtime::Lxc | Runtime::Kvm | Runtime::Oci => self.nfs_export().is_none(),
+            Runtime::MicroVm | Runtime::Ec2 => false,
+        }
+    }
+}
+
+/// Prefix of the sources of images published to the LXD server itself rather than pulled from
+/// the image server, such as the images built by the image builder.
+crate const LOCAL_IMAGE_PREFIX: &str = "local:";
+
+/// A build of an image, which runs a provisioning script on a temporary instance created from
+/// a base image and publishes the root filesystem of the instance to the catalog.
+#[derive(Debug, Clone, Serialize, Deserialize, Eq, PartialEq)]
+crate struct ImageBuild {
+    // Name of the image to publish.
+    crate name: Image,
+    crate username: String,
+    crate base_image: Image,
+    // Either lxc or kvm, the published image is only available on that runtime.
+    crate runtime: Runtime,
+    crate script: String,
+    // The temporary instance of the user the script runs on.
+    crate instance_name: String,
+    crate status: ImageBuildStatus,
+    // The LXD operation running the script or publishing the image, if any.
+    crate operation: Option<String>,
+    // Progress of the build and the output of the script, oldest first.
+    crate events: Vec<Event>,
+}
+
+impl ImageBuild {
+    crate fn add_event(&mut self, message: String) {
+        push_event(&mut self.events, message, MAX_IMAGE_BUILD_EVENTS);
+    }
+
+    crate fn is_finished(&self) -> bool {
+        matches!(
+            self.status,
+            ImageBuildStatus::Succeeded | ImageBuildStatus::Failed(_)
+        )
+    }
+}
+
+const MAX_IMAGE_BUILD_EVENTS: usize = 100;
+
+#[derive(Debug, Clone, Serialize, Deserialize, Eq, PartialEq)]
+crate enum ImageBuildStatus {
+    Pending,
+    Provisioning,
+    Running,
+    Stopping,
+    Publishing,
+    Succeeded,
+    Failed(String),
+}
+
+impl fmt::Display for ImageBuildStatus {
+    fn fmt(&self, f: &mut Formatter<'_>) -> fmt::Result {
+        match self {
+            ImageBuildStatus::Pending => write!(f, "Pending"),
+            ImageBuildStatus::Provisioning => write!(f, "Provisioning"),
+            ImageBuildStatus::Running => write!(f, "Running"),
+            ImageBuildStatus::Stopping => write!(f, "Stopping"),
+            ImageBuildStatus::Publishing => write!(f, "Publishing"),
+            ImageBuildStatus::Succeeded => write!(f, "Succeeded"),
+            ImageBuildStatus::Failed(msg) => write!(f, "Failed: {}", msg),
+        }
+    }
+}
+
+impl CatalogImage {
+    /// Returns the name of the image `name` registered by `username`.
+    crate fn custom_name(username: &str, name: &str) -> String {
+        format!("{}/{}", username, name)
+    }
+
+    crate fn available_to(&self, username: &str) -> bool {
+        self.enabled && self.owner.as_deref().map_or(true, |o| o == username)
+    }
+
+    crate fn source(&self, runtime: &Runtime) -> Option<&str> {
+        self.sources.get(&runtime.to_string()).map(|s| s.as_str())
+    }
+}
+
+/// Sysctls users may set on their instances. Pods can only set the namespaced ones kubelet allows
+/// with `--allowed-unsafe-sysctls`, see `Runtime::supports_sysctl`.
+crate const TUNABLE_SYSCTLS: [&str; 3] = [
+    "fs.inotify.max_user_watches",
+    "fs.inotify.max_user_instances",
+    "net.core.somaxconn",
+];
+
 #[derive(Debug, Clone, Serialize, Deserialize, Eq, PartialEq)]
 crate struct Instance {
+    // UUID assigned at creation, which is kept across renames and ownership transfers.
+    #[serde(default)]
+    crate id: String,
     crate name: String,
     crate cpu: usize,
     crate memory: usize,
     crate disk_size: usize,
+    // The actual usage of the root disk in GiB, as last reported by the runtime.
+    #[serde(default)]
+    crate disk_used: Option<usize>,
     crate image: Image,
+    #[serde(default)]
+    crate arch: Arch,
     // Deprecated: hostname is now the same as name.
     crate hostname: String,
     // Deprecated: use external_ip instead.
     crate ssh_host: Option<String>,
     // Deprecated: use 22 instead.
     crate ssh_port: Option<i32>,
+    // The root password, or its PBKDF2 hash once provisioned if password_hashed is set.
     crate password: String,
+    #[serde(default)]
+    crate password_hashed: bool,
+    // Public keys authorized to log in as root.
+    #[serde(default)]
+    crate ssh_authorized_keys: Vec<String>,
     crate stage: InstanceStage,
     crate status: InstanceStatus,
     crate internal_ip: Option<String>,
     crate external_ip: Option<String>,
+    // The IP pool external_ip is allocated from, see `crate::config::IpPool`. None stands for the
+    // default pool.
+    #[serde(default)]
+    crate ip_pool: Option<String>,
+    // Whether the external IP stays reserved for the name of the instance once it's deleted.
+    #[serde(default)]
+    crate ip_pinned: bool,
+    // Rate limits of the traffic to and from the instance in Mbit/s, unlimited if None.
+    #[serde(default)]
+    crate ingress_limit: Option<usize>,
+    #[serde(default)]
+    crate egress_limit: Option<usize>,
+    // Whether the instance runs on host CPUs of its own, which are not overcommitted.
+    #[serde(default)]
+    crate dedicated_cpu: bool,
+    // The host CPUs a dedicated instance is pinned to, assigned by the scheduler.
+    #[serde(default)]
+    crate pinned_cpus: Vec<usize>,
+    // Whether the instance can run containers of its own, e.g. Docker or minikube.
+    #[serde(default)]
+    crate nesting: bool,
+    // Whether /dev/kvm of the host is passed through to the instance to run VMs inside.
+    #[serde(default)]
+    crate kvm_passthrough: bool,
+    // Options tuning the VM of Kata instances, see `operator_k8s::KATA_RUNTIME_OPTIONS`.
+    #[serde(default)]
+    crate runtime_options: BTreeMap<String, String>,
+    // Sysctls set in the instance, out of `TUNABLE_SYSCTLS`.
+    #[serde(default)]
+    crate sysctls: BTreeMap<String, String>,
+    // Size of /dev/shm in MiB, the default of the runtime if None.
+    #[serde(default)]
+    crate shm_size: Option<usize>,
+    // Volumes besides the root disk, which outlive rebuilds of the root filesystem.
+    #[serde(default)]
+    crate volumes: Vec<Volume>,
+    // Shares of the image mounted into the instance, as allowed when it was created.
+    #[serde(default)]
+    crate mounts: Vec<SharedMount>,
     crate runtime: Runtime,
     crate node_name: Option<String>,
     crate storage_pool: Option<String>,
+    // The latest export or import of this instance, if any.
+    #[serde(default)]
+    crate transfer: Option<Transfer>,
+    // Progress of the provisioning while the instance is being created.
+    #[serde(default)]
+    crate progress: Option<Progress>,
+    // The latest conversion to another runtime, until its previous backend is deleted.
+    #[serde(default)]
+    crate conversion: Option<Conversion>,
+    // The ID of the instance in the cloud provider, only set for cloud runtimes such as EC2.
+    #[serde(default)]
+    crate cloud_instance_id: Option<String>,
+    // The most recent events of this instance, oldest first.
+    #[serde(default)]
+    crate events: Vec<Event>,
+    // The most recent status transitions of this instance, oldest first.
+    #[serde(default)]
+    crate status_history: Vec<StatusTransition>,
+    // Unix timestamp the instance entered Running, None while it's not running.
+    #[serde(default)]
+    crate started_at: Option<i64>,
+    // Unix timestamp the instance was last seen running, set when it leaves Running.
+    #[serde(default)]
+    crate last_running_at: Option<i64>,
+    // Seconds the instance has been running in total, not counting the current run.
+    #[serde(default)]
+    crate accumulated_uptime: u64,
+    // Unix timestamps the instance was created, last changed and marked for deletion. None for
+    // instances created before they were recorded.
+    #[serde(default)]
+    crate created_at: Option<i64>,
+    #[serde(default)]
+    crate updated_at: Option<i64>,
+    #[serde(default)]
+    crate deleted_at: Option<i64>,
+    // Unix timestamp the instance was moved to the recycle bin, where it's kept stopped along
+    // with its disk until it's restored or `Config::recycle_bin_retention` is over.
+    #[serde(default)]
+    crate trashed_at: Option<i64>,
+    #[serde(default)]
+    crate schedule: Option<PowerSchedule>,
+    #[serde(default)]
+    crate backup_policy: Option<BackupPolicy>,
+    // The project the instance belongs to, None for personal instances.
+    #[serde(default)]
+    crate project: Option<String>,
+    // Other users granted access to the instance by the owner.
+    #[serde(default)]
+    crate shares: Vec<InstanceShare>,
+    // Name of the pod, LXD instance, etc. backing the instance, if it differs from the default
+    // derived from the username, which is the case after the ownership is transferred.
+    #[serde(default)]
+    crate backend_name: Option<String>,
+    // The source and family of the image in the catalog when the instance was created, see
+    // `CatalogImage`.
+    #[serde(default)]
+    crate image_source: Option<String>,
+    #[serde(default)]
+    crate image_family: ImageFamily,
+    // Free-form key/value pairs set by the owner, exposed to the guest by the metadata endpoint.
+    #[serde(default)]
+    crate labels: BTreeMap<String, String>,
+    // Free-form notes of the owner on what the instance is for.
+    #[serde(default)]
+    crate description: String,
+    #[serde(default)]
+    crate priority: Priority,
+    // Name of the `PriorityClass` of the instance, if any.
+    #[serde(default)]
+    crate priority_class: Option<String>,
+    // Name of the `InstanceGroup` of the owner the instance was created as a member of.
+    #[serde(default)]
+    crate instance_group: Option<String>,
+    // Role of the member in the topology of its group, if created from a `TopologyTemplate`.
+    #[serde(default)]
+    crate group_role: Option<String>,
+    // Public SSH host keys like `ssh-ed25519 AAAA...`, as posted by the guest once created, which
+    // only LXD and EC2 instances do, see `metadata_routes`. Cleared when the instance is rebuilt
+    // so that stale keys are never published.
+    #[serde(default)]
+    crate ssh_host_keys: Vec<String>,
+    // The last attempt of the scheduler to place the instance.
+    #[serde(default)]
+    crate scheduling: Option<SchedulingDecision>,
+    // Whether the root disk outlives the instance as a `DetachedVolume` of the owner, in which
+    // case it's left behind when the backend is deleted.
+    #[serde(default)]
+    crate keep_disk: bool,
 }
 
+/// Why the scheduler placed an instance on its node, or couldn't place it. Among the nodes the
+/// instance fits, the one with the most free CPUs wins, then the most free memory and storage.
 #[derive(Debug, Clone, Serialize, Deserialize, Eq, PartialEq)]
-crate struct User {
-    crate username: String,
-    crate cpu_quota: usize,
-    crate memory_quota: usize,
-    crate disk_quota: usize,
-    crate instance_quota: usize,
-    crate instances: Vec<Instance>,
-}
-
-impl User {
-    #[allow(dead_code)]
-    crate fn find_instance(&self, name: &str) -> Option<&Instance> {
-        self.instances.iter().find(|i| i.name == name)
-    }
-
-    crate fn find_mut_instance(&mut self, name: &str) -> Option<&mut Instance> {
-        self.instances.iter_mut().find(|i| i.name == name)
-    }
-
-    crate fn remove_instance(&mut self, name: &str) {
-        self.instances
-            .iter_mut()
-            .position(|i| i.name == name)
-            .map(|i| self.instances.remove(i));
-    }
+crate struct SchedulingDecision {
+    // Unix timestamp in seconds.
+    crate decided_at: i64,
+    // None if the instance fits no node.
+    crate node_name: Option<String>,
+    crate storage_pool: Option<String>,
+    crate nodes: Vec<NodeVerdict>,
 }
 
+/// How a node fared when the scheduler tried to place an instance.
 #[derive(Debug, Clone, Serialize, Deserialize, Eq, PartialEq)]
-crate struct Node {
-    crate name: String,
-    crate storage_pools: Vec<StoragePool>,
-    crate runtimes: Vec<Runtime>,
-    crate cpu_total: usize,
-    crate cpu_allocated: usize,
-    crate memory_total: usize,
-    crate memory_allocated: usize,
-    crate storage_total: usize,
-    crate storage_used: usize,
-    crate storage_allocated: usize,
+crate struct NodeVerdict {
+    crate node_name: String,
+    // Why the instance doesn't fit the node, None if it does.
+    crate rejection: Option<String>,
+    // Resources of the node not allocated yet, memory and storage in GiB.
+    crate free_cpu: usize,
+    crate free_memory: usize,
+    crate free_storage: usize,
 }
 
+/// A volume of an instance besides its root disk, backed by a PersistentVolumeClaim on Kubernetes.
 #[derive(Debug, Clone, Serialize, Deserialize, Eq, PartialEq)]
-crate struct StoragePool {
+crate struct Volume {
     crate name: String,
-    crate total: usize,
-    crate used: usize,
-    crate allocated: usize,
+    // In GiB.
+    crate size: usize,
+    crate mount_path: String,
+    // The PersistentVolumeClaim of the `DetachedVolume` the volume was attached from, if any.
+    #[serde(default)]
+    crate claim_name: Option<String>,
 }
 
-#[derive(Debug, Default, Clone, Serialize, Deserialize, Eq, PartialEq)]
-crate struct State {
-    crate users: Vec<User>,
+/// Access to an instance granted to a user other than the owner. The user may view, start and
+/// stop the instance.
+#[derive(Debug, Clone, Serialize, Deserialize, Eq, PartialEq)]
+crate struct InstanceShare {
+    crate username: String,
+    // Public keys of the user authorized to log in as root. They are installed the next time the
+    // root filesystem is initialized, which is on every start for Kubernetes runtimes and only
+    // on creation for the others.
     #[serde(default)]
-    crate nodes: Vec<Node>,
+    crate ssh_authorized_keys: Vec<String>,
 }
 
-impl State {
-    crate fn find_user(&self, username: &str) -> Option<&User> {
-        self.users.iter().find(|u| u.username == username)
+impl Instance {
+    /// Returns the name of the resources backing the instance owned by `username`.
+    crate fn backend_name(&self, username: &str) -> String {
+        match &self.backend_name {
+            Some(name) => name.clone(),
+            None => format!("{}-{}", username, self.name),
+        }
     }
 
-    crate fn find_mut_user(&mut self, username: &str) -> Option<&mut User> {
-        self.users.iter_mut().find(|u| u.username == username)
+    /// Returns why the status differs from what the stage asks for, e.g. whether an instance is
+    /// stopped because the user asked for it or because the backend stopped it.
+    crate fn status_reason(&self) -> Option<&'static str> {
+        match (&self.stage, &self.status) {
+            (_, InstanceStatus::Error(_)) => Some("BackendError"),
+            (_, InstanceStatus::Missing) => Some("BackendMissing"),
+            (InstanceStage::Deleted, _) => Some("DeletionRequested"),
+            (InstanceStage::Stopped, InstanceStatus::Stopping | InstanceStatus::Stopped) => {
+                Some("StopRequested")
+            }
+            (InstanceStage::Running, InstanceStatus::Stopping | InstanceStatus::Stopped) => {
+                Some("StoppedByBackend")
+            }
+            _ => None,
+        }
     }
 
-    crate fn sync_allocated_resources(&mut self) {
-        let mut cpu_allocated: HashMap<String, usize> = HashMap::new();
-        let mut memory_allocated: HashMap<String, usize> = HashMap::new();
-        // Map of (node_name, storage_pool) to the allocated capacity of each storage pool.
-        let mut storage_allocated: HashMap<(String, String), usize> = HashMap::new();
-        // Map of node name to total allocated capacity of all storage pools on each node.
-        let mut node_storage_allocated_total: HashMap<String, usize> = HashMap::new();
+    /// Returns the Unix timestamp the instance entered its status, None if it's not recorded.
+    crate fn status_since(&self) -> Option<i64> {
+        self.status_history
+            .last()
+            .filter(|t| t.status == self.status)
+            .map(|t| t.timestamp)
+    }
 
-        for u in &mut self.users {
+    /// Returns the seconds the instance has been running in total up to `now`.
+    crate fn uptime(&self, now: i64) -> u64 {
+        let current = self
+            .started_at
+            .map(|t| (now - t).max(0) as u64)
+            .unwrap_or_default();
+        self.accumulated_uptime + current
+    }
+
+    crate fn is_shared_with(&self, username: &str) -> bool {
+        self.shares.iter().any(|s| s.username == username)
+    }
+
+    /// Returns true if the instance is shared with the user along with keys to log in as root.
+    crate fn grants_ssh_to(&self, username: &str) -> bool {
+        self.shares
+            .iter()
+            .any(|s| s.username == username && !s.ssh_authorized_keys.is_empty())
+    }
+
+    /// Returns true if the CPU and memory of the instance count towards the quotas of its owner.
+    /// Preemptible instances give them up when preempted, while they still count as instances
+    /// and their disks take storage all along.
+    crate fn counts_towards_compute_quota(&self) -> bool {
+        self.priority == Priority::Normal
+    }
+
+    /// Returns true if the CPU and memory of the instance are held on its node. Stopped
+    /// preemptible instances give them up to others.
+    crate fn holds_compute(&self) -> bool {
+        self.priority == Priority::Normal || self.stage == InstanceStage::Running
+    }
+
+    /// Returns the size of the root disk and the volumes of the instance in GiB, which is what
+    /// counts towards the disk quotas and the storage of its node. The previous backend kept by a
+    /// conversion holds a root disk of its own until it's deleted, while a root disk kept as a
+    /// detached volume counts as the volume.
+    crate fn total_disk_size(&self) -> usize {
+        let root = if self.keep_disk { 0 } else { self.disk_size };
+        let previous = self.conversion.as_ref().map_or(0, |_| self.disk_size);
+        root + previous + self.volumes.iter().map(|v| v.size).sum::<usize>()
+    }
+
+    /// Returns the CPUs the instance takes out of the overcommitted capacity of its node.
+    crate fn allocated_cpu(&self) -> usize {
+        if self.dedicated_cpu {
+            dedicated_cpu_allocation(self.cpu)
+        } else {
+            self.cpu
+        }
+    }
+
+    /// Returns the address and port SSH connections to the instance should go to, along with
+    /// whether the address is only reachable from inside the cluster, i.e. through the bastion.
+    ///
+    /// Pods without a load balancer IP are reached through the NodePort of their service on the
+    /// node they run on.
+    crate fn ssh_target(&self) -> Option<(String, i32, bool)> {
+        if let Some(ip) = &self.external_ip {
+            return Some((ip.clone(), 22, false));
+        }
+        if let (Some(host), Some(port)) = (&self.ssh_host, self.ssh_port) {
+            return Some((host.clone(), port, true));
+        }
+        self.internal_ip.as_ref().map(|ip| (ip.clone(), 22, true))
+    }
+
+    /// Returns the keys of the owner followed by those of the users the instance is shared with.
+    crate fn authorized_keys(&self) -> Vec<String> {
+        let mut keys = self.ssh_authorized_keys.clone();
+        for share in &self.shares {
+            for key in &share.ssh_authorized_keys {
+                if !keys.contains(key) {
+                    keys.push(key.clone());
+                }
+            }
+        }
+        keys
+    }
+
+    /// Replaces the password with its hash. The plain password is no longer needed once the
+    /// instance is provisioned, as it's only used to initialize the root filesystem.
+    crate fn hash_password(&mut self) {
+        if self.password_hashed {
+            return;
+        }
+        let mut salt = [0u8; 16];
+        thread_rng().fill(&mut salt);
+        let mut hash = [0u8; digest::SHA256_OUTPUT_LEN];
+        pbkdf2::derive(
+            pbkdf2::PBKDF2_HMAC_SHA256,
+            NonZeroU32::new(PASSWORD_HASH_ITERATIONS).unwrap(),
+            &salt,
+            self.password.as_bytes(),
+            &mut hash,
+        );
+        self.password = format!(
+            "pbkdf2-sha256${}${}${}",
+            PASSWORD_HASH_ITERATIONS,
+            base64::encode(salt),
+            base64::encode(hash)
+        );
+        self.password_hashed = true;
+    }
+
+    /// Records an event unless it repeats the latest one, keeping at most MAX_INSTANCE_EVENTS.
+    crate fn add_event(&mut self, message: String) {
+        push_event(&mut self.events, message, MAX_INSTANCE_EVENTS);
+    }
+}
+
+const MAX_INSTANCE_EVENTS: usize = 20;
+
+const MAX_STATUS_HISTORY: usize = 50;
+
+/// Appends the event unless it repeats the last one, keeping at most `max` events.
+fn push_event(events: &mut Vec<Event>, message: String, max: usize) {
+    if events.last().map(|e| &e.message) == Some(&message) {
+        return;
+    }
+    events.push(Event {
+        timestamp: Utc::now().timestamp(),
+        message,
+    });
+    if events.len() > max {
+        events.remove(0);
+    }
+}
+
+/// Times of day at which an instance is started and stopped automatically, e.g. started at 08:00
+/// and stopped at 20:00 on weekdays.
+#[derive(Debug, Clone, Serialize, Deserialize, Eq, PartialEq)]
+crate struct PowerSchedule {
+    // Times of day formatted as HH:MM in the timezone of utc_offset.
+    crate start_at: Option<String>,
+    crate stop_at: Option<String>,
+    // Days of week the schedule applies to, e.g. ["mon", "tue"]. Every day if empty.
+    crate days: Vec<String>,
+    // Offset of the timezone from UTC in minutes, e.g. 480 for UTC+8.
+    crate utc_offset: i32,
+    // Scheduled actions due before this Unix timestamp are skipped.
+    #[serde(default)]
+    crate skip_until: Option<i64>,
+    // Unix timestamp of the last scheduled action that was due, so that each one is applied once.
+    #[serde(default)]
+    crate last_due: i64,
+}
+
+impl PowerSchedule {
+    crate fn validate(&self) -> Result<()> {
+        if self.start_at.is_none() && self.stop_at.is_none() {
+            return Err(anyhow!("neither start_at nor stop_at is set"));
+        }
+        for t in self.start_at.iter().chain(self.stop_at.iter()) {
+            NaiveTime::parse_from_str(t, "%H:%M")?;
+        }
+        for d in &self.days {
+            d.parse::<Weekday>()
+                .map_err(|_| anyhow!("invalid day {}", d))?;
+        }
+        if self.utc_offset.abs() >= 24 * 60 {
+            return Err(anyhow!("invalid utc offset {}", self.utc_offset));
+        }
+        Ok(())
+    }
+
+    /// Returns the latest action due at or before `now` which has not been applied yet, as the
+    /// stage the instance should be in and the Unix timestamp the action was due.
+    crate fn due_action(&self, now: DateTime<Utc>) -> Option<(InstanceStage, i64)> {
+        let tz = FixedOffset::east(self.utc_offset * 60);
+        let today = now.with_timezone(&tz).naive_local().date();
+        let actions = [
+            (&self.start_at, InstanceStage::Running),
+            (&self.stop_at, InstanceStage::Stopped),
+        ];
+        let mut due: Option<(InstanceStage, i64)> = None;
+        // Every action recurs at least weekly, so looking back a week finds the latest one.
+        for days_ago in 0..=7 {
+            let date = today - chrono::Duration::days(days_ago);
+            if !self.days.is_empty()
+                && !self
+                    .days
+                    .iter()
+                    .any(|d| d.parse::<Weekday>().ok() == Some(date.weekday()))
+            {
+                continue;
+            }
+            for (at, stage) in &actions {
+                let time = match at
+                    .as_ref()
+                    .and_then(|t| NaiveTime::parse_from_str(t, "%H:%M").ok())
+                {
+                    Some(time) => time,
+                    None => continue,
+                };
+                let timestamp = match tz.from_local_datetime(&date.and_time(time)).single() {
+                    Some(t) => t.timestamp(),
+                    None => continue,
+                };
+                if timestamp <= now.timestamp()
+                    && timestamp > self.last_due
+                    && due.as_ref().map_or(true, |(_, t)| timestamp > *t)
+                {
+                    due = Some((stage.clone(), timestamp));
+                }
+            }
+        }
+        due
+    }
+}
+
+const PASSWORD_HASH_ITERATIONS: u32 = 100_000;
+
+#[derive(Debug, Clone, Serialize, Deserialize, Eq, PartialEq)]
+crate struct Event {
+    // Unix timestamp in seconds.
+    crate timestamp: i64,
+    crate message: String,
+}
+
+/// A setting of the backend of an instance that differs from its spec, e.g. after being edited
+/// by hand.
+#[derive(Debug, Clone, Eq, PartialEq)]
+crate struct Drift {
+    crate setting: String,
+    crate actual: String,
+    crate desired: String,
+    // Whether the setting can be corrected without restarting or recreating the backend.
+    crate correctable: bool,
+}
+
+impl fmt::Display for Drift {
+    fn fmt(&self, f: &mut Formatter<'_>) -> fmt::Result {
+        write!(
+            f,
+            "{} is {} instead of {}",
+            self.setting, self.actual, self.desired
+        )
+    }
+}
+
+/// Returns the event recording the drifts, e.g. `configuration drifted: limits.cpu is 8 instead
+/// of 4`.
+crate fn drift_event(prefix: &str, drifts: &[&Drift]) -> String {
+    let drifts: Vec<String> = drifts.iter().map(|d| d.to_string()).collect();
+    format!("{}: {}", prefix, drifts.join(", "))
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize, Eq, PartialEq)]
+crate struct StatusTransition {
+    // Unix timestamp in seconds.
+    crate timestamp: i64,
+    // The status entered, the previous one being the status of the preceding transition.
+    crate status: InstanceStatus,
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize, Eq, PartialEq)]
+crate enum TransferKind {
+    Export,
+    Import,
+}
+
+impl fmt::Display for TransferKind {
+    fn fmt(&self, f: &mut Formatter<'_>) -> fmt::Result {
+        match self {
+            TransferKind::Export => write!(f, "Export"),
+            TransferKind::Import => write!(f, "Import"),
+        }
+    }
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize, Eq, PartialEq)]
+crate enum TransferStatus {
+    Pending,
+    Running,
+    Succeeded,
+    Failed(String),
+}
+
+impl fmt::Display for TransferStatus {
+    fn fmt(&self, f: &mut Formatter<'_>) -> fmt::Result {
+        match self {
+            TransferStatus::Pending => write!(f, "Pending"),
+            TransferStatus::Running => write!(f, "Running"),
+            TransferStatus::Succeeded => write!(f, "Succeeded"),
+            TransferStatus::Failed(msg) => write!(f, "Failed: {}", msg),
+        }
+    }
+}
+
+/// An export of an instance backup to the backup storage, or an import of an instance from it.
+#[derive(Debug, Clone, Serialize, Deserialize, Eq, PartialEq)]
+crate struct Transfer {
+    crate kind: TransferKind,
+    // The key of the backup tarball in the backup storage bucket.
+    crate object_key: String,
+    // The bucket of the backup storage holding the tarball, BACKUP_S3_BUCKET if empty.
+    #[serde(default)]
+    crate bucket: String,
+    // The LXD operation tracking the backup creation or the import.
+    crate operation: Option<String>,
+    crate status: TransferStatus,
+    // Human readable progress reported by the LXD operation.
+    crate progress: Option<String>,
+}
+
+/// Progress of the provisioning of an instance, reported by the operators until it's running.
+#[derive(Debug, Clone, Serialize, Deserialize, Eq, PartialEq)]
+crate struct Progress {
+    // What the instance is waiting for, e.g. "Downloading image".
+    crate phase: String,
+    // Percentage of the phase done, if the backend reports it.
+    crate percent: Option<u8>,
+    // The LXD operation creating the instance.
+    #[serde(default)]
+    crate operation: Option<String>,
+}
+
+impl Progress {
+    crate fn new(phase: &str) -> Self {
+        Progress {
+            phase: phase.to_owned(),
+            percent: None,
+            operation: None,
+        }
+    }
+}
+
+impl Transfer {
+    crate fn is_finished(&self) -> bool {
+        matches!(
+            self.status,
+            TransferStatus::Succeeded | TransferStatus::Failed(_)
+        )
+    }
+}
+
+// Where the root disk of the previous backend is attached in an instance rebuilt by a conversion:
+// the root filesystem of a container, or the disk image of a VM, which containers can't mount and
+// get as a file at this path with `.img` appended.
+crate const PREVIOUS_ROOT_PATH: &str = "/mnt/previous-root";
+
+/// A conversion of an instance to another runtime by rebuilding it from its image, with the same
+/// name, IP and password. The root disk of the previous backend is attached to the rebuilt
+/// instance read-only, see `PREVIOUS_ROOT_PATH`, so that the data can be copied over.
+#[derive(Debug, Clone, Serialize, Deserialize, Eq, PartialEq)]
+crate struct Conversion {
+    // The runtime the instance is converted from.
+    crate from: Runtime,
+    // The name the backend of the instance is renamed to before the instance is rebuilt. It's kept
+    // stopped as a recovery point of the data until the conversion is discarded.
+    crate previous_backend: String,
+    // Whether the backend is renamed, after which the instance is rebuilt.
+    crate renamed: bool,
+    // Whether the previous backend is to be deleted.
+    crate discarded: bool,
+}
+
+/// Backups of an instance exported periodically to the backup storage, see `crate::backup`.
+#[derive(Debug, Clone, Serialize, Deserialize, Eq, PartialEq)]
+crate struct BackupPolicy {
+    // Hours between the starts of two backups.
+    crate interval: u32,
+    // Number of successful backups kept, older ones are deleted from the bucket.
+    crate retention: usize,
+    // The bucket of the backup storage the backups are stored in, BACKUP_S3_BUCKET if empty.
+    #[serde(default)]
+    crate bucket: String,
+    // Backups taken according to the policy, oldest first.
+    #[serde(default)]
+    crate backups: Vec<Backup>,
+}
+
+impl BackupPolicy {
+    /// Returns true if a backup should be started at the given Unix timestamp.
+    crate fn is_due(&self, now: i64) -> bool {
+        match self.backups.last() {
+            Some(b) => b.is_finished() && b.started_at + i64::from(self.interval) * 3600 <= now,
+            None => true,
+        }
+    }
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize, Eq, PartialEq)]
+crate struct Backup {
+    crate object_key: String,
+    crate bucket: String,
+    // Unix timestamp in seconds.
+    crate started_at: i64,
+    crate status: TransferStatus,
+}
+
+impl Backup {
+    crate fn is_finished(&self) -> bool {
+        matches!(
+            self.status,
+            TransferStatus::Succeeded | TransferStatus::Failed(_)
+        )
+    }
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize, Eq, PartialEq)]
+crate struct User {
+    crate username: String,
+    // Quotas fall back to the configured defaults if they are not set.
+    #[serde(default)]
+    crate cpu_quota: Option<usize>,
+    #[serde(default)]
+    crate memory_quota: Option<usize>,
+    #[serde(default)]
+    crate disk_quota: Option<usize>,
+    #[serde(default)]
+    crate instance_quota: Option<usize>,
+    crate instances: Vec<Instance>,
+    // Unix timestamps of the instances created within the last day, oldest first.
+    #[serde(default)]
+    crate recent_creations: Vec<i64>,
+    // Unix timestamp the user first appeared in the state. Users are added to the state file by
+    // the admins, so this is when the state was first written after they were added.
+    #[serde(default)]
+    crate created_at: Option<i64>,
+    // The volume mounted at the home of the user in every instance of theirs, if opted in.
+    #[serde(default)]
+    crate home_volume: Option<HomeVolume>,
+    // Root disks of deleted instances, kept until they're attached to another instance or
+    // deleted.
+    #[serde(default)]
+    crate detached_volumes: Vec<DetachedVolume>,
+}
+
+/// A volume shared by the instances of a user, see `Config::home_volume_path`.
+#[derive(Debug, Clone, Serialize, Deserialize, Eq, PartialEq)]
+crate struct HomeVolume {
+    // In GiB, counted towards the disk quota of the user.
+    crate size: usize,
+    crate created_at: i64,
+}
+
+/// The root disk of a deleted instance, see `Instance::keep_disk`. It's backed by the
+/// PersistentVolumeClaim of the root disk, which is local to the node the instance ran on.
+#[derive(Debug, Clone, Serialize, Deserialize, Eq, PartialEq)]
+crate struct DetachedVolume {
+    // Name of the instance the volume was kept from.
+    crate name: String,
+    // In GiB, counted towards the disk quota of the user.
+    crate size: usize,
+    crate claim_name: String,
+    // Instances the volume is attached to are placed on this node.
+    crate node_name: Option<String>,
+    // The image the instance was created from.
+    crate image: String,
+    crate created_at: i64,
+}
+
+impl User {
+    crate fn cpu_quota(&self) -> usize {
+        self.cpu_quota
+            .unwrap_or_else(|| config::current().default_cpu_quota)
+    }
+
+    crate fn memory_quota(&self) -> usize {
+        self.memory_quota
+            .unwrap_or_else(|| config::current().default_memory_quota)
+    }
+
+    crate fn disk_quota(&self) -> usize {
+        self.disk_quota
+            .unwrap_or_else(|| config::current().default_disk_quota)
+    }
+
+    crate fn instance_quota(&self) -> usize {
+        self.instance_quota
+            .unwrap_or_else(|| config::current().default_instance_quota)
+    }
+
+    /// Returns the disk counted towards the disk quota of the user in GiB.
+    crate fn used_disk_size(&self) -> usize {
+        self.home_volume_size()
+            + self.detached_volume_size()
+            + self
+                .instances
+                .iter()
+                .map(|i| i.total_disk_size())
+                .sum::<usize>()
+    }
+
+    /// Returns the size of the home volume of the user in GiB, 0 if the user has none.
+    crate fn home_volume_size(&self) -> usize {
+        self.home_volume.as_ref().map_or(0, |v| v.size)
+    }
+
+    /// Returns the total size of the detached volumes of the user in GiB.
+    crate fn detached_volume_size(&self) -> usize {
+        self.detached_volumes.iter().map(|v| v.size).sum()
+    }
+
+    crate fn find_detached_volume(&self, name: &str) -> Option<&DetachedVolume> {
+        self.detached_volumes.iter().find(|v| v.name == name)
+    }
+
+    crate fn find_instance(&self, name: &str) -> Option<&Instance> {
+        self.instances.iter().find(|i| i.name == name)
+    }
+
+    /// Returns the creation rate limit the user has reached at `now` as the limit and its
+    /// period, along with the seconds until another instance may be created.
+    crate fn creation_rate_limit(&self, now: i64) -> Option<(usize, &'static str, i64)> {
+        let config = config::current();
+        for (limit, period, seconds) in [
+            (config.max_creations_per_hour, "hour", 3600),
+            (config.max_creations_per_day, "day", 24 * 3600),
+        ] {
+            if limit == 0 {
+                continue;
+            }
+            let recent: Vec<i64> = self
+                .recent_creations
+                .iter()
+                .copied()
+                .filter(|t| now - t < seconds)
+                .collect();
+            // Another instance may be created once enough of the recent creations age out.
+            if recent.len() >= limit {
+                let retry_after = recent[recent.len() - limit] + seconds - now;
+                return Some((limit, period, retry_after));
+            }
+        }
+        None
+    }
+
+    /// Records the creation of an instance at `now`, forgetting the creations older than a day.
+    crate fn record_creation(&mut self, now: i64) {
+        self.recent_creations.retain(|t| now - t < 24 * 3600);
+        self.recent_creations.push(now);
+    }
+
+    crate fn find_mut_instance(&mut self, name: &str) -> Option<&mut Instance> {
+        self.instances.iter_mut().find(|i| i.name == name)
+    }
+
+    crate fn remove_instance(&mut self, name: &str) {
+        self.instances
+            .iter_mut()
+            .position(|i| i.name == name)
+            .map(|i| self.instances.remove(i));
+    }
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize, Eq, PartialEq)]
+crate struct Node {
+    crate name: String,
+    crate storage_pools: Vec<StoragePool>,
+    crate runtimes: Vec<Runtime>,
+    crate cpu_total: usize,
+    crate cpu_allocated: usize,
+    crate memory_total: usize,
+    crate memory_allocated: usize,
+    crate storage_total: usize,
+    crate storage_used: usize,
+    crate storage_allocated: usize,
+    // None if the node is only known to runtimes that don't report the architecture.
+    #[serde(default)]
+    crate arch: Option<Arch>,
+    // Why the node can't run instances right now, None if the node is healthy.
+    #[serde(default)]
+    crate unhealthy_reason: Option<String>,
+    // The host CPUs of each NUMA node, only reported by LXD.
+    #[serde(default)]
+    crate numa_cpus: Vec<Vec<usize>>,
+    // The host CPUs dedicated instances on the node are pinned to.
+    #[serde(default)]
+    crate pinned_cpus: Vec<usize>,
+    // Whether new instances may be scheduled to the node, cleared by admins to cordon it.
+    #[serde(default = "default_schedulable")]
+    crate schedulable: bool,
+    // Free-form key/value pairs and notes set by admins.
+    #[serde(default)]
+    crate labels: BTreeMap<String, String>,
+    #[serde(default)]
+    crate notes: String,
+    // When the backends stopped reporting the node, None if the last collection reported it.
+    #[serde(default)]
+    crate missing_since: Option<i64>,
+    // Whether the instances on the node are being stopped, one at a time and the lowest priority
+    // first, e.g. before taking the node down. Draining nodes take no new instances.
+    #[serde(default)]
+    crate draining: bool,
+    #[serde(default)]
+    crate maintenance_windows: Vec<MaintenanceWindow>,
+}
+
+fn default_schedulable() -> bool {
+    true
+}
+
+/// A period during which a node is taken down for maintenance, e.g. to patch its kernel.
+#[derive(Debug, Clone, Serialize, Deserialize, Eq, PartialEq)]
+crate struct MaintenanceWindow {
+    // Unix timestamps of the start and the end of the window.
+    crate start: i64,
+    crate end: i64,
+    crate reason: String,
+    // Whether the owners of the instances on the node have been warned of the window.
+    #[serde(default)]
+    crate notified: bool,
+}
+
+impl Node {
+    crate fn is_healthy(&self) -> bool {
+        self.unhealthy_reason.is_none()
+    }
+
+    /// Returns true if new instances may be placed on the node.
+    crate fn is_schedulable(&self) -> bool {
+        self.schedulable
+            && !self.draining
+            && self.is_healthy()
+            && !self.in_maintenance(Utc::now().timestamp())
+    }
+
+    /// Returns true if one of the maintenance windows of the node is open at `now`.
+    crate fn in_maintenance(&self, now: i64) -> bool {
+        self.maintenance_windows
+            .iter()
+            .any(|w| w.start <= now && now < w.end)
+    }
+
+    crate fn can_run_arch(&self, arch: &Arch) -> bool {
+        self.arch.as_ref().unwrap_or(&Arch::Amd64) == arch
+    }
+
+    /// Returns the CPUs of the node instances may be allocated, leaving out the reserve.
+    crate fn usable_cpu(&self) -> usize {
+        let (cpu, _) = config::current().node_reserve(&self.name);
+        self.cpu_total - self.cpu_total * cpu / 100
+    }
+
+    /// Returns the memory of the node instances may be allocated, leaving out the reserve.
+    crate fn usable_memory(&self) -> usize {
+        let (_, memory) = config::current().node_reserve(&self.name);
+        self.memory_total - self.memory_total * memory / 100
+    }
+
+    /// Returns the storage of the node instances may be allocated, leaving out the reserves of
+    /// its pools.
+    crate fn usable_storage(&self) -> usize {
+        let reserved: usize = self
+            .storage_pools
+            .iter()
+            .map(|p| p.total - p.usable())
+            .sum();
+        self.storage_total.saturating_sub(reserved)
+    }
+
+    /// Returns `count` host CPUs not pinned yet, or None if there are not enough of them. The
+    /// CPUs are taken from the single NUMA node with the fewest free CPUs that still fit, so that
+    /// the instance doesn't access remote memory, and only spread over several NUMA nodes if
+    /// none of them fits alone.
+    crate fn pick_dedicated_cpus(&self, count: usize) -> Option<Vec<usize>> {
+        let mut free: Vec<Vec<usize>> = self
+            .numa_cpus
+            .iter()
+            .map(|cpus| {
+                cpus.iter()
+                    .filter(|c| !self.pinned_cpus.contains(c))
+                    .cloned()
+                    .collect()
+            })
+            .collect();
+        if free.iter().map(|cpus| cpus.len()).sum::<usize>() < count {
+            return None;
+        }
+        if let Some(cpus) = free
+            .iter()
+            .filter(|cpus| cpus.len() >= count)
+            .min_by_key(|cpus| cpus.len())
+        {
+            return Some(cpus[..count].to_vec());
+        }
+        free.sort_by_key(|cpus| std::cmp::Reverse(cpus.len()));
+        let mut picked: Vec<usize> = free.into_iter().flatten().take(count).collect();
+        picked.sort_unstable();
+        Some(picked)
+    }
+}
+
+// Namespace of the name-based IDs of the instances created before instances had IDs.
+const LEGACY_INSTANCE_ID_NAMESPACE: [u8; 16] = [
+    0x3d, 0x8e, 0x5a, 0x41, 0x0c, 0x7b, 0x4f, 0x52, 0x9a, 0x1e, 0x66, 0xd2, 0x84, 0x3b, 0xf0, 0x17,
+];
+
+fn format_uuid(bytes: &[u8]) -> String {
+    let hex: String = bytes.iter().map(|b| format!("{:02x}", b)).collect();
+    format!(
+        "{}-{}-{}-{}-{}",
+        &hex[..8],
+        &hex[8..12],
+        &hex[12..16],
+        &hex[16..20],
+        &hex[20..32]
+    )
+}
+
+/// Returns a random (version 4) UUID for a new instance.
+crate fn new_instance_id() -> String {
+    let mut bytes: [u8; 16] = thread_rng().gen();
+    bytes[6] = bytes[6] & 0x0f | 0x40;
+    bytes[8] = bytes[8] & 0x3f | 0x80;
+    format_uuid(&bytes)
+}
+
+/// Returns the name-based (version 5) UUID of an instance created before instances had IDs.
+fn legacy_instance_id(username: &str, name: &str) -> String {
+    let mut ctx = digest::Context::new(&digest::SHA1_FOR_LEGACY_USE_ONLY);
+    ctx.update(&LEGACY_INSTANCE_ID_NAMESPACE);
+    ctx.update(format!("{}/{}", username, name).as_bytes());
+    let mut bytes = [0u8; 16];
+    bytes.copy_from_slice(&ctx.finish().as_ref()[..16]);
+    bytes[6] = bytes[6] & 0x0f | 0x50;
+    bytes[8] = bytes[8] & 0x3f | 0x80;
+    format_uuid(&bytes)
+}
+
+/// Returns the overcommitted CPUs taken by an instance with `cpu` dedicated CPUs, which are not
+/// shared with other instances.
+crate fn dedicated_cpu_allocation(cpu: usize) -> usize {
+    (cpu as f64 * config::current().cpu_overcommit_factor.max(1.0)).ceil() as usize
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize, Eq, PartialEq)]
+crate struct StoragePool {
+    crate name: String,
+    crate total: usize,
+    crate used: usize,
+    crate allocated: usize,
+    // Whether the pool is shared by all nodes, such as ceph, rather than local to each node.
+    #[serde(default)]
+    crate shared: bool,
+}
+
+impl StoragePool {
+    /// Returns the size of the pool instances may be allocated, leaving out the reserve.
+    crate fn usable(&self) -> usize {
+        let reserve = config::current().storage_pool_reserve(&self.name);
+        self.total - self.total * reserve / 100
+    }
+}
+
+#[derive(Debug, Default, Clone, Serialize, Deserialize, Eq, PartialEq)]
+crate struct State {
+    crate users: Vec<User>,
+    #[serde(default)]
+    crate nodes: Vec<Node>,
+    // Resource consumption of running instances per user and day.
+    #[serde(default)]
+    crate usage: Vec<UsageRecord>,
+    #[serde(default)]
+    crate groups: Vec<Group>,
+    #[serde(default)]
+    crate projects: Vec<Project>,
+    #[serde(default = "default_images")]
+    crate images: Vec<CatalogImage>,
+    #[serde(default)]
+    crate image_builds: Vec<ImageBuild>,
+    // Assignments of the addresses of the IP pools to instances, oldest first.
+    #[serde(default)]
+    crate ip_assignments: Vec<IpAssignment>,
+    // Idempotency keys of the instances created recently, oldest first.
+    #[serde(default)]
+    crate idempotency_keys: Vec<IdempotencyKey>,
+    // Addresses of the IP pools edited through the admin API.
+    #[serde(default)]
+    crate ip_pool_ranges: Vec<IpPoolRanges>,
+    #[serde(default)]
+    crate priority_classes: Vec<PriorityClass>,
+    #[serde(default)]
+    crate instance_groups: Vec<InstanceGroup>,
+    #[serde(default)]
+    crate topology_templates: Vec<TopologyTemplate>,
+}
+
+/// Instances of a user created together from a template, e.g. the nodes of a test cluster. The
+/// members are named `<group>-1` to `<group>-<size>`, or after their roles if created from a
+/// `TopologyTemplate`, and are started, stopped and deleted together.
+#[derive(Debug, Clone, Serialize, Deserialize, Eq, PartialEq)]
+crate struct InstanceGroup {
+    crate name: String,
+    crate owner: String,
+    crate size: usize,
+    // Labels shared by the members, on top of the ones of the template.
+    crate labels: BTreeMap<String, String>,
+    // Unix timestamp in seconds.
+    crate created_at: i64,
+    // The `TopologyTemplate` the group was created from, if any.
+    #[serde(default)]
+    crate topology: Option<String>,
+    // Port each role of the topology serves on, copied from the template so that later edits of
+    // the template don't affect the group.
+    #[serde(default)]
+    crate ports: BTreeMap<String, u16>,
+    // Unix timestamp the group was deleted. It's kept while members are left, e.g. in the recycle
+    // bin, and revived if one of them is restored.
+    #[serde(default)]
+    crate deleted_at: Option<i64>,
+}
+
+/// A stack of instances in roles defined by admins in the catalog, e.g. the PD, TiKV and TiDB
+/// servers of a TiDB cluster. Groups created from the template have `count` members of each role,
+/// named `<group>-<role>-<index>`.
+#[derive(Debug, Clone, Serialize, Deserialize, Eq, PartialEq)]
+crate struct TopologyTemplate {
+    crate name: String,
+    crate description: String,
+    crate roles: Vec<TopologyRole>,
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize, Eq, PartialEq)]
+crate struct TopologyRole {
+    crate name: String,
+    crate count: usize,
+    crate cpu: usize,
+    // Memory and disk sizes in GiB.
+    crate memory: usize,
+    crate disk_size: usize,
+    // Image and runtime of the members, the defaults if empty.
+    crate image: String,
+    crate runtime: String,
+    // Port the members serve clients on, reported in the connection info of groups. 0 if none.
+    crate port: u16,
+}
+
+impl TopologyTemplate {
+    /// Returns the number of members of groups created from the template.
+    crate fn size(&self) -> usize {
+        self.roles.iter().map(|r| r.count).sum()
+    }
+}
+
+/// A tier of instances defined by admins. When nodes are drained or short of resources, the
+/// instances of the lowest values are stopped first.
+#[derive(Debug, Clone, Serialize, Deserialize, Eq, PartialEq)]
+crate struct PriorityClass {
+    crate name: String,
+    // Instances without a class have the value 0.
+    crate value: i32,
+    // Whether the instances of the class are preemptible, see `Priority`.
+    crate preemptible: bool,
+    crate description: String,
+    // Users allowed to create instances of the class besides the admins, anyone if empty.
+    #[serde(default)]
+    crate users: Vec<String>,
+}
+
+impl PriorityClass {
+    crate fn is_allowed(&self, username: &str) -> bool {
+        self.users.is_empty() || self.users.iter().any(|u| u == username)
+    }
+}
+
+/// The addresses of an IP pool as edited at runtime, taking the place of the ranges and
+/// exclusions the config gives the pool. They are seeded from the config by the first edit, so
+/// later changes of the config don't affect the pool anymore.
+#[derive(Debug, Clone, Serialize, Deserialize, Eq, PartialEq)]
+crate struct IpPoolRanges {
+    crate pool: String,
+    crate ranges: Vec<String>,
+    crate exclude: Vec<String>,
+}
+
+/// The outcome of a create request carrying an `Idempotency-Key` header, so that retries of the
+/// request get the instance created by the first one. Failed requests are not recorded as they
+/// change nothing and can simply be retried.
+#[derive(Debug, Clone, Serialize, Deserialize, Eq, PartialEq)]
+crate struct IdempotencyKey {
+    crate username: String,
+    crate key: String,
+    // Digest of the request, which retries must repeat.
+    crate request_digest: String,
+    crate instance: String,
+    // Unix timestamp in seconds.
+    crate created_at: i64,
+}
+
+/// Seconds an idempotency key is kept for.
+crate const IDEMPOTENCY_KEY_TTL: i64 = 24 * 3600;
+
+/// An external IP held by an instance, kept after the release to audit the reuse of addresses
+/// and to hold released addresses back for a while, see `crate::scheduler`.
+#[derive(Debug, Clone, Serialize, Deserialize, Eq, PartialEq)]
+crate struct IpAssignment {
+    crate ip: String,
+    crate username: String,
+    crate instance: String,
+    // Unix timestamps.
+    crate assigned_at: i64,
+    crate released_at: Option<i64>,
+    // Whether the IP stays reserved for the instance name after the release, so that rebuilding
+    // the instance keeps the IP.
+    #[serde(default)]
+    crate pinned: bool,
+}
+
+impl IpAssignment {
+    /// Returns true if the released IP may not be allocated to other instances yet.
+    crate fn is_reserved(&self, now: i64, grace_period: i64) -> bool {
+        match self.released_at {
+            Some(t) => self.pinned || now - t < grace_period,
+            None => false,
+        }
+    }
+}
+
+/// Returns the catalog of the images supported before the catalog was introduced.
+fn default_images() -> Vec<CatalogImage> {
+    let image = |name: &str, family, sources: &[(Runtime, &str)], default| {
+        let mut sources: BTreeMap<String, String> = sources
+            .iter()
+            .map(|(runtime, source)| (runtime.to_string(), source.to_string()))
+            .collect();
+        if let Some(ami) = EC2_IMAGES.get(name) {
+            sources.insert(Runtime::Ec2.to_string(), ami.clone());
+        }
+        CatalogImage {
+            name: Image(name.to_owned()),
+            family,
+            sources,
+            arches: vec![Arch::Amd64, Arch::Arm64],
+            enabled: true,
+            default,
+            owner: None,
+            mounts: Vec::new(),
+        }
+    };
+    vec![
+        image(
+            "centos:7",
+            ImageFamily::CentOS,
+            &[
+                (Runtime::Lxc, "centos/7/cloud"),
+                (Runtime::Kvm, "centos/7/cloud"),
+            ],
+            false,
+        ),
+        image(
+            "centos:9-Stream",
+            ImageFamily::CentOS,
+            &[
+                (Runtime::Lxc, "centos/9-Stream/cloud"),
+                (Runtime::Kvm, "centos/9-Stream/cloud"),
+            ],
+            false,
+        ),
+        image(
+            "ubuntu:20.04",
+            ImageFamily::Ubuntu,
+            &[
+                (Runtime::Lxc, "ubuntu/20.04/cloud"),
+                (Runtime::Kvm, "ubuntu/20.04/cloud"),
+                (Runtime::MicroVm, "ubuntu/20.04"),
+            ],
+            false,
+        ),
+        image(
+            "ubuntu:22.04",
+            ImageFamily::Ubuntu,
+            &[
+                (Runtime::Lxc, "ubuntu/22.04/cloud"),
+                (Runtime::Kvm, "ubuntu/22.04/cloud"),
+                (Runtime::MicroVm, "ubuntu/22.04"),
+            ],
+            true,
+        ),
+    ]
+}
+
+/// Users pooling their capacity. The aggregate usage of the members is limited by the group
+/// quotas in addition to their own quotas.
+#[derive(Debug, Clone, Serialize, Deserialize, Eq, PartialEq)]
+crate struct Group {
+    crate name: String,
+    crate members: Vec<String>,
+    crate cpu_quota: usize,
+    crate memory_quota: usize,
+    crate disk_quota: usize,
+    crate instance_quota: usize,
+}
+
+/// Instances shared by the members of a project. The aggregate usage of the instances in the
+/// project is limited by the project quotas in addition to the quotas of their owners.
+#[derive(Debug, Clone, Serialize, Deserialize, Eq, PartialEq)]
+crate struct Project {
+    crate name: String,
+    crate members: Vec<String>,
+    crate cpu_quota: usize,
+    crate memory_quota: usize,
+    crate disk_quota: usize,
+    crate instance_quota: usize,
+}
+
+impl Project {
+    crate fn is_member(&self, username: &str) -> bool {
+        self.members.iter().any(|m| m == username)
+    }
+}
+
+/// Resources consumed by the running instances of a user on a day, in resource-seconds.
+#[derive(Debug, Default, Clone, Serialize, Deserialize, Eq, PartialEq)]
+crate struct UsageRecord {
+    crate username: String,
+    // UTC date formatted as YYYY-MM-DD.
+    crate date: String,
+    crate instance_seconds: u64,
+    crate cpu_seconds: u64,
+    // Memory and disk are in GiB.
+    crate memory_seconds: u64,
+    crate disk_seconds: u64,
+}
+
+impl State {
+    crate fn find_user(&self, username: &str) -> Option<&User> {
+        self.users.iter().find(|u| u.username == username)
+    }
+
+    crate fn find_mut_user(&mut self, username: &str) -> Option<&mut User> {
+        self.users.iter_mut().find(|u| u.username == username)
+    }
+
+    /// Returns the groups the user is a member of.
+    crate fn groups_of<'a>(&'a self, username: &'a str) -> impl Iterator<Item = &'a Group> {
+        self.groups
+            .iter()
+            .filter(move |g| g.members.iter().any(|m| m == username))
+    }
+
+    /// Returns the instance named `name` of `owner`, or of the user if `owner` is None, provided
+    /// that the user owns it or it is shared with the user.
+    crate fn find_mut_accessible_instance(
+        &mut self,
+        username: &str,
+        owner: Option<&str>,
+        name: &str,
+    ) -> Option<&mut Instance> {
+        let owner = owner.unwrap_or(username);
+        self.find_mut_user(owner)
+            .and_then(|u| u.find_mut_instance(name))
+            .filter(|i| owner == username || i.is_shared_with(username))
+    }
+
+    /// Returns true if the node of the instance has room for the CPU and memory the instance gave
+    /// up when it was stopped, if any.
+    crate fn has_room_to_start(&self, instance: &Instance) -> bool {
+        if instance.holds_compute() {
+            return true;
+        }
+        match instance
+            .node_name
+            .as_deref()
+            .and_then(|n| self.find_node(n))
+        {
+            Some(n) => {
+                instance.allocated_cpu() + n.cpu_allocated <= n.usable_cpu()
+                    && instance.memory + n.memory_allocated <= n.usable_memory()
+            }
+            None => true,
+        }
+    }
+
+    /// Returns the CPU and memory held by the running preemptible instances on the node, which
+    /// the scheduler may take back for normal instances.
+    crate fn preemptible_compute(&self, node_name: &str) -> (usize, usize) {
+        self.users
+            .iter()
+            .flat_map(|u| &u.instances)
+            .filter(|i| {
+                i.priority == Priority::Preemptible
+                    && i.stage == InstanceStage::Running
+                    && i.node_name.as_deref() == Some(node_name)
+            })
+            .fold((0, 0), |(cpu, memory), i| {
+                (cpu + i.allocated_cpu(), memory + i.memory)
+            })
+    }
+
+    crate fn find_accessible_instance(
+        &self,
+        username: &str,
+        owner: Option<&str>,
+        name: &str,
+    ) -> Option<&Instance> {
+        let owner = owner.unwrap_or(username);
+        self.find_user(owner)
+            .and_then(|u| u.find_instance(name))
+            .filter(|i| owner == username || i.is_shared_with(username))
+    }
+
+    /// Returns the IP pools along with their addresses, starting with the default one. Edited
+    /// pools have their addresses taken from `ip_pool_ranges`.
+    crate fn ip_pools(&self) -> Vec<(IpPool, Vec<String>)> {
+        let config = config::current();
+        let mut pools = vec![config.ip_pool(None)];
+        pools.extend(
+            config
+                .ip_pools
+                .iter()
+                .map(|p| config.ip_pool(Some(&p.name))),
+        );
+        pools
+            .into_iter()
+            .map(|mut p| {
+                if let Some(r) = self.ip_pool_ranges.iter().find(|r| r.pool == p.name) {
+                    p.ranges = r.ranges.clone();
+                    p.exclude = r.exclude.clone();
+                }
+                // Edits are validated, so the addresses only fail to parse in a broken state.
+                let ips = p.addresses().unwrap_or_default();
+                (p, ips)
+            })
+            .collect()
+    }
+
+    /// Returns the owner and the instance with the internal or external IP, if any.
+    crate fn find_instance_by_ip(&self, ip: &str) -> Option<(&User, &Instance)> {
+        self.users.iter().find_map(|u| {
+            u.instances
+                .iter()
+                .find(|i| {
+                    i.stage != InstanceStage::Deleted
+                        && (i.internal_ip.as_deref() == Some(ip)
+                            || i.external_ip.as_deref() == Some(ip))
+                })
+                .map(|i| (u, i))
+        })
+    }
+
+    crate fn find_mut_instance_by_ip(&mut self, ip: &str) -> Option<&mut Instance> {
+        self.users
+            .iter_mut()
+            .flat_map(|u| &mut u.instances)
+            .find(|i| {
+                i.stage != InstanceStage::Deleted
+                    && (i.internal_ip.as_deref() == Some(ip)
+                        || i.external_ip.as_deref() == Some(ip))
+            })
+    }
+
+    crate fn find_project(&self, name: &str) -> Option<&Project> {
+        self.projects.iter().find(|p| p.name == name)
+    }
+
+    crate fn find_instance_group(&self, owner: &str, name: &str) -> Option<&InstanceGroup> {
+        self.instance_groups
+            .iter()
+            .find(|g| g.owner == owner && g.name == name)
+    }
+
+    /// Forgets the deleted instance groups which have no members left. Returns true if any group
+    /// was forgotten.
+    crate fn forget_deleted_instance_groups(&mut self) -> bool {
+        let users = &self.users;
+        let len = self.instance_groups.len();
+        self.instance_groups.retain(|g| {
+            g.deleted_at.is_none()
+                || users
+                    .iter()
+                    .filter(|u| u.username == g.owner)
+                    .flat_map(|u| &u.instances)
+                    .any(|i| i.instance_group.as_deref() == Some(g.name.as_str()))
+        });
+        self.instance_groups.len() != len
+    }
+
+    crate fn find_topology_template(&self, name: &str) -> Option<&TopologyTemplate> {
+        self.topology_templates.iter().find(|t| t.name == name)
+    }
+
+    crate fn find_priority_class(&self, name: &str) -> Option<&PriorityClass> {
+        self.priority_classes.iter().find(|c| c.name == name)
+    }
+
+    /// Returns the value of the priority class of the instance, 0 if it has none.
+    crate fn priority_value(&self, instance: &Instance) -> i32 {
+        instance
+            .priority_class
+            .as_deref()
+            .and_then(|c| self.find_priority_class(c))
+            .map_or(0, |c| c.value)
+    }
+
+    crate fn find_node(&self, name: &str) -> Option<&Node> {
+        self.nodes.iter().find(|n| n.name == name)
+    }
+
+    crate fn find_mut_node(&mut self, name: &str) -> Option<&mut Node> {
+        self.nodes.iter_mut().find(|n| n.name == name)
+    }
+
+    /// Returns the event to record if the node of the instance is unhealthy and the instance
+    /// doesn't know it yet.
+    crate fn unhealthy_node_event(&self, instance: &Instance) -> Option<String> {
+        let node = instance
+            .node_name
+            .as_ref()
+            .and_then(|n| self.find_node(n))?;
+        let message = format!(
+            "node {} is unhealthy: {}",
+            node.name,
+            node.unhealthy_reason.as_ref()?
+        );
+        if instance.events.last().map(|e| &e.message) == Some(&message) {
+            return None;
+        }
+        Some(message)
+    }
+
+    /// Records the status transitions of the instances since the previous state, keeping at most
+    /// MAX_STATUS_HISTORY transitions per instance.
+    crate fn record_status_transitions(&mut self, previous: &State) {
+        let now = Utc::now().timestamp();
+        for u in &mut self.users {
+            let previous_user = previous.find_user(&u.username);
+            for i in &mut u.instances {
+                let previous_status = previous_user
+                    .and_then(|p| p.find_instance(&i.name))
+                    .map(|p| &p.status);
+                // Instances moved between users keep their history.
+                if previous_status == Some(&i.status)
+                    || i.status_history.last().map(|t| &t.status) == Some(&i.status)
+                {
+                    continue;
+                }
+                i.status_history.push(StatusTransition {
+                    timestamp: now,
+                    status: i.status.clone(),
+                });
+                if i.status_history.len() > MAX_STATUS_HISTORY {
+                    i.status_history.remove(0);
+                }
+            }
+        }
+    }
+
+    /// Starts the current run of the instances which entered Running and adds the runs of the
+    /// ones which left it to their uptime.
+    crate fn track_uptime(&mut self) {
+        let now = Utc::now().timestamp();
+        for i in self.users.iter_mut().flat_map(|u| u.instances.iter_mut()) {
+            let running = i.status == InstanceStatus::Running;
+            match i.started_at {
+                None if running => i.started_at = Some(now),
+                Some(_) if !running => {
+                    i.accumulated_uptime = i.uptime(now);
+                    i.started_at = None;
+                    i.last_running_at = Some(now);
+                }
+                _ => {}
+            }
+        }
+    }
+
+    /// Clears the progress of the instances which are no longer being provisioned.
+    crate fn clear_provisioning_progress(&mut self) {
+        for i in self.users.iter_mut().flat_map(|u| u.instances.iter_mut()) {
+            if !matches!(
+                i.status,
+                InstanceStatus::Creating | InstanceStatus::Starting
+            ) {
+                i.progress = None;
+            }
+        }
+    }
+
+    /// Stamps the users added and the instances changed or marked for deletion since the
+    /// previous state.
+    crate fn stamp_timestamps(&mut self, previous: &State) {
+        let now = Utc::now().timestamp();
+        for u in &mut self.users {
+            u.created_at.get_or_insert(now);
+            let previous_user = previous.find_user(&u.username);
+            for i in &mut u.instances {
+                if previous_user.and_then(|p| p.find_instance(&i.name)) != Some(&*i) {
+                    i.updated_at = Some(now);
+                }
+                if i.stage == InstanceStage::Deleted {
+                    i.deleted_at.get_or_insert(now);
+                }
+            }
+        }
+    }
+
+    /// Hashes the passwords of the instances which have been provisioned.
+    crate fn hash_provisioned_passwords(&mut self) {
+        for u in &mut self.users {
+            for i in &mut u.instances {
+                if i.status == InstanceStatus::Running {
+                    i.hash_password();
+                }
+            }
+        }
+    }
+
+    crate fn sync_allocated_resources(&mut self) {
+        let mut cpu_allocated: HashMap<String, usize> = HashMap::new();
+        let mut pinned_cpus: HashMap<String, Vec<usize>> = HashMap::new();
+        let mut memory_allocated: HashMap<String, usize> = HashMap::new();
+        // Map of (node_name, storage_pool) to the allocated capacity of each storage pool.
+        let mut storage_allocated: HashMap<(String, String), usize> = HashMap::new();
+        // Map of node name to total allocated capacity of all storage pools on each node.
+        let mut node_storage_allocated_total: HashMap<String, usize> = HashMap::new();
+        // Map of storage pool to the capacity allocated on it from all nodes.
+        let mut pool_allocated_total: HashMap<String, usize> = HashMap::new();
+
+        for u in &mut self.users {
             for i in &mut u.instances {
                 if let Some(node_name) = &i.node_name {
-                    *cpu_allocated.entry(node_name.clone()).or_default() += i.cpu;
-                    *memory_allocated.entry(node_name.clone()).or_default() += i.memory;
+                    if i.holds_compute() {
+                        *cpu_allocated.entry(node_name.clone()).or_default() += i.allocated_cpu();
+                        *memory_allocated.entry(node_name.clone()).or_default() += i.memory;
+                    }
+                    pinned_cpus
+                        .entry(node_name.clone())
+                        .or_default()
+                        .extend(&i.pinned_cpus);
                     if let Some(storage_pool) = &i.storage_pool {
                         *storage_allocated
                             .entry((node_name.clone(), storage_pool.clone()))
-                            .or_default() += i.disk_size;
+                            .or_default() += i.total_disk_size();
+                        *pool_allocated_total
+                            .entry(storage_pool.clone())
+                            .or_default() += i.total_disk_size();
                     }
                     *node_storage_allocated_total
                         .entry(node_name.clone())
-                        .or_default() += i.disk_size;
+                        .or_default() += i.total_disk_size();
                 }
             }
         }
 
         for node in &mut self.nodes {
             node.cpu_allocated = cpu_allocated.get(&node.name).cloned().unwrap_or_default();
+            node.pinned_cpus = pinned_cpus.remove(&node.name).unwrap_or_default();
+            node.pinned_cpus.sort_unstable();
             node.memory_allocated = memory_allocated
                 .get(&node.name)
                 .cloned()
@@ -351,6 +2161,15 @@ impl State {
                     .get(&(node.name.clone(), s.name.clone()))
                     .cloned()
                     .unwrap_or_default();
+                // Instances on other nodes consume the same shared pool.
+                if s.shared {
+                    let total = pool_allocated_total
+                        .get(&s.name)
+                        .cloned()
+                        .unwrap_or_default();
+                    node.storage_allocated += total - s.allocated;
+                    s.allocated = total;
+                }
             }
         }
     }
@@ -358,6 +2177,365 @@ impl State {
 
 impl State {
     crate fn new() -> Self {
-        Default::default()
+        State {
+            images: default_images(),
+            ..Default::default()
+        }
+    }
+
+    /// Returns the image named `name` available to the user, or the default image if `name` is
+    /// None.
+    crate fn find_image(&self, name: Option<&Image>, username: &str) -> Option<&CatalogImage> {
+        self.images
+            .iter()
+            .filter(|i| i.available_to(username))
+            .find(|i| match name {
+                Some(name) => &i.name == name,
+                None => i.default && i.owner.is_none(),
+            })
+    }
+
+    /// Assigns IDs to the instances created before instances had them. The IDs are derived from
+    /// the owners and names, so that they are the same on every load until the state is written.
+    crate fn assign_instance_ids(&mut self) {
+        for u in &mut self.users {
+            for i in u.instances.iter_mut().filter(|i| i.id.is_empty()) {
+                i.id = legacy_instance_id(&u.username, &i.name);
+            }
+        }
+    }
+
+    /// Repairs the instances whose statuses contradict their stages, which earlier versions could
+    /// leave behind.
+    crate fn repair_instance_statuses(&mut self) {
+        for u in &mut self.users {
+            for i in &mut u.instances {
+                lifecycle::repair(i);
+            }
+        }
+    }
+
+    /// Updates the nodes with the ones collected from the backends, keeping what admins set on
+    /// them and their allocations. Nodes no longer reported are kept and flagged as missing
+    /// rather than dropped, so that the instances on them don't silently lose their node.
+    crate fn merge_collected_nodes(&mut self, collected: &[Node], now: i64) {
+        for n in &mut self.nodes {
+            match collected.iter().find(|c| c.name == n.name) {
+                Some(c) => {
+                    n.storage_pools = c
+                        .storage_pools
+                        .iter()
+                        .map(|p| StoragePool {
+                            allocated: n
+                                .storage_pools
+                                .iter()
+                                .find(|o| o.name == p.name)
+                                .map_or(0, |o| o.allocated),
+                            ..p.clone()
+                        })
+                        .collect();
+                    n.runtimes = c.runtimes.clone();
+                    n.cpu_total = c.cpu_total;
+                    n.memory_total = c.memory_total;
+                    n.storage_total = c.storage_total;
+                    n.storage_used = c.storage_used;
+                    n.arch = c.arch.clone();
+                    n.unhealthy_reason = c.unhealthy_reason.clone();
+                    n.numa_cpus = c.numa_cpus.clone();
+                    n.missing_since = None;
+                }
+                None => {
+                    n.missing_since.get_or_insert(now);
+                    n.unhealthy_reason = Some("not reported by any backend".to_owned());
+                }
+            }
+        }
+        for c in collected {
+            if self.find_node(&c.name).is_none() {
+                self.nodes.push(c.clone());
+            }
+        }
+        self.nodes.sort_by(|a, b| a.name.cmp(&b.name));
+    }
+
+    /// Returns the owner and the instance with the ID, if any.
+    crate fn find_instance_by_id(&self, id: &str) -> Option<(&User, &Instance)> {
+        self.users
+            .iter()
+            .find_map(|u| u.instances.iter().find(|i| i.id == id).map(|i| (u, i)))
+    }
+
+    /// Resolves the image source and family of the instances created before the image catalog
+    /// was introduced.
+    crate fn resolve_image_sources(&mut self) {
+        // The Kubernetes runtimes no longer accept new instances, so their images are not in
+        // the catalog.
+        const K8S_REPOSITORIES: [(&str, &str); 2] = [
+            ("centos:7", "tispace/centos7"),
+            ("ubuntu:20.04", "tispace/ubuntu2004"),
+        ];
+        for u in &mut self.users {
+            for i in &mut u.instances {
+                if i.image_source.is_some() {
+                    continue;
+                }
+                let name = i.image.to_string();
+                i.image_family = if name.starts_with("centos") {
+                    ImageFamily::CentOS
+                } else {
+                    ImageFamily::Ubuntu
+                };
+                i.image_source = match i.runtime {
+                    Runtime::Kata | Runtime::Runc => K8S_REPOSITORIES
+                        .iter()
+                        .find(|(image, _)| *image == name)
+                        .map(|(_, repository)| repository.to_string()),
+                    _ => self
+                        .images
+                        .iter()
+                        .find(|c| c.name == i.image)
+                        .and_then(|c| c.source(&i.runtime))
+                        .map(|s| s.to_owned()),
+                };
+            }
+        }
+    }
+
+    /// Forgets the usage of the days before `date`. Returns true if any usage was forgotten.
+    crate fn prune_usage(&mut self, date: &str) -> bool {
+        let len = self.usage.len();
+        // Dates formatted as YYYY-MM-DD sort chronologically.
+        self.usage.retain(|r| r.date.as_str() >= date);
+        self.usage.len() != len
+    }
+
+    /// Adds `seconds` of running time of every running instance to the usage of `date`.
+    crate fn meter_usage(&mut self, date: &str, seconds: u64) {
+        for u in &self.users {
+            let running = u
+                .instances
+                .iter()
+                .filter(|i| i.status == InstanceStatus::Running);
+            let mut record: Option<UsageRecord> = None;
+            for i in running {
+                let r = record.get_or_insert_with(Default::default);
+                r.instance_seconds += seconds;
+                r.cpu_seconds += i.cpu as u64 * seconds;
+                r.memory_seconds += i.memory as u64 * seconds;
+                r.disk_seconds += i.total_disk_size() as u64 * seconds;
+            }
+            let record = match record {
+                Some(r) => r,
+                None => continue,
+            };
+            match self
+                .usage
+                .iter_mut()
+                .find(|r| r.username == u.username && r.date == date)
+            {
+                Some(r) => {
+                    r.instance_seconds += record.instance_seconds;
+                    r.cpu_seconds += record.cpu_seconds;
+                    r.memory_seconds += record.memory_seconds;
+                    r.disk_seconds += record.disk_seconds;
+                }
+                None => self.usage.push(UsageRecord {
+                    username: u.username.clone(),
+                    date: date.to_owned(),
+                    ..record
+                }),
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_custom_image_name() {
+        for name in ["dev/base:1.0", "Dev_User/base:1.0", "ubuntu:22.04"] {
+            assert_eq!(name.parse::<Image>().unwrap().to_string(), name);
+        }
+        for name in ["-dev/base", "dev/base/1.0", "dev/", "dev user/base"] {
+            assert!(name.parse::<Image>().is_err(), "{}", name);
+        }
+    }
+
+    fn power_schedule(days: &[&str], utc_offset: i32) -> PowerSchedule {
+        PowerSchedule {
+            start_at: Some("08:00".to_owned()),
+            stop_at: Some("20:00".to_owned()),
+            days: days.iter().map(|d| d.to_string()).collect(),
+            utc_offset,
+            skip_until: None,
+            last_due: 0,
+        }
+    }
+
+    fn utc(day: u32, hour: u32) -> DateTime<Utc> {
+        // 2024-01-01 is a Monday.
+        Utc.ymd(2024, 1, day).and_hms(hour, 0, 0)
+    }
+
+    #[test]
+    fn test_power_schedule_same_day() {
+        let schedule = power_schedule(&[], 0);
+        assert_eq!(
+            schedule.due_action(utc(2, 7)),
+            Some((InstanceStage::Stopped, utc(1, 20).timestamp()))
+        );
+        assert_eq!(
+            schedule.due_action(utc(2, 8)),
+            Some((InstanceStage::Running, utc(2, 8).timestamp()))
+        );
+        assert_eq!(
+            schedule.due_action(utc(2, 12)),
+            Some((InstanceStage::Running, utc(2, 8).timestamp()))
+        );
+        assert_eq!(
+            schedule.due_action(utc(2, 21)),
+            Some((InstanceStage::Stopped, utc(2, 20).timestamp()))
+        );
+    }
+
+    #[test]
+    fn test_power_schedule_days() {
+        // Today is excluded, the latest action is the stop on the last day included.
+        let schedule = power_schedule(&["fri"], 0);
+        assert_eq!(
+            schedule.due_action(utc(1, 12)),
+            Some((
+                InstanceStage::Stopped,
+                Utc.ymd(2023, 12, 29).and_hms(20, 0, 0).timestamp()
+            ))
+        );
+        let schedule = power_schedule(&["mon", "wed"], 0);
+        assert_eq!(
+            schedule.due_action(utc(2, 12)),
+            Some((InstanceStage::Stopped, utc(1, 20).timestamp()))
+        );
+        assert_eq!(
+            schedule.due_action(utc(3, 9)),
+            Some((InstanceStage::Running, utc(3, 8).timestamp()))
+        );
+    }
+
+    #[test]
+    fn test_power_schedule_negative_utc_offset() {
+        // UTC-5, where 08:00 and 20:00 are 13:00 and 01:00 of the next day in UTC.
+        let schedule = power_schedule(&["mon"], -300);
+        // Before 08:00 on Monday, the latest action is the stop of the Monday before.
+        assert_eq!(
+            schedule.due_action(utc(1, 12)),
+            Some((
+                InstanceStage::Stopped,
+                Utc.ymd(2023, 12, 26).and_hms(1, 0, 0).timestamp()
+            ))
+        );
+        assert_eq!(
+            schedule.due_action(utc(1, 13)),
+            Some((InstanceStage::Running, utc(1, 13).timestamp()))
+        );
+        // Still Monday in UTC-5 while it's Tuesday in UTC.
+        assert_eq!(
+            schedule.due_action(utc(2, 0)),
+            Some((InstanceStage::Running, utc(1, 13).timestamp()))
+        );
+        assert_eq!(
+            schedule.due_action(utc(2, 1)),
+            Some((InstanceStage::Stopped, utc(2, 1).timestamp()))
+        );
+    }
+
+    #[test]
+    fn test_power_schedule_applied() {
+        let mut schedule = power_schedule(&[], 0);
+        schedule.last_due = utc(2, 8).timestamp();
+        assert_eq!(schedule.due_action(utc(2, 12)), None);
+        assert_eq!(
+            schedule.due_action(utc(2, 20)),
+            Some((InstanceStage::Stopped, utc(2, 20).timestamp()))
+        );
+        schedule.last_due = utc(2, 20).timestamp();
+        assert_eq!(schedule.due_action(utc(2, 23)), None);
+        assert_eq!(
+            schedule.due_action(utc(3, 8)),
+            Some((InstanceStage::Running, utc(3, 8).timestamp()))
+        );
+    }
+
+    fn new_node(numa_cpus: serde_json::Value, pinned_cpus: &[usize]) -> Node {
+        serde_json::from_value(serde_json::json!({
+            "name": "node1",
+            "storage_pools": [],
+            "runtimes": ["lxc"],
+            "cpu_total": 12,
+            "cpu_allocated": 0,
+            "memory_total": 64,
+            "memory_allocated": 0,
+            "storage_total": 100,
+            "storage_used": 0,
+            "storage_allocated": 0,
+            "numa_cpus": numa_cpus,
+            "pinned_cpus": pinned_cpus,
+        }))
+        .unwrap()
+    }
+
+    #[test]
+    fn test_pick_dedicated_cpus_single_numa_node() {
+        // The free CPUs are 1-3, 4-5 and 6-11.
+        let node = new_node(
+            serde_json::json!([[0, 1, 2, 3], [4, 5], [6, 7, 8, 9, 10, 11]]),
+            &[0],
+        );
+        assert_eq!(node.pick_dedicated_cpus(1), Some(vec![4]));
+        assert_eq!(node.pick_dedicated_cpus(2), Some(vec![4, 5]));
+        assert_eq!(node.pick_dedicated_cpus(3), Some(vec![1, 2, 3]));
+        assert_eq!(node.pick_dedicated_cpus(4), Some(vec![6, 7, 8, 9]));
+        assert_eq!(node.pick_dedicated_cpus(6), Some(vec![6, 7, 8, 9, 10, 11]));
+    }
+
+    #[test]
+    fn test_pick_dedicated_cpus_spread() {
+        let node = new_node(
+            serde_json::json!([[0, 1, 2, 3], [4, 5], [6, 7, 8, 9, 10, 11]]),
+            &[0, 8],
+        );
+        // The NUMA nodes with the most free CPUs are taken first.
+        assert_eq!(node.pick_dedicated_cpus(6), Some(vec![1, 6, 7, 9, 10, 11]));
+        assert_eq!(
+            node.pick_dedicated_cpus(9),
+            Some(vec![1, 2, 3, 4, 6, 7, 9, 10, 11])
+        );
+    }
+
+    #[test]
+    fn test_pick_dedicated_cpus_exhausted() {
+        let node = new_node(serde_json::json!([[0, 1], [2, 3]]), &[0, 3]);
+        assert_eq!(node.pick_dedicated_cpus(2), Some(vec![1, 2]));
+        assert_eq!(node.pick_dedicated_cpus(3), None);
+        let node = new_node(serde_json::json!([[0, 1], [2, 3]]), &[0, 1, 2, 3]);
+        assert_eq!(node.pick_dedicated_cpus(1), None);
+        // Nodes without NUMA topology have no CPUs to pin.
+        let node = new_node(serde_json::json!([]), &[]);
+        assert_eq!(node.pick_dedicated_cpus(1), None);
+    }
+
+    #[test]
+    fn test_priority_class_users() {
+        let mut class = PriorityClass {
+            name: "batch".to_owned(),
+            value: -10,
+            preemptible: true,
+            description: String::new(),
+            users: vec![],
+        };
+        assert!(class.is_allowed("dev"));
+        class.users = vec!["ci".to_owned()];
+        assert!(class.is_allowed("ci"));
+        assert!(!class.is_allowed("dev"));
     }
 }
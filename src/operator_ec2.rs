@@ -0,0 +1,502 @@
+use anyhow::{anyhow, Result};
+use reqwest::header::CONTENT_TYPE;
+use reqwest::{Client, Url};
+use tracing::{info, instrument, warn};
+
+use crate::aws::{self, parse_xml_value, sha256_hex, uri_encode, Credentials};
+use crate::config;
+use crate::env::{
+    EC2_ACCESS_KEY, EC2_ENDPOINT, EC2_INSTANCE_TYPES, EC2_KEY_NAME, EC2_REGION,
+    EC2_ROOT_DEVICE_NAME, EC2_SECRET_KEY, EC2_SECURITY_GROUP_IDS, EC2_SUBNET_ID,
+};
+use crate::instance_lock;
+use crate::lifecycle;
+use crate::metrics::{BACKEND_ERRORS, RECONCILE_DURATION};
+use crate::model::{Instance, InstanceStage, InstanceStatus, Runtime, User};
+use crate::operator_lxd::build_user_data;
+use crate::reconcile;
+use crate::shutdown;
+use crate::storage::Storage;
+
+const API_VERSION: &str = "2016-11-15";
+
+pub struct Operator {
+    client: Client,
+    storage: Storage,
+    credentials: Credentials,
+}
+
+impl Operator {
+    pub fn new(client: Client, storage: Storage) -> Self {
+        Operator {
+            client,
+            storage,
+            credentials: Credentials {
+                region: EC2_REGION.to_owned(),
+                access_key: EC2_ACCESS_KEY.to_owned(),
+                secret_key: EC2_SECRET_KEY.to_owned(),
+            },
+        }
+    }
+
+    pub async fn run(&self) {
+        let mut wakeup = reconcile::subscribe();
+        while !shutdown::is_triggered() {
+            self.run_once().await;
+            reconcile::sleep(&mut wakeup, config::current().reconcile_wait()).await;
+        }
+    }
+
+    #[instrument(skip_all)]
+    async fn run_once(&self) {
+        let _timer = RECONCILE_DURATION.with_label_values(&["ec2"]).start_timer();
+        let state = self.storage.snapshot().await;
+        for user in &state.users {
+            for instance in &user.instances {
+                // Stop between instances so that no instance is left half-synced.
+                if shutdown::is_triggered() {
+                    return;
+                }
+                if instance.runtime != Runtime::Ec2 {
+                    continue;
+                }
+                let _lock = instance_lock::lock(&user.username, &instance.name).await;
+                self.sync_instance(user, instance).await;
+            }
+        }
+    }
+
+    #[instrument(skip_all, fields(username = %user.username, instance = %instance.name, runtime = %instance.runtime))]
+    async fn sync_instance(&self, user: &User, instance: &Instance) {
+        match instance.stage {
+            InstanceStage::Stopped => {
+                if instance.status != InstanceStatus::Stopped
+                    && instance.status != InstanceStatus::Missing
+                {
+                    if let Err(e) = self.stop_instance(user, instance).await {
+                        warn!(
+                            username = user.username.as_str(),
+                            instance = instance.name.as_str(),
+                            runtime = instance.runtime.to_string().as_str(),
+                            error = e.to_string().as_str(),
+                            "stopping instance encountered error"
+                        );
+                        BACKEND_ERRORS.with_label_values(&["ec2"]).inc();
+                    }
+                }
+            }
+            InstanceStage::Running => {
+                if instance.status != InstanceStatus::Running {
+                    if instance.status == InstanceStatus::Creating {
+                        if instance.cloud_instance_id.is_none() {
+                            if let Err(e) = self.create_instance(user, instance).await {
+                                warn!(
+                                    username = user.username.as_str(),
+                                    instance = instance.name.as_str(),
+                                    runtime = instance.runtime.to_string().as_str(),
+                                    error = e.to_string().as_str(),
+                                    "creating instance encountered error"
+                                );
+                                BACKEND_ERRORS.with_label_values(&["ec2"]).inc();
+                            }
+                        }
+                    } else if instance.status != InstanceStatus::Missing {
+                        if let Err(e) = self.start_instance(user, instance).await {
+                            warn!(
+                                username = user.username.as_str(),
+                                instance = instance.name.as_str(),
+                                runtime = instance.runtime.to_string().as_str(),
+                                error = e.to_string().as_str(),
+                                "starting instance encountered error"
+                            );
+                            BACKEND_ERRORS.with_label_values(&["ec2"]).inc();
+                        }
+                    }
+                }
+            }
+            InstanceStage::Deleted => {
+                if let Err(e) = self.delete_instance(user, instance).await {
+                    warn!(
+                        username = user.username.as_str(),
+                        instance = instance.name.as_str(),
+                        runtime = instance.runtime.to_string().as_str(),
+                        error = e.to_string().as_str(),
+                        "deleting instance encountered error"
+                    );
+                    BACKEND_ERRORS.with_label_values(&["ec2"]).inc();
+                }
+            }
+        }
+        if let Err(e) = self.update_instance_status(user, instance).await {
+            warn!(
+                username = user.username.as_str(),
+                instance = instance.name.as_str(),
+                runtime = instance.runtime.to_string().as_str(),
+                error = e.to_string().as_str(),
+                "updating instance status encountered error"
+            );
+            BACKEND_ERRORS.with_label_values(&["ec2"]).inc();
+        }
+    }
+
+    async fn create_instance(&self, user: &User, instance: &Instance) -> Result<()> {
+        info!(
+            username = user.username.as_str(),
+            instance = instance.name.as_str(),
+            runtime = instance.runtime.to_string().as_str(),
+            "creating instance"
+        );
+        let name = instance.backend_name(&user.username);
+        let image_id = instance
+            .image_source
+            .as_ref()
+            .ok_or_else(|| anyhow!("no ami configured for image {}", instance.image))?;
+        let instance_type = get_instance_type(instance.cpu, instance.memory)?;
+
+        let mut params = vec![
+            ("Action".to_owned(), "RunInstances".to_owned()),
+            ("ImageId".to_owned(), image_id.to_owned()),
+            ("InstanceType".to_owned(), instance_type),
+            ("MinCount".to_owned(), "1".to_owned()),
+            ("MaxCount".to_owned(), "1".to_owned()),
+            // The password is generated on creation, so the token is unique even if an instance
+            // is recreated with the same name, but it's stable across retries of this creation.
+            (
+                "ClientToken".to_owned(),
+                sha256_hex(format!("{}/{}", name, instance.password).as_bytes()),
+            ),
+            (
+                "UserData".to_owned(),
+                base64::encode(build_user_data(instance)),
+            ),
+            ("NetworkInterface.1.DeviceIndex".to_owned(), "0".to_owned()),
+            (
+                "NetworkInterface.1.AssociatePublicIpAddress".to_owned(),
+                "true".to_owned(),
+            ),
+            (
+                "BlockDeviceMapping.1.DeviceName".to_owned(),
+                EC2_ROOT_DEVICE_NAME.to_owned(),
+            ),
+            (
+                "BlockDeviceMapping.1.Ebs.VolumeSize".to_owned(),
+                instance.disk_size.to_string(),
+            ),
+            (
+                "BlockDeviceMapping.1.Ebs.VolumeType".to_owned(),
+                "gp3".to_owned(),
+            ),
+            (
+                "BlockDeviceMapping.1.Ebs.DeleteOnTermination".to_owned(),
+                "true".to_owned(),
+            ),
+            (
+                "TagSpecification.1.ResourceType".to_owned(),
+                "instance".to_owned(),
+            ),
+            ("TagSpecification.1.Tag.1.Key".to_owned(), "Name".to_owned()),
+            ("TagSpecification.1.Tag.1.Value".to_owned(), name.clone()),
+            (
+                "TagSpecification.1.Tag.2.Key".to_owned(),
+                "tispace/user".to_owned(),
+            ),
+            (
+                "TagSpecification.1.Tag.2.Value".to_owned(),
+                user.username.clone(),
+            ),
+            (
+                "TagSpecification.1.Tag.3.Key".to_owned(),
+                "tispace/instance-id".to_owned(),
+            ),
+            (
+                "TagSpecification.1.Tag.3.Value".to_owned(),
+                instance.id.clone(),
+            ),
+        ];
+        if !EC2_SUBNET_ID.is_empty() {
+            params.push((
+                "NetworkInterface.1.SubnetId".to_owned(),
+                EC2_SUBNET_ID.to_owned(),
+            ));
+        }
+        for (i, group_id) in EC2_SECURITY_GROUP_IDS.iter().enumerate() {
+            params.push((
+                format!("NetworkInterface.1.SecurityGroupId.{}", i + 1),
+                group_id.to_owned(),
+            ));
+        }
+        if !EC2_KEY_NAME.is_empty() {
+            params.push(("KeyName".to_owned(), EC2_KEY_NAME.to_owned()));
+        }
+
+        let res = self.call(params).await?;
+        let instance_id = parse_xml_value(&res, "instanceId")
+            .ok_or_else(|| anyhow!("no instance id in response"))?;
+        info!(
+            username = user.username.as_str(),
+            instance = instance.name.as_str(),
+            runtime = instance.runtime.to_string().as_str(),
+            instance_id = instance_id.as_str(),
+            "ec2 instance is launched"
+        );
+        self.storage
+            .read_write(|state| {
+                if let Some(i) = state
+                    .find_mut_user(&user.username)
+                    .and_then(|u| u.find_mut_instance(&instance.name))
+                {
+                    i.cloud_instance_id = Some(instance_id.clone());
+                }
+                true
+            })
+            .await
+            .map_err(|e| anyhow!(e))
+    }
+
+    async fn delete_instance(&self, user: &User, instance: &Instance) -> Result<()> {
+        let instance_id = match &instance.cloud_instance_id {
+            Some(id) => id,
+            None => return Ok(()),
+        };
+        info!(
+            username = user.username.as_str(),
+            instance = instance.name.as_str(),
+            runtime = instance.runtime.to_string().as_str(),
+            "deleting instance"
+        );
+        self.call(vec![
+            ("Action".to_owned(), "TerminateInstances".to_owned()),
+            ("InstanceId.1".to_owned(), instance_id.to_owned()),
+        ])
+        .await?;
+        Ok(())
+    }
+
+    async fn start_instance(&self, user: &User, instance: &Instance) -> Result<()> {
+        let instance_id = match &instance.cloud_instance_id {
+            Some(id) => id,
+            None => return Ok(()),
+        };
+        info!(
+            username = user.username.as_str(),
+            instance = instance.name.as_str(),
+            runtime = instance.runtime.to_string().as_str(),
+            "starting instance"
+        );
+
+        self.sync_instance_type(user, instance, instance_id).await?;
+
+        self.call(vec![
+            ("Action".to_owned(), "StartInstances".to_owned()),
+            ("InstanceId.1".to_owned(), instance_id.to_owned()),
+        ])
+        .await?;
+        Ok(())
+    }
+
+    async fn sync_instance_type(
+        &self,
+        user: &User,
+        instance: &Instance,
+        instance_id: &str,
+    ) -> Result<()> {
+        let res = self.describe_instance(instance_id).await?;
+        if parse_instance_status(&res).unwrap_or_default() != "stopped" {
+            return Ok(());
+        }
+        let instance_type = parse_xml_value(&res, "instanceType").unwrap_or_default();
+        let new_instance_type = get_instance_type(instance.cpu, instance.memory)?;
+        if instance_type != new_instance_type {
+            info!(
+                username = user.username.as_str(),
+                instance = instance.name.as_str(),
+                runtime = instance.runtime.to_string().as_str(),
+                instance_type = instance_type.as_str(),
+                new_instance_type = new_instance_type.as_str(),
+                "instance limits are chagned, updating"
+            );
+            self.call(vec![
+                ("Action".to_owned(), "ModifyInstanceAttribute".to_owned()),
+                ("InstanceId".to_owned(), instance_id.to_owned()),
+                ("InstanceType.Value".to_owned(), new_instance_type),
+            ])
+            .await?;
+        }
+        Ok(())
+    }
+
+    async fn stop_instance(&self, user: &User, instance: &Instance) -> Result<()> {
+        let instance_id = match &instance.cloud_instance_id {
+            Some(id) => id,
+            None => return Ok(()),
+        };
+        info!(
+            username = user.username.as_str(),
+            instance = instance.name.as_str(),
+            runtime = instance.runtime.to_string().as_str(),
+            "stopping instance"
+        );
+        self.call(vec![
+            ("Action".to_owned(), "StopInstances".to_owned()),
+            ("InstanceId.1".to_owned(), instance_id.to_owned()),
+        ])
+        .await?;
+        Ok(())
+    }
+
+    async fn update_instance_status(&self, user: &User, instance: &Instance) -> Result<()> {
+        let res = match &instance.cloud_instance_id {
+            Some(instance_id) => match self.describe_instance(instance_id).await {
+                Ok(res) => Some(res),
+                Err(e) if e.to_string().starts_with("InvalidInstanceID.NotFound") => None,
+                Err(e) => return Err(e),
+            },
+            None => None,
+        };
+        let status = res
+            .as_ref()
+            .and_then(|res| parse_instance_status(res))
+            .unwrap_or_default();
+        // Terminated instances are still visible for a while.
+        if res.is_none() || status == "terminated" {
+            if instance.status == InstanceStatus::Creating {
+                return Ok(());
+            }
+            return self
+                .storage
+                .read_write_deferred(|state| {
+                    if let Some(i) = state
+                        .find_mut_user(&user.username)
+                        .and_then(|u| u.find_mut_instance(&instance.name))
+                    {
+                        if i.stage == InstanceStage::Deleted {
+                            state
+                                .find_mut_user(&user.username)
+                                .unwrap()
+                                .remove_instance(&instance.name);
+                        } else {
+                            lifecycle::observe(i, InstanceStatus::Missing);
+                            warn!(
+                                username = user.username.as_str(),
+                                instance = instance.name.as_str(),
+                                runtime = instance.runtime.to_string().as_str(),
+                                "instance is missing unexpectedly"
+                            );
+                        }
+                    }
+                    true
+                })
+                .await
+                .map_err(|e| anyhow!(e));
+        }
+        let res = res.unwrap();
+
+        let public_ip = parse_xml_value(&res, "ipAddress");
+        let private_ip = parse_xml_value(&res, "privateIpAddress");
+        self.storage
+            .read_write_deferred(|state| {
+                if let Some(i) = state
+                    .find_mut_user(&user.username)
+                    .and_then(|u| u.find_mut_instance(&instance.name))
+                {
+                    match i.stage {
+                        InstanceStage::Stopped => {
+                            if status == "stopped" {
+                                lifecycle::observe(i, InstanceStatus::Stopped);
+                            }
+                        }
+                        InstanceStage::Running => {
+                            if status == "running" {
+                                lifecycle::observe(i, InstanceStatus::Running);
+                            } else if status == "stopped" && i.status == InstanceStatus::Creating {
+                                lifecycle::observe(i, InstanceStatus::Starting);
+                            }
+                            i.internal_ip = private_ip.clone();
+                            i.external_ip = public_ip.clone();
+                        }
+                        InstanceStage::Deleted => {
+                            lifecycle::observe(i, InstanceStatus::Deleting);
+                        }
+                    }
+                }
+                true
+            })
+            .await
+            .map_err(|e| anyhow!(e))
+    }
+
+    async fn describe_instance(&self, instance_id: &str) -> Result<String> {
+        self.call(vec![
+            ("Action".to_owned(), "DescribeInstances".to_owned()),
+            ("InstanceId.1".to_owned(), instance_id.to_owned()),
+        ])
+        .await
+    }
+
+    /// Calls the EC2 query API and returns the XML response.
+    async fn call(&self, mut params: Vec<(String, String)>) -> Result<String> {
+        params.push(("Version".to_owned(), API_VERSION.to_owned()));
+        let body = params
+            .iter()
+            .map(|(k, v)| format!("{}={}", uri_encode(k), uri_encode(v)))
+            .collect::<Vec<_>>()
+            .join("&");
+        let url = Url::parse(&format!("{}/", EC2_ENDPOINT.as_str()))?;
+        let headers = aws::sign(
+            &self.credentials,
+            "ec2",
+            "POST",
+            &url,
+            &sha256_hex(body.as_bytes()),
+        )?;
+        let res = self
+            .client
+            .post(url)
+            .headers(headers)
+            .header(
+                CONTENT_TYPE,
+                "application/x-www-form-urlencoded; charset=utf-8",
+            )
+            .body(body)
+            .send()
+            .await?;
+        let status = res.status();
+        let text = res.text().await?;
+        if !status.is_success() {
+            // The response is like:
+            // <Response>
+            //   <Errors>
+            //     <Error>
+            //       <Code>InvalidInstanceID.NotFound</Code>
+            //       <Message>The instance ID 'i-1a2b3c4d' does not exist</Message>
+            //     </Error>
+            //   </Errors>
+            //   <RequestID>ea966190-f9aa-478e-9ede-example</RequestID>
+            // </Response>
+            return Err(anyhow!(
+                "{}: {}",
+                parse_xml_value(&text, "Code").unwrap_or_else(|| status.to_string()),
+                parse_xml_value(&text, "Message").unwrap_or_default()
+            ));
+        }
+        Ok(text)
+    }
+}
+
+/// Returns the smallest configured instance type which has enough cpu and memory.
+fn get_instance_type(cpu: usize, memory: usize) -> Result<String> {
+    EC2_INSTANCE_TYPES
+        .iter()
+        .find(|(_, c, m)| *c >= cpu && *m >= memory)
+        .map(|(name, _, _)| name.to_owned())
+        .ok_or_else(|| anyhow!("no instance type has {} cpu and {}GiB memory", cpu, memory))
+}
+
+fn parse_instance_status(res: &str) -> Option<String> {
+    // The instance state is like:
+    // <instanceState>
+    //   <code>16</code>
+    //   <name>running</name>
+    // </instanceState>
+    parse_xml_value(res, "instanceState").and_then(|s| parse_xml_value(&s, "name"))
+}
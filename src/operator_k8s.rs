@@ -1,28 +1,43 @@
 use anyhow::{anyhow, Result};
 use either::Either;
+use k8s_openapi::api::apps::v1::{DaemonSet, DaemonSetSpec};
 use k8s_openapi::api::core::v1::{
-    Capabilities, ConfigMapVolumeSource, Container, EnvVar, PersistentVolume,
-    PersistentVolumeClaim, PersistentVolumeClaimSpec, PersistentVolumeClaimVolumeSource, Pod,
-    PodDNSConfig, PodSpec, ResourceRequirements, SecurityContext, Service, ServicePort,
-    ServiceSpec, Volume, VolumeMount,
+    Affinity, Capabilities, ConfigMapVolumeSource, Container, EmptyDirVolumeSource, EnvVar,
+    HostPathVolumeSource, NFSVolumeSource, NodeAffinity, NodeSelector, NodeSelectorRequirement,
+    NodeSelectorTerm, PersistentVolume, PersistentVolumeClaim, PersistentVolumeClaimSpec,
+    PersistentVolumeClaimVolumeSource, Pod, PodDNSConfig, PodSecurityContext, PodSpec,
+    PodTemplateSpec, ResourceRequirements, SecurityContext, Service, ServicePort, ServiceSpec,
+    Sysctl, Toleration, Volume, VolumeMount,
 };
 use k8s_openapi::apimachinery::pkg::api::resource::Quantity;
-use k8s_openapi::apimachinery::pkg::apis::meta::v1::ObjectMeta;
+use k8s_openapi::apimachinery::pkg::apis::meta::v1::{LabelSelector, ObjectMeta};
 use k8s_openapi::apimachinery::pkg::util::intstr::IntOrString;
-use kube::api::{DeleteParams, PostParams};
+use kube::api::{DeleteParams, Patch, PatchParams, PostParams};
 use kube::error::ErrorResponse;
 use kube::{Api, Client};
+use ring::digest;
 use std::collections::BTreeMap;
-use tokio::time::{sleep, Duration};
-use tracing::{info, warn};
+use std::sync::Mutex;
+use tokio::time::{Duration, Instant};
+use tracing::{info, instrument, warn};
 
+use crate::config;
 use crate::env::{DEFAULT_ROOTFS_IMAGE_TAG, LXD_STORAGE_POOL_MAPPING, STORAGE_CLASS_NAME};
-use crate::model::{Image, Instance, InstanceStage, InstanceStatus, Runtime, User};
+use crate::instance_lock;
+use crate::lifecycle;
+use crate::metrics::{BACKEND_ERRORS, RECONCILE_DURATION};
+use crate::model::{
+    drift_event, Arch, Drift, Instance, InstanceStage, InstanceStatus, Progress, Runtime,
+    SharedMount, User, Volume as InstanceVolume,
+};
+use crate::reconcile;
+use crate::shutdown;
 use crate::storage::Storage;
 
-const NAMESPACE: &str = "tispace";
+crate const NAMESPACE: &str = "tispace";
 const FAKE_IMAGE: &str = "k8s.gcr.io/pause:3.5";
 const PASSWORD_ENV_KEY: &str = "PASSWORD";
+const SSH_AUTHORIZED_KEYS_ENV_KEY: &str = "SSH_AUTHORIZED_KEYS";
 
 const DEFAULT_CONTAINER_CAPS: [&str; 14] = [
     "CHOWN",
@@ -41,36 +56,90 @@ const DEFAULT_CONTAINER_CAPS: [&str; 14] = [
     "AUDIT_WRITE",
 ];
 
-fn build_container(
-    pod_name: &str,
-    cpu_limit: usize,
-    memory_limit: usize,
-    runtime: &Runtime,
-) -> Container {
+/// The home volume of the owner of an instance, see `Config::home_volume_path`.
+struct HomeMount {
+    pvc_name: String,
+    mount_path: String,
+}
+
+fn build_container(pod_name: &str, instance: &Instance, home: Option<&HomeMount>) -> Container {
+    let mut volume_mounts = vec![VolumeMount {
+        name: "rootfs".to_owned(),
+        mount_path: "/".to_owned(),
+        ..Default::default()
+    }];
+    if instance.shm_size.is_some() {
+        volume_mounts.push(VolumeMount {
+            name: "shm".to_owned(),
+            mount_path: "/dev/shm".to_owned(),
+            ..Default::default()
+        });
+    }
+    for v in &instance.volumes {
+        volume_mounts.push(VolumeMount {
+            name: format!("vol-{}", v.name),
+            mount_path: v.mount_path.clone(),
+            ..Default::default()
+        });
+    }
+    if let Some(home) = home {
+        volume_mounts.push(VolumeMount {
+            name: "home".to_owned(),
+            mount_path: home.mount_path.clone(),
+            ..Default::default()
+        });
+    }
+    for (i, m) in instance.mounts.iter().enumerate() {
+        volume_mounts.push(VolumeMount {
+            name: format!("mount-{}", i),
+            mount_path: m.path.clone(),
+            read_only: Some(m.read_only),
+            ..Default::default()
+        });
+    }
     Container {
         name: pod_name.to_owned(),
         command: Some(vec!["/sbin/init".to_owned()]),
         image: Some(FAKE_IMAGE.to_owned()),
         image_pull_policy: Some("IfNotPresent".to_owned()),
-        security_context: Some(build_security_context(runtime)),
-        volume_mounts: Some(vec![VolumeMount {
-            name: "rootfs".to_owned(),
-            mount_path: "/".to_owned(),
-            ..Default::default()
-        }]),
-        resources: Some(ResourceRequirements {
-            limits: Some(BTreeMap::from([
-                ("cpu".to_owned(), Quantity(cpu_limit.to_string())),
-                ("memory".to_owned(), Quantity(format!("{}Gi", memory_limit))),
-            ])),
-            ..Default::default()
-        }),
+        security_context: Some(build_security_context(instance)),
+        volume_mounts: Some(volume_mounts),
+        resources: Some(build_resources(instance)),
         ..Default::default()
     }
 }
 
-fn build_security_context(runtime: &Runtime) -> SecurityContext {
-    if runtime == &Runtime::Kata {
+/// Returns the limits of the container along with its requests if they're configured to be
+/// lower, otherwise the requests default to the limits.
+fn build_resources(instance: &Instance) -> ResourceRequirements {
+    let (cpu_ratio, memory_ratio) =
+        config::current().k8s_request_ratios(&instance.runtime.to_string());
+    let mut requests = BTreeMap::new();
+    if cpu_ratio < 1.0 {
+        let millis = (instance.cpu as f64 * 1000.0 * cpu_ratio).ceil() as u64;
+        requests.insert("cpu".to_owned(), Quantity(format!("{}m", millis)));
+    }
+    if memory_ratio < 1.0 {
+        let mebibytes = (instance.memory as f64 * 1024.0 * memory_ratio).ceil() as u64;
+        requests.insert("memory".to_owned(), Quantity(format!("{}Mi", mebibytes)));
+    }
+    ResourceRequirements {
+        limits: Some(BTreeMap::from([
+            ("cpu".to_owned(), Quantity(instance.cpu.to_string())),
+            (
+                "memory".to_owned(),
+                Quantity(format!("{}Gi", instance.memory)),
+            ),
+        ])),
+        requests: (!requests.is_empty()).then(|| requests),
+    }
+}
+
+fn build_security_context(instance: &Instance) -> SecurityContext {
+    // Kata containers are confined by their VM, which is what lets them run nested containers and
+    // VMs. Runc containers share the kernel of the host and are never privileged, whatever the
+    // instance asks for.
+    if instance.runtime == Runtime::Kata {
         SecurityContext {
             privileged: Some(true),
             ..Default::default()
@@ -78,14 +147,13 @@ fn build_security_context(runtime: &Runtime) -> SecurityContext {
     } else {
         // It's unsafe to enable privileged mode in container whose runtime is not kata.
         // But leave a least capabilities set to ensure systemd can run properly.
+        let caps: Vec<String> = DEFAULT_CONTAINER_CAPS
+            .iter()
+            .map(|s| s.to_string())
+            .collect();
         SecurityContext {
             capabilities: Some(Capabilities {
-                add: Some(
-                    DEFAULT_CONTAINER_CAPS
-                        .iter()
-                        .map(|s| s.to_string())
-                        .collect(),
-                ),
+                add: Some(caps),
                 ..Default::default()
             }),
             ..Default::default()
@@ -93,12 +161,14 @@ fn build_security_context(runtime: &Runtime) -> SecurityContext {
     }
 }
 
-fn build_init_container(pod_name: &str, password: &str, image_url: &str) -> Container {
+fn build_init_container(pod_name: &str, instance: &Instance, image_url: &str) -> Container {
     Container {
         name: format!("{}-init", pod_name),
         command: Some(vec!["/tmp/init-rootfs.sh".to_owned()]),
         image: Some(image_url.to_owned()),
         image_pull_policy: Some("IfNotPresent".to_owned()),
+        // The tail of the log tells why the script failed, see `init_container_failure`.
+        termination_message_policy: Some("FallbackToLogsOnError".to_owned()),
         volume_mounts: Some(vec![
             VolumeMount {
                 name: "rootfs".to_owned(),
@@ -112,20 +182,56 @@ fn build_init_container(pod_name: &str, password: &str, image_url: &str) -> Cont
                 ..Default::default()
             },
         ]),
-        env: Some(vec![EnvVar {
+        env: Some(build_init_env(instance)),
+        ..Default::default()
+    }
+}
+
+/// Returns the environment the init container sets the root password and the authorized keys
+/// from. A hashed password is left out, the root filesystem keeps the password it was
+/// initialized with.
+fn build_init_env(instance: &Instance) -> Vec<EnvVar> {
+    let mut env = vec![EnvVar {
+        name: SSH_AUTHORIZED_KEYS_ENV_KEY.to_owned(),
+        value: Some(instance.authorized_keys().join("\n")),
+        ..Default::default()
+    }];
+    if !instance.password_hashed {
+        env.push(EnvVar {
             name: PASSWORD_ENV_KEY.to_owned(),
-            value: Some(password.to_owned()),
+            value: Some(instance.password.clone()),
             ..Default::default()
-        }]),
-        ..Default::default()
+        });
+    }
+    env
+}
+
+/// Returns the name of the PersistentVolumeClaim of the volume of the instance backed by the pod,
+/// which is the claim of the detached volume the volume was attached from, if any.
+crate fn volume_pvc_name(pod_name: &str, volume: &InstanceVolume) -> String {
+    match &volume.claim_name {
+        Some(claim_name) => claim_name.clone(),
+        None => format!("{}-vol-{}", pod_name, volume.name),
     }
 }
 
-fn build_rootfs_pvc(pvc_name: &str, disk_size: usize) -> PersistentVolumeClaim {
+/// Returns the PersistentVolumeClaim of `size` GiB for the root disk or a volume of the instance
+/// backed by the pod.
+fn build_pvc(
+    pvc_name: &str,
+    pod_name: &str,
+    instance: &Instance,
+    size: usize,
+) -> PersistentVolumeClaim {
     PersistentVolumeClaim {
         metadata: ObjectMeta {
             name: Some(pvc_name.to_owned()),
             namespace: Some(NAMESPACE.to_owned()),
+            // Lets the garbage collector find the PVCs of deleted instances.
+            labels: Some(BTreeMap::from([
+                ("tispace/instance".to_owned(), pod_name.to_owned()),
+                ("tispace/instance-id".to_owned(), instance.id.clone()),
+            ])),
             ..Default::default()
         },
         spec: Some(PersistentVolumeClaimSpec {
@@ -133,7 +239,7 @@ fn build_rootfs_pvc(pvc_name: &str, disk_size: usize) -> PersistentVolumeClaim {
             resources: Some(ResourceRequirements {
                 requests: Some(BTreeMap::from([(
                     "storage".to_owned(),
-                    Quantity(format!("{}Gi", disk_size)),
+                    Quantity(format!("{}Gi", size)),
                 )])),
                 ..Default::default()
             }),
@@ -144,9 +250,48 @@ fn build_rootfs_pvc(pvc_name: &str, disk_size: usize) -> PersistentVolumeClaim {
     }
 }
 
-fn build_rootfs_volume(pvc_name: &str) -> Volume {
+/// Returns the name of the PersistentVolumeClaim of the home volume of the user.
+crate fn home_pvc_name(username: &str) -> String {
+    format!("home-{}", username)
+}
+
+/// Returns the PersistentVolumeClaim of the home volume of the user, which the pods of all the
+/// instances of the user mount at once, wherever they run.
+fn build_home_pvc(
+    pvc_name: &str,
+    username: &str,
+    size: usize,
+    storage_class: &str,
+) -> PersistentVolumeClaim {
+    PersistentVolumeClaim {
+        metadata: ObjectMeta {
+            name: Some(pvc_name.to_owned()),
+            namespace: Some(NAMESPACE.to_owned()),
+            labels: Some(BTreeMap::from([(
+                "tispace/home".to_owned(),
+                username.to_owned(),
+            )])),
+            ..Default::default()
+        },
+        spec: Some(PersistentVolumeClaimSpec {
+            access_modes: Some(vec!["ReadWriteMany".to_owned()]),
+            resources: Some(ResourceRequirements {
+                requests: Some(BTreeMap::from([(
+                    "storage".to_owned(),
+                    Quantity(format!("{}Gi", size)),
+                )])),
+                ..Default::default()
+            }),
+            storage_class_name: Some(storage_class.to_owned()),
+            ..Default::default()
+        }),
+        ..Default::default()
+    }
+}
+
+fn build_pvc_volume(name: &str, pvc_name: &str) -> Volume {
     Volume {
-        name: "rootfs".to_owned(),
+        name: name.to_owned(),
         persistent_volume_claim: Some(PersistentVolumeClaimVolumeSource {
             claim_name: pvc_name.to_owned(),
             read_only: Some(false),
@@ -155,6 +300,62 @@ fn build_rootfs_volume(pvc_name: &str) -> Volume {
     }
 }
 
+/// Returns the volume of the share, from its NFS export or the path of the node.
+fn build_shared_volume(name: &str, mount: &SharedMount) -> Volume {
+    match mount.nfs_export() {
+        Some((server, path)) => Volume {
+            name: name.to_owned(),
+            nfs: Some(NFSVolumeSource {
+                server: server.to_owned(),
+                path: path.to_owned(),
+                read_only: Some(mount.read_only),
+            }),
+            ..Default::default()
+        },
+        None => Volume {
+            name: name.to_owned(),
+            host_path: Some(HostPathVolumeSource {
+                path: mount.source.clone(),
+                // The pod doesn't start rather than creating a missing directory on the node.
+                type_: Some("Directory".to_owned()),
+            }),
+            ..Default::default()
+        },
+    }
+}
+
+/// Returns the memory-backed volume mounted at /dev/shm, whose size is counted towards the memory
+/// limit of the container.
+fn build_shm_volume(size: usize) -> Volume {
+    Volume {
+        name: "shm".to_owned(),
+        empty_dir: Some(EmptyDirVolumeSource {
+            medium: Some("Memory".to_owned()),
+            size_limit: Some(Quantity(format!("{}Mi", size))),
+        }),
+        ..Default::default()
+    }
+}
+
+fn build_pod_security_context(instance: &Instance) -> Option<PodSecurityContext> {
+    if instance.sysctls.is_empty() {
+        return None;
+    }
+    Some(PodSecurityContext {
+        sysctls: Some(
+            instance
+                .sysctls
+                .iter()
+                .map(|(name, value)| Sysctl {
+                    name: name.clone(),
+                    value: value.clone(),
+                })
+                .collect(),
+        ),
+        ..Default::default()
+    })
+}
+
 fn build_init_rootfs_volume() -> Volume {
     Volume {
         name: "init-rootfs".to_owned(),
@@ -171,6 +372,10 @@ fn build_subdomain_service(subdomain: &str) -> Service {
     Service {
         metadata: ObjectMeta {
             name: Some(subdomain.to_owned()),
+            labels: Some(BTreeMap::from([(
+                "tispace/subdomain".to_owned(),
+                subdomain.to_owned(),
+            )])),
             ..Default::default()
         },
         spec: Some(ServiceSpec {
@@ -189,6 +394,10 @@ fn build_pod_service(pod_name: &str) -> Service {
     Service {
         metadata: ObjectMeta {
             name: Some(pod_name.to_owned()),
+            labels: Some(BTreeMap::from([(
+                "tispace/instance".to_owned(),
+                pod_name.to_owned(),
+            )])),
             ..Default::default()
         },
         spec: Some(ServiceSpec {
@@ -211,22 +420,146 @@ fn build_pod_service(pod_name: &str) -> Service {
     }
 }
 
-fn build_pod(pod_name: &str, pvc_name: &str, subdomain: &str, instance: &Instance) -> Result<Pod> {
-    let mut volumes = vec![build_rootfs_volume(pvc_name)];
+/// Returns the annotations limiting the bandwidth of the pod, honoured by the bandwidth CNI
+/// plugin.
+fn build_bandwidth_annotations(instance: &Instance) -> BTreeMap<String, String> {
+    let mut annotations = BTreeMap::new();
+    if let Some(limit) = instance.ingress_limit {
+        annotations.insert(
+            "kubernetes.io/ingress-bandwidth".to_owned(),
+            format!("{}M", limit),
+        );
+    }
+    if let Some(limit) = instance.egress_limit {
+        annotations.insert(
+            "kubernetes.io/egress-bandwidth".to_owned(),
+            format!("{}M", limit),
+        );
+    }
+    annotations
+}
+
+/// Runtime options of Kata instances and the annotations they're applied as. The annotations are
+/// only honoured if they're listed in `enable_annotations` of the Kata configuration.
+const KATA_RUNTIME_OPTIONS: [(&str, &str); 3] = [
+    (
+        "default_vcpus",
+        "io.katacontainers.config.hypervisor.default_vcpus",
+    ),
+    (
+        "default_memory",
+        "io.katacontainers.config.hypervisor.default_memory",
+    ),
+    (
+        "enable_hugepages",
+        "io.katacontainers.config.hypervisor.enable_hugepages",
+    ),
+];
+
+fn build_pod_annotations(instance: &Instance) -> BTreeMap<String, String> {
+    let mut annotations = build_bandwidth_annotations(instance);
+    // Pods are created again on every start, which is when they pick up a changed description.
+    if !instance.description.is_empty() {
+        annotations.insert(
+            "tispace/description".to_owned(),
+            instance.description.clone(),
+        );
+    }
+    if instance.runtime == Runtime::Kata {
+        for (option, annotation) in KATA_RUNTIME_OPTIONS {
+            if let Some(value) = instance.runtime_options.get(option) {
+                annotations.insert(annotation.to_owned(), value.clone());
+            }
+        }
+    }
+    annotations
+}
+
+/// Returns the configured tolerations of pods.
+fn build_tolerations() -> Option<Vec<Toleration>> {
+    let tolerations: Vec<Toleration> = config::current()
+        .k8s_tolerations()
+        .into_iter()
+        .map(|(key, value, effect)| Toleration {
+            key: Some(key),
+            operator: Some(if value.is_some() { "Equal" } else { "Exists" }.to_owned()),
+            value,
+            effect,
+            ..Default::default()
+        })
+        .collect();
+    (!tolerations.is_empty()).then(|| tolerations)
+}
+
+/// Returns the affinity requiring nodes to have the configured labels.
+fn build_affinity() -> Option<Affinity> {
+    let labels: BTreeMap<String, String> = config::current()
+        .k8s_node_affinity
+        .iter()
+        .map(|(k, v)| (k.clone(), v.clone()))
+        .collect();
+    if labels.is_empty() {
+        return None;
+    }
+    let match_expressions = labels
+        .into_iter()
+        .map(|(key, value)| NodeSelectorRequirement {
+            key,
+            operator: "In".to_owned(),
+            values: Some(vec![value]),
+        })
+        .collect();
+    Some(Affinity {
+        node_affinity: Some(NodeAffinity {
+            required_during_scheduling_ignored_during_execution: Some(NodeSelector {
+                node_selector_terms: vec![NodeSelectorTerm {
+                    match_expressions: Some(match_expressions),
+                    ..Default::default()
+                }],
+            }),
+            ..Default::default()
+        }),
+        ..Default::default()
+    })
+}
+
+fn build_pod(
+    pod_name: &str,
+    pvc_name: &str,
+    subdomain: &str,
+    instance: &Instance,
+    home: Option<&HomeMount>,
+) -> Result<Pod> {
+    let mut volumes = vec![build_pvc_volume("rootfs", pvc_name)];
+    if let Some(size) = instance.shm_size {
+        volumes.push(build_shm_volume(size));
+    }
+    for v in &instance.volumes {
+        volumes.push(build_pvc_volume(
+            &format!("vol-{}", v.name),
+            &volume_pvc_name(pod_name, v),
+        ));
+    }
+    if let Some(home) = home {
+        volumes.push(build_pvc_volume("home", &home.pvc_name));
+    }
+    for (i, m) in instance.mounts.iter().enumerate() {
+        volumes.push(build_shared_volume(&format!("mount-{}", i), m));
+    }
     let mut init_containers = None;
 
-    if instance.status == InstanceStatus::Creating {
-        let image_url = get_image_url(&instance.image)?;
+    // The rootfs is initialized again if the pod is recreated before it succeeded.
+    if instance.status == InstanceStatus::Creating || failed_to_init(instance) {
+        let image_url = get_image_url(instance)?;
         volumes.push(build_init_rootfs_volume());
-        init_containers = Some(vec![build_init_container(
-            pod_name,
-            &instance.password,
-            &image_url,
-        )]);
+        init_containers = Some(vec![build_init_container(pod_name, instance, &image_url)]);
     }
 
     let node_selector = instance.node_name.as_ref().map(|node_name| {
-        BTreeMap::from([("kubernetes.io/hostname".to_owned(), node_name.to_owned())])
+        BTreeMap::from([
+            ("kubernetes.io/hostname".to_owned(), node_name.to_owned()),
+            ("kubernetes.io/arch".to_owned(), instance.arch.to_string()),
+        ])
     });
     Ok(Pod {
         metadata: ObjectMeta {
@@ -235,19 +568,17 @@ fn build_pod(pod_name: &str, pvc_name: &str, subdomain: &str, instance: &Instanc
             labels: Some(BTreeMap::from([
                 ("tispace/subdomain".to_owned(), subdomain.to_owned()),
                 ("tispace/instance".to_owned(), pod_name.to_owned()),
+                ("tispace/instance-id".to_owned(), instance.id.clone()),
             ])),
+            annotations: Some(build_pod_annotations(instance)),
             ..Default::default()
         },
         spec: Some(PodSpec {
             hostname: Some(instance.name.to_owned()),
             subdomain: Some(subdomain.to_owned()),
             automount_service_account_token: Some(false),
-            containers: vec![build_container(
-                pod_name,
-                instance.cpu,
-                instance.memory,
-                &instance.runtime,
-            )],
+            security_context: build_pod_security_context(instance),
+            containers: vec![build_container(pod_name, instance, home)],
             init_containers,
             volumes: Some(volumes),
             restart_policy: Some("Always".to_owned()),
@@ -257,12 +588,107 @@ fn build_pod(pod_name: &str, pvc_name: &str, subdomain: &str, instance: &Instanc
             }),
             runtime_class_name: Some(get_runtime_class_name(&instance.runtime)?),
             node_selector,
+            tolerations: build_tolerations(),
+            affinity: build_affinity(),
             ..Default::default()
         }),
         ..Default::default()
     })
 }
 
+/// Returns why the init container of the pod failed, along with the tail of its log, if it's
+/// failing to pull its image or to run.
+fn init_container_failure(pod: &Pod) -> Option<String> {
+    let statuses = pod.status.as_ref()?.init_container_statuses.as_ref()?;
+    for status in statuses {
+        let state = status.state.as_ref();
+        let terminated = state
+            .and_then(|s| s.terminated.as_ref())
+            .filter(|t| t.exit_code != 0)
+            // The container is restarted after failing, waiting in CrashLoopBackOff.
+            .or_else(|| {
+                status
+                    .last_state
+                    .as_ref()
+                    .and_then(|s| s.terminated.as_ref())
+                    .filter(|t| t.exit_code != 0)
+            });
+        if let Some(t) = terminated {
+            let mut failure = format!("{} exited with code {}", INIT_FAILURE_PREFIX, t.exit_code);
+            if let Some(reason) = &t.reason {
+                failure.push_str(&format!(" ({})", reason));
+            }
+            if let Some(message) = t.message.as_deref().map(log_tail).filter(|m| !m.is_empty()) {
+                failure.push_str(&format!(": {}", message));
+            }
+            return Some(failure);
+        }
+        let waiting = state.and_then(|s| s.waiting.as_ref());
+        if let Some(reason) = waiting.and_then(|w| w.reason.as_deref()) {
+            if INIT_CONTAINER_FAILURES.contains(&reason) {
+                let mut failure = format!("{} is waiting ({})", INIT_FAILURE_PREFIX, reason);
+                if let Some(message) = waiting.and_then(|w| w.message.as_deref()) {
+                    failure.push_str(&format!(": {}", message));
+                }
+                return Some(failure);
+            }
+        }
+    }
+    None
+}
+
+/// Returns what the pod, which isn't running yet, is waiting for.
+fn provisioning_progress(pod: &Pod) -> Progress {
+    let status = pod.status.as_ref();
+    if pod
+        .spec
+        .as_ref()
+        .and_then(|s| s.node_name.as_ref())
+        .is_none()
+    {
+        return Progress::new("Scheduling pod");
+    }
+    let init_statuses = status.and_then(|s| s.init_container_statuses.as_ref());
+    for s in init_statuses.into_iter().flatten() {
+        let state = s.state.as_ref();
+        if state.and_then(|s| s.running.as_ref()).is_some() {
+            return Progress::new("Initializing rootfs");
+        }
+        if state.and_then(|s| s.waiting.as_ref()).is_some() {
+            return Progress::new("Pulling image");
+        }
+    }
+    Progress::new("Starting container")
+}
+
+const INIT_FAILURE_PREFIX: &str = "Init container";
+
+/// Returns true if the status of the instance is an error reported by `init_container_failure`.
+fn failed_to_init(instance: &Instance) -> bool {
+    matches!(&instance.status, InstanceStatus::Error(e) if e.starts_with(INIT_FAILURE_PREFIX))
+}
+
+// Reasons of init containers waiting to be created which won't go away without intervention.
+const INIT_CONTAINER_FAILURES: [&str; 5] = [
+    "ErrImagePull",
+    "ImagePullBackOff",
+    "InvalidImageName",
+    "CreateContainerConfigError",
+    "CreateContainerError",
+];
+
+const MAX_LOG_TAIL_LINES: usize = 10;
+
+/// Returns the last lines of the log, joined into one line to fit in the status.
+fn log_tail(log: &str) -> String {
+    let lines: Vec<&str> = log
+        .lines()
+        .map(|l| l.trim())
+        .filter(|l| !l.is_empty())
+        .collect();
+    lines[lines.len().saturating_sub(MAX_LOG_TAIL_LINES)..].join(" | ")
+}
+
 fn get_ssh_port(svc: &Service) -> Option<i32> {
     svc.spec
         .as_ref()
@@ -289,17 +715,184 @@ fn get_external_ip(svc: &Service) -> Option<String> {
         })
 }
 
-fn get_image_url(image: &Image) -> Result<String> {
-    match image {
-        Image::CentOS7 => Ok(format!(
-            "tispace/centos7:{}",
-            DEFAULT_ROOTFS_IMAGE_TAG.as_str()
-        )),
-        Image::Ubuntu2004 => Ok(format!(
-            "tispace/ubuntu2004:{}",
-            DEFAULT_ROOTFS_IMAGE_TAG.as_str()
-        )),
-        _ => Err(anyhow!("invalid image {}", image)),
+/// Returns the settings of the Pod and the Service of the instance that differ from the spec.
+fn find_drifts(pod_name: &str, instance: &Instance, pod: &Pod, svc: &Service) -> Vec<Drift> {
+    let mut drifts = Vec::new();
+    let mut check = |setting: &str, actual: String, desired: String, correctable: bool| {
+        if actual != desired {
+            drifts.push(Drift {
+                setting: setting.to_owned(),
+                actual,
+                desired,
+                correctable,
+            });
+        }
+    };
+
+    let limits = pod
+        .spec
+        .as_ref()
+        .and_then(|spec| spec.containers.iter().find(|c| c.name == pod_name))
+        .and_then(|c| c.resources.as_ref())
+        .and_then(|r| r.limits.clone())
+        .unwrap_or_default();
+    let limit = |key: &str| limits.get(key).map(|q| q.0.clone()).unwrap_or_default();
+    check("cpu limit", limit("cpu"), instance.cpu.to_string(), false);
+    check(
+        "memory limit",
+        limit("memory"),
+        format!("{}Gi", instance.memory),
+        false,
+    );
+
+    let annotations = pod.metadata.annotations.clone().unwrap_or_default();
+    let desired_annotations = build_bandwidth_annotations(instance);
+    for key in [
+        "kubernetes.io/ingress-bandwidth",
+        "kubernetes.io/egress-bandwidth",
+    ] {
+        let annotation = |a: &BTreeMap<String, String>| {
+            a.get(key)
+                .cloned()
+                .unwrap_or_else(|| "unlimited".to_owned())
+        };
+        check(
+            key,
+            annotation(&annotations),
+            annotation(&desired_annotations),
+            false,
+        );
+    }
+
+    let spec = svc.spec.clone().unwrap_or_default();
+    check(
+        "service type",
+        spec.type_.unwrap_or_default(),
+        "LoadBalancer".to_owned(),
+        true,
+    );
+    let ssh_port = spec
+        .ports
+        .unwrap_or_default()
+        .into_iter()
+        .find(|port| matches!(port.name.as_deref(), Some("ssh")))
+        .map(|port| match port.target_port {
+            Some(IntOrString::Int(target)) => format!("{}:{}", port.port, target),
+            Some(IntOrString::String(target)) => format!("{}:{}", port.port, target),
+            None => port.port.to_string(),
+        })
+        .unwrap_or_else(|| "absent".to_owned());
+    check("ssh port", ssh_port, "22:22".to_owned(), true);
+    drifts
+}
+
+fn get_image_url(instance: &Instance) -> Result<String> {
+    let repository = instance
+        .image_source
+        .as_ref()
+        .ok_or_else(|| anyhow!("no source of image {}", instance.image))?;
+    Ok(rootfs_image_url(repository, &instance.arch))
+}
+
+/// Returns the reference of the rootfs image of the arch in the repository.
+crate fn rootfs_image_url(repository: &str, arch: &Arch) -> String {
+    // Images registered by users are complete references with a tag or digest.
+    let name = repository.rsplit('/').next().unwrap_or_default();
+    if name.contains(':') || name.contains('@') {
+        return repository.to_owned();
+    }
+    // The amd64 variants are tagged without suffix for compatibility with existing images.
+    match arch {
+        Arch::Amd64 => format!("{}:{}", repository, DEFAULT_ROOTFS_IMAGE_TAG.as_str()),
+        _ => format!(
+            "{}:{}-{}",
+            repository,
+            DEFAULT_ROOTFS_IMAGE_TAG.as_str(),
+            arch
+        ),
+    }
+}
+
+/// Returns the name of the DaemonSet pulling the rootfs image onto the nodes of the arch, named
+/// after a digest of the image as references don't make valid names.
+crate fn prepull_daemon_set_name(arch: &Arch, image: &str) -> String {
+    let digest = digest::digest(&digest::SHA256, image.as_bytes());
+    let hex: String = digest
+        .as_ref()
+        .iter()
+        .take(6)
+        .map(|b| format!("{:02x}", b))
+        .collect();
+    format!("tispace-prepull-{}-{}", arch, hex)
+}
+
+/// Returns the DaemonSet pulling the rootfs image onto the nodes of the arch. The image is pulled
+/// by an init container which exits right away, the pod is then left sleeping so that kubelet
+/// keeps the image from being garbage collected while it's in use. Each image has a DaemonSet of
+/// its own, so that an image failing to pull doesn't hold back the others.
+crate fn build_prepull_daemon_set(arch: &Arch, nodes: &[String], image: &str) -> DaemonSet {
+    let name = prepull_daemon_set_name(arch, image);
+    let labels = BTreeMap::from([
+        ("tispace/prepull".to_owned(), arch.to_string()),
+        ("tispace/prepull-name".to_owned(), name.clone()),
+    ]);
+    let init_containers = vec![Container {
+        name: "pull".to_owned(),
+        image: Some(image.to_owned()),
+        image_pull_policy: Some("IfNotPresent".to_owned()),
+        command: Some(vec!["/bin/true".to_owned()]),
+        ..Default::default()
+    }];
+    DaemonSet {
+        metadata: ObjectMeta {
+            name: Some(name.clone()),
+            namespace: Some(NAMESPACE.to_owned()),
+            labels: Some(labels.clone()),
+            ..Default::default()
+        },
+        spec: Some(DaemonSetSpec {
+            selector: LabelSelector {
+                match_labels: Some(labels.clone()),
+                ..Default::default()
+            },
+            template: PodTemplateSpec {
+                metadata: Some(ObjectMeta {
+                    labels: Some(labels),
+                    ..Default::default()
+                }),
+                spec: Some(PodSpec {
+                    init_containers: Some(init_containers),
+                    containers: vec![Container {
+                        name,
+                        image: Some(FAKE_IMAGE.to_owned()),
+                        image_pull_policy: Some("IfNotPresent".to_owned()),
+                        ..Default::default()
+                    }],
+                    affinity: Some(Affinity {
+                        node_affinity: Some(NodeAffinity {
+                            required_during_scheduling_ignored_during_execution: Some(
+                                NodeSelector {
+                                    node_selector_terms: vec![NodeSelectorTerm {
+                                        match_expressions: Some(vec![NodeSelectorRequirement {
+                                            key: "kubernetes.io/hostname".to_owned(),
+                                            operator: "In".to_owned(),
+                                            values: Some(nodes.to_vec()),
+                                        }]),
+                                        ..Default::default()
+                                    }],
+                                },
+                            ),
+                            ..Default::default()
+                        }),
+                        ..Default::default()
+                    }),
+                    tolerations: build_tolerations(),
+                    ..Default::default()
+                }),
+            },
+            ..Default::default()
+        }),
+        ..Default::default()
     }
 }
 
@@ -314,18 +907,30 @@ fn get_runtime_class_name(runtime: &Runtime) -> Result<String> {
 pub struct Operator {
     client: Client,
     storage: Storage,
+    last_drift_check: Mutex<Option<Instant>>,
 }
 
 impl Operator {
     pub fn new(client: Client, storage: Storage) -> Self {
-        Operator { client, storage }
+        Operator {
+            client,
+            storage,
+            last_drift_check: Mutex::new(None),
+        }
     }
 
     pub async fn run(&self) {
-        loop {
+        let mut wakeup = reconcile::subscribe();
+        while !shutdown::is_triggered() {
+            let timer = RECONCILE_DURATION.with_label_values(&["k8s"]).start_timer();
             let state = self.storage.snapshot().await;
+            let check_drift = self.drift_check_due();
             for user in &state.users {
                 for instance in &user.instances {
+                    // Stop between instances so that no instance is left half-synced.
+                    if shutdown::is_triggered() {
+                        return;
+                    }
                     if instance.runtime != Runtime::Kata && instance.runtime != Runtime::Runc {
                         continue;
                     }
@@ -333,7 +938,26 @@ impl Operator {
                     if instance.status == InstanceStatus::Creating && instance.node_name.is_none() {
                         continue;
                     }
+                    self.storage
+                        .record_unhealthy_node(&state, &user.username, instance)
+                        .await;
+                    let _lock = instance_lock::lock(&user.username, &instance.name).await;
                     self.sync_instance(user, instance).await;
+                    if check_drift
+                        && instance.stage == InstanceStage::Running
+                        && instance.status == InstanceStatus::Running
+                    {
+                        if let Err(e) = self.check_drift(user, instance).await {
+                            warn!(
+                                username = user.username.as_str(),
+                                instance = instance.name.as_str(),
+                                runtime = instance.runtime.to_string().as_str(),
+                                error = e.to_string().as_str(),
+                                "checking instance drift encountered error"
+                            );
+                            BACKEND_ERRORS.with_label_values(&["k8s"]).inc();
+                        }
+                    }
                 }
                 // If a user has no instance, delete the Service.
                 if user.instances.is_empty() {
@@ -344,13 +968,16 @@ impl Operator {
                             error = e.to_string().as_str(),
                             "deleting service encountered error"
                         );
+                        BACKEND_ERRORS.with_label_values(&["k8s"]).inc();
                     }
                 }
             }
-            sleep(Duration::from_secs(3)).await;
+            timer.observe_duration();
+            reconcile::sleep(&mut wakeup, config::current().reconcile_wait()).await;
         }
     }
 
+    #[instrument(skip_all, fields(username = %user.username, instance = %instance.name, runtime = %instance.runtime))]
     async fn sync_instance(&self, user: &User, instance: &Instance) {
         match instance.stage {
             InstanceStage::Stopped => {
@@ -369,6 +996,7 @@ impl Operator {
                             error = e.to_string().as_str(),
                             "stopping instance encountered error"
                         );
+                        BACKEND_ERRORS.with_label_values(&["k8s"]).inc();
                     }
                 }
             }
@@ -391,6 +1019,7 @@ impl Operator {
                             error = e.to_string().as_str(),
                             "starting instance encountered error"
                         );
+                        BACKEND_ERRORS.with_label_values(&["k8s"]).inc();
                     }
                 }
             }
@@ -409,6 +1038,7 @@ impl Operator {
                         error = e.to_string().as_str(),
                         "deleting instance encountered error"
                     );
+                    BACKEND_ERRORS.with_label_values(&["k8s"]).inc();
                 }
             }
         }
@@ -420,7 +1050,84 @@ impl Operator {
                 error = e.to_string().as_str(),
                 "updating instance status encountered error"
             );
+            BACKEND_ERRORS.with_label_values(&["k8s"]).inc();
+        }
+    }
+
+    /// Returns whether the running instances should be checked for drift in this pass.
+    fn drift_check_due(&self) -> bool {
+        let interval = config::current().drift_check_interval;
+        if interval == 0 {
+            return false;
+        }
+        let mut last = self.last_drift_check.lock().unwrap();
+        if matches!(*last, Some(t) if t.elapsed() < Duration::from_secs(interval * 60)) {
+            return false;
+        }
+        *last = Some(Instant::now());
+        true
+    }
+
+    /// Compares the Pod and the Service of the instance with the spec, recording the drifted
+    /// settings as an event and patching the Service back if drift correction is enabled. The
+    /// Pod can't be changed without recreating it, so its drift is only reported.
+    async fn check_drift(&self, user: &User, instance: &Instance) -> Result<()> {
+        let pod_name = instance.backend_name(&user.username);
+        let pods: Api<Pod> = Api::namespaced(self.client.clone(), NAMESPACE);
+        let services: Api<Service> = Api::namespaced(self.client.clone(), NAMESPACE);
+        let pod = pods.get(&pod_name).await?;
+        let svc = services.get(&pod_name).await?;
+        let drifts = find_drifts(&pod_name, instance, &pod, &svc);
+        if drifts.is_empty() {
+            return Ok(());
+        }
+
+        let correction = config::current().drift_correction;
+        let (corrected, reported): (Vec<&Drift>, Vec<&Drift>) =
+            drifts.iter().partition(|d| correction && d.correctable);
+        if !corrected.is_empty() {
+            info!("correcting service {}", pod_name);
+            // The node port is kept so that the SSH port of the instance doesn't change.
+            let mut port = serde_json::json!({
+                "name": "ssh",
+                "port": 22,
+                "targetPort": 22,
+                "protocol": "TCP"
+            });
+            if let Some(node_port) = get_ssh_port(&svc) {
+                port["nodePort"] = node_port.into();
+            }
+            let patch = serde_json::json!({
+                "spec": {
+                    "type": "LoadBalancer",
+                    "ports": [port]
+                }
+            });
+            services
+                .patch(&pod_name, &PatchParams::default(), &Patch::Merge(&patch))
+                .await?;
+            self.storage
+                .add_instance_event(
+                    &user.username,
+                    &instance.name,
+                    drift_event("configuration drift corrected", &corrected),
+                )
+                .await?;
+        }
+        if !reported.is_empty() {
+            let event = drift_event("configuration drifted", &reported);
+            warn!(
+                username = user.username.as_str(),
+                instance = instance.name.as_str(),
+                runtime = instance.runtime.to_string().as_str(),
+                event = event.as_str(),
+                "instance is drifted"
+            );
+            self.storage
+                .add_instance_event(&user.username, &instance.name, event)
+                .await?;
         }
+        Ok(())
     }
 
     async fn delete_pod(&self, pod_name: &str) -> Result<()> {
@@ -472,13 +1179,13 @@ impl Operator {
     }
 
     async fn stop_instance(&self, user: &User, instance: &Instance) -> Result<()> {
-        let pod_name = format!("{}-{}", user.username, instance.name);
+        let pod_name = instance.backend_name(&user.username);
         info!("deleting pod {}", pod_name);
         self.delete_pod(&pod_name).await
     }
 
     async fn start_instance(&self, user: &User, instance: &Instance) -> Result<()> {
-        let pod_name = format!("{}-{}", user.username, instance.name);
+        let pod_name = instance.backend_name(&user.username);
 
         // 1. Ensure sudomain service is created.
         let subdomain = user.username.clone();
@@ -508,28 +1215,52 @@ impl Operator {
             }
         }
 
-        // 3. Ensure PersistentVolumeClaim is created.
-        let pvc_name = format!("{}-{}-rootfs", user.username, instance.name);
+        // 3. Ensure PersistentVolumeClaims of the root disk and the volumes are created.
+        let pvc_name = format!("{}-rootfs", instance.backend_name(&user.username));
         let pvcs: Api<PersistentVolumeClaim> = Api::namespaced(self.client.clone(), NAMESPACE);
-        match pvcs.get(&pvc_name).await {
-            Ok(_) => {}
-            Err(kube::Error::Api(ErrorResponse { code: 404, .. })) => {
-                info!("creating persistentvolumeclaim {}", pvc_name);
-                let pvc = build_rootfs_pvc(&pvc_name, instance.disk_size);
-                pvcs.create(&PostParams::default(), &pvc).await?;
-            }
-            Err(e) => {
-                return Err(anyhow!(e));
+        let mut claims = vec![(pvc_name.clone(), instance.disk_size)];
+        claims.extend(
+            instance
+                .volumes
+                .iter()
+                .map(|v| (volume_pvc_name(&pod_name, v), v.size)),
+        );
+        for (name, size) in claims {
+            match pvcs.get(&name).await {
+                Ok(_) => {}
+                Err(kube::Error::Api(ErrorResponse { code: 404, .. })) => {
+                    info!("creating persistentvolumeclaim {}", name);
+                    let pvc = build_pvc(&name, &pod_name, instance, size);
+                    pvcs.create(&PostParams::default(), &pvc).await?;
+                }
+                Err(e) => {
+                    return Err(anyhow!(e));
+                }
             }
         }
 
-        // 4. Ensure Pod is created.
+        // 4. Ensure PersistentVolumeClaim of the home volume of the user is created.
+        let storage_class = config::current().home_volume_storage_class.clone();
+        let home = match &user.home_volume {
+            Some(v) if !storage_class.is_empty() => {
+                let name = home_pvc_name(&user.username);
+                self.ensure_home_pvc(&name, &user.username, v.size, &storage_class)
+                    .await?;
+                Some(HomeMount {
+                    pvc_name: name,
+                    mount_path: config::current().home_volume_mount_path(&user.username),
+                })
+            }
+            _ => None,
+        };
+
+        // 5. Ensure Pod is created.
         let pods: Api<Pod> = Api::namespaced(self.client.clone(), NAMESPACE);
         match pods.get(&pod_name).await {
             Ok(_) => {}
             Err(kube::Error::Api(ErrorResponse { code: 404, .. })) => {
                 info!("creating pod {}", pod_name);
-                let pod = build_pod(&pod_name, &pvc_name, &subdomain, instance)?;
+                let pod = build_pod(&pod_name, &pvc_name, &subdomain, instance, home.as_ref())?;
                 pods.create(&PostParams::default(), &pod).await?;
             }
             Err(e) => {
@@ -539,19 +1270,71 @@ impl Operator {
         Ok(())
     }
 
+    /// Creates the PersistentVolumeClaim of the home volume of the user, or grows it to the size
+    /// of the volume.
+    async fn ensure_home_pvc(
+        &self,
+        pvc_name: &str,
+        username: &str,
+        size: usize,
+        storage_class: &str,
+    ) -> Result<()> {
+        let pvcs: Api<PersistentVolumeClaim> = Api::namespaced(self.client.clone(), NAMESPACE);
+        let desired = Quantity(format!("{}Gi", size));
+        match pvcs.get(pvc_name).await {
+            Ok(pvc) => {
+                let current = pvc
+                    .spec
+                    .and_then(|s| s.resources)
+                    .and_then(|r| r.requests)
+                    .and_then(|r| r.get("storage").cloned());
+                if current.as_ref() != Some(&desired) {
+                    info!(
+                        "resizing persistentvolumeclaim {} to {}",
+                        pvc_name, desired.0
+                    );
+                    let patch = serde_json::json!({
+                        "spec": {
+                            "resources": {
+                                "requests": { "storage": desired }
+                            }
+                        }
+                    });
+                    pvcs.patch(pvc_name, &PatchParams::default(), &Patch::Merge(&patch))
+                        .await?;
+                }
+            }
+            Err(kube::Error::Api(ErrorResponse { code: 404, .. })) => {
+                info!("creating persistentvolumeclaim {}", pvc_name);
+                let pvc = build_home_pvc(pvc_name, username, size, storage_class);
+                pvcs.create(&PostParams::default(), &pvc).await?;
+            }
+            Err(e) => {
+                return Err(anyhow!(e));
+            }
+        }
+        Ok(())
+    }
+
     async fn delete_instance(&self, user: &User, instance: &Instance) -> Result<()> {
-        let pod_name = format!("{}-{}", user.username, instance.name);
-        let pvc_name = format!("{}-{}-rootfs", user.username, instance.name);
+        let pod_name = instance.backend_name(&user.username);
+        let pvc_name = format!("{}-rootfs", instance.backend_name(&user.username));
         self.delete_pod(&pod_name).await?;
-        self.delete_pvc(&pvc_name).await?;
+        // The root disk kept as a detached volume is left to its owner.
+        if !instance.keep_disk {
+            self.delete_pvc(&pvc_name).await?;
+        }
+        for v in &instance.volumes {
+            self.delete_pvc(&volume_pvc_name(&pod_name, v)).await?;
+        }
         self.delete_service(&pod_name).await?;
         Ok(())
     }
 
     async fn update_instance_status(&self, user: &User, instance: &Instance) -> Result<()> {
-        let pod_name = format!("{}-{}", user.username, instance.name);
+        let pod_name = instance.backend_name(&user.username);
         let pods: Api<Pod> = Api::namespaced(self.client.clone(), NAMESPACE);
-        let pvc_name = format!("{}-{}-rootfs", user.username, instance.name);
+        let pvc_name = format!("{}-rootfs", instance.backend_name(&user.username));
         let pvcs: Api<PersistentVolumeClaim> = Api::namespaced(self.client.clone(), NAMESPACE);
         let services: Api<Service> = Api::namespaced(self.client.clone(), NAMESPACE);
         let mut new_status = instance.status.clone();
@@ -560,6 +1343,7 @@ impl Operator {
         let mut new_internal_ip = None;
         let mut new_external_ip = None;
         let mut new_node_name = None;
+        let mut new_progress = None;
         let mut deleted = false;
         match instance.stage {
             InstanceStage::Stopped => match pods.get(&pod_name).await {
@@ -579,8 +1363,24 @@ impl Operator {
                             .as_ref()
                             .map(|s| s.phase.clone().unwrap_or_default())
                             .unwrap_or_default();
+                        if pod_status != "Running" {
+                            new_progress = Some(provisioning_progress(&pod));
+                        }
                         if pod_status == "Running" {
                             new_status = InstanceStatus::Running;
+                        } else if let Some(failure) = init_container_failure(&pod) {
+                            new_status = InstanceStatus::Error(failure.clone());
+                            if instance.status != new_status {
+                                warn!(
+                                    username = user.username.as_str(),
+                                    instance = instance.name.as_str(),
+                                    failure = failure.as_str(),
+                                    "init container failed"
+                                );
+                                self.storage
+                                    .add_instance_event(&user.username, &instance.name, failure)
+                                    .await?;
+                            }
                         } else {
                             match instance.status {
                                 InstanceStatus::Running
@@ -623,6 +1423,9 @@ impl Operator {
                             }
                         };
                     }
+                    // The pod of an instance which failed to initialize is recreated with the init container.
+                    Err(kube::Error::Api(ErrorResponse { code: 404, .. }))
+                        if failed_to_init(instance) => {}
                     Err(kube::Error::Api(ErrorResponse { code: 404, .. })) => {
                         match instance.status {
                             InstanceStatus::Running | InstanceStatus::Error(_) => {
@@ -652,13 +1455,20 @@ impl Operator {
                         return Err(anyhow!(e));
                     }
                 };
-                match pvcs.get(&pvc_name).await {
-                    Ok(_) => {
-                        deleted = false;
-                    }
-                    Err(kube::Error::Api(ErrorResponse { code: 404, .. })) => {}
-                    Err(e) => {
-                        return Err(anyhow!(e));
+                let volume_pvc_names = instance
+                    .volumes
+                    .iter()
+                    .map(|v| volume_pvc_name(&pod_name, v));
+                let root_pvc_name = Some(pvc_name.clone()).filter(|_| !instance.keep_disk);
+                for name in root_pvc_name.into_iter().chain(volume_pvc_names) {
+                    match pvcs.get(&name).await {
+                        Ok(_) => {
+                            deleted = false;
+                        }
+                        Err(kube::Error::Api(ErrorResponse { code: 404, .. })) => {}
+                        Err(e) => {
+                            return Err(anyhow!(e));
+                        }
                     }
                 }
                 match services.get(&pod_name).await {
@@ -682,8 +1492,19 @@ impl Operator {
                 .map(|s| s.to_owned());
         }
 
+        // Let the collector refresh the node the instance was created on or deleted from.
+        let dirty_node = if deleted {
+            instance.node_name.clone()
+        } else if instance.status != InstanceStatus::Running
+            && new_status == InstanceStatus::Running
+        {
+            new_node_name.clone()
+        } else {
+            None
+        };
+
         self.storage
-            .read_write(|state| {
+            .read_write_deferred(|state| {
                 if let Some(u) = state.find_mut_user(&user.username) {
                     for i in 0..u.instances.len() {
                         if u.instances[i].name == instance.name
@@ -694,7 +1515,8 @@ impl Operator {
                             } else {
                                 u.instances[i].ssh_host = new_ssh_host.clone();
                                 u.instances[i].ssh_port = new_ssh_port;
-                                u.instances[i].status = new_status.clone();
+                                lifecycle::observe(&mut u.instances[i], new_status.clone());
+                                u.instances[i].progress = new_progress.clone();
                                 u.instances[i].internal_ip = new_internal_ip.clone();
                                 u.instances[i].external_ip = new_external_ip.clone();
                                 if new_node_name.is_some() {
@@ -711,7 +1533,11 @@ impl Operator {
                 false
             })
             .await
-            .map_err(|e| anyhow!(e))
+            .map_err(|e| anyhow!(e))?;
+        if let Some(node_name) = &dirty_node {
+            self.storage.mark_node_dirty(node_name);
+        }
+        Ok(())
     }
 
     async fn get_lvm_volume_name(
@@ -719,7 +1545,7 @@ impl Operator {
         user: &User,
         instance: &Instance,
     ) -> Result<Option<String>> {
-        let pvc_name = format!("{}-{}-rootfs", user.username, instance.name);
+        let pvc_name = format!("{}-rootfs", instance.backend_name(&user.username));
         let pvcs: Api<PersistentVolumeClaim> = Api::namespaced(self.client.clone(), NAMESPACE);
         let pv_name = match pvcs.get(&pvc_name).await {
             Ok(pvc) => pvc.spec.and_then(|s| s.volume_name).unwrap_or_default(),
@@ -1,33 +1,266 @@
+use std::str::FromStr;
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::sync::Mutex;
+
 use anyhow::{anyhow, Result};
-use reqwest::Client;
-use tokio::time::{sleep, Duration};
-use tracing::{info, warn};
+use reqwest::header::{CONTENT_LENGTH, CONTENT_TYPE};
+use reqwest::{Body, Client, Identity};
+use tokio::time::{Duration, Instant};
+use tracing::{info, instrument, warn};
 
-use crate::env::{EXTERNAL_IP_PREFIX_LENGTH, LXD_IMAGE_SERVER_URL, LXD_PROJECT, LXD_SERVER_URL};
-use crate::model::{Image, Instance, InstanceStage, InstanceStatus, Runtime, User};
+use crate::config;
+use crate::env::{
+    HASH_PASSWORDS, LXD_API_FLAVOR, LXD_CLIENT_CERT, LXD_IMAGE_SERVER_URL, LXD_PROJECT,
+    LXD_SERVER_URLS,
+};
+use crate::instance_lock;
+use crate::lifecycle;
+use crate::metrics::{BACKEND_ERRORS, RECONCILE_DURATION};
+use crate::model::{
+    drift_event, Conversion, Drift, ImageFamily, Instance, InstanceStage, InstanceStatus, Progress,
+    Runtime, Transfer, TransferKind, TransferStatus, User, LOCAL_IMAGE_PREFIX, PREVIOUS_ROOT_PATH,
+};
+use crate::reconcile;
+use crate::s3::Bucket;
+use crate::shutdown;
 use crate::storage::Storage;
 
+/// The REST API dialect spoken by the server. Incus is a fork of LXD which kept the API under
+/// `/1.0` but renamed the custom headers and moved the cloud-init keys into their own namespace.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+crate enum ApiFlavor {
+    Lxd,
+    Incus,
+}
+
+impl ApiFlavor {
+    crate fn header(&self, name: &str) -> String {
+        match self {
+            ApiFlavor::Lxd => format!("X-LXD-{}", name),
+            ApiFlavor::Incus => format!("X-Incus-{}", name),
+        }
+    }
+
+    fn user_data_key(&self) -> &'static str {
+        match self {
+            ApiFlavor::Lxd => "user.user-data",
+            ApiFlavor::Incus => "cloud-init.user-data",
+        }
+    }
+
+    fn network_config_key(&self) -> &'static str {
+        match self {
+            ApiFlavor::Lxd => "user.network-config",
+            ApiFlavor::Incus => "cloud-init.network-config",
+        }
+    }
+}
+
+impl FromStr for ApiFlavor {
+    type Err = anyhow::Error;
+
+    fn from_str(s: &str) -> Result<Self> {
+        match s {
+            "lxd" => Ok(ApiFlavor::Lxd),
+            "incus" => Ok(ApiFlavor::Incus),
+            _ => Err(anyhow!("invalid api flavor {}", s)),
+        }
+    }
+}
+
+/// Returns a client authenticated with the client certificate trusted by the server.
+pub fn new_client() -> Client {
+    let buf = std::fs::read(LXD_CLIENT_CERT.as_str()).unwrap();
+    let id = Identity::from_pkcs12_der(&buf, "").unwrap();
+    Client::builder()
+        .danger_accept_invalid_certs(true)
+        .identity(id)
+        .build()
+        .unwrap()
+}
+
+// Index into LXD_SERVER_URLS of the cluster member requests are sent to.
+static ACTIVE_SERVER: AtomicUsize = AtomicUsize::new(0);
+
+/// Returns the URL of the cluster member requests are sent to.
+crate fn server_url() -> &'static str {
+    let urls = &*LXD_SERVER_URLS;
+    &urls[ACTIVE_SERVER.load(Ordering::Relaxed) % urls.len()]
+}
+
+/// Returns the URL of `path` under the versioned API root of the server.
+crate fn api_url(path: &str) -> String {
+    format!("{}/1.0{}", server_url(), path)
+}
+
+/// Checks that the cluster member requests are sent to is healthy, failing over to the next
+/// healthy member otherwise.
+crate async fn check_server(client: &Client) -> Result<()> {
+    let urls = &*LXD_SERVER_URLS;
+    if urls.len() < 2 {
+        return Ok(());
+    }
+    let active = ACTIVE_SERVER.load(Ordering::Relaxed) % urls.len();
+    for i in 0..urls.len() {
+        let index = (active + i) % urls.len();
+        if let Err(e) = probe_server(client, &urls[index]).await {
+            warn!(
+                server = urls[index].as_str(),
+                error = e.to_string().as_str(),
+                "lxd server is unhealthy"
+            );
+            continue;
+        }
+        if index != active {
+            warn!(
+                from = urls[active].as_str(),
+                to = urls[index].as_str(),
+                "failing over to another lxd server"
+            );
+            ACTIVE_SERVER.store(index, Ordering::Relaxed);
+        }
+        return Ok(());
+    }
+    Err(anyhow!("no lxd server is healthy"))
+}
+
+async fn probe_server(client: &Client, url: &str) -> Result<()> {
+    let res: serde_json::Value = client
+        .get(format!("{}/1.0", url))
+        .timeout(LXD_PROBE_TIMEOUT)
+        .send()
+        .await?
+        .json()
+        .await?;
+    check_error(&res)
+}
+
+const LXD_PROBE_TIMEOUT: Duration = Duration::from_secs(5);
+
+// Config key of LXD instances holding the ID of the instance they back.
+const INSTANCE_ID_KEY: &str = "user.tispace.instance-id";
+
+// The device of a rebuilt instance attaching the root disk of the previous backend.
+const PREVIOUS_ROOT_DEVICE: &str = "previous-root";
+
+// Features of the project instances are created in. Profiles, and so the NICs they define, are
+// shared with the default project, while images built or imported by users are kept apart.
+const PROJECT_FEATURES: [(&str, &str); 4] = [
+    ("features.images", "true"),
+    ("features.profiles", "false"),
+    ("features.storage.volumes", "true"),
+    ("features.networks", "false"),
+];
+
 pub struct Operator {
     client: Client,
     storage: Storage,
+    bucket: Option<Bucket>,
+    last_drift_check: Mutex<Option<Instant>>,
 }
 
 impl Operator {
     pub fn new(client: Client, storage: Storage) -> Self {
-        Operator { client, storage }
+        Operator {
+            client,
+            storage,
+            bucket: Bucket::from_env(),
+            last_drift_check: Mutex::new(None),
+        }
     }
 
     pub async fn run(&self) {
-        loop {
+        while !shutdown::is_triggered() {
+            match self.ensure_project().await {
+                Ok(()) => break,
+                Err(e) => {
+                    warn!(
+                        project = LXD_PROJECT.as_str(),
+                        error = e.to_string().as_str(),
+                        "ensuring lxd project encountered error"
+                    );
+                    BACKEND_ERRORS.with_label_values(&["lxd"]).inc();
+                    shutdown::sleep(Duration::from_secs(10)).await;
+                }
+            }
+        }
+        let mut wakeup = reconcile::subscribe();
+        while !shutdown::is_triggered() {
             self.run_once().await;
-            sleep(Duration::from_secs(3)).await;
+            reconcile::sleep(&mut wakeup, config::current().reconcile_wait()).await;
         }
     }
 
+    /// Creates the project instances are created in if it doesn't exist. An existing project is
+    /// left as is, with a warning for each feature differing from the expected one.
+    async fn ensure_project(&self) -> Result<()> {
+        check_server(&self.client).await?;
+        let url = api_url(&format!("/projects/{}", LXD_PROJECT.as_str()));
+        let res: serde_json::Value = self.client.get(url).send().await?.json().await?;
+        if is_not_found(&res) {
+            info!(project = LXD_PROJECT.as_str(), "creating lxd project");
+            let config: serde_json::Map<String, serde_json::Value> = PROJECT_FEATURES
+                .iter()
+                .map(|(k, v)| (k.to_string(), v.to_string().into()))
+                .collect();
+            let res: serde_json::Value = self
+                .client
+                .post(api_url("/projects"))
+                .json(&serde_json::json!({
+                    "name": LXD_PROJECT.as_str(),
+                    "description": "Instances managed by tispace",
+                    "config": config
+                }))
+                .send()
+                .await?
+                .json()
+                .await?;
+            return check_error(&res);
+        }
+        check_error(&res)?;
+        // The features of the default project can't be changed.
+        if LXD_PROJECT.as_str() == "default" {
+            return Ok(());
+        }
+        for (key, expected) in PROJECT_FEATURES {
+            let actual = res
+                .get("metadata")
+                .and_then(|m| m.get("config"))
+                .and_then(|c| c.get(key))
+                .and_then(|v| v.as_str())
+                .unwrap_or_default();
+            if actual != expected {
+                warn!(
+                    project = LXD_PROJECT.as_str(),
+                    feature = key,
+                    actual,
+                    expected,
+                    "lxd project has an unexpected feature"
+                );
+            }
+        }
+        Ok(())
+    }
+
+    #[instrument(skip_all)]
     async fn run_once(&self) {
+        let _timer = RECONCILE_DURATION.with_label_values(&["lxd"]).start_timer();
+        if let Err(e) = check_server(&self.client).await {
+            warn!(
+                error = e.to_string().as_str(),
+                "checking lxd servers encountered error"
+            );
+            BACKEND_ERRORS.with_label_values(&["lxd"]).inc();
+            return;
+        }
         let state = self.storage.snapshot().await;
+        let check_drift = self.drift_check_due();
         for user in &state.users {
             for instance in &user.instances {
+                // Stop between instances so that no instance is left half-synced.
+                if shutdown::is_triggered() {
+                    return;
+                }
                 if instance.runtime != Runtime::Lxc && instance.runtime != Runtime::Kvm {
                     continue;
                 }
@@ -35,16 +268,140 @@ impl Operator {
                 if instance.status == InstanceStatus::Creating
                     && (instance.external_ip.is_none()
                         || instance.node_name.is_none()
-                        || instance.storage_pool.is_none())
+                        || instance.storage_pool.is_none()
+                        || instance.dedicated_cpu && instance.pinned_cpus.is_empty())
                 {
                     continue;
                 }
+                self.storage
+                    .record_unhealthy_node(&state, &user.username, instance)
+                    .await;
+                let _lock = instance_lock::lock(&user.username, &instance.name).await;
                 self.sync_instance(user, instance).await;
+                if check_drift
+                    && instance.stage == InstanceStage::Running
+                    && instance.status == InstanceStatus::Running
+                    && instance.conversion.is_none()
+                {
+                    if let Err(e) = self.check_drift(user, instance).await {
+                        warn!(
+                            username = user.username.as_str(),
+                            instance = instance.name.as_str(),
+                            runtime = instance.runtime.to_string().as_str(),
+                            error = e.to_string().as_str(),
+                            "checking instance drift encountered error"
+                        );
+                        BACKEND_ERRORS.with_label_values(&["lxd"]).inc();
+                    }
+                }
             }
         }
     }
 
+    /// Returns whether the running instances should be checked for drift in this pass.
+    fn drift_check_due(&self) -> bool {
+        let interval = config::current().drift_check_interval;
+        if interval == 0 {
+            return false;
+        }
+        let mut last = self.last_drift_check.lock().unwrap();
+        if matches!(*last, Some(t) if t.elapsed() < Duration::from_secs(interval * 60)) {
+            return false;
+        }
+        *last = Some(Instant::now());
+        true
+    }
+
+    /// Compares the LXD instance with the spec, recording the drifted settings as an event and
+    /// growing the root disk back to its size if drift correction is enabled.
+    async fn check_drift(&self, user: &User, instance: &Instance) -> Result<()> {
+        let name = instance.backend_name(&user.username);
+        let url = api_url(&format!(
+            "/instances/{}?project={}",
+            name,
+            LXD_PROJECT.as_str()
+        ));
+        let res: serde_json::Value = self.client.get(url.clone()).send().await?.json().await?;
+        check_error(&res)?;
+        let metadata = res
+            .get("metadata")
+            .ok_or_else(|| anyhow!("cannot find instance metadata"))?;
+        let drifts = find_drifts(instance, metadata);
+        if drifts.is_empty() {
+            return Ok(());
+        }
+
+        let correction = config::current().drift_correction;
+        let (corrected, reported): (Vec<&Drift>, Vec<&Drift>) =
+            drifts.iter().partition(|d| correction && d.correctable);
+        if !corrected.is_empty() {
+            // The root disk is the only correctable setting.
+            let mut root = metadata
+                .get("devices")
+                .and_then(|d| d.get("root"))
+                .cloned()
+                .ok_or_else(|| anyhow!("cannot find root disk device"))?;
+            root["size"] = format!("{}GiB", instance.disk_size).into();
+            info!(
+                username = user.username.as_str(),
+                instance = instance.name.as_str(),
+                runtime = instance.runtime.to_string().as_str(),
+                disk_size = instance.disk_size,
+                "root disk size is drifted, correcting"
+            );
+            let res: serde_json::Value = self
+                .client
+                .patch(url)
+                .json(&serde_json::json!({ "devices": { "root": root } }))
+                .send()
+                .await?
+                .json()
+                .await?;
+            check_error(&res)?;
+            self.storage
+                .add_instance_event(
+                    &user.username,
+                    &instance.name,
+                    drift_event("configuration drift corrected", &corrected),
+                )
+                .await?;
+        }
+        if !reported.is_empty() {
+            let event = drift_event("configuration drifted", &reported);
+            warn!(
+                username = user.username.as_str(),
+                instance = instance.name.as_str(),
+                runtime = instance.runtime.to_string().as_str(),
+                event = event.as_str(),
+                "instance is drifted"
+            );
+            self.storage
+                .add_instance_event(&user.username, &instance.name, event)
+                .await?;
+        }
+        Ok(())
+    }
+
+    #[instrument(skip_all, fields(username = %user.username, instance = %instance.name, runtime = %instance.runtime))]
     async fn sync_instance(&self, user: &User, instance: &Instance) {
+        if let Some(c) = &instance.conversion {
+            if !c.renamed || c.discarded {
+                if let Err(e) = self.sync_conversion(user, instance, c).await {
+                    warn!(
+                        username = user.username.as_str(),
+                        instance = instance.name.as_str(),
+                        runtime = instance.runtime.to_string().as_str(),
+                        error = e.to_string().as_str(),
+                        "converting instance encountered error"
+                    );
+                    BACKEND_ERRORS.with_label_values(&["lxd"]).inc();
+                }
+            }
+            // The instance is rebuilt only once the name of its backend is free.
+            if !c.renamed {
+                return;
+            }
+        }
         match instance.stage {
             InstanceStage::Stopped => {
                 if instance.status != InstanceStatus::Stopped
@@ -58,13 +415,27 @@ impl Operator {
                             error = e.to_string().as_str(),
                             "stopping instance encountered error"
                         );
+                        BACKEND_ERRORS.with_label_values(&["lxd"]).inc();
                     }
                 }
             }
             InstanceStage::Running => {
                 if instance.status != InstanceStatus::Running {
                     if instance.status == InstanceStatus::Creating {
-                        if let Err(e) = self.create_instance(user, instance).await {
+                        let operation = instance
+                            .progress
+                            .as_ref()
+                            .and_then(|p| p.operation.as_ref());
+                        let res = match (&instance.transfer, operation) {
+                            (Some(t), _) if t.kind == TransferKind::Import => {
+                                self.import_instance(user, instance, t).await
+                            }
+                            (_, Some(operation)) => {
+                                self.follow_creation(user, instance, operation).await
+                            }
+                            _ => self.create_instance(user, instance).await,
+                        };
+                        if let Err(e) = res {
                             warn!(
                                 username = user.username.as_str(),
                                 instance = instance.name.as_str(),
@@ -72,6 +443,7 @@ impl Operator {
                                 error = e.to_string().as_str(),
                                 "creating instance encountered error"
                             );
+                            BACKEND_ERRORS.with_label_values(&["lxd"]).inc();
                         }
                     } else if instance.status != InstanceStatus::Missing {
                         if let Err(e) = self.start_instance(user, instance).await {
@@ -82,8 +454,18 @@ impl Operator {
                                 error = e.to_string().as_str(),
                                 "starting instance encountered error"
                             );
+                            BACKEND_ERRORS.with_label_values(&["lxd"]).inc();
                         }
                     }
+                } else if let Err(e) = self.sync_instance_limits(user, instance).await {
+                    warn!(
+                        username = user.username.as_str(),
+                        instance = instance.name.as_str(),
+                        runtime = instance.runtime.to_string().as_str(),
+                        error = e.to_string().as_str(),
+                        "resizing instance encountered error"
+                    );
+                    BACKEND_ERRORS.with_label_values(&["lxd"]).inc();
                 }
             }
             InstanceStage::Deleted => {
@@ -96,6 +478,7 @@ impl Operator {
                             error = e.to_string().as_str(),
                             "stopping instance encountered error"
                         );
+                        BACKEND_ERRORS.with_label_values(&["lxd"]).inc();
                     }
                 } else if let Err(e) = self.delete_instance(user, instance).await {
                     warn!(
@@ -105,6 +488,21 @@ impl Operator {
                         error = e.to_string().as_str(),
                         "deleting instance encountered error"
                     );
+                    BACKEND_ERRORS.with_label_values(&["lxd"]).inc();
+                }
+            }
+        }
+        if let Some(t) = &instance.transfer {
+            if t.kind == TransferKind::Export && !t.is_finished() {
+                if let Err(e) = self.export_instance(user, instance, t).await {
+                    warn!(
+                        username = user.username.as_str(),
+                        instance = instance.name.as_str(),
+                        runtime = instance.runtime.to_string().as_str(),
+                        error = e.to_string().as_str(),
+                        "exporting instance encountered error"
+                    );
+                    BACKEND_ERRORS.with_label_values(&["lxd"]).inc();
                 }
             }
         }
@@ -116,6 +514,7 @@ impl Operator {
                 error = e.to_string().as_str(),
                 "updating instance status encountered error"
             );
+            BACKEND_ERRORS.with_label_values(&["lxd"]).inc();
         }
     }
 
@@ -126,116 +525,169 @@ impl Operator {
             runtime = instance.runtime.to_string().as_str(),
             "creating instance"
         );
-        let name = format!("{}-{}", user.username, instance.name);
-        let url = format!(
-            "{}/1.0/instances?project={}&target={}",
-            LXD_SERVER_URL.as_str(),
+        let name = instance.backend_name(&user.username);
+        let url = api_url(&format!(
+            "/instances?project={}&target={}",
             LXD_PROJECT.as_str(),
             instance.node_name.as_ref().unwrap()
-        );
+        ));
 
         let type_ = get_instance_type(&instance.runtime)?;
 
-        let eip = format!(
-            "{}/{}",
-            instance.external_ip.as_ref().unwrap(),
-            EXTERNAL_IP_PREFIX_LENGTH.to_owned()
-        );
-
-        let user_data = format!(
-            r#"#cloud-config
-hostname: {}
-fqdn: {}
-ssh_pwauth: true
-disable_root: false
-chpasswd:
-  expire: false
-  list:
-  - root:{}
-"#,
-            instance.name, instance.name, instance.password
-        );
-        let network_config = match instance.image {
-            Image::CentOS7 | Image::CentOS8 | Image::CentOS9Stream => {
-                format!(
-                    r#"network:
-  version: 1
-  config:
-  - type: physical
-    name: eth0
-    subnets:
-    - type: dhcp
-  - type: physical
-    name: eth1
-    subnets:
-    - type: static
-      address: {}
-"#,
-                    eip
-                )
+        let user_data = build_user_data(instance);
+        let network_config = build_network_config(instance);
+        let mut devices = serde_json::json!({
+            "root": {
+                "path": "/",
+                "pool": instance.storage_pool.as_ref().unwrap(),
+                "size": format!("{}GiB",instance.disk_size),
+                "type":"disk"
             }
-            Image::Ubuntu2004 | Image::Ubuntu2204 => {
-                let mut eth0 = "eth0";
-                let mut eth1 = "eth1";
-                if instance.runtime == Runtime::Kvm {
-                    eth0 = "enp5s0";
-                    eth1 = "enp6s0";
-                }
-                format!(
-                    r#"network:
-  version: 2
-  ethernets:
-    eth0:
-      match:
-        name: {}
-      dhcp4: true
-      dhcp6: false
-    eth1:
-      match:
-        name: {}
-      dhcp4: false
-      dhcp6: false
-      addresses:
-      - {}
-"#,
-                    eth0, eth1, eip
-                )
+        });
+        // The external NIC overrides the one of the profiles if the pool is on another segment.
+        let pool = config::current().ip_pool(instance.ip_pool.as_deref());
+        if !pool.parent.is_empty() {
+            let mut nic = serde_json::json!({
+                "type": "nic",
+                "nictype": "macvlan",
+                "parent": pool.parent,
+                "name": "eth1"
+            });
+            if let Some(vlan) = pool.vlan {
+                nic["vlan"] = vlan.to_string().into();
             }
-        };
+            if let Some(limit) = instance.ingress_limit {
+                nic["limits.ingress"] = format!("{}Mbit", limit).into();
+            }
+            if let Some(limit) = instance.egress_limit {
+                nic["limits.egress"] = format!("{}Mbit", limit).into();
+            }
+            devices["eth1"] = nic;
+        }
+
+        if let Some(home) = self.ensure_home_volume(user).await? {
+            devices["home"] = home;
+        }
+        if let Some(c) = instance.conversion.as_ref().filter(|c| !c.discarded) {
+            devices[PREVIOUS_ROOT_DEVICE] = build_previous_root_device(instance, c);
+        }
+        // NFS exports are only mounted by Kubernetes, hosts mount them for LXD instances.
+        for (i, m) in instance.mounts.iter().enumerate() {
+            if m.nfs_export().is_some() {
+                continue;
+            }
+            devices[format!("mount-{}", i)] = serde_json::json!({
+                "type": "disk",
+                "source": m.source,
+                "path": m.path,
+                "readonly": m.read_only.to_string()
+            });
+        }
 
+        let mut body = serde_json::json!({
+            "description": instance.description,
+            "devices": devices,
+            "name": name,
+            "source": build_image_source(instance)?,
+            "config": {
+                "limits.cpu": build_cpu_limit(instance),
+                "limits.memory": format!("{}GiB", instance.memory),
+                INSTANCE_ID_KEY: instance.id,
+                LXD_API_FLAVOR.user_data_key(): user_data,
+                LXD_API_FLAVOR.network_config_key(): network_config
+            },
+            "type": type_
+        });
+        apply_nesting(instance, &mut body);
+        for (key, value) in &instance.sysctls {
+            body["config"][format!("linux.sysctl.{}", key)] = value.as_str().into();
+        }
         let res: serde_json::Value = self
             .client
             .post(url)
-            .json(&serde_json::json!({
-                "devices": {
-                    "root": {
-                        "path": "/",
-                        "pool": instance.storage_pool.as_ref().unwrap(),
-                        "size": format!("{}GiB",instance.disk_size),
-                        "type":"disk"
-                    }
-                },
-                "name": name,
-                "source": {
-                    "type": "image",
-                    "alias": get_image_alias(&instance.image)?,
-                    "protocol": "simplestreams",
-                    "mode": "pull",
-                    "server": LXD_IMAGE_SERVER_URL.as_str()
-                },
-                "config": {
-                    "limits.cpu": instance.cpu.to_string(),
-                    "limits.memory": format!("{}GiB", instance.memory),
-                    "user.user-data": user_data,
-                    "user.network-config": network_config
-                },
-                "type": type_
-            }))
+            .json(&body)
             .send()
             .await?
             .json()
             .await?;
-        check_error(&res)
+        check_error(&res)?;
+        let mut progress = Progress::new("Creating instance");
+        progress.operation = Some(parse_operation(&res)?);
+        self.update_progress(user, instance, Some(progress)).await
+    }
+
+    /// Follows the operation creating the instance, reporting the download and unpacking of the
+    /// image as the progress of the instance.
+    async fn follow_creation(
+        &self,
+        user: &User,
+        instance: &Instance,
+        operation: &str,
+    ) -> Result<()> {
+        let progress = match self.get_operation(operation).await {
+            Ok(OperationStatus::Running(progress)) => {
+                let mut progress = progress
+                    .as_deref()
+                    .map(parse_creation_progress)
+                    .unwrap_or_else(|| Progress::new("Creating instance"));
+                progress.operation = Some(operation.to_owned());
+                Some(progress)
+            }
+            Ok(OperationStatus::Success) => {
+                // Let the collector refresh the storage usage of the node the instance was
+                // created on, now that its disk is allocated.
+                if let Some(node_name) = &instance.node_name {
+                    self.storage.mark_node_dirty(node_name);
+                }
+                Some(Progress::new("Starting instance"))
+            }
+            Ok(OperationStatus::Failure(err)) => {
+                self.storage
+                    .add_instance_event(
+                        &user.username,
+                        &instance.name,
+                        format!("creation failed: {}", err),
+                    )
+                    .await?;
+                // The instance is created again in the next pass.
+                None
+            }
+            // Finished operations are soon forgotten by the server, after which the status of
+            // the instance tells whether it was created.
+            Err(e) => {
+                info!(
+                    username = user.username.as_str(),
+                    instance = instance.name.as_str(),
+                    error = e.to_string().as_str(),
+                    "creation operation is gone"
+                );
+                Some(Progress::new("Creating instance"))
+            }
+        };
+        self.update_progress(user, instance, progress).await
+    }
+
+    async fn update_progress(
+        &self,
+        user: &User,
+        instance: &Instance,
+        progress: Option<Progress>,
+    ) -> Result<()> {
+        self.storage
+            .read_write_deferred(|state| {
+                match state
+                    .find_mut_user(&user.username)
+                    .and_then(|u| u.find_mut_instance(&instance.name))
+                {
+                    Some(i) if i.progress != progress => {
+                        i.progress = progress.clone();
+                        true
+                    }
+                    _ => false,
+                }
+            })
+            .await
+            .map_err(|e| anyhow!(e))
     }
 
     async fn delete_instance(&self, user: &User, instance: &Instance) -> Result<()> {
@@ -245,13 +697,19 @@ chpasswd:
             runtime = instance.runtime.to_string().as_str(),
             "deleting instance"
         );
-        let name = format!("{}-{}", user.username, instance.name);
-        let url = format!(
-            "{}/1.0/instances/{}?project={}",
-            LXD_SERVER_URL.as_str(),
+        if let Some(c) = instance.conversion.as_ref().filter(|c| c.renamed) {
+            self.delete_backend(&c.previous_backend).await?;
+        }
+        self.delete_backend(&instance.backend_name(&user.username))
+            .await
+    }
+
+    async fn delete_backend(&self, name: &str) -> Result<()> {
+        let url = api_url(&format!(
+            "/instances/{}?project={}",
             name,
-            LXD_PROJECT.as_str(),
-        );
+            LXD_PROJECT.as_str()
+        ));
 
         let res: serde_json::Value = self.client.delete(url).send().await?.json().await?;
         if is_not_found(&res) {
@@ -270,13 +728,12 @@ chpasswd:
 
         self.sync_instance_limits(user, instance).await?;
 
-        let name = format!("{}-{}", user.username, instance.name);
-        let url = format!(
-            "{}/1.0/instances/{}/state?project={}",
-            LXD_SERVER_URL.as_str(),
+        let name = instance.backend_name(&user.username);
+        let url = api_url(&format!(
+            "/instances/{}/state?project={}",
             name,
-            LXD_PROJECT.as_str(),
-        );
+            LXD_PROJECT.as_str()
+        ));
 
         let res: serde_json::Value = self
             .client
@@ -292,66 +749,152 @@ chpasswd:
     }
 
     async fn sync_instance_limits(&self, user: &User, instance: &Instance) -> Result<()> {
-        let name = format!("{}-{}", user.username, instance.name);
-        let url = format!(
-            "{}/1.0/instances/{}?project={}",
-            LXD_SERVER_URL.as_str(),
+        let name = instance.backend_name(&user.username);
+        let url = api_url(&format!(
+            "/instances/{}?project={}",
             name,
-            LXD_PROJECT.as_str(),
-        );
+            LXD_PROJECT.as_str()
+        ));
         let res: serde_json::Value = self.client.get(url.clone()).send().await?.json().await?;
         check_error(&res)?;
 
-        if parse_instance_status(&res).unwrap_or_default() != "Stopped" {
-            return Ok(());
-        }
+        // Running instances are resized in place as far as their runtime can hotplug the
+        // resources, the rest is applied when they are started again.
+        let running = match parse_instance_status(&res).unwrap_or_default().as_str() {
+            "Stopped" => false,
+            "Running" => true,
+            _ => return Ok(()),
+        };
 
         let config = res
             .get("metadata")
             .and_then(|m| m.get("config"))
             .ok_or_else(|| anyhow!("cannot find instance config"))?;
-        let cpu_limit = config
-            .get("limits.cpu")
-            .and_then(|v| v.as_str())
-            .unwrap_or_default();
-        let memory_limit = config
-            .get("limits.memory")
-            .and_then(|v| v.as_str())
-            .unwrap_or_default();
-        if cpu_limit != instance.cpu.to_string().as_str()
-            || memory_limit != format!("{}GiB", instance.memory)
-        {
+        let mut metadata = res.get("metadata").unwrap().clone();
+        let mut changed = false;
+        for (key, desired, hotplug) in [
+            (
+                "limits.cpu",
+                build_cpu_limit(instance),
+                instance.runtime.supports_cpu_hotplug(),
+            ),
+            (
+                "limits.memory",
+                format!("{}GiB", instance.memory),
+                instance.runtime.supports_memory_hotplug(),
+            ),
+        ] {
+            let current = config.get(key).and_then(|v| v.as_str()).unwrap_or_default();
+            if current == desired || running && !hotplug {
+                continue;
+            }
+            changed = true;
             info!(
                 username = user.username.as_str(),
                 instance = instance.name.as_str(),
                 runtime = instance.runtime.to_string().as_str(),
-                cpu_limit = cpu_limit,
-                memory_limit = memory_limit,
-                new_cpu_limit = instance.cpu,
-                new_memory_limit = format!("{}GiB", instance.memory).as_str(),
-                "instance limits are chagned, updating"
+                key = key,
+                limit = current,
+                new_limit = desired.as_str(),
+                running = running,
+                "instance limit is changed, updating"
             );
-
-            let mut metadata = res.get("metadata").unwrap().clone();
             metadata
                 .get_mut("config")
                 .unwrap()
                 .as_object_mut()
                 .unwrap()
-                .insert(
-                    "limits.cpu".to_string(),
-                    serde_json::Value::String(instance.cpu.to_string()),
+                .insert(key.to_string(), serde_json::Value::String(desired));
+        }
+
+        let description = metadata
+            .get("description")
+            .and_then(|v| v.as_str())
+            .unwrap_or_default();
+        if description != instance.description {
+            changed = true;
+            metadata["description"] = instance.description.as_str().into();
+        }
+
+        // The external NIC is either a device of the instance or inherited from the profiles, in
+        // which case it's overridden by a device of the instance carrying the limits.
+        let mut nic = res
+            .get("metadata")
+            .and_then(|m| m.get("devices"))
+            .and_then(|d| d.get("eth1"))
+            .or_else(|| {
+                res.get("metadata")
+                    .and_then(|m| m.get("expanded_devices"))
+                    .and_then(|d| d.get("eth1"))
+            })
+            .cloned()
+            .unwrap_or_default();
+        // Bandwidth limits are only changed along with a restart.
+        if let Some(nic) = nic.as_object_mut().filter(|_| !running) {
+            let mut nic_changed = false;
+            for (key, limit) in [
+                ("limits.ingress", instance.ingress_limit),
+                ("limits.egress", instance.egress_limit),
+            ] {
+                let current = nic.get(key).and_then(|v| v.as_str()).map(str::to_owned);
+                let desired = limit.map(|limit| format!("{}Mbit", limit));
+                if current == desired {
+                    continue;
+                }
+                nic_changed = true;
+                match desired {
+                    Some(desired) => nic.insert(key.to_string(), desired.into()),
+                    None => nic.remove(key),
+                };
+            }
+            if nic_changed {
+                changed = true;
+                info!(
+                    username = user.username.as_str(),
+                    instance = instance.name.as_str(),
+                    runtime = instance.runtime.to_string().as_str(),
+                    ingress_limit = instance.ingress_limit.unwrap_or_default(),
+                    egress_limit = instance.egress_limit.unwrap_or_default(),
+                    "instance bandwidth limits are changed, updating"
                 );
-            metadata
-                .get_mut("config")
-                .unwrap()
+                metadata
+                    .as_object_mut()
+                    .unwrap()
+                    .entry("devices")
+                    .or_insert_with(|| serde_json::json!({}))
+                    .as_object_mut()
+                    .ok_or_else(|| anyhow!("invalid instance devices"))?
+                    .insert("eth1".to_string(), serde_json::Value::Object(nic.clone()));
+            }
+        }
+
+        // The home volume is attached or detached along with a restart.
+        if !running {
+            let home = self.ensure_home_volume(user).await?;
+            let devices = metadata
                 .as_object_mut()
                 .unwrap()
-                .insert(
-                    "limits.memory".to_string(),
-                    serde_json::Value::String(format!("{}GiB", instance.memory)),
+                .entry("devices")
+                .or_insert_with(|| serde_json::json!({}))
+                .as_object_mut()
+                .ok_or_else(|| anyhow!("invalid instance devices"))?;
+            if devices.get("home") != home.as_ref() {
+                changed = true;
+                info!(
+                    username = user.username.as_str(),
+                    instance = instance.name.as_str(),
+                    runtime = instance.runtime.to_string().as_str(),
+                    home_volume = home.is_some(),
+                    "instance home volume is changed, updating"
                 );
+                match home {
+                    Some(home) => devices.insert("home".to_string(), home),
+                    None => devices.remove("home"),
+                };
+            }
+        }
 
+        if changed {
             let res = self
                 .client
                 .put(url)
@@ -365,6 +908,83 @@ chpasswd:
         Ok(())
     }
 
+    /// Creates the custom volume backing the home volume of the user, or grows it to the size of
+    /// the home volume. Returns the disk device mounting it, None if the user has no home volume
+    /// or LXD instances get none.
+    async fn ensure_home_volume(&self, user: &User) -> Result<Option<serde_json::Value>> {
+        let config = config::current();
+        let size = match &user.home_volume {
+            Some(v) if !config.home_volume_lxd_pool.is_empty() => format!("{}GiB", v.size),
+            _ => return Ok(None),
+        };
+        let pool = config.home_volume_lxd_pool.as_str();
+        let name = home_volume_name(&user.username);
+        let url = api_url(&format!(
+            "/storage-pools/{}/volumes/custom/{}?project={}",
+            pool,
+            name,
+            LXD_PROJECT.as_str()
+        ));
+        let res: serde_json::Value = self.client.get(url.clone()).send().await?.json().await?;
+        if is_not_found(&res) {
+            info!(
+                username = user.username.as_str(),
+                pool,
+                volume = name.as_str(),
+                "creating home volume"
+            );
+            let url = api_url(&format!(
+                "/storage-pools/{}/volumes/custom?project={}",
+                pool,
+                LXD_PROJECT.as_str()
+            ));
+            let res: serde_json::Value = self
+                .client
+                .post(url)
+                .json(&serde_json::json!({
+                    "name": name,
+                    "config": { "size": size }
+                }))
+                .send()
+                .await?
+                .json()
+                .await?;
+            check_error(&res)?;
+        } else {
+            check_error(&res)?;
+            let current = res
+                .get("metadata")
+                .and_then(|m| m.get("config"))
+                .and_then(|c| c.get("size"))
+                .and_then(|s| s.as_str())
+                .unwrap_or_default();
+            if current != size {
+                info!(
+                    username = user.username.as_str(),
+                    pool,
+                    volume = name.as_str(),
+                    size = size.as_str(),
+                    "resizing home volume"
+                );
+                let res: serde_json::Value = self
+                    .client
+                    .patch(url)
+                    .json(&serde_json::json!({ "config": { "size": size } }))
+                    .send()
+                    .await?
+                    .json()
+                    .await?;
+                check_error(&res)?;
+            }
+        }
+        Ok(Some(serde_json::json!({
+            "type": "disk",
+            "pool": pool,
+            "source": name,
+            "path": config.home_volume_mount_path(&user.username)
+        })))
+    }
+
     async fn stop_instance(&self, user: &User, instance: &Instance) -> Result<()> {
         info!(
             username = user.username.as_str(),
@@ -372,13 +992,12 @@ chpasswd:
             runtime = instance.runtime.to_string().as_str(),
             "stopping instance"
         );
-        let name = format!("{}-{}", user.username, instance.name);
-        let url = format!(
-            "{}/1.0/instances/{}/state?project={}",
-            LXD_SERVER_URL.as_str(),
+        let name = instance.backend_name(&user.username);
+        let url = api_url(&format!(
+            "/instances/{}/state?project={}",
             name,
-            LXD_PROJECT.as_str(),
-        );
+            LXD_PROJECT.as_str()
+        ));
 
         let res: serde_json::Value = self
             .client
@@ -393,22 +1012,363 @@ chpasswd:
         check_error(&res)
     }
 
+    async fn export_instance(
+        &self,
+        user: &User,
+        instance: &Instance,
+        transfer: &Transfer,
+    ) -> Result<()> {
+        let bucket = self
+            .bucket
+            .as_ref()
+            .ok_or_else(|| anyhow!("backup storage is not configured"))?
+            .with_bucket(&transfer.bucket);
+        let name = instance.backend_name(&user.username);
+        let backup_name = get_backup_name(&transfer.object_key);
+        let mut transfer = transfer.clone();
+
+        match &transfer.operation {
+            None => {
+                info!(
+                    username = user.username.as_str(),
+                    instance = instance.name.as_str(),
+                    runtime = instance.runtime.to_string().as_str(),
+                    object_key = transfer.object_key.as_str(),
+                    "creating instance backup"
+                );
+                let url = api_url(&format!(
+                    "/instances/{}/backups?project={}",
+                    name,
+                    LXD_PROJECT.as_str()
+                ));
+                let res: serde_json::Value = self
+                    .client
+                    .post(url)
+                    .json(&serde_json::json!({
+                        "name": backup_name,
+                        "instance_only": true,
+                        "optimized_storage": false,
+                        "compression_algorithm": "gzip"
+                    }))
+                    .send()
+                    .await?
+                    .json()
+                    .await?;
+                check_error(&res)?;
+                transfer.operation = Some(parse_operation(&res)?);
+                transfer.status = TransferStatus::Running;
+            }
+            Some(operation) => match self.get_operation(operation).await? {
+                OperationStatus::Running(progress) => {
+                    transfer.progress = progress;
+                }
+                OperationStatus::Failure(err) => {
+                    transfer.status = TransferStatus::Failed(err);
+                }
+                OperationStatus::Success => {
+                    info!(
+                        username = user.username.as_str(),
+                        instance = instance.name.as_str(),
+                        runtime = instance.runtime.to_string().as_str(),
+                        object_key = transfer.object_key.as_str(),
+                        "uploading instance backup"
+                    );
+                    let url = api_url(&format!(
+                        "/instances/{}/backups/{}?project={}",
+                        name,
+                        backup_name,
+                        LXD_PROJECT.as_str()
+                    ));
+                    let res = self
+                        .client
+                        .get(api_url(&format!(
+                            "/instances/{}/backups/{}/export?project={}",
+                            name,
+                            backup_name,
+                            LXD_PROJECT.as_str()
+                        )))
+                        .send()
+                        .await?
+                        .error_for_status()?;
+                    let len = res
+                        .content_length()
+                        .ok_or_else(|| anyhow!("unknown backup size"))?;
+                    bucket
+                        .put_object(
+                            &transfer.object_key,
+                            Body::wrap_stream(res.bytes_stream()),
+                            len,
+                        )
+                        .await?;
+
+                    // The backup has been uploaded, it's useless to keep it in LXD.
+                    let res: serde_json::Value =
+                        self.client.delete(url).send().await?.json().await?;
+                    if !is_not_found(&res) {
+                        check_error(&res)?;
+                    }
+                    transfer.progress = None;
+                    transfer.status = TransferStatus::Succeeded;
+                }
+            },
+        }
+        self.update_transfer(user, instance, transfer).await
+    }
+
+    async fn import_instance(
+        &self,
+        user: &User,
+        instance: &Instance,
+        transfer: &Transfer,
+    ) -> Result<()> {
+        let bucket = self
+            .bucket
+            .as_ref()
+            .ok_or_else(|| anyhow!("backup storage is not configured"))?
+            .with_bucket(&transfer.bucket);
+        let name = instance.backend_name(&user.username);
+        let mut transfer = transfer.clone();
+
+        match &transfer.operation {
+            _ if transfer.is_finished() => return Ok(()),
+            None => {
+                info!(
+                    username = user.username.as_str(),
+                    instance = instance.name.as_str(),
+                    runtime = instance.runtime.to_string().as_str(),
+                    object_key = transfer.object_key.as_str(),
+                    "importing instance"
+                );
+                let url = api_url(&format!(
+                    "/instances?project={}&target={}",
+                    LXD_PROJECT.as_str(),
+                    instance.node_name.as_ref().unwrap()
+                ));
+                let object = bucket.get_object(&transfer.object_key).await?;
+                let mut req = self
+                    .client
+                    .post(url)
+                    .header(CONTENT_TYPE, "application/octet-stream")
+                    .header(LXD_API_FLAVOR.header("name"), name.as_str())
+                    .header(
+                        LXD_API_FLAVOR.header("pool"),
+                        instance.storage_pool.as_ref().unwrap().as_str(),
+                    );
+                if let Some(len) = object.content_length() {
+                    req = req.header(CONTENT_LENGTH, len);
+                }
+                let res: serde_json::Value = req
+                    .body(Body::wrap_stream(object.bytes_stream()))
+                    .send()
+                    .await?
+                    .json()
+                    .await?;
+                check_error(&res)?;
+                transfer.operation = Some(parse_operation(&res)?);
+                transfer.status = TransferStatus::Running;
+            }
+            Some(operation) => match self.get_operation(operation).await? {
+                OperationStatus::Running(progress) => {
+                    transfer.progress = progress;
+                }
+                OperationStatus::Failure(err) => {
+                    transfer.status = TransferStatus::Failed(err);
+                }
+                OperationStatus::Success => {
+                    // The imported instance still carries the configuration of the exported one,
+                    // so apply the limits, password and network of this instance to it.
+                    let url = api_url(&format!(
+                        "/instances/{}?project={}",
+                        name,
+                        LXD_PROJECT.as_str()
+                    ));
+                    let mut body = serde_json::json!({
+                        "config": {
+                            "limits.cpu": build_cpu_limit(instance),
+                            "limits.memory": format!("{}GiB", instance.memory),
+                            INSTANCE_ID_KEY: instance.id,
+                            LXD_API_FLAVOR.user_data_key(): build_user_data(instance),
+                            LXD_API_FLAVOR.network_config_key(): build_network_config(instance)
+                        }
+                    });
+                    apply_nesting(instance, &mut body);
+                    let res: serde_json::Value = self
+                        .client
+                        .patch(url)
+                        .json(&body)
+                        .send()
+                        .await?
+                        .json()
+                        .await?;
+                    check_error(&res)?;
+                    transfer.progress = None;
+                    transfer.status = TransferStatus::Succeeded;
+                }
+            },
+        }
+        self.update_transfer(user, instance, transfer).await
+    }
+
+    /// Renames the backend of an instance being converted out of the way, or deletes it once the
+    /// conversion is discarded.
+    async fn sync_conversion(
+        &self,
+        user: &User,
+        instance: &Instance,
+        conversion: &Conversion,
+    ) -> Result<()> {
+        let name = instance.backend_name(&user.username);
+        if !conversion.renamed {
+            let url = api_url(&format!(
+                "/instances/{}?project={}",
+                name,
+                LXD_PROJECT.as_str()
+            ));
+            let res: serde_json::Value = self.client.get(url.clone()).send().await?.json().await?;
+            // The rename is done once the backend is gone under its current name.
+            if !is_not_found(&res) {
+                check_error(&res)?;
+                info!(
+                    username = user.username.as_str(),
+                    instance = instance.name.as_str(),
+                    runtime = instance.runtime.to_string().as_str(),
+                    previous_backend = conversion.previous_backend.as_str(),
+                    "renaming instance backend before conversion"
+                );
+                let res: serde_json::Value = self
+                    .client
+                    .post(url)
+                    .json(&serde_json::json!({ "name": conversion.previous_backend }))
+                    .send()
+                    .await?
+                    .json()
+                    .await?;
+                return check_error(&res);
+            }
+        } else {
+            info!(
+                username = user.username.as_str(),
+                instance = instance.name.as_str(),
+                runtime = instance.runtime.to_string().as_str(),
+                previous_backend = conversion.previous_backend.as_str(),
+                "deleting previous backend of converted instance"
+            );
+            self.detach_previous_root(&name).await?;
+            self.delete_backend(&conversion.previous_backend).await?;
+        }
+        self.storage
+            .read_write(|state| {
+                match state
+                    .find_mut_user(&user.username)
+                    .and_then(|u| u.find_mut_instance(&instance.name))
+                {
+                    Some(i) => {
+                        if conversion.renamed {
+                            i.conversion = None;
+                        } else if let Some(c) = &mut i.conversion {
+                            c.renamed = true;
+                        }
+                        true
+                    }
+                    None => false,
+                }
+            })
+            .await
+            .map_err(|e| anyhow!(e))
+    }
+
+    /// Removes the root disk of the previous backend from the devices of the rebuilt instance,
+    /// if it was rebuilt at all, so that the previous backend can be deleted.
+    async fn detach_previous_root(&self, name: &str) -> Result<()> {
+        let url = api_url(&format!(
+            "/instances/{}?project={}",
+            name,
+            LXD_PROJECT.as_str()
+        ));
+        let res: serde_json::Value = self.client.get(url.clone()).send().await?.json().await?;
+        if is_not_found(&res) {
+            return Ok(());
+        }
+        check_error(&res)?;
+        let mut metadata = res.get("metadata").cloned().unwrap_or_default();
+        match metadata
+            .get_mut("devices")
+            .and_then(|d| d.as_object_mut())
+            .and_then(|d| d.remove(PREVIOUS_ROOT_DEVICE))
+        {
+            Some(_) => {
+                let res: serde_json::Value = self
+                    .client
+                    .put(url)
+                    .json(&metadata)
+                    .send()
+                    .await?
+                    .json()
+                    .await?;
+                check_error(&res)
+            }
+            None => Ok(()),
+        }
+    }
+
+    async fn get_operation(&self, operation: &str) -> Result<OperationStatus> {
+        let url = format!("{}{}", server_url(), operation);
+        let res: serde_json::Value = self.client.get(url).send().await?.json().await?;
+        check_error(&res)?;
+        parse_operation_status(&res)
+    }
+
+    async fn update_transfer(
+        &self,
+        user: &User,
+        instance: &Instance,
+        transfer: Transfer,
+    ) -> Result<()> {
+        self.storage
+            .read_write(|state| {
+                if let Some(i) = state
+                    .find_mut_user(&user.username)
+                    .and_then(|u| u.find_mut_instance(&instance.name))
+                {
+                    if let TransferStatus::Failed(err) = &transfer.status {
+                        warn!(
+                            username = user.username.as_str(),
+                            instance = instance.name.as_str(),
+                            runtime = instance.runtime.to_string().as_str(),
+                            kind = transfer.kind.to_string().as_str(),
+                            error = err.as_str(),
+                            "transfer failed"
+                        );
+                        if transfer.kind == TransferKind::Import {
+                            lifecycle::observe(
+                                i,
+                                InstanceStatus::Error(format!("Import failed: {}", err)),
+                            );
+                        }
+                    }
+                    i.transfer = Some(transfer.clone());
+                }
+                true
+            })
+            .await
+            .map_err(|e| anyhow!(e))
+    }
+
     async fn update_instance_status(&self, user: &User, instance: &Instance) -> Result<()> {
-        let name = format!("{}-{}", user.username, instance.name);
-        let url = format!(
-            "{}/1.0/instances/{}/state?project={}",
-            LXD_SERVER_URL.as_str(),
+        let name = instance.backend_name(&user.username);
+        let url = api_url(&format!(
+            "/instances/{}/state?project={}",
             name,
-            LXD_PROJECT.as_str(),
-        );
+            LXD_PROJECT.as_str()
+        ));
         let res: serde_json::Value = self.client.get(url).send().await?.json().await?;
         if is_not_found(&res) {
             if instance.status == InstanceStatus::Creating {
                 return Ok(());
             }
-            return self
+            let res = self
                 .storage
-                .read_write(|state| {
+                .read_write_deferred(|state| {
                     if let Some(i) = state
                         .find_mut_user(&user.username)
                         .and_then(|u| u.find_mut_instance(&instance.name))
@@ -419,7 +1379,7 @@ chpasswd:
                                 .unwrap()
                                 .remove_instance(&instance.name);
                         } else {
-                            i.status = InstanceStatus::Missing;
+                            lifecycle::observe(i, InstanceStatus::Missing);
                             warn!(
                                 username = user.username.as_str(),
                                 instance = instance.name.as_str(),
@@ -432,13 +1392,20 @@ chpasswd:
                 })
                 .await
                 .map_err(|e| anyhow!(e));
+            // Let the collector refresh the storage usage of the node the instance was deleted from.
+            if instance.stage == InstanceStage::Deleted {
+                if let Some(node_name) = &instance.node_name {
+                    self.storage.mark_node_dirty(node_name);
+                }
+            }
+            return res;
         }
         check_error(&res)?;
 
         let status = parse_instance_status(&res).unwrap_or_default();
         let internal_ip = parse_internal_ip(&res);
         self.storage
-            .read_write(|state| {
+            .read_write_deferred(|state| {
                 if let Some(i) = state
                     .find_mut_user(&user.username)
                     .and_then(|u| u.find_mut_instance(&instance.name))
@@ -446,20 +1413,28 @@ chpasswd:
                     match i.stage {
                         InstanceStage::Stopped => {
                             if status == "Stopped" {
-                                i.status = InstanceStatus::Stopped;
+                                lifecycle::observe(i, InstanceStatus::Stopped);
                             }
                         }
                         InstanceStage::Running => {
-                            if status == "Stopped" && i.status == InstanceStatus::Creating {
-                                i.status = InstanceStatus::Starting;
-                            } else if status == "Running" {
-                                i.status = InstanceStatus::Running;
+                            // An importing instance shows up as stopped before it is
+                            // reconfigured, it must not be started until the import finishes.
+                            let importing = matches!(
+                                &i.transfer,
+                                Some(t) if t.kind == TransferKind::Import
+                                    && t.status != TransferStatus::Succeeded
+                            );
+                            if !(importing
+                                && status == "Stopped"
+                                && i.status == InstanceStatus::Creating)
+                            {
+                                lifecycle::observe_running(i, &status);
                             }
                             i.internal_ip = internal_ip.clone();
                         }
                         InstanceStage::Deleted => {
                             if status == "Stopped" {
-                                i.status = InstanceStatus::Deleting;
+                                lifecycle::observe(i, InstanceStatus::Deleting);
                             }
                         }
                     }
@@ -471,16 +1446,367 @@ chpasswd:
     }
 }
 
-fn get_image_alias(image: &Image) -> Result<String> {
-    match image {
-        Image::CentOS7 => Ok("centos/7/cloud".to_owned()),
-        Image::CentOS9Stream => Ok("centos/9-Stream/cloud".to_owned()),
-        Image::Ubuntu2004 => Ok("ubuntu/20.04/cloud".to_owned()),
-        Image::Ubuntu2204 => Ok("ubuntu/22.04/cloud".to_owned()),
-        _ => Err(anyhow!("invalid image {}", image)),
+/// Returns the name of the custom volume backing the home volume of the user.
+crate fn home_volume_name(username: &str) -> String {
+    format!("home-{}", username)
+}
+
+crate fn build_user_data(instance: &Instance) -> String {
+    // Keys are the primary way to log in if passwords are not stored in plain text.
+    let keys = instance.authorized_keys();
+    let ssh_pwauth = !(*HASH_PASSWORDS && !keys.is_empty());
+    let mut user_data = format!(
+        r#"#cloud-config
+hostname: {}
+fqdn: {}
+ssh_pwauth: {}
+disable_root: false
+"#,
+        instance.name, instance.name, ssh_pwauth
+    );
+    // A hashed password can't be set in the guest, which is then reached with the authorized
+    // keys, or the password set when it was created as long as its root disk is kept.
+    if !instance.password_hashed {
+        user_data.push_str(&format!(
+            r#"chpasswd:
+  expire: false
+  list:
+  - root:{}
+"#,
+            instance.password
+        ));
+    }
+    if !keys.is_empty() {
+        user_data.push_str("ssh_authorized_keys:\n");
+        for key in &keys {
+            // JSON strings are valid YAML scalars, which takes care of quoting.
+            user_data.push_str(&format!("- {}\n", serde_json::to_string(key).unwrap()));
+        }
+    }
+    // The host keys are posted once per instance, so again after a rebuild.
+    let metadata_url = config::current().metadata_url.clone();
+    if !metadata_url.is_empty() {
+        user_data.push_str(&format!(
+            r#"phone_home:
+  url: {}
+  post: [pub_key_rsa, pub_key_ecdsa, pub_key_ed25519]
+  tries: 10
+"#,
+            serde_json::to_string(&format!(
+                "{}/metadata/host-keys",
+                metadata_url.trim_end_matches('/')
+            ))
+            .unwrap()
+        ));
+    }
+    user_data
+}
+
+/// Returns the disk device attaching the root disk of the previous backend of the converted
+/// instance read-only, see `PREVIOUS_ROOT_PATH`. It's passed from where the node mounts the
+/// storage pool: the root filesystem of a container as a directory, and the disk image of a VM
+/// as a file.
+fn build_previous_root_device(instance: &Instance, conversion: &Conversion) -> serde_json::Value {
+    let (kind, root, path) = match conversion.from {
+        Runtime::Kvm => (
+            "virtual-machines",
+            "root.img",
+            format!("{}.img", PREVIOUS_ROOT_PATH),
+        ),
+        _ => ("containers", "rootfs", PREVIOUS_ROOT_PATH.to_owned()),
+    };
+    // Volumes of instances outside the default project are prefixed with the project.
+    let volume = match LXD_PROJECT.as_str() {
+        "default" => conversion.previous_backend.clone(),
+        project => format!("{}_{}", project, conversion.previous_backend),
+    };
+    serde_json::json!({
+        "type": "disk",
+        "source": format!(
+            "{}/{}/{}/{}/{}",
+            config::current().lxd_storage_pools_dir.trim_end_matches('/'),
+            instance.storage_pool.as_deref().unwrap_or_default(),
+            kind,
+            volume,
+            root
+        ),
+        "path": path,
+        "readonly": "true"
+    })
+}
+
+/// Returns the CPU limit of the instance, either the number of CPUs or the set of host CPUs it's
+/// pinned to.
+fn build_cpu_limit(instance: &Instance) -> String {
+    match instance.pinned_cpus.as_slice() {
+        [] => instance.cpu.to_string(),
+        // A single number would be taken as the number of CPUs.
+        [cpu] => format!("{}-{}", cpu, cpu),
+        cpus => cpus
+            .iter()
+            .map(|c| c.to_string())
+            .collect::<Vec<_>>()
+            .join(","),
+    }
+}
+
+/// Returns the progress of a creation reported by its operation, e.g. `rootfs: 45% (12.34MB/s)`
+/// while the image is downloaded or `Unpack: 50%` while it's unpacked.
+fn parse_creation_progress(progress: &str) -> Progress {
+    let phase = if progress.starts_with("Unpack") {
+        "Unpacking image"
+    } else {
+        "Downloading image"
+    };
+    let mut p = Progress::new(phase);
+    p.percent = progress
+        .split_whitespace()
+        .find_map(|w| w.strip_suffix('%'))
+        .and_then(|w| w.parse().ok());
+    p
+}
+
+/// Adds the config and devices letting the container run containers or VMs of its own to the
+/// body of a request creating or updating it.
+fn apply_nesting(instance: &Instance, body: &mut serde_json::Value) {
+    if instance.nesting {
+        for (key, value) in [
+            ("security.nesting", "true"),
+            ("security.syscalls.intercept.mknod", "true"),
+            ("security.syscalls.intercept.setxattr", "true"),
+            (
+                "linux.kernel_modules",
+                "overlay,br_netfilter,ip_tables,ip6_tables,nf_nat",
+            ),
+        ] {
+            body["config"][key] = value.into();
+        }
+    }
+    if instance.kvm_passthrough {
+        body["devices"]["kvm"] = serde_json::json!({
+            "type": "unix-char",
+            "source": "/dev/kvm",
+            "path": "/dev/kvm"
+        });
+    }
+}
+
+/// Returns the settings of the LXD instance, as described by its metadata, that differ from the
+/// spec.
+fn find_drifts(instance: &Instance, metadata: &serde_json::Value) -> Vec<Drift> {
+    let config = metadata
+        .get("expanded_config")
+        .or_else(|| metadata.get("config"));
+    let config_value = |key: &str| {
+        config
+            .and_then(|c| c.get(key))
+            .and_then(|v| v.as_str())
+            .map(str::to_owned)
+    };
+    let devices = metadata
+        .get("expanded_devices")
+        .or_else(|| metadata.get("devices"));
+    let device = |name: &str| devices.and_then(|d| d.get(name));
+
+    let mut drifts = Vec::new();
+    let mut check = |setting: &str, actual: String, desired: String, correctable: bool| {
+        let same = match (parse_size(&actual), parse_size(&desired)) {
+            (Some(a), Some(d)) => a == d,
+            _ => match (parse_bit_rate(&actual), parse_bit_rate(&desired)) {
+                (Some(a), Some(d)) => a == d,
+                _ => actual == desired,
+            },
+        };
+        if !same {
+            drifts.push(Drift {
+                setting: setting.to_owned(),
+                actual,
+                desired,
+                correctable,
+            });
+        }
+    };
+
+    check(
+        "limits.cpu",
+        config_value("limits.cpu").unwrap_or_default(),
+        build_cpu_limit(instance),
+        false,
+    );
+    // The memory of running VMs is resized when they are started again, and reported in the
+    // unit the hot-plugged memory is counted in, so it's only checked where it's applied live.
+    if instance.runtime.supports_memory_hotplug() {
+        check(
+            "limits.memory",
+            config_value("limits.memory").unwrap_or_default(),
+            format!("{}GiB", instance.memory),
+            false,
+        );
+    }
+    check(
+        "security.nesting",
+        config_value("security.nesting").unwrap_or_else(|| "false".to_owned()),
+        instance.nesting.to_string(),
+        false,
+    );
+
+    let root_size = device("root")
+        .and_then(|d| d.get("size"))
+        .and_then(|v| v.as_str())
+        .unwrap_or_default()
+        .to_owned();
+    let desired_root_size = format!("{}GiB", instance.disk_size);
+    // Growing a disk is safe while shrinking it is not.
+    let growable = matches!(
+        (parse_size(&root_size), parse_size(&desired_root_size)),
+        (Some(a), Some(d)) if a < d
+    );
+    check("root size", root_size, desired_root_size, growable);
+
+    let kvm = |present: bool| if present { "present" } else { "absent" }.to_owned();
+    check(
+        "/dev/kvm",
+        kvm(device("kvm").is_some()),
+        kvm(instance.kvm_passthrough),
+        false,
+    );
+
+    if let Some(nic) = device("eth1") {
+        for (key, limit) in [
+            ("limits.ingress", instance.ingress_limit),
+            ("limits.egress", instance.egress_limit),
+        ] {
+            let unlimited = || "unlimited".to_owned();
+            check(
+                &format!("eth1 {}", key),
+                nic.get(key)
+                    .and_then(|v| v.as_str())
+                    .map(str::to_owned)
+                    .unwrap_or_else(unlimited),
+                limit
+                    .map(|limit| format!("{}Mbit", limit))
+                    .unwrap_or_else(unlimited),
+                false,
+            );
+        }
+    }
+    drifts
+}
+
+/// Parses a size in LXD notation, e.g. `10GiB`, `512MB` or a number of bytes, into bytes.
+fn parse_size(s: &str) -> Option<u64> {
+    let split = s.find(|c: char| !c.is_ascii_digit()).unwrap_or(s.len());
+    let (number, unit) = s.split_at(split);
+    let number: u64 = number.parse().ok()?;
+    let multiplier: u64 = match unit {
+        "" | "B" => 1,
+        "kB" => 1000,
+        "MB" => 1000_u64.pow(2),
+        "GB" => 1000_u64.pow(3),
+        "TB" => 1000_u64.pow(4),
+        "KiB" => 1024,
+        "MiB" => 1024_u64.pow(2),
+        "GiB" => 1024_u64.pow(3),
+        "TiB" => 1024_u64.pow(4),
+        _ => return None,
+    };
+    number.checked_mul(multiplier)
+}
+
+/// Parses a bit rate in LXD notation, e.g. `100Mbit` or `1Gbit`, into bits per second.
+fn parse_bit_rate(s: &str) -> Option<u64> {
+    let split = s.find(|c: char| !c.is_ascii_digit()).unwrap_or(s.len());
+    let (number, unit) = s.split_at(split);
+    let number: u64 = number.parse().ok()?;
+    let multiplier: u64 = match unit {
+        "" | "bit" => 1,
+        "kbit" => 1000,
+        "Mbit" => 1000_u64.pow(2),
+        "Gbit" => 1000_u64.pow(3),
+        "Tbit" => 1000_u64.pow(4),
+        _ => return None,
+    };
+    number.checked_mul(multiplier)
+}
+
+fn build_network_config(instance: &Instance) -> String {
+    let pool = config::current().ip_pool(instance.ip_pool.as_deref());
+    let eip = format!(
+        "{}/{}",
+        instance.external_ip.as_ref().unwrap(),
+        pool.prefix_length
+    );
+    match instance.image_family {
+        ImageFamily::CentOS => {
+            format!(
+                r#"network:
+  version: 1
+  config:
+  - type: physical
+    name: eth0
+    subnets:
+    - type: dhcp
+  - type: physical
+    name: eth1
+    subnets:
+    - type: static
+      address: {}
+"#,
+                eip
+            )
+        }
+        ImageFamily::Ubuntu => {
+            let mut eth0 = "eth0";
+            let mut eth1 = "eth1";
+            if instance.runtime == Runtime::Kvm {
+                eth0 = "enp5s0";
+                eth1 = "enp6s0";
+            }
+            format!(
+                r#"network:
+  version: 2
+  ethernets:
+    eth0:
+      match:
+        name: {}
+      dhcp4: true
+      dhcp6: false
+    eth1:
+      match:
+        name: {}
+      dhcp4: false
+      dhcp6: false
+      addresses:
+      - {}
+"#,
+                eth0, eth1, eip
+            )
+        }
     }
 }
 
+/// Returns the source of the instance in the requests creating it.
+fn build_image_source(instance: &Instance) -> Result<serde_json::Value> {
+    let alias = instance
+        .image_source
+        .as_ref()
+        .ok_or_else(|| anyhow!("no source of image {}", instance.image))?;
+    // Local images are built for a single arch.
+    if let Some(alias) = alias.strip_prefix(LOCAL_IMAGE_PREFIX) {
+        return Ok(serde_json::json!({
+            "type": "image",
+            "alias": alias
+        }));
+    }
+    Ok(serde_json::json!({
+        "type": "image",
+        "alias": format!("{}/{}", alias, instance.arch),
+        "protocol": "simplestreams",
+        "mode": "pull",
+        "server": LXD_IMAGE_SERVER_URL.as_str()
+    }))
+}
+
 fn get_instance_type(runtime: &Runtime) -> Result<String> {
     match runtime {
         Runtime::Lxc => Ok("container".to_owned()),
@@ -539,3 +1865,65 @@ fn parse_internal_ip(res: &serde_json::Value) -> Option<String> {
             None
         })
 }
+
+/// Returns the name of the LXD backup an object key is exported from, e.g. the backup name of
+/// `alice/dev/20220401120000.tar.gz` is `20220401120000`.
+fn get_backup_name(object_key: &str) -> String {
+    let file_name = object_key.rsplit('/').next().unwrap_or(object_key);
+    file_name
+        .strip_suffix(".tar.gz")
+        .unwrap_or(file_name)
+        .to_owned()
+}
+
+crate fn parse_operation(res: &serde_json::Value) -> Result<String> {
+    res.get("operation")
+        .and_then(|o| o.as_str())
+        .map(|o| o.to_owned())
+        .ok_or_else(|| anyhow!("no operation"))
+}
+
+crate enum OperationStatus {
+    // The operation is still running, with the latest progress if reported.
+    Running(Option<String>),
+    Success,
+    Failure(String),
+}
+
+crate fn parse_operation_status(res: &serde_json::Value) -> Result<OperationStatus> {
+    // The response is like:
+    // {
+    //   "metadata": {
+    //     "id": "6916c8a6-9b7d-4abd-90b3-aedfec7ec7da",
+    //     "status": "Running",
+    //     "status_code": 103,
+    //     "metadata": {
+    //       "create_instance_from_image_unpack_progress": "Unpack: 50%"
+    //     },
+    //     "err": ""
+    //   },
+    //   ...
+    // }
+    let metadata = res.get("metadata").ok_or_else(|| anyhow!("no metadata"))?;
+    let status = metadata
+        .get("status")
+        .and_then(|s| s.as_str())
+        .ok_or_else(|| anyhow!("no operation status"))?;
+    match status {
+        "Success" => Ok(OperationStatus::Success),
+        "Failure" | "Cancelled" => Ok(OperationStatus::Failure(
+            metadata
+                .get("err")
+                .and_then(|e| e.as_str())
+                .unwrap_or(status)
+                .to_owned(),
+        )),
+        _ => Ok(OperationStatus::Running(
+            metadata
+                .get("metadata")
+                .and_then(|m| m.as_object())
+                .and_then(|m| m.values().find_map(|v| v.as_str()))
+                .map(|p| p.to_owned()),
+        )),
+    }
+}
@@ -0,0 +1,420 @@
+//! Operator of micro-VMs backed by Cloud Hypervisor.
+//!
+//! Every node designated to run micro-VMs runs an agent which manages one Cloud Hypervisor
+//! process per micro-VM and exposes the Cloud Hypervisor API of each of them under
+//! `/api/v1/vms/{name}`. Besides the standard Cloud Hypervisor actions, the agent accepts:
+//!
+//! - `PUT /api/v1/vms/{name}`: prepares the root disk from `image` on `storage_pool`, builds the
+//!   cloud-init seed from `user_data` and `network_config`, attaches the VM to the external
+//!   network and creates the VM with the given Cloud Hypervisor `config`.
+//! - `DELETE /api/v1/vms/{name}`: deletes the VM together with its root disk.
+
+use anyhow::{anyhow, Result};
+use reqwest::{Client, Response, StatusCode};
+use tracing::{info, instrument, warn};
+
+use crate::config;
+use crate::env::MICROVM_AGENTS;
+use crate::instance_lock;
+use crate::lifecycle;
+use crate::metrics::{BACKEND_ERRORS, RECONCILE_DURATION};
+use crate::model::{Instance, InstanceStage, InstanceStatus, Runtime, User};
+use crate::operator_lxd::build_user_data;
+use crate::reconcile;
+use crate::shutdown;
+use crate::storage::Storage;
+
+pub struct Operator {
+    client: Client,
+    storage: Storage,
+}
+
+impl Operator {
+    pub fn new(client: Client, storage: Storage) -> Self {
+        Operator { client, storage }
+    }
+
+    pub async fn run(&self) {
+        let mut wakeup = reconcile::subscribe();
+        while !shutdown::is_triggered() {
+            self.run_once().await;
+            reconcile::sleep(&mut wakeup, config::current().reconcile_wait()).await;
+        }
+    }
+
+    #[instrument(skip_all)]
+    async fn run_once(&self) {
+        let _timer = RECONCILE_DURATION
+            .with_label_values(&["microvm"])
+            .start_timer();
+        let state = self.storage.snapshot().await;
+        for user in &state.users {
+            for instance in &user.instances {
+                // Stop between instances so that no instance is left half-synced.
+                if shutdown::is_triggered() {
+                    return;
+                }
+                if instance.runtime != Runtime::MicroVm {
+                    continue;
+                }
+                // Wait for the scheduler to allocate an IP address and schedule node and storage pool for this instance.
+                if instance.status == InstanceStatus::Creating
+                    && (instance.external_ip.is_none()
+                        || instance.node_name.is_none()
+                        || instance.storage_pool.is_none())
+                {
+                    continue;
+                }
+                self.storage
+                    .record_unhealthy_node(&state, &user.username, instance)
+                    .await;
+                let _lock = instance_lock::lock(&user.username, &instance.name).await;
+                self.sync_instance(user, instance).await;
+            }
+        }
+    }
+
+    #[instrument(skip_all, fields(username = %user.username, instance = %instance.name, runtime = %instance.runtime))]
+    async fn sync_instance(&self, user: &User, instance: &Instance) {
+        match instance.stage {
+            InstanceStage::Stopped => {
+                if instance.status != InstanceStatus::Stopped
+                    && instance.status != InstanceStatus::Missing
+                {
+                    if let Err(e) = self.stop_instance(user, instance).await {
+                        warn!(
+                            username = user.username.as_str(),
+                            instance = instance.name.as_str(),
+                            runtime = instance.runtime.to_string().as_str(),
+                            error = e.to_string().as_str(),
+                            "stopping instance encountered error"
+                        );
+                        BACKEND_ERRORS.with_label_values(&["microvm"]).inc();
+                    }
+                }
+            }
+            InstanceStage::Running => {
+                if instance.status != InstanceStatus::Running {
+                    if instance.status == InstanceStatus::Creating {
+                        if let Err(e) = self.create_instance(user, instance).await {
+                            warn!(
+                                username = user.username.as_str(),
+                                instance = instance.name.as_str(),
+                                runtime = instance.runtime.to_string().as_str(),
+                                error = e.to_string().as_str(),
+                                "creating instance encountered error"
+                            );
+                            BACKEND_ERRORS.with_label_values(&["microvm"]).inc();
+                        }
+                    } else if instance.status != InstanceStatus::Missing {
+                        if let Err(e) = self.start_instance(user, instance).await {
+                            warn!(
+                                username = user.username.as_str(),
+                                instance = instance.name.as_str(),
+                                runtime = instance.runtime.to_string().as_str(),
+                                error = e.to_string().as_str(),
+                                "starting instance encountered error"
+                            );
+                            BACKEND_ERRORS.with_label_values(&["microvm"]).inc();
+                        }
+                    }
+                }
+            }
+            InstanceStage::Deleted => {
+                if instance.status != InstanceStatus::Deleting {
+                    if let Err(e) = self.stop_instance(user, instance).await {
+                        warn!(
+                            username = user.username.as_str(),
+                            instance = instance.name.as_str(),
+                            runtime = instance.runtime.to_string().as_str(),
+                            error = e.to_string().as_str(),
+                            "stopping instance encountered error"
+                        );
+                        BACKEND_ERRORS.with_label_values(&["microvm"]).inc();
+                    }
+                } else if let Err(e) = self.delete_instance(user, instance).await {
+                    warn!(
+                        username = user.username.as_str(),
+                        instance = instance.name.as_str(),
+                        runtime = instance.runtime.to_string().as_str(),
+                        error = e.to_string().as_str(),
+                        "deleting instance encountered error"
+                    );
+                    BACKEND_ERRORS.with_label_values(&["microvm"]).inc();
+                }
+            }
+        }
+        if let Err(e) = self.update_instance_status(user, instance).await {
+            warn!(
+                username = user.username.as_str(),
+                instance = instance.name.as_str(),
+                runtime = instance.runtime.to_string().as_str(),
+                error = e.to_string().as_str(),
+                "updating instance status encountered error"
+            );
+            BACKEND_ERRORS.with_label_values(&["microvm"]).inc();
+        }
+    }
+
+    async fn create_instance(&self, user: &User, instance: &Instance) -> Result<()> {
+        info!(
+            username = user.username.as_str(),
+            instance = instance.name.as_str(),
+            runtime = instance.runtime.to_string().as_str(),
+            "creating instance"
+        );
+        let url = get_vm_url(user, instance)?;
+        let res = self
+            .client
+            .put(url)
+            .json(&serde_json::json!({
+                "config": build_vm_config(instance),
+                "image": get_image_name(instance)?,
+                "storage_pool": instance.storage_pool.as_ref().unwrap(),
+                "disk_size": instance.disk_size,
+                "user_data": build_user_data(instance),
+                "network_config": build_network_config(instance),
+            }))
+            .send()
+            .await?;
+        check_error(res).await
+    }
+
+    async fn delete_instance(&self, user: &User, instance: &Instance) -> Result<()> {
+        info!(
+            username = user.username.as_str(),
+            instance = instance.name.as_str(),
+            runtime = instance.runtime.to_string().as_str(),
+            "deleting instance"
+        );
+        let url = get_vm_url(user, instance)?;
+        let res = self.client.delete(url).send().await?;
+        if res.status() == StatusCode::NOT_FOUND {
+            return Ok(());
+        }
+        check_error(res).await
+    }
+
+    async fn start_instance(&self, user: &User, instance: &Instance) -> Result<()> {
+        info!(
+            username = user.username.as_str(),
+            instance = instance.name.as_str(),
+            runtime = instance.runtime.to_string().as_str(),
+            "starting instance"
+        );
+
+        self.sync_instance_limits(user, instance).await?;
+
+        let url = format!("{}/vm.boot", get_vm_url(user, instance)?);
+        let res = self.client.put(url).send().await?;
+        check_error(res).await
+    }
+
+    async fn sync_instance_limits(&self, user: &User, instance: &Instance) -> Result<()> {
+        let url = get_vm_url(user, instance)?;
+        let res = self
+            .client
+            .get(format!("{}/vm.info", url))
+            .send()
+            .await?
+            .error_for_status()?
+            .json::<serde_json::Value>()
+            .await?;
+
+        if parse_instance_status(&res).unwrap_or_default() == "Running" {
+            return Ok(());
+        }
+
+        let vcpus = res
+            .pointer("/config/cpus/boot_vcpus")
+            .and_then(|v| v.as_u64())
+            .unwrap_or_default();
+        let ram = res
+            .pointer("/config/memory/size")
+            .and_then(|v| v.as_u64())
+            .unwrap_or_default();
+        if vcpus != instance.cpu as u64 || ram != (instance.memory as u64) << 30 {
+            info!(
+                username = user.username.as_str(),
+                instance = instance.name.as_str(),
+                runtime = instance.runtime.to_string().as_str(),
+                cpu_limit = vcpus,
+                memory_limit = ram >> 30,
+                new_cpu_limit = instance.cpu,
+                new_memory_limit = instance.memory,
+                "instance limits are chagned, updating"
+            );
+            let res = self
+                .client
+                .put(format!("{}/vm.resize", url))
+                .json(&serde_json::json!({
+                    "desired_vcpus": instance.cpu,
+                    "desired_ram": (instance.memory as u64) << 30,
+                }))
+                .send()
+                .await?;
+            check_error(res).await?;
+        }
+        Ok(())
+    }
+
+    async fn stop_instance(&self, user: &User, instance: &Instance) -> Result<()> {
+        info!(
+            username = user.username.as_str(),
+            instance = instance.name.as_str(),
+            runtime = instance.runtime.to_string().as_str(),
+            "stopping instance"
+        );
+        let url = format!("{}/vm.shutdown", get_vm_url(user, instance)?);
+        let res = self.client.put(url).send().await?;
+        check_error(res).await
+    }
+
+    async fn update_instance_status(&self, user: &User, instance: &Instance) -> Result<()> {
+        let url = format!("{}/vm.info", get_vm_url(user, instance)?);
+        let res = self.client.get(url).send().await?;
+        if res.status() == StatusCode::NOT_FOUND {
+            if instance.status == InstanceStatus::Creating {
+                return Ok(());
+            }
+            return self
+                .storage
+                .read_write_deferred(|state| {
+                    if let Some(i) = state
+                        .find_mut_user(&user.username)
+                        .and_then(|u| u.find_mut_instance(&instance.name))
+                    {
+                        if i.stage == InstanceStage::Deleted {
+                            state
+                                .find_mut_user(&user.username)
+                                .unwrap()
+                                .remove_instance(&instance.name);
+                        } else {
+                            lifecycle::observe(i, InstanceStatus::Missing);
+                            warn!(
+                                username = user.username.as_str(),
+                                instance = instance.name.as_str(),
+                                runtime = instance.runtime.to_string().as_str(),
+                                "instance is missing unexpectedly"
+                            );
+                        }
+                    }
+                    true
+                })
+                .await
+                .map_err(|e| anyhow!(e));
+        }
+        let res: serde_json::Value = res.error_for_status()?.json().await?;
+
+        let status = parse_instance_status(&res).unwrap_or_default();
+        self.storage
+            .read_write_deferred(|state| {
+                if let Some(i) = state
+                    .find_mut_user(&user.username)
+                    .and_then(|u| u.find_mut_instance(&instance.name))
+                {
+                    match i.stage {
+                        InstanceStage::Stopped => {
+                            if status == "Stopped" {
+                                lifecycle::observe(i, InstanceStatus::Stopped);
+                            }
+                        }
+                        InstanceStage::Running => {
+                            lifecycle::observe_running(i, &status);
+                        }
+                        InstanceStage::Deleted => {
+                            if status == "Stopped" {
+                                lifecycle::observe(i, InstanceStatus::Deleting);
+                            }
+                        }
+                    }
+                }
+                true
+            })
+            .await
+            .map_err(|e| anyhow!(e))
+    }
+}
+
+fn get_vm_url(user: &User, instance: &Instance) -> Result<String> {
+    let node_name = instance
+        .node_name
+        .as_ref()
+        .ok_or_else(|| anyhow!("instance is not scheduled"))?;
+    let agent = MICROVM_AGENTS
+        .get(node_name)
+        .ok_or_else(|| anyhow!("no micro-VM agent on node {}", node_name))?;
+    Ok(format!(
+        "{}/api/v1/vms/{}",
+        agent,
+        instance.backend_name(&user.username)
+    ))
+}
+
+fn get_image_name(instance: &Instance) -> Result<String> {
+    instance
+        .image_source
+        .clone()
+        .ok_or_else(|| anyhow!("no source of image {}", instance.image))
+}
+
+fn build_vm_config(instance: &Instance) -> serde_json::Value {
+    serde_json::json!({
+        "cpus": {
+            "boot_vcpus": instance.cpu,
+            "max_vcpus": instance.cpu
+        },
+        "memory": {
+            "size": (instance.memory as u64) << 30
+        },
+        "serial": {
+            "mode": "Tty"
+        },
+        "console": {
+            "mode": "Off"
+        }
+    })
+}
+
+fn build_network_config(instance: &Instance) -> String {
+    let pool = config::current().ip_pool(instance.ip_pool.as_deref());
+    let mut network_config = format!(
+        r#"network:
+  version: 2
+  ethernets:
+    eth0:
+      match:
+        driver: virtio_net
+      dhcp4: false
+      dhcp6: false
+      addresses:
+      - {}/{}
+"#,
+        instance.external_ip.as_ref().unwrap(),
+        pool.prefix_length
+    );
+    if !pool.gateway.is_empty() {
+        network_config.push_str(&format!("      gateway4: {}\n", pool.gateway));
+    }
+    network_config
+}
+
+async fn check_error(res: Response) -> Result<()> {
+    let status = res.status();
+    if status.is_success() {
+        return Ok(());
+    }
+    Err(anyhow!(
+        "{}: {}",
+        status,
+        res.text().await.unwrap_or_default()
+    ))
+}
+
+fn parse_instance_status(res: &serde_json::Value) -> Option<String> {
+    // Cloud Hypervisor reports the state as one of Created, Running, Shutdown and Paused.
+    res.get("state").and_then(|s| s.as_str()).map(|s| match s {
+        "Created" | "Shutdown" => "Stopped".to_owned(),
+        s => s.to_owned(),
+    })
+}
@@ -0,0 +1,471 @@
+//! Operator of system containers backed by Podman.
+//!
+//! Every node designated to run Podman containers serves the Podman REST API, which is an
+//! unauthenticated root API of the node and must never listen on TCP. Keep
+//! `podman system service` on its unix socket and forward a local port of the tispace host to it
+//! over SSH, e.g. `ssh -N -L 127.0.0.1:18080:/run/podman/podman.sock root@node-1` for the
+//! endpoint http://127.0.0.1:18080. The containers boot the init of their images and share the
+//! kernel of the node, so they are meant for cheap shell boxes rather than untrusted workloads.
+//! They are attached to `oci_network` with their external IPs, and their images are expected to
+//! set the root password and the authorized keys of root from the `PASSWORD` and
+//! `SSH_AUTHORIZED_KEYS` environment variables at boot, like the rootfs images of Kubernetes.
+//! `PASSWORD` is unset once the password is hashed, in which case it must be left as it is.
+
+use anyhow::{anyhow, Result};
+use reqwest::{Client, Response, StatusCode};
+use tracing::{info, instrument, warn};
+
+use crate::config;
+use crate::env::OCI_AGENTS;
+use crate::instance_lock;
+use crate::lifecycle;
+use crate::metrics::{BACKEND_ERRORS, RECONCILE_DURATION};
+use crate::model::{Instance, InstanceStage, InstanceStatus, Runtime, User};
+use crate::reconcile;
+use crate::shutdown;
+use crate::storage::Storage;
+
+// The version of the libpod API, served by Podman 4 and later.
+const API_VERSION: &str = "v4.0.0";
+
+// Microseconds of the CPU quota period of the containers.
+const CPU_PERIOD: u64 = 100_000;
+
+pub struct Operator {
+    client: Client,
+    storage: Storage,
+}
+
+impl Operator {
+    pub fn new(client: Client, storage: Storage) -> Self {
+        Operator { client, storage }
+    }
+
+    pub async fn run(&self) {
+        let mut wakeup = reconcile::subscribe();
+        while !shutdown::is_triggered() {
+            self.run_once().await;
+            reconcile::sleep(&mut wakeup, config::current().reconcile_wait()).await;
+        }
+    }
+
+    #[instrument(skip_all)]
+    async fn run_once(&self) {
+        let _timer = RECONCILE_DURATION.with_label_values(&["oci"]).start_timer();
+        let state = self.storage.snapshot().await;
+        for user in &state.users {
+            for instance in &user.instances {
+                // Stop between instances so that no instance is left half-synced.
+                if shutdown::is_triggered() {
+                    return;
+                }
+                if instance.runtime != Runtime::Oci {
+                    continue;
+                }
+                // Wait for the scheduler to allocate an IP address and a node to the instance.
+                if instance.status == InstanceStatus::Creating
+                    && (instance.external_ip.is_none() || instance.node_name.is_none())
+                {
+                    continue;
+                }
+                self.storage
+                    .record_unhealthy_node(&state, &user.username, instance)
+                    .await;
+                let _lock = instance_lock::lock(&user.username, &instance.name).await;
+                self.sync_instance(user, instance).await;
+            }
+        }
+    }
+
+    #[instrument(skip_all, fields(username = %user.username, instance = %instance.name, runtime = %instance.runtime))]
+    async fn sync_instance(&self, user: &User, instance: &Instance) {
+        match instance.stage {
+            InstanceStage::Stopped => {
+                if instance.status != InstanceStatus::Stopped
+                    && instance.status != InstanceStatus::Missing
+                {
+                    if let Err(e) = self.stop_instance(user, instance).await {
+                        warn!(
+                            username = user.username.as_str(),
+                            instance = instance.name.as_str(),
+                            runtime = instance.runtime.to_string().as_str(),
+                            error = e.to_string().as_str(),
+                            "stopping instance encountered error"
+                        );
+                        BACKEND_ERRORS.with_label_values(&["oci"]).inc();
+                    }
+                }
+            }
+            InstanceStage::Running => {
+                if instance.status != InstanceStatus::Running {
+                    if instance.status == InstanceStatus::Creating {
+                        if let Err(e) = self.create_instance(user, instance).await {
+                            warn!(
+                                username = user.username.as_str(),
+                                instance = instance.name.as_str(),
+                                runtime = instance.runtime.to_string().as_str(),
+                                error = e.to_string().as_str(),
+                                "creating instance encountered error"
+                            );
+                            BACKEND_ERRORS.with_label_values(&["oci"]).inc();
+                        }
+                    } else if instance.status != InstanceStatus::Missing {
+                        if let Err(e) = self.start_instance(user, instance).await {
+                            warn!(
+                                username = user.username.as_str(),
+                                instance = instance.name.as_str(),
+                                runtime = instance.runtime.to_string().as_str(),
+                                error = e.to_string().as_str(),
+                                "starting instance encountered error"
+                            );
+                            BACKEND_ERRORS.with_label_values(&["oci"]).inc();
+                        }
+                    }
+                }
+            }
+            InstanceStage::Deleted => {
+                if instance.status != InstanceStatus::Deleting {
+                    if let Err(e) = self.stop_instance(user, instance).await {
+                        warn!(
+                            username = user.username.as_str(),
+                            instance = instance.name.as_str(),
+                            runtime = instance.runtime.to_string().as_str(),
+                            error = e.to_string().as_str(),
+                            "stopping instance encountered error"
+                        );
+                        BACKEND_ERRORS.with_label_values(&["oci"]).inc();
+                    }
+                } else if let Err(e) = self.delete_instance(user, instance).await {
+                    warn!(
+                        username = user.username.as_str(),
+                        instance = instance.name.as_str(),
+                        runtime = instance.runtime.to_string().as_str(),
+                        error = e.to_string().as_str(),
+                        "deleting instance encountered error"
+                    );
+                    BACKEND_ERRORS.with_label_values(&["oci"]).inc();
+                }
+            }
+        }
+        if let Err(e) = self.update_instance_status(user, instance).await {
+            warn!(
+                username = user.username.as_str(),
+                instance = instance.name.as_str(),
+                runtime = instance.runtime.to_string().as_str(),
+                error = e.to_string().as_str(),
+                "updating instance status encountered error"
+            );
+            BACKEND_ERRORS.with_label_values(&["oci"]).inc();
+        }
+    }
+
+    async fn create_instance(&self, user: &User, instance: &Instance) -> Result<()> {
+        info!(
+            username = user.username.as_str(),
+            instance = instance.name.as_str(),
+            runtime = instance.runtime.to_string().as_str(),
+            "creating instance"
+        );
+        let api = get_api_url(instance)?;
+        let image = get_image_name(instance)?;
+        // The body streams the progress of the pull, which is done once it's read to the end.
+        let res = self
+            .client
+            .post(format!("{}/images/pull", api))
+            .query(&[("reference", image.as_str()), ("quiet", "true")])
+            .send()
+            .await?;
+        let status = res.status();
+        let body = res.text().await?;
+        if !status.is_success() || body.contains("\"error\"") {
+            return Err(anyhow!("pull image {}: {}: {}", image, status, body));
+        }
+
+        let res = self
+            .client
+            .post(format!("{}/containers/create", api))
+            .json(&build_container_spec(user, instance, &image))
+            .send()
+            .await?;
+        check_error(res).await
+    }
+
+    async fn delete_instance(&self, user: &User, instance: &Instance) -> Result<()> {
+        info!(
+            username = user.username.as_str(),
+            instance = instance.name.as_str(),
+            runtime = instance.runtime.to_string().as_str(),
+            "deleting instance"
+        );
+        let url = get_container_url(user, instance)?;
+        let res = self
+            .client
+            .delete(url)
+            .query(&[("force", "true"), ("v", "true")])
+            .send()
+            .await?;
+        if res.status() == StatusCode::NOT_FOUND {
+            return Ok(());
+        }
+        check_error(res).await
+    }
+
+    async fn start_instance(&self, user: &User, instance: &Instance) -> Result<()> {
+        info!(
+            username = user.username.as_str(),
+            instance = instance.name.as_str(),
+            runtime = instance.runtime.to_string().as_str(),
+            "starting instance"
+        );
+
+        self.sync_instance_limits(user, instance).await?;
+
+        let url = format!("{}/start", get_container_url(user, instance)?);
+        let res = self.client.post(url).send().await?;
+        // Podman answers 304 if the container is already running.
+        if res.status() == StatusCode::NOT_MODIFIED {
+            return Ok(());
+        }
+        check_error(res).await
+    }
+
+    async fn sync_instance_limits(&self, user: &User, instance: &Instance) -> Result<()> {
+        let url = get_container_url(user, instance)?;
+        let res = self
+            .client
+            .get(format!("{}/json", url))
+            .send()
+            .await?
+            .error_for_status()?
+            .json::<serde_json::Value>()
+            .await?;
+
+        if parse_instance_status(&res).unwrap_or_default() == "Running" {
+            return Ok(());
+        }
+
+        let quota = res
+            .pointer("/HostConfig/CpuQuota")
+            .and_then(|v| v.as_u64())
+            .unwrap_or_default();
+        let memory = res
+            .pointer("/HostConfig/Memory")
+            .and_then(|v| v.as_u64())
+            .unwrap_or_default();
+        if quota != instance.cpu as u64 * CPU_PERIOD || memory != (instance.memory as u64) << 30 {
+            info!(
+                username = user.username.as_str(),
+                instance = instance.name.as_str(),
+                runtime = instance.runtime.to_string().as_str(),
+                cpu_limit = quota / CPU_PERIOD,
+                memory_limit = memory >> 30,
+                new_cpu_limit = instance.cpu,
+                new_memory_limit = instance.memory,
+                "instance limits are changed, updating"
+            );
+            let res = self
+                .client
+                .post(format!("{}/update", url))
+                .json(&build_resource_limits(instance))
+                .send()
+                .await?;
+            check_error(res).await?;
+        }
+        Ok(())
+    }
+
+    async fn stop_instance(&self, user: &User, instance: &Instance) -> Result<()> {
+        info!(
+            username = user.username.as_str(),
+            instance = instance.name.as_str(),
+            runtime = instance.runtime.to_string().as_str(),
+            "stopping instance"
+        );
+        let url = format!("{}/stop", get_container_url(user, instance)?);
+        let res = self.client.post(url).send().await?;
+        // Podman answers 304 if the container is already stopped.
+        if res.status() == StatusCode::NOT_MODIFIED {
+            return Ok(());
+        }
+        check_error(res).await
+    }
+
+    async fn update_instance_status(&self, user: &User, instance: &Instance) -> Result<()> {
+        let url = format!("{}/json", get_container_url(user, instance)?);
+        let res = self.client.get(url).send().await?;
+        if res.status() == StatusCode::NOT_FOUND {
+            if instance.status == InstanceStatus::Creating {
+                return Ok(());
+            }
+            return self
+                .storage
+                .read_write_deferred(|state| {
+                    if let Some(i) = state
+                        .find_mut_user(&user.username)
+                        .and_then(|u| u.find_mut_instance(&instance.name))
+                    {
+                        if i.stage == InstanceStage::Deleted {
+                            state
+                                .find_mut_user(&user.username)
+                                .unwrap()
+                                .remove_instance(&instance.name);
+                        } else {
+                            lifecycle::observe(i, InstanceStatus::Missing);
+                            warn!(
+                                username = user.username.as_str(),
+                                instance = instance.name.as_str(),
+                                runtime = instance.runtime.to_string().as_str(),
+                                "instance is missing unexpectedly"
+                            );
+                        }
+                    }
+                    true
+                })
+                .await
+                .map_err(|e| anyhow!(e));
+        }
+        let res: serde_json::Value = res.error_for_status()?.json().await?;
+
+        let status = parse_instance_status(&res).unwrap_or_default();
+        self.storage
+            .read_write_deferred(|state| {
+                if let Some(i) = state
+                    .find_mut_user(&user.username)
+                    .and_then(|u| u.find_mut_instance(&instance.name))
+                {
+                    match i.stage {
+                        InstanceStage::Stopped => {
+                            if status == "Stopped" {
+                                lifecycle::observe(i, InstanceStatus::Stopped);
+                            }
+                        }
+                        InstanceStage::Running => {
+                            lifecycle::observe_running(i, &status);
+                        }
+                        InstanceStage::Deleted => {
+                            if status == "Stopped" {
+                                lifecycle::observe(i, InstanceStatus::Deleting);
+                            }
+                        }
+                    }
+                }
+                true
+            })
+            .await
+            .map_err(|e| anyhow!(e))
+    }
+}
+
+/// Returns the URL of the libpod API on the node of the instance.
+fn get_api_url(instance: &Instance) -> Result<String> {
+    let node_name = instance
+        .node_name
+        .as_ref()
+        .ok_or_else(|| anyhow!("instance is not scheduled"))?;
+    let agent = OCI_AGENTS
+        .get(node_name)
+        .ok_or_else(|| anyhow!("no podman api on node {}", node_name))?;
+    Ok(format!("{}/{}/libpod", agent, API_VERSION))
+}
+
+fn get_container_url(user: &User, instance: &Instance) -> Result<String> {
+    Ok(format!(
+        "{}/containers/{}",
+        get_api_url(instance)?,
+        instance.backend_name(&user.username)
+    ))
+}
+
+fn get_image_name(instance: &Instance) -> Result<String> {
+    instance
+        .image_source
+        .clone()
+        .ok_or_else(|| anyhow!("no source of image {}", instance.image))
+}
+
+fn build_resource_limits(instance: &Instance) -> serde_json::Value {
+    serde_json::json!({
+        "cpu": {
+            "quota": instance.cpu as u64 * CPU_PERIOD,
+            "period": CPU_PERIOD
+        },
+        "memory": {
+            "limit": (instance.memory as u64) << 30
+        }
+    })
+}
+
+/// Returns the spec of the container of the instance, see the `SpecGenerator` of libpod.
+fn build_container_spec(user: &User, instance: &Instance, image: &str) -> serde_json::Value {
+    let name = instance.backend_name(&user.username);
+    let mounts: Vec<serde_json::Value> = instance
+        .mounts
+        .iter()
+        .filter(|m| m.nfs_export().is_none())
+        .map(|m| {
+            serde_json::json!({
+                "type": "bind",
+                "source": m.source,
+                "destination": m.path,
+                "options": if m.read_only { vec!["ro"] } else { vec!["rw"] }
+            })
+        })
+        .collect();
+    let mut networks = serde_json::Map::new();
+    networks.insert(
+        config::current().oci_network.clone(),
+        serde_json::json!({ "static_ips": [instance.external_ip.as_ref().unwrap()] }),
+    );
+    let mut env = serde_json::json!({
+        "SSH_AUTHORIZED_KEYS": instance.authorized_keys().join("\n")
+    });
+    // A hashed password is left out, the image keeps the password it booted with before.
+    if !instance.password_hashed {
+        env["PASSWORD"] = instance.password.as_str().into();
+    }
+    serde_json::json!({
+        "name": name,
+        "hostname": instance.name,
+        "image": image,
+        "command": ["/sbin/init"],
+        "systemd": "always",
+        "env": env,
+        "labels": {
+            "tispace/instance": name
+        },
+        "netns": {
+            "nsmode": "bridge"
+        },
+        "Networks": networks,
+        "resource_limits": build_resource_limits(instance),
+        // Only enforced on overlay over XFS with project quotas.
+        "storage_opts": {
+            "size": format!("{}G", instance.disk_size)
+        },
+        "mounts": mounts
+    })
+}
+
+async fn check_error(res: Response) -> Result<()> {
+    let status = res.status();
+    if status.is_success() {
+        return Ok(());
+    }
+    Err(anyhow!(
+        "{}: {}",
+        status,
+        res.text().await.unwrap_or_default()
+    ))
+}
+
+fn parse_instance_status(res: &serde_json::Value) -> Option<String> {
+    // Podman reports the state as one of created, configured, running, paused, stopping, exited,
+    // stopped and removing.
+    res.pointer("/State/Status")
+        .and_then(|s| s.as_str())
+        .map(|s| match s {
+            "created" | "configured" | "exited" | "stopped" => "Stopped".to_owned(),
+            "running" => "Running".to_owned(),
+            s => s.to_owned(),
+        })
+}
@@ -0,0 +1,99 @@
+use std::collections::HashSet;
+
+use chrono::Utc;
+use tokio::time::Duration;
+use tracing::{info, instrument, warn};
+
+use crate::lifecycle::{self, Action};
+use crate::model::{InstanceStage, InstanceStatus};
+use crate::shutdown;
+use crate::storage::Storage;
+
+/// Starts and stops instances according to their power schedules.
+pub struct PowerScheduler {
+    storage: Storage,
+}
+
+impl PowerScheduler {
+    pub fn new(storage: Storage) -> Self {
+        PowerScheduler { storage }
+    }
+
+    pub async fn run(&self) {
+        while !shutdown::is_triggered() {
+            self.run_once().await;
+            shutdown::sleep(Duration::from_secs(30)).await;
+        }
+    }
+
+    #[instrument(skip_all)]
+    async fn run_once(&self) {
+        let now = Utc::now();
+        let res = self
+            .storage
+            .read_write(|state| {
+                let mut changed = false;
+                // Stopped instances which no longer fit on their nodes, as the CPU and memory
+                // they used were taken by others meanwhile.
+                let crowded_out: HashSet<String> = state
+                    .users
+                    .iter()
+                    .flat_map(|u| &u.instances)
+                    .filter(|i| i.stage == InstanceStage::Stopped && !state.has_room_to_start(i))
+                    .map(|i| i.id.clone())
+                    .collect();
+                for u in &mut state.users {
+                    for i in &mut u.instances {
+                        if i.stage == InstanceStage::Deleted {
+                            continue;
+                        }
+                        let schedule = match &mut i.schedule {
+                            Some(schedule) => schedule,
+                            None => continue,
+                        };
+                        let (stage, due) = match schedule.due_action(now) {
+                            Some(action) => action,
+                            None => continue,
+                        };
+                        schedule.last_due = due;
+                        changed = true;
+
+                        let (action, verb) = match stage {
+                            InstanceStage::Running => (Action::Start, "start"),
+                            _ => (Action::Stop, "stop"),
+                        };
+                        if schedule.skip_until.map_or(false, |t| due < t) {
+                            i.add_event(format!("scheduled {} skipped", verb));
+                            continue;
+                        }
+                        // Leave instances being provisioned alone, they are started once ready.
+                        if i.stage == stage || i.status == InstanceStatus::Creating {
+                            continue;
+                        }
+                        if action == Action::Start && crowded_out.contains(&i.id) {
+                            i.add_event(format!("scheduled {} failed: node is full", verb));
+                            continue;
+                        }
+                        info!(
+                            username = u.username.as_str(),
+                            instance = i.name.as_str(),
+                            stage = stage.to_string().as_str(),
+                            "applying power schedule"
+                        );
+                        if lifecycle::apply(i, action).is_err() {
+                            continue;
+                        }
+                        i.add_event(format!("scheduled {} applied", verb));
+                    }
+                }
+                changed
+            })
+            .await;
+        if let Err(e) = res {
+            warn!(
+                error = e.to_string().as_str(),
+                "apply power schedules encountered error"
+            );
+        }
+    }
+}
@@ -0,0 +1,309 @@
+//! Pulls the images of the catalog onto the nodes ahead of the first instances created from
+//! them, so that an instance created on a fresh node doesn't wait for its image to be
+//! downloaded.
+//!
+//! On Kubernetes, a DaemonSet per image and arch pulls the rootfs image onto the kata and runc
+//! nodes of the arch. On LXD, the simplestreams images are downloaded into the image store of the
+//! cluster and kept up to date by LXD, from where members copy them over the cluster network
+//! instead of the image server. Set `cluster.images_minimal_replica` to -1 to have LXD copy them
+//! to all members right away. Only the images managed by admins are pulled.
+
+use std::collections::{BTreeSet, HashMap};
+use std::sync::Mutex;
+
+use anyhow::Result;
+use k8s_openapi::api::apps::v1::DaemonSet;
+use kube::api::{DeleteParams, ListParams, Patch, PatchParams};
+use kube::{Api, Client as KubeClient};
+use reqwest::Client as ReqwestClient;
+use tokio::time::Duration;
+use tracing::{info, instrument, warn};
+
+use crate::env::{IMAGE_PREPULL_INTERVAL, LXD_IMAGE_SERVER_URL, LXD_PROJECT};
+use crate::metrics::BACKEND_ERRORS;
+use crate::model::{Arch, Runtime, State, LOCAL_IMAGE_PREFIX};
+use crate::operator_k8s::{
+    build_prepull_daemon_set, prepull_daemon_set_name, rootfs_image_url, NAMESPACE,
+};
+use crate::operator_lxd::{
+    api_url, check_error, parse_operation, parse_operation_status, server_url, OperationStatus,
+};
+use crate::shutdown;
+use crate::storage::Storage;
+
+// A simplestreams image of LXD, identified by its alias like ubuntu/22.04/amd64 and its type.
+type LxdImage = (String, &'static str);
+
+pub struct ImagePrePuller {
+    storage: Storage,
+    kube_client: Option<KubeClient>,
+    lxd_client: Option<ReqwestClient>,
+    // Operations of the LXD images being downloaded.
+    downloads: Mutex<HashMap<LxdImage, String>>,
+}
+
+impl ImagePrePuller {
+    pub fn new(
+        storage: Storage,
+        kube_client: Option<KubeClient>,
+        lxd_client: Option<ReqwestClient>,
+    ) -> Self {
+        ImagePrePuller {
+            storage,
+            kube_client,
+            lxd_client,
+            downloads: Mutex::new(HashMap::new()),
+        }
+    }
+
+    pub async fn run(&self) {
+        while !shutdown::is_triggered() {
+            self.run_once().await;
+            shutdown::sleep(Duration::from_secs(*IMAGE_PREPULL_INTERVAL * 60)).await;
+        }
+    }
+
+    #[instrument(skip_all)]
+    async fn run_once(&self) {
+        let state = self.storage.snapshot().await;
+        if let Some(kube_client) = &self.kube_client {
+            if let Err(e) = sync_daemon_sets(kube_client, &state).await {
+                warn!(
+                    error = e.to_string().as_str(),
+                    "pre-pulling rootfs images encountered error"
+                );
+                BACKEND_ERRORS.with_label_values(&["k8s"]).inc();
+            }
+        }
+        if let Some(lxd_client) = &self.lxd_client {
+            if let Err(e) = self.download_lxd_images(lxd_client, &state).await {
+                warn!(
+                    error = e.to_string().as_str(),
+                    "pre-downloading lxd images encountered error"
+                );
+                BACKEND_ERRORS.with_label_values(&["lxd"]).inc();
+            }
+        }
+    }
+
+    /// Starts downloading the LXD images which are neither in the store nor being downloaded.
+    async fn download_lxd_images(&self, lxd_client: &ReqwestClient, state: &State) -> Result<()> {
+        let wanted = lxd_images(state);
+        let stored = list_lxd_images(lxd_client).await?;
+        let pending: Vec<(LxdImage, String)> = {
+            let mut downloads = self.downloads.lock().unwrap();
+            downloads.retain(|image, _| wanted.contains(image) && !stored.contains(image));
+            downloads
+                .iter()
+                .map(|(image, op)| (image.clone(), op.clone()))
+                .collect()
+        };
+        let mut downloading = BTreeSet::new();
+        for (image, operation) in pending {
+            let status = get_operation_status(lxd_client, &operation)
+                .await
+                .map_err(|e| e.to_string());
+            match status {
+                Ok(OperationStatus::Running(_)) => {
+                    downloading.insert(image);
+                }
+                Ok(OperationStatus::Success) => {}
+                Ok(OperationStatus::Failure(error)) | Err(error) => {
+                    warn!(
+                        alias = image.0.as_str(),
+                        type_ = image.1,
+                        error = error.as_str(),
+                        "downloading lxd image failed"
+                    );
+                    BACKEND_ERRORS.with_label_values(&["lxd"]).inc();
+                }
+            }
+        }
+
+        // Images are downloaded independently, so that one failing doesn't hold back the others.
+        for image in wanted {
+            if stored.contains(&image) || downloading.contains(&image) {
+                continue;
+            }
+            info!(
+                alias = image.0.as_str(),
+                type_ = image.1,
+                "downloading lxd image"
+            );
+            match download_lxd_image(lxd_client, &image).await {
+                Ok(operation) => {
+                    self.downloads.lock().unwrap().insert(image, operation);
+                }
+                Err(e) => {
+                    warn!(
+                        alias = image.0.as_str(),
+                        type_ = image.1,
+                        error = e.to_string().as_str(),
+                        "downloading lxd image failed"
+                    );
+                    BACKEND_ERRORS.with_label_values(&["lxd"]).inc();
+                }
+            }
+        }
+        Ok(())
+    }
+}
+
+/// Starts downloading the image into the store, returning the operation of the download.
+async fn download_lxd_image(lxd_client: &ReqwestClient, image: &LxdImage) -> Result<String> {
+    let url = api_url(&format!("/images?project={}", LXD_PROJECT.as_str()));
+    let res: serde_json::Value = lxd_client
+        .post(url)
+        .json(&serde_json::json!({
+            "auto_update": true,
+            "source": {
+                "type": "image",
+                "mode": "pull",
+                "protocol": "simplestreams",
+                "server": LXD_IMAGE_SERVER_URL.as_str(),
+                "alias": image.0,
+                "image_type": image.1
+            }
+        }))
+        .send()
+        .await?
+        .json()
+        .await?;
+    check_error(&res)?;
+    parse_operation(&res)
+}
+
+async fn get_operation_status(
+    lxd_client: &ReqwestClient,
+    operation: &str,
+) -> Result<OperationStatus> {
+    let url = format!("{}{}", server_url(), operation);
+    let res: serde_json::Value = lxd_client.get(url).send().await?.json().await?;
+    check_error(&res)?;
+    parse_operation_status(&res)
+}
+
+/// Returns the rootfs images of the catalog and the kata and runc nodes of each arch having
+/// both. Nodes which don't report their arch are left out.
+fn rootfs_images(state: &State) -> Vec<(Arch, Vec<String>, Vec<String>)> {
+    let mut images = Vec::new();
+    for arch in [Arch::Amd64, Arch::Arm64] {
+        let nodes: Vec<String> = state
+            .nodes
+            .iter()
+            .filter(|n| n.arch.as_ref() == Some(&arch))
+            .filter(|n| n.runtimes.contains(&Runtime::Kata) || n.runtimes.contains(&Runtime::Runc))
+            .map(|n| n.name.clone())
+            .collect();
+        let urls: BTreeSet<String> = state
+            .images
+            .iter()
+            .filter(|i| i.enabled && i.owner.is_none() && i.arches.contains(&arch))
+            .flat_map(|i| {
+                [Runtime::Kata, Runtime::Runc]
+                    .iter()
+                    .filter_map(move |r| i.source(r))
+            })
+            .map(|r| rootfs_image_url(r, &arch))
+            .collect();
+        if !nodes.is_empty() && !urls.is_empty() {
+            images.push((arch, urls.into_iter().collect(), nodes));
+        }
+    }
+    images
+}
+
+/// Applies the DaemonSet of each image of each arch with nodes, and deletes the others.
+async fn sync_daemon_sets(kube_client: &KubeClient, state: &State) -> Result<()> {
+    let api: Api<DaemonSet> = Api::namespaced(kube_client.clone(), NAMESPACE);
+    let mut expected = BTreeSet::new();
+    for (arch, urls, nodes) in rootfs_images(state) {
+        for url in urls {
+            let name = prepull_daemon_set_name(&arch, &url);
+            let daemon_set = build_prepull_daemon_set(&arch, &nodes, &url);
+            // The daemon set is kept if it fails to apply, as it may still pull the image.
+            expected.insert(name.clone());
+            if let Err(e) = api
+                .patch(
+                    &name,
+                    &PatchParams::apply("tispace").force(),
+                    &Patch::Apply(&daemon_set),
+                )
+                .await
+            {
+                warn!(
+                    image = url.as_str(),
+                    error = e.to_string().as_str(),
+                    "applying pre-pull daemon set encountered error"
+                );
+                BACKEND_ERRORS.with_label_values(&["k8s"]).inc();
+            }
+        }
+    }
+    let list = api
+        .list(&ListParams::default().labels("tispace/prepull"))
+        .await?;
+    for name in list.into_iter().filter_map(|d| d.metadata.name) {
+        if !expected.contains(&name) {
+            info!(name = name.as_str(), "deleting pre-pull daemon set");
+            api.delete(&name, &DeleteParams::default()).await?;
+        }
+    }
+    Ok(())
+}
+
+/// Returns the simplestreams images of the catalog for the arches and types of the LXD nodes.
+fn lxd_images(state: &State) -> BTreeSet<LxdImage> {
+    let mut images = BTreeSet::new();
+    for (runtime, type_) in [
+        (Runtime::Lxc, "container"),
+        (Runtime::Kvm, "virtual-machine"),
+    ] {
+        let arches: Vec<&Arch> = state
+            .nodes
+            .iter()
+            .filter(|n| n.runtimes.contains(&runtime))
+            .filter_map(|n| n.arch.as_ref())
+            .collect();
+        for i in state
+            .images
+            .iter()
+            .filter(|i| i.enabled && i.owner.is_none())
+        {
+            // Local images are already in the store.
+            let alias = match i.source(&runtime) {
+                Some(alias) if !alias.starts_with(LOCAL_IMAGE_PREFIX) => alias,
+                _ => continue,
+            };
+            for arch in i.arches.iter().filter(|a| arches.contains(a)) {
+                images.insert((format!("{}/{}", alias, arch), type_));
+            }
+        }
+    }
+    images
+}
+
+/// Returns the images in the store which LXD keeps up to date from the image server.
+async fn list_lxd_images(lxd_client: &ReqwestClient) -> Result<BTreeSet<LxdImage>> {
+    let url = api_url(&format!(
+        "/images?recursion=1&project={}",
+        LXD_PROJECT.as_str()
+    ));
+    let res: serde_json::Value = lxd_client.get(url).send().await?.json().await?;
+    check_error(&res)?;
+    Ok(res
+        .get("metadata")
+        .and_then(|m| m.as_array())
+        .into_iter()
+        .flatten()
+        .filter_map(|i| {
+            let alias = i.get("update_source")?.get("alias")?.as_str()?;
+            let type_ = match i.get("type")?.as_str()? {
+                "container" => "container",
+                "virtual-machine" => "virtual-machine",
+                _ => return None,
+            };
+            Some((alias.to_owned(), type_))
+        })
+        .collect())
+}
@@ -0,0 +1,112 @@
+use std::collections::HashMap;
+use std::future::Future;
+use std::pin::Pin;
+use std::sync::{Arc, Mutex};
+use std::task::{Context, Poll};
+use std::time::Instant;
+
+use axum::body::Body;
+use axum::extract::{FromRequest, RequestParts};
+use axum::http::Request;
+use axum::response::{IntoResponse, Response};
+use tower::{Layer, Service};
+
+use crate::auth::UserClaims;
+use crate::env::{RATE_LIMIT_BURST, RATE_LIMIT_PER_SECOND};
+use crate::error::RateLimited;
+
+struct Bucket {
+    tokens: f64,
+    updated_at: Instant,
+}
+
+/// Token buckets keyed by username, each holding up to RATE_LIMIT_BURST tokens and refilled at
+/// RATE_LIMIT_PER_SECOND tokens per second.
+#[derive(Clone, Default)]
+struct Buckets(Arc<Mutex<HashMap<String, Bucket>>>);
+
+impl Buckets {
+    /// Takes a token from the bucket of the user, or returns the seconds to wait for one.
+    fn acquire(&self, username: &str) -> Result<(), u64> {
+        let burst = *RATE_LIMIT_BURST as f64;
+        let rate = *RATE_LIMIT_PER_SECOND;
+        let now = Instant::now();
+        let mut buckets = self.0.lock().unwrap();
+        let bucket = buckets.entry(username.to_owned()).or_insert(Bucket {
+            tokens: burst,
+            updated_at: now,
+        });
+        let elapsed = now.duration_since(bucket.updated_at).as_secs_f64();
+        bucket.tokens = (bucket.tokens + elapsed * rate).min(burst);
+        bucket.updated_at = now;
+        if bucket.tokens >= 1.0 {
+            bucket.tokens -= 1.0;
+            Ok(())
+        } else {
+            Err(((1.0 - bucket.tokens) / rate).ceil() as u64)
+        }
+    }
+}
+
+/// Layer limiting the rate of requests of each authenticated user.
+///
+/// Requests that fail authentication are passed through and rejected by the handlers. The claims
+/// of the others are passed on in the request extensions, so the token is only verified once.
+#[derive(Clone, Default)]
+crate struct RateLimitLayer {
+    buckets: Buckets,
+}
+
+impl<S> Layer<S> for RateLimitLayer {
+    type Service = RateLimit<S>;
+
+    fn layer(&self, inner: S) -> Self::Service {
+        RateLimit {
+            inner,
+            buckets: self.buckets.clone(),
+        }
+    }
+}
+
+#[derive(Clone)]
+crate struct RateLimit<S> {
+    inner: S,
+    buckets: Buckets,
+}
+
+impl<S> Service<Request<Body>> for RateLimit<S>
+where
+    S: Service<Request<Body>, Response = Response> + Clone + Send + 'static,
+    S::Future: Send + 'static,
+{
+    type Response = Response;
+    type Error = S::Error;
+    #[allow(clippy::type_complexity)]
+    type Future = Pin<Box<dyn Future<Output = Result<Self::Response, Self::Error>> + Send>>;
+
+    fn poll_ready(&mut self, cx: &mut Context<'_>) -> Poll<Result<(), Self::Error>> {
+        self.inner.poll_ready(cx)
+    }
+
+    fn call(&mut self, req: Request<Body>) -> Self::Future {
+        // The inner service is ready, use it and leave the clone for the next call.
+        let clone = self.inner.clone();
+        let mut inner = std::mem::replace(&mut self.inner, clone);
+        let buckets = self.buckets.clone();
+        Box::pin(async move {
+            let mut parts = RequestParts::new(req);
+            if let Ok(user) = UserClaims::from_request(&mut parts).await {
+                if let Err(retry_after) = buckets.acquire(&user.username) {
+                    return Ok(RateLimited { retry_after }.into_response());
+                }
+                // The handlers take the verified claims instead of verifying the token again.
+                if let Some(extensions) = parts.extensions_mut() {
+                    extensions.insert(user);
+                }
+            }
+            // The body is never extracted by UserClaims.
+            let req = parts.try_into_request().unwrap();
+            inner.call(req).await
+        })
+    }
+}
@@ -0,0 +1,31 @@
+//! Wakes the operators and the scheduler out of their sleep on demand, so that admins debugging
+//! a stuck instance don't have to wait for the next pass.
+
+use once_cell::sync::Lazy;
+use tokio::sync::watch;
+use tokio::time::Duration;
+
+use crate::shutdown;
+
+// A receiver is kept alive so that sending never fails.
+static WAKEUP: Lazy<(watch::Sender<()>, watch::Receiver<()>)> = Lazy::new(|| watch::channel(()));
+
+/// Requests the loops to start their next pass right away.
+crate fn trigger() {
+    WAKEUP.0.send(()).unwrap();
+}
+
+/// Returns a receiver to pass to `sleep`, to be kept across the passes of a loop so that requests
+/// made during a pass are noticed.
+crate fn subscribe() -> watch::Receiver<()> {
+    WAKEUP.1.clone()
+}
+
+/// Sleeps for the duration, or until a pass is requested or shutdown is triggered, whichever
+/// comes first. Requests made while the caller was busy end the sleep right away.
+crate async fn sleep(wakeup: &mut watch::Receiver<()>, duration: Duration) {
+    tokio::select! {
+        _ = shutdown::sleep(duration) => {}
+        _ = wakeup.changed() => {}
+    }
+}
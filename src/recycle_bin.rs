@@ -0,0 +1,68 @@
+use chrono::Utc;
+use tokio::time::Duration;
+use tracing::{info, instrument, warn};
+
+use crate::config;
+use crate::lifecycle::{self, Action};
+use crate::shutdown;
+use crate::storage::Storage;
+
+/// Deletes the instances for good once they have stayed in the recycle bin for longer than the
+/// retention, and forgets the deleted instance groups once their members are gone.
+pub struct RecycleBinPurger {
+    storage: Storage,
+}
+
+impl RecycleBinPurger {
+    pub fn new(storage: Storage) -> Self {
+        RecycleBinPurger { storage }
+    }
+
+    pub async fn run(&self) {
+        while !shutdown::is_triggered() {
+            self.run_once().await;
+            shutdown::sleep(Duration::from_secs(60)).await;
+        }
+    }
+
+    #[instrument(skip_all)]
+    async fn run_once(&self) {
+        let now = Utc::now().timestamp();
+        let retention = config::current().recycle_bin_retention as i64 * 3600;
+        let res = self
+            .storage
+            .read_write(|state| {
+                let mut changed = false;
+                for u in &mut state.users {
+                    for i in u
+                        .instances
+                        .iter_mut()
+                        .filter(|i| !lifecycle::is_terminal(&i.stage))
+                    {
+                        match i.trashed_at {
+                            Some(t) if t + retention <= now => {}
+                            _ => continue,
+                        }
+                        if lifecycle::apply(i, Action::Delete).unwrap_or(false) {
+                            info!(
+                                username = u.username.as_str(),
+                                instance = i.name.as_str(),
+                                "purging instance from the recycle bin"
+                            );
+                            i.add_event("purged from the recycle bin".to_string());
+                            changed = true;
+                        }
+                    }
+                }
+                changed |= state.forget_deleted_instance_groups();
+                changed
+            })
+            .await;
+        if let Err(e) = res {
+            warn!(
+                error = e.to_string().as_str(),
+                "purge recycle bin encountered error"
+            );
+        }
+    }
+}
@@ -0,0 +1,155 @@
+use anyhow::{anyhow, Result};
+use reqwest::header::CONTENT_LENGTH;
+use reqwest::{Body, Client, Response, Url};
+
+use crate::aws::{self, uri_encode, Credentials, UNSIGNED_PAYLOAD};
+use crate::env::{
+    BACKUP_S3_ACCESS_KEY, BACKUP_S3_BUCKET, BACKUP_S3_ENDPOINT, BACKUP_S3_EXTRA_BUCKETS,
+    BACKUP_S3_REGION, BACKUP_S3_SECRET_KEY,
+};
+
+/// Returns true if the backup storage is configured.
+crate fn is_configured() -> bool {
+    !BACKUP_S3_ENDPOINT.is_empty() && !BACKUP_S3_BUCKET.is_empty()
+}
+
+/// Returns true if backups may be stored in the bucket, the default one if empty.
+crate fn is_allowed_bucket(bucket: &str) -> bool {
+    bucket.is_empty()
+        || bucket == *BACKUP_S3_BUCKET
+        || BACKUP_S3_EXTRA_BUCKETS.iter().any(|b| b == bucket)
+}
+
+/// A minimal S3 client which only supports streaming objects in and out of a single bucket.
+///
+/// Requests are signed with AWS Signature Version 4 using path-style addressing, so it works
+/// with both AWS S3 and S3 compatible services such as MinIO.
+#[derive(Clone)]
+crate struct Bucket {
+    client: Client,
+    endpoint: String,
+    bucket: String,
+    credentials: Credentials,
+}
+
+impl Bucket {
+    /// Returns the bucket configured by the BACKUP_S3_* variables, or None if the backup storage
+    /// is not configured.
+    crate fn from_env() -> Option<Self> {
+        if !is_configured() {
+            return None;
+        }
+        Some(Bucket {
+            client: Client::new(),
+            endpoint: BACKUP_S3_ENDPOINT.trim_end_matches('/').to_owned(),
+            bucket: BACKUP_S3_BUCKET.to_owned(),
+            credentials: Credentials {
+                region: BACKUP_S3_REGION.to_owned(),
+                access_key: BACKUP_S3_ACCESS_KEY.to_owned(),
+                secret_key: BACKUP_S3_SECRET_KEY.to_owned(),
+            },
+        })
+    }
+
+    /// Returns the client of another bucket of the same storage, or of this bucket if empty.
+    crate fn with_bucket(&self, bucket: &str) -> Bucket {
+        let mut b = self.clone();
+        if !bucket.is_empty() {
+            b.bucket = bucket.to_owned();
+        }
+        b
+    }
+
+    /// Uploads an object of `len` bytes whose content is streamed from `body`.
+    crate async fn put_object(&self, key: &str, body: Body, len: u64) -> Result<()> {
+        let url = self.object_url(key)?;
+        let res = self
+            .client
+            .put(url.clone())
+            .headers(aws::sign(
+                &self.credentials,
+                "s3",
+                "PUT",
+                &url,
+                UNSIGNED_PAYLOAD,
+            )?)
+            .header(CONTENT_LENGTH, len)
+            .body(body)
+            .send()
+            .await?;
+        let status = res.status();
+        if !status.is_success() {
+            return Err(anyhow!(
+                "put object {} failed with status {}: {}",
+                key,
+                status,
+                res.text().await.unwrap_or_default()
+            ));
+        }
+        Ok(())
+    }
+
+    /// Downloads an object, the content can be streamed from the returned response.
+    crate async fn get_object(&self, key: &str) -> Result<Response> {
+        let url = self.object_url(key)?;
+        let res = self
+            .client
+            .get(url.clone())
+            .headers(aws::sign(
+                &self.credentials,
+                "s3",
+                "GET",
+                &url,
+                UNSIGNED_PAYLOAD,
+            )?)
+            .send()
+            .await?;
+        let status = res.status();
+        if !status.is_success() {
+            return Err(anyhow!(
+                "get object {} failed with status {}: {}",
+                key,
+                status,
+                res.text().await.unwrap_or_default()
+            ));
+        }
+        Ok(res)
+    }
+
+    /// Deletes an object, which succeeds if it doesn't exist either.
+    crate async fn delete_object(&self, key: &str) -> Result<()> {
+        let url = self.object_url(key)?;
+        let res = self
+            .client
+            .delete(url.clone())
+            .headers(aws::sign(
+                &self.credentials,
+                "s3",
+                "DELETE",
+                &url,
+                UNSIGNED_PAYLOAD,
+            )?)
+            .send()
+            .await?;
+        let status = res.status();
+        if !status.is_success() {
+            return Err(anyhow!(
+                "delete object {} failed with status {}: {}",
+                key,
+                status,
+                res.text().await.unwrap_or_default()
+            ));
+        }
+        Ok(())
+    }
+
+    fn object_url(&self, key: &str) -> Result<Url> {
+        let path = key.split('/').map(uri_encode).collect::<Vec<_>>().join("/");
+        Ok(Url::parse(&format!(
+            "{}/{}/{}",
+            self.endpoint,
+            uri_encode(&self.bucket),
+            path
+        ))?)
+    }
+}
@@ -1,15 +1,26 @@
 use rand::seq::SliceRandom;
 use rand::thread_rng;
-use std::cmp::Ordering;
-use std::collections::HashSet;
+use std::cmp::{Ordering, Reverse};
+use std::collections::{HashMap, HashSet};
 
-use tokio::time::{sleep, Duration};
-use tracing::{info, warn};
+use chrono::Utc;
+use tracing::{info, instrument, warn};
 
-use crate::env::EXTERNAL_IP_POOL;
-use crate::model::{InstanceStatus, Node, Runtime, State, StoragePool};
+use crate::config::{self, IpPool};
+use crate::instance_lock;
+use crate::lifecycle::{self, Action};
+use crate::metrics::{PREEMPTIONS, SCHEDULING_FAILURES};
+use crate::model::{
+    Instance, InstanceStage, InstanceStatus, IpAssignment, Node, NodeVerdict, Priority, Runtime,
+    SchedulingDecision, State, StoragePool,
+};
+use crate::reconcile;
+use crate::shutdown;
 use crate::storage::Storage;
 
+// Released assignments kept for auditing, the oldest ones are dropped first.
+const MAX_RELEASED_IP_ASSIGNMENTS: usize = 10000;
+
 pub struct Scheduler {
     storage: Storage,
 }
@@ -20,18 +31,23 @@ impl Scheduler {
     }
 
     pub async fn run(&self) {
-        loop {
+        let mut wakeup = reconcile::subscribe();
+        while !shutdown::is_triggered() {
             self.run_once().await;
-            sleep(Duration::from_secs(3)).await;
+            reconcile::sleep(&mut wakeup, config::current().schedule_wait()).await;
         }
     }
 
+    #[instrument(skip_all)]
     async fn run_once(&self) {
+        // The operators mark the nodes dirty once the backends allocated the instances.
         if let Err(e) = self
             .storage
             .read_write(|state| {
-                Scheduler::allocate_ip(state);
-                Scheduler::schedule(state);
+                Scheduler::track_ip_assignments(state, Utc::now().timestamp());
+                Scheduler::schedule(state, false);
+                Scheduler::preempt(state);
+                Scheduler::drain(state);
                 true
             })
             .await
@@ -40,7 +56,78 @@ impl Scheduler {
         }
     }
 
-    fn allocate_ip(state: &mut State) {
+    /// Records the external IPs instances got or released since the last call.
+    ///
+    /// Releases are noticed once the instances are removed from the state, which happens after
+    /// their backends are deleted, or when they are transferred to another user.
+    fn track_ip_assignments(state: &mut State, now: i64) {
+        // Whether the IPs are pinned, keyed by the IPs and their holders.
+        let mut held = HashMap::new();
+        for u in &state.users {
+            for i in &u.instances {
+                if let (Some(ip), true) = (&i.external_ip, uses_ip_pools(i)) {
+                    held.insert(
+                        (ip.as_str(), u.username.as_str(), i.name.as_str()),
+                        i.ip_pinned,
+                    );
+                }
+            }
+        }
+        let assignments = &mut state.ip_assignments;
+        for a in assignments.iter_mut().filter(|a| a.released_at.is_none()) {
+            match held.remove(&(a.ip.as_str(), a.username.as_str(), a.instance.as_str())) {
+                Some(pinned) => a.pinned = pinned,
+                None => {
+                    info!(
+                        "external IP {} of instance {} of user {} is released",
+                        a.ip, a.instance, a.username
+                    );
+                    a.released_at = Some(now);
+                }
+            }
+        }
+        for ((ip, username, instance), pinned) in held {
+            assignments.push(IpAssignment {
+                ip: ip.to_owned(),
+                username: username.to_owned(),
+                instance: instance.to_owned(),
+                assigned_at: now,
+                released_at: None,
+                pinned,
+            });
+        }
+        // Pinned IPs stay reserved until they are claimed again, unpinned or the pins expire.
+        let ttl = config::current().pinned_ip_ttl as i64 * 3600;
+        for a in assignments.iter_mut().filter(|a| a.pinned) {
+            if let (Some(t), true) = (a.released_at, ttl > 0) {
+                if now - t >= ttl {
+                    info!(
+                        "pin of external IP {} of deleted instance {} of user {} expired",
+                        a.ip, a.instance, a.username
+                    );
+                    a.pinned = false;
+                }
+            }
+        }
+        let expired = |a: &IpAssignment| a.released_at.is_some() && !a.pinned;
+        let excess = assignments
+            .iter()
+            .filter(|a| expired(a))
+            .count()
+            .saturating_sub(MAX_RELEASED_IP_ASSIGNMENTS);
+        let mut removed = 0;
+        assignments.retain(|a| {
+            if removed < excess && expired(a) {
+                removed += 1;
+                return false;
+            }
+            true
+        });
+    }
+
+    /// Returns the addresses of each IP pool that are neither allocated to any instance nor
+    /// released within the grace period, shuffled.
+    fn free_ips(state: &State) -> Vec<(IpPool, Vec<String>)> {
         let mut allocated_ips = HashSet::new();
         for u in &state.users {
             for i in &u.instances {
@@ -49,96 +136,94 @@ impl Scheduler {
                 }
             }
         }
-
-        let mut ip_pool = EXTERNAL_IP_POOL.clone();
-        ip_pool.shuffle(&mut thread_rng());
-
-        for u in &mut state.users {
-            for i in &mut u.instances {
-                match i.runtime {
-                    Runtime::Lxc | Runtime::Kvm => {
-                        if i.external_ip.is_none() {
-                            for ip in ip_pool.iter() {
-                                if !allocated_ips.contains(ip) {
-                                    i.external_ip = Some(ip.clone());
-                                    allocated_ips.insert(ip.clone());
-                                    break;
-                                }
-                            }
-                            if i.external_ip.is_none() {
-                                warn!("external IP pool is exhausted, no more IPs available");
-                                return;
-                            }
-                        }
-                    }
-                    _ => {}
-                }
+        let grace_period = config::current().ip_release_grace_period as i64 * 60;
+        let now = Utc::now().timestamp();
+        for a in &state.ip_assignments {
+            if a.is_reserved(now, grace_period) {
+                allocated_ips.insert(a.ip.clone());
             }
         }
+
+        // IPs already allocated out of a shrunk pool are kept until their instances are deleted.
+        let mut pools = state.ip_pools();
+        for (_, ips) in &mut pools {
+            ips.retain(|ip| !allocated_ips.contains(ip));
+            ips.shuffle(&mut thread_rng());
+        }
+        pools
     }
 
-    fn schedule(state: &mut State) {
+    /// Takes an address out of the first pool serving the node that still has free addresses, and
+    /// returns it along with the name of the pool.
+    fn allocate_ip(
+        free_ips: &mut [(IpPool, Vec<String>)],
+        node_name: &str,
+    ) -> Option<(String, String)> {
+        free_ips
+            .iter_mut()
+            .filter(|(p, _)| p.serves(node_name))
+            .find_map(|(p, ips)| ips.pop().map(|ip| (p.name.clone(), ip)))
+    }
+
+    /// Schedules the pending instances and returns the nodes they are scheduled to. A dry run
+    /// neither logs the placements nor counts the failures.
+    fn schedule(state: &mut State, dry_run: bool) -> Vec<String> {
+        let mut scheduled_nodes = Vec::new();
+        let mut free_ips = Scheduler::free_ips(state);
         let mut instances = Vec::new();
+        // Instances an operator is working on are left to the next pass.
+        let mut locks = Vec::new();
         for u in &mut state.users {
             for i in &mut u.instances {
-                if i.status != InstanceStatus::Creating {
+                if !is_pending(i) {
                     continue;
                 }
-                match i.runtime {
-                    Runtime::Lxc | Runtime::Kvm => {
-                        if i.external_ip.is_some()
-                            && (i.node_name.is_none() || i.storage_pool.is_none())
-                        {
-                            instances.push(i);
-                        }
-                    }
-                    Runtime::Runc | Runtime::Kata => {
-                        if i.node_name.is_none() {
-                            instances.push(i);
-                        }
+                if !dry_run {
+                    match instance_lock::try_lock(&u.username, &i.name) {
+                        Some(lock) => locks.push(lock),
+                        None => continue,
                     }
                 }
+                instances.push(i);
             }
         }
         if instances.is_empty() {
-            return;
+            return scheduled_nodes;
         }
 
+        let now = Utc::now().timestamp();
         for i in instances {
             let mut best_node: Option<&mut Node> = None;
+            let mut verdicts = Vec::new();
             for n in &mut state.nodes {
                 if let Some(node_name) = &i.node_name {
                     if node_name != &n.name {
                         continue;
                     }
                 }
-                if !n.runtimes.contains(&i.runtime) {
-                    continue;
-                }
-                if i.cpu + n.cpu_allocated > n.cpu_total
-                    || i.memory + n.memory_allocated > n.memory_total
-                    || i.disk_size + n.storage_allocated > n.storage_total
-                    || i.disk_size + n.storage_used > n.storage_total
-                {
-                    continue;
-                }
-                if !n.storage_pools.iter().any(|s| {
-                    if let Some(storage_pool) = &i.storage_pool {
-                        if storage_pool != &s.name {
-                            return false;
-                        }
-                    }
-                    s.allocated.max(s.used) + i.disk_size <= s.total
-                }) {
+                let rejection = Scheduler::check_node(i, n, &free_ips).err();
+                verdicts.push(NodeVerdict {
+                    node_name: n.name.clone(),
+                    rejection: rejection.clone(),
+                    free_cpu: n.usable_cpu().saturating_sub(n.cpu_allocated),
+                    free_memory: n.usable_memory().saturating_sub(n.memory_allocated),
+                    free_storage: n
+                        .usable_storage()
+                        .saturating_sub(n.storage_allocated.max(n.storage_used)),
+                });
+                if rejection.is_some() {
                     continue;
                 }
 
                 if let Some(bn) = &best_node {
-                    let a = (n.cpu_total - n.cpu_allocated).cmp(&(bn.cpu_total - bn.cpu_allocated));
-                    let b = (n.memory_total - n.memory_allocated)
-                        .cmp(&(bn.memory_total - bn.memory_allocated));
-                    let c = (n.storage_total - n.storage_allocated.max(n.storage_used))
-                        .cmp(&(bn.storage_total - bn.storage_allocated.max(bn.storage_used)));
+                    // The nodes fit the instance, so their allocations don't exceed the usable
+                    // resources.
+                    let a = (n.usable_cpu() - n.cpu_allocated)
+                        .cmp(&(bn.usable_cpu() - bn.cpu_allocated));
+                    let b = (n.usable_memory() - n.memory_allocated)
+                        .cmp(&(bn.usable_memory() - bn.memory_allocated));
+                    let c = (n.usable_storage() - n.storage_allocated.max(n.storage_used))
+                        .cmp(&(bn.usable_storage() - bn.storage_allocated.max(bn.storage_used)));
                     if a == Ordering::Greater
                         || a == Ordering::Equal && b == Ordering::Greater
                         || a == Ordering::Equal && b == Ordering::Equal && c == Ordering::Greater
@@ -150,6 +235,22 @@ impl Scheduler {
                 }
             }
             if best_node.is_none() {
+                record_decision(
+                    i,
+                    SchedulingDecision {
+                        decided_at: now,
+                        node_name: None,
+                        storage_pool: None,
+                        nodes: verdicts,
+                    },
+                );
+                if dry_run {
+                    continue;
+                }
+                if needs_external_ip(i) && free_ips.iter().all(|(_, ips)| ips.is_empty()) {
+                    warn!("external IP pools are exhausted, no more IPs available");
+                }
+                SCHEDULING_FAILURES.inc();
                 warn!(
                     "no node has enough resources to schedule instance {}",
                     i.name
@@ -166,7 +267,9 @@ impl Scheduler {
                     }
                 }
                 if let Some(bs) = &best_storage_pool {
-                    if s.total - s.allocated.max(s.used) > bs.total - bs.allocated.max(bs.used) {
+                    if s.usable().saturating_sub(s.allocated.max(s.used))
+                        > bs.usable().saturating_sub(bs.allocated.max(bs.used))
+                    {
                         best_storage_pool = Some(s);
                     }
                 } else {
@@ -174,26 +277,484 @@ impl Scheduler {
                 }
             }
             let best_storage_pool = best_storage_pool.unwrap();
+            if needs_external_ip(i) {
+                let (pool, ip) = Scheduler::allocate_ip(&mut free_ips, &best_node.name).unwrap();
+                if !dry_run {
+                    info!(
+                        "allocated external IP {} of pool {} to instance {}",
+                        ip, pool, i.name
+                    );
+                }
+                i.external_ip = Some(ip);
+                i.ip_pool = Some(pool);
+            }
 
-            best_storage_pool.allocated += i.disk_size;
-            best_node.cpu_allocated += i.cpu;
+            best_storage_pool.allocated += i.total_disk_size();
+            best_node.cpu_allocated += i.allocated_cpu();
+            if i.dedicated_cpu {
+                i.pinned_cpus = best_node.pick_dedicated_cpus(i.cpu).unwrap();
+                best_node.pinned_cpus.extend(&i.pinned_cpus);
+            }
             best_node.memory_allocated += i.memory;
-            best_node.storage_allocated += i.disk_size;
+            best_node.storage_allocated += i.total_disk_size();
             i.node_name = Some(best_node.name.clone());
+            scheduled_nodes.push(best_node.name.clone());
 
+            if matches!(i.runtime, Runtime::Lxc | Runtime::Kvm | Runtime::MicroVm) {
+                i.storage_pool = Some(best_storage_pool.name.clone());
+            }
+            let decision = SchedulingDecision {
+                decided_at: now,
+                node_name: i.node_name.clone(),
+                storage_pool: i.storage_pool.clone(),
+                nodes: verdicts,
+            };
+            record_decision(i, decision);
+            if dry_run {
+                continue;
+            }
             match i.runtime {
-                Runtime::Lxc | Runtime::Kvm => {
-                    i.storage_pool = Some(best_storage_pool.name.clone());
+                Runtime::Lxc | Runtime::Kvm | Runtime::MicroVm => {
                     info!(
                         "scheduled instance {} to node {} on storage pool {}",
                         i.name, best_node.name, best_storage_pool.name
                     );
                 }
-                Runtime::Runc | Runtime::Kata => {
-                    // Runc and Kata doesn't support specifying storage pool.
+                Runtime::Runc | Runtime::Kata | Runtime::Oci | Runtime::Ec2 => {
+                    // Runc, Kata and Podman don't support specifying storage pool.
                     info!("scheduled instance {} to node {}", i.name, best_node.name);
                 }
             }
         }
+        scheduled_nodes
+    }
+
+    /// Checks whether the instance fits the node, returning why not if it doesn't.
+    fn check_node(
+        i: &Instance,
+        n: &Node,
+        free_ips: &[(IpPool, Vec<String>)],
+    ) -> Result<(), String> {
+        if !n.runtimes.contains(&i.runtime) {
+            return Err(format!("runtime {} is unavailable", i.runtime));
+        }
+        if !n.is_schedulable() {
+            let reason = match &n.unhealthy_reason {
+                _ if !n.schedulable => "node is cordoned".to_owned(),
+                _ if n.draining => "node is draining".to_owned(),
+                Some(reason) => format!("node is unhealthy: {}", reason),
+                None => "node is under maintenance".to_owned(),
+            };
+            return Err(reason);
+        }
+        if !n.can_run_arch(&i.arch) {
+            return Err(format!("arch {} is unavailable", i.arch));
+        }
+        // Requested IPs are allocated at creation and tie the instance to their pool.
+        if let Some(pool) = &i.ip_pool {
+            if !config::current().ip_pool(Some(pool)).serves(&n.name) {
+                return Err(format!("IP pool {} doesn't serve the node", pool));
+            }
+        }
+        // The external IP is allocated out of a pool serving the node, as the pools may be on
+        // different L2 segments.
+        if needs_external_ip(i)
+            && !free_ips
+                .iter()
+                .any(|(p, ips)| p.serves(&n.name) && !ips.is_empty())
+        {
+            return Err("no IP pool serving the node has free IPs".to_owned());
+        }
+        if i.dedicated_cpu && n.pick_dedicated_cpus(i.cpu).is_none() {
+            return Err(format!("fewer than {} CPUs can be dedicated", i.cpu));
+        }
+        if i.allocated_cpu() + n.cpu_allocated > n.usable_cpu() {
+            return Err(format!(
+                "not enough CPUs, requested: {}, free: {}",
+                i.allocated_cpu(),
+                n.usable_cpu().saturating_sub(n.cpu_allocated)
+            ));
+        }
+        if i.memory + n.memory_allocated > n.usable_memory() {
+            return Err(format!(
+                "not enough memory, requested: {}GiB, free: {}GiB",
+                i.memory,
+                n.usable_memory().saturating_sub(n.memory_allocated)
+            ));
+        }
+        if i.total_disk_size() + n.storage_allocated.max(n.storage_used) > n.usable_storage() {
+            return Err(format!(
+                "not enough storage, requested: {}GiB, free: {}GiB",
+                i.total_disk_size(),
+                n.usable_storage()
+                    .saturating_sub(n.storage_allocated.max(n.storage_used))
+            ));
+        }
+        if !n.storage_pools.iter().any(|s| {
+            if let Some(storage_pool) = &i.storage_pool {
+                if storage_pool != &s.name {
+                    return false;
+                }
+            }
+            s.allocated.max(s.used) + i.total_disk_size() <= s.usable()
+        }) {
+            return Err(match &i.storage_pool {
+                Some(storage_pool) => format!("not enough space in storage pool {}", storage_pool),
+                None => "no storage pool has enough space".to_owned(),
+            });
+        }
+        Ok(())
+    }
+
+    /// Stops preemptible instances to make room for the pending instances of normal priority which
+    /// no node has enough CPU or memory for. The pending instances are placed by the next pass,
+    /// as the stopped instances give up their CPU and memory.
+    fn preempt(state: &mut State) {
+        let pending: Vec<Instance> = state
+            .users
+            .iter()
+            .flat_map(|u| &u.instances)
+            // The pinned CPUs of stopped instances are kept, so dedicated instances are not helped.
+            .filter(|i| i.priority == Priority::Normal && !i.dedicated_cpu && is_pending(i))
+            .cloned()
+            .collect();
+        for i in pending {
+            let victims = match Scheduler::pick_victims(state, &i) {
+                Some(victims) => victims,
+                None => continue,
+            };
+            let priority = describe_priority(state, &i);
+            for (username, name) in victims {
+                let stopped = Scheduler::stop(state, &username, &name, |p| {
+                    format!(
+                        "preempted ({}) to make room for instance {} ({})",
+                        p, i.name, priority
+                    )
+                });
+                if stopped {
+                    info!(
+                        "preempted instance {} of user {} to make room for instance {}",
+                        name, username, i.name
+                    );
+                    PREEMPTIONS.inc();
+                }
+            }
+            // The next pending instances see the room made.
+            state.sync_allocated_resources();
+        }
+    }
+
+    /// Returns the running preemptible instances to stop, along with their owners, for the
+    /// instance to fit a node, if CPU and memory are all it lacks. Only instances whose priority
+    /// values don't exceed the one of the instance are stopped, the lowest first. The node needing
+    /// the fewest of them is chosen.
+    fn pick_victims(state: &State, i: &Instance) -> Option<Vec<(String, String)>> {
+        let mut best: Option<Vec<(String, String)>> = None;
+        for n in &state.nodes {
+            if i.node_name.as_ref().map_or(false, |name| name != &n.name) {
+                continue;
+            }
+            if !n.runtimes.contains(&i.runtime)
+                || !n.is_schedulable()
+                || !n.can_run_arch(&i.arch)
+                || i.total_disk_size() + n.storage_allocated.max(n.storage_used)
+                    > n.usable_storage()
+            {
+                continue;
+            }
+            let mut cpu_short =
+                (i.allocated_cpu() + n.cpu_allocated).saturating_sub(n.usable_cpu());
+            let mut memory_short =
+                (i.memory + n.memory_allocated).saturating_sub(n.usable_memory());
+            // Something else keeps the instance off the node.
+            if cpu_short == 0 && memory_short == 0 {
+                continue;
+            }
+            let mut candidates: Vec<(&str, &Instance)> = state
+                .users
+                .iter()
+                .flat_map(|u| u.instances.iter().map(move |v| (u.username.as_str(), v)))
+                .filter(|(_, v)| {
+                    v.priority == Priority::Preemptible
+                        && v.stage == InstanceStage::Running
+                        && v.node_name.as_ref() == Some(&n.name)
+                        && state.priority_value(v) <= state.priority_value(i)
+                })
+                .collect();
+            // Among the same priority values, the youngest instances go first, they lose the
+            // least work.
+            candidates.sort_by_key(|(_, v)| (state.priority_value(v), Reverse(v.created_at)));
+            let mut victims = Vec::new();
+            for (username, v) in candidates {
+                if cpu_short == 0 && memory_short == 0 {
+                    break;
+                }
+                cpu_short = cpu_short.saturating_sub(v.allocated_cpu());
+                memory_short = memory_short.saturating_sub(v.memory);
+                victims.push((username.to_owned(), v.name.clone()));
+            }
+            if cpu_short > 0 || memory_short > 0 {
+                continue;
+            }
+            if best.as_ref().map_or(true, |b| victims.len() < b.len()) {
+                best = Some(victims);
+            }
+        }
+        best
+    }
+
+    /// Stops the instances running on draining nodes one at a time, the lowest priority values
+    /// first, so that the instances which matter most keep running the longest. The next one is
+    /// stopped once the previous one has stopped.
+    fn drain(state: &mut State) {
+        let mut victims = Vec::new();
+        for n in state.nodes.iter().filter(|n| n.draining) {
+            let on_node = || {
+                state.users.iter().flat_map(|u| {
+                    u.instances
+                        .iter()
+                        .filter(|i| i.node_name.as_ref() == Some(&n.name))
+                        .map(move |i| (u.username.as_str(), i))
+                })
+            };
+            if on_node().any(|(_, i)| i.status == InstanceStatus::Stopping) {
+                continue;
+            }
+            if let Some((username, i)) = on_node()
+                .filter(|(_, i)| i.stage == InstanceStage::Running)
+                .min_by_key(|(_, i)| (state.priority_value(i), Reverse(i.created_at)))
+            {
+                victims.push((username.to_owned(), i.name.clone(), n.name.clone()));
+            }
+        }
+        for (username, name, node_name) in victims {
+            let stopped = Scheduler::stop(state, &username, &name, |p| {
+                format!("stopped ({}) to drain node {}", p, node_name)
+            });
+            if stopped {
+                info!(
+                    "stopped instance {} of user {} to drain node {}",
+                    name, username, node_name
+                );
+            }
+        }
+    }
+
+    /// Stops the instance of the user, recording the event made out of the description of its
+    /// priority. Returns whether the instance was stopped.
+    fn stop(
+        state: &mut State,
+        username: &str,
+        name: &str,
+        event: impl FnOnce(&str) -> String,
+    ) -> bool {
+        let priority = match state
+            .find_user(username)
+            .and_then(|u| u.find_instance(name))
+        {
+            Some(v) => describe_priority(state, v),
+            None => return false,
+        };
+        let v = match state
+            .find_mut_user(username)
+            .and_then(|u| u.find_mut_instance(name))
+        {
+            Some(v) => v,
+            None => return false,
+        };
+        if !lifecycle::apply(v, Action::Stop).unwrap_or(false) {
+            return false;
+        }
+        v.add_event(event(&priority));
+        true
+    }
+
+    /// Returns the node and the storage pool the pending instance would be scheduled to, without
+    /// changing the state. The instances pending before it are placed first, as they would be.
+    crate fn predict_placement(
+        state: &State,
+        username: &str,
+        instance_name: &str,
+    ) -> Option<(String, Option<String>)> {
+        let mut state = state.clone();
+        Scheduler::schedule(&mut state, true);
+        let i = state.find_user(username)?.find_instance(instance_name)?;
+        Some((i.node_name.clone()?, i.storage_pool.clone()))
+    }
+}
+
+/// Records the decision on the instance. The previous decision is kept as is if the verdicts are
+/// the same, so that the passes retrying a pending instance leave the state alone until the nodes
+/// change.
+fn record_decision(instance: &mut Instance, decision: SchedulingDecision) {
+    if let Some(previous) = &instance.scheduling {
+        if previous.node_name == decision.node_name
+            && previous.storage_pool == decision.storage_pool
+            && previous.nodes == decision.nodes
+        {
+            return;
+        }
+    }
+    instance.scheduling = Some(decision);
+}
+
+/// Describes the priority of the instance for its events.
+fn describe_priority(state: &State, instance: &Instance) -> String {
+    match &instance.priority_class {
+        Some(class) => format!(
+            "priority class {}, value {}",
+            class,
+            state.priority_value(instance)
+        ),
+        None => format!("priority {}", instance.priority),
+    }
+}
+
+/// Returns true if the instance waits for a node, or a storage pool or CPUs on its node.
+crate fn is_pending(i: &Instance) -> bool {
+    if i.status != InstanceStatus::Creating {
+        return false;
+    }
+    match i.runtime {
+        Runtime::Lxc | Runtime::Kvm | Runtime::MicroVm => {
+            i.node_name.is_none()
+                || i.storage_pool.is_none()
+                || i.dedicated_cpu && i.pinned_cpus.is_empty()
+        }
+        Runtime::Runc | Runtime::Kata | Runtime::Oci => i.node_name.is_none(),
+        // EC2 instances are placed by AWS.
+        Runtime::Ec2 => false,
+    }
+}
+
+/// Returns true if the external IP of the instance comes from the IP pools.
+crate fn uses_ip_pools(instance: &Instance) -> bool {
+    matches!(
+        instance.runtime,
+        Runtime::Lxc | Runtime::Kvm | Runtime::MicroVm | Runtime::Oci
+    )
+}
+
+/// Returns true if the instance is exposed through an address of the IP pools it doesn't have yet.
+fn needs_external_ip(instance: &Instance) -> bool {
+    uses_ip_pools(instance) && instance.external_ip.is_none()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::model::PriorityClass;
+
+    fn new_instance(name: &str, cpu: usize, created_at: i64) -> Instance {
+        serde_json::from_value(serde_json::json!({
+            "name": name,
+            "cpu": cpu,
+            "memory": 4,
+            "disk_size": 10,
+            "image": "ubuntu:22.04",
+            "hostname": name,
+            "password": "",
+            "stage": "Running",
+            "status": "Running",
+            "runtime": "lxc",
+            "node_name": "node1",
+            "storage_pool": "default",
+            "created_at": created_at,
+        }))
+        .unwrap()
+    }
+
+    fn new_preemptible(name: &str, cpu: usize, created_at: i64, class: Option<&str>) -> Instance {
+        let mut instance = new_instance(name, cpu, created_at);
+        instance.priority = Priority::Preemptible;
+        instance.priority_class = class.map(str::to_owned);
+        instance
+    }
+
+    fn new_priority_class(name: &str, value: i32) -> PriorityClass {
+        PriorityClass {
+            name: name.to_owned(),
+            value,
+            preemptible: true,
+            description: String::new(),
+            users: vec![],
+        }
+    }
+
+    /// Returns the state of a node whose 8 CPUs are all allocated, to a normal instance and to
+    /// preemptible ones, along with a pending instance of normal priority needing `cpu` CPUs.
+    fn new_state(cpu: usize) -> State {
+        config::init_defaults();
+        let mut pending = new_instance("pending", cpu, 5);
+        pending.status = InstanceStatus::Creating;
+        pending.node_name = None;
+        pending.storage_pool = None;
+        let mut state: State = serde_json::from_value(serde_json::json!({
+            "users": [{"username": "dev", "instances": []}],
+            "nodes": [{
+                "name": "node1",
+                "storage_pools": [],
+                "runtimes": ["lxc"],
+                "cpu_total": 8,
+                "cpu_allocated": 0,
+                "memory_total": 32,
+                "memory_allocated": 0,
+                "storage_total": 1000,
+                "storage_used": 0,
+                "storage_allocated": 0,
+            }],
+        }))
+        .unwrap();
+        state.priority_classes = vec![
+            new_priority_class("low", -10),
+            new_priority_class("high", 10),
+        ];
+        state.users[0].instances = vec![
+            new_instance("normal", 2, 0),
+            new_preemptible("low-old", 1, 1, Some("low")),
+            new_preemptible("low-young", 1, 2, Some("low")),
+            new_preemptible("default", 2, 3, None),
+            new_preemptible("high", 2, 4, Some("high")),
+            pending,
+        ];
+        state.sync_allocated_resources();
+        state
+    }
+
+    fn pick_victims(cpu: usize) -> Option<Vec<String>> {
+        let state = new_state(cpu);
+        let pending = state.users[0].find_instance("pending").unwrap();
+        Scheduler::pick_victims(&state, pending)
+            .map(|victims| victims.into_iter().map(|(_, name)| name).collect())
+    }
+
+    #[test]
+    fn test_pick_victims() {
+        // The lowest values go first, and the youngest first among the same values.
+        assert_eq!(pick_victims(1).unwrap(), ["low-young"]);
+        assert_eq!(pick_victims(2).unwrap(), ["low-young", "low-old"]);
+        assert_eq!(
+            pick_victims(4).unwrap(),
+            ["low-young", "low-old", "default"]
+        );
+        // Instances of higher values than the pending one are never stopped.
+        assert_eq!(pick_victims(5), None);
+    }
+
+    #[test]
+    fn test_preempt() {
+        let mut state = new_state(2);
+        Scheduler::preempt(&mut state);
+        let instance = |name: &str| state.users[0].find_instance(name).unwrap();
+        for name in ["low-old", "low-young"] {
+            assert_eq!(instance(name).stage, InstanceStage::Stopped);
+            let event = &instance(name).events.last().unwrap().message;
+            assert!(event.starts_with("preempted (priority class low, value -10)"));
+        }
+        // Only as many instances as needed for the room are stopped.
+        for name in ["normal", "default", "high"] {
+            assert_eq!(instance(name).stage, InstanceStage::Running);
+        }
+        assert_eq!(state.nodes[0].cpu_allocated, 6);
     }
 }
@@ -1,29 +1,96 @@
 use axum::{
-    extract::{Extension, Path},
-    http::StatusCode,
-    response::IntoResponse,
-    routing::{delete, get, post},
+    async_trait,
+    body::Body,
+    extract::{
+        ws::{Message, WebSocket, WebSocketUpgrade},
+        BodyStream, ConnectInfo, Extension, Form, FromRequest, Path, Query, RequestParts,
+    },
+    http::{
+        header::{AUTHORIZATION, CONTENT_TYPE, ETAG, IF_NONE_MATCH, LOCATION},
+        HeaderMap, Request, StatusCode, Uri,
+    },
+    response::{
+        sse::{Event, KeepAlive, Sse},
+        IntoResponse, Response,
+    },
+    routing::{delete, get, get_service, patch, post, put},
     Json, Router,
 };
+use chrono::{NaiveDate, Utc};
+use futures::stream::{self, Stream};
+use futures::{SinkExt, StreamExt};
 use once_cell::sync::Lazy;
-use prometheus::{Encoder, GaugeVec, Opts, Registry, TextEncoder};
 use rand::{distributions::Alphanumeric, thread_rng, Rng};
 use regex::Regex;
+use ring::digest;
+use serde_json::json;
+use std::collections::{BTreeMap, HashMap, HashSet};
+use std::convert::Infallible;
+use std::net::SocketAddr;
 use std::str::FromStr;
-use tracing::warn;
+use std::time::Duration;
+use tokio::io::{AsyncReadExt, AsyncWriteExt};
+use tokio::net::TcpStream;
+use tokio::sync::broadcast::error::RecvError;
+use tokio::time::timeout;
+use tower::ServiceExt;
+use tower_http::add_extension::AddExtensionLayer;
+use tower_http::services::{ServeDir, ServeFile};
+use tracing::{info, instrument, warn, Instrument};
 
-use crate::model::{Image, InstanceStatus, Runtime};
+use crate::config::{self, Config};
+use crate::dns;
+use crate::env::{
+    EC2_BURST, EC2_REGION, LXD_CLIENT_CERT, OCI_AGENTS, READINESS_COLLECTOR_MAX_AGE, SSH_BASTION,
+};
+use crate::files;
+use crate::ip_allowlist::IpAllowlistLayer;
+use crate::leader::{self, Leader};
+use crate::lifecycle::{self, Action};
+use crate::metrics;
+use crate::model::{
+    dedicated_cpu_allocation, new_instance_id, Arch, BackupPolicy, CatalogImage, Conversion,
+    DetachedVolume, Group, HomeVolume, IdempotencyKey, Image, ImageBuild, ImageBuildStatus,
+    ImageFamily, InstanceShare, InstanceStatus, IpAssignment, IpPoolRanges, MaintenanceWindow,
+    PowerSchedule, Priority, PriorityClass, Project, Runtime, SharedMount, State, TopologyRole,
+    TopologyTemplate, Transfer, TransferKind, TransferStatus, User, Volume, IDEMPOTENCY_KEY_TTL,
+    LOCAL_IMAGE_PREFIX, TUNABLE_SYSCTLS,
+};
+use crate::rate_limit::RateLimitLayer;
+use crate::reconcile;
+use crate::s3;
+use crate::scheduler;
+use crate::shutdown;
 use crate::storage::Storage;
 use crate::{
-    auth::UserClaims,
+    auth::{UserClaims, WEBSOCKET_BEARER_PROTOCOL},
     dto::{
-        CreateInstanceRequest, Instance as InstanceDto, ListInstancesResponse,
-        UpdateInstanceRequest,
+        BackupPolicy as BackupPolicyDto, BatchInstanceResult, BatchInstancesRequest,
+        BatchInstancesResponse, CatalogImage as CatalogImageDto, ConvertInstanceRequest,
+        CreateImageBuildRequest, CreateInstanceGroupRequest, CreateInstanceRequest,
+        DeleteClusterQuery, DeleteInstanceQuery, DetachedVolume as DetachedVolumeDto, DryRunQuery,
+        FileQuery, Group as GroupDto, GroupRole as GroupRoleDto, HomeVolume as HomeVolumeDto,
+        HostKeysForm, ImageBuild as ImageBuildDto, Instance as InstanceDto,
+        InstanceGroup as InstanceGroupDto, InstanceGroupTemplate, InstanceHistoryResponse,
+        InstanceMetadata, InstanceOwnerQuery, InstanceSchedulingResponse, InstanceStatusEvent,
+        IpAddress as IpAddressDto, IpAssignment as IpAssignmentDto, IpAssignmentsQuery,
+        IpPool as IpPoolDto, IpPoolQuery, IpRangeRequest, ListGroupsResponse,
+        ListImageBuildsResponse, ListImagesResponse, ListInstanceGroupsResponse,
+        ListInstancesQuery, ListInstancesResponse, ListIpAssignmentsResponse, ListIpPoolsResponse,
+        ListNodesResponse, ListPriorityClassesResponse, ListProjectsResponse,
+        ListTopologyTemplatesResponse, ListVolumesResponse, Node as NodeDto,
+        PowerSchedule as PowerScheduleDto, PriorityClass as PriorityClassDto,
+        Project as ProjectDto, ReconcileQuery, RegisterImageRequest,
+        SchedulingDecision as SchedulingDecisionDto, ShareInstanceRequest,
+        SharedMount as SharedMountDto, SkipScheduleRequest,
+        StatusTransition as StatusTransitionDto, TopologyTemplate as TopologyTemplateDto,
+        Transfer as TransferDto, TransferOwnershipRequest, UpdateInstanceRequest,
+        UpdateNodeRequest, UsageQuery, UsageReport, UsageRow, Volume as VolumeDto,
     },
 };
 use crate::{
-    error::InstanceError,
-    model::{Instance, InstanceStage},
+    error::{AdminError, AuthError, InstanceError},
+    model::{Instance, InstanceGroup, InstanceStage},
 };
 
 static INSTANCE_NAME_REGEX: Lazy<Regex> =
@@ -38,12 +105,1013 @@ fn verify_instance_name(name: &str) -> bool {
     INSTANCE_NAME_REGEX.is_match(name)
 }
 
-pub fn protected_routes() -> Router {
+/// Returns true if the key looks like a single line of an authorized_keys file, such as
+/// `ssh-ed25519 AAAA... user@host`.
+fn verify_ssh_authorized_key(key: &str) -> bool {
+    let mut fields = key.split_whitespace();
+    !key.contains('\n')
+        && matches!(fields.next(), Some(t) if t.starts_with("ssh-") || t.starts_with("ecdsa-"))
+        && fields.next().is_some()
+}
+
+static LABEL_KEY_REGEX: Lazy<Regex> =
+    Lazy::new(|| Regex::new(r"^[a-zA-Z0-9]([-a-zA-Z0-9_./]{0,61}[a-zA-Z0-9])?$").unwrap());
+
+const MAX_LABELS: usize = 64;
+
+const MAX_DESCRIPTION_LEN: usize = 1024;
+
+const MAX_INSTANCE_GROUP_SIZE: usize = 16;
+
+// Header of create requests whose retries must not create the instance again.
+const IDEMPOTENCY_KEY_HEADER: &str = "idempotency-key";
+
+const MAX_IDEMPOTENCY_KEY_LEN: usize = 255;
+
+const SSH_CONNECT_TIMEOUT: Duration = Duration::from_secs(10);
+
+const SSH_PROXY_BUFFER_SIZE: usize = 16 * 1024;
+
+/// Returns true if the option is known to the runtime and its value is valid for an instance of
+/// `cpu` CPUs and `memory` GiB. The VM can't be given more than the instance is allocated.
+fn verify_runtime_option(
+    runtime: &Runtime,
+    key: &str,
+    value: &str,
+    cpu: usize,
+    memory: usize,
+) -> bool {
+    if *runtime != Runtime::Kata {
+        return false;
+    }
+    match key {
+        "default_vcpus" => value.parse::<usize>().map_or(false, |v| v > 0 && v <= cpu),
+        // In MiB.
+        "default_memory" => value
+            .parse::<usize>()
+            .map_or(false, |v| v > 0 && v <= memory * 1024),
+        "enable_hugepages" => value.parse::<bool>().is_ok(),
+        _ => false,
+    }
+}
+
+const MAX_VOLUMES: usize = 8;
+
+// Names of the volumes are prefixed to name the volumes of the pods, which must fit a DNS label.
+const MAX_VOLUME_NAME_LEN: usize = 32;
+
+/// Returns true if the path, without trailing slashes, is absolute and leaves the root, /dev,
+/// /proc and /sys to the runtime.
+fn verify_mount_path(path: &str) -> bool {
+    path.starts_with('/')
+        && !path.split('/').any(|c| c == "." || c == "..")
+        && !["/dev", "/proc", "/sys"]
+            .iter()
+            .any(|p| path == *p || path.starts_with(&format!("{}/", p)))
+}
+
+/// Returns true if the volumes have distinct names and mount paths, and the mount paths are
+/// valid.
+fn verify_volumes(volumes: &[VolumeDto]) -> bool {
+    if volumes.len() > MAX_VOLUMES {
+        return false;
+    }
+    let mut names = HashSet::new();
+    let mut mount_paths = HashSet::new();
+    volumes.iter().all(|v| {
+        let path = v.mount_path.trim_end_matches('/');
+        verify_instance_name(&v.name)
+            && v.name.len() <= MAX_VOLUME_NAME_LEN
+            && (v.size > 0 || !v.source.is_empty())
+            && verify_mount_path(path)
+            && names.insert(v.name.as_str())
+            && mount_paths.insert(path)
+    })
+}
+
+/// Returns true if a volume is mounted at the path of a share or of the home volume of the user,
+/// which the home volume may take later on. Pods can't mount two volumes at the same path.
+fn volumes_collide(volumes: &[VolumeDto], mounts: &[SharedMount], home_path: &str) -> bool {
+    let home_path = home_path.trim_end_matches('/');
+    volumes.iter().any(|v| {
+        let path = v.mount_path.trim_end_matches('/');
+        path == home_path || mounts.iter().any(|m| m.path.trim_end_matches('/') == path)
+    })
+}
+
+/// Returns the node the instance must run on to attach the detached volumes of the user among
+/// `volumes`, as they are local to the node of the instance they were kept from. Volumes kept on
+/// different nodes can't be attached together, nor to an instance pinned to another node.
+fn detached_volume_node(
+    user: &User,
+    volumes: &[VolumeDto],
+    node_name: &str,
+) -> Result<Option<String>, InstanceError> {
+    let nodes: HashSet<&str> = volumes
+        .iter()
+        .filter_map(|v| user.find_detached_volume(&v.source))
+        .filter_map(|v| v.node_name.as_deref())
+        .collect();
+    if nodes.len() > 1
+        || nodes
+            .iter()
+            .any(|n| !node_name.is_empty() && node_name != *n)
+    {
+        return Err(InstanceError::InvalidArgs("volumes".to_string()));
+    }
+    Ok(nodes.into_iter().next().map(|n| n.to_owned()))
+}
+
+/// Returns the volumes of a new instance of the user, taking the detached volumes named as their
+/// sources away from the user.
+fn attach_volumes(user: &mut User, volumes: &[VolumeDto]) -> Result<Vec<Volume>, InstanceError> {
+    let mut attached = Vec::new();
+    for v in volumes {
+        let (size, claim_name) = if v.source.is_empty() {
+            (v.size, None)
+        } else {
+            let i = user
+                .detached_volumes
+                .iter()
+                .position(|d| d.name == v.source)
+                .ok_or(InstanceError::VolumeNotFound)?;
+            let d = user.detached_volumes.remove(i);
+            (d.size, Some(d.claim_name))
+        };
+        attached.push(Volume {
+            name: v.name.clone(),
+            size,
+            mount_path: v.mount_path.clone(),
+            claim_name,
+        });
+    }
+    Ok(attached)
+}
+
+/// Deletes the instance of the user, keeping its root disk as a detached volume of the user.
+/// Returns false if the instance doesn't exist or is already deleted.
+fn keep_root_disk(user: &mut User, instance_name: &str, now: i64) -> Result<bool, InstanceError> {
+    let volume_exists = user.find_detached_volume(instance_name).is_some();
+    let username = user.username.clone();
+    let instance = match user.find_mut_instance(instance_name) {
+        Some(i) if !lifecycle::is_terminal(&i.stage) => i,
+        _ => return Ok(false),
+    };
+    // Only the root disks of the Kubernetes runtimes are volumes of their own, LXD root disks
+    // can't outlive their instances.
+    if !instance.runtime.supports_volumes() {
+        return Err(InstanceError::KeepDiskUnsupported {
+            runtime: instance.runtime.to_string(),
+        });
+    }
+    if volume_exists {
+        return Err(InstanceError::VolumeAlreadyExists);
+    }
+    lifecycle::apply(instance, Action::Delete)?;
+    instance.keep_disk = true;
+    instance.add_event("root disk kept as a detached volume".to_string());
+    let volume = DetachedVolume {
+        name: instance.name.clone(),
+        size: instance.disk_size,
+        claim_name: format!("{}-rootfs", instance.backend_name(&username)),
+        node_name: instance.node_name.clone(),
+        image: instance.image.clone(),
+        created_at: now,
+    };
+    user.detached_volumes.push(volume);
+    Ok(true)
+}
+
+/// Returns true if the PersistentVolumeClaim backs a detached volume, or a volume attached from
+/// one.
+fn claim_in_use(state: &State, claim_name: &str) -> bool {
+    state.users.iter().any(|u| {
+        u.detached_volumes
+            .iter()
+            .any(|v| v.claim_name == claim_name)
+            || u.instances
+                .iter()
+                .flat_map(|i| &i.volumes)
+                .any(|v| v.claim_name.as_deref() == Some(claim_name))
+    })
+}
+
+/// Returns true if the shares come from allowed host paths or NFS exports, and are mounted at
+/// distinct valid paths.
+fn verify_shared_mounts(mounts: &[SharedMountDto]) -> bool {
+    let config = config::current();
+    let mut paths = HashSet::new();
+    mounts.iter().all(|m| {
+        let path = m.path.trim_end_matches('/');
+        let mount = SharedMount {
+            source: m.source.clone(),
+            path: path.to_owned(),
+            read_only: m.read_only,
+        };
+        (m.source.starts_with('/') || mount.nfs_export().is_some())
+            && config.shared_mount_allowed(&m.source)
+            && verify_mount_path(path)
+            && paths.insert(path)
+    })
+}
+
+/// Returns true if the sysctl may be tuned on instances of the runtime and its value is a positive
+/// integer.
+fn verify_sysctl(runtime: &Runtime, key: &str, value: &str) -> bool {
+    TUNABLE_SYSCTLS.contains(&key)
+        && runtime.supports_sysctl(key)
+        && value.parse::<u64>().map_or(false, |v| v > 0)
+}
+
+/// Returns true if the label is safe to expose to the guest, where scripts may turn the keys into
+/// file names or variable names.
+fn verify_label(key: &str, value: &str) -> bool {
+    LABEL_KEY_REGEX.is_match(key) && value.len() <= 255 && !value.contains('\n')
+}
+
+/// Returns true if the description fits and has no control characters other than line breaks.
+fn verify_description(description: &str) -> bool {
+    description.chars().count() <= MAX_DESCRIPTION_LEN
+        && !description.chars().any(|c| c.is_control() && c != '\n')
+}
+
+static LXD_IMAGE_ALIAS_REGEX: Lazy<Regex> =
+    Lazy::new(|| Regex::new(r"^[a-zA-Z0-9][-a-zA-Z0-9._/]{0,254}$").unwrap());
+
+static OCI_IMAGE_REGEX: Lazy<Regex> = Lazy::new(|| {
+    Regex::new(
+        r"^[a-z0-9]+([._-][a-z0-9]+)*(:[0-9]+)?(/[a-z0-9]+([._-][a-z0-9]+)*)+(:[a-zA-Z0-9_][-a-zA-Z0-9_.]{0,127}|@sha256:[0-9a-f]{64})$",
+    )
+    .unwrap()
+});
+
+/// Returns true if the reference names a repository of a registry along with a tag or digest,
+/// such as `ghcr.io/acme/dev:1.0`.
+fn verify_oci_image(reference: &str) -> bool {
+    OCI_IMAGE_REGEX.is_match(reference)
+}
+
+/// Returns true if the admins allow users to register images from the source.
+fn custom_image_source_allowed(source: &str) -> bool {
+    config::current()
+        .custom_image_allowlist
+        .iter()
+        .any(|prefix| source.starts_with(prefix.as_str()))
+}
+
+/// Validates the external IP requested for the instance, or the IP pinned by a deleted instance of
+/// the same name if none is requested, and returns it along with its pool and whether it was
+/// pinned. Claiming a pinned IP lifts the reservation, as the new instance holds the IP then.
+fn reserve_external_ip(
+    state: &mut State,
+    username: &str,
+    req: &CreateInstanceRequest,
+) -> Result<Option<(String, String, bool)>, InstanceError> {
+    let same_instance = |a: &IpAssignment| a.username == username && a.instance == req.name;
+    let pinned = state
+        .ip_assignments
+        .iter()
+        .rev()
+        .find(|a| a.released_at.is_some() && a.pinned && same_instance(a))
+        .map(|a| a.ip.clone());
+    let ip = if !req.external_ip.is_empty() {
+        req.external_ip.clone()
+    } else {
+        match &pinned {
+            Some(ip) => ip.clone(),
+            None => return Ok(None),
+        }
+    };
+
+    let config = config::current();
+    let pool = state
+        .ip_pools()
+        .into_iter()
+        .find(|(_, ips)| ips.contains(&ip))
+        .map(|(p, _)| p)
+        .ok_or_else(|| InstanceError::InvalidArgs("external_ip".to_string()))?;
+    if !req.node_name.is_empty() && !pool.serves(&req.node_name) {
+        return Err(InstanceError::InvalidArgs("external_ip".to_string()));
+    }
+    let held = state
+        .users
+        .iter()
+        .flat_map(|u| &u.instances)
+        .any(|i| i.external_ip.as_deref() == Some(ip.as_str()));
+    let grace_period = config.ip_release_grace_period as i64 * 60;
+    let now = Utc::now().timestamp();
+    let reserved = state
+        .ip_assignments
+        .iter()
+        .any(|a| a.ip == ip && !same_instance(a) && a.is_reserved(now, grace_period));
+    if held || reserved {
+        return Err(InstanceError::IpUnavailable(ip));
+    }
+
+    let claimed = pinned.as_deref() == Some(ip.as_str());
+    for a in &mut state.ip_assignments {
+        if a.ip == ip && same_instance(a) {
+            a.pinned = false;
+        }
+    }
+    Ok(Some((pool.name, ip, claimed)))
+}
+
+/// Fails if the user can't pin another external IP, counting the IPs pinned by the instances of
+/// the user and the IPs still reserved for deleted instances of the user.
+fn check_pinned_ip_limit(state: &State, username: &str) -> Result<(), InstanceError> {
+    let limit = config::current().max_pinned_ips_per_user;
+    if limit == 0 {
+        return Ok(());
+    }
+    let held = state
+        .find_user(username)
+        .map_or(0, |u| u.instances.iter().filter(|i| i.ip_pinned).count());
+    let reserved = state
+        .ip_assignments
+        .iter()
+        .filter(|a| a.released_at.is_some() && a.pinned && a.username == username)
+        .count();
+    if held + reserved + 1 > limit {
+        return Err(InstanceError::QuotaExceeded {
+            resource: "Pinned IP".to_string(),
+            quota: limit,
+            remaining: limit.saturating_sub(held + reserved),
+            requested: 1,
+            unit: "".to_string(),
+        });
+    }
+    Ok(())
+}
+
+/// Returns the addresses of the IP pools held by instances or reserved after their release, with
+/// the status and the instance filled in.
+fn ip_allocations(state: &State) -> HashMap<String, IpAddressDto> {
+    let mut allocations = HashMap::new();
+    let grace_period = config::current().ip_release_grace_period as i64 * 60;
+    let now = Utc::now().timestamp();
+    for a in &state.ip_assignments {
+        if a.is_reserved(now, grace_period) {
+            allocations.insert(
+                a.ip.clone(),
+                IpAddressDto {
+                    ip: a.ip.clone(),
+                    status: "reserved".to_owned(),
+                    username: Some(a.username.clone()),
+                    instance: Some(a.instance.clone()),
+                },
+            );
+        }
+    }
+    for u in &state.users {
+        for i in u.instances.iter().filter(|i| scheduler::uses_ip_pools(i)) {
+            if let Some(ip) = &i.external_ip {
+                allocations.insert(
+                    ip.clone(),
+                    IpAddressDto {
+                        ip: ip.clone(),
+                        status: "allocated".to_owned(),
+                        username: Some(u.username.clone()),
+                        instance: Some(i.name.clone()),
+                    },
+                );
+            }
+        }
+    }
+    allocations
+}
+
+fn build_ip_pool_dto(
+    state: &State,
+    pool: &config::IpPool,
+    ips: &[String],
+    allocations: &HashMap<String, IpAddressDto>,
+) -> IpPoolDto {
+    IpPoolDto {
+        name: pool.name.clone(),
+        ranges: pool.ranges.clone(),
+        exclude: pool.exclude.clone(),
+        nodes: pool.nodes.clone(),
+        edited: state.ip_pool_ranges.iter().any(|r| r.pool == pool.name),
+        addresses: ips
+            .iter()
+            .map(|ip| {
+                allocations
+                    .get(ip)
+                    .cloned()
+                    .unwrap_or_else(|| IpAddressDto {
+                        ip: ip.clone(),
+                        status: "free".to_owned(),
+                        ..Default::default()
+                    })
+            })
+            .collect(),
+    }
+}
+
+/// Adds the range to or removes it from the pool, or its exclusions. Addresses may neither end up
+/// in two pools nor be taken out of the pool while they are allocated or reserved.
+fn edit_ip_pool(
+    state: &mut State,
+    req: &IpRangeRequest,
+    add: bool,
+) -> Result<IpPoolDto, AdminError> {
+    let pool_name = if req.pool.is_empty() {
+        config::DEFAULT_IP_POOL
+    } else {
+        req.pool.as_str()
+    };
+    let pools = state.ip_pools();
+    let (mut pool, old_ips) = pools
+        .iter()
+        .find(|(p, _)| p.name == pool_name)
+        .cloned()
+        .ok_or_else(|| AdminError::IpPoolNotFound(pool_name.to_owned()))?;
+    let entries = if req.exclude {
+        &mut pool.exclude
+    } else {
+        &mut pool.ranges
+    };
+    if add {
+        if entries.contains(&req.range) {
+            return Err(AdminError::InvalidArgs("range".to_owned()));
+        }
+        entries.push(req.range.clone());
+    } else {
+        let len = entries.len();
+        entries.retain(|r| r != &req.range);
+        if entries.len() == len {
+            return Err(AdminError::InvalidArgs("range".to_owned()));
+        }
+    }
+    let ips = pool
+        .addresses()
+        .map_err(|_| AdminError::InvalidArgs("range".to_owned()))?;
+
+    let new_ips: HashSet<&String> = ips.iter().collect();
+    for (p, other_ips) in pools.iter().filter(|(p, _)| p.name != pool.name) {
+        if let Some(ip) = other_ips.iter().find(|ip| new_ips.contains(ip)) {
+            return Err(AdminError::IpRangeConflict(format!(
+                "{} is in pool {}",
+                ip, p.name
+            )));
+        }
+    }
+    let allocations = ip_allocations(state);
+    for ip in old_ips.iter().filter(|ip| !new_ips.contains(ip)) {
+        if let Some(a) = allocations.get(ip) {
+            return Err(AdminError::IpRangeConflict(format!(
+                "{} is {} by instance {} of user {}",
+                ip,
+                a.status,
+                a.instance.as_deref().unwrap_or_default(),
+                a.username.as_deref().unwrap_or_default()
+            )));
+        }
+    }
+
+    let ranges = IpPoolRanges {
+        pool: pool.name.clone(),
+        ranges: pool.ranges.clone(),
+        exclude: pool.exclude.clone(),
+    };
+    match state
+        .ip_pool_ranges
+        .iter_mut()
+        .find(|r| r.pool == pool.name)
+    {
+        Some(r) => *r = ranges,
+        None => state.ip_pool_ranges.push(ranges),
+    }
+    Ok(build_ip_pool_dto(state, &pool, &ips, &allocations))
+}
+
+/// Returns the error if `instances` together with an instance of the given resources exceed the
+/// quotas. `new_instance` is whether the instance is not among `instances` yet.
+fn check_aggregate_quotas<'a>(
+    owner: &str,
+    quotas: [usize; 4],
+    instances: impl Iterator<Item = &'a Instance>,
+    new_instance: bool,
+    cpu: usize,
+    memory: usize,
+    disk_size: usize,
+) -> Option<InstanceError> {
+    let [instance_quota, cpu_quota, memory_quota, disk_quota] = quotas;
+    let (mut count, mut total_cpu, mut total_memory, mut total_disk_size) = (0, 0, 0, 0);
+    for i in instances {
+        count += 1;
+        total_disk_size += i.total_disk_size();
+        if i.counts_towards_compute_quota() {
+            total_cpu += i.cpu;
+            total_memory += i.memory;
+        }
+    }
+    let checks = [
+        (
+            "instance",
+            count,
+            instance_quota,
+            usize::from(new_instance),
+            "",
+        ),
+        ("CPU", total_cpu, cpu_quota, cpu, "C"),
+        ("memory", total_memory, memory_quota, memory, "GiB"),
+        ("disk size", total_disk_size, disk_quota, disk_size, "GiB"),
+    ];
+    for (resource, used, quota, requested, unit) in checks {
+        if requested > 0 && used + requested > quota {
+            return Some(InstanceError::QuotaExceeded {
+                resource: format!("{} {}", owner, resource),
+                quota,
+                remaining: quota.saturating_sub(used),
+                requested,
+                unit: unit.to_string(),
+            });
+        }
+    }
+    None
+}
+
+/// Returns the error if the instance of the user, with the given resources, would exceed the
+/// quota of any group the user is a member of or of the project of the instance. The current
+/// resources of the instance, if it exists, are not counted.
+fn check_shared_quotas(
+    state: &State,
+    username: &str,
+    instance_name: &str,
+    project: Option<&str>,
+    cpu: usize,
+    memory: usize,
+    disk_size: usize,
+) -> Option<InstanceError> {
+    let exists = state
+        .find_user(username)
+        .and_then(|u| u.find_instance(instance_name))
+        .is_some();
+    // All instances except the one being checked, along with their owners.
+    let others = || {
+        state.users.iter().flat_map(move |u| {
+            u.instances
+                .iter()
+                .filter(move |i| !(u.username == username && i.name == instance_name))
+                .map(move |i| (u.username.as_str(), i))
+        })
+    };
+    for g in state.groups_of(username) {
+        let instances = others()
+            .filter(|(owner, _)| g.members.iter().any(|m| m == owner))
+            .map(|(_, i)| i);
+        let quotas = [g.instance_quota, g.cpu_quota, g.memory_quota, g.disk_quota];
+        let owner = format!("Group {}", g.name);
+        if let Some(e) =
+            check_aggregate_quotas(&owner, quotas, instances, !exists, cpu, memory, disk_size)
+        {
+            return Some(e);
+        }
+    }
+    if let Some(p) = project.and_then(|p| state.find_project(p)) {
+        let instances = others()
+            .filter(|(_, i)| i.project.as_deref() == Some(p.name.as_str()))
+            .map(|(_, i)| i);
+        let quotas = [p.instance_quota, p.cpu_quota, p.memory_quota, p.disk_quota];
+        let owner = format!("Project {}", p.name);
+        if let Some(e) =
+            check_aggregate_quotas(&owner, quotas, instances, !exists, cpu, memory, disk_size)
+        {
+            return Some(e);
+        }
+    }
+    None
+}
+
+/// Returns the error if an instance of the given resources would exceed the size limits of a
+/// single instance.
+fn check_size_limits(cpu: usize, memory: usize, disk_size: usize) -> Option<InstanceError> {
+    let config = config::current();
+    for (resource, limit, requested, unit) in [
+        ("CPU", config.max_instance_cpu, cpu, "C"),
+        ("Memory", config.max_instance_memory, memory, "GiB"),
+        ("Disk size", config.max_instance_disk_size, disk_size, "GiB"),
+    ] {
+        if limit > 0 && requested > limit {
+            return Some(InstanceError::SizeLimitExceeded {
+                resource: resource.to_string(),
+                limit,
+                requested,
+                unit: unit.to_string(),
+            });
+        }
+    }
+    let (min, max) = (config.min_memory_per_cpu, config.max_memory_per_cpu);
+    let memory_per_cpu = memory as f64 / cpu as f64;
+    if memory_per_cpu < min || max > 0.0 && memory_per_cpu > max {
+        let allowed = if max == 0.0 {
+            format!("at least {}", min)
+        } else if min == 0.0 {
+            format!("at most {}", max)
+        } else {
+            format!("within {}-{}", min, max)
+        };
+        return Some(InstanceError::MemoryPerCpuUnallowed {
+            cpu,
+            memory,
+            allowed,
+        });
+    }
+    None
+}
+
+/// Returns a copy of the running instance accessible to the user.
+async fn find_running_instance(
+    storage: &Storage,
+    username: &str,
+    owner: Option<&str>,
+    name: &str,
+) -> Result<Instance, InstanceError> {
+    let mut instance = None;
+    storage
+        .read_only(|state| {
+            instance = state
+                .find_accessible_instance(username, owner, name)
+                .cloned();
+        })
+        .await;
+    match instance {
+        Some(i) if i.status == InstanceStatus::Running => Ok(i),
+        Some(_) => Err(InstanceError::NotRunning),
+        None => Err(InstanceError::NotFound),
+    }
+}
+
+/// Checks whether the user may transfer files of the instance of the owner, which reads and
+/// writes them as root. Sharing only grants that along with the keys to log in as root.
+fn check_file_access(
+    instance: &Instance,
+    username: &str,
+    owner: Option<&str>,
+) -> Result<(), InstanceError> {
+    match owner {
+        Some(owner) if owner != username && !instance.grants_ssh_to(username) => {
+            Err(InstanceError::FilesForbidden)
+        }
+        _ => Ok(()),
+    }
+}
+
+/// Returns true if the path is absolute, as the backends resolve relative paths differently.
+fn verify_file_path(path: &str) -> bool {
+    path.starts_with('/') && !path.contains('\0')
+}
+
+/// Records a file transfer in the events of the instance for auditing.
+async fn record_file_transfer(
+    storage: &Storage,
+    username: &str,
+    owner: Option<&str>,
+    name: &str,
+    event: String,
+) {
+    if let Err(e) = storage
+        .read_write(
+            |state| match state.find_mut_accessible_instance(username, owner, name) {
+                Some(instance) => {
+                    instance.add_event(event.clone());
+                    true
+                }
+                None => false,
+            },
+        )
+        .await
+    {
+        warn!(
+            error = e.to_string().as_str(),
+            "recording file transfer encountered error"
+        );
+    }
+}
+
+/// Relays the frames of `socket` to `stream` and the bytes read from `stream` back as binary
+/// frames until either side closes.
+async fn bridge_ssh(socket: WebSocket, stream: TcpStream) {
+    let (mut ws_tx, mut ws_rx) = socket.split();
+    let (mut tcp_rx, mut tcp_tx) = stream.into_split();
+    let upstream = async {
+        while let Some(Ok(msg)) = ws_rx.next().await {
+            let data = match msg {
+                Message::Binary(data) => data,
+                Message::Text(text) => text.into_bytes(),
+                Message::Close(_) => break,
+                Message::Ping(_) | Message::Pong(_) => continue,
+            };
+            if tcp_tx.write_all(&data).await.is_err() {
+                break;
+            }
+        }
+        let _ = tcp_tx.shutdown().await;
+    };
+    let downstream = async {
+        let mut buf = vec![0; SSH_PROXY_BUFFER_SIZE];
+        loop {
+            match tcp_rx.read(&mut buf).await {
+                Ok(0) | Err(_) => break,
+                Ok(n) => {
+                    if ws_tx
+                        .send(Message::Binary(buf[..n].to_vec()))
+                        .await
+                        .is_err()
+                    {
+                        return;
+                    }
+                }
+            }
+        }
+        let _ = ws_tx.send(Message::Close(None)).await;
+    };
+    tokio::select! {
+        _ = upstream => {}
+        _ = downstream => {}
+    }
+}
+
+/// Returns the entity tag of the instances listed for the user by the query at the revision of
+/// the state. Fields derived from the time, e.g. the uptime, are as of the last change.
+fn list_instances_etag(revision: u64, username: &str, query: &ListInstancesQuery) -> String {
+    let digest = digest::digest(
+        &digest::SHA256,
+        format!(
+            "{}/{}/{}",
+            revision,
+            username,
+            serde_json::to_string(query).unwrap()
+        )
+        .as_bytes(),
+    );
+    format!(
+        "W/\"{}\"",
+        base64::encode_config(&digest.as_ref()[..16], base64::URL_SAFE_NO_PAD)
+    )
+}
+
+/// Returns true if the If-None-Match header of the request matches the entity tag.
+fn etag_matches(headers: &HeaderMap, etag: &str) -> bool {
+    let opaque = |t: &str| t.trim().trim_start_matches("W/").to_owned();
+    headers
+        .get_all(IF_NONE_MATCH)
+        .iter()
+        .filter_map(|v| v.to_str().ok())
+        .flat_map(|v| v.split(','))
+        .any(|t| t.trim() == "*" || opaque(t) == opaque(etag))
+}
+
+/// Returns true if the user may view the instance of the owner, which is the case if it's shared
+/// with the user or belongs to a project the user is a member of.
+fn is_visible(state: &State, user: &UserClaims, owner: &str, instance: &Instance) -> bool {
+    owner == user.username
+        || instance.is_shared_with(&user.username)
+        || instance.project.as_ref().map_or(false, |p| {
+            state
+                .find_project(p)
+                .map_or(false, |p| p.is_member(&user.username) || user.is_admin())
+        })
+}
+
+/// Fills in the fields of an instance of the owner which depend on the user viewing it.
+fn present_instance(mut instance: InstanceDto, user: &UserClaims, owner: &str) -> InstanceDto {
+    if owner != user.username {
+        instance.owner = owner.to_owned();
+        // Only the owner may see the password.
+        instance.password = String::new();
+    }
+    if instance.external_ip.is_some() {
+        instance.dns_name = dns::record_name(owner, &instance.name);
+    }
+    instance
+}
+
+/// Returns the group along with its members, presented to the user as in `present_instance`.
+fn present_instance_group(
+    state: &State,
+    user: &UserClaims,
+    group: &InstanceGroup,
+) -> InstanceGroupDto {
+    let members: Vec<InstanceDto> = state
+        .find_user(&group.owner)
+        .into_iter()
+        .flat_map(|u| &u.instances)
+        .filter(|i| i.instance_group.as_deref() == Some(group.name.as_str()))
+        .map(|i| present_instance(InstanceDto::from(i), user, &group.owner))
+        .collect();
+    let roles = group
+        .ports
+        .iter()
+        .map(|(role, port)| {
+            let endpoints = members
+                .iter()
+                .filter(|m| m.group_role.as_ref() == Some(role))
+                .filter_map(|m| {
+                    // Only instances with an external IP have DNS records.
+                    let host = match &m.external_ip {
+                        Some(ip) => dns::group_record_name(&group.owner, &group.name, &m.name)
+                            .unwrap_or_else(|| ip.clone()),
+                        None => m.internal_ip.clone()?,
+                    };
+                    Some(match port {
+                        0 => host,
+                        port => format!("{}:{}", host, port),
+                    })
+                })
+                .collect();
+            GroupRoleDto {
+                name: role.clone(),
+                port: *port,
+                endpoints,
+            }
+        })
+        .collect();
+    InstanceGroupDto {
+        name: group.name.clone(),
+        size: group.size,
+        labels: group.labels.clone(),
+        members,
+        created_at: group.created_at,
+        topology: group.topology.clone(),
+        roles,
+        deleted_at: group.deleted_at,
+    }
+}
+
+/// Returns the public key of the file content like `ssh-ed25519 AAAA... root@host` without the
+/// comment, or None if it doesn't look like a public key.
+fn parse_host_key(content: &str) -> Option<String> {
+    let mut fields = content.split_whitespace();
+    let (kind, blob) = (fields.next()?, fields.next()?);
+    if !(kind.starts_with("ssh-") || kind.starts_with("ecdsa-")) || base64::decode(blob).is_err() {
+        return None;
+    }
+    Some(format!("{} {}", kind, blob))
+}
+
+/// Sorts the listed instances by the field named by `sort`, see `ListInstancesQuery::sort`.
+fn sort_instances(instances: &mut [InstanceDto], sort: &str) -> Result<(), InstanceError> {
+    if sort.is_empty() {
+        return Ok(());
+    }
+    let (field, descending) = match sort.strip_prefix('-') {
+        Some(field) => (field, true),
+        None => (sort, false),
+    };
+    match field {
+        "name" => instances.sort_by(|a, b| (&a.name, &a.owner).cmp(&(&b.name, &b.owner))),
+        "created_at" => instances.sort_by_key(|i| i.created_at),
+        "updated_at" => instances.sort_by_key(|i| i.updated_at),
+        _ => return Err(InstanceError::InvalidArgs("sort".to_string())),
+    }
+    if descending {
+        instances.reverse();
+    }
+    Ok(())
+}
+
+/// Versions of the API, each one served under its own prefix, e.g. `/v1/instances`, so that
+/// breaking changes ship as a new version while the clients of the previous ones keep working.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord)]
+crate enum ApiVersion {
+    V1,
+}
+
+impl ApiVersion {
+    crate const ALL: [ApiVersion; 1] = [ApiVersion::V1];
+
+    crate fn prefix(self) -> &'static str {
+        match self {
+            ApiVersion::V1 => "/v1",
+        }
+    }
+}
+
+/// Extractor of the version of the API the request is served by, for handlers whose requests or
+/// responses differ between the versions.
+#[async_trait]
+impl<B> FromRequest<B> for ApiVersion
+where
+    B: Send,
+{
+    type Rejection = Infallible;
+
+    async fn from_request(req: &mut RequestParts<B>) -> Result<Self, Self::Rejection> {
+        Ok(req
+            .extensions()
+            .and_then(|e| e.get::<ApiVersion>())
+            .copied()
+            .unwrap_or(ApiVersion::V1))
+    }
+}
+
+/// Returns the routes of the API under the prefix of each version. The unversioned paths are
+/// aliases of `/v1` kept for the deployed clients, until they have moved to the prefixed paths.
+pub fn api_routes() -> Router {
+    let mut router = Router::new();
+    for version in ApiVersion::ALL {
+        // The aliases share the routes, and thus the rate limits, with their version.
+        let routes = protected_routes()
+            .merge(admin_routes())
+            .layer(AddExtensionLayer::new(version))
+            .layer(IpAllowlistLayer::new(Config::api_allowed));
+        if version == ApiVersion::V1 {
+            router = router.merge(routes.clone());
+        }
+        router = router.nest(version.prefix(), routes);
+    }
+    router
+}
+
+/// Rewrites the paths which overlap with other routes, and which the router therefore rejects,
+/// to the routes serving them, e.g. `/instances/by-id/:id` overlaps with
+/// `/instances/:instance_name/start` and is served by `/instances-by-id/:id`, while
+/// `/instances:batch` overlaps with `/instances` and is served by `/batch/instances`.
+/// Likewise `/instances/events` overlaps with `/instances/:instance_name` and is served by
+/// `/events/instances`. It must run before the routing.
+///
+/// The routes serving the rewritten paths are internal, their handlers take `Rewritten` to
+/// reject the requests addressing them directly.
+pub fn rewrite_overlapping_paths<B>(mut req: Request<B>) -> Request<B> {
+    let path = req.uri().path();
+    let prefix = ApiVersion::ALL
+        .iter()
+        .map(|v| v.prefix())
+        .find(|p| {
+            path.strip_prefix(p)
+                .map_or(false, |rest| rest.starts_with('/'))
+        })
+        .unwrap_or_default();
+    let rewritten = match &path[prefix.len()..] {
+        "/instances:batch" => format!("{}/batch/instances", prefix),
+        "/instances/events" => format!("{}/events/instances", prefix),
+        rest => match rest.strip_prefix("/instances/by-id/") {
+            Some(id) => format!("{}/instances-by-id/{}", prefix, id),
+            None => return req,
+        },
+    };
+    let path_and_query = match req.uri().query() {
+        Some(query) => format!("{}?{}", rewritten, query),
+        None => rewritten,
+    };
+    let mut parts = req.uri().clone().into_parts();
+    // The path comes from a valid URI, so it parses again.
+    if let Ok(path_and_query) = path_and_query.parse() {
+        parts.path_and_query = Some(path_and_query);
+        if let Ok(uri) = Uri::from_parts(parts) {
+            *req.uri_mut() = uri;
+            req.extensions_mut().insert(Rewritten);
+        }
+    }
+    req
+}
+
+/// Extractor rejecting with 404 the requests whose path was not rewritten by
+/// `rewrite_overlapping_paths`, so that the internal routes are not part of the API.
+#[derive(Debug, Clone, Copy)]
+crate struct Rewritten;
+
+#[async_trait]
+impl<B> FromRequest<B> for Rewritten
+where
+    B: Send,
+{
+    type Rejection = StatusCode;
+
+    async fn from_request(req: &mut RequestParts<B>) -> Result<Self, Self::Rejection> {
+        req.extensions()
+            .and_then(|e| e.get::<Rewritten>())
+            .copied()
+            .ok_or(StatusCode::NOT_FOUND)
+    }
+}
+
+fn protected_routes() -> Router {
+    #[instrument(skip_all, fields(username = %user.username, instance = %req.name))]
     async fn create_instance(
+        _leader: Leader,
         user: UserClaims,
-        Json(req): Json<CreateInstanceRequest>,
+        Query(query): Query<DryRunQuery>,
+        Json(mut req): Json<CreateInstanceRequest>,
+        headers: HeaderMap,
         Extension(storage): Extension<Storage>,
     ) -> Result<impl IntoResponse, InstanceError> {
+        let idempotency_key = match headers.get(IDEMPOTENCY_KEY_HEADER) {
+            Some(v) => match v.to_str() {
+                Ok(k) if !k.is_empty() && k.len() <= MAX_IDEMPOTENCY_KEY_LEN => Some(k.to_owned()),
+                _ => return Err(InstanceError::InvalidArgs("Idempotency-Key".to_string())),
+            },
+            None => None,
+        };
+        // A dry run creates nothing to be replayed later.
+        let idempotency_key = idempotency_key.filter(|_| !query.dry_run);
+        let request_digest = base64::encode(digest::digest(
+            &digest::SHA256,
+            &serde_json::to_vec(&req).unwrap(),
+        ));
         if !verify_instance_name(req.name.as_str()) {
             return Err(InstanceError::InvalidArgs("name".to_string()));
         }
@@ -56,142 +1124,499 @@ pub fn protected_routes() -> Router {
         if req.disk_size == 0 {
             return Err(InstanceError::InvalidArgs("disk_size".to_string()));
         }
-        if req.image.is_empty() {
-            return Err(InstanceError::InvalidArgs("image".to_string()));
-        }
         if req.runtime.is_empty() {
             return Err(InstanceError::InvalidArgs("runtime".to_string()));
         }
-        let image: Image = req
-            .image
-            .parse()
-            .map_err(|_| InstanceError::InvalidArgs("image".to_string()))?;
-        let runtime: Runtime = req
+        // The root disk and the volumes are allocated out of the same storage, where detached
+        // volumes being attached already take their room.
+        let total_disk_size = req.disk_size
+            + req
+                .volumes
+                .iter()
+                .filter(|v| v.source.is_empty())
+                .map(|v| v.size)
+                .sum::<usize>();
+        if let Some(e) = check_size_limits(req.cpu, req.memory, total_disk_size) {
+            return Err(e);
+        }
+        if req
+            .ssh_authorized_keys
+            .iter()
+            .any(|k| !verify_ssh_authorized_key(k))
+        {
+            return Err(InstanceError::InvalidArgs(
+                "ssh_authorized_keys".to_string(),
+            ));
+        }
+        if req.labels.len() > MAX_LABELS || req.labels.iter().any(|(k, v)| !verify_label(k, v)) {
+            return Err(InstanceError::InvalidArgs("labels".to_string()));
+        }
+        if !verify_description(&req.description) {
+            return Err(InstanceError::InvalidArgs("description".to_string()));
+        }
+        let priority: Priority = if req.priority.is_empty() {
+            Priority::Normal
+        } else {
+            req.priority
+                .parse()
+                .map_err(|_| InstanceError::InvalidArgs("priority".to_string()))?
+        };
+        // The default image of the catalog is used if none is specified.
+        let image: Option<Image> = if req.image.is_empty() {
+            None
+        } else {
+            Some(
+                req.image
+                    .parse()
+                    .map_err(|_| InstanceError::InvalidArgs("image".to_string()))?,
+            )
+        };
+        let mut runtime: Runtime = req
             .runtime
             .parse()
             .map_err(|_| InstanceError::InvalidArgs("runtime".to_owned()))?;
-        if !runtime.supported_images().contains(&image) {
-            return Err(InstanceError::ImageUnavailable {
-                image: image.to_string(),
+        let arch: Arch = if req.arch.is_empty() {
+            Arch::Amd64
+        } else {
+            req.arch
+                .parse()
+                .map_err(|_| InstanceError::InvalidArgs("arch".to_owned()))?
+        };
+        // EC2 instance types and images are only configured for amd64.
+        if runtime == Runtime::Ec2 && arch != Arch::Amd64 {
+            return Err(InstanceError::ArchUnavailable {
+                arch: arch.to_string(),
                 runtime: runtime.to_string(),
             });
         }
-        if !req.storage_pool.is_empty() && (runtime == Runtime::Kata || runtime == Runtime::Runc) {
+        if !req.storage_pool.is_empty()
+            && matches!(
+                runtime,
+                Runtime::Kata | Runtime::Runc | Runtime::Oci | Runtime::Ec2
+            )
+        {
             return Err(InstanceError::StoragePoolCannotBeSpecified {
                 runtime: runtime.to_string(),
             });
         }
+        if runtime == Runtime::Ec2 {
+            if EC2_REGION.is_empty() {
+                return Err(InstanceError::RuntimeUnavailable {
+                    runtime: runtime.to_string(),
+                });
+            }
+            if !req.node_name.is_empty() {
+                return Err(InstanceError::NodeCannotBeSpecified {
+                    runtime: runtime.to_string(),
+                });
+            }
+        }
+        if runtime == Runtime::Oci && OCI_AGENTS.is_empty() {
+            return Err(InstanceError::RuntimeUnavailable {
+                runtime: runtime.to_string(),
+            });
+        }
+        if !req.backup.is_empty() {
+            if runtime != Runtime::Lxc && runtime != Runtime::Kvm {
+                return Err(InstanceError::TransferUnsupported {
+                    runtime: runtime.to_string(),
+                });
+            }
+            if !s3::is_configured() {
+                return Err(InstanceError::BackupStorageUnavailable);
+            }
+            // Users can only import the backups exported from their own instances.
+            let own = req
+                .backup
+                .strip_prefix(&format!("{}/", user.username))
+                .map_or(false, |key| {
+                    !key.split('/').any(|p| p.is_empty() || p == "..")
+                });
+            if !own {
+                return Err(InstanceError::InvalidArgs("backup".to_string()));
+            }
+        }
+        for (field, limit) in [
+            ("ingress_limit", req.ingress_limit),
+            ("egress_limit", req.egress_limit),
+        ] {
+            if limit.is_some() && (limit == Some(0) || !runtime.supports_bandwidth_limits()) {
+                return Err(InstanceError::InvalidArgs(field.to_string()));
+            }
+        }
+        if req.dedicated_cpu && !runtime.supports_dedicated_cpu() {
+            return Err(InstanceError::InvalidArgs("dedicated_cpu".to_string()));
+        }
+        for (field, enabled) in [
+            ("nesting", req.nesting),
+            ("kvm_passthrough", req.kvm_passthrough),
+        ] {
+            if enabled && !runtime.supports_nesting() {
+                return Err(InstanceError::InvalidArgs(field.to_string()));
+            }
+        }
+        if req
+            .runtime_options
+            .iter()
+            .any(|(k, v)| !verify_runtime_option(&runtime, k, v, req.cpu, req.memory))
+        {
+            return Err(InstanceError::InvalidArgs("runtime_options".to_string()));
+        }
+        if !req.sysctls.is_empty()
+            && (!runtime.supports_sysctls()
+                || req
+                    .sysctls
+                    .iter()
+                    .any(|(k, v)| !verify_sysctl(&runtime, k, v)))
+        {
+            return Err(InstanceError::InvalidArgs("sysctls".to_string()));
+        }
+        if let Some(size) = req.shm_size {
+            if size == 0 || !runtime.supports_shm_size() {
+                return Err(InstanceError::InvalidArgs("shm_size".to_string()));
+            }
+        }
+        if !req.volumes.is_empty() && (!runtime.supports_volumes() || !verify_volumes(&req.volumes))
+        {
+            return Err(InstanceError::InvalidArgs("volumes".to_string()));
+        }
+        // Detached volumes are local to the node of the instance they were kept from, where the
+        // instance attaching them must run.
+        let mut volume_node = Ok(None);
+        storage
+            .read_only(|state| {
+                if let Some(u) = state.find_user(&user.username) {
+                    volume_node = detached_volume_node(u, &req.volumes, &req.node_name);
+                }
+            })
+            .await;
+        if let Some(node_name) = volume_node? {
+            req.node_name = node_name;
+        }
+        // Burst to EC2 if no on-premise node can hold the instance and it's not pinned to any
+        // node or storage pool.
+        let can_burst = *EC2_BURST
+            && !EC2_REGION.is_empty()
+            && (runtime == Runtime::Lxc || runtime == Runtime::Kvm)
+            && arch == Arch::Amd64
+            && req.node_name.is_empty()
+            && req.storage_pool.is_empty()
+            && req.backup.is_empty()
+            && req.external_ip.is_empty()
+            && req.ingress_limit.is_none()
+            && req.egress_limit.is_none()
+            && !req.dedicated_cpu
+            && !req.kvm_passthrough;
+        if !req.external_ip.is_empty()
+            && !matches!(
+                runtime,
+                Runtime::Lxc | Runtime::Kvm | Runtime::MicroVm | Runtime::Oci
+            )
+        {
+            return Err(InstanceError::InvalidArgs("external_ip".to_string()));
+        }
 
-        let mut user_err = None;
-        match storage
-            .read_write(|state| {
-                let mut node_exists = false;
-                let mut storage_pool_exists = false;
-                if !state.nodes.iter().any(|n| {
-                    if !req.node_name.is_empty() && req.node_name != n.name {
-                        return false;
-                    }
-                    node_exists = true;
+        let project = if req.project.is_empty() {
+            None
+        } else {
+            Some(req.project.clone())
+        };
 
-                    if !req.storage_pool.is_empty()
-                        && !n.storage_pools.iter().any(|p| p.name == req.storage_pool)
+        let mut created = None;
+        let mut placement = None;
+        let mut replayed = false;
+        let now = Utc::now().timestamp();
+        match storage
+            .try_read_write(|state| {
+                if let Some(key) = &idempotency_key {
+                    if let Some(k) = state
+                        .idempotency_keys
+                        .iter()
+                        .find(|k| k.username == user.username && &k.key == key)
+                        .filter(|k| now - k.created_at < IDEMPOTENCY_KEY_TTL)
                     {
-                        return false;
-                    }
-                    storage_pool_exists = true;
-
-                    if req.cpu + n.cpu_allocated > n.cpu_total {
-                        return false;
+                        if k.request_digest != request_digest {
+                            return Err(InstanceError::IdempotencyKeyReused);
+                        }
+                        created = state
+                            .find_user(&user.username)
+                            .and_then(|u| u.find_instance(&k.instance))
+                            .cloned();
+                        replayed = true;
+                        return Ok(false);
                     }
-                    if req.memory + n.memory_allocated > n.memory_total {
-                        return false;
+                }
+                if let Some((limit, period, retry_after)) = state
+                    .find_user(&user.username)
+                    .and_then(|u| u.creation_rate_limit(now))
+                {
+                    return Err(InstanceError::CreationRateLimited {
+                        limit,
+                        period: period.to_owned(),
+                        retry_after,
+                    });
+                }
+                if let Some(project) = &project {
+                    // Projects the user is not a member of are reported as unknown to not
+                    // reveal their existence.
+                    match state.find_project(project) {
+                        Some(p) if p.is_member(&user.username) || user.is_admin() => {}
+                        _ => return Err(InstanceError::UnknownProject(project.clone())),
                     }
-                    if req.disk_size + n.storage_allocated.max(n.storage_used) > n.storage_total {
-                        return false;
+                }
+                // The priority class decides whether the instance is preemptible.
+                let priority = match req.priority_class.as_str() {
+                    "" => priority,
+                    name => match state.find_priority_class(name) {
+                        Some(c) if !c.is_allowed(&user.username) && !user.is_admin() => {
+                            return Err(InstanceError::InvalidArgs("priority_class".to_string()))
+                        }
+                        Some(c) if c.preemptible => Priority::Preemptible,
+                        Some(_) => Priority::Normal,
+                        None => {
+                            return Err(InstanceError::InvalidArgs("priority_class".to_string()))
+                        }
+                    },
+                };
+
+                let catalog_image = match state.find_image(image.as_ref(), &user.username) {
+                    Some(i) => i.clone(),
+                    None => {
+                        return Err(match &image {
+                            Some(image) => InstanceError::ImageUnavailable {
+                                image: image.to_string(),
+                                runtime: runtime.to_string(),
+                            },
+                            None => InstanceError::InvalidArgs("image".to_string()),
+                        });
                     }
+                };
+                // The allowlist may have changed since the image was registered. Images built by
+                // the image builder live on the LXD server and are not subject to it.
+                let allowed = catalog_image.owner.is_none()
+                    || catalog_image.source(&runtime).map_or(false, |s| {
+                        s.starts_with(LOCAL_IMAGE_PREFIX) || custom_image_source_allowed(s)
+                    });
+                if catalog_image.source(&runtime).is_none() || !allowed {
+                    return Err(InstanceError::ImageUnavailable {
+                        image: catalog_image.name.to_string(),
+                        runtime: runtime.to_string(),
+                    });
+                }
+                if !catalog_image.arches.contains(&arch) {
+                    return Err(InstanceError::ArchUnavailable {
+                        arch: arch.to_string(),
+                        runtime: runtime.to_string(),
+                    });
+                }
+                let can_burst = can_burst && catalog_image.source(&Runtime::Ec2).is_some();
+                // Shares no longer allowed by the admins are left out.
+                let mounts: Vec<SharedMount> = catalog_image
+                    .mounts
+                    .iter()
+                    .filter(|m| m.supported_on(&runtime))
+                    .filter(|m| config::current().shared_mount_allowed(&m.source))
+                    .cloned()
+                    .collect();
+                if volumes_collide(
+                    &req.volumes,
+                    &mounts,
+                    &config::current().home_volume_mount_path(&user.username),
+                ) {
+                    return Err(InstanceError::InvalidArgs("volumes".to_string()));
+                }
 
-                    n.storage_pools.iter().any(|p| {
-                        if !req.storage_pool.is_empty() && req.storage_pool != p.name {
+                let mut node_exists = false;
+                let mut storage_pool_exists = false;
+                // EC2 instances don't consume the resources of on-premise nodes.
+                if runtime != Runtime::Ec2
+                    && !state.nodes.iter().any(|n| {
+                        if !req.node_name.is_empty() && req.node_name != n.name {
                             return false;
                         }
-                        if req.disk_size + p.allocated.max(p.used) > p.total {
+                        node_exists = true;
+
+                        if !req.storage_pool.is_empty()
+                            && !n.storage_pools.iter().any(|p| p.name == req.storage_pool)
+                        {
                             return false;
                         }
-                        true
-                    })
-                }) {
-                    if !req.node_name.is_empty() && !node_exists {
-                        user_err = Some(InstanceError::UnknownNode(req.node_name.clone()));
-                    } else if !req.storage_pool.is_empty() && !storage_pool_exists {
-                        user_err =
-                            Some(InstanceError::UnknownStoragePool(req.storage_pool.clone()));
-                    } else {
-                        user_err = Some(InstanceError::ResourceExhausted);
+                        storage_pool_exists = true;
+
+                        if !n.is_schedulable() || !n.can_run_arch(&arch) {
+                            return false;
+                        }
+                        let cpu = if req.dedicated_cpu {
+                            if n.pick_dedicated_cpus(req.cpu).is_none() {
+                                return false;
+                            }
+                            dedicated_cpu_allocation(req.cpu)
+                        } else {
+                            req.cpu
+                        };
+                        // Normal instances may take the room of preemptible ones, which the
+                        // scheduler stops to make it.
+                        let (reclaimable_cpu, reclaimable_memory) =
+                            if priority == Priority::Normal && !req.dedicated_cpu {
+                                state.preemptible_compute(&n.name)
+                            } else {
+                                (0, 0)
+                            };
+                        if cpu + n.cpu_allocated > n.usable_cpu() + reclaimable_cpu {
+                            return false;
+                        }
+                        if req.memory + n.memory_allocated > n.usable_memory() + reclaimable_memory
+                        {
+                            return false;
+                        }
+                        if total_disk_size + n.storage_allocated.max(n.storage_used)
+                            > n.usable_storage()
+                        {
+                            return false;
+                        }
+
+                        n.storage_pools.iter().any(|p| {
+                            if !req.storage_pool.is_empty() && req.storage_pool != p.name {
+                                return false;
+                            }
+                            if total_disk_size + p.allocated.max(p.used) > p.usable() {
+                                return false;
+                            }
+                            true
+                        })
+                    })
+                {
+                    if !req.node_name.is_empty() && !node_exists {
+                        return Err(InstanceError::UnknownNode(req.node_name.clone()));
+                    } else if !req.storage_pool.is_empty() && !storage_pool_exists {
+                        return Err(InstanceError::UnknownStoragePool(req.storage_pool.clone()));
+                    } else if can_burst {
+                        info!(
+                            username = user.username.as_str(),
+                            instance = req.name.as_str(),
+                            runtime = runtime.to_string().as_str(),
+                            "no node has enough resources, bursting instance to ec2"
+                        );
+                        runtime = Runtime::Ec2;
+                    } else {
+                        return Err(InstanceError::ResourceExhausted);
                     }
-                    return false;
                 }
 
+                // Instances transferred to other users keep the name of their backend resources,
+                // which must not be taken by a new instance.
+                let backend_name = format!("{}-{}", user.username, req.name);
+                if state
+                    .users
+                    .iter()
+                    .flat_map(|u| &u.instances)
+                    .any(|i| i.backend_name.as_deref() == Some(backend_name.as_str()))
+                {
+                    return Err(InstanceError::AlreadyExists);
+                }
+                // Nor the claim of the root disk, which detached volumes keep wherever they're
+                // attached.
+                if claim_in_use(state, &format!("{}-rootfs", backend_name)) {
+                    return Err(InstanceError::VolumeAlreadyExists);
+                }
+
+                // The CPU and memory of preemptible instances are neither checked against nor
+                // counted towards the quotas, unlike the instances themselves and their disks.
+                let quota_checked = priority == Priority::Normal;
+                let (quota_cpu, quota_memory) = if quota_checked {
+                    (req.cpu, req.memory)
+                } else {
+                    (0, 0)
+                };
+                if let Some(e) = check_shared_quotas(
+                    state,
+                    &user.username,
+                    &req.name,
+                    project.as_deref(),
+                    quota_cpu,
+                    quota_memory,
+                    total_disk_size,
+                ) {
+                    return Err(e);
+                }
+
+                let reserved_ip = if runtime == Runtime::Ec2 {
+                    None
+                } else {
+                    reserve_external_ip(state, &user.username, &req)?
+                };
+                let claimed = reserved_ip.as_ref().map_or(false, |r| r.2);
+                if req.pin_external_ip && !claimed {
+                    check_pinned_ip_limit(state, &user.username)?;
+                }
+                let ip_pinned = req.pin_external_ip || claimed;
+
                 match state.find_mut_user(&user.username) {
                     Some(u) => {
-                        if u.instances.len() + 1 > u.instance_quota {
-                            user_err = Some(InstanceError::QuotaExceeded {
+                        if u.find_instance(&req.name).is_some() {
+                            return Err(InstanceError::AlreadyExists);
+                        }
+                        if u.instances.len() + 1 > u.instance_quota() {
+                            return Err(InstanceError::QuotaExceeded {
                                 resource: "Instance".to_string(),
-                                quota: u.instance_quota,
-                                remaining: u.instance_quota - u.instances.len(),
+                                quota: u.instance_quota(),
+                                remaining: u.instance_quota().saturating_sub(u.instances.len()),
                                 requested: 1,
                                 unit: "".to_string(),
                             });
-                            return false;
                         }
                         let mut total_cpu = 0;
                         let mut total_memory = 0;
-                        let mut total_disk_size = 0;
-                        for instance in &u.instances {
-                            if instance.name == req.name {
-                                user_err = Some(InstanceError::AlreadyExists);
-                                return false;
-                            }
+                        for instance in u
+                            .instances
+                            .iter()
+                            .filter(|i| i.counts_towards_compute_quota())
+                        {
                             total_cpu += instance.cpu;
                             total_memory += instance.memory;
-                            total_disk_size += instance.disk_size;
                         }
-                        if total_cpu + req.cpu > u.cpu_quota {
-                            user_err = Some(InstanceError::QuotaExceeded {
+                        // The home volume and the detached volumes of the user count towards the
+                        // disk quota as well.
+                        let used_disk_size = u.used_disk_size();
+                        if quota_checked && total_cpu + req.cpu > u.cpu_quota() {
+                            return Err(InstanceError::QuotaExceeded {
                                 resource: "CPU".to_string(),
-                                quota: u.cpu_quota,
-                                remaining: u.cpu_quota - total_cpu,
+                                quota: u.cpu_quota(),
+                                remaining: u.cpu_quota().saturating_sub(total_cpu),
                                 requested: req.cpu,
                                 unit: "C".to_string(),
                             });
-                            return false;
                         }
-                        if total_memory + req.memory > u.memory_quota {
-                            user_err = Some(InstanceError::QuotaExceeded {
+                        if quota_checked && total_memory + req.memory > u.memory_quota() {
+                            return Err(InstanceError::QuotaExceeded {
                                 resource: "Memory".to_string(),
-                                quota: u.memory_quota,
-                                remaining: u.memory_quota - total_memory,
+                                quota: u.memory_quota(),
+                                remaining: u.memory_quota().saturating_sub(total_memory),
                                 requested: req.memory,
                                 unit: "GiB".to_string(),
                             });
-                            return false;
                         }
-                        if total_disk_size + req.disk_size > u.disk_quota {
-                            user_err = Some(InstanceError::QuotaExceeded {
+                        if used_disk_size + total_disk_size > u.disk_quota() {
+                            return Err(InstanceError::QuotaExceeded {
                                 resource: "Disk size".to_string(),
-                                quota: u.disk_quota,
-                                remaining: u.disk_quota - total_disk_size,
-                                requested: req.disk_size,
+                                quota: u.disk_quota(),
+                                remaining: u.disk_quota().saturating_sub(used_disk_size),
+                                requested: total_disk_size,
                                 unit: "GiB".to_string(),
                             });
-                            return false;
                         }
 
-                        u.instances.push(Instance {
+                        let volumes = attach_volumes(u, &req.volumes)?;
+
+                        let instance = Instance {
+                            id: new_instance_id(),
                             name: req.name.clone(),
-                            image: image.clone(),
+                            image: catalog_image.name.clone(),
+                            arch: arch.clone(),
                             cpu: req.cpu,
                             memory: req.memory,
                             disk_size: req.disk_size,
+                            disk_used: None,
                             stage: InstanceStage::Running,
                             hostname: req.name.clone(),
                             ssh_host: None,
@@ -201,9 +1626,25 @@ pub fn protected_routes() -> Router {
                                 .take(16)
                                 .map(char::from)
                                 .collect(),
+                            password_hashed: false,
+                            ssh_authorized_keys: req.ssh_authorized_keys.clone(),
                             status: InstanceStatus::Creating,
                             internal_ip: None,
-                            external_ip: None,
+                            external_ip: reserved_ip.as_ref().map(|r| r.1.clone()),
+                            ip_pool: reserved_ip.as_ref().map(|r| r.0.clone()),
+                            ip_pinned,
+                            ingress_limit: req.ingress_limit,
+                            egress_limit: req.egress_limit,
+                            dedicated_cpu: req.dedicated_cpu,
+                            pinned_cpus: Vec::new(),
+                            nesting: req.nesting,
+                            kvm_passthrough: req.kvm_passthrough,
+                            runtime_options: req.runtime_options.clone(),
+                            sysctls: req.sysctls.clone(),
+                            shm_size: req.shm_size,
+                            volumes,
+                            mounts,
+                            conversion: None,
                             runtime: runtime.clone(),
                             node_name: if req.node_name.is_empty() {
                                 None
@@ -215,375 +1656,3734 @@ pub fn protected_routes() -> Router {
                             } else {
                                 Some(req.storage_pool.clone())
                             },
-                        });
-                        true
+                            transfer: if req.backup.is_empty() {
+                                None
+                            } else {
+                                Some(Transfer {
+                                    kind: TransferKind::Import,
+                                    object_key: req.backup.clone(),
+                                    bucket: String::new(),
+                                    operation: None,
+                                    status: TransferStatus::Pending,
+                                    progress: None,
+                                })
+                            },
+                            progress: None,
+                            cloud_instance_id: None,
+                            events: Vec::new(),
+                            status_history: Vec::new(),
+                            started_at: None,
+                            last_running_at: None,
+                            accumulated_uptime: 0,
+                            created_at: Some(now),
+                            updated_at: Some(now),
+                            deleted_at: None,
+                            trashed_at: None,
+                            schedule: None,
+                            backup_policy: None,
+                            project: project.clone(),
+                            shares: Vec::new(),
+                            backend_name: None,
+                            image_source: catalog_image.source(&runtime).map(|s| s.to_owned()),
+                            image_family: catalog_image.family,
+                            labels: req.labels.clone(),
+                            description: req.description.clone(),
+                            priority,
+                            priority_class: (!req.priority_class.is_empty())
+                                .then(|| req.priority_class.clone()),
+                            instance_group: None,
+                            group_role: None,
+                            ssh_host_keys: Vec::new(),
+                            scheduling: None,
+                            keep_disk: false,
+                        };
+                        created = Some(instance.clone());
+                        u.instances.push(instance);
+                        u.record_creation(now);
+                        if query.dry_run {
+                            placement = scheduler::Scheduler::predict_placement(
+                                state,
+                                &user.username,
+                                &req.name,
+                            );
+                            return Ok(false);
+                        }
+                        if let Some(key) = &idempotency_key {
+                            state
+                                .idempotency_keys
+                                .retain(|k| now - k.created_at < IDEMPOTENCY_KEY_TTL);
+                            state.idempotency_keys.push(IdempotencyKey {
+                                username: user.username.clone(),
+                                key: key.clone(),
+                                request_digest: request_digest.clone(),
+                                instance: req.name.clone(),
+                                created_at: now,
+                            });
+                        }
+                        Ok(true)
                     }
-                    None => false,
+                    None => Ok(false),
                 }
             })
             .await
         {
-            Ok(_) => (),
+            Ok(res) => res?,
             Err(e) => {
                 warn!(
                     username = user.username.as_str(),
                     instance = req.name.as_str(),
                     error = e.to_string().as_str(),
-                    "create instance encountered error"
+                    "create instance encountered error"
+                );
+                return Err(InstanceError::CreateFailed);
+            }
+        }
+
+        let created = match created {
+            Some(created) => created,
+            // The instance created by the request being retried has been removed since.
+            None if replayed => return Err(InstanceError::NotFound),
+            None => return Err(InstanceError::CreateFailed),
+        };
+        let mut instance = InstanceDto::from(&created);
+        if query.dry_run {
+            // The password is generated again when the instance is actually created.
+            instance.password = String::new();
+            if let Some((node_name, storage_pool)) = placement {
+                instance.node_name = Some(node_name);
+                instance.storage_pool = storage_pool;
+            }
+            return Ok(Json(instance).into_response());
+        }
+        // This is the only chance to get the password if password hashing is enabled, while a
+        // retried request gets whatever is left of it.
+        if !replayed {
+            instance.password = created.password;
+        }
+        if instance.external_ip.is_some() {
+            instance.dns_name = dns::record_name(&user.username, &instance.name);
+        }
+        Ok((
+            StatusCode::CREATED,
+            [(LOCATION, format!("/instances/{}", instance.name))],
+            Json(instance),
+        )
+            .into_response())
+    }
+
+    /// Sets the instance to be deleted by the operators, or moves it to the recycle bin.
+    #[instrument(skip_all, fields(username = %user.username, instance = %instance_name))]
+    async fn delete_instance(
+        _leader: Leader,
+        user: UserClaims,
+        Path(instance_name): Path<String>,
+        Query(query): Query<DeleteInstanceQuery>,
+        Extension(storage): Extension<Storage>,
+    ) -> Result<impl IntoResponse, InstanceError> {
+        let config = config::current();
+        if config.delete_confirmation && query.confirm != instance_name {
+            return Err(InstanceError::ConfirmationRequired);
+        }
+        let now = Utc::now().timestamp();
+        match storage
+            .try_read_write(|state| {
+                let u = match state.find_mut_user(&user.username) {
+                    Some(u) => u,
+                    None => return Ok(false),
+                };
+                if !query.keep_disk {
+                    return Ok(match u.find_mut_instance(&instance_name) {
+                        Some(instance) => {
+                            lifecycle::delete(instance, config.recycle_bin_retention, now)
+                                .unwrap_or(false)
+                        }
+                        None => false,
+                    });
+                }
+                keep_root_disk(u, &instance_name, now)
+            })
+            .await
+        {
+            Ok(res) => res?,
+            Err(e) => {
+                warn!(
+                    username = user.username.as_str(),
+                    instance = instance_name.as_str(),
+                    error = e.to_string().as_str(),
+                    "delete instance encountered error"
+                );
+                return Err(InstanceError::DeleteFailed);
+            }
+        }
+        Ok(StatusCode::NO_CONTENT)
+    }
+
+    #[instrument(skip_all, fields(username = %user.username, instance = %instance_name))]
+    async fn update_instance(
+        _leader: Leader,
+        user: UserClaims,
+        Path(instance_name): Path<String>,
+        Query(query): Query<DryRunQuery>,
+        Json(req): Json<UpdateInstanceRequest>,
+        Extension(storage): Extension<Storage>,
+    ) -> Result<impl IntoResponse, InstanceError> {
+        if let Some(0) = req.cpu {
+            return Err(InstanceError::InvalidArgs("cpu".to_string()));
+        }
+        if let Some(0) = req.memory {
+            return Err(InstanceError::InvalidArgs("memory".to_string()));
+        }
+        if let Some(runtime) = &req.runtime {
+            let _ = Runtime::from_str(runtime)
+                .map_err(|_| InstanceError::InvalidArgs(runtime.to_owned()))?;
+        }
+        if let Some(description) = &req.description {
+            if !verify_description(description) {
+                return Err(InstanceError::InvalidArgs("description".to_string()));
+            }
+        }
+        let mut updated = None;
+        match storage
+            .try_read_write(|state| {
+                let mut repinned = None;
+                if let Some(i) = state
+                    .find_user(&user.username)
+                    .and_then(|u| u.find_instance(&instance_name))
+                {
+                    // Dedicated instances are pinned to other CPUs of their node when resized.
+                    if let Some(cpu) = req.cpu.filter(|cpu| i.dedicated_cpu && *cpu != i.cpu) {
+                        let mut node = match state
+                            .nodes
+                            .iter()
+                            .find(|n| i.node_name.as_ref() == Some(&n.name))
+                        {
+                            Some(n) => n.clone(),
+                            None => return Err(InstanceError::ResourceExhausted),
+                        };
+                        node.pinned_cpus.retain(|c| !i.pinned_cpus.contains(c));
+                        match node.pick_dedicated_cpus(cpu) {
+                            Some(cpus) => repinned = Some(cpus),
+                            None => return Err(InstanceError::ResourceExhausted),
+                        }
+                    }
+                    // Instances created before the limits were lowered may keep their sizes. The
+                    // disk size is not updatable and thus not checked.
+                    if req.cpu.is_some() || req.memory.is_some() {
+                        if let Some(e) = check_size_limits(
+                            req.cpu.unwrap_or(i.cpu),
+                            req.memory.unwrap_or(i.memory),
+                            0,
+                        ) {
+                            return Err(e);
+                        }
+                    }
+                    if i.counts_towards_compute_quota() {
+                        if let Some(e) = check_shared_quotas(
+                            state,
+                            &user.username,
+                            &instance_name,
+                            i.project.as_deref(),
+                            req.cpu.unwrap_or(i.cpu),
+                            req.memory.unwrap_or(i.memory),
+                            i.total_disk_size(),
+                        ) {
+                            return Err(e);
+                        }
+                    }
+                }
+                match state.find_mut_user(&user.username) {
+                    Some(u) => {
+                        let mut total_cpu = 0;
+                        let mut total_memory = 0;
+                        for instance in u
+                            .instances
+                            .iter()
+                            .filter(|i| i.counts_towards_compute_quota())
+                        {
+                            if instance.name != instance_name {
+                                total_cpu += instance.cpu;
+                                total_memory += instance.memory;
+                            }
+                        }
+                        match u
+                            .instances
+                            .iter_mut()
+                            .find(|instance| instance.name == instance_name)
+                        {
+                            Some(instance) => {
+                                if instance.stage == InstanceStage::Deleted {
+                                    return Err(InstanceError::AlreadyDeleted);
+                                }
+                                // Running instances may be resized if their runtime can hotplug
+                                // the resources, other changes need them to be stopped.
+                                let hot_resize = instance.status == InstanceStatus::Running
+                                    && req.runtime.is_none()
+                                    && req.ingress_limit.is_none()
+                                    && req.egress_limit.is_none()
+                                    && (req.cpu.is_none()
+                                        || instance.runtime.supports_cpu_hotplug())
+                                    && (req.memory.is_none()
+                                        || instance.runtime.supports_memory_hotplug());
+                                // The description alone may be changed in any status.
+                                let description_only = req.cpu.is_none()
+                                    && req.memory.is_none()
+                                    && req.runtime.is_none()
+                                    && req.ingress_limit.is_none()
+                                    && req.egress_limit.is_none();
+                                if instance.status != InstanceStatus::Stopped
+                                    && !hot_resize
+                                    && !description_only
+                                {
+                                    return Err(InstanceError::NotYetStopped);
+                                }
+                                if let Some(description) = &req.description {
+                                    instance.description = description.clone();
+                                }
+                                let quota_checked = instance.counts_towards_compute_quota();
+                                if let Some(cpu) = req.cpu {
+                                    if quota_checked && total_cpu + cpu > u.cpu_quota() {
+                                        return Err(InstanceError::QuotaExceeded {
+                                            resource: "CPU".to_string(),
+                                            quota: u.cpu_quota(),
+                                            remaining: u.cpu_quota().saturating_sub(total_cpu),
+                                            requested: cpu,
+                                            unit: "C".to_string(),
+                                        });
+                                    }
+                                    instance.cpu = cpu;
+                                    if let Some(cpus) = &repinned {
+                                        instance.pinned_cpus = cpus.clone();
+                                    }
+                                }
+                                if let Some(memory) = req.memory {
+                                    if quota_checked && total_memory + memory > u.memory_quota() {
+                                        return Err(InstanceError::QuotaExceeded {
+                                            resource: "Memory".to_string(),
+                                            quota: u.memory_quota(),
+                                            remaining: u
+                                                .memory_quota()
+                                                .saturating_sub(total_memory),
+                                            requested: memory,
+                                            unit: "GiB".to_string(),
+                                        });
+                                    }
+                                    instance.memory = memory;
+                                }
+                                if let Some(runtime) = &req.runtime {
+                                    let runtime = Runtime::from_str(runtime).unwrap();
+                                    if instance.runtime.compatiable_with(&runtime) {
+                                        instance.runtime = runtime;
+                                    } else {
+                                        return Err(InstanceError::RuntimeIncompatible {
+                                            current: instance.runtime.to_string(),
+                                            target: runtime.to_string(),
+                                        });
+                                    }
+                                }
+                                // The VM of Kata instances must still fit the instance.
+                                if instance.runtime == Runtime::Kata
+                                    && (req.cpu.is_some() || req.memory.is_some())
+                                    && instance.runtime_options.iter().any(|(k, v)| {
+                                        !verify_runtime_option(
+                                            &instance.runtime,
+                                            k,
+                                            v,
+                                            instance.cpu,
+                                            instance.memory,
+                                        )
+                                    })
+                                {
+                                    return Err(InstanceError::InvalidArgs(
+                                        "runtime_options".to_string(),
+                                    ));
+                                }
+                                if req.ingress_limit.is_some() || req.egress_limit.is_some() {
+                                    if !instance.runtime.supports_bandwidth_limits() {
+                                        return Err(InstanceError::InvalidArgs(
+                                            "ingress_limit".to_string(),
+                                        ));
+                                    }
+                                    // The limits are applied when the instance is started.
+                                    if let Some(limit) = req.ingress_limit {
+                                        instance.ingress_limit = (limit != 0).then(|| limit);
+                                    }
+                                    if let Some(limit) = req.egress_limit {
+                                        instance.egress_limit = (limit != 0).then(|| limit);
+                                    }
+                                }
+                                if query.dry_run {
+                                    updated = Some(instance.clone());
+                                    return Ok(false);
+                                }
+                                Ok(true)
+                            }
+                            None => Ok(false),
+                        }
+                    }
+                    None => Ok(false),
+                }
+            })
+            .await
+        {
+            Ok(res) => res?,
+            Err(e) => {
+                warn!(
+                    username = user.username.as_str(),
+                    instance = instance_name.as_str(),
+                    error = e.to_string().as_str(),
+                    "update instance encountered error"
+                );
+                return Err(InstanceError::UpdateFailed);
+            }
+        }
+
+        if query.dry_run {
+            let updated = updated.ok_or(InstanceError::NotFound)?;
+            let mut instance = InstanceDto::from(&updated);
+            if instance.external_ip.is_some() {
+                instance.dns_name = dns::record_name(&user.username, &instance.name);
+            }
+            return Ok(Json(instance).into_response());
+        }
+        Ok(StatusCode::NO_CONTENT.into_response())
+    }
+
+    #[instrument(skip_all, fields(username = %user.username, instance = %instance_name, owner = %query.owner))]
+    async fn start_instance(
+        _leader: Leader,
+        user: UserClaims,
+        Path(instance_name): Path<String>,
+        Query(query): Query<InstanceOwnerQuery>,
+        Extension(storage): Extension<Storage>,
+    ) -> Result<impl IntoResponse, InstanceError> {
+        let owner = (!query.owner.is_empty()).then(|| query.owner.as_str());
+        match storage
+            .try_read_write(|state| {
+                if let Some(i) =
+                    state.find_accessible_instance(&user.username, owner, &instance_name)
+                {
+                    if i.stage == InstanceStage::Stopped && !state.has_room_to_start(i) {
+                        return Err(InstanceError::ResourceExhausted);
+                    }
+                }
+                match state.find_mut_accessible_instance(&user.username, owner, &instance_name) {
+                    Some(instance) => {
+                        let started = lifecycle::apply(instance, Action::Start)?;
+                        if started && owner.map_or(false, |o| o != user.username) {
+                            instance.add_event(format!("started by {}", user.username));
+                        }
+                        Ok(started)
+                    }
+                    None => Ok(false),
+                }
+            })
+            .await
+        {
+            Ok(res) => res?,
+            Err(_) => return Err(InstanceError::StartFailed),
+        }
+        Ok(StatusCode::NO_CONTENT)
+    }
+
+    /// Takes the instance out of the recycle bin. It stays stopped until it's started again.
+    #[instrument(skip_all, fields(username = %user.username, instance = %instance_name))]
+    async fn restore_instance(
+        _leader: Leader,
+        user: UserClaims,
+        Path(instance_name): Path<String>,
+        Extension(storage): Extension<Storage>,
+    ) -> Result<impl IntoResponse, InstanceError> {
+        storage
+            .try_read_write(|state| {
+                let instance = state
+                    .find_mut_user(&user.username)
+                    .and_then(|u| u.find_mut_instance(&instance_name))
+                    .ok_or(InstanceError::NotFound)?;
+                if lifecycle::is_terminal(&instance.stage) {
+                    return Err(InstanceError::AlreadyDeleted);
+                }
+                if instance.trashed_at.take().is_none() {
+                    return Err(InstanceError::NotInRecycleBin);
+                }
+                instance.add_event("restored from the recycle bin".to_string());
+                // The group of the instance is back along with it.
+                if let Some(name) = instance.instance_group.clone() {
+                    if let Some(g) = state
+                        .instance_groups
+                        .iter_mut()
+                        .find(|g| g.owner == user.username && g.name == name)
+                    {
+                        g.deleted_at = None;
+                    }
+                }
+                Ok(true)
+            })
+            .await
+            .map_err(|e| {
+                warn!(
+                    username = user.username.as_str(),
+                    instance = instance_name.as_str(),
+                    error = e.to_string().as_str(),
+                    "restore instance encountered error"
+                );
+                InstanceError::UpdateFailed
+            })??;
+        Ok(StatusCode::NO_CONTENT)
+    }
+
+    /// Bridges a WebSocket to the SSH port of the instance's internal IP, so browser-based clients
+    /// can reach instances whose external IPs are firewalled. Browsers pass the token as the
+    /// subprotocol following `bearer`, see `WEBSOCKET_BEARER_PROTOCOL`.
+    #[instrument(skip_all, fields(username = %user.username, instance = %instance_name, owner = %query.owner))]
+    async fn ssh_instance(
+        user: UserClaims,
+        Path(instance_name): Path<String>,
+        Query(query): Query<InstanceOwnerQuery>,
+        ws: WebSocketUpgrade,
+        Extension(storage): Extension<Storage>,
+    ) -> Result<impl IntoResponse, InstanceError> {
+        let owner = (!query.owner.is_empty()).then(|| query.owner.as_str());
+        let instance =
+            find_running_instance(&storage, &user.username, owner, &instance_name).await?;
+        let ip = instance.internal_ip.ok_or(InstanceError::SshUnreachable)?;
+        let stream = match timeout(SSH_CONNECT_TIMEOUT, TcpStream::connect((ip.as_str(), 22))).await
+        {
+            Ok(Ok(stream)) => stream,
+            Ok(Err(e)) => {
+                warn!(ip = ip.as_str(), error = %e, "connect to ssh failed");
+                return Err(InstanceError::SshUnreachable);
+            }
+            Err(_) => {
+                warn!(ip = ip.as_str(), "connect to ssh timed out");
+                return Err(InstanceError::SshUnreachable);
+            }
+        };
+        let span = tracing::Span::current();
+        // Browsers authenticating by the subprotocol require the server to accept it.
+        let ws = ws.protocols([WEBSOCKET_BEARER_PROTOCOL]);
+        Ok(ws.on_upgrade(move |socket| {
+            async move {
+                info!("ssh session opened");
+                bridge_ssh(socket, stream).await;
+                info!("ssh session closed");
+            }
+            .instrument(span)
+        }))
+    }
+
+    #[instrument(skip_all, fields(username = %user.username, instance = %instance_name, owner = %query.owner, path = %query.path))]
+    async fn pull_file(
+        _leader: Leader,
+        user: UserClaims,
+        Path(instance_name): Path<String>,
+        Query(query): Query<FileQuery>,
+        Extension(storage): Extension<Storage>,
+    ) -> Result<impl IntoResponse, InstanceError> {
+        if !verify_file_path(&query.path) {
+            return Err(InstanceError::InvalidArgs("path".to_string()));
+        }
+        let owner = (!query.owner.is_empty()).then(|| query.owner.as_str());
+        let instance =
+            find_running_instance(&storage, &user.username, owner, &instance_name).await?;
+        check_file_access(&instance, &user.username, owner)?;
+        let limit = config::current().max_file_transfer_size * 1024 * 1024;
+        let data = files::pull(
+            owner.unwrap_or(&user.username),
+            &instance,
+            &query.path,
+            limit,
+        )
+        .await?;
+        info!(size = data.len(), "file pulled");
+        record_file_transfer(
+            &storage,
+            &user.username,
+            owner,
+            &instance_name,
+            format!("file {} pulled by {}", query.path, user.username),
+        )
+        .await;
+        Ok(([(CONTENT_TYPE, "application/octet-stream")], data))
+    }
+
+    #[instrument(skip_all, fields(username = %user.username, instance = %instance_name, owner = %query.owner, path = %query.path))]
+    async fn push_file(
+        _leader: Leader,
+        user: UserClaims,
+        Path(instance_name): Path<String>,
+        Query(query): Query<FileQuery>,
+        Extension(storage): Extension<Storage>,
+        mut body: BodyStream,
+    ) -> Result<impl IntoResponse, InstanceError> {
+        if !verify_file_path(&query.path) {
+            return Err(InstanceError::InvalidArgs("path".to_string()));
+        }
+        let owner = (!query.owner.is_empty()).then(|| query.owner.as_str());
+        let instance =
+            find_running_instance(&storage, &user.username, owner, &instance_name).await?;
+        check_file_access(&instance, &user.username, owner)?;
+        let limit = config::current().max_file_transfer_size * 1024 * 1024;
+        let mut data = Vec::new();
+        while let Some(chunk) = body.next().await {
+            let chunk = chunk.map_err(|_| InstanceError::InvalidArgs("body".to_string()))?;
+            if data.len() + chunk.len() > limit {
+                return Err(InstanceError::FileTooLarge { limit });
+            }
+            data.extend_from_slice(&chunk);
+        }
+        let size = data.len();
+        files::push(
+            owner.unwrap_or(&user.username),
+            &instance,
+            &query.path,
+            data,
+        )
+        .await?;
+        info!(size, "file pushed");
+        record_file_transfer(
+            &storage,
+            &user.username,
+            owner,
+            &instance_name,
+            format!("file {} pushed by {}", query.path, user.username),
+        )
+        .await;
+        Ok(StatusCode::NO_CONTENT)
+    }
+
+    #[instrument(skip_all, fields(username = %user.username, instance = %instance_name, owner = %query.owner))]
+    async fn stop_instance(
+        _leader: Leader,
+        user: UserClaims,
+        Path(instance_name): Path<String>,
+        Query(query): Query<InstanceOwnerQuery>,
+        Extension(storage): Extension<Storage>,
+    ) -> Result<impl IntoResponse, InstanceError> {
+        let owner = (!query.owner.is_empty()).then(|| query.owner.as_str());
+        match storage
+            .try_read_write(|state| {
+                match state.find_mut_accessible_instance(&user.username, owner, &instance_name) {
+                    Some(instance) => {
+                        let stopped = lifecycle::apply(instance, Action::Stop)?;
+                        if stopped && owner.map_or(false, |o| o != user.username) {
+                            instance.add_event(format!("stopped by {}", user.username));
+                        }
+                        Ok(stopped)
+                    }
+                    None => Ok(false),
+                }
+            })
+            .await
+        {
+            Ok(res) => res?,
+            Err(_) => return Err(InstanceError::StopFailed),
+        }
+        Ok(StatusCode::NO_CONTENT)
+    }
+
+    #[instrument(skip_all, fields(username = %user.username, instance = %instance_name))]
+    async fn export_instance(
+        _leader: Leader,
+        user: UserClaims,
+        Path(instance_name): Path<String>,
+        Extension(storage): Extension<Storage>,
+    ) -> Result<impl IntoResponse, InstanceError> {
+        if !s3::is_configured() {
+            return Err(InstanceError::BackupStorageUnavailable);
+        }
+        let transfer = Transfer {
+            kind: TransferKind::Export,
+            object_key: format!(
+                "{}/{}/{}.tar.gz",
+                user.username,
+                instance_name,
+                Utc::now().format("%Y%m%d%H%M%S")
+            ),
+            bucket: String::new(),
+            operation: None,
+            status: TransferStatus::Pending,
+            progress: None,
+        };
+        match storage
+            .try_read_write(|state| {
+                match state
+                    .find_mut_user(&user.username)
+                    .and_then(|u| u.find_mut_instance(&instance_name))
+                {
+                    Some(instance) => {
+                        if instance.stage == InstanceStage::Deleted {
+                            return Err(InstanceError::AlreadyDeleted);
+                        }
+                        if instance.runtime != Runtime::Lxc && instance.runtime != Runtime::Kvm {
+                            return Err(InstanceError::TransferUnsupported {
+                                runtime: instance.runtime.to_string(),
+                            });
+                        }
+                        if instance.status != InstanceStatus::Stopped {
+                            return Err(InstanceError::NotYetStopped);
+                        }
+                        if matches!(&instance.transfer, Some(t) if !t.is_finished()) {
+                            return Err(InstanceError::TransferInProgress);
+                        }
+                        instance.transfer = Some(transfer.clone());
+                        Ok(true)
+                    }
+                    None => Err(InstanceError::NotFound),
+                }
+            })
+            .await
+        {
+            Ok(res) => res?,
+            Err(e) => {
+                warn!(
+                    username = user.username.as_str(),
+                    instance = instance_name.as_str(),
+                    error = e.to_string().as_str(),
+                    "export instance encountered error"
+                );
+                return Err(InstanceError::ExportFailed);
+            }
+        }
+        Ok((StatusCode::ACCEPTED, Json(TransferDto::from(&transfer))))
+    }
+
+    #[instrument(skip_all, fields(username = %user.username, instance = %instance_name))]
+    async fn convert_instance(
+        _leader: Leader,
+        user: UserClaims,
+        Path(instance_name): Path<String>,
+        Json(req): Json<ConvertInstanceRequest>,
+        Extension(storage): Extension<Storage>,
+    ) -> Result<impl IntoResponse, InstanceError> {
+        let target: Runtime = req
+            .runtime
+            .parse()
+            .map_err(|_| InstanceError::InvalidArgs("runtime".to_owned()))?;
+        let mut converted = None;
+        storage
+            .try_read_write(|state| {
+                let image_source = match state
+                    .find_user(&user.username)
+                    .and_then(|u| u.find_instance(&instance_name))
+                {
+                    Some(i) => state
+                        .find_image(Some(&i.image), &user.username)
+                        .and_then(|c| c.source(&target))
+                        .map(|s| s.to_owned())
+                        .ok_or_else(|| InstanceError::ImageUnavailable {
+                            image: i.image.to_string(),
+                            runtime: target.to_string(),
+                        })?,
+                    None => return Ok(false),
+                };
+                let instance = state
+                    .find_mut_user(&user.username)
+                    .and_then(|u| u.find_mut_instance(&instance_name))
+                    .unwrap();
+                lifecycle::check(instance, Action::Convert)?;
+                if !instance.runtime.convertible_to(&target) {
+                    return Err(InstanceError::RuntimeIncompatible {
+                        current: instance.runtime.to_string(),
+                        target: target.to_string(),
+                    });
+                }
+                if matches!(&instance.transfer, Some(t) if !t.is_finished()) {
+                    return Err(InstanceError::TransferInProgress);
+                }
+                if instance.conversion.is_some() {
+                    return Err(InstanceError::ConversionPending);
+                }
+                // The previous backend keeps a copy of the root disk until it's discarded.
+                let disk_size = instance.disk_size;
+                let u = state.find_user(&user.username).unwrap();
+                let used = u.used_disk_size();
+                if used + disk_size > u.disk_quota() {
+                    return Err(InstanceError::QuotaExceeded {
+                        resource: "Disk size".to_string(),
+                        quota: u.disk_quota(),
+                        remaining: u.disk_quota().saturating_sub(used),
+                        requested: disk_size,
+                        unit: "GiB".to_string(),
+                    });
+                }
+                let instance = state
+                    .find_mut_user(&user.username)
+                    .and_then(|u| u.find_mut_instance(&instance_name))
+                    .unwrap();
+                let backend_name = instance.backend_name(&user.username);
+                instance.conversion = Some(Conversion {
+                    from: instance.runtime.clone(),
+                    previous_backend: format!("{}-{}", backend_name, instance.runtime),
+                    renamed: false,
+                    discarded: false,
+                });
+                // The instance keeps its node, storage pool and IP, and is started once rebuilt.
+                // Nesting is neither needed nor supported by VMs.
+                instance.runtime = target.clone();
+                instance.image_source = Some(image_source);
+                instance.nesting = false;
+                instance.kvm_passthrough = false;
+                instance.backend_name = Some(backend_name);
+                instance.ssh_host_keys.clear();
+                lifecycle::apply(instance, Action::Convert)?;
+                instance.add_event(format!("converting to runtime {}", target));
+                converted = Some(instance.clone());
+                Ok(true)
+            })
+            .await
+            .map_err(|e| {
+                warn!(
+                    username = user.username.as_str(),
+                    instance = instance_name.as_str(),
+                    error = e.to_string().as_str(),
+                    "convert instance encountered error"
+                );
+                InstanceError::ConvertFailed
+            })??;
+        match converted {
+            Some(instance) => Ok((StatusCode::ACCEPTED, Json(InstanceDto::from(&instance)))),
+            None => Err(InstanceError::NotFound),
+        }
+    }
+
+    #[instrument(skip_all, fields(username = %user.username, instance = %instance_name))]
+    async fn discard_conversion(
+        _leader: Leader,
+        user: UserClaims,
+        Path(instance_name): Path<String>,
+        Extension(storage): Extension<Storage>,
+    ) -> Result<impl IntoResponse, InstanceError> {
+        storage
+            .try_read_write(|state| {
+                match state
+                    .find_mut_user(&user.username)
+                    .and_then(|u| u.find_mut_instance(&instance_name))
+                    .and_then(|i| i.conversion.as_mut())
+                {
+                    Some(c) if !c.discarded => {
+                        c.discarded = true;
+                        Ok(true)
+                    }
+                    Some(_) => Ok(false),
+                    None => Err(InstanceError::NotFound),
+                }
+            })
+            .await
+            .map_err(|e| {
+                warn!(
+                    username = user.username.as_str(),
+                    instance = instance_name.as_str(),
+                    error = e.to_string().as_str(),
+                    "discard conversion encountered error"
+                );
+                InstanceError::ConvertFailed
+            })??;
+        Ok(StatusCode::NO_CONTENT)
+    }
+
+    #[instrument(skip_all, fields(username = %user.username))]
+    async fn list_instances(
+        user: UserClaims,
+        Query(query): Query<ListInstancesQuery>,
+        headers: HeaderMap,
+        Extension(storage): Extension<Storage>,
+    ) -> Result<impl IntoResponse, InstanceError> {
+        let mut instances = Vec::new();
+        let mut user_err = None;
+        let mut revision = 0;
+        storage
+            .read_only(|state| {
+                revision = storage.revision();
+                if query.shared {
+                    for u in &state.users {
+                        for i in &u.instances {
+                            if u.username != user.username && i.is_shared_with(&user.username) {
+                                let mut instance = InstanceDto::from(i);
+                                instance.owner = u.username.clone();
+                                instance.password = String::new();
+                                instances.push(instance);
+                            }
+                        }
+                    }
+                    return;
+                }
+                if query.project.is_empty() {
+                    if let Some(u) = state.find_user(&user.username) {
+                        instances = u
+                            .instances
+                            .iter()
+                            .filter(|i| i.project.is_none())
+                            .map(InstanceDto::from)
+                            .collect();
+                    }
+                    return;
+                }
+                match state.find_project(&query.project) {
+                    Some(p) if p.is_member(&user.username) || user.is_admin() => {}
+                    _ => {
+                        user_err = Some(InstanceError::UnknownProject(query.project.clone()));
+                        return;
+                    }
+                }
+                for u in &state.users {
+                    for i in &u.instances {
+                        if i.project.as_deref() == Some(query.project.as_str()) {
+                            let mut instance = InstanceDto::from(i);
+                            instance.owner = u.username.clone();
+                            // Only the owner may see the password.
+                            if u.username != user.username {
+                                instance.password = String::new();
+                            }
+                            instances.push(instance);
+                        }
+                    }
+                }
+            })
+            .await;
+        if let Some(e) = user_err {
+            return Err(e);
+        }
+        // The frontend polls the instances, which mostly haven't changed since the last time.
+        let etag = list_instances_etag(revision, &user.username, &query);
+        if etag_matches(&headers, &etag) {
+            return Ok((StatusCode::NOT_MODIFIED, [(ETAG, etag)], ()).into_response());
+        }
+        sort_instances(&mut instances, &query.sort)?;
+        for i in &mut instances {
+            let owner = if i.owner.is_empty() {
+                &user.username
+            } else {
+                &i.owner
+            };
+            if i.external_ip.is_some() {
+                i.dns_name = dns::record_name(owner, &i.name);
+            }
+        }
+        Ok(([(ETAG, etag)], Json(ListInstancesResponse { instances })).into_response())
+    }
+
+    /// Returns an instance of the user, or one owned by another user which is shared with the
+    /// user or belongs to a project the user is a member of.
+    #[instrument(skip_all, fields(username = %user.username, instance = %instance_name, owner = %query.owner))]
+    async fn get_instance(
+        user: UserClaims,
+        Path(instance_name): Path<String>,
+        Query(query): Query<InstanceOwnerQuery>,
+        Extension(storage): Extension<Storage>,
+    ) -> Result<impl IntoResponse, InstanceError> {
+        let owner = if query.owner.is_empty() {
+            user.username.clone()
+        } else {
+            query.owner.clone()
+        };
+        let mut instance = None;
+        storage
+            .read_only(|state| {
+                instance = state
+                    .find_user(&owner)
+                    .and_then(|u| u.find_instance(&instance_name))
+                    .filter(|i| is_visible(state, &user, &owner, i))
+                    .map(InstanceDto::from);
+            })
+            .await;
+        let instance = instance.ok_or(InstanceError::NotFound)?;
+        Ok(Json(present_instance(instance, &user, &owner)))
+    }
+
+    /// Returns the instance with the ID, which stays the same across renames and ownership
+    /// transfers, if it's visible to the user as in `get_instance`.
+    #[instrument(skip_all, fields(username = %user.username, id = %id))]
+    async fn get_instance_by_id(
+        _rewritten: Rewritten,
+        user: UserClaims,
+        Path(id): Path<String>,
+        Extension(storage): Extension<Storage>,
+    ) -> Result<impl IntoResponse, InstanceError> {
+        let mut found = None;
+        storage
+            .read_only(|state| {
+                found = state
+                    .find_instance_by_id(&id)
+                    .filter(|(u, i)| is_visible(state, &user, &u.username, i))
+                    .map(|(u, i)| (u.username.clone(), InstanceDto::from(i)));
+            })
+            .await;
+        let (owner, instance) = found.ok_or(InstanceError::NotFound)?;
+        Ok(Json(present_instance(instance, &user, &owner)))
+    }
+
+    #[instrument(skip_all, fields(username = %user.username, action = %req.action))]
+    async fn batch_instances(
+        _rewritten: Rewritten,
+        _leader: Leader,
+        user: UserClaims,
+        Json(req): Json<BatchInstancesRequest>,
+        Extension(storage): Extension<Storage>,
+    ) -> Result<impl IntoResponse, InstanceError> {
+        if !matches!(req.action.as_str(), "start" | "stop" | "delete") {
+            return Err(InstanceError::InvalidArgs("action".to_string()));
+        }
+        if req.names.is_empty() {
+            return Err(InstanceError::InvalidArgs("names".to_string()));
+        }
+        let config = config::current();
+        let retention = config.recycle_bin_retention;
+        let now = Utc::now().timestamp();
+        let mut results = Vec::new();
+        storage
+            .read_write(|state| {
+                results.clear();
+                let mut changed = false;
+                for name in &req.names {
+                    let room = req.action != "start"
+                        || state
+                            .find_user(&user.username)
+                            .and_then(|u| u.find_instance(name))
+                            .map_or(true, |i| state.has_room_to_start(i));
+                    let res = match state
+                        .find_mut_user(&user.username)
+                        .and_then(|u| u.find_mut_instance(name))
+                    {
+                        None => Err(InstanceError::NotFound),
+                        Some(i) if !room && i.stage == InstanceStage::Stopped => {
+                            Err(InstanceError::ResourceExhausted)
+                        }
+                        Some(_)
+                            if req.action == "delete"
+                                && config.delete_confirmation
+                                && !req.confirm.contains(name) =>
+                        {
+                            Err(InstanceError::ConfirmationRequired)
+                        }
+                        Some(i) => {
+                            let res = match req.action.as_str() {
+                                "start" => lifecycle::apply(i, Action::Start),
+                                "stop" => lifecycle::apply(i, Action::Stop),
+                                _ => lifecycle::delete(i, retention, now),
+                            };
+                            res.map(|applied| changed |= applied)
+                        }
+                    };
+                    results.push(BatchInstanceResult {
+                        name: name.clone(),
+                        code: res.as_ref().err().map(|e| e.code().to_string()),
+                        error: res.err().map(|e| e.to_string()),
+                    });
+                }
+                changed
+            })
+            .await
+            .map_err(|e| {
+                warn!(
+                    username = user.username.as_str(),
+                    error = e.to_string().as_str(),
+                    "batch instances encountered error"
+                );
+                InstanceError::UpdateFailed
+            })?;
+        Ok(Json(BatchInstancesResponse { results }))
+    }
+
+    #[instrument(skip_all, fields(username = %user.username))]
+    async fn list_topology_templates(
+        user: UserClaims,
+        Extension(storage): Extension<Storage>,
+    ) -> Result<impl IntoResponse, InstanceError> {
+        let mut topology_templates = Vec::new();
+        storage
+            .read_only(|state| {
+                topology_templates = state
+                    .topology_templates
+                    .iter()
+                    .map(TopologyTemplateDto::from)
+                    .collect()
+            })
+            .await;
+        Ok(Json(ListTopologyTemplatesResponse { topology_templates }))
+    }
+
+    /// Creates the group and then its members one at a time. If a member can't be created, the
+    /// members created so far are deleted along with the group, so that no partial group is
+    /// left behind.
+    #[instrument(skip_all, fields(username = %user.username, cluster = %req.name, size = req.size))]
+    async fn create_cluster(
+        _leader: Leader,
+        user: UserClaims,
+        Json(req): Json<CreateInstanceGroupRequest>,
+        Extension(storage): Extension<Storage>,
+    ) -> Result<impl IntoResponse, InstanceError> {
+        // The requests creating the members, along with their roles in the topology if any.
+        let group_name = &req.name;
+        let mut topology = None;
+        let planned: Vec<(CreateInstanceRequest, Option<String>)> = match &req.template {
+            InstanceGroupTemplate::Instance(template) => (1..=req.size)
+                .map(|index| {
+                    let mut member = template.clone();
+                    member.name = format!("{}-{}", group_name, index);
+                    (member, None)
+                })
+                .collect(),
+            InstanceGroupTemplate::Topology(topology_name) => {
+                storage
+                    .read_only(|state| {
+                        topology = state.find_topology_template(topology_name).cloned()
+                    })
+                    .await;
+                let topology = topology
+                    .as_ref()
+                    .ok_or_else(|| InstanceError::UnknownTopologyTemplate(topology_name.clone()))?;
+                topology
+                    .roles
+                    .iter()
+                    .flat_map(|r| {
+                        (1..=r.count).map(move |index| {
+                            let member = CreateInstanceRequest {
+                                name: format!("{}-{}-{}", group_name, r.name, index),
+                                cpu: r.cpu,
+                                memory: r.memory,
+                                disk_size: r.disk_size,
+                                image: r.image.clone(),
+                                runtime: r.runtime.clone(),
+                                ..Default::default()
+                            };
+                            (member, Some(r.name.clone()))
+                        })
+                    })
+                    .collect()
+            }
+        };
+        if planned.is_empty() || planned.len() > MAX_INSTANCE_GROUP_SIZE {
+            return Err(InstanceError::InvalidArgs("size".to_string()));
+        }
+        if !verify_instance_name(&req.name)
+            || planned.iter().any(|(m, _)| !verify_instance_name(&m.name))
+        {
+            return Err(InstanceError::InvalidArgs("name".to_string()));
+        }
+        if req.labels.len() > MAX_LABELS || req.labels.iter().any(|(k, v)| !verify_label(k, v)) {
+            return Err(InstanceError::InvalidArgs("labels".to_string()));
+        }
+        let group = InstanceGroup {
+            name: req.name.clone(),
+            owner: user.username.clone(),
+            size: planned.len(),
+            labels: req.labels.clone(),
+            created_at: Utc::now().timestamp(),
+            topology: topology.as_ref().map(|t| t.name.clone()),
+            ports: topology
+                .iter()
+                .flat_map(|t| &t.roles)
+                .map(|r| (r.name.clone(), r.port))
+                .collect(),
+            deleted_at: None,
+        };
+        storage
+            .try_read_write(|state| {
+                if state
+                    .find_instance_group(&user.username, &group.name)
+                    .is_some()
+                {
+                    return Err(InstanceError::ClusterAlreadyExists);
+                }
+                state.instance_groups.push(group.clone());
+                Ok(true)
+            })
+            .await
+            .map_err(|e| {
+                warn!(
+                    username = user.username.as_str(),
+                    cluster = group.name.as_str(),
+                    error = e.to_string().as_str(),
+                    "create cluster encountered error"
+                );
+                InstanceError::CreateFailed
+            })??;
+
+        let mut members = Vec::new();
+        let mut failure = None;
+        for (mut member, role) in planned {
+            let name = member.name.clone();
+            member.labels.extend(group.labels.clone());
+            let res = create_instance(
+                Leader,
+                user.clone(),
+                Query(DryRunQuery::default()),
+                Json(member),
+                HeaderMap::new(),
+                Extension(storage.clone()),
+            )
+            .await;
+            if let Err(e) = res {
+                failure = Some(e);
+                break;
+            }
+            members.push((name, role));
+        }
+
+        let mut created = None;
+        let res = storage
+            .read_write(|state| {
+                if let Some(u) = state.find_mut_user(&user.username) {
+                    for (name, role) in &members {
+                        if let Some(i) = u.find_mut_instance(name) {
+                            if failure.is_some() {
+                                lifecycle::apply(i, Action::Delete).ok();
+                            }
+                            i.instance_group = Some(group.name.clone());
+                            i.group_role = role.clone();
+                        }
+                    }
+                }
+                if failure.is_some() {
+                    state
+                        .instance_groups
+                        .retain(|g| g.owner != user.username || g.name != group.name);
+                } else {
+                    created = Some(present_instance_group(state, &user, &group));
+                }
+                true
+            })
+            .await;
+        if let Err(e) = res {
+            warn!(
+                username = user.username.as_str(),
+                cluster = group.name.as_str(),
+                error = e.to_string().as_str(),
+                "create cluster encountered error"
+            );
+            return Err(InstanceError::CreateFailed);
+        }
+        if let Some(e) = failure {
+            info!(
+                username = user.username.as_str(),
+                cluster = group.name.as_str(),
+                created = members.len(),
+                error = e.to_string().as_str(),
+                "deleted members of cluster which failed to be created"
+            );
+            return Err(e);
+        }
+        Ok((StatusCode::CREATED, Json(created.unwrap())))
+    }
+
+    #[instrument(skip_all, fields(username = %user.username))]
+    async fn list_clusters(
+        user: UserClaims,
+        Extension(storage): Extension<Storage>,
+    ) -> Result<impl IntoResponse, InstanceError> {
+        let mut instance_groups = Vec::new();
+        storage
+            .read_only(|state| {
+                instance_groups = state
+                    .instance_groups
+                    .iter()
+                    .filter(|g| g.owner == user.username)
+                    .map(|g| present_instance_group(state, &user, g))
+                    .collect();
+            })
+            .await;
+        Ok(Json(ListInstanceGroupsResponse { instance_groups }))
+    }
+
+    #[instrument(skip_all, fields(username = %user.username, cluster = %cluster_name))]
+    async fn get_cluster(
+        user: UserClaims,
+        Path(cluster_name): Path<String>,
+        Extension(storage): Extension<Storage>,
+    ) -> Result<impl IntoResponse, InstanceError> {
+        let mut group = None;
+        storage
+            .read_only(|state| {
+                group = state
+                    .find_instance_group(&user.username, &cluster_name)
+                    .map(|g| present_instance_group(state, &user, g));
+            })
+            .await;
+        Ok(Json(group.ok_or(InstanceError::ClusterNotFound)?))
+    }
+
+    /// Deletes the members as `delete_instance` does, so they go to the recycle bin if it's
+    /// enabled. The group is forgotten by the recycle bin purger once no member is left.
+    #[instrument(skip_all, fields(username = %user.username, cluster = %cluster_name))]
+    async fn delete_cluster(
+        _leader: Leader,
+        user: UserClaims,
+        Path(cluster_name): Path<String>,
+        Query(query): Query<DeleteClusterQuery>,
+        Extension(storage): Extension<Storage>,
+    ) -> Result<impl IntoResponse, InstanceError> {
+        let config = config::current();
+        if config.delete_confirmation && query.confirm != cluster_name {
+            return Err(InstanceError::ConfirmationRequired);
+        }
+        let now = Utc::now().timestamp();
+        storage
+            .try_read_write(|state| {
+                match state
+                    .instance_groups
+                    .iter_mut()
+                    .find(|g| g.owner == user.username && g.name == cluster_name)
+                {
+                    Some(g) => g.deleted_at = Some(now),
+                    None => return Err(InstanceError::ClusterNotFound),
+                }
+                if let Some(u) = state.find_mut_user(&user.username) {
+                    for i in u
+                        .instances
+                        .iter_mut()
+                        .filter(|i| i.instance_group.as_deref() == Some(cluster_name.as_str()))
+                    {
+                        lifecycle::delete(i, config.recycle_bin_retention, now).ok();
+                    }
+                }
+                state.forget_deleted_instance_groups();
+                Ok(true)
+            })
+            .await
+            .map_err(|e| {
+                warn!(
+                    username = user.username.as_str(),
+                    cluster = cluster_name.as_str(),
+                    error = e.to_string().as_str(),
+                    "delete cluster encountered error"
+                );
+                InstanceError::DeleteFailed
+            })??;
+        Ok(StatusCode::NO_CONTENT)
+    }
+
+    #[instrument(skip_all, fields(username = %user.username, cluster = %cluster_name))]
+    async fn start_cluster(
+        _leader: Leader,
+        user: UserClaims,
+        Path(cluster_name): Path<String>,
+        Extension(storage): Extension<Storage>,
+    ) -> Result<impl IntoResponse, InstanceError> {
+        apply_cluster_action(user, &cluster_name, "start", storage).await
+    }
+
+    #[instrument(skip_all, fields(username = %user.username, cluster = %cluster_name))]
+    async fn stop_cluster(
+        _leader: Leader,
+        user: UserClaims,
+        Path(cluster_name): Path<String>,
+        Extension(storage): Extension<Storage>,
+    ) -> Result<impl IntoResponse, InstanceError> {
+        apply_cluster_action(user, &cluster_name, "stop", storage).await
+    }
+
+    /// Takes the action on the members of the group as a batch, see `batch_instances`.
+    async fn apply_cluster_action(
+        user: UserClaims,
+        cluster_name: &str,
+        action: &str,
+        storage: Storage,
+    ) -> Result<Response, InstanceError> {
+        let mut names = None;
+        storage
+            .read_only(|state| {
+                if state
+                    .find_instance_group(&user.username, cluster_name)
+                    .is_none()
+                {
+                    return;
+                }
+                names = Some(
+                    state
+                        .find_user(&user.username)
+                        .into_iter()
+                        .flat_map(|u| &u.instances)
+                        .filter(|i| i.instance_group.as_deref() == Some(cluster_name))
+                        .map(|i| i.name.clone())
+                        .collect::<Vec<_>>(),
+                );
+            })
+            .await;
+        let names = names.ok_or(InstanceError::ClusterNotFound)?;
+        if names.is_empty() {
+            return Ok(Json(BatchInstancesResponse::default()).into_response());
+        }
+        let req = BatchInstancesRequest {
+            names,
+            action: action.to_owned(),
+        };
+        batch_instances(Rewritten, Leader, user, Json(req), Extension(storage))
+            .await
+            .map(IntoResponse::into_response)
+    }
+
+    #[instrument(skip_all, fields(username = %user.username, instance = %instance_name, to = %req.to))]
+    async fn transfer_ownership(
+        _leader: Leader,
+        user: UserClaims,
+        Path(instance_name): Path<String>,
+        Json(req): Json<TransferOwnershipRequest>,
+        Extension(storage): Extension<Storage>,
+    ) -> Result<impl IntoResponse, InstanceError> {
+        if req.to == user.username {
+            return Err(InstanceError::InvalidArgs("to".to_string()));
+        }
+        storage
+            .try_read_write(|state| {
+                let owner = match state.find_mut_user(&user.username) {
+                    Some(u) => u,
+                    None => return Err(InstanceError::NotFound),
+                };
+                let mut instance = match owner.find_instance(&instance_name) {
+                    Some(i) if i.stage == InstanceStage::Deleted => {
+                        return Err(InstanceError::AlreadyDeleted)
+                    }
+                    Some(i) => i.clone(),
+                    None => return Err(InstanceError::NotFound),
+                };
+                // The backend resources are left untouched, so their name must not change.
+                instance.backend_name = Some(instance.backend_name(&user.username));
+                // Groups don't change hands with their members.
+                instance.instance_group = None;
+                instance.group_role = None;
+                owner.remove_instance(&instance_name);
+
+                if let Some(project) = &instance.project {
+                    if !state
+                        .find_project(project)
+                        .map_or(false, |p| p.is_member(&req.to))
+                    {
+                        return Err(InstanceError::InvalidArgs("to".to_string()));
+                    }
+                }
+                // The instance no longer counts towards the quotas of the previous owner.
+                let (quota_cpu, quota_memory) = if instance.counts_towards_compute_quota() {
+                    (instance.cpu, instance.memory)
+                } else {
+                    (0, 0)
+                };
+                if let Some(e) = check_shared_quotas(
+                    state,
+                    &req.to,
+                    &instance_name,
+                    instance.project.as_deref(),
+                    quota_cpu,
+                    quota_memory,
+                    instance.total_disk_size(),
+                ) {
+                    return Err(e);
+                }
+                let recipient = match state.find_mut_user(&req.to) {
+                    Some(u) => u,
+                    None => return Err(InstanceError::InvalidArgs("to".to_string())),
+                };
+                if recipient.find_instance(&instance_name).is_some() {
+                    return Err(InstanceError::AlreadyExists);
+                }
+                let quotas = [
+                    recipient.instance_quota(),
+                    recipient.cpu_quota(),
+                    recipient.memory_quota(),
+                    recipient.disk_quota(),
+                ];
+                if let Some(e) = check_aggregate_quotas(
+                    "Recipient",
+                    quotas,
+                    recipient.instances.iter(),
+                    true,
+                    quota_cpu,
+                    quota_memory,
+                    instance.total_disk_size(),
+                ) {
+                    return Err(e);
+                }
+
+                // Access granted by the previous owner is revoked.
+                instance.shares.clear();
+                instance.add_event(format!(
+                    "ownership transferred from {} to {}",
+                    user.username, req.to
+                ));
+                recipient.instances.push(instance);
+                Ok(true)
+            })
+            .await
+            .map_err(|e| {
+                warn!(
+                    username = user.username.as_str(),
+                    instance = instance_name.as_str(),
+                    error = e.to_string().as_str(),
+                    "transfer ownership encountered error"
+                );
+                InstanceError::UpdateFailed
+            })??;
+        Ok(StatusCode::NO_CONTENT)
+    }
+
+    #[instrument(skip_all, fields(username = %user.username, instance = %instance_name, grantee = %grantee))]
+    async fn share_instance(
+        _leader: Leader,
+        user: UserClaims,
+        Path((instance_name, grantee)): Path<(String, String)>,
+        Json(req): Json<ShareInstanceRequest>,
+        Extension(storage): Extension<Storage>,
+    ) -> Result<impl IntoResponse, InstanceError> {
+        if grantee == user.username {
+            return Err(InstanceError::InvalidArgs("username".to_string()));
+        }
+        if req
+            .ssh_authorized_keys
+            .iter()
+            .any(|k| !verify_ssh_authorized_key(k))
+        {
+            return Err(InstanceError::InvalidArgs(
+                "ssh_authorized_keys".to_string(),
+            ));
+        }
+        storage
+            .try_read_write(|state| {
+                if state.find_user(&grantee).is_none() {
+                    return Err(InstanceError::InvalidArgs("username".to_string()));
+                }
+                let instance = match state
+                    .find_mut_user(&user.username)
+                    .and_then(|u| u.find_mut_instance(&instance_name))
+                {
+                    Some(instance) => instance,
+                    None => return Err(InstanceError::NotFound),
+                };
+                let share = InstanceShare {
+                    username: grantee.clone(),
+                    ssh_authorized_keys: req.ssh_authorized_keys.clone(),
+                };
+                match instance.shares.iter_mut().find(|s| s.username == grantee) {
+                    Some(s) => *s = share,
+                    None => {
+                        instance.shares.push(share);
+                        instance.add_event(format!("shared with {}", grantee));
+                    }
+                }
+                Ok(true)
+            })
+            .await
+            .map_err(|e| {
+                warn!(
+                    username = user.username.as_str(),
+                    instance = instance_name.as_str(),
+                    error = e.to_string().as_str(),
+                    "share instance encountered error"
+                );
+                InstanceError::UpdateFailed
+            })??;
+        Ok(StatusCode::NO_CONTENT)
+    }
+
+    #[instrument(skip_all, fields(username = %user.username, instance = %instance_name, grantee = %grantee))]
+    async fn unshare_instance(
+        _leader: Leader,
+        user: UserClaims,
+        Path((instance_name, grantee)): Path<(String, String)>,
+        Extension(storage): Extension<Storage>,
+    ) -> Result<impl IntoResponse, InstanceError> {
+        storage
+            .try_read_write(|state| {
+                let instance = match state
+                    .find_mut_user(&user.username)
+                    .and_then(|u| u.find_mut_instance(&instance_name))
+                {
+                    Some(instance) => instance,
+                    None => return Err(InstanceError::NotFound),
+                };
+                if !instance.is_shared_with(&grantee) {
+                    return Ok(false);
+                }
+                instance.shares.retain(|s| s.username != grantee);
+                instance.add_event(format!("unshared with {}", grantee));
+                Ok(true)
+            })
+            .await
+            .map_err(|e| {
+                warn!(
+                    username = user.username.as_str(),
+                    instance = instance_name.as_str(),
+                    error = e.to_string().as_str(),
+                    "unshare instance encountered error"
+                );
+                InstanceError::UpdateFailed
+            })??;
+        Ok(StatusCode::NO_CONTENT)
+    }
+
+    /// Returns an OpenSSH config with a `<username>-<instance>` host per reachable instance of the
+    /// user, so that users can log in with e.g. `ssh alice-dev`.
+    async fn get_ssh_config(
+        user: UserClaims,
+        Extension(storage): Extension<Storage>,
+    ) -> impl IntoResponse {
+        let mut config = String::new();
+        storage
+            .read_only(|state| {
+                let u = match state.find_user(&user.username) {
+                    Some(u) => u,
+                    None => return,
+                };
+                for i in &u.instances {
+                    if i.stage == InstanceStage::Deleted {
+                        continue;
+                    }
+                    let (host, port, internal) = match i.ssh_target() {
+                        Some(target) => target,
+                        None => continue,
+                    };
+                    if internal && SSH_BASTION.is_empty() {
+                        continue;
+                    }
+                    config.push_str(&format!(
+                        "Host {}-{}\n    HostName {}\n    Port {}\n    User root\n",
+                        user.username, i.name, host, port
+                    ));
+                    if internal {
+                        config.push_str(&format!("    ProxyJump {}\n", SSH_BASTION.as_str()));
+                    }
+                    config.push('\n');
+                }
+            })
+            .await;
+        ([(CONTENT_TYPE, "text/plain")], config)
+    }
+
+    /// Returns an OpenSSH known_hosts file with the host keys of the reachable instances of the
+    /// user, under the addresses `get_ssh_config` connects to and their DNS names.
+    async fn get_known_hosts(
+        user: UserClaims,
+        Extension(storage): Extension<Storage>,
+    ) -> impl IntoResponse {
+        let mut known_hosts = String::new();
+        storage
+            .read_only(|state| {
+                let u = match state.find_user(&user.username) {
+                    Some(u) => u,
+                    None => return,
+                };
+                for i in &u.instances {
+                    if i.stage == InstanceStage::Deleted || i.ssh_host_keys.is_empty() {
+                        continue;
+                    }
+                    let (host, port, internal) = match i.ssh_target() {
+                        Some(target) => target,
+                        None => continue,
+                    };
+                    let mut hosts = vec![host];
+                    if !internal {
+                        hosts.extend(dns::record_name(&user.username, &i.name));
+                    }
+                    let hosts: Vec<String> = hosts
+                        .into_iter()
+                        .map(|h| match port {
+                            22 => h,
+                            port => format!("[{}]:{}", h, port),
+                        })
+                        .collect();
+                    for key in &i.ssh_host_keys {
+                        known_hosts.push_str(&format!("{} {}\n", hosts.join(","), key));
+                    }
+                }
+            })
+            .await;
+        ([(CONTENT_TYPE, "text/plain")], known_hosts)
+    }
+
+    async fn get_home_volume(
+        user: UserClaims,
+        Extension(storage): Extension<Storage>,
+    ) -> Result<impl IntoResponse, InstanceError> {
+        let mut size = None;
+        storage
+            .read_only(|state| {
+                size = state
+                    .find_user(&user.username)
+                    .and_then(|u| u.home_volume.as_ref())
+                    .map(|v| v.size)
+            })
+            .await;
+        let size = size.ok_or(InstanceError::HomeVolumeNotFound)?;
+        Ok(Json(HomeVolumeDto {
+            size,
+            mount_path: config::current().home_volume_mount_path(&user.username),
+        }))
+    }
+
+    /// Opts the user in to a home volume, or grows it. The instances of the user mount it the
+    /// next time they are started.
+    #[instrument(skip_all, fields(username = %user.username))]
+    async fn put_home_volume(
+        _leader: Leader,
+        user: UserClaims,
+        Json(req): Json<HomeVolumeDto>,
+        Extension(storage): Extension<Storage>,
+    ) -> Result<impl IntoResponse, InstanceError> {
+        if !config::current().home_volumes_enabled() {
+            return Err(InstanceError::HomeVolumeUnavailable);
+        }
+        if req.size == 0 {
+            return Err(InstanceError::InvalidArgs("size".to_string()));
+        }
+        storage
+            .try_read_write(|state| {
+                let u = match state.find_mut_user(&user.username) {
+                    Some(u) => u,
+                    None => return Err(InstanceError::HomeVolumeUnavailable),
+                };
+                let current = u.home_volume_size();
+                // Volumes can't be shrunk by their backends.
+                if req.size < current {
+                    return Err(InstanceError::InvalidArgs("size".to_string()));
+                }
+                if req.size == current {
+                    return Ok(false);
+                }
+                let used = u.used_disk_size();
+                if used + req.size - current > u.disk_quota() {
+                    return Err(InstanceError::QuotaExceeded {
+                        resource: "Disk size".to_string(),
+                        quota: u.disk_quota(),
+                        remaining: u.disk_quota().saturating_sub(used),
+                        requested: req.size - current,
+                        unit: "GiB".to_string(),
+                    });
+                }
+                match &mut u.home_volume {
+                    Some(v) => v.size = req.size,
+                    None => {
+                        u.home_volume = Some(HomeVolume {
+                            size: req.size,
+                            created_at: Utc::now().timestamp(),
+                        })
+                    }
+                }
+                info!(
+                    username = user.username.as_str(),
+                    size = req.size,
+                    "home volume updated"
+                );
+                Ok(true)
+            })
+            .await
+            .map_err(|e| {
+                warn!(
+                    username = user.username.as_str(),
+                    error = e.to_string().as_str(),
+                    "put home volume encountered error"
+                );
+                InstanceError::UpdateFailed
+            })??;
+        Ok(Json(HomeVolumeDto {
+            size: req.size,
+            mount_path: config::current().home_volume_mount_path(&user.username),
+        }))
+    }
+
+    /// Opts the user out of the home volume, whose backend is then deleted by the garbage
+    /// collector along with the data.
+    #[instrument(skip_all, fields(username = %user.username))]
+    async fn delete_home_volume(
+        _leader: Leader,
+        user: UserClaims,
+        Extension(storage): Extension<Storage>,
+    ) -> Result<impl IntoResponse, InstanceError> {
+        storage
+            .try_read_write(|state| {
+                let u = match state.find_mut_user(&user.username) {
+                    Some(u) if u.home_volume.is_some() => u,
+                    _ => return Err(InstanceError::HomeVolumeNotFound),
+                };
+                // The backends can't detach the volume from instances which mount it.
+                if !u.instances.is_empty() {
+                    return Err(InstanceError::HomeVolumeInUse);
+                }
+                u.home_volume = None;
+                info!(username = user.username.as_str(), "home volume deleted");
+                Ok(true)
+            })
+            .await
+            .map_err(|e| {
+                warn!(
+                    username = user.username.as_str(),
+                    error = e.to_string().as_str(),
+                    "delete home volume encountered error"
+                );
+                InstanceError::UpdateFailed
+            })??;
+        Ok(StatusCode::NO_CONTENT)
+    }
+
+    async fn list_volumes(
+        user: UserClaims,
+        Extension(storage): Extension<Storage>,
+    ) -> impl IntoResponse {
+        let mut volumes = Vec::new();
+        storage
+            .read_only(|state| {
+                if let Some(u) = state.find_user(&user.username) {
+                    volumes = u
+                        .detached_volumes
+                        .iter()
+                        .map(DetachedVolumeDto::from)
+                        .collect();
+                }
+            })
+            .await;
+        Json(ListVolumesResponse { volumes })
+    }
+
+    /// Deletes the detached volume, whose backend is then deleted by the garbage collector along
+    /// with the data.
+    #[instrument(skip_all, fields(username = %user.username, volume = %volume_name))]
+    async fn delete_volume(
+        _leader: Leader,
+        user: UserClaims,
+        Path(volume_name): Path<String>,
+        Extension(storage): Extension<Storage>,
+    ) -> Result<impl IntoResponse, InstanceError> {
+        storage
+            .try_read_write(|state| {
+                let u = match state.find_mut_user(&user.username) {
+                    Some(u) if u.find_detached_volume(&volume_name).is_some() => u,
+                    _ => return Err(InstanceError::VolumeNotFound),
+                };
+                u.detached_volumes.retain(|v| v.name != volume_name);
+                info!(
+                    username = user.username.as_str(),
+                    volume = volume_name.as_str(),
+                    "detached volume deleted"
+                );
+                Ok(true)
+            })
+            .await
+            .map_err(|e| {
+                warn!(
+                    username = user.username.as_str(),
+                    volume = volume_name.as_str(),
+                    error = e.to_string().as_str(),
+                    "delete volume encountered error"
+                );
+                InstanceError::UpdateFailed
+            })??;
+        Ok(StatusCode::NO_CONTENT)
+    }
+
+    async fn get_instance_history(
+        user: UserClaims,
+        Path(instance_name): Path<String>,
+        Query(query): Query<InstanceOwnerQuery>,
+        Extension(storage): Extension<Storage>,
+    ) -> Result<impl IntoResponse, InstanceError> {
+        let owner = (!query.owner.is_empty()).then(|| query.owner.as_str());
+        let mut history = None;
+        storage
+            .read_only(|state| {
+                history = state
+                    .find_accessible_instance(&user.username, owner, &instance_name)
+                    .map(|i| {
+                        i.status_history
+                            .iter()
+                            .map(StatusTransitionDto::from)
+                            .collect()
+                    });
+            })
+            .await;
+        match history {
+            Some(history) => Ok(Json(InstanceHistoryResponse { history })),
+            None => Err(InstanceError::NotFound),
+        }
+    }
+
+    /// Explains why the instance was placed on its node, or why it still waits for one.
+    async fn get_instance_scheduling(
+        user: UserClaims,
+        Path(instance_name): Path<String>,
+        Query(query): Query<InstanceOwnerQuery>,
+        Extension(storage): Extension<Storage>,
+    ) -> Result<impl IntoResponse, InstanceError> {
+        let owner = (!query.owner.is_empty()).then(|| query.owner.as_str());
+        let mut scheduling = None;
+        storage
+            .read_only(|state| {
+                scheduling = state
+                    .find_accessible_instance(&user.username, owner, &instance_name)
+                    .map(|i| InstanceSchedulingResponse {
+                        pending: scheduler::is_pending(i),
+                        decision: i.scheduling.as_ref().map(SchedulingDecisionDto::from),
+                    });
+            })
+            .await;
+        match scheduling {
+            Some(scheduling) => Ok(Json(scheduling)),
+            None => Err(InstanceError::NotFound),
+        }
+    }
+
+    /// Streams the status changes of the instances of the user as server-sent events. A `resync`
+    /// event asks the client to list the instances again as some changes were missed.
+    async fn watch_instances(
+        _rewritten: Rewritten,
+        user: UserClaims,
+        Extension(storage): Extension<Storage>,
+    ) -> Sse<impl Stream<Item = Result<Event, axum::Error>>> {
+        let receiver = storage.subscribe_status_changes();
+        let events = stream::unfold(receiver, move |mut receiver| {
+            let username = user.username.clone();
+            async move {
+                loop {
+                    let change = tokio::select! {
+                        change = receiver.recv() => change,
+                        // Let the server shut down gracefully instead of waiting for the clients.
+                        _ = shutdown::triggered() => return None,
+                    };
+                    let event = match change {
+                        Ok(c) if c.username == username => Event::default()
+                            .event("status")
+                            .json_data(InstanceStatusEvent::from(&c)),
+                        Ok(_) => continue,
+                        Err(RecvError::Lagged(_)) => Ok(Event::default().event("resync").data("")),
+                        Err(RecvError::Closed) => return None,
+                    };
+                    return Some((event, receiver));
+                }
+            }
+        });
+        Sse::new(events).keep_alive(KeepAlive::default())
+    }
+
+    async fn list_images(
+        user: UserClaims,
+        Extension(storage): Extension<Storage>,
+    ) -> impl IntoResponse {
+        let mut images = Vec::new();
+        storage
+            .read_only(|state| {
+                images = state
+                    .images
+                    .iter()
+                    .filter(|i| i.available_to(&user.username))
+                    .map(CatalogImageDto::from)
+                    .collect()
+            })
+            .await;
+        Json(ListImagesResponse { images })
+    }
+
+    #[instrument(skip_all, fields(username = %user.username, image = %image_name))]
+    async fn register_image(
+        _leader: Leader,
+        user: UserClaims,
+        Path(image_name): Path<String>,
+        Json(req): Json<RegisterImageRequest>,
+        Extension(storage): Extension<Storage>,
+    ) -> Result<impl IntoResponse, InstanceError> {
+        let name: Image = CatalogImage::custom_name(&user.username, &image_name)
+            .parse()
+            .map_err(|_| InstanceError::InvalidArgs("name".to_string()))?;
+        let family: ImageFamily = req
+            .family
+            .parse()
+            .map_err(|_| InstanceError::InvalidArgs("family".to_string()))?;
+        let arches = if req.arches.is_empty() {
+            vec![Arch::Amd64]
+        } else {
+            req.arches
+                .iter()
+                .map(|a| a.parse())
+                .collect::<Result<Vec<Arch>, _>>()
+                .map_err(|_| InstanceError::InvalidArgs("arches".to_string()))?
+        };
+        let mut sources = BTreeMap::new();
+        if !req.lxd_alias.is_empty() {
+            if !LXD_IMAGE_ALIAS_REGEX.is_match(&req.lxd_alias)
+                || !custom_image_source_allowed(&req.lxd_alias)
+            {
+                return Err(InstanceError::InvalidArgs("lxd_alias".to_string()));
+            }
+            for runtime in [Runtime::Lxc, Runtime::Kvm] {
+                sources.insert(runtime.to_string(), req.lxd_alias.clone());
+            }
+        }
+        if !req.oci_image.is_empty() {
+            if !verify_oci_image(&req.oci_image) || !custom_image_source_allowed(&req.oci_image) {
+                return Err(InstanceError::InvalidArgs("oci_image".to_string()));
+            }
+            for runtime in [Runtime::Kata, Runtime::Runc] {
+                sources.insert(runtime.to_string(), req.oci_image.clone());
+            }
+        }
+        if sources.is_empty() {
+            return Err(InstanceError::InvalidArgs("lxd_alias".to_string()));
+        }
+        let image = CatalogImage {
+            name: name.clone(),
+            family,
+            sources,
+            arches,
+            enabled: true,
+            default: false,
+            owner: Some(user.username.clone()),
+            mounts: Vec::new(),
+        };
+        storage
+            .try_read_write(|state| {
+                match state.images.iter_mut().find(|i| i.name == name) {
+                    Some(i) if i.owner.as_deref() == Some(user.username.as_str()) => {
+                        *i = image.clone()
+                    }
+                    // Images of admins take precedence.
+                    Some(_) => return Err(InstanceError::InvalidArgs("name".to_string())),
+                    None => state.images.push(image.clone()),
+                }
+                Ok(true)
+            })
+            .await
+            .map_err(|e| {
+                warn!(
+                    username = user.username.as_str(),
+                    image = image_name.as_str(),
+                    error = e.to_string().as_str(),
+                    "register image encountered error"
+                );
+                InstanceError::UpdateFailed
+            })??;
+        Ok(Json(CatalogImageDto::from(&image)))
+    }
+
+    #[instrument(skip_all, fields(username = %user.username, image = %image_name))]
+    async fn unregister_image(
+        _leader: Leader,
+        user: UserClaims,
+        Path(image_name): Path<String>,
+        Extension(storage): Extension<Storage>,
+    ) -> Result<impl IntoResponse, InstanceError> {
+        let name = CatalogImage::custom_name(&user.username, &image_name);
+        let mut found = false;
+        storage
+            .read_write(|state| {
+                let len = state.images.len();
+                state.images.retain(|i| {
+                    i.name.to_string() != name || i.owner.as_deref() != Some(user.username.as_str())
+                });
+                found = state.images.len() != len;
+                found
+            })
+            .await
+            .map_err(|e| {
+                warn!(
+                    username = user.username.as_str(),
+                    image = image_name.as_str(),
+                    error = e.to_string().as_str(),
+                    "unregister image encountered error"
+                );
+                InstanceError::UpdateFailed
+            })?;
+        if !found {
+            return Err(InstanceError::NotFound);
+        }
+        Ok(StatusCode::NO_CONTENT)
+    }
+
+    async fn list_image_builds(
+        user: UserClaims,
+        Extension(storage): Extension<Storage>,
+    ) -> impl IntoResponse {
+        let mut image_builds = Vec::new();
+        storage
+            .read_only(|state| {
+                image_builds = state
+                    .image_builds
+                    .iter()
+                    .filter(|b| b.username == user.username)
+                    .map(ImageBuildDto::from)
+                    .collect()
+            })
+            .await;
+        Json(ListImageBuildsResponse { image_builds })
+    }
+
+    #[instrument(skip_all, fields(username = %user.username, image = %req.name))]
+    async fn create_image_build(
+        _leader: Leader,
+        user: UserClaims,
+        Json(req): Json<CreateImageBuildRequest>,
+        Extension(storage): Extension<Storage>,
+    ) -> Result<impl IntoResponse, InstanceError> {
+        // The builder instance is named after the image and must be a valid instance name.
+        let instance_name = format!("build-{}", req.name);
+        if !verify_instance_name(&req.name) || !verify_instance_name(&instance_name) {
+            return Err(InstanceError::InvalidArgs("name".to_string()));
+        }
+        let name: Image = CatalogImage::custom_name(&user.username, &req.name)
+            .parse()
+            .map_err(|_| InstanceError::InvalidArgs("name".to_string()))?;
+        let base_image: Image = req
+            .base_image
+            .parse()
+            .map_err(|_| InstanceError::InvalidArgs("base_image".to_string()))?;
+        let runtime = if req.runtime.is_empty() {
+            Runtime::Lxc
+        } else {
+            req.runtime
+                .parse()
+                .map_err(|_| InstanceError::InvalidArgs("runtime".to_string()))?
+        };
+        // Images are published on the LXD server, so only the LXD runtimes can build them.
+        if runtime != Runtime::Lxc && runtime != Runtime::Kvm {
+            return Err(InstanceError::InvalidArgs("runtime".to_string()));
+        }
+        if LXD_CLIENT_CERT.is_empty() {
+            return Err(InstanceError::RuntimeUnavailable {
+                runtime: runtime.to_string(),
+            });
+        }
+        if req.script.trim().is_empty() {
+            return Err(InstanceError::InvalidArgs("script".to_string()));
+        }
+
+        let mut build = ImageBuild {
+            name: name.clone(),
+            username: user.username.clone(),
+            base_image: base_image.clone(),
+            runtime: runtime.clone(),
+            script: req.script.clone(),
+            instance_name: instance_name.clone(),
+            status: ImageBuildStatus::Pending,
+            operation: None,
+            events: Vec::new(),
+        };
+        build.add_event(format!("build of {} from {} requested", name, base_image));
+        storage
+            .try_read_write(|state| {
+                match state.find_image(Some(&base_image), &user.username) {
+                    Some(i) if i.source(&runtime).is_some() => {}
+                    _ => {
+                        return Err(InstanceError::ImageUnavailable {
+                            image: base_image.to_string(),
+                            runtime: runtime.to_string(),
+                        });
+                    }
+                }
+                // Images of admins take precedence.
+                if state
+                    .images
+                    .iter()
+                    .any(|i| i.name == name && i.owner.as_deref() != Some(user.username.as_str()))
+                {
+                    return Err(InstanceError::InvalidArgs("name".to_string()));
+                }
+                let u = match state.find_user(&user.username) {
+                    Some(u) => u,
+                    None => return Err(InstanceError::CreateFailed),
+                };
+                if u.find_instance(&instance_name).is_some() {
+                    return Err(InstanceError::AlreadyExists);
+                }
+                // Finished builds of the image are replaced by the new one.
+                let builds = &mut state.image_builds;
+                if builds
+                    .iter()
+                    .any(|b| b.name == build.name && !b.is_finished())
+                {
+                    return Err(InstanceError::AlreadyExists);
+                }
+                builds.retain(|b| b.name != build.name);
+                builds.push(build.clone());
+                Ok(true)
+            })
+            .await
+            .map_err(|e| {
+                warn!(
+                    username = user.username.as_str(),
+                    image = req.name.as_str(),
+                    error = e.to_string().as_str(),
+                    "create image build encountered error"
+                );
+                InstanceError::CreateFailed
+            })??;
+        Ok((StatusCode::ACCEPTED, Json(ImageBuildDto::from(&build))))
+    }
+
+    /// Applies `f` to the schedule of the instance, mapping a missing or deleted instance to the
+    /// corresponding error.
+    async fn update_schedule<F>(
+        storage: &Storage,
+        username: &str,
+        instance_name: &str,
+        mut f: F,
+    ) -> Result<(), InstanceError>
+    where
+        F: FnMut(&mut Option<PowerSchedule>),
+    {
+        match storage
+            .try_read_write(|state| {
+                match state
+                    .find_mut_user(username)
+                    .and_then(|u| u.find_mut_instance(instance_name))
+                {
+                    Some(instance) if instance.stage == InstanceStage::Deleted => {
+                        Err(InstanceError::AlreadyDeleted)
+                    }
+                    Some(instance) => {
+                        f(&mut instance.schedule);
+                        Ok(true)
+                    }
+                    None => Err(InstanceError::NotFound),
+                }
+            })
+            .await
+        {
+            Ok(res) => res?,
+            Err(e) => {
+                warn!(
+                    username = username,
+                    instance = instance_name,
+                    error = e.to_string().as_str(),
+                    "update schedule encountered error"
+                );
+                return Err(InstanceError::ScheduleFailed);
+            }
+        }
+        Ok(())
+    }
+
+    #[instrument(skip_all, fields(username = %user.username, instance = %instance_name))]
+    async fn set_schedule(
+        _leader: Leader,
+        user: UserClaims,
+        Path(instance_name): Path<String>,
+        Json(req): Json<PowerScheduleDto>,
+        Extension(storage): Extension<Storage>,
+    ) -> Result<impl IntoResponse, InstanceError> {
+        let schedule = PowerSchedule {
+            start_at: req.start_at,
+            stop_at: req.stop_at,
+            days: req.days,
+            utc_offset: req.utc_offset,
+            skip_until: req.skip_until,
+            // Actions due before the schedule is set are not applied.
+            last_due: Utc::now().timestamp(),
+        };
+        schedule
+            .validate()
+            .map_err(|_| InstanceError::InvalidArgs("schedule".to_string()))?;
+        update_schedule(&storage, &user.username, &instance_name, |s| {
+            *s = Some(schedule.clone())
+        })
+        .await?;
+        Ok(Json(PowerScheduleDto::from(&schedule)))
+    }
+
+    #[instrument(skip_all, fields(username = %user.username, instance = %instance_name))]
+    async fn set_backup_policy(
+        _leader: Leader,
+        user: UserClaims,
+        Path(instance_name): Path<String>,
+        Json(req): Json<BackupPolicyDto>,
+        Extension(storage): Extension<Storage>,
+    ) -> Result<impl IntoResponse, InstanceError> {
+        if !s3::is_configured() {
+            return Err(InstanceError::BackupStorageUnavailable);
+        }
+        if req.interval == 0 {
+            return Err(InstanceError::InvalidArgs("interval".to_string()));
+        }
+        if req.retention == 0 {
+            return Err(InstanceError::InvalidArgs("retention".to_string()));
+        }
+        if !s3::is_allowed_bucket(&req.bucket) {
+            return Err(InstanceError::InvalidArgs("bucket".to_string()));
+        }
+        match storage
+            .try_read_write(|state| {
+                let instance = match state
+                    .find_mut_user(&user.username)
+                    .and_then(|u| u.find_mut_instance(&instance_name))
+                {
+                    Some(i) if i.stage == InstanceStage::Deleted => {
+                        return Err(InstanceError::AlreadyDeleted)
+                    }
+                    Some(i) => i,
+                    None => return Err(InstanceError::NotFound),
+                };
+                if instance.runtime != Runtime::Lxc && instance.runtime != Runtime::Kvm {
+                    return Err(InstanceError::TransferUnsupported {
+                        runtime: instance.runtime.to_string(),
+                    });
+                }
+                // The backups taken so far are kept so that the retention still applies to them.
+                let backups = instance
+                    .backup_policy
+                    .take()
+                    .map(|p| p.backups)
+                    .unwrap_or_default();
+                instance.backup_policy = Some(BackupPolicy {
+                    interval: req.interval,
+                    retention: req.retention,
+                    bucket: req.bucket.clone(),
+                    backups,
+                });
+                Ok(true)
+            })
+            .await
+        {
+            Ok(res) => res?,
+            Err(e) => {
+                warn!(
+                    username = user.username.as_str(),
+                    instance = instance_name.as_str(),
+                    error = e.to_string().as_str(),
+                    "set backup policy encountered error"
+                );
+                return Err(InstanceError::BackupPolicyFailed);
+            }
+        }
+        Ok(Json(req))
+    }
+
+    /// Removes the backup policy of the instance, the backups taken are left in the bucket.
+    #[instrument(skip_all, fields(username = %user.username, instance = %instance_name))]
+    async fn delete_backup_policy(
+        _leader: Leader,
+        user: UserClaims,
+        Path(instance_name): Path<String>,
+        Extension(storage): Extension<Storage>,
+    ) -> Result<impl IntoResponse, InstanceError> {
+        let mut found = false;
+        let res = storage
+            .read_write(|state| {
+                match state
+                    .find_mut_user(&user.username)
+                    .and_then(|u| u.find_mut_instance(&instance_name))
+                {
+                    Some(i) => {
+                        found = true;
+                        i.backup_policy.take().is_some()
+                    }
+                    None => false,
+                }
+            })
+            .await;
+        if let Err(e) = res {
+            warn!(
+                username = user.username.as_str(),
+                instance = instance_name.as_str(),
+                error = e.to_string().as_str(),
+                "delete backup policy encountered error"
+            );
+            return Err(InstanceError::BackupPolicyFailed);
+        }
+        if !found {
+            return Err(InstanceError::NotFound);
+        }
+        Ok(StatusCode::NO_CONTENT)
+    }
+
+    #[instrument(skip_all, fields(username = %user.username, instance = %instance_name))]
+    async fn pin_ip(
+        _leader: Leader,
+        user: UserClaims,
+        Path(instance_name): Path<String>,
+        Extension(storage): Extension<Storage>,
+    ) -> Result<impl IntoResponse, InstanceError> {
+        storage
+            .try_read_write(|state| {
+                let instance = match state
+                    .find_mut_user(&user.username)
+                    .and_then(|u| u.find_mut_instance(&instance_name))
+                {
+                    Some(i) if i.stage == InstanceStage::Deleted => {
+                        return Err(InstanceError::AlreadyDeleted)
+                    }
+                    Some(i) => i,
+                    None => return Err(InstanceError::NotFound),
+                };
+                // Only IPs of the pools can be reserved, the others belong to the clouds.
+                if instance.external_ip.is_none() || !scheduler::uses_ip_pools(instance) {
+                    return Err(InstanceError::InvalidArgs("external_ip".to_string()));
+                }
+                if instance.ip_pinned {
+                    return Ok(false);
+                }
+                check_pinned_ip_limit(state, &user.username)?;
+                let instance = state
+                    .find_mut_user(&user.username)
+                    .and_then(|u| u.find_mut_instance(&instance_name))
+                    .ok_or(InstanceError::NotFound)?;
+                instance.ip_pinned = true;
+                instance.add_event("external IP pinned".to_owned());
+                Ok(true)
+            })
+            .await
+            .map_err(|e| {
+                warn!(
+                    username = user.username.as_str(),
+                    instance = instance_name.as_str(),
+                    error = e.to_string().as_str(),
+                    "pin ip encountered error"
+                );
+                InstanceError::UpdateFailed
+            })??;
+        Ok(StatusCode::NO_CONTENT)
+    }
+
+    /// Unpins the IP of the instance, or lifts the reservation of the IP pinned by a deleted
+    /// instance of that name.
+    #[instrument(skip_all, fields(username = %user.username, instance = %instance_name))]
+    async fn unpin_ip(
+        _leader: Leader,
+        user: UserClaims,
+        Path(instance_name): Path<String>,
+        Extension(storage): Extension<Storage>,
+    ) -> Result<impl IntoResponse, InstanceError> {
+        let mut found = false;
+        storage
+            .read_write(|state| {
+                if let Some(i) = state
+                    .find_mut_user(&user.username)
+                    .and_then(|u| u.find_mut_instance(&instance_name))
+                {
+                    found = true;
+                    if i.ip_pinned {
+                        i.ip_pinned = false;
+                        i.add_event("external IP unpinned".to_owned());
+                    }
+                }
+                for a in &mut state.ip_assignments {
+                    if a.released_at.is_some()
+                        && a.pinned
+                        && a.username == user.username
+                        && a.instance == instance_name
+                    {
+                        found = true;
+                        a.pinned = false;
+                    }
+                }
+                found
+            })
+            .await
+            .map_err(|e| {
+                warn!(
+                    username = user.username.as_str(),
+                    instance = instance_name.as_str(),
+                    error = e.to_string().as_str(),
+                    "unpin ip encountered error"
+                );
+                InstanceError::UpdateFailed
+            })?;
+        if !found {
+            return Err(InstanceError::NotFound);
+        }
+        Ok(StatusCode::NO_CONTENT)
+    }
+
+    #[instrument(skip_all, fields(username = %user.username, instance = %instance_name))]
+    async fn delete_schedule(
+        _leader: Leader,
+        user: UserClaims,
+        Path(instance_name): Path<String>,
+        Extension(storage): Extension<Storage>,
+    ) -> Result<impl IntoResponse, InstanceError> {
+        update_schedule(&storage, &user.username, &instance_name, |s| *s = None).await?;
+        Ok(StatusCode::NO_CONTENT)
+    }
+
+    #[instrument(skip_all, fields(username = %user.username, instance = %instance_name))]
+    async fn skip_schedule(
+        _leader: Leader,
+        user: UserClaims,
+        Path(instance_name): Path<String>,
+        Json(req): Json<SkipScheduleRequest>,
+        Extension(storage): Extension<Storage>,
+    ) -> Result<impl IntoResponse, InstanceError> {
+        let mut found = true;
+        update_schedule(&storage, &user.username, &instance_name, |s| match s {
+            Some(s) => s.skip_until = Some(req.until),
+            None => found = false,
+        })
+        .await?;
+        if !found {
+            return Err(InstanceError::InvalidArgs("schedule".to_string()));
+        }
+        Ok(StatusCode::NO_CONTENT)
+    }
+
+    Router::new()
+        .route("/instances", get(list_instances).post(create_instance))
+        .route("/images", get(list_images))
+        .route("/ssh-config", get(get_ssh_config))
+        .route("/known-hosts", get(get_known_hosts))
+        .route(
+            "/home-volume",
+            get(get_home_volume)
+                .put(put_home_volume)
+                .delete(delete_home_volume),
+        )
+        .route("/volumes", get(list_volumes))
+        .route("/volumes/:volume_name", delete(delete_volume))
+        .route(
+            "/images/:image_name",
+            put(register_image).delete(unregister_image),
+        )
+        .route(
+            "/image-builds",
+            get(list_image_builds).post(create_image_build),
+        )
+        // Serves `/instances:batch`, see `rewrite_overlapping_paths`.
+        .route("/batch/instances", post(batch_instances))
+        .route("/clusters", get(list_clusters).post(create_cluster))
+        .route(
+            "/clusters/:cluster_name",
+            get(get_cluster).delete(delete_cluster),
+        )
+        .route("/clusters/:cluster_name/start", post(start_cluster))
+        .route("/clusters/:cluster_name/stop", post(stop_cluster))
+        .route("/topologies", get(list_topology_templates))
+        // Serves `/instances/events`, see `rewrite_overlapping_paths`.
+        .route("/events/instances", get(watch_instances))
+        // Serves `/instances/by-id/:id`, see `rewrite_overlapping_paths`.
+        .route("/instances-by-id/:id", get(get_instance_by_id))
+        .route(
+            "/instances/:instance_name",
+            get(get_instance)
+                .delete(delete_instance)
+                .patch(update_instance),
+        )
+        .route("/instances/:instance_name/start", post(start_instance))
+        .route("/instances/:instance_name/stop", post(stop_instance))
+        .route("/instances/:instance_name/ssh", get(ssh_instance))
+        .route(
+            "/instances/:instance_name/files",
+            get(pull_file).put(push_file),
+        )
+        .route("/instances/:instance_name/export", post(export_instance))
+        .route(
+            "/instances/:instance_name/conversion",
+            post(convert_instance).delete(discard_conversion),
+        )
+        .route(
+            "/instances/:instance_name/history",
+            get(get_instance_history),
+        )
+        .route(
+            "/instances/:instance_name/scheduling",
+            get(get_instance_scheduling),
+        )
+        .route("/instances/:instance_name/restore", post(restore_instance))
+        .route(
+            "/instances/:instance_name/schedule",
+            put(set_schedule).delete(delete_schedule),
+        )
+        .route(
+            "/instances/:instance_name/schedule/skip",
+            post(skip_schedule),
+        )
+        .route(
+            "/instances/:instance_name/transfer",
+            post(transfer_ownership),
+        )
+        .route(
+            "/instances/:instance_name/backup-policy",
+            put(set_backup_policy).delete(delete_backup_policy),
+        )
+        .route(
+            "/instances/:instance_name/ip-pin",
+            put(pin_ip).delete(unpin_ip),
+        )
+        .route(
+            "/instances/:instance_name/shares/:username",
+            put(share_instance).delete(unshare_instance),
+        )
+        .layer(RateLimitLayer::default())
+}
+
+pub fn health_routes() -> Router {
+    async fn healthz() -> impl IntoResponse {
+        "ok"
+    }
+
+    async fn readyz(Extension(storage): Extension<Storage>) -> impl IntoResponse {
+        let mut errors = Vec::new();
+        if let Err(e) = storage.check_writable().await {
+            errors.push(format!("state file is not writable: {}", e));
+        }
+        // Only the leader runs the collector.
+        if leader::is_leader() {
+            let max_age = Duration::from_secs(*READINESS_COLLECTOR_MAX_AGE * 60);
+            match storage.last_collected() {
+                Some(t) if t.elapsed() <= max_age => {}
+                Some(t) => errors.push(format!(
+                    "backends were last collected {}s ago",
+                    t.elapsed().as_secs()
+                )),
+                None => errors.push("backends have not been collected yet".to_owned()),
+            }
+        }
+        if errors.is_empty() {
+            (StatusCode::OK, Json(json!({ "status": "ok" })))
+        } else {
+            (
+                StatusCode::SERVICE_UNAVAILABLE,
+                Json(json!({ "status": "unavailable", "errors": errors })),
+            )
+        }
+    }
+
+    Router::new()
+        .route("/healthz", get(healthz))
+        .route("/readyz", get(readyz))
+}
+
+fn admin_routes() -> Router {
+    async fn reload_config(user: UserClaims) -> Result<impl IntoResponse, AdminError> {
+        if !user.is_admin() {
+            return Err(AdminError::Forbidden);
+        }
+        config::reload().map_err(|e| AdminError::InvalidConfig(format!("{:#}", e)))?;
+        info!(username = user.username.as_str(), "config reloaded");
+        Ok(StatusCode::NO_CONTENT)
+    }
+
+    /// Wakes the operators and the scheduler out of their sleep. If scoped to a user or an
+    /// instance, the instances are repaired first if their status contradicts their stage, and
+    /// their nodes are refreshed by the collector.
+    #[instrument(skip_all, fields(username = %user.username))]
+    async fn request_reconcile(
+        _leader: Leader,
+        user: UserClaims,
+        Query(query): Query<ReconcileQuery>,
+        Extension(storage): Extension<Storage>,
+    ) -> Result<impl IntoResponse, AdminError> {
+        if !user.is_admin() {
+            return Err(AdminError::Forbidden);
+        }
+        if !query.instance.is_empty() && query.username.is_empty() {
+            return Err(AdminError::InvalidArgs("username".to_owned()));
+        }
+        if !query.username.is_empty() {
+            let mut found = false;
+            let mut node_names = HashSet::new();
+            storage
+                .read_write(|state| {
+                    let u = match state.find_mut_user(&query.username) {
+                        Some(u) => u,
+                        None => return false,
+                    };
+                    let mut changed = false;
+                    for i in u
+                        .instances
+                        .iter_mut()
+                        .filter(|i| query.instance.is_empty() || i.name == query.instance)
+                        .filter(|i| i.stage != InstanceStage::Deleted)
+                    {
+                        found = true;
+                        changed |= lifecycle::repair(i);
+                        node_names.extend(i.node_name.clone());
+                    }
+                    changed
+                })
+                .await
+                .map_err(|e| {
+                    warn!(
+                        error = e.to_string().as_str(),
+                        "reconcile encountered error"
+                    );
+                    AdminError::UpdateFailed
+                })?;
+            if !found {
+                let arg = if query.instance.is_empty() {
+                    "username"
+                } else {
+                    "instance"
+                };
+                return Err(AdminError::InvalidArgs(arg.to_owned()));
+            }
+            for node_name in &node_names {
+                storage.mark_node_dirty(node_name);
+            }
+        }
+        info!(
+            username = user.username.as_str(),
+            scope_username = query.username.as_str(),
+            scope_instance = query.instance.as_str(),
+            "reconcile requested"
+        );
+        reconcile::trigger();
+        Ok(StatusCode::ACCEPTED)
+    }
+
+    #[instrument(skip_all, fields(username = %user.username))]
+    async fn get_usage(
+        user: UserClaims,
+        Query(query): Query<UsageQuery>,
+        Extension(storage): Extension<Storage>,
+    ) -> Result<Response, AdminError> {
+        if !user.is_admin() {
+            return Err(AdminError::Forbidden);
+        }
+        for date in [&query.from, &query.to] {
+            if NaiveDate::parse_from_str(date, "%Y-%m-%d").is_err() {
+                return Err(AdminError::InvalidArgs(date.to_owned()));
+            }
+        }
+        let group_by = if query.group_by.is_empty() {
+            "user"
+        } else {
+            query.group_by.as_str()
+        };
+        if group_by != "user" && group_by != "day" {
+            return Err(AdminError::InvalidArgs("group_by".to_owned()));
+        }
+
+        let mut rows: BTreeMap<String, UsageRow> = BTreeMap::new();
+        storage
+            .read_only(|state| {
+                // Dates formatted as YYYY-MM-DD compare in chronological order.
+                for r in state
+                    .usage
+                    .iter()
+                    .filter(|r| query.from <= r.date && r.date <= query.to)
+                {
+                    let key = if group_by == "user" {
+                        r.username.clone()
+                    } else {
+                        r.date.clone()
+                    };
+                    let row = rows.entry(key.clone()).or_insert_with(|| UsageRow {
+                        key,
+                        ..Default::default()
+                    });
+                    row.instance_hours += r.instance_seconds as f64 / 3600.0;
+                    row.cpu_hours += r.cpu_seconds as f64 / 3600.0;
+                    row.memory_gib_hours += r.memory_seconds as f64 / 3600.0;
+                    row.disk_gib_hours += r.disk_seconds as f64 / 3600.0;
+                }
+            })
+            .await;
+        let rows: Vec<UsageRow> = rows.into_values().collect();
+
+        match query.format.as_str() {
+            "" | "json" => Ok(Json(UsageReport {
+                from: query.from,
+                to: query.to,
+                group_by: group_by.to_owned(),
+                rows,
+            })
+            .into_response()),
+            "csv" => {
+                let mut csv = format!(
+                    "{},instance_hours,cpu_hours,memory_gib_hours,disk_gib_hours\n",
+                    group_by
+                );
+                for r in rows {
+                    csv.push_str(&format!(
+                        "{},{:.2},{:.2},{:.2},{:.2}\n",
+                        r.key, r.instance_hours, r.cpu_hours, r.memory_gib_hours, r.disk_gib_hours
+                    ));
+                }
+                Ok(([(CONTENT_TYPE, "text/csv")], csv).into_response())
+            }
+            _ => Err(AdminError::InvalidArgs("format".to_owned())),
+        }
+    }
+
+    #[instrument(skip_all, fields(username = %user.username))]
+    async fn list_ip_assignments(
+        user: UserClaims,
+        Query(query): Query<IpAssignmentsQuery>,
+        Extension(storage): Extension<Storage>,
+    ) -> Result<impl IntoResponse, AdminError> {
+        if !user.is_admin() {
+            return Err(AdminError::Forbidden);
+        }
+        let mut ip_assignments = Vec::new();
+        storage
+            .read_only(|state| {
+                ip_assignments = state
+                    .ip_assignments
+                    .iter()
+                    .filter(|a| query.ip.is_empty() || a.ip == query.ip)
+                    .filter(|a| query.username.is_empty() || a.username == query.username)
+                    .map(IpAssignmentDto::from)
+                    .collect()
+            })
+            .await;
+        Ok(Json(ListIpAssignmentsResponse { ip_assignments }))
+    }
+
+    #[instrument(skip_all, fields(username = %user.username))]
+    async fn list_ip_pools(
+        user: UserClaims,
+        Query(query): Query<IpPoolQuery>,
+        Extension(storage): Extension<Storage>,
+    ) -> Result<impl IntoResponse, AdminError> {
+        if !user.is_admin() {
+            return Err(AdminError::Forbidden);
+        }
+        let mut pools = Vec::new();
+        storage
+            .read_only(|state| {
+                let allocations = ip_allocations(state);
+                pools = state
+                    .ip_pools()
+                    .iter()
+                    .filter(|(p, _)| query.pool.is_empty() || p.name == query.pool)
+                    .map(|(p, ips)| build_ip_pool_dto(state, p, ips, &allocations))
+                    .collect()
+            })
+            .await;
+        Ok(Json(ListIpPoolsResponse { pools }))
+    }
+
+    #[instrument(skip_all, fields(username = %user.username))]
+    async fn add_ip_range(
+        _leader: Leader,
+        user: UserClaims,
+        Json(req): Json<IpRangeRequest>,
+        Extension(storage): Extension<Storage>,
+    ) -> Result<impl IntoResponse, AdminError> {
+        if !user.is_admin() {
+            return Err(AdminError::Forbidden);
+        }
+        let mut pool = None;
+        storage
+            .try_read_write(|state| {
+                edit_ip_pool(state, &req, true).map(|p| {
+                    pool = Some(p);
+                    true
+                })
+            })
+            .await
+            .map_err(|e| {
+                warn!(
+                    error = e.to_string().as_str(),
+                    "add ip range encountered error"
+                );
+                AdminError::UpdateFailed
+            })??;
+        info!(
+            username = user.username.as_str(),
+            pool = req.pool.as_str(),
+            range = req.range.as_str(),
+            exclude = req.exclude,
+            "ip range added"
+        );
+        Ok(Json(pool.unwrap()))
+    }
+
+    #[instrument(skip_all, fields(username = %user.username))]
+    async fn remove_ip_range(
+        _leader: Leader,
+        user: UserClaims,
+        Query(req): Query<IpRangeRequest>,
+        Extension(storage): Extension<Storage>,
+    ) -> Result<impl IntoResponse, AdminError> {
+        if !user.is_admin() {
+            return Err(AdminError::Forbidden);
+        }
+        let mut pool = None;
+        storage
+            .try_read_write(|state| {
+                edit_ip_pool(state, &req, false).map(|p| {
+                    pool = Some(p);
+                    true
+                })
+            })
+            .await
+            .map_err(|e| {
+                warn!(
+                    error = e.to_string().as_str(),
+                    "remove ip range encountered error"
+                );
+                AdminError::UpdateFailed
+            })??;
+        info!(
+            username = user.username.as_str(),
+            pool = req.pool.as_str(),
+            range = req.range.as_str(),
+            exclude = req.exclude,
+            "ip range removed"
+        );
+        Ok(Json(pool.unwrap()))
+    }
+
+    #[instrument(skip_all, fields(username = %user.username))]
+    async fn list_groups(
+        user: UserClaims,
+        Extension(storage): Extension<Storage>,
+    ) -> Result<impl IntoResponse, AdminError> {
+        if !user.is_admin() {
+            return Err(AdminError::Forbidden);
+        }
+        let mut groups = Vec::new();
+        storage
+            .read_only(|state| groups = state.groups.iter().map(GroupDto::from).collect())
+            .await;
+        Ok(Json(ListGroupsResponse { groups }))
+    }
+
+    #[instrument(skip_all, fields(username = %user.username, group = %group_name))]
+    async fn put_group(
+        _leader: Leader,
+        user: UserClaims,
+        Path(group_name): Path<String>,
+        Json(req): Json<GroupDto>,
+        Extension(storage): Extension<Storage>,
+    ) -> Result<impl IntoResponse, AdminError> {
+        if !user.is_admin() {
+            return Err(AdminError::Forbidden);
+        }
+        if !verify_instance_name(&group_name) {
+            return Err(AdminError::InvalidArgs("name".to_owned()));
+        }
+        let group = Group {
+            name: group_name.clone(),
+            members: req.members,
+            cpu_quota: req.cpu_quota,
+            memory_quota: req.memory_quota,
+            disk_quota: req.disk_quota,
+            instance_quota: req.instance_quota,
+        };
+        storage
+            .read_write(|state| {
+                match state.groups.iter_mut().find(|g| g.name == group_name) {
+                    Some(g) => *g = group.clone(),
+                    None => state.groups.push(group.clone()),
+                }
+                true
+            })
+            .await
+            .map_err(|e| {
+                warn!(
+                    error = e.to_string().as_str(),
+                    "put group encountered error"
+                );
+                AdminError::UpdateFailed
+            })?;
+        Ok(Json(GroupDto::from(&group)))
+    }
+
+    #[instrument(skip_all, fields(username = %user.username, group = %group_name))]
+    async fn delete_group(
+        _leader: Leader,
+        user: UserClaims,
+        Path(group_name): Path<String>,
+        Extension(storage): Extension<Storage>,
+    ) -> Result<impl IntoResponse, AdminError> {
+        if !user.is_admin() {
+            return Err(AdminError::Forbidden);
+        }
+        let mut found = false;
+        storage
+            .read_write(|state| {
+                let len = state.groups.len();
+                state.groups.retain(|g| g.name != group_name);
+                found = state.groups.len() != len;
+                found
+            })
+            .await
+            .map_err(|e| {
+                warn!(
+                    error = e.to_string().as_str(),
+                    "delete group encountered error"
+                );
+                AdminError::UpdateFailed
+            })?;
+        if !found {
+            return Err(AdminError::GroupNotFound(group_name));
+        }
+        Ok(StatusCode::NO_CONTENT)
+    }
+
+    #[instrument(skip_all, fields(username = %user.username))]
+    async fn list_catalog_images(
+        user: UserClaims,
+        Extension(storage): Extension<Storage>,
+    ) -> Result<impl IntoResponse, AdminError> {
+        if !user.is_admin() {
+            return Err(AdminError::Forbidden);
+        }
+        let mut images = Vec::new();
+        storage
+            .read_only(|state| images = state.images.iter().map(CatalogImageDto::from).collect())
+            .await;
+        Ok(Json(ListImagesResponse { images }))
+    }
+
+    #[instrument(skip_all, fields(username = %user.username, image = %image_name))]
+    async fn put_catalog_image(
+        _leader: Leader,
+        user: UserClaims,
+        Path(image_name): Path<String>,
+        Json(req): Json<CatalogImageDto>,
+        Extension(storage): Extension<Storage>,
+    ) -> Result<impl IntoResponse, AdminError> {
+        if !user.is_admin() {
+            return Err(AdminError::Forbidden);
+        }
+        let name: Image = image_name
+            .parse()
+            .map_err(|_| AdminError::InvalidArgs("name".to_owned()))?;
+        let family: ImageFamily = req
+            .family
+            .parse()
+            .map_err(|_| AdminError::InvalidArgs("family".to_owned()))?;
+        if req.sources.keys().any(|r| Runtime::from_str(r).is_err()) {
+            return Err(AdminError::InvalidArgs("sources".to_owned()));
+        }
+        if !verify_shared_mounts(&req.mounts) {
+            return Err(AdminError::InvalidArgs("mounts".to_owned()));
+        }
+        let arches = req
+            .arches
+            .iter()
+            .map(|a| a.parse())
+            .collect::<Result<Vec<Arch>, _>>()
+            .map_err(|_| AdminError::InvalidArgs("arches".to_owned()))?;
+        // Keys are normalized so that they match the runtimes of instances.
+        let sources = req
+            .sources
+            .iter()
+            .map(|(r, s)| (Runtime::from_str(r).unwrap().to_string(), s.clone()))
+            .collect();
+        let image = CatalogImage {
+            name: name.clone(),
+            family,
+            sources,
+            arches,
+            enabled: req.enabled,
+            default: req.default,
+            owner: None,
+            mounts: req
+                .mounts
+                .iter()
+                .map(|m| SharedMount {
+                    source: m.source.clone(),
+                    path: m.path.trim_end_matches('/').to_owned(),
+                    read_only: m.read_only,
+                })
+                .collect(),
+        };
+        storage
+            .read_write(|state| {
+                // There is at most one default image.
+                if image.default {
+                    for i in &mut state.images {
+                        i.default = false;
+                    }
+                }
+                match state.images.iter_mut().find(|i| i.name == name) {
+                    Some(i) => *i = image.clone(),
+                    None => state.images.push(image.clone()),
+                }
+                true
+            })
+            .await
+            .map_err(|e| {
+                warn!(
+                    error = e.to_string().as_str(),
+                    "put image encountered error"
+                );
+                AdminError::UpdateFailed
+            })?;
+        Ok(Json(CatalogImageDto::from(&image)))
+    }
+
+    #[instrument(skip_all, fields(username = %user.username, image = %image_name))]
+    async fn delete_catalog_image(
+        _leader: Leader,
+        user: UserClaims,
+        Path(image_name): Path<String>,
+        Extension(storage): Extension<Storage>,
+    ) -> Result<impl IntoResponse, AdminError> {
+        if !user.is_admin() {
+            return Err(AdminError::Forbidden);
+        }
+        let name: Image = image_name
+            .parse()
+            .map_err(|_| AdminError::ImageNotFound(image_name.clone()))?;
+        let mut found = false;
+        // Existing instances are unaffected as they keep the source they were created from.
+        storage
+            .read_write(|state| {
+                let len = state.images.len();
+                state.images.retain(|i| i.name != name);
+                found = state.images.len() != len;
+                found
+            })
+            .await
+            .map_err(|e| {
+                warn!(
+                    error = e.to_string().as_str(),
+                    "delete image encountered error"
+                );
+                AdminError::UpdateFailed
+            })?;
+        if !found {
+            return Err(AdminError::ImageNotFound(image_name));
+        }
+        Ok(StatusCode::NO_CONTENT)
+    }
+
+    #[instrument(skip_all, fields(username = %user.username))]
+    async fn list_projects(
+        user: UserClaims,
+        Extension(storage): Extension<Storage>,
+    ) -> Result<impl IntoResponse, AdminError> {
+        if !user.is_admin() {
+            return Err(AdminError::Forbidden);
+        }
+        let mut projects = Vec::new();
+        storage
+            .read_only(|state| projects = state.projects.iter().map(ProjectDto::from).collect())
+            .await;
+        Ok(Json(ListProjectsResponse { projects }))
+    }
+
+    #[instrument(skip_all, fields(username = %user.username, project = %project_name))]
+    async fn put_project(
+        _leader: Leader,
+        user: UserClaims,
+        Path(project_name): Path<String>,
+        Json(req): Json<ProjectDto>,
+        Extension(storage): Extension<Storage>,
+    ) -> Result<impl IntoResponse, AdminError> {
+        if !user.is_admin() {
+            return Err(AdminError::Forbidden);
+        }
+        if !verify_instance_name(&project_name) {
+            return Err(AdminError::InvalidArgs("name".to_owned()));
+        }
+        let project = Project {
+            name: project_name.clone(),
+            members: req.members,
+            cpu_quota: req.cpu_quota,
+            memory_quota: req.memory_quota,
+            disk_quota: req.disk_quota,
+            instance_quota: req.instance_quota,
+        };
+        storage
+            .read_write(|state| {
+                match state.projects.iter_mut().find(|p| p.name == project_name) {
+                    Some(p) => *p = project.clone(),
+                    None => state.projects.push(project.clone()),
+                }
+                true
+            })
+            .await
+            .map_err(|e| {
+                warn!(
+                    error = e.to_string().as_str(),
+                    "put project encountered error"
+                );
+                AdminError::UpdateFailed
+            })?;
+        Ok(Json(ProjectDto::from(&project)))
+    }
+
+    #[instrument(skip_all, fields(username = %user.username, project = %project_name))]
+    async fn delete_project(
+        _leader: Leader,
+        user: UserClaims,
+        Path(project_name): Path<String>,
+        Extension(storage): Extension<Storage>,
+    ) -> Result<impl IntoResponse, AdminError> {
+        if !user.is_admin() {
+            return Err(AdminError::Forbidden);
+        }
+        let mut found = false;
+        let mut in_use = false;
+        storage
+            .read_write(|state| {
+                // Instances would otherwise escape the quotas of the project.
+                in_use = state
+                    .users
+                    .iter()
+                    .flat_map(|u| &u.instances)
+                    .any(|i| i.project.as_deref() == Some(project_name.as_str()));
+                if in_use {
+                    return false;
+                }
+                let len = state.projects.len();
+                state.projects.retain(|p| p.name != project_name);
+                found = state.projects.len() != len;
+                found
+            })
+            .await
+            .map_err(|e| {
+                warn!(
+                    error = e.to_string().as_str(),
+                    "delete project encountered error"
+                );
+                AdminError::UpdateFailed
+            })?;
+        if in_use {
+            return Err(AdminError::ProjectInUse(project_name));
+        }
+        if !found {
+            return Err(AdminError::ProjectNotFound(project_name));
+        }
+        Ok(StatusCode::NO_CONTENT)
+    }
+
+    #[instrument(skip_all, fields(username = %user.username))]
+    async fn list_priority_classes(
+        user: UserClaims,
+        Extension(storage): Extension<Storage>,
+    ) -> Result<impl IntoResponse, AdminError> {
+        if !user.is_admin() {
+            return Err(AdminError::Forbidden);
+        }
+        let mut priority_classes = Vec::new();
+        storage
+            .read_only(|state| {
+                priority_classes = state
+                    .priority_classes
+                    .iter()
+                    .map(PriorityClassDto::from)
+                    .collect()
+            })
+            .await;
+        Ok(Json(ListPriorityClassesResponse { priority_classes }))
+    }
+
+    /// Creates or replaces the priority class. Instances stay preemptible or not as their class
+    /// was when they were created.
+    #[instrument(skip_all, fields(username = %user.username, class = %class_name))]
+    async fn put_priority_class(
+        _leader: Leader,
+        user: UserClaims,
+        Path(class_name): Path<String>,
+        Json(req): Json<PriorityClassDto>,
+        Extension(storage): Extension<Storage>,
+    ) -> Result<impl IntoResponse, AdminError> {
+        if !user.is_admin() {
+            return Err(AdminError::Forbidden);
+        }
+        if !verify_instance_name(&class_name) {
+            return Err(AdminError::InvalidArgs("name".to_owned()));
+        }
+        if !verify_description(&req.description) {
+            return Err(AdminError::InvalidArgs("description".to_owned()));
+        }
+        let class = PriorityClass {
+            name: class_name.clone(),
+            value: req.value,
+            preemptible: req.preemptible,
+            description: req.description,
+            users: req.users,
+        };
+        storage
+            .read_write(|state| {
+                match state
+                    .priority_classes
+                    .iter_mut()
+                    .find(|c| c.name == class_name)
+                {
+                    Some(c) => *c = class.clone(),
+                    None => state.priority_classes.push(class.clone()),
+                }
+                true
+            })
+            .await
+            .map_err(|e| {
+                warn!(
+                    error = e.to_string().as_str(),
+                    "put priority class encountered error"
+                );
+                AdminError::UpdateFailed
+            })?;
+        Ok(Json(PriorityClassDto::from(&class)))
+    }
+
+    #[instrument(skip_all, fields(username = %user.username, class = %class_name))]
+    async fn delete_priority_class(
+        _leader: Leader,
+        user: UserClaims,
+        Path(class_name): Path<String>,
+        Extension(storage): Extension<Storage>,
+    ) -> Result<impl IntoResponse, AdminError> {
+        if !user.is_admin() {
+            return Err(AdminError::Forbidden);
+        }
+        let mut found = false;
+        let mut in_use = false;
+        storage
+            .read_write(|state| {
+                in_use = state
+                    .users
+                    .iter()
+                    .flat_map(|u| &u.instances)
+                    .any(|i| i.priority_class.as_deref() == Some(class_name.as_str()));
+                if in_use {
+                    return false;
+                }
+                let len = state.priority_classes.len();
+                state.priority_classes.retain(|c| c.name != class_name);
+                found = state.priority_classes.len() != len;
+                found
+            })
+            .await
+            .map_err(|e| {
+                warn!(
+                    error = e.to_string().as_str(),
+                    "delete priority class encountered error"
                 );
-                return Err(InstanceError::CreateFailed);
-            }
+                AdminError::UpdateFailed
+            })?;
+        if in_use {
+            return Err(AdminError::PriorityClassInUse(class_name));
         }
-
-        match user_err {
-            Some(e) => Err(e),
-            None => Ok(StatusCode::CREATED),
+        if !found {
+            return Err(AdminError::PriorityClassNotFound(class_name));
         }
+        Ok(StatusCode::NO_CONTENT)
     }
 
-    async fn delete_instance(
+    /// Creates or replaces the topology template. Existing groups keep the members and ports
+    /// they were created with.
+    #[instrument(skip_all, fields(username = %user.username, topology = %topology_name))]
+    async fn put_topology_template(
+        _leader: Leader,
         user: UserClaims,
-        Path(instance_name): Path<String>,
+        Path(topology_name): Path<String>,
+        Json(req): Json<TopologyTemplateDto>,
         Extension(storage): Extension<Storage>,
-    ) -> Result<impl IntoResponse, InstanceError> {
-        match storage
+    ) -> Result<impl IntoResponse, AdminError> {
+        if !user.is_admin() {
+            return Err(AdminError::Forbidden);
+        }
+        if !verify_instance_name(&topology_name) {
+            return Err(AdminError::InvalidArgs("name".to_owned()));
+        }
+        if !verify_description(&req.description) {
+            return Err(AdminError::InvalidArgs("description".to_owned()));
+        }
+        let mut role_names = HashSet::new();
+        let roles_valid = req.roles.iter().all(|r| {
+            verify_instance_name(&r.name)
+                && role_names.insert(r.name.as_str())
+                && r.count > 0
+                && r.cpu > 0
+                && r.memory > 0
+                && r.disk_size > 0
+                && (r.runtime.is_empty() || Runtime::from_str(&r.runtime).is_ok())
+        });
+        let size: usize = req.roles.iter().map(|r| r.count).sum();
+        if !roles_valid || size == 0 || size > MAX_INSTANCE_GROUP_SIZE {
+            return Err(AdminError::InvalidArgs("roles".to_owned()));
+        }
+        // The members are checked like single instances, so that the template fails when it's
+        // saved instead of when clusters are created from it.
+        for r in &req.roles {
+            if let Some(e) = check_size_limits(r.cpu, r.memory, r.disk_size) {
+                return Err(AdminError::InvalidArgs(format!("roles.{}: {}", r.name, e)));
+            }
+        }
+        let template = TopologyTemplate {
+            name: topology_name.clone(),
+            description: req.description,
+            roles: req
+                .roles
+                .iter()
+                .map(|r| TopologyRole {
+                    name: r.name.clone(),
+                    count: r.count,
+                    cpu: r.cpu,
+                    memory: r.memory,
+                    disk_size: r.disk_size,
+                    image: r.image.clone(),
+                    runtime: r.runtime.clone(),
+                    port: r.port,
+                })
+                .collect(),
+        };
+        storage
             .read_write(|state| {
                 match state
-                    .find_mut_user(&user.username)
-                    .and_then(|u| u.find_mut_instance(&instance_name))
+                    .topology_templates
+                    .iter_mut()
+                    .find(|t| t.name == topology_name)
                 {
-                    Some(instance) if instance.stage != InstanceStage::Deleted => {
-                        instance.stage = InstanceStage::Deleted;
-                        match instance.runtime {
-                            Runtime::Kata | Runtime::Runc => {
-                                instance.status = InstanceStatus::Deleting;
-                            }
-                            Runtime::Lxc | Runtime::Kvm => {
-                                instance.status = InstanceStatus::Stopping;
-                            }
-                        }
-
-                        true
-                    }
-                    _ => false,
+                    Some(t) => *t = template.clone(),
+                    None => state.topology_templates.push(template.clone()),
                 }
+                true
             })
             .await
-        {
-            Ok(_) => (),
-            Err(e) => {
+            .map_err(|e| {
                 warn!(
-                    username = user.username.as_str(),
-                    instance = instance_name.as_str(),
                     error = e.to_string().as_str(),
-                    "delete instance encountered error"
+                    "put topology template encountered error"
                 );
-                return Err(InstanceError::DeleteFailed);
-            }
+                AdminError::UpdateFailed
+            })?;
+        Ok(Json(TopologyTemplateDto::from(&template)))
+    }
+
+    #[instrument(skip_all, fields(username = %user.username, topology = %topology_name))]
+    async fn delete_topology_template(
+        _leader: Leader,
+        user: UserClaims,
+        Path(topology_name): Path<String>,
+        Extension(storage): Extension<Storage>,
+    ) -> Result<impl IntoResponse, AdminError> {
+        if !user.is_admin() {
+            return Err(AdminError::Forbidden);
+        }
+        let mut found = false;
+        storage
+            .read_write(|state| {
+                let len = state.topology_templates.len();
+                state.topology_templates.retain(|t| t.name != topology_name);
+                found = state.topology_templates.len() != len;
+                found
+            })
+            .await
+            .map_err(|e| {
+                warn!(
+                    error = e.to_string().as_str(),
+                    "delete topology template encountered error"
+                );
+                AdminError::UpdateFailed
+            })?;
+        if !found {
+            return Err(AdminError::TopologyTemplateNotFound(topology_name));
         }
         Ok(StatusCode::NO_CONTENT)
     }
 
-    async fn update_instance(
+    #[instrument(skip_all, fields(username = %user.username))]
+    async fn list_nodes(
         user: UserClaims,
-        Path(instance_name): Path<String>,
-        Json(req): Json<UpdateInstanceRequest>,
         Extension(storage): Extension<Storage>,
-    ) -> Result<impl IntoResponse, InstanceError> {
-        if let Some(0) = req.cpu {
-            return Err(InstanceError::InvalidArgs("cpu".to_string()));
+    ) -> Result<impl IntoResponse, AdminError> {
+        if !user.is_admin() {
+            return Err(AdminError::Forbidden);
         }
-        if let Some(0) = req.memory {
-            return Err(InstanceError::InvalidArgs("memory".to_string()));
+        let mut nodes = Vec::new();
+        storage
+            .read_only(|state| nodes = state.nodes.iter().map(NodeDto::from).collect())
+            .await;
+        Ok(Json(ListNodesResponse { nodes }))
+    }
+
+    /// Cordons, uncordons or drains the node and sets its labels, notes and maintenance windows.
+    /// These survive the collections of the nodes, which only refresh what the backends report.
+    #[instrument(skip_all, fields(username = %user.username, node = %node_name))]
+    async fn update_node(
+        _leader: Leader,
+        user: UserClaims,
+        Path(node_name): Path<String>,
+        Json(req): Json<UpdateNodeRequest>,
+        Extension(storage): Extension<Storage>,
+    ) -> Result<impl IntoResponse, AdminError> {
+        if !user.is_admin() {
+            return Err(AdminError::Forbidden);
         }
-        if let Some(runtime) = &req.runtime {
-            let _ = Runtime::from_str(runtime)
-                .map_err(|_| InstanceError::InvalidArgs(runtime.to_owned()))?;
+        if let Some(labels) = &req.labels {
+            if labels.len() > MAX_LABELS || labels.iter().any(|(k, v)| !verify_label(k, v)) {
+                return Err(AdminError::InvalidArgs("labels".to_owned()));
+            }
         }
-        let mut user_err = None;
-        match storage
-            .read_write(|state| match state.find_mut_user(&user.username) {
-                Some(u) => {
-                    let mut total_cpu = 0;
-                    let mut total_memory = 0;
-                    for instance in &u.instances {
-                        if instance.name != instance_name {
-                            total_cpu += instance.cpu;
-                            total_memory += instance.memory;
-                        }
+        if let Some(notes) = &req.notes {
+            if !verify_description(notes) {
+                return Err(AdminError::InvalidArgs("notes".to_owned()));
+            }
+        }
+        if let Some(windows) = &req.maintenance_windows {
+            if windows
+                .iter()
+                .any(|w| w.start >= w.end || !verify_description(&w.reason))
+            {
+                return Err(AdminError::InvalidArgs("maintenance_windows".to_owned()));
+            }
+        }
+        let mut updated = None;
+        storage
+            .read_write(|state| match state.find_mut_node(&node_name) {
+                Some(n) => {
+                    if let Some(schedulable) = req.schedulable {
+                        n.schedulable = schedulable;
                     }
-                    match u
-                        .instances
-                        .iter_mut()
-                        .find(|instance| instance.name == instance_name)
-                    {
-                        Some(instance) => {
-                            if instance.stage == InstanceStage::Deleted {
-                                user_err = Some(InstanceError::AlreadyDeleted);
-                                return false;
-                            }
-                            if instance.status != InstanceStatus::Stopped {
-                                user_err = Some(InstanceError::NotYetStopped);
-                                return false;
-                            }
-                            if let Some(cpu) = req.cpu {
-                                if total_cpu + cpu > u.cpu_quota {
-                                    user_err = Some(InstanceError::QuotaExceeded {
-                                        resource: "CPU".to_string(),
-                                        quota: u.cpu_quota,
-                                        remaining: u.cpu_quota - total_cpu,
-                                        requested: cpu,
-                                        unit: "C".to_string(),
-                                    });
-                                    return false;
-                                }
-                                instance.cpu = cpu;
-                            }
-                            if let Some(memory) = req.memory {
-                                if total_memory + memory > u.memory_quota {
-                                    user_err = Some(InstanceError::QuotaExceeded {
-                                        resource: "Memory".to_string(),
-                                        quota: u.memory_quota,
-                                        remaining: u.memory_quota - total_memory,
-                                        requested: memory,
-                                        unit: "GiB".to_string(),
-                                    });
-                                    return false;
-                                }
-                                instance.memory = memory;
-                            }
-                            if let Some(runtime) = &req.runtime {
-                                let runtime = Runtime::from_str(runtime).unwrap();
-                                if instance.runtime.compatiable_with(&runtime) {
-                                    instance.runtime = runtime;
-                                } else {
-                                    user_err = Some(InstanceError::RuntimeIncompatible {
-                                        current: instance.runtime.to_string(),
-                                        target: runtime.to_string(),
-                                    });
-                                    return false;
-                                }
-                            }
-                            true
-                        }
-                        None => false,
+                    if let Some(labels) = &req.labels {
+                        n.labels = labels.clone();
                     }
+                    if let Some(notes) = &req.notes {
+                        n.notes = notes.clone();
+                    }
+                    if let Some(draining) = req.draining {
+                        n.draining = draining;
+                    }
+                    if let Some(windows) = &req.maintenance_windows {
+                        // Owners are not warned again of the windows which are kept.
+                        n.maintenance_windows = windows
+                            .iter()
+                            .map(|w| MaintenanceWindow {
+                                start: w.start,
+                                end: w.end,
+                                reason: w.reason.clone(),
+                                notified: n
+                                    .maintenance_windows
+                                    .iter()
+                                    .any(|o| o.start == w.start && o.end == w.end && o.notified),
+                            })
+                            .collect();
+                    }
+                    updated = Some(NodeDto::from(&*n));
+                    true
                 }
                 None => false,
             })
             .await
-        {
-            Ok(_) => (),
-            Err(e) => {
+            .map_err(|e| {
                 warn!(
-                    username = user.username.as_str(),
-                    instance = instance_name.as_str(),
                     error = e.to_string().as_str(),
-                    "update instance encountered error"
+                    "update node encountered error"
                 );
-                return Err(InstanceError::UpdateFailed);
-            }
-        }
-
-        match user_err {
-            Some(e) => Err(e),
-            None => Ok(StatusCode::NO_CONTENT),
-        }
+                AdminError::UpdateFailed
+            })?;
+        let node = updated.ok_or(AdminError::NodeNotFound(node_name))?;
+        info!(
+            username = user.username.as_str(),
+            node = node.name.as_str(),
+            schedulable = node.schedulable,
+            draining = node.draining,
+            "node updated"
+        );
+        Ok(Json(node))
     }
 
-    async fn start_instance(
+    /// Forgets a node the backends no longer report, once no instance is left on it.
+    #[instrument(skip_all, fields(username = %user.username, node = %node_name))]
+    async fn delete_node(
+        _leader: Leader,
         user: UserClaims,
-        Path(instance_name): Path<String>,
+        Path(node_name): Path<String>,
         Extension(storage): Extension<Storage>,
-    ) -> Result<impl IntoResponse, InstanceError> {
-        let mut user_err = None;
-        match storage
+    ) -> Result<impl IntoResponse, AdminError> {
+        if !user.is_admin() {
+            return Err(AdminError::Forbidden);
+        }
+        let mut found = false;
+        let mut in_use = false;
+        storage
             .read_write(|state| {
-                match state
-                    .find_mut_user(&user.username)
-                    .and_then(|u| u.find_mut_instance(&instance_name))
-                {
-                    Some(instance) => {
-                        if instance.stage == InstanceStage::Deleted {
-                            user_err = Some(InstanceError::AlreadyDeleted);
-                            return false;
-                        }
-                        if instance.stage != InstanceStage::Running {
-                            instance.stage = InstanceStage::Running;
-                            instance.status = InstanceStatus::Starting;
-                            true
-                        } else {
-                            false
-                        }
-                    }
-                    None => false,
+                let missing = match state.find_node(&node_name) {
+                    Some(n) => n.missing_since.is_some(),
+                    None => return false,
+                };
+                found = true;
+                in_use = !missing
+                    || state
+                        .users
+                        .iter()
+                        .flat_map(|u| &u.instances)
+                        .any(|i| i.node_name.as_deref() == Some(node_name.as_str()));
+                if in_use {
+                    return false;
                 }
+                state.nodes.retain(|n| n.name != node_name);
+                true
             })
             .await
-        {
-            Ok(_) => (),
-            Err(_) => return Err(InstanceError::StartFailed),
+            .map_err(|e| {
+                warn!(
+                    error = e.to_string().as_str(),
+                    "delete node encountered error"
+                );
+                AdminError::UpdateFailed
+            })?;
+        if !found {
+            return Err(AdminError::NodeNotFound(node_name));
         }
-        match user_err {
-            Some(e) => Err(e),
-            None => Ok(StatusCode::NO_CONTENT),
+        if in_use {
+            return Err(AdminError::NodeInUse(node_name));
         }
+        Ok(StatusCode::NO_CONTENT)
     }
 
-    async fn stop_instance(
-        user: UserClaims,
-        Path(instance_name): Path<String>,
+    Router::new()
+        .route("/admin/config/reload", post(reload_config))
+        .route("/admin/reconcile", post(request_reconcile))
+        .route("/admin/usage", get(get_usage))
+        .route("/admin/ip-assignments", get(list_ip_assignments))
+        .route("/admin/ip-pool", get(list_ip_pools))
+        .route(
+            "/admin/ip-pool/ranges",
+            post(add_ip_range).delete(remove_ip_range),
+        )
+        .route("/admin/groups", get(list_groups))
+        .route(
+            "/admin/groups/:group_name",
+            put(put_group).delete(delete_group),
+        )
+        .route("/admin/images", get(list_catalog_images))
+        .route(
+            "/admin/images/:image_name",
+            put(put_catalog_image).delete(delete_catalog_image),
+        )
+        .route("/admin/projects", get(list_projects))
+        .route(
+            "/admin/projects/:project_name",
+            put(put_project).delete(delete_project),
+        )
+        .route("/admin/nodes", get(list_nodes))
+        .route(
+            "/admin/nodes/:node_name",
+            patch(update_node).delete(delete_node),
+        )
+        .route("/admin/priority-classes", get(list_priority_classes))
+        .route(
+            "/admin/priority-classes/:class_name",
+            put(put_priority_class).delete(delete_priority_class),
+        )
+        .route(
+            "/admin/topologies/:topology_name",
+            put(put_topology_template).delete(delete_topology_template),
+        )
+}
+
+/// Routes served to the guests, see [`crate::config::Config::metadata_port`].
+///
+/// Guests are identified by the source address of the request, so they need no credentials.
+pub fn metadata_routes() -> Router {
+    async fn get_metadata(
+        ConnectInfo(addr): ConnectInfo<SocketAddr>,
         Extension(storage): Extension<Storage>,
     ) -> Result<impl IntoResponse, InstanceError> {
-        let mut user_err = None;
-        match storage
-            .read_write(|state| {
-                match state
-                    .find_mut_user(&user.username)
-                    .and_then(|u| u.find_mut_instance(&instance_name))
-                {
-                    Some(instance) => {
-                        if instance.stage == InstanceStage::Deleted {
-                            user_err = Some(InstanceError::AlreadyDeleted);
-                            return false;
-                        }
-                        if instance.stage != InstanceStage::Stopped {
-                            instance.stage = InstanceStage::Stopped;
-                            instance.status = InstanceStatus::Stopping;
-                            true
-                        } else {
-                            false
-                        }
-                    }
-                    None => false,
-                }
+        let ip = addr.ip().to_string();
+        let mut metadata = None;
+        storage
+            .read_only(|state| {
+                metadata = state
+                    .find_instance_by_ip(&ip)
+                    .map(|(u, i)| InstanceMetadata {
+                        owner: u.username.clone(),
+                        name: i.name.clone(),
+                        hostname: i.hostname.clone(),
+                        project: i.project.clone(),
+                        runtime: i.runtime.to_string(),
+                        image: i.image.to_string(),
+                        arch: i.arch.to_string(),
+                        cpu: i.cpu,
+                        memory: i.memory,
+                        disk_size: i.disk_size,
+                        internal_ip: i.internal_ip.clone(),
+                        external_ip: i.external_ip.clone(),
+                        labels: i.labels.clone(),
+                        ssh_authorized_keys: i.authorized_keys(),
+                    })
             })
-            .await
-        {
-            Ok(_) => (),
-            Err(_) => return Err(InstanceError::StopFailed),
-        }
-        match user_err {
-            Some(e) => Err(e),
-            None => Ok(StatusCode::NO_CONTENT),
+            .await;
+        match metadata {
+            Some(metadata) => Ok(Json(metadata)),
+            None => Err(InstanceError::NotFound),
         }
     }
 
-    async fn list_instances(
-        user: UserClaims,
+    /// Records the SSH host keys posted by the guest, replacing the ones it posted before. Only
+    /// the phone_home module of cloud-init posts them, so only the LXD and EC2 runtimes publish
+    /// host keys: the guests of the Kubernetes, micro-VM and OCI runtimes don't run cloud-init.
+    async fn post_host_keys(
+        _leader: Leader,
+        ConnectInfo(addr): ConnectInfo<SocketAddr>,
+        Form(form): Form<HostKeysForm>,
         Extension(storage): Extension<Storage>,
-    ) -> impl IntoResponse {
-        let mut instances = Vec::new();
+    ) -> Result<impl IntoResponse, InstanceError> {
+        let ip = addr.ip().to_string();
+        let keys: Vec<String> = [form.pub_key_rsa, form.pub_key_ecdsa, form.pub_key_ed25519]
+            .iter()
+            .flatten()
+            .filter_map(|k| parse_host_key(k))
+            .collect();
+        if keys.is_empty() {
+            return Err(InstanceError::InvalidArgs("keys".to_string()));
+        }
+        let mut found = false;
         storage
-            .read_only(|state| {
-                if let Some(u) = state.find_user(&user.username) {
-                    instances = u.instances.iter().map(InstanceDto::from).collect();
+            .read_write(|state| {
+                let instance = match state.find_mut_instance_by_ip(&ip) {
+                    Some(instance) => instance,
+                    None => return false,
+                };
+                found = true;
+                if instance.ssh_host_keys == keys {
+                    return false;
                 }
+                instance.ssh_host_keys = keys.clone();
+                instance.add_event("published ssh host keys".to_string());
+                true
             })
-            .await;
-        let resp = ListInstancesResponse { instances };
-        Json(resp)
+            .await
+            .map_err(|e| {
+                warn!(
+                    ip = ip.as_str(),
+                    error = e.to_string().as_str(),
+                    "post host keys encountered error"
+                );
+                InstanceError::UpdateFailed
+            })?;
+        if !found {
+            return Err(InstanceError::NotFound);
+        }
+        Ok(StatusCode::NO_CONTENT)
     }
 
     Router::new()
-        .route("/instances", get(list_instances).post(create_instance))
-        .route(
-            "/instances/:instance_name",
-            delete(delete_instance).patch(update_instance),
-        )
-        .route("/instances/:instance_name/start", post(start_instance))
-        .route("/instances/:instance_name/stop", post(stop_instance))
+        .route("/metadata", get(get_metadata))
+        .route("/metadata/host-keys", post(post_host_keys))
 }
 
 pub fn metrics_routes() -> Router {
-    async fn metrics(Extension(storage): Extension<Storage>) -> impl IntoResponse {
-        let cpu_allocated = GaugeVec::new(
-            Opts::new("cpu_allocated", "Total cpu allocated").namespace("tispace"),
-            &["node_name"],
-        )
-        .unwrap();
-        let memory_allocated = GaugeVec::new(
-            Opts::new("memory_allocated", "Total memory allocated").namespace("tispace"),
-            &["node_name"],
-        )
-        .unwrap();
-        let storage_total = GaugeVec::new(
-            Opts::new("storage_total", "Total storage").namespace("tispace"),
-            &["node_name", "storage_pool"],
-        )
-        .unwrap();
-        let storage_allocated = GaugeVec::new(
-            Opts::new("storage_allocated", "Total storage allocated").namespace("tispace"),
-            &["node_name", "storage_pool"],
-        )
-        .unwrap();
-        let storage_used = GaugeVec::new(
-            Opts::new("storage_used", "Total storage used").namespace("tispace"),
-            &["node_name", "storage_pool"],
-        )
-        .unwrap();
-        let instance_status = GaugeVec::new(
-            Opts::new("instance_status", "Instance status").namespace("tispace"),
-            &["node_name", "storage_pool", "runtime", "status"],
-        )
-        .unwrap();
-
+    async fn metrics(
+        headers: HeaderMap,
+        Extension(storage): Extension<Storage>,
+    ) -> Result<impl IntoResponse, AuthError> {
+        let authorization = headers.get(AUTHORIZATION).and_then(|v| v.to_str().ok());
+        if !config::current().metrics_authorized(authorization) {
+            return Err(AuthError::UnauthorizedUser);
+        }
         let snapshot = storage.snapshot().await;
-        for node in &snapshot.nodes {
-            cpu_allocated
-                .with_label_values(&[node.name.as_str()])
-                .add(node.cpu_allocated as f64);
-            memory_allocated
-                .with_label_values(&[node.name.as_str()])
-                .add(node.memory_allocated as f64);
-            for pool in &node.storage_pools {
-                storage_total
-                    .with_label_values(&[node.name.as_str(), pool.name.as_str()])
-                    .add(pool.total as f64);
-                storage_allocated
-                    .with_label_values(&[node.name.as_str(), pool.name.as_str()])
-                    .add(pool.allocated as f64);
-                storage_used
-                    .with_label_values(&[node.name.as_str(), pool.name.as_str()])
-                    .add(pool.used as f64);
-            }
-        }
-
-        for instance in snapshot.users.iter().flat_map(|u| u.instances.iter()) {
-            let mut status = instance.status.to_string();
-            if status.starts_with("Error:") {
-                status = "Error".to_owned();
-            }
-
-            let node_name = instance.node_name.clone().unwrap_or_default();
-            let storage_pool = instance.storage_pool.clone().unwrap_or_default();
-
-            instance_status
-                .with_label_values(&[
-                    node_name.as_str(),
-                    storage_pool.as_str(),
-                    instance.runtime.to_string().as_str(),
-                    status.as_str(),
-                ])
-                .inc();
-        }
-
-        let r = Registry::new();
-        r.register(Box::new(cpu_allocated)).unwrap();
-        r.register(Box::new(memory_allocated)).unwrap();
-        r.register(Box::new(storage_total)).unwrap();
-        r.register(Box::new(storage_used)).unwrap();
-        r.register(Box::new(storage_allocated)).unwrap();
-        r.register(Box::new(instance_status)).unwrap();
-
-        let mut buffer = vec![];
-        let encoder = TextEncoder::new();
-        let metric_families = r.gather();
-        encoder.encode(&metric_families, &mut buffer).unwrap();
-        String::from_utf8(buffer).unwrap()
-    }
-
-    Router::new().route("/metrics", get(metrics))
+        Ok(metrics::gather(&snapshot))
+    }
+
+    Router::new()
+        .route("/metrics", get(metrics))
+        .layer(IpAllowlistLayer::new(Config::metrics_allowed))
+}
+
+/// Routes serving the web UI built into `dir`, see [`crate::config::Config::web_ui_dir`].
+pub fn web_ui_routes(dir: &str) -> Router {
+    let files = ServeDir::new(dir);
+    let index = ServeFile::new(std::path::Path::new(dir).join("index.html"));
+    let service = tower::service_fn(move |req: Request<Body>| {
+        let files = files.clone();
+        let index = index.clone();
+        async move {
+            // Pages routed by the client are rendered by the index page, while missing assets
+            // are reported as such.
+            let page = !req
+                .uri()
+                .path()
+                .rsplit('/')
+                .next()
+                .unwrap_or_default()
+                .contains('.');
+            let res = files.oneshot(req).await?;
+            if res.status() != StatusCode::NOT_FOUND || !page {
+                return Ok(res);
+            }
+            index.oneshot(Request::new(Body::empty())).await
+        }
+    });
+    Router::new().fallback(
+        get_service(service).handle_error(|e: std::io::Error| async move {
+            (
+                StatusCode::INTERNAL_SERVER_ERROR,
+                format!("Unhandled internal error: {}", e),
+            )
+        }),
+    )
 }
 
 #[cfg(test)]
@@ -602,4 +5402,245 @@ mod tests {
         assert!(verify_instance_name("dev-new"));
         assert!(!verify_instance_name("01dev"));
     }
+
+    #[test]
+    fn test_verify_ssh_authorized_key() {
+        assert!(verify_ssh_authorized_key(
+            "ssh-ed25519 AAAAC3NzaC1lZDI1NTE5 dev@host"
+        ));
+        assert!(verify_ssh_authorized_key("ssh-rsa AAAAB3NzaC1yc2E"));
+        assert!(verify_ssh_authorized_key(
+            "ecdsa-sha2-nistp256 AAAAE2VjZHNh"
+        ));
+        assert!(!verify_ssh_authorized_key(""));
+        assert!(!verify_ssh_authorized_key("ssh-ed25519"));
+        assert!(!verify_ssh_authorized_key("AAAAC3NzaC1lZDI1NTE5"));
+        assert!(!verify_ssh_authorized_key("ssh-rsa AAAA\nssh-rsa BBBB"));
+    }
+
+    #[test]
+    fn test_verify_oci_image() {
+        assert!(verify_oci_image("ghcr.io/acme/dev:1.0"));
+        assert!(verify_oci_image("localhost:5000/dev/rootfs:latest"));
+        assert!(verify_oci_image(&format!(
+            "docker.io/acme/dev@sha256:{}",
+            "a".repeat(64)
+        )));
+        assert!(!verify_oci_image("ghcr.io/acme/dev"));
+        assert!(!verify_oci_image("dev:1.0"));
+        assert!(!verify_oci_image("ghcr.io/Acme/dev:1.0"));
+        assert!(!verify_oci_image("ghcr.io/acme/dev:1.0 --privileged"));
+    }
+
+    fn new_instance(name: &str, disk_size: usize, node_name: &str) -> Instance {
+        serde_json::from_value(serde_json::json!({
+            "name": name,
+            "cpu": 2,
+            "memory": 4,
+            "disk_size": disk_size,
+            "image": "ubuntu:22.04",
+            "hostname": name,
+            "password": "",
+            "stage": "Running",
+            "status": "Running",
+            "runtime": "kata",
+            "node_name": node_name,
+        }))
+        .unwrap()
+    }
+
+    fn new_user(instances: Vec<Instance>) -> User {
+        serde_json::from_value(serde_json::json!({
+            "username": "dev",
+            "instances": instances,
+        }))
+        .unwrap()
+    }
+
+    fn new_volume(name: &str, size: usize, source: &str) -> VolumeDto {
+        VolumeDto {
+            name: name.to_string(),
+            size,
+            mount_path: format!("/{}", name),
+            source: source.to_string(),
+        }
+    }
+
+    #[test]
+    fn test_detached_volume_node() {
+        let mut user = new_user(vec![
+            new_instance("a", 10, "node1"),
+            new_instance("b", 10, "node2"),
+        ]);
+        assert!(keep_root_disk(&mut user, "a", 0).unwrap());
+        assert!(keep_root_disk(&mut user, "b", 0).unwrap());
+
+        let fresh = [new_volume("data", 10, "")];
+        assert_eq!(detached_volume_node(&user, &fresh, "").unwrap(), None);
+        let kept = [new_volume("data", 0, "a")];
+        assert_eq!(
+            detached_volume_node(&user, &kept, "").unwrap(),
+            Some("node1".to_string())
+        );
+        assert_eq!(
+            detached_volume_node(&user, &kept, "node1").unwrap(),
+            Some("node1".to_string())
+        );
+        // Pinned to another node than the one holding the volume.
+        assert!(detached_volume_node(&user, &kept, "node2").is_err());
+        // Volumes kept on different nodes.
+        let both = [new_volume("data", 0, "a"), new_volume("logs", 0, "b")];
+        assert!(detached_volume_node(&user, &both, "").is_err());
+        // Unknown volumes are reported when they're attached.
+        let unknown = [new_volume("data", 0, "c")];
+        assert_eq!(
+            detached_volume_node(&user, &unknown, "node2").unwrap(),
+            None
+        );
+        assert!(matches!(
+            attach_volumes(&mut user, &unknown),
+            Err(InstanceError::VolumeNotFound)
+        ));
+    }
+
+    #[test]
+    fn test_keep_root_disk_counted_once() {
+        let mut user = new_user(vec![new_instance("a", 10, "node1")]);
+        assert_eq!(user.used_disk_size(), 10);
+
+        assert!(keep_root_disk(&mut user, "a", 0).unwrap());
+        assert!(matches!(keep_root_disk(&mut user, "a", 0), Ok(false)));
+        assert_eq!(user.detached_volumes.len(), 1);
+        assert_eq!(user.detached_volumes[0].claim_name, "dev-a-rootfs");
+        // The instance is being deleted while the volume already exists.
+        assert_eq!(user.used_disk_size(), 10);
+
+        user.instances.clear();
+        assert_eq!(user.used_disk_size(), 10);
+
+        let volumes = attach_volumes(&mut user, &[new_volume("data", 0, "a")]).unwrap();
+        assert_eq!(volumes[0].size, 10);
+        assert_eq!(volumes[0].claim_name.as_deref(), Some("dev-a-rootfs"));
+        assert!(user.detached_volumes.is_empty());
+        let mut b = new_instance("b", 5, "node1");
+        b.volumes = volumes;
+        user.instances.push(b);
+        assert_eq!(user.used_disk_size(), 15);
+    }
+
+    #[test]
+    fn test_claim_in_use() {
+        let mut user = new_user(vec![new_instance("a", 10, "node1")]);
+        keep_root_disk(&mut user, "a", 0).unwrap();
+        let mut state = State::default();
+        state.users.push(user.clone());
+        assert!(claim_in_use(&state, "dev-a-rootfs"));
+        assert!(!claim_in_use(&state, "dev-b-rootfs"));
+
+        // The claim stays in use once the volume is attached to another instance.
+        let mut b = new_instance("b", 10, "node1");
+        b.volumes = attach_volumes(&mut user, &[new_volume("data", 0, "a")]).unwrap();
+        user.instances = vec![b];
+        state.users = vec![user];
+        assert!(claim_in_use(&state, "dev-a-rootfs"));
+    }
+
+    #[test]
+    fn test_keep_root_disk_unsupported() {
+        let mut a = new_instance("a", 10, "node1");
+        a.runtime = Runtime::Lxc;
+        let mut user = new_user(vec![a]);
+        assert!(matches!(
+            keep_root_disk(&mut user, "a", 0),
+            Err(InstanceError::KeepDiskUnsupported { .. })
+        ));
+        assert!(user.detached_volumes.is_empty());
+    }
+
+    #[test]
+    fn test_rewrite_overlapping_paths() {
+        let rewrite = |uri: &str| {
+            let req = Request::builder().uri(uri).body(()).unwrap();
+            rewrite_overlapping_paths(req).uri().to_string()
+        };
+        assert_eq!(rewrite("/instances/by-id/42"), "/instances-by-id/42");
+        assert_eq!(rewrite("/v1/instances/by-id/42"), "/v1/instances-by-id/42");
+        assert_eq!(
+            rewrite("/instances/by-id/42?a=b"),
+            "/instances-by-id/42?a=b"
+        );
+        assert_eq!(rewrite("/instances:batch"), "/batch/instances");
+        assert_eq!(
+            rewrite("/v1/instances:batch?confirm=a"),
+            "/v1/batch/instances?confirm=a"
+        );
+        assert_eq!(rewrite("/instances/events"), "/events/instances");
+        assert_eq!(rewrite("/v1/instances/events"), "/v1/events/instances");
+        assert_eq!(rewrite("/instances/dev/start"), "/instances/dev/start");
+        assert_eq!(
+            rewrite("/v10/instances/by-id/42"),
+            "/v10/instances/by-id/42"
+        );
+    }
+
+    #[tokio::test]
+    async fn test_rewritten_internal_paths() {
+        let extract = |uri: &str| {
+            let req = Request::builder().uri(uri).body(()).unwrap();
+            let mut parts = RequestParts::new(rewrite_overlapping_paths(req));
+            async move { Rewritten::from_request(&mut parts).await.map(|_| ()) }
+        };
+        assert_eq!(extract("/instances/by-id/42").await, Ok(()));
+        assert_eq!(extract("/v1/instances:batch").await, Ok(()));
+        assert_eq!(extract("/instances/events").await, Ok(()));
+        for uri in [
+            "/instances-by-id/42",
+            "/batch/instances",
+            "/v1/events/instances",
+        ] {
+            assert_eq!(extract(uri).await, Err(StatusCode::NOT_FOUND));
+        }
+    }
+
+    #[test]
+    fn test_check_file_access() {
+        let mut instance = new_instance("a", 10, "node1");
+        instance.shares = vec![serde_json::from_value(serde_json::json!({
+            "username": "guest",
+        }))
+        .unwrap()];
+        assert!(check_file_access(&instance, "dev", None).is_ok());
+        assert!(check_file_access(&instance, "dev", Some("dev")).is_ok());
+        let err = check_file_access(&instance, "guest", Some("dev")).unwrap_err();
+        assert_eq!(err.into_response().status(), StatusCode::FORBIDDEN);
+
+        // Users who may log in as root may as well transfer files.
+        instance.shares[0].ssh_authorized_keys = vec!["ssh-ed25519 AAAA guest".to_owned()];
+        assert!(check_file_access(&instance, "guest", Some("dev")).is_ok());
+    }
+
+    #[test]
+    fn test_preemptible_quotas() {
+        let mut preemptible = new_instance("b", 10, "node1");
+        preemptible.priority = Priority::Preemptible;
+        let instances = [new_instance("a", 10, "node1"), preemptible];
+        let check = |quotas, cpu, memory, disk_size| {
+            check_aggregate_quotas(
+                "dev",
+                quotas,
+                instances.iter(),
+                true,
+                cpu,
+                memory,
+                disk_size,
+            )
+        };
+        // Only the CPU and memory of the preemptible instance are left out.
+        assert!(check([3, 4, 8, 30], 2, 4, 10).is_none());
+        assert!(check([2, 4, 8, 30], 2, 4, 10).is_some());
+        assert!(check([3, 4, 8, 20], 2, 4, 10).is_some());
+        assert!(check([3, 3, 8, 30], 2, 4, 10).is_some());
+        // Preemptible instances request no CPU and memory.
+        assert!(check([3, 2, 4, 30], 0, 0, 10).is_none());
+    }
 }
@@ -0,0 +1,39 @@
+//! Coordinates the graceful shutdown of the server.
+//!
+//! Once shutdown is triggered, the HTTP server stops accepting connections and the background
+//! loops stop after the instance or node they are working on, so that no backend operation is
+//! abandoned halfway.
+
+use once_cell::sync::Lazy;
+use tokio::sync::watch;
+use tokio::time::Duration;
+
+// A receiver is kept alive so that sending never fails.
+static SHUTDOWN: Lazy<(watch::Sender<bool>, watch::Receiver<bool>)> =
+    Lazy::new(|| watch::channel(false));
+
+pub fn trigger() {
+    SHUTDOWN.0.send(true).unwrap();
+}
+
+pub fn is_triggered() -> bool {
+    *SHUTDOWN.1.borrow()
+}
+
+/// Waits until shutdown is triggered.
+pub async fn triggered() {
+    let mut rx = SHUTDOWN.1.clone();
+    while !*rx.borrow() {
+        if rx.changed().await.is_err() {
+            return;
+        }
+    }
+}
+
+/// Sleeps for the duration or until shutdown is triggered, whichever comes first.
+crate async fn sleep(duration: Duration) {
+    tokio::select! {
+        _ = tokio::time::sleep(duration) => {}
+        _ = triggered() => {}
+    }
+}
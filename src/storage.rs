@@ -1,32 +1,159 @@
+use std::collections::HashSet;
+use std::convert::Infallible;
 use std::io::ErrorKind;
-use std::sync::Arc;
+use std::sync::atomic::{AtomicBool, AtomicU64, Ordering};
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, Instant};
 
-use tokio::sync::RwLock;
+use rand::{thread_rng, Rng};
+use ring::aead::{Aad, LessSafeKey, Nonce, UnboundKey, AES_256_GCM, NONCE_LEN};
+use tokio::sync::{broadcast, RwLock};
+use tracing::{instrument, warn};
 
-use crate::{error::*, model::State};
+use crate::config;
+use crate::env::{HASH_PASSWORDS, LEADER_ELECTION_IDENTITY, STATE_ENCRYPTION_KEY};
+use crate::{
+    error::*,
+    model::{Instance, State},
+};
 
 #[derive(Clone)]
 pub struct Storage {
     path: String,
     state: Arc<RwLock<State>>,
+    // Nodes whose capacity changed since they were last collected.
+    dirty_nodes: Arc<Mutex<HashSet<String>>>,
+    // When the collector last refreshed all nodes successfully.
+    last_collected: Arc<Mutex<Option<Instant>>>,
+    status_changes: broadcast::Sender<StatusChange>,
+    // Whether the state has changes not written to the file yet, and whether a write of them is
+    // scheduled. Both are only changed while holding the write lock of the state.
+    unpersisted: Arc<AtomicBool>,
+    persist_scheduled: Arc<AtomicBool>,
+    // Bumped on every change of the state while holding its write lock. It starts at random, so
+    // that the revisions of other replicas and previous runs don't collide.
+    revision: Arc<AtomicU64>,
 }
 
+/// A change of the status of an instance, published whenever the state is written.
+#[derive(Debug, Clone)]
+crate struct StatusChange {
+    crate username: String,
+    crate instance: String,
+    // The status of the instance, or "Deleted" once it's removed.
+    crate status: String,
+}
+
+// Prefix of an encrypted state file, followed by the nonce and the sealed JSON of the state.
+const ENCRYPTED_STATE_PREFIX: &[u8] = b"tispace-aes-256-gcm:";
+
+/// Returns the key the state file is encrypted with, or None if it's written in plaintext.
+fn state_key() -> Option<LessSafeKey> {
+    if STATE_ENCRYPTION_KEY.is_empty() {
+        return None;
+    }
+    // The key is validated when the config is loaded.
+    let key = base64::decode(STATE_ENCRYPTION_KEY.as_str()).unwrap();
+    Some(LessSafeKey::new(
+        UnboundKey::new(&AES_256_GCM, &key).unwrap(),
+    ))
+}
+
+/// Serializes the state, encrypted if a key is configured.
+fn encode_state(state: &State) -> Result<Vec<u8>> {
+    let mut data = serde_json::to_vec(state).unwrap();
+    let key = match state_key() {
+        Some(key) => key,
+        None => return Ok(data),
+    };
+    let mut nonce = [0u8; NONCE_LEN];
+    thread_rng().fill(&mut nonce);
+    key.seal_in_place_append_tag(Nonce::assume_unique_for_key(nonce), Aad::empty(), &mut data)
+        .map_err(|_| "encrypt state failed")?;
+    Ok([ENCRYPTED_STATE_PREFIX, &nonce, &data].concat())
+}
+
+/// Deserializes the state, which is decrypted first if it's encrypted.
+fn decode_state(contents: &[u8]) -> Result<State> {
+    let sealed = match contents.strip_prefix(ENCRYPTED_STATE_PREFIX) {
+        Some(sealed) => sealed,
+        None => return Ok(serde_json::from_slice(contents)?),
+    };
+    let key = state_key().ok_or("state is encrypted but state_encryption_key is not set")?;
+    if sealed.len() < NONCE_LEN {
+        return Err("encrypted state is truncated".into());
+    }
+    let (nonce, data) = sealed.split_at(NONCE_LEN);
+    let mut data = data.to_vec();
+    let plaintext = key
+        .open_in_place(
+            Nonce::try_assume_unique_for_key(nonce).unwrap(),
+            Aad::empty(),
+            &mut data,
+        )
+        .map_err(|_| "decrypt state failed, the key may be wrong")?;
+    Ok(serde_json::from_slice(plaintext)?)
+}
+
+// Subscribers lagging behind by more changes than this miss some of them.
+const STATUS_CHANGES_CAPACITY: usize = 1024;
+
 impl Storage {
     pub async fn open(path: &str) -> Result<Self> {
         let mut state = State::new();
         match tokio::fs::read(path).await {
             Ok(contents) => {
-                state = serde_json::from_slice(&contents)?;
+                state = decode_state(&contents)?;
             }
             Err(ref e) if e.kind() == ErrorKind::NotFound => {}
             Err(e) => return Err(Box::new(e)),
         }
+        state.resolve_image_sources();
+        state.assign_instance_ids();
+        state.repair_instance_statuses();
         Ok(Storage {
             path: path.to_string(),
             state: Arc::new(RwLock::new(state)),
+            dirty_nodes: Arc::new(Mutex::new(HashSet::new())),
+            last_collected: Arc::new(Mutex::new(None)),
+            status_changes: broadcast::channel(STATUS_CHANGES_CAPACITY).0,
+            unpersisted: Arc::new(AtomicBool::new(false)),
+            persist_scheduled: Arc::new(AtomicBool::new(false)),
+            revision: Arc::new(AtomicU64::new(thread_rng().gen())),
         })
     }
 
+    /// Reads the state again from the file, which is written by another replica.
+    pub async fn reload(&self) -> Result<()> {
+        let contents = tokio::fs::read(&self.path).await?;
+        let mut state = decode_state(&contents)?;
+        state.assign_instance_ids();
+        state.repair_instance_statuses();
+        let current = &mut *self.state.write().await;
+        self.publish_status_changes(current, &state);
+        *current = state;
+        self.revision.fetch_add(1, Ordering::SeqCst);
+        Ok(())
+    }
+
+    /// Removes the temporary file left by a write interrupted before it replaced the state. Only
+    /// the leader may call it, as the file of a write in progress is removed as well.
+    pub async fn remove_interrupted_write(&self) -> Result<()> {
+        match tokio::fs::remove_file(format!("{}.tmp", self.path)).await {
+            Err(e) if e.kind() != ErrorKind::NotFound => Err(Box::new(e)),
+            _ => Ok(()),
+        }
+    }
+
+    /// Waits for the write in progress, if any, to be persisted, and writes the deferred changes.
+    pub async fn flush(&self) -> Result<()> {
+        let state = self.state.write().await;
+        if self.unpersisted.load(Ordering::SeqCst) {
+            self.persist(&state).await?;
+        }
+        Ok(())
+    }
+
     crate async fn read_only<F>(&self, mut f: F)
     where
         F: FnMut(&State),
@@ -37,19 +164,239 @@ impl Storage {
     crate async fn read_write<F>(&self, mut f: F) -> Result<()>
     where
         F: FnMut(&mut State) -> bool,
+    {
+        self.try_read_write(|state| Ok::<_, Infallible>(f(state)))
+            .await
+            .map(|_| ())
+    }
+
+    /// Applies the changes made by the closure to a copy of the state if it returns `Ok(true)`.
+    /// An error returned by the closure, e.g. a validation failure, discards the copy and is
+    /// passed back in the inner result, while the outer one fails if the state can't be written.
+    #[instrument(level = "debug", skip_all)]
+    crate async fn try_read_write<F, E>(&self, f: F) -> Result<std::result::Result<(), E>>
+    where
+        F: FnMut(&mut State) -> std::result::Result<bool, E>,
+    {
+        self.apply(f, false).await
+    }
+
+    /// Like `read_write`, but the changes are persisted together with the others made within
+    /// `state_write_delay`, so that the status updates of the background workers don't write the
+    /// file one by one. Changes lost by a crash before they are written must be made again by
+    /// the next pass of the worker, e.g. statuses observed from the backends.
+    #[instrument(level = "debug", skip_all)]
+    crate async fn read_write_deferred<F>(&self, mut f: F) -> Result<()>
+    where
+        F: FnMut(&mut State) -> bool,
+    {
+        self.apply(|state| Ok::<_, Infallible>(f(state)), true)
+            .await
+            .map(|_| ())
+    }
+
+    async fn apply<F, E>(&self, mut f: F, deferred: bool) -> Result<std::result::Result<(), E>>
+    where
+        F: FnMut(&mut State) -> std::result::Result<bool, E>,
     {
         let state = &mut *self.state.write().await;
         let mut new_state = state.clone();
-        if f(&mut new_state) {
-            new_state.sync_allocated_resources();
-            if new_state != *state {
-                let data = serde_json::to_vec(&new_state).unwrap();
-                let tmp_path = format!("{}.tmp", self.path);
-                tokio::fs::write(&tmp_path, data).await?;
-                tokio::fs::rename(&tmp_path, &self.path).await?;
-                *state = new_state;
+        match f(&mut new_state) {
+            Ok(true) => {}
+            Ok(false) => return Ok(Ok(())),
+            Err(e) => return Ok(Err(e)),
+        }
+        new_state.sync_allocated_resources();
+        new_state.record_status_transitions(state);
+        new_state.track_uptime();
+        new_state.clear_provisioning_progress();
+        new_state.stamp_timestamps(state);
+        if *HASH_PASSWORDS {
+            new_state.hash_provisioned_passwords();
+        }
+        if new_state != *state {
+            let delay = config::current().state_write_delay;
+            if deferred && delay > 0 {
+                self.unpersisted.store(true, Ordering::SeqCst);
+                self.schedule_persist(Duration::from_millis(delay));
+            } else {
+                self.persist(&new_state).await?;
+            }
+            self.publish_status_changes(state, &new_state);
+            *state = new_state;
+            self.revision.fetch_add(1, Ordering::SeqCst);
+        }
+        Ok(Ok(()))
+    }
+
+    /// Writes the state to the file, including the deferred changes made before.
+    async fn persist(&self, state: &State) -> Result<()> {
+        let data = encode_state(state)?;
+        let tmp_path = format!("{}.tmp", self.path);
+        tokio::fs::write(&tmp_path, data).await?;
+        tokio::fs::rename(&tmp_path, &self.path).await?;
+        self.unpersisted.store(false, Ordering::SeqCst);
+        Ok(())
+    }
+
+    /// Writes the deferred changes after the delay unless a write is scheduled already. Failed
+    /// writes are retried after the same delay.
+    fn schedule_persist(&self, delay: Duration) {
+        if self.persist_scheduled.swap(true, Ordering::SeqCst) {
+            return;
+        }
+        let storage = self.clone();
+        tokio::spawn(async move {
+            loop {
+                tokio::time::sleep(delay).await;
+                let state = storage.state.write().await;
+                // Synchronous writes in the meantime persist the deferred changes as well.
+                if storage.unpersisted.load(Ordering::SeqCst) {
+                    if let Err(e) = storage.persist(&state).await {
+                        warn!(
+                            error = e.to_string().as_str(),
+                            "writing deferred state changes encountered error"
+                        );
+                        continue;
+                    }
+                }
+                storage.persist_scheduled.store(false, Ordering::SeqCst);
+                break;
+            }
+        });
+    }
+
+    /// Records an event on the given instance if it still exists.
+    crate async fn add_instance_event(
+        &self,
+        username: &str,
+        instance_name: &str,
+        message: String,
+    ) -> Result<()> {
+        self.read_write_deferred(|state| {
+            if let Some(i) = state
+                .find_mut_user(username)
+                .and_then(|u| u.find_mut_instance(instance_name))
+            {
+                i.add_event(message.clone());
+                return true;
+            }
+            false
+        })
+        .await
+    }
+
+    /// Records on the instance that its node is unhealthy, once per reason, see
+    /// `State::unhealthy_node_event`. Errors are logged as the operators carry on regardless.
+    crate async fn record_unhealthy_node(
+        &self,
+        state: &State,
+        username: &str,
+        instance: &Instance,
+    ) {
+        let event = match state.unhealthy_node_event(instance) {
+            Some(event) => event,
+            None => return,
+        };
+        warn!(
+            username = username,
+            instance = instance.name.as_str(),
+            runtime = instance.runtime.to_string().as_str(),
+            event = event.as_str(),
+            "node of instance is unhealthy"
+        );
+        if let Err(e) = self
+            .add_instance_event(username, &instance.name, event)
+            .await
+        {
+            warn!(
+                username = username,
+                instance = instance.name.as_str(),
+                runtime = instance.runtime.to_string().as_str(),
+                error = e.to_string().as_str(),
+                "recording instance event encountered error"
+            );
+        }
+    }
+
+    /// Subscribes to the changes of the statuses of all instances.
+    crate fn subscribe_status_changes(&self) -> broadcast::Receiver<StatusChange> {
+        self.status_changes.subscribe()
+    }
+
+    fn publish_status_changes(&self, old: &State, new: &State) {
+        if self.status_changes.receiver_count() == 0 {
+            return;
+        }
+        for u in &new.users {
+            let old_user = old.find_user(&u.username);
+            for i in &u.instances {
+                let old_status = old_user
+                    .and_then(|o| o.find_instance(&i.name))
+                    .map(|o| &o.status);
+                if old_status != Some(&i.status) {
+                    // Sending fails only if all receivers are dropped in the meantime.
+                    let _ = self.status_changes.send(StatusChange {
+                        username: u.username.clone(),
+                        instance: i.name.clone(),
+                        status: i.status.to_string(),
+                    });
+                }
             }
         }
+        for u in &old.users {
+            let new_user = new.find_user(&u.username);
+            for i in &u.instances {
+                if new_user.and_then(|n| n.find_instance(&i.name)).is_none() {
+                    let _ = self.status_changes.send(StatusChange {
+                        username: u.username.clone(),
+                        instance: i.name.clone(),
+                        status: "Deleted".to_string(),
+                    });
+                }
+            }
+        }
+    }
+
+    /// Returns the revision of the state, which is consistent with the state while the lock of
+    /// `read_only` is held.
+    crate fn revision(&self) -> u64 {
+        self.revision.load(Ordering::SeqCst)
+    }
+
+    /// Asks the collector to refresh the node before its next periodic collection.
+    crate fn mark_node_dirty(&self, node_name: &str) {
+        self.dirty_nodes
+            .lock()
+            .unwrap()
+            .insert(node_name.to_owned());
+    }
+
+    crate fn take_dirty_nodes(&self) -> Vec<String> {
+        self.dirty_nodes.lock().unwrap().drain().collect()
+    }
+
+    crate fn mark_collected(&self) {
+        *self.last_collected.lock().unwrap() = Some(Instant::now());
+    }
+
+    crate fn last_collected(&self) -> Option<Instant> {
+        *self.last_collected.lock().unwrap()
+    }
+
+    /// Checks that the directory of the state file is writable, which persisting the state
+    /// requires. Replicas share the directory, so each probe writes a file of its own.
+    crate async fn check_writable(&self) -> Result<()> {
+        static PROBES: AtomicU64 = AtomicU64::new(0);
+        let probe_path = format!(
+            "{}.probe.{}.{}.{}",
+            self.path,
+            LEADER_ELECTION_IDENTITY.as_str(),
+            std::process::id(),
+            PROBES.fetch_add(1, Ordering::Relaxed)
+        );
+        tokio::fs::write(&probe_path, b"").await?;
+        tokio::fs::remove_file(&probe_path).await?;
         Ok(())
     }
 